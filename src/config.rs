@@ -1,11 +1,15 @@
+use crate::error::{FailureKind, ResultExt};
 use anyhow::format_err;
-use cargo::core::{TargetKind, Workspace};
+use cargo::core::resolver::CliFeatures;
+use cargo::core::{Package, TargetKind, Workspace};
 use cargo::ops;
 use cargo::util::CargoResult;
 use cargo::CliError;
+use cargo_util::{ProcessBuilder, Sha256};
 use itertools::Itertools;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::collections::btree_map::BTreeMap;
+use std::collections::BTreeSet;
 use std::env;
 use std::fs;
 use std::fs::File;
@@ -13,6 +17,7 @@ use std::io::Read;
 use std::iter::FromIterator;
 use std::path::Path;
 use std::path::PathBuf;
+use std::rc::Rc;
 use toml;
 
 #[derive(Clone)]
@@ -35,18 +40,32 @@ pub struct AndroidConfig {
 
     /// Path to the android.jar for the selected android platform
     pub android_jar_path: PathBuf,
+    /// API level of the selected android platform, the app is compiled against
+    pub android_version: u32,
 
     /// Version of android:targetSdkVersion (optional). Default Value = android_version
     pub target_sdk_version: u32,
     /// Version of android:minSdkVersion (optional). Default Value = android_version
     pub min_sdk_version: u32,
 
-    /// Version of the build tools to use
-    pub build_tools_version: String,
+    /// Version of the build tools to use, if any is installed
+    pub build_tools_version: Option<String>,
 
     /// Should we build in release mode?
     pub release: bool,
 
+    /// Serial of the device selected with `--device`, given to adb as `-s`
+    pub device: Option<String>,
+
+    /// Device ports reversed to the same host ports after installing a debug build
+    pub dev_ports: Vec<u16>,
+
+    /// Destination of `cargo quad-apk publish`
+    pub publish: Option<PublishConfig>,
+
+    /// Key signing the release builds, the debug keystore signs them otherwise
+    pub signing: Option<SigningConfig>,
+
     /// Target configuration settings that are associated with a specific target
     default_target_config: TomlAndroidTarget,
 
@@ -54,9 +73,317 @@ pub struct AndroidConfig {
     target_configs: BTreeMap<(TargetKind, String), TomlAndroidTarget>,
 
     pub java_packages: Vec<String>,
+
+    /// Directories of the package with Java sources of the app itself
+    pub java_sources: Vec<String>,
+    /// .jar files of the package used when compiling the Java sources
+    pub comptime_jars: Vec<String>,
+    /// .jar files of the package included in the dex
+    pub runtime_jars: Vec<String>,
+    /// Java classes and members the libraries reach through JNI without naming them, kept
+    /// by the generated keep rules
+    pub jni_keep: Vec<String>,
+    /// Java source of the package replacing the MainActivity of miniquad
+    pub main_activity: Option<PathBuf>,
+
+    /// Core library desugaring of the dex, when enabled
+    pub desugaring: Option<CoreLibraryDesugaring>,
+
+    /// Framework the app is built with
+    pub framework: Framework,
+
+    /// Whether one APK per ABI is built in addition to the APK with every ABI
+    pub split_apks: bool,
+
+    /// Rust toolchain the libraries are compiled with, instead of the one running cargo
+    pub rust_toolchain: Option<String>,
+
+    /// bundletool jar building the app bundles, downloaded to the cache when not set
+    pub bundletool_path: Option<PathBuf>,
+
+    /// Size in KiB of the pages the native libraries of the APKs are aligned on, 4 or 16
+    pub page_alignment: u32,
+
+    /// What is stripped from the libraries of the APKs, defaults to the profile with `None`
+    pub strip: Option<Strip>,
+
+    /// Whether calls of the Java code to APIs above `min_sdk_version` fail the build
+    pub strict_api_lint: bool,
+
+    /// Classes and methods, like `com.example.Camera.open`, whose calls to APIs above
+    /// `min_sdk_version` are not reported
+    pub api_lint_allow: Vec<String>,
+
+    /// Whether `application_attributes` and `activity_attributes` unknown to the attribute table
+    /// are rendered without a warning
+    pub allow_unknown_attributes: bool,
+
+    /// Keys with the values used in their place when the package has no
+    /// `[package.metadata.android]` at all, empty otherwise
+    pub defaulted_keys: Vec<(&'static str, String)>,
+
+    /// Cargo features enabled on the package for the current build
+    pub cargo_features: BTreeSet<String>,
+
+    /// Whether the default features were disabled with `--no-default-features`
+    pub no_default_features: bool,
+
+    /// Whether the `build_targets` were replaced for this build only, by `--abi`, by
+    /// `run --emulator` or by the ABI of the device of `run`
+    pub build_targets_overridden: bool,
+
+    /// Configuration blocks applied on top of the target configuration when their condition holds
+    conditional_configs: BTreeMap<String, TomlAndroidConditional>,
+
+    /// Flavors of the APKs, by name
+    flavors: BTreeMap<String, TomlFlavor>,
+
+    /// Flavor being built, selected with `--flavor`
+    pub flavor: Option<String>,
+
+    /// Values overriding the configuration of every target
+    pub overrides: ManifestOverrides,
+}
+
+/// Manifest values given on the command line or in the environment, which take precedence over
+/// the configuration of every target
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ManifestOverrides {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version_name: Option<Override<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version_code: Option<Override<i32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<Override<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profileable: Option<Override<bool>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Override<T> {
+    pub value: T,
+    /// `cli` or the name of the environment variable
+    pub source: String,
+}
+
+impl ManifestOverrides {
+    /// Combines the command line values with the `CARGO_QUAD_APK_VERSION_NAME`,
+    /// `CARGO_QUAD_APK_VERSION_CODE` and `CARGO_QUAD_APK_LABEL` environment variables read
+    /// through `env`. Command line values win.
+    pub fn new(
+        version_name: Option<&String>,
+        version_code: Option<&String>,
+        label: Option<&String>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> CargoResult<ManifestOverrides> {
+        let pick = |cli: Option<&String>, var: &str| match cli {
+            Some(value) => Some(Override {
+                value: value.clone(),
+                source: "cli".to_owned(),
+            }),
+            None => env(var).map(|value| Override {
+                value,
+                source: var.to_owned(),
+            }),
+        };
+
+        let version_code = pick(version_code, "CARGO_QUAD_APK_VERSION_CODE")
+            .map(|version_code| {
+                let value = version_code.value.parse::<i32>().map_err(|_| {
+                    format_err!(
+                        "Invalid version code `{}` from {}, expected an integer up to {}",
+                        version_code.value,
+                        version_code.source,
+                        i32::MAX
+                    )
+                })?;
+                Ok::<_, anyhow::Error>(Override {
+                    value,
+                    source: version_code.source,
+                })
+            })
+            .transpose()?;
+
+        Ok(ManifestOverrides {
+            version_name: pick(version_name, "CARGO_QUAD_APK_VERSION_NAME"),
+            version_code,
+            label: pick(label, "CARGO_QUAD_APK_LABEL"),
+            profileable: None,
+        })
+    }
+}
+
+#[test]
+fn manifest_overrides_precedence() {
+    let env = |var: &str| match var {
+        "CARGO_QUAD_APK_VERSION_NAME" => Some("1.2.3-env".to_owned()),
+        "CARGO_QUAD_APK_VERSION_CODE" => Some("7".to_owned()),
+        _ => None,
+    };
+    let config = |overrides: ManifestOverrides| {
+        let mut config = from_metadata(
+            r#"
+            version_name = "1.0"
+            version_code = 1
+            label = "App"
+            "#,
+        );
+        config.overrides = overrides;
+        config.resolve((TargetKind::Bin, "app".to_owned())).unwrap()
+    };
+
+    // The environment overrides the manifest
+    let overrides = ManifestOverrides::new(None, None, None, env).unwrap();
+    let target_config = config(overrides.clone());
+    assert_eq!(target_config.version_name, "1.2.3-env");
+    assert_eq!(target_config.version_code, 7);
+    assert_eq!(target_config.package_label, "App");
+    assert_eq!(
+        overrides.version_code.unwrap().source,
+        "CARGO_QUAD_APK_VERSION_CODE"
+    );
+
+    // The command line overrides the environment
+    let overrides = ManifestOverrides::new(
+        Some(&"1.2.3-rc1".to_owned()),
+        Some(&"10203001".to_owned()),
+        Some(&"App RC".to_owned()),
+        env,
+    )
+    .unwrap();
+    let target_config = config(overrides.clone());
+    assert_eq!(target_config.version_name, "1.2.3-rc1");
+    assert_eq!(target_config.version_code, 10203001);
+    assert_eq!(target_config.package_label, "App RC");
+    assert_eq!(overrides.version_name.unwrap().source, "cli");
+
+    assert!(ManifestOverrides::new(None, Some(&"3000000000".to_owned()), None, env).is_err());
+    assert!(ManifestOverrides::new(None, Some(&"1.0".to_owned()), None, env).is_err());
 }
 
 impl AndroidConfig {
+    /// Returns the path to `adb`, failing when the SDK platform-tools are not installed
+    pub fn adb(&self) -> CargoResult<PathBuf> {
+        find_adb(&self.sdk_path)
+            .ok_or_else(|| {
+                format_err!(
+                    "Android SDK at `{}` has no platform-tools, install them with \
+                 `sdkmanager \"platform-tools\"`",
+                    self.sdk_path.display()
+                )
+            })
+            .failure_kind(FailureKind::Environment)
+    }
+
+    /// Returns an `adb` command for the device selected with `--device`, if any
+    pub fn adb_command(&self) -> CargoResult<ProcessBuilder> {
+        let mut adb = ProcessBuilder::new(self.adb()?);
+        if let Some(serial) = &self.device {
+            adb.arg("-s").arg(serial);
+        }
+        Ok(adb)
+    }
+
+    /// Returns the directory of the build tools, failing when none are installed
+    pub fn build_tools_path(&self) -> CargoResult<PathBuf> {
+        let version = self
+            .build_tools_version
+            .as_ref()
+            .ok_or_else(|| {
+                format_err!(
+                    "Android SDK at `{}` has no build-tools, install them with \
+                 `sdkmanager \"build-tools;<version>\"`",
+                    self.sdk_path.display()
+                )
+            })
+            .failure_kind(FailureKind::Environment)?;
+        Ok(self.sdk_path.join("build-tools").join(version))
+    }
+
+    /// Builds for `build_targets` in place of those of the metadata
+    pub fn override_build_targets(&mut self, build_targets: Vec<AndroidBuildTarget>) {
+        self.build_targets = build_targets;
+        self.build_targets_overridden = true;
+    }
+
+    /// Returns the config building the flavor `name`
+    pub fn with_flavor(&self, name: &str) -> CargoResult<AndroidConfig> {
+        if !self.flavors.contains_key(name) {
+            return Err(if self.flavors.is_empty() {
+                format_err!(
+                    "Unknown flavor `{}`, the package has no \
+                     `[package.metadata.android.flavors]`",
+                    name
+                )
+            } else {
+                format_err!(
+                    "Unknown flavor `{}`, expected one of: {}",
+                    name,
+                    self.flavors.keys().join(", ")
+                )
+            });
+        }
+        Ok(AndroidConfig {
+            flavor: Some(name.to_owned()),
+            ..self.clone()
+        })
+    }
+
+    /// Returns the names of the flavors of the package
+    pub fn flavor_names(&self) -> Vec<String> {
+        self.flavors.keys().cloned().collect()
+    }
+
+    fn selected_flavor(&self) -> Option<&TomlFlavor> {
+        self.flavor.as_ref().and_then(|name| self.flavors.get(name))
+    }
+
+    /// Returns the features of the command line along with those of the flavor being built
+    pub fn flavor_features(&self, cli_features: &CliFeatures) -> CargoResult<CliFeatures> {
+        let features = match self
+            .selected_flavor()
+            .and_then(|flavor| flavor.features.as_ref())
+        {
+            Some(features) => features,
+            None => return Ok(cli_features.clone()),
+        };
+        let flavor_features = CliFeatures::from_command_line(features, false, true)?;
+        let mut features = (*cli_features.features).clone();
+        features.extend(flavor_features.features.iter().cloned());
+        Ok(CliFeatures {
+            features: Rc::new(features),
+            ..cli_features.clone()
+        })
+    }
+
+    /// Returns the suffix of the package name given by the flavor being built
+    pub fn flavor_package_name_suffix(&self) -> Option<&str> {
+        self.selected_flavor()
+            .and_then(|flavor| flavor.package_name_suffix.as_deref())
+    }
+
+    /// Returns a hash of the active cargo features and of `--no-default-features`, recorded
+    /// with the APKs so that an APK built with other features is never installed. Overridden
+    /// build targets are hashed too, the APKs of a regular build having libraries for every
+    /// target of the metadata.
+    pub fn features_fingerprint(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(format!("no-default-features={}\n", self.no_default_features).as_bytes());
+        for feature in &self.cargo_features {
+            hasher.update(feature.as_bytes());
+            hasher.update(b"\n");
+        }
+        if self.build_targets_overridden {
+            let abis = self
+                .build_targets
+                .iter()
+                .map(|target| target.android_abi())
+                .join(",");
+            hasher.update(format!("build-targets={}\n", abis).as_bytes());
+        }
+        hasher.finish_hex()[..16].to_owned()
+    }
+
     /// Builds the android target config based on the default target config and the specific target configs defined in the manifest
     pub fn resolve(&self, target: (TargetKind, String)) -> CargoResult<AndroidTargetConfig> {
         let primary_config = self.target_configs.get(&target);
@@ -64,7 +391,11 @@ impl AndroidConfig {
         let is_default_target = target_name == self.cargo_package_name;
         let example = target.0 == TargetKind::ExampleBin;
 
-        Ok(AndroidTargetConfig {
+        let debuggable_attribute = primary_config
+            .and_then(|a| a.debuggable)
+            .or_else(|| self.default_target_config.debuggable);
+        let mut target_config = AndroidTargetConfig {
+            framework: self.framework,
             package_name: primary_config
                 .and_then(|a| a.package_name.clone())
                 .or_else(|| {
@@ -76,11 +407,20 @@ impl AndroidConfig {
                 })
                 .unwrap_or_else(|| {
                     if example {
-                        format!("rust.{}.example.{}", self.cargo_package_name, target_name)
+                        format!(
+                            "rust.{}.example.{}",
+                            package_name_segment(&self.cargo_package_name),
+                            package_name_segment(&target_name)
+                        )
                     } else {
-                        format!("rust.{}", target_name)
+                        format!("rust.{}", package_name_segment(&target_name))
                     }
                 }),
+            library_name: String::new(),
+            main_activity_class: match &self.main_activity {
+                Some(path) => main_activity_class(path)?,
+                None => "MainActivity".to_owned(),
+            },
             package_label: primary_config
                 .and_then(|a| a.label.clone())
                 .or_else(|| {
@@ -106,22 +446,153 @@ impl AndroidConfig {
                 .and_then(|a| a.assets.as_ref())
                 .or_else(|| self.default_target_config.assets.as_ref())
                 .map(|p| self.manifest_path.parent().unwrap().join(p)),
-            res_path: primary_config
+            res_paths: primary_config
                 .and_then(|a| a.res.as_ref())
                 .or_else(|| self.default_target_config.res.as_ref())
+                .map_or_else(Vec::new, |res| {
+                    res.paths(self.manifest_path.parent().unwrap())
+                }),
+            manifest_template: primary_config
+                .and_then(|a| a.manifest_path.as_ref())
+                .or_else(|| self.default_target_config.manifest_path.as_ref())
                 .map(|p| self.manifest_path.parent().unwrap().join(p)),
             fullscreen: primary_config
                 .and_then(|a| a.fullscreen)
                 .or_else(|| self.default_target_config.fullscreen)
                 .unwrap_or(false),
+            request_legacy_external_storage: primary_config
+                .and_then(|a| a.request_legacy_external_storage)
+                .or_else(|| self.default_target_config.request_legacy_external_storage)
+                .unwrap_or(false),
+            test_only: primary_config
+                .and_then(|a| a.test_only)
+                .or_else(|| self.default_target_config.test_only)
+                .unwrap_or(false),
+            allow_resource_warnings: primary_config
+                .and_then(|a| a.allow_resource_warnings)
+                .or_else(|| self.default_target_config.allow_resource_warnings)
+                .unwrap_or(false),
+            target_sandbox_version: primary_config
+                .and_then(|a| a.target_sandbox_version)
+                .or_else(|| self.default_target_config.target_sandbox_version)
+                .map(|version| match version {
+                    1 | 2 => Ok(version),
+                    _ => Err(format_err!(
+                        "Invalid target_sandbox_version `{}`, expected 1 or 2",
+                        version
+                    )),
+                })
+                .transpose()?,
+            profileable: primary_config
+                .and_then(|a| a.profileable)
+                .or_else(|| self.default_target_config.profileable)
+                .unwrap_or(false),
+            generate_asset_manifest: primary_config
+                .and_then(|a| a.generate_asset_manifest)
+                .or_else(|| self.default_target_config.generate_asset_manifest)
+                .unwrap_or(false),
+            assets_exclude: primary_config
+                .and_then(|a| a.assets_exclude.clone())
+                .or_else(|| self.default_target_config.assets_exclude.clone())
+                .unwrap_or_default(),
+            assets_subset: vec![],
+            verify_assets: primary_config
+                .and_then(|a| a.verify_assets.as_ref())
+                .or_else(|| self.default_target_config.verify_assets.as_ref())
+                .into_iter()
+                .flatten()
+                .map(|list| self.manifest_path.parent().unwrap().join(list))
+                .collect(),
+            embed_build_env: match primary_config
+                .and_then(|a| a.embed_build_env.as_ref())
+                .or_else(|| self.default_target_config.embed_build_env.as_ref())
+            {
+                None | Some(TomlEmbedBuildEnv::Enabled(false)) => false,
+                Some(TomlEmbedBuildEnv::Enabled(true)) => !self.release,
+                Some(TomlEmbedBuildEnv::Mode(mode)) if mode == "always" => true,
+                Some(TomlEmbedBuildEnv::Mode(mode)) => {
+                    return Err(format_err!(
+                        "Invalid embed_build_env `{}`, expected true, false or \"always\"",
+                        mode
+                    ))
+                }
+            },
+            debug_assets_external: !self.release
+                && primary_config
+                    .and_then(|a| a.debug_assets_external)
+                    .or_else(|| self.default_target_config.debug_assets_external)
+                    .unwrap_or(false),
+            supported_locales: primary_config
+                .and_then(|a| a.supported_locales.clone())
+                .or_else(|| self.default_target_config.supported_locales.clone())
+                .unwrap_or_else(Vec::new)
+                .into_iter()
+                .map(|locale| {
+                    if is_language_tag(&locale) {
+                        Ok(locale)
+                    } else {
+                        Err(format_err!(
+                            "Invalid locale `{}` in `supported_locales`, expected a BCP-47 \
+                             language tag like `en`, `de` or `zh-CN`",
+                            locale
+                        ))
+                    }
+                })
+                .collect::<CargoResult<_>>()?,
+            debuggable: debuggable_attribute
+                .or_else(|| {
+                    primary_config
+                        .and_then(|a| a.application_attributes.as_ref())
+                        .or_else(|| self.default_target_config.application_attributes.as_ref())
+                        .and_then(|attributes| attributes.get("android:debuggable"))
+                        .map(|debuggable| debuggable == "true")
+                })
+                .unwrap_or(!self.release),
+            debuggable_attribute,
+            allow_backup: primary_config
+                .and_then(|a| a.allow_backup)
+                .or_else(|| self.default_target_config.allow_backup),
+            hardware_accelerated: primary_config
+                .and_then(|a| a.hardware_accelerated)
+                .or_else(|| self.default_target_config.hardware_accelerated),
+            orientation: primary_config
+                .and_then(|a| a.orientation.clone())
+                .or_else(|| self.default_target_config.orientation.clone())
+                .map(|orientation| {
+                    validate_values("orientation", &[orientation], SCREEN_ORIENTATIONS)
+                })
+                .transpose()?,
+            resizable_activity: primary_config
+                .and_then(|a| a.resizable_activity)
+                .or_else(|| self.default_target_config.resizable_activity),
+            launch_mode: primary_config
+                .and_then(|a| a.launch_mode.clone())
+                .or_else(|| self.default_target_config.launch_mode.clone())
+                .map(|mode| validate_values("launch_mode", &[mode], LAUNCH_MODES))
+                .transpose()?,
+            aapt_args: primary_config
+                .and_then(|a| a.aapt_args.clone())
+                .or_else(|| self.default_target_config.aapt_args.clone())
+                .into_iter()
+                .chain(if self.release {
+                    primary_config
+                        .and_then(|a| a.release_aapt_args.clone())
+                        .or_else(|| self.default_target_config.release_aapt_args.clone())
+                } else {
+                    primary_config
+                        .and_then(|a| a.debug_aapt_args.clone())
+                        .or_else(|| self.default_target_config.debug_aapt_args.clone())
+                })
+                .flatten()
+                .collect(),
             application_attributes: primary_config
                 .and_then(|a| a.application_attributes.clone())
                 .or_else(|| self.default_target_config.application_attributes.clone())
-                .map(build_attribute_string),
+                .map_or(vec![], |attributes| attributes.into_iter().collect()),
             activity_attributes: primary_config
                 .and_then(|a| a.activity_attributes.clone())
                 .or_else(|| self.default_target_config.activity_attributes.clone())
-                .map(build_attribute_string),
+                .map_or(vec![], |attributes| attributes.into_iter().collect()),
             opengles_version_major: primary_config
                 .and_then(|a| a.opengles_version_major)
                 .or_else(|| self.default_target_config.opengles_version_major)
@@ -144,24 +615,666 @@ impl AndroidConfig {
                 .into_iter()
                 .map(AndroidPermission::from)
                 .collect(),
-        })
+            application_process: primary_config
+                .and_then(|a| a.application_process.clone())
+                .or_else(|| self.default_target_config.application_process.clone()),
+            activity_process: primary_config
+                .and_then(|a| a.activity_process.clone())
+                .or_else(|| self.default_target_config.activity_process.clone()),
+            services: primary_config
+                .and_then(|a| a.service.clone())
+                .or_else(|| self.default_target_config.service.clone())
+                .unwrap_or_else(Vec::new)
+                .into_iter()
+                .map(AndroidComponent::from)
+                .collect(),
+            receivers: primary_config
+                .and_then(|a| a.receiver.clone())
+                .or_else(|| self.default_target_config.receiver.clone())
+                .unwrap_or_else(Vec::new)
+                .into_iter()
+                .map(AndroidComponent::from)
+                .collect(),
+            config_changes: validate_values(
+                "config_changes",
+                &primary_config
+                    .and_then(|a| a.config_changes.clone())
+                    .or_else(|| self.default_target_config.config_changes.clone())
+                    .unwrap_or_else(|| self.default_config_changes()),
+                CONFIG_CHANGES,
+            )?,
+            soft_input_mode: primary_config
+                .and_then(|a| a.soft_input_mode.clone())
+                .or_else(|| self.default_target_config.soft_input_mode.clone())
+                .map(|mode| {
+                    let modes = mode.split('|').map(str::to_owned).collect::<Vec<_>>();
+                    validate_values("soft_input_mode", &modes, SOFT_INPUT_MODES)
+                })
+                .transpose()?,
+            main_activity_exported: primary_config
+                .and_then(|a| a.activity.as_ref())
+                .or_else(|| self.default_target_config.activity.as_ref())
+                .and_then(|activity| activity.exported),
+            intent_filters: primary_config
+                .and_then(|a| a.activity.as_ref())
+                .or_else(|| self.default_target_config.activity.as_ref())
+                .and_then(|activity| activity.intent_filter.clone())
+                .unwrap_or_default()
+                .into_iter()
+                .map(AndroidIntentFilter::from)
+                .collect(),
+            activities: primary_config
+                .and_then(|a| a.activities.clone())
+                .or_else(|| self.default_target_config.activities.clone())
+                .unwrap_or_else(Vec::new)
+                .into_iter()
+                .map(AndroidActivity::from)
+                .collect(),
+        };
+
+        target_config.library_name = target_config
+            .package_name
+            .split('.')
+            .last()
+            .unwrap()
+            .to_owned();
+
+        // Conditional blocks are applied in the lexical order of their conditions,
+        // so later blocks win when several of them set the same key
+        for (condition, conditional_config) in &self.conditional_configs {
+            if !self
+                .cargo_features
+                .contains(&parse_when_condition(condition)?)
+            {
+                continue;
+            }
+
+            if let Some(label) = &conditional_config.label {
+                target_config.package_label = label.clone();
+            }
+            if let Some(suffix) = &conditional_config.package_name_suffix {
+                target_config.package_name.push_str(suffix);
+            }
+            if let Some(assets) = &conditional_config.assets {
+                target_config.assets_path = Some(self.manifest_path.parent().unwrap().join(assets));
+            }
+            if let Some(res) = &conditional_config.res {
+                target_config.res_paths = res.paths(self.manifest_path.parent().unwrap());
+            }
+            target_config.permissions.extend(
+                conditional_config
+                    .permission
+                    .iter()
+                    .flatten()
+                    .cloned()
+                    .map(AndroidPermission::from),
+            );
+        }
+
+        if let Some(flavor) = self.selected_flavor() {
+            if let Some(label) = &flavor.label {
+                target_config.package_label = label.clone();
+            }
+            if let Some(suffix) = &flavor.package_name_suffix {
+                target_config.package_name.push_str(suffix);
+            }
+            target_config.assets_subset = flavor.assets.clone().unwrap_or_default();
+        }
+
+        if let Some(version_name) = &self.overrides.version_name {
+            target_config.version_name = version_name.value.clone();
+        }
+        if let Some(version_code) = &self.overrides.version_code {
+            target_config.version_code = version_code.value;
+        }
+        if let Some(label) = &self.overrides.label {
+            target_config.package_label = label.value.clone();
+        }
+        if let Some(profileable) = &self.overrides.profileable {
+            target_config.profileable = profileable.value;
+        }
+        // Also after an overridden version name, which names the release rather than the flavor
+        if let Some(suffix) = self
+            .selected_flavor()
+            .and_then(|flavor| flavor.version_name_suffix.as_ref())
+        {
+            target_config.version_name.push_str(suffix);
+        }
+        // Such APKs don't work without `cargo quad-apk install`, they must not pass for shippable
+        if target_config.debug_assets_external {
+            target_config.version_name.push_str("-extassets");
+        }
+
+        Ok(target_config)
+    }
+
+    /// Configuration changes handled by the main activity when `config_changes` is not set
+    fn default_config_changes(&self) -> Vec<String> {
+        let mut config_changes = vec!["orientation", "keyboardHidden", "screenSize"];
+        // Foldables and dark mode switches restart the activity otherwise
+        if self.target_sdk_version >= 24 {
+            config_changes.extend(&["uiMode", "density"]);
+        }
+        config_changes.into_iter().map(str::to_owned).collect()
+    }
+}
+
+/// Values allowed in `android:configChanges`
+const CONFIG_CHANGES: &[&str] = &[
+    "mcc",
+    "mnc",
+    "locale",
+    "touchscreen",
+    "keyboard",
+    "keyboardHidden",
+    "navigation",
+    "screenLayout",
+    "fontScale",
+    "uiMode",
+    "orientation",
+    "density",
+    "screenSize",
+    "smallestScreenSize",
+    "layoutDirection",
+    "colorMode",
+    "grammaticalGender",
+    "fontWeightAdjustment",
+];
+
+/// Values allowed in `android:windowSoftInputMode`
+const SOFT_INPUT_MODES: &[&str] = &[
+    "stateUnspecified",
+    "stateUnchanged",
+    "stateHidden",
+    "stateAlwaysHidden",
+    "stateVisible",
+    "stateAlwaysVisible",
+    "adjustUnspecified",
+    "adjustResize",
+    "adjustPan",
+    "adjustNothing",
+];
+
+/// Values allowed in `android:screenOrientation`
+const SCREEN_ORIENTATIONS: &[&str] = &[
+    "unspecified",
+    "behind",
+    "landscape",
+    "portrait",
+    "reverseLandscape",
+    "reversePortrait",
+    "sensorLandscape",
+    "sensorPortrait",
+    "userLandscape",
+    "userPortrait",
+    "sensor",
+    "fullSensor",
+    "nosensor",
+    "user",
+    "fullUser",
+    "locked",
+];
+
+/// Values allowed in `android:launchMode`
+const LAUNCH_MODES: &[&str] = &[
+    "standard",
+    "singleTop",
+    "singleTask",
+    "singleInstance",
+    "singleInstancePerTask",
+];
+
+/// Checks that all the values of a key are allowed and returns them joined with `|`
+fn validate_values(key: &str, values: &[String], allowed: &[&str]) -> CargoResult<String> {
+    if let Some(value) = values.iter().find(|v| !allowed.contains(&v.as_str())) {
+        return Err(format_err!(
+            "Invalid value `{}` for `{}`, allowed values are: {}",
+            value,
+            key,
+            allowed.join(", ")
+        ));
+    }
+    Ok(values.join("|"))
+}
+
+/// Whether `tag` has the shape of a BCP-47 language tag: a language, then optionally a script,
+/// a region and variants, like `en`, `zh-Hans-CN` or `de-CH-1996`
+fn is_language_tag(tag: &str) -> bool {
+    let is_alpha = |s: &str, lengths: &[usize]| {
+        lengths.contains(&s.len()) && s.chars().all(|c| c.is_ascii_alphabetic())
+    };
+    let mut subtags = tag.split('-').peekable();
+    if !subtags
+        .next()
+        .map_or(false, |language| is_alpha(language, &[2, 3, 5, 6, 7, 8]))
+    {
+        return false;
     }
+    subtags.next_if(|script| is_alpha(script, &[4]));
+    subtags.next_if(|region| {
+        is_alpha(region, &[2]) || (region.len() == 3 && region.chars().all(|c| c.is_ascii_digit()))
+    });
+    subtags.all(|variant| {
+        let alphanumeric = variant.chars().all(|c| c.is_ascii_alphanumeric());
+        alphanumeric
+            && ((5..=8).contains(&variant.len())
+                || (variant.len() == 4 && variant.starts_with(|c: char| c.is_ascii_digit())))
+    })
+}
+
+#[test]
+fn aapt_args_of_profile() {
+    let target = (TargetKind::Bin, "app".to_owned());
+    let metadata = r#"
+        aapt_args = ["--auto-add-overlay"]
+        debug_aapt_args = ["--rename-instrumentation-target-package", "rust.app test"]
+        release_aapt_args = ["--max-res-version", "29"]
+    "#;
+
+    let target_config = from_metadata(metadata).resolve(target.clone()).unwrap();
+    assert!(target_config.debuggable);
+    assert_eq!(
+        target_config.aapt_args,
+        vec![
+            "--auto-add-overlay",
+            "--rename-instrumentation-target-package",
+            "rust.app test"
+        ]
+    );
+
+    let mut config = from_metadata(metadata);
+    config.release = true;
+    let target_config = config.resolve(target.clone()).unwrap();
+    assert!(!target_config.debuggable);
+    assert_eq!(
+        target_config.aapt_args,
+        vec!["--auto-add-overlay", "--max-res-version", "29"]
+    );
+
+    // An explicit `android:debuggable` wins over the profile
+    let config = from_metadata("[application_attributes]\n\"android:debuggable\" = \"false\"");
+    assert!(!config.resolve(target.clone()).unwrap().debuggable);
+    let mut config = from_metadata("[application_attributes]\n\"android:debuggable\" = \"true\"");
+    config.release = true;
+    assert!(config.resolve(target).unwrap().debuggable);
+}
+
+#[test]
+fn embed_build_env() {
+    let target = (TargetKind::Bin, "app".to_owned());
+    let resolve = |metadata: &str, release: bool| {
+        let mut config = from_metadata(metadata);
+        config.release = release;
+        config
+            .resolve(target.clone())
+            .map(|config| config.embed_build_env)
+    };
+
+    // Off by default, and only for debug builds unless asked for release ones too
+    assert!(!resolve("", false).unwrap());
+    assert!(resolve("embed_build_env = true", false).unwrap());
+    assert!(!resolve("embed_build_env = true", true).unwrap());
+    assert!(resolve(r#"embed_build_env = "always""#, true).unwrap());
+    assert_eq!(
+        resolve(r#"embed_build_env = "release""#, false)
+            .unwrap_err()
+            .to_string(),
+        "Invalid embed_build_env `release`, expected true, false or \"always\""
+    );
+}
+
+#[test]
+fn debug_assets_external() {
+    let target = (TargetKind::Bin, "app".to_owned());
+    let metadata = r#"
+        version_name = "1.2.0"
+        debug_assets_external = true
+    "#;
+
+    let target_config = from_metadata(metadata).resolve(target.clone()).unwrap();
+    assert!(target_config.debug_assets_external);
+    assert_eq!(target_config.version_name, "1.2.0-extassets");
+
+    // Release builds always embed the assets
+    let mut config = from_metadata(metadata);
+    config.release = true;
+    let target_config = config.resolve(target).unwrap();
+    assert!(!target_config.debug_assets_external);
+    assert_eq!(target_config.version_name, "1.2.0");
+}
+
+#[test]
+fn supported_locales() {
+    for tag in &[
+        "en",
+        "de",
+        "zh-CN",
+        "zh-Hans-CN",
+        "es-419",
+        "de-CH-1996",
+        "fil",
+    ] {
+        assert!(is_language_tag(tag), "{}", tag);
+    }
+    for tag in &[
+        "", "e", "en_US", "zh-rCN", "en-", "de-CH-96", "419", "en-US-x",
+    ] {
+        assert!(!is_language_tag(tag), "{}", tag);
+    }
+
+    let target = (TargetKind::Bin, "app".to_owned());
+    let target_config = from_metadata(r#"supported_locales = ["en", "de", "zh-CN"]"#)
+        .resolve(target.clone())
+        .unwrap();
+    assert_eq!(target_config.supported_locales, vec!["en", "de", "zh-CN"]);
+
+    let err = from_metadata(r#"supported_locales = ["en", "en_US"]"#)
+        .resolve(target)
+        .err()
+        .unwrap();
+    assert_eq!(
+        err.to_string(),
+        "Invalid locale `en_US` in `supported_locales`, expected a BCP-47 language tag like \
+         `en`, `de` or `zh-CN`"
+    );
+}
+
+#[test]
+fn config_changes_and_soft_input_mode() {
+    let target = (TargetKind::Bin, "app".to_owned());
+
+    let target_config = from_metadata("target_sdk_version = 23")
+        .resolve(target.clone())
+        .unwrap();
+    assert_eq!(
+        target_config.config_changes,
+        "orientation|keyboardHidden|screenSize"
+    );
+    assert_eq!(target_config.soft_input_mode, None);
+
+    let target_config = from_metadata("target_sdk_version = 24")
+        .resolve(target.clone())
+        .unwrap();
+    assert_eq!(
+        target_config.config_changes,
+        "orientation|keyboardHidden|screenSize|uiMode|density"
+    );
+
+    let target_config = from_metadata(
+        r#"
+        config_changes = ["orientation", "screenSize"]
+        soft_input_mode = "stateHidden|adjustResize"
+        "#,
+    )
+    .resolve(target.clone())
+    .unwrap();
+    assert_eq!(target_config.config_changes, "orientation|screenSize");
+    assert_eq!(
+        target_config.soft_input_mode.as_deref(),
+        Some("stateHidden|adjustResize")
+    );
+
+    let err = from_metadata(r#"config_changes = ["rotation"]"#)
+        .resolve(target.clone())
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(err.contains("`rotation`"));
+    assert!(err.contains("keyboardHidden"));
+
+    let err = from_metadata(r#"soft_input_mode = "resize""#)
+        .resolve(target)
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(err.contains("adjustResize"));
+}
+
+#[test]
+fn modelled_attributes() {
+    let target = (TargetKind::Bin, "app".to_owned());
+
+    let target_config = from_metadata("").resolve(target.clone()).unwrap();
+    assert!(target_config.debuggable);
+    assert_eq!(target_config.debuggable_attribute, None);
+    assert_eq!(target_config.orientation, None);
+
+    let mut config = from_metadata(
+        r#"
+        orientation = "sensorLandscape"
+        launch_mode = "singleTop"
+        debuggable = true
+        allow_backup = false
+
+        [[bin]]
+        name = "app"
+        launch_mode = "singleInstance"
+        "#,
+    );
+    config.release = true;
+    let target_config = config.resolve(target.clone()).unwrap();
+    assert!(target_config.debuggable);
+    assert_eq!(target_config.debuggable_attribute, Some(true));
+    assert_eq!(target_config.allow_backup, Some(false));
+    assert_eq!(
+        target_config.orientation.as_deref(),
+        Some("sensorLandscape")
+    );
+    assert_eq!(target_config.launch_mode.as_deref(), Some("singleInstance"));
+
+    // `debuggable` wins over the attribute
+    let config = from_metadata(
+        "debuggable = false\n[application_attributes]\n\"android:debuggable\" = \"true\"",
+    );
+    assert!(!config.resolve(target.clone()).unwrap().debuggable);
+
+    let err = from_metadata(r#"orientation = "sideways""#)
+        .resolve(target.clone())
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(err.starts_with("Invalid value `sideways` for `orientation`"));
+    assert!(err.contains("reverseLandscape"));
+    let err = from_metadata(r#"launch_mode = "singletask""#)
+        .resolve(target)
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(err.starts_with("Invalid value `singletask` for `launch_mode`"));
+
+    // Keys which are not modelled
+    let err = toml::from_str::<TomlAndroid>("screen_orientation = \"landscape\"")
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("unknown field `screen_orientation`"));
 }
 
-/// Build targets supported by NDK
-#[derive(Debug, Copy, Clone, Deserialize)]
+/// Parses the condition of a `[package.metadata.android.when.'feature = "name"']` block and
+/// returns the name of the cargo feature it depends on.
+fn parse_when_condition(condition: &str) -> CargoResult<String> {
+    condition
+        .trim()
+        .strip_prefix("feature")
+        .map(str::trim_start)
+        .and_then(|c| c.strip_prefix('='))
+        .map(str::trim)
+        .and_then(|c| c.strip_prefix('"'))
+        .and_then(|c| c.strip_suffix('"'))
+        .filter(|feature| !feature.is_empty() && !feature.contains('"'))
+        .map(str::to_owned)
+        .ok_or_else(|| {
+            format_err!(
+                "Invalid condition `{}` in `package.metadata.android.when`, expected `feature = \"name\"`",
+                condition
+            )
+        })
+}
+
+#[test]
+fn conditional_config_by_feature() {
+    let metadata = r#"
+        label = "Lite"
+        package_name = "com.example.app"
+        res = "res"
+
+        [[permission]]
+        name = "android.permission.VIBRATE"
+
+        [when.'feature = "full"']
+        label = "Full"
+        package_name_suffix = ".full"
+        assets = "assets/full"
+        res = ["res", "res-full"]
+
+        [[when.'feature = "full"'.permission]]
+        name = "android.permission.INTERNET"
+
+        [when.'feature = "tracing"']
+        label = "Tracing"
+    "#;
+    let target = (TargetKind::Bin, "app".to_owned());
+
+    let config = from_metadata(metadata);
+    let target_config = config.resolve(target.clone()).unwrap();
+    assert_eq!(target_config.package_label, "Lite");
+    assert_eq!(target_config.package_name, "com.example.app");
+    assert_eq!(target_config.assets_path, None);
+    assert_eq!(target_config.res_paths, vec![PathBuf::from("/app/res")]);
+    assert_eq!(target_config.permissions.len(), 1);
+
+    let mut config = from_metadata(metadata);
+    config.cargo_features.insert("full".to_owned());
+    let target_config = config.resolve(target.clone()).unwrap();
+    assert_eq!(target_config.package_label, "Full");
+    assert_eq!(target_config.package_name, "com.example.app.full");
+    assert_eq!(
+        target_config.assets_path,
+        Some(PathBuf::from("/app/assets/full"))
+    );
+    assert_eq!(
+        target_config.res_paths,
+        vec![PathBuf::from("/app/res"), PathBuf::from("/app/res-full")]
+    );
+    assert_eq!(
+        target_config
+            .permissions
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect::<Vec<_>>(),
+        vec!["android.permission.VIBRATE", "android.permission.INTERNET"]
+    );
+
+    config.cargo_features.insert("tracing".to_owned());
+    let target_config = config.resolve(target).unwrap();
+    assert_eq!(target_config.package_label, "Tracing");
+}
+
+#[test]
+fn flavor_config() {
+    let metadata = r#"
+        label = "Game"
+        package_name = "com.example.game"
+        version_name = "1.2.0"
+
+        [flavors.demo]
+        label = "Game Demo"
+        package_name_suffix = ".demo"
+        assets = ["levels/1", "intro.ogg"]
+        version_name_suffix = "-demo"
+
+        [flavors.full]
+        features = ["full"]
+    "#;
+    let target = (TargetKind::Bin, "app".to_owned());
+
+    let config = from_metadata(metadata);
+    assert_eq!(config.flavor_names(), vec!["demo", "full"]);
+    let target_config = config.resolve(target.clone()).unwrap();
+    assert_eq!(target_config.package_name, "com.example.game");
+    assert!(target_config.assets_subset.is_empty());
+
+    let mut demo = config.with_flavor("demo").unwrap();
+    let target_config = demo.resolve(target.clone()).unwrap();
+    assert_eq!(target_config.package_label, "Game Demo");
+    assert_eq!(target_config.package_name, "com.example.game.demo");
+    assert_eq!(target_config.library_name, "game");
+    assert_eq!(target_config.version_name, "1.2.0-demo");
+    assert_eq!(target_config.assets_subset, vec!["levels/1", "intro.ogg"]);
+
+    // The suffix names the flavor of an overridden version name too
+    demo.overrides.version_name = Some(Override {
+        value: "1.3.0-rc.1".to_owned(),
+        source: "cli".to_owned(),
+    });
+    let target_config = demo.resolve(target.clone()).unwrap();
+    assert_eq!(target_config.version_name, "1.3.0-rc.1-demo");
+
+    // The features of the flavor are added to those of the command line
+    let full = config.with_flavor("full").unwrap();
+    let cli_features =
+        CliFeatures::from_command_line(&["tracing".to_owned()], false, false).unwrap();
+    let features = full.flavor_features(&cli_features).unwrap();
+    assert_eq!(
+        features
+            .features
+            .iter()
+            .map(|feature| feature.to_string())
+            .collect::<Vec<_>>(),
+        vec!["full", "tracing"]
+    );
+    assert!(!features.uses_default_features);
+    assert_eq!(demo.flavor_features(&cli_features).unwrap(), cli_features);
+
+    assert_eq!(
+        config.with_flavor("beta").err().unwrap().to_string(),
+        "Unknown flavor `beta`, expected one of: demo, full"
+    );
+    assert_eq!(
+        from_metadata("")
+            .with_flavor("beta")
+            .err()
+            .unwrap()
+            .to_string(),
+        "Unknown flavor `beta`, the package has no `[package.metadata.android.flavors]`"
+    );
+}
+
+#[test]
+fn invalid_when_condition() {
+    assert_eq!(parse_when_condition(r#"feature = "full""#).unwrap(), "full");
+    assert_eq!(parse_when_condition(r#"feature="full""#).unwrap(), "full");
+    assert!(parse_when_condition(r#"target_os = "android""#).is_err());
+    assert!(parse_when_condition(r#"feature = full"#).is_err());
+}
+
+#[test]
+fn features_fingerprint() {
+    let mut config = from_metadata("");
+    let default = config.features_fingerprint();
+    assert_eq!(default.len(), 16);
+
+    config.cargo_features.insert("full".to_owned());
+    let full = config.features_fingerprint();
+    assert_ne!(full, default);
+
+    config.cargo_features.remove("full");
+    assert_eq!(config.features_fingerprint(), default);
+
+    // The same features reached with the default features disabled still differ
+    config.no_default_features = true;
+    assert_ne!(config.features_fingerprint(), default);
+}
+
+/// Build targets supported by NDK, named in `build_targets` by their rust triple or their ABI
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum AndroidBuildTarget {
-    #[serde(rename(deserialize = "armv7-linux-androideabi"))]
     ArmV7a,
-    #[serde(rename(deserialize = "aarch64-linux-android"))]
     Arm64V8a,
-    #[serde(rename(deserialize = "i686-linux-android"))]
     X86,
-    #[serde(rename(deserialize = "x86_64-linux-android"))]
     X86_64,
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct AndroidFeature {
     pub name: String,
     pub required: bool,
@@ -193,13 +1306,253 @@ impl From<TomlPermission> for AndroidPermission {
     }
 }
 
+/// Keystore of `[package.metadata.android.signing]`. The passwords are never stored in the
+/// manifest, only the names of the environment variables holding them.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SigningConfig {
+    /// Relative to the package root once loaded
+    pub keystore: PathBuf,
+    pub key_alias: String,
+    pub store_password_env: String,
+    /// Defaults to the password of the keystore
+    pub key_password_env: Option<String>,
+}
+
+/// Where `cargo quad-apk publish` sends the APKs
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum PublishConfig {
+    /// Upload to an HTTP endpoint
+    Http {
+        url: String,
+        /// `POST` (default) sends a multipart form, `PUT` sends the APK as the body
+        method: Option<String>,
+        /// Environment variable holding a bearer token
+        token_env: Option<String>,
+        /// Name of the multipart form field, defaults to `file`
+        form_field: Option<String>,
+    },
+    /// Copy to a directory, relative to the package root
+    Copy { path: PathBuf },
+}
+
+/// Framework providing the entry point of the app, from the `framework` key
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Framework {
+    /// The glue of miniquad is injected into the library, and its MainActivity is compiled
+    #[default]
+    Miniquad,
+    /// `android.app.NativeActivity` loads the library, which exports `ANativeActivity_onCreate`
+    /// itself. The APK has no Java code.
+    None,
+}
+
+/// What the post-link strip step removes from the libraries, from the `strip` key or `--strip`
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Strip {
+    /// Symbol table and debug sections, the default of release builds
+    All,
+    /// Debug sections only, keeping the symbols of stack traces
+    Debuginfo,
+    /// Nothing, the default of debug builds
+    None,
+}
+
+impl Strip {
+    pub fn from_name(name: &str) -> CargoResult<Strip> {
+        match name {
+            "all" => Ok(Strip::All),
+            "debuginfo" => Ok(Strip::Debuginfo),
+            "none" => Ok(Strip::None),
+            _ => Err(format_err!(
+                "Invalid `strip` `{}`, expected \"all\", \"debuginfo\" or \"none\"",
+                name
+            )),
+        }
+    }
+
+    /// Returns the flag of llvm-strip, `None` when nothing is stripped
+    pub fn llvm_strip_flag(self) -> Option<&'static str> {
+        match self {
+            Strip::All => Some("--strip-all"),
+            Strip::Debuginfo => Some("--strip-debug"),
+            Strip::None => None,
+        }
+    }
+}
+
+/// Artifacts of desugar_jdk_libs used to desugar `java.time` and friends for old devices
+#[derive(Debug, Clone)]
+pub struct CoreLibraryDesugaring {
+    /// desugar_jdk_libs configuration JSON given to `d8 --desugared-lib`
+    pub config: PathBuf,
+    /// Runtime jars added to the dex inputs
+    pub jars: Vec<PathBuf>,
+}
+
+fn core_library_desugaring(
+    manifest_path: &Path,
+    android: &TomlAndroid,
+) -> CargoResult<Option<CoreLibraryDesugaring>> {
+    if !android.core_library_desugaring.unwrap_or(false) {
+        return Ok(None);
+    }
+    let package_root = manifest_path.parent().unwrap();
+    let config = android.desugar_lib_config.as_ref().ok_or_else(|| {
+        format_err!("`core_library_desugaring` requires the `desugar_lib_config` path to be set")
+    })?;
+    let jars = android.desugar_lib_jars.clone().unwrap_or_default();
+    if jars.is_empty() {
+        return Err(format_err!(
+            "`core_library_desugaring` requires the `desugar_lib_jars` paths to be set"
+        ));
+    }
+    Ok(Some(CoreLibraryDesugaring {
+        config: package_root.join(config),
+        jars: jars.iter().map(|jar| package_root.join(jar)).collect(),
+    }))
+}
+
+#[test]
+fn desugaring_requires_artifacts() {
+    let android = |metadata: &str| toml::from_str::<TomlAndroid>(metadata).unwrap();
+    let manifest_path = Path::new("/app/Cargo.toml");
+
+    assert!(core_library_desugaring(manifest_path, &android(""))
+        .unwrap()
+        .is_none());
+    assert!(
+        core_library_desugaring(manifest_path, &android("core_library_desugaring = true")).is_err()
+    );
+
+    let desugaring = core_library_desugaring(
+        manifest_path,
+        &android(
+            r#"
+            core_library_desugaring = true
+            desugar_lib_config = "desugar/desugar.json"
+            desugar_lib_jars = ["desugar/desugar_jdk_libs.jar"]
+            "#,
+        ),
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(desugaring.config, Path::new("/app/desugar/desugar.json"));
+    assert_eq!(
+        desugaring.jars,
+        vec![PathBuf::from("/app/desugar/desugar_jdk_libs.jar")]
+    );
+}
+
+/// A `<service>` or `<receiver>` entry of the manifest
+#[derive(Clone)]
+pub struct AndroidComponent {
+    pub name: String,
+    pub enabled: bool,
+    pub process: Option<String>,
+    /// `android:exported`, services default to unexported when targeting Android 12 or later
+    pub exported: Option<bool>,
+}
+
+impl From<TomlService> for AndroidComponent {
+    fn from(s: TomlService) -> Self {
+        AndroidComponent {
+            name: s.name,
+            enabled: s.enabled,
+            process: s.process,
+            exported: s.exported,
+        }
+    }
+}
+
+/// An additional `<activity>` of the manifest, next to the main activity
+#[derive(Clone)]
+pub struct AndroidActivity {
+    /// Java class of the activity, either fully qualified or starting with `.`
+    pub name: String,
+    pub exported: bool,
+    pub label: Option<String>,
+    pub theme: Option<String>,
+    pub launch_mode: Option<String>,
+    pub intent_filters: Vec<AndroidIntentFilter>,
+}
+
+impl From<TomlActivity> for AndroidActivity {
+    fn from(a: TomlActivity) -> Self {
+        AndroidActivity {
+            name: a.name,
+            exported: a.exported.unwrap_or(false),
+            label: a.label,
+            theme: a.theme,
+            launch_mode: a.launch_mode,
+            intent_filters: a
+                .intent_filter
+                .unwrap_or_default()
+                .into_iter()
+                .map(AndroidIntentFilter::from)
+                .collect(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AndroidIntentFilter {
+    pub actions: Vec<String>,
+    pub categories: Vec<String>,
+    pub data: Vec<AndroidIntentData>,
+    /// `android:autoVerify`, for the App Links verified against the `https` hosts
+    pub auto_verify: bool,
+}
+
+impl From<TomlIntentFilter> for AndroidIntentFilter {
+    fn from(f: TomlIntentFilter) -> Self {
+        AndroidIntentFilter {
+            actions: f.actions,
+            categories: f.categories.unwrap_or_default(),
+            data: f
+                .data
+                .unwrap_or_default()
+                .into_iter()
+                .map(|d| AndroidIntentData {
+                    scheme: d.scheme,
+                    host: d.host,
+                    path_prefix: d.path_prefix,
+                    mime_type: d.mime_type,
+                })
+                .collect(),
+            auto_verify: f.auto_verify.unwrap_or(false),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AndroidIntentData {
+    pub scheme: Option<String>,
+    pub host: Option<String>,
+    pub path_prefix: Option<String>,
+    pub mime_type: Option<String>,
+}
+
 /// Android build settings for a specific target
 pub struct AndroidTargetConfig {
+    /// Framework of the package, see `AndroidConfig::framework`
+    pub framework: Framework,
+
     /// Name that the package will have on the Android machine.
     /// This is the key that Android uses to identify your package, so it should be unique for
     /// for each application and should contain the vendor's name.
     pub package_name: String,
 
+    /// Name of the library the MainActivity loads, the last segment of the package name without
+    /// the suffixes of the `when` blocks and of the flavor
+    pub library_name: String,
+
+    /// Name of the class of the MainActivity, the one declared by `main_activity` when set
+    pub main_activity_class: String,
+
     /// Label for the package.
     pub package_label: String,
 
@@ -218,20 +1571,93 @@ pub struct AndroidTargetConfig {
     /// The assets can later be loaded with the runtime library.
     pub assets_path: Option<PathBuf>,
 
-    /// If `Some`, a path that contains the list of resources to ship as part of the package.
+    /// Directories that contain the resources to ship as part of the package, which override the
+    /// resources contributed by the dependencies.
     ///
     /// The resources can later be loaded with the runtime library.
-    /// This folder contains for example the launcher icon, the styles and resolution dependent images.
-    pub res_path: Option<PathBuf>,
+    /// These folders contain for example the launcher icon, the styles and resolution dependent images.
+    pub res_paths: Vec<PathBuf>,
+
+    /// If `Some`, the hand-written AndroidManifest.xml of `manifest_path`, which the generated
+    /// elements and attributes are merged into
+    pub manifest_template: Option<PathBuf>,
 
     /// Should this app be in fullscreen mode (hides the title bar)?
     pub fullscreen: bool,
 
-    /// Appends this string to the application attributes in the AndroidManifest.xml
-    pub application_attributes: Option<String>,
+    /// android:requestLegacyExternalStorage of the application, only rendered up to target SDK 29
+    pub request_legacy_external_storage: bool,
+
+    /// android:testOnly of the application, such APKs can only be installed with `adb install -t`
+    pub test_only: bool,
+
+    /// Whether invalid resource names and the warnings of aapt about them only warn, instead of
+    /// failing the build
+    pub allow_resource_warnings: bool,
+
+    /// android:targetSandboxVersion of the manifest
+    pub target_sandbox_version: Option<u32>,
+
+    /// Whether `<profileable android:shell="true"/>` is rendered, so that profilers can attach
+    /// to release builds from API 29
+    pub profileable: bool,
+
+    /// Whether `assets/.manifest.json` listing the packaged assets is generated
+    pub generate_asset_manifest: bool,
+
+    /// Globs of the files of `assets_path` left out of the APK, in addition to those of the
+    /// `.gitignore` and `.apkignore` files
+    pub assets_exclude: Vec<String>,
+
+    /// Files and directories of `assets_path` packaged by the flavor, every asset when empty
+    pub assets_subset: Vec<String>,
+
+    /// Lists of the expected assets, which the packaged assets are compared with
+    pub verify_assets: Vec<PathBuf>,
+
+    /// Whether `assets/.build-env.json` records how the APK was built. Only for debug builds,
+    /// unless set to "always".
+    pub embed_build_env: bool,
+
+    /// Whether the assets of debug builds are pushed to the external files directory of the
+    /// app instead of being packaged. Always false for release builds.
+    pub debug_assets_external: bool,
+
+    /// BCP-47 tags of the locales listed in `res/xml/locales_config.xml`
+    pub supported_locales: Vec<String>,
+
+    /// Whether the app is debuggable, from `debuggable` or `android:debuggable` in
+    /// `application_attributes`, and otherwise for debug builds only. aapt sets it with
+    /// `--debug-mode`.
+    pub debuggable: bool,
 
-    /// Appends this string to the activity attributes in the AndroidManifest.xml
-    pub activity_attributes: Option<String>,
+    /// android:debuggable of the application, when `debuggable` is set
+    pub debuggable_attribute: Option<bool>,
+
+    /// android:allowBackup of the application
+    pub allow_backup: Option<bool>,
+
+    /// android:hardwareAccelerated of the application
+    pub hardware_accelerated: Option<bool>,
+
+    /// android:screenOrientation of the main activity
+    pub orientation: Option<String>,
+
+    /// android:resizeableActivity of the main activity
+    pub resizable_activity: Option<bool>,
+
+    /// android:launchMode of the main activity
+    pub launch_mode: Option<String>,
+
+    /// Extra arguments of `aapt package`, those of `aapt_args` followed by the ones of
+    /// `debug_aapt_args` or `release_aapt_args`
+    pub aapt_args: Vec<String>,
+
+    /// Attributes added to the `<application>` element of the AndroidManifest.xml, by name
+    pub application_attributes: Vec<(String, String)>,
+
+    /// Attributes added to the `<activity>` element of the main activity, by name
+    pub activity_attributes: Vec<(String, String)>,
 
     /// The OpenGL ES major version in the AndroidManifest.xml
     pub opengles_version_major: u8,
@@ -244,12 +1670,241 @@ pub struct AndroidTargetConfig {
 
     /// uses-permission in AndroidManifest.xml
     pub permissions: Vec<AndroidPermission>,
+
+    /// android:process of the application
+    pub application_process: Option<String>,
+
+    /// android:process of the main activity
+    pub activity_process: Option<String>,
+
+    /// service in AndroidManifest.xml
+    pub services: Vec<AndroidComponent>,
+
+    /// receiver in AndroidManifest.xml
+    pub receivers: Vec<AndroidComponent>,
+
+    /// android:configChanges of the main activity, joined with `|`
+    pub config_changes: String,
+
+    /// android:windowSoftInputMode of the main activity
+    pub soft_input_mode: Option<String>,
+
+    /// Intent filters of the main activity, after the one of the launcher
+    pub intent_filters: Vec<AndroidIntentFilter>,
+
+    /// android:exported of the main activity, which otherwise is exported when targeting
+    /// Android 12 or later or when it has intent filters
+    pub main_activity_exported: Option<bool>,
+
+    /// Additional activities in AndroidManifest.xml
+    pub activities: Vec<AndroidActivity>,
 }
 
-pub fn load(
-    workspace: &Workspace,
+/// Keywords and literals of Java, which can't be segments of a Java package
+const JAVA_RESERVED_WORDS: &[&str] = &[
+    "_",
+    "abstract",
+    "assert",
+    "boolean",
+    "break",
+    "byte",
+    "case",
+    "catch",
+    "char",
+    "class",
+    "const",
+    "continue",
+    "default",
+    "do",
+    "double",
+    "else",
+    "enum",
+    "extends",
+    "false",
+    "final",
+    "finally",
+    "float",
+    "for",
+    "goto",
+    "if",
+    "implements",
+    "import",
+    "instanceof",
+    "int",
+    "interface",
+    "long",
+    "native",
+    "new",
+    "null",
+    "package",
+    "private",
+    "protected",
+    "public",
+    "return",
+    "short",
+    "static",
+    "strictfp",
+    "super",
+    "switch",
+    "synchronized",
+    "this",
+    "throw",
+    "throws",
+    "transient",
+    "true",
+    "try",
+    "void",
+    "volatile",
+    "while",
+];
+
+impl AndroidTargetConfig {
+    /// Returns the application id, the `package` of the manifest
+    pub fn application_id(&self) -> String {
+        self.package_name.replace("-", "_")
+    }
+
+    /// Returns the Java package of the MainActivity and the other generated classes. Segments of
+    /// the application id which are Java keywords get a `_` appended, so `com.example.native`
+    /// uses the Java package `com.example.native_`.
+    pub fn java_package(&self) -> String {
+        self.application_id()
+            .split('.')
+            .map(|segment| {
+                if JAVA_RESERVED_WORDS.contains(&segment) {
+                    format!("{}_", segment)
+                } else {
+                    segment.to_owned()
+                }
+            })
+            .join(".")
+    }
+
+    /// Returns the name of the main activity for the manifest. The MainActivity is relative to
+    /// the application id unless the Java package differs from it, apps without framework use
+    /// the NativeActivity of the platform.
+    pub fn main_activity_name(&self) -> String {
+        if self.framework == Framework::None {
+            return "android.app.NativeActivity".to_owned();
+        }
+        let java_package = self.java_package();
+        if java_package == self.application_id() {
+            format!(".{}", self.main_activity_class)
+        } else {
+            format!("{}.{}", java_package, self.main_activity_class)
+        }
+    }
+}
+
+#[test]
+fn java_package_of_keywords() {
+    let target_config = |package_name: &str| {
+        from_metadata(&format!("package_name = {:?}", package_name))
+            .resolve((TargetKind::Bin, "app".to_owned()))
+            .unwrap()
+    };
+
+    let config = target_config("com.example.my-app");
+    assert_eq!(config.application_id(), "com.example.my_app");
+    assert_eq!(config.java_package(), "com.example.my_app");
+    assert_eq!(config.main_activity_name(), ".MainActivity");
+
+    let config = target_config("org.foo.new.native");
+    assert_eq!(config.application_id(), "org.foo.new.native");
+    assert_eq!(config.java_package(), "org.foo.new_.native_");
+    assert_eq!(
+        config.main_activity_name(),
+        "org.foo.new_.native_.MainActivity"
+    );
+
+    // Only whole segments are escaped
+    assert_eq!(
+        target_config("com.newer.natives").java_package(),
+        "com.newer.natives"
+    );
+}
+
+/// Returns the name of the class declared by the Java source of `main_activity`
+fn main_activity_class(path: &Path) -> CargoResult<String> {
+    let java_src = fs::read_to_string(path).map_err(|err| {
+        format_err!(
+            "Unable to read `main_activity` `{}`: {}",
+            path.display(),
+            err
+        )
+    })?;
+    java_class_name(&java_src).ok_or_else(|| {
+        format_err!(
+            "`main_activity` `{}` doesn't declare a class",
+            path.display()
+        )
+    })
+}
+
+/// Returns the name of the first top-level class of a Java source, outside of comments and braces
+fn java_class_name(java_src: &str) -> Option<String> {
+    let mut code = String::new();
+    let mut rest = java_src;
+    while let Some(start) = rest.find('/') {
+        code.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = if rest.starts_with("//") {
+            rest.find('\n').unwrap_or(rest.len())
+        } else if rest.starts_with("/*") {
+            rest.find("*/").map_or(rest.len(), |end| end + 2)
+        } else {
+            1
+        };
+        code.push(' ');
+        rest = &rest[end..];
+    }
+    code.push_str(rest);
+
+    let mut depth = 0;
+    let mut words = vec![];
+    for part in code.split_inclusive(|c: char| c == '{' || c == '}') {
+        if depth == 0 {
+            words.extend(
+                part.split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$'))
+                    .filter(|word| !word.is_empty()),
+            );
+        }
+        if part.ends_with('{') {
+            depth += 1;
+        } else if part.ends_with('}') {
+            depth -= 1;
+        }
+    }
+    words
+        .windows(2)
+        .find(|pair| pair[0] == "class")
+        .map(|pair| pair[1].to_owned())
+}
+
+#[test]
+fn java_class_names() {
+    assert_eq!(
+        java_class_name(
+            "package TARGET_PACKAGE_NAME;\n\
+             // public class Commented {}\n\
+             /* class Blocked */\n\
+             import android.app.Activity;\n\
+             @SuppressWarnings(\"unused\")\n\
+             public final class GameActivity extends Activity {\n\
+             \x20   static class Inner {}\n\
+             }\n\
+             class Helper {}\n"
+        ),
+        Some("GameActivity".to_owned())
+    );
+    assert_eq!(java_class_name("interface Callback {}\n"), None);
+}
+
+/// Returns the package requested by the user, with its Android-specific metadata
+fn package_metadata<'a>(
+    workspace: &'a Workspace,
     flag_package: &Option<String>,
-) -> Result<AndroidConfig, CliError> {
+) -> CargoResult<(&'a Package, Option<TomlAndroid>)> {
     // Find out the package requested by the user.
     let package = {
         let packages = Vec::from_iter(flag_package.iter().cloned());
@@ -277,14 +1932,56 @@ pub fn load(
         let config: TomlConfig = toml::from_str(&content).map_err(anyhow::Error::from)?;
         config.package.metadata.and_then(|m| m.android)
     };
+    Ok((package, manifest_content))
+}
+
+/// Returns the compile and target SDK versions of the metadata, `android_version` and
+/// `target_sdk_version`
+fn sdk_versions(manifest_content: Option<&TomlAndroid>) -> (u32, u32) {
+    // Packages without any Android metadata get defaults for current devices
+    let android_version =
+        manifest_content
+            .and_then(|a| a.android_version)
+            .unwrap_or(if manifest_content.is_none() {
+                FIRST_RUN_TARGET_SDK_VERSION
+            } else {
+                31
+            });
+    let target_sdk_version = manifest_content
+        .and_then(|a| a.target_sdk_version)
+        .unwrap_or(android_version);
+    (android_version, target_sdk_version)
+}
+
+/// Returns the SDK platforms the package is compiled against and targets, without needing the
+/// SDK, for `bootstrap` to install them
+pub fn requested_platforms(
+    workspace: &Workspace,
+    flag_package: &Option<String>,
+) -> CargoResult<BTreeSet<u32>> {
+    let (_, manifest_content) = package_metadata(workspace, flag_package)?;
+    let (android_version, target_sdk_version) = sdk_versions(manifest_content.as_ref());
+    Ok([android_version, target_sdk_version]
+        .iter()
+        .copied()
+        .collect())
+}
+
+pub fn load(
+    workspace: &Workspace,
+    flag_package: &Option<String>,
+) -> Result<AndroidConfig, CliError> {
+    let (package, manifest_content) = package_metadata(workspace, flag_package)?;
 
     // Determine the NDK path
-    let ndk_path = env::var("NDK_HOME").map_err(|_| {
-        format_err!(
-            "Please set the path to the Android NDK with the \
+    let ndk_path = env::var("NDK_HOME")
+        .map_err(|_| {
+            format_err!(
+                "Please set the path to the Android NDK with the \
              $NDK_HOME environment variable."
-        )
-    })?;
+            )
+        })
+        .failure_kind(FailureKind::Environment)?;
 
     let sdk_path = {
         let mut sdk_path = env::var("ANDROID_SDK_HOME").ok();
@@ -293,78 +1990,100 @@ pub fn load(
             sdk_path = env::var("ANDROID_HOME").ok();
         }
 
-        sdk_path.ok_or_else(|| {
-            format_err!(
-                "Please set the path to the Android SDK with either the $ANDROID_SDK_HOME or \
+        sdk_path
+            .ok_or_else(|| {
+                format_err!(
+                    "Please set the path to the Android SDK with either the $ANDROID_SDK_HOME or \
                  the $ANDROID_HOME environment variable."
-            )
-        })?
+                )
+            })
+            .failure_kind(FailureKind::Environment)?
     };
 
-    // Find the highest build tools.
-    let build_tools_version = {
-        let dir = fs::read_dir(Path::new(&sdk_path).join("build-tools"))
-            .map_err(|_| format_err!("Android SDK has no build-tools directory"))?;
-
-        let mut versions = Vec::new();
-        for next in dir {
-            let next = next.unwrap();
-
-            let meta = next.metadata().unwrap();
-            if !meta.is_dir() {
-                if !meta.is_file() {
-                    // It seems, symlink is here, so we should follow it
-                    let meta = next.path().metadata().unwrap();
-
-                    if !meta.is_dir() {
+    // Find the highest build tools. They are only needed to package APKs, which is checked
+    // when a build needs them
+    let build_tools_version = fs::read_dir(Path::new(&sdk_path).join("build-tools"))
+        .ok()
+        .and_then(|dir| {
+            let mut versions = Vec::new();
+            for next in dir {
+                let next = next.unwrap();
+
+                let meta = next.metadata().unwrap();
+                if !meta.is_dir() {
+                    if !meta.is_file() {
+                        // It seems, symlink is here, so we should follow it
+                        let meta = next.path().metadata().unwrap();
+
+                        if !meta.is_dir() {
+                            continue;
+                        }
+                    } else {
                         continue;
                     }
-                } else {
+                }
+
+                let file_name = next.file_name().into_string().unwrap();
+                if !file_name.chars().next().unwrap().is_digit(10) {
                     continue;
                 }
-            }
 
-            let file_name = next.file_name().into_string().unwrap();
-            if !file_name.chars().next().unwrap().is_digit(10) {
-                continue;
+                versions.push(file_name);
             }
 
-            versions.push(file_name);
-        }
+            versions.sort_by(|a, b| b.cmp(&a));
+            versions.into_iter().next()
+        });
 
-        versions.sort_by(|a, b| b.cmp(&a));
-        versions
-            .into_iter()
-            .next()
-            .ok_or_else(|| format_err!("Unable to determine build tools version"))?
-    };
+    // Packages without any Android metadata get defaults for current devices, and are told so
+    let first_run = manifest_content.is_none();
 
     // Determine the Sdk versions (compile, target, min)
-    let android_version = manifest_content
-        .as_ref()
-        .and_then(|a| a.android_version)
-        .unwrap_or(31);
+    let (android_version, target_sdk_version) = sdk_versions(manifest_content.as_ref());
 
     // Check that the tool for the android platform is installed
-    let android_jar_path = Path::new(&sdk_path)
-        .join("platforms")
-        .join(format!("android-{}", android_version))
-        .join("android.jar");
-    if !android_jar_path.exists() {
-        Err(format_err!(
-            "'{}' does not exist",
-            android_jar_path.to_string_lossy()
-        ))?;
-    }
-
-    let target_sdk_version = manifest_content
+    let auto_platform = manifest_content
         .as_ref()
-        .and_then(|a| a.target_sdk_version)
-        .unwrap_or(android_version);
+        .and_then(|a| a.auto_platform)
+        .unwrap_or(first_run);
+    let (android_jar_path, android_version) = find_android_jar(
+        workspace,
+        Path::new(&sdk_path),
+        android_version,
+        target_sdk_version,
+        auto_platform,
+    )?;
+
     let min_sdk_version = manifest_content
         .as_ref()
         .and_then(|a| a.min_sdk_version)
-        .unwrap_or(18);
+        .unwrap_or(if first_run {
+            FIRST_RUN_MIN_SDK_VERSION
+        } else {
+            18
+        });
+
+    // Targets are named by their rust triple or their ABI, and built once however many times they
+    // are named
+    let build_targets = match manifest_content
+        .as_ref()
+        .and_then(|a| a.build_targets.as_ref())
+    {
+        Some(names) => {
+            let (build_targets, warnings) = AndroidBuildTarget::from_names(names)
+                .map_err(|err| format_err!("Invalid `build_targets`: {}", err))?;
+            for warning in warnings {
+                workspace.gctx().shell().warn(warning)?;
+            }
+            build_targets
+        }
+        None if first_run => FIRST_RUN_BUILD_TARGETS.to_vec(),
+        None => vec![
+            AndroidBuildTarget::ArmV7a,
+            AndroidBuildTarget::Arm64V8a,
+            AndroidBuildTarget::X86,
+        ],
+    };
 
     let default_target_config = manifest_content
         .as_ref()
@@ -376,23 +2095,17 @@ pub fn load(
         .and_then(|a| a.java_packages.clone())
         .unwrap_or_else(Default::default);
 
-    let mut target_configs = BTreeMap::new();
-    manifest_content
+    let target_configs = collect_target_configs(&manifest_content);
+
+    let conditional_configs = manifest_content
         .as_ref()
-        .and_then(|a| a.bin.as_ref())
-        .unwrap_or(&Vec::new())
-        .iter()
-        .for_each(|t| {
-            target_configs.insert((TargetKind::Bin, t.name.clone()), t.config.clone());
-        });
-    manifest_content
+        .and_then(|a| a.when.clone())
+        .unwrap_or_default();
+
+    let flavors = manifest_content
         .as_ref()
-        .and_then(|a| a.example.as_ref())
-        .unwrap_or(&Vec::new())
-        .iter()
-        .for_each(|t| {
-            target_configs.insert((TargetKind::ExampleBin, t.name.clone()), t.config.clone());
-        });
+        .and_then(|a| a.flavors.clone())
+        .unwrap_or_default();
 
     // For the moment some fields of the config are dummies.
     Ok(AndroidConfig {
@@ -402,31 +2115,441 @@ pub fn load(
         sdk_path: Path::new(&sdk_path).to_owned(),
         ndk_path: Path::new(&ndk_path).to_owned(),
         android_jar_path,
+        android_version,
         target_sdk_version,
         min_sdk_version,
         build_tools_version,
         release: false,
-        build_targets: manifest_content
+        device: None,
+        dev_ports: manifest_content
+            .as_ref()
+            .and_then(|a| a.dev_ports.clone())
+            .unwrap_or_default(),
+        publish: manifest_content.as_ref().and_then(|a| a.publish.clone()),
+        signing: manifest_content
             .as_ref()
-            .and_then(|a| a.build_targets.clone())
-            .unwrap_or_else(|| {
-                vec![
-                    AndroidBuildTarget::ArmV7a,
-                    AndroidBuildTarget::Arm64V8a,
-                    AndroidBuildTarget::X86,
-                ]
+            .and_then(|a| a.signing.clone())
+            .map(|signing| SigningConfig {
+                keystore: package.root().join(&signing.keystore),
+                ..signing
             }),
+        build_targets,
         default_target_config,
         target_configs,
         java_packages,
+        java_sources: manifest_content
+            .as_ref()
+            .and_then(|a| a.java_sources.clone())
+            .unwrap_or_default(),
+        comptime_jars: manifest_content
+            .as_ref()
+            .and_then(|a| a.comptime_jars.clone())
+            .unwrap_or_default(),
+        runtime_jars: manifest_content
+            .as_ref()
+            .and_then(|a| a.runtime_jars.clone())
+            .unwrap_or_default(),
+        jni_keep: manifest_content
+            .as_ref()
+            .and_then(|a| a.jni_keep.clone())
+            .unwrap_or_default(),
+        main_activity: manifest_content
+            .as_ref()
+            .and_then(|a| a.main_activity.as_ref())
+            .map(|path| package.root().join(path)),
+        desugaring: match &manifest_content {
+            Some(android) => core_library_desugaring(package.manifest_path(), android)?,
+            None => None,
+        },
+        framework: manifest_content
+            .as_ref()
+            .and_then(|a| a.framework)
+            .unwrap_or_default(),
+        split_apks: manifest_content
+            .as_ref()
+            .and_then(|a| a.split_apks)
+            .unwrap_or(false),
+        rust_toolchain: manifest_content
+            .as_ref()
+            .and_then(|a| a.rust_toolchain.clone()),
+        bundletool_path: manifest_content
+            .as_ref()
+            .and_then(|a| a.bundletool_path.as_ref())
+            .map(|path| package.root().join(path)),
+        page_alignment: page_alignment(manifest_content.as_ref().and_then(|a| a.page_alignment))?,
+        strip: manifest_content.as_ref().and_then(|a| a.strip),
+        strict_api_lint: manifest_content
+            .as_ref()
+            .and_then(|a| a.strict_api_lint)
+            .unwrap_or(false),
+        api_lint_allow: manifest_content
+            .as_ref()
+            .and_then(|a| a.api_lint_allow.clone())
+            .unwrap_or_default(),
+        allow_unknown_attributes: manifest_content
+            .as_ref()
+            .and_then(|a| a.allow_unknown_attributes)
+            .unwrap_or(false),
+        defaulted_keys: if first_run {
+            first_run_defaults(&package.name())
+        } else {
+            vec![]
+        },
+        cargo_features: BTreeSet::new(),
+        no_default_features: false,
+        build_targets_overridden: false,
+        conditional_configs,
+        flavors,
+        flavor: None,
+        overrides: ManifestOverrides::default(),
     })
 }
 
-fn build_attribute_string(input_map: BTreeMap<String, String>) -> String {
-    input_map
+fn collect_target_configs(
+    manifest_content: &Option<TomlAndroid>,
+) -> BTreeMap<(TargetKind, String), TomlAndroidTarget> {
+    let mut target_configs = BTreeMap::new();
+    manifest_content
+        .as_ref()
+        .and_then(|a| a.bin.as_ref())
+        .unwrap_or(&Vec::new())
+        .iter()
+        .for_each(|t| {
+            target_configs.insert((TargetKind::Bin, t.name.clone()), t.config.clone());
+        });
+    manifest_content
+        .as_ref()
+        .and_then(|a| a.example.as_ref())
+        .unwrap_or(&Vec::new())
+        .iter()
+        .for_each(|t| {
+            target_configs.insert((TargetKind::ExampleBin, t.name.clone()), t.config.clone());
+        });
+    target_configs
+}
+
+/// Builds a config for the package `app` from the contents of a `[package.metadata.android]`
+/// table, without looking at the environment or the SDK.
+#[cfg(test)]
+pub fn from_metadata(metadata: &str) -> AndroidConfig {
+    let manifest_content: Option<TomlAndroid> = Some(toml::from_str(metadata).unwrap());
+    let android = manifest_content.as_ref().unwrap();
+    let android_version = android.android_version.unwrap_or(31);
+
+    AndroidConfig {
+        cargo_package_name: "app".to_owned(),
+        cargo_package_version: "0.1.0".to_owned(),
+        manifest_path: PathBuf::from("/app/Cargo.toml"),
+        sdk_path: PathBuf::from("/sdk"),
+        ndk_path: PathBuf::from("/ndk"),
+        build_targets: android
+            .build_targets
+            .as_ref()
+            .map(|names| AndroidBuildTarget::from_names(names).unwrap().0)
+            .unwrap_or_else(|| vec![AndroidBuildTarget::Arm64V8a]),
+        android_jar_path: PathBuf::from(format!(
+            "/sdk/platforms/android-{}/android.jar",
+            android_version
+        )),
+        android_version,
+        target_sdk_version: android.target_sdk_version.unwrap_or(android_version),
+        min_sdk_version: android.min_sdk_version.unwrap_or(18),
+        build_tools_version: Some("31.0.0".to_owned()),
+        release: false,
+        device: None,
+        dev_ports: android.dev_ports.clone().unwrap_or_default(),
+        publish: android.publish.clone(),
+        signing: android.signing.clone().map(|signing| SigningConfig {
+            keystore: Path::new("/app").join(&signing.keystore),
+            ..signing
+        }),
+        default_target_config: android.default_target_config.clone(),
+        target_configs: collect_target_configs(&manifest_content),
+        java_packages: android.java_packages.clone().unwrap_or_default(),
+        java_sources: android.java_sources.clone().unwrap_or_default(),
+        comptime_jars: android.comptime_jars.clone().unwrap_or_default(),
+        runtime_jars: android.runtime_jars.clone().unwrap_or_default(),
+        jni_keep: android.jni_keep.clone().unwrap_or_default(),
+        main_activity: android
+            .main_activity
+            .as_ref()
+            .map(|path| PathBuf::from("/app").join(path)),
+        desugaring: core_library_desugaring(Path::new("/app/Cargo.toml"), android).unwrap(),
+        framework: android.framework.unwrap_or_default(),
+        split_apks: android.split_apks.unwrap_or(false),
+        rust_toolchain: android.rust_toolchain.clone(),
+        bundletool_path: android
+            .bundletool_path
+            .as_ref()
+            .map(|path| Path::new("/app").join(path)),
+        page_alignment: page_alignment(android.page_alignment).unwrap(),
+        strip: android.strip,
+        strict_api_lint: android.strict_api_lint.unwrap_or(false),
+        api_lint_allow: android.api_lint_allow.clone().unwrap_or_default(),
+        allow_unknown_attributes: android.allow_unknown_attributes.unwrap_or(false),
+        defaulted_keys: vec![],
+        cargo_features: BTreeSet::new(),
+        no_default_features: false,
+        build_targets_overridden: false,
+        conditional_configs: android.when.clone().unwrap_or_default(),
+        flavors: android.flavors.clone().unwrap_or_default(),
+        flavor: None,
+        overrides: ManifestOverrides::default(),
+    }
+}
+
+/// API levels and targets of packages without `[package.metadata.android]`, for the devices in use
+/// today rather than the compatibility the defaults of the individual keys keep
+const FIRST_RUN_TARGET_SDK_VERSION: u32 = 34;
+const FIRST_RUN_MIN_SDK_VERSION: u32 = 26;
+/// Returns the `page_alignment` of the config, the pages of 4 KiB of most devices by default
+fn page_alignment(page_alignment: Option<u32>) -> CargoResult<u32> {
+    match page_alignment.unwrap_or(4) {
+        page_alignment @ (4 | 16) => Ok(page_alignment),
+        page_alignment => Err(format_err!(
+            "Invalid `page_alignment` {}, expected the size in KiB of the pages of the devices, \
+             4 or 16",
+            page_alignment
+        )),
+    }
+}
+
+const FIRST_RUN_BUILD_TARGETS: &[AndroidBuildTarget] = &[AndroidBuildTarget::Arm64V8a];
+
+/// Returns a segment of the default package name for a cargo name. Hyphens become underscores
+/// and names starting with a digit get an `app_` prefix, as segments must start with a letter.
+fn package_name_segment(name: &str) -> String {
+    let segment = name.replace('-', "_");
+    if segment.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("app_{}", segment)
+    } else {
+        segment
+    }
+}
+
+/// Returns the keys of `[package.metadata.android]` which matter the most to a package which has
+/// none, with the values used in their place as TOML
+fn first_run_defaults(cargo_package_name: &str) -> Vec<(&'static str, String)> {
+    vec![
+        (
+            "package_name",
+            format!("\"rust.{}\"", package_name_segment(cargo_package_name)),
+        ),
+        ("label", format!("{:?}", cargo_package_name)),
+        ("version_code", "1".to_owned()),
+        (
+            "build_targets",
+            format!(
+                "[{}]",
+                FIRST_RUN_BUILD_TARGETS
+                    .iter()
+                    .map(|target| format!("\"{}\"", target.rust_triple()))
+                    .join(", ")
+            ),
+        ),
+        ("min_sdk_version", FIRST_RUN_MIN_SDK_VERSION.to_string()),
+        (
+            "target_sdk_version",
+            FIRST_RUN_TARGET_SDK_VERSION.to_string(),
+        ),
+    ]
+}
+
+/// Returns the notice about the defaults of a package without `[package.metadata.android]`, in
+/// the form of the table which would choose them
+pub fn defaults_notice(defaulted_keys: &[(&str, String)]) -> Option<String> {
+    if defaulted_keys.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "Cargo.toml has no `[package.metadata.android]`, building with these defaults:\n\n\
+         [package.metadata.android]\n{}\n\
+         Add the table to Cargo.toml to choose other values, the README lists every key.",
+        defaulted_keys
+            .iter()
+            .map(|(key, value)| format!("{} = {}\n", key, value))
+            .collect::<String>()
+    ))
+}
+
+#[test]
+fn first_run_notice() {
+    assert_eq!(package_name_segment("my-game"), "my_game");
+    assert_eq!(package_name_segment("3d-demo"), "app_3d_demo");
+    assert_eq!(package_name_segment("app"), "app");
+
+    let defaults = first_run_defaults("3d-demo");
+    assert_eq!(
+        defaults[0],
+        ("package_name", "\"rust.app_3d_demo\"".to_owned())
+    );
+    assert_eq!(defaults[1], ("label", "\"3d-demo\"".to_owned()));
+    assert_eq!(
+        defaults_notice(&defaults).unwrap(),
+        "Cargo.toml has no `[package.metadata.android]`, building with these defaults:\n\
+         \n\
+         [package.metadata.android]\n\
+         package_name = \"rust.app_3d_demo\"\n\
+         label = \"3d-demo\"\n\
+         version_code = 1\n\
+         build_targets = [\"aarch64-linux-android\"]\n\
+         min_sdk_version = 26\n\
+         target_sdk_version = 34\n\
+         \n\
+         Add the table to Cargo.toml to choose other values, the README lists every key."
+    );
+    assert_eq!(defaults_notice(&[]), None);
+
+    // The defaults are those the targets resolve to
+    let mut config = from_metadata("");
+    config.cargo_package_name = "3d-demo".to_owned();
+    let target_config = config
+        .resolve((TargetKind::Bin, "3d-demo".to_owned()))
+        .unwrap();
+    assert_eq!(target_config.package_name, "rust.app_3d_demo");
+    assert_eq!(target_config.package_label, "3d-demo");
+    assert_eq!(target_config.version_code, 1);
+    let target_config = config
+        .resolve((TargetKind::ExampleBin, "2-player".to_owned()))
+        .unwrap();
+    assert_eq!(
+        target_config.package_name,
+        "rust.app_3d_demo.example.app_2_player"
+    );
+}
+
+/// Returns the path to `android.jar` for the requested platform, with its API level. When the
+/// platform is not installed and `auto_platform` is set, the nearest installed platform at or
+/// above `target_sdk_version` is used instead.
+fn find_android_jar(
+    workspace: &Workspace,
+    sdk_path: &Path,
+    android_version: u32,
+    target_sdk_version: u32,
+    auto_platform: bool,
+) -> CargoResult<(PathBuf, u32)> {
+    let platforms_dir = sdk_path.join("platforms");
+    let android_jar_path = |version: u32| {
+        platforms_dir
+            .join(format!("android-{}", version))
+            .join("android.jar")
+    };
+
+    let requested_jar_path = android_jar_path(android_version);
+    if requested_jar_path.exists() {
+        return Ok((requested_jar_path, android_version));
+    }
+
+    let installed = installed_platforms(&platforms_dir);
+    if auto_platform {
+        if let Some(version) = select_platform(&installed, target_sdk_version) {
+            workspace.gctx().shell().warn(format!(
+                "Android platform `android-{}` is not installed, using `android-{}` instead",
+                android_version, version
+            ))?;
+            return Ok((android_jar_path(version), version));
+        }
+    }
+
+    let installed = if installed.is_empty() {
+        "none".to_owned()
+    } else {
+        installed
+            .iter()
+            .map(|version| format!("android-{}", version))
+            .join(", ")
+    };
+    Err(FailureKind::Environment.mark(format_err!(
+        "'{}' does not exist.\n\
+         Installed platforms in '{}': {}\n\
+         Install the missing platform with: sdkmanager \"platforms;android-{}\"",
+        requested_jar_path.to_string_lossy(),
+        platforms_dir.to_string_lossy(),
+        installed,
+        android_version
+    )))
+}
+
+/// Lists the API levels of the `platforms/android-*` directories containing an `android.jar`,
+/// in ascending order.
+fn installed_platforms(platforms_dir: &Path) -> Vec<u32> {
+    let mut versions = fs::read_dir(platforms_dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().join("android.jar").exists())
+        .filter_map(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_prefix("android-"))
+                .and_then(|version| version.parse().ok())
+        })
+        .collect::<Vec<u32>>();
+    versions.sort();
+    versions
+}
+
+/// Picks the lowest installed platform which is at least `min_version`.
+fn select_platform(installed: &[u32], min_version: u32) -> Option<u32> {
+    installed
         .iter()
-        .map(|(key, val)| format!("\n{}=\"{}\"", key, val))
-        .join("")
+        .copied()
+        .filter(|&version| version >= min_version)
+        .min()
+}
+
+#[test]
+fn select_platform_from_platforms_dir() {
+    let platforms_dir =
+        env::temp_dir().join(format!("cargo-quad-apk-platforms-{}", std::process::id()));
+    for (name, with_jar) in &[
+        ("android-29", true),
+        ("android-31", true),
+        ("android-33", false),
+        ("android-34", true),
+        ("android-TiramisuPrivacySandbox", true),
+    ] {
+        let dir = platforms_dir.join(name);
+        fs::create_dir_all(&dir).unwrap();
+        if *with_jar {
+            File::create(dir.join("android.jar")).unwrap();
+        }
+    }
+
+    let installed = installed_platforms(&platforms_dir);
+    fs::remove_dir_all(&platforms_dir).unwrap();
+
+    assert_eq!(installed, vec![29, 31, 34]);
+    assert_eq!(select_platform(&installed, 30), Some(31));
+    assert_eq!(select_platform(&installed, 31), Some(31));
+    assert_eq!(select_platform(&installed, 32), Some(34));
+    assert_eq!(select_platform(&installed, 35), None);
+}
+
+/// Returns the path to `adb` in the platform-tools of the SDK, if they are installed
+pub fn find_adb(sdk_path: &Path) -> Option<PathBuf> {
+    let adb = sdk_path
+        .join("platform-tools")
+        .join(format!("adb{}", env::consts::EXE_SUFFIX));
+    Some(adb).filter(|adb| adb.is_file())
+}
+
+#[test]
+fn find_adb_in_sdk() {
+    let sdk_path = env::temp_dir().join(format!("cargo-quad-apk-adb-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&sdk_path);
+
+    fs::create_dir_all(sdk_path.join("platform-tools")).unwrap();
+    assert_eq!(find_adb(&sdk_path), None);
+
+    let adb = sdk_path
+        .join("platform-tools")
+        .join(format!("adb{}", env::consts::EXE_SUFFIX));
+    fs::write(&adb, "").unwrap();
+    assert_eq!(find_adb(&sdk_path), Some(adb));
+
+    fs::remove_dir_all(&sdk_path).unwrap();
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -451,7 +2574,11 @@ struct TomlAndroid {
     android_version: Option<u32>,
     target_sdk_version: Option<u32>,
     min_sdk_version: Option<u32>,
-    build_targets: Option<Vec<AndroidBuildTarget>>,
+    build_targets: Option<Vec<String>>,
+    auto_platform: Option<bool>,
+    dev_ports: Option<Vec<u16>>,
+    publish: Option<PublishConfig>,
+    signing: Option<SigningConfig>,
 
     #[serde(flatten)]
     default_target_config: TomlAndroidTarget,
@@ -460,11 +2587,30 @@ struct TomlAndroid {
     example: Option<Vec<TomlAndroidSpecificTarget>>,
     java_packages: Option<Vec<String>>,
     java_crates: Option<Vec<String>>,
+    java_sources: Option<Vec<String>>,
+    comptime_jars: Option<Vec<String>>,
+    runtime_jars: Option<Vec<String>>,
+    jni_keep: Option<Vec<String>>,
+    main_activity: Option<String>,
+    core_library_desugaring: Option<bool>,
+    desugar_lib_config: Option<String>,
+    desugar_lib_jars: Option<Vec<String>>,
+    framework: Option<Framework>,
+    split_apks: Option<bool>,
+    rust_toolchain: Option<String>,
+    bundletool_path: Option<String>,
+    page_alignment: Option<u32>,
+    strip: Option<Strip>,
+    strict_api_lint: Option<bool>,
+    api_lint_allow: Option<Vec<String>>,
+    allow_unknown_attributes: Option<bool>,
+    when: Option<BTreeMap<String, TomlAndroidConditional>>,
+    flavors: Option<BTreeMap<String, TomlFlavor>>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
-struct TomlFeature {
+pub(crate) struct TomlFeature {
     name: String,
     required: Option<bool>,
     version: Option<String>,
@@ -482,6 +2628,71 @@ struct TomlPermission {
 struct TomlService {
     name: String,
     enabled: bool,
+    process: Option<String>,
+    exported: Option<bool>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlActivity {
+    name: String,
+    exported: Option<bool>,
+    label: Option<String>,
+    theme: Option<String>,
+    launch_mode: Option<String>,
+    intent_filter: Option<Vec<TomlIntentFilter>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlIntentFilter {
+    actions: Vec<String>,
+    categories: Option<Vec<String>>,
+    data: Option<Vec<TomlIntentData>>,
+    auto_verify: Option<bool>,
+}
+
+/// `[package.metadata.android.activity]`, the main activity
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlMainActivity {
+    exported: Option<bool>,
+    intent_filter: Option<Vec<TomlIntentFilter>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlIntentData {
+    scheme: Option<String>,
+    host: Option<String>,
+    path_prefix: Option<String>,
+    mime_type: Option<String>,
+}
+
+/// `res`, either a directory or a list of directories
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum TomlRes {
+    Path(String),
+    Paths(Vec<String>),
+}
+
+impl TomlRes {
+    /// Returns the directories, relative to `package_root`
+    fn paths(&self, package_root: &Path) -> Vec<PathBuf> {
+        match self {
+            TomlRes::Path(path) => vec![package_root.join(path)],
+            TomlRes::Paths(paths) => paths.iter().map(|path| package_root.join(path)).collect(),
+        }
+    }
+}
+
+/// `embed_build_env`, either a boolean or "always"
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum TomlEmbedBuildEnv {
+    Enabled(bool),
+    Mode(String),
 }
 
 /// Configuration specific to a single cargo target
@@ -494,6 +2705,28 @@ struct TomlAndroidSpecificTarget {
     config: TomlAndroidTarget,
 }
 
+/// Configuration merged into the target configuration when a cargo feature is enabled
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlAndroidConditional {
+    label: Option<String>,
+    package_name_suffix: Option<String>,
+    assets: Option<String>,
+    res: Option<TomlRes>,
+    permission: Option<Vec<TomlPermission>>,
+}
+
+/// Variant of the APKs built with `--flavor`
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlFlavor {
+    features: Option<Vec<String>>,
+    package_name_suffix: Option<String>,
+    label: Option<String>,
+    assets: Option<Vec<String>>,
+    version_name_suffix: Option<String>,
+}
+
 #[derive(Debug, Default, Clone, Deserialize)]
 struct TomlAndroidTarget {
     package_name: Option<String>,
@@ -502,13 +2735,90 @@ struct TomlAndroidTarget {
     version_name: Option<String>,
     icon: Option<String>,
     assets: Option<String>,
-    res: Option<String>,
+    res: Option<TomlRes>,
+    manifest_path: Option<String>,
     fullscreen: Option<bool>,
+    request_legacy_external_storage: Option<bool>,
+    test_only: Option<bool>,
+    allow_resource_warnings: Option<bool>,
+    target_sandbox_version: Option<u32>,
+    profileable: Option<bool>,
+    generate_asset_manifest: Option<bool>,
+    assets_exclude: Option<Vec<String>>,
+    verify_assets: Option<Vec<String>>,
+    embed_build_env: Option<TomlEmbedBuildEnv>,
+    debug_assets_external: Option<bool>,
+    supported_locales: Option<Vec<String>>,
+    aapt_args: Option<Vec<String>>,
+    debug_aapt_args: Option<Vec<String>>,
+    release_aapt_args: Option<Vec<String>>,
+    debuggable: Option<bool>,
+    allow_backup: Option<bool>,
+    hardware_accelerated: Option<bool>,
+    orientation: Option<String>,
+    resizable_activity: Option<bool>,
+    launch_mode: Option<String>,
     application_attributes: Option<BTreeMap<String, String>>,
     activity_attributes: Option<BTreeMap<String, String>>,
     opengles_version_major: Option<u8>,
     opengles_version_minor: Option<u8>,
     feature: Option<Vec<TomlFeature>>,
     permission: Option<Vec<TomlPermission>>,
+    application_process: Option<String>,
+    activity_process: Option<String>,
     service: Option<Vec<TomlService>>,
+    receiver: Option<Vec<TomlService>>,
+    config_changes: Option<Vec<String>>,
+    soft_input_mode: Option<String>,
+    activity: Option<TomlMainActivity>,
+    activities: Option<Vec<TomlActivity>>,
+}
+
+#[test]
+fn page_alignment_values() {
+    assert_eq!(page_alignment(None).unwrap(), 4);
+    assert_eq!(page_alignment(Some(16)).unwrap(), 16);
+    assert_eq!(
+        page_alignment(Some(64)).unwrap_err().to_string(),
+        "Invalid `page_alignment` 64, expected the size in KiB of the pages of the devices, 4 or 16"
+    );
+}
+
+#[test]
+fn strip_values() {
+    assert_eq!(from_metadata("").strip, None);
+    assert_eq!(
+        from_metadata(r#"strip = "debuginfo""#).strip,
+        Some(Strip::Debuginfo)
+    );
+    assert_eq!(Strip::from_name("all").unwrap(), Strip::All);
+    assert_eq!(
+        Strip::from_name("symbols").unwrap_err().to_string(),
+        "Invalid `strip` `symbols`, expected \"all\", \"debuginfo\" or \"none\""
+    );
+}
+
+#[test]
+fn build_targets_override_fingerprint() {
+    let metadata = r#"build_targets = ["armv7-linux-androideabi", "aarch64-linux-android"]"#;
+    let regular = from_metadata(metadata);
+    let mut emulator = from_metadata(metadata);
+    emulator.override_build_targets(vec![AndroidBuildTarget::X86_64]);
+    assert_eq!(emulator.build_targets, vec![AndroidBuildTarget::X86_64]);
+    assert_ne!(
+        emulator.features_fingerprint(),
+        regular.features_fingerprint()
+    );
+
+    // The next regular build loads the configuration again, and records the fingerprint the
+    // builds recorded before build targets could be overridden
+    let next = from_metadata(metadata);
+    assert_eq!(
+        next.build_targets,
+        vec![AndroidBuildTarget::ArmV7a, AndroidBuildTarget::Arm64V8a]
+    );
+    assert_eq!(next.features_fingerprint(), regular.features_fingerprint());
+    let mut empty = from_metadata(metadata);
+    empty.cargo_features.clear();
+    assert_eq!(empty.features_fingerprint(), "9d77660195f6dad2");
 }
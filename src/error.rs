@@ -0,0 +1,144 @@
+//! Categories of the failures of the commands, so that scripts running them can tell a
+//! compilation error from a misconfigured environment or a missing device.
+//!
+//! Errors are marked with their category where they happen, the innermost mark winning, and
+//! `main` turns the category into the exit code of the process. Marks are transparent: the
+//! error prints exactly as it would without one.
+
+use cargo::util::CargoResult;
+use serde_json::json;
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailureKind {
+    /// The code of the package doesn't compile
+    Compilation,
+    /// The SDK, NDK, JDK or one of their tools is missing or misconfigured
+    Environment,
+    /// adb failed to reach the device, or the device refused a command
+    Device,
+    /// The APK could not be signed
+    Signing,
+}
+
+impl FailureKind {
+    /// Exit code of the process, `None` keeping the one of cargo
+    pub fn exit_code(self) -> Option<i32> {
+        match self {
+            FailureKind::Compilation => None,
+            FailureKind::Environment => Some(3),
+            FailureKind::Device => Some(4),
+            FailureKind::Signing => Some(5),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FailureKind::Compilation => "compilation",
+            FailureKind::Environment => "environment",
+            FailureKind::Device => "device",
+            FailureKind::Signing => "signing",
+        }
+    }
+
+    /// Marks `error` as a failure of this kind, unless it already has a kind
+    pub fn mark(self, error: anyhow::Error) -> anyhow::Error {
+        if failure_kind(&error).is_some() {
+            error
+        } else {
+            anyhow::Error::new(Marked { kind: self, error })
+        }
+    }
+}
+
+/// Error marked with its kind, displayed as the error itself
+#[derive(Debug)]
+struct Marked {
+    kind: FailureKind,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for Marked {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Without the alternate flag, which would print the sources twice
+        write!(f, "{}", self.error)
+    }
+}
+
+impl Error for Marked {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.error.source()
+    }
+}
+
+pub trait ResultExt<T> {
+    /// Marks the error, if any, as a failure of `kind`
+    fn failure_kind(self, kind: FailureKind) -> CargoResult<T>;
+}
+
+impl<T> ResultExt<T> for CargoResult<T> {
+    fn failure_kind(self, kind: FailureKind) -> CargoResult<T> {
+        self.map_err(|error| kind.mark(error))
+    }
+}
+
+/// Returns the kind `error` was marked with, if any
+pub fn failure_kind(error: &anyhow::Error) -> Option<FailureKind> {
+    error.downcast_ref::<Marked>().map(|marked| marked.kind)
+}
+
+/// Removes the mark of `error`, which cargo would otherwise not see through when looking for its
+/// own error types
+pub fn unmark(error: anyhow::Error) -> anyhow::Error {
+    match error.downcast::<Marked>() {
+        Ok(marked) => marked.error,
+        Err(error) => error,
+    }
+}
+
+/// Returns the final record printed with `--message-format json`, in the style of the messages of
+/// cargo
+pub fn failure_record(error: &anyhow::Error, exit_code: i32) -> serde_json::Value {
+    json!({
+        "reason": "cargo-quad-apk-failure",
+        "failure_kind": failure_kind(error).map_or("other", FailureKind::name),
+        "exit_code": exit_code,
+        "message": format!("{:#}", error),
+    })
+}
+
+#[test]
+fn marked_errors() {
+    use anyhow::{format_err, Context};
+
+    let error = FailureKind::Device.mark(format_err!("error: no devices/emulators found"));
+    assert_eq!(failure_kind(&error), Some(FailureKind::Device));
+    assert_eq!(error.to_string(), "error: no devices/emulators found");
+
+    // The innermost mark wins, and marks are found through contexts
+    let error = FailureKind::Device.mark(FailureKind::Environment.mark(format_err!("no adb")));
+    assert_eq!(failure_kind(&error), Some(FailureKind::Environment));
+    let error = Err::<(), _>(error)
+        .context("Unable to install")
+        .unwrap_err();
+    assert_eq!(failure_kind(&error), Some(FailureKind::Environment));
+    assert_eq!(format!("{:#}", error), "Unable to install: no adb");
+
+    let error = FailureKind::Signing.mark(format_err!("bad keystore").context("apksigner failed"));
+    assert_eq!(format!("{:#}", error), "apksigner failed: bad keystore");
+    assert_eq!(
+        failure_record(&error, 5).to_string(),
+        r#"{"exit_code":5,"failure_kind":"signing","message":"apksigner failed: bad keystore","reason":"cargo-quad-apk-failure"}"#
+    );
+    assert_eq!(
+        format!("{:#}", unmark(error)),
+        "apksigner failed: bad keystore"
+    );
+
+    assert_eq!(failure_kind(&format_err!("other")), None);
+    assert_eq!(
+        failure_record(&format_err!("other"), 101)["failure_kind"],
+        "other"
+    );
+}
@@ -3,16 +3,18 @@
 use anyhow::format_err;
 use cargo::core::Workspace;
 use cargo::util::{
-    command_prelude::{opt, ArgMatchesExt, CommandExt},
-    GlobalContext,
+    command_prelude::{flag, multi_opt, opt, ArgMatchesExt, CommandExt},
+    CargoResult, GlobalContext,
 };
 use cargo_util::ProcessBuilder;
 use clap::{Arg, ArgAction, ArgMatches, Command};
 
 mod config;
+mod error;
 mod ops;
 
 fn main() {
+    ops::install_interrupt_handler();
     let mut cargo_gctx = GlobalContext::default().unwrap();
 
     let args = match cli().try_get_matches() {
@@ -57,10 +59,21 @@ fn main() {
         "build" => execute_build(&subcommand_args, &cargo_gctx),
         "install" => execute_install(&subcommand_args, &cargo_gctx),
         "run" => execute_run(&subcommand_args, &cargo_gctx),
+        "uninstall" => execute_uninstall(&subcommand_args, &cargo_gctx),
+        "restart" => execute_restart(&subcommand_args, &cargo_gctx),
+        "hot" => execute_hot(&subcommand_args, &cargo_gctx),
+        "devices" => execute_devices(&subcommand_args, &cargo_gctx),
+        "cache" => execute_cache(&subcommand_args, &cargo_gctx),
+        "clean" => execute_clean(&subcommand_args, &cargo_gctx),
         "logcat" => execute_logcat(&subcommand_args, &cargo_gctx),
+        "publish" => execute_publish(&subcommand_args, &cargo_gctx),
+        "diff" => execute_diff(&subcommand_args, &cargo_gctx),
+        "release-check" => execute_release_check(&subcommand_args, &cargo_gctx),
+        "compat" => execute_compat(&subcommand_args, &cargo_gctx),
+        "bootstrap" => execute_bootstrap(&subcommand_args, &cargo_gctx),
         _ => cargo::exit_with_error(
             format_err!(
-                "Expected `build`, `install`, `run`, or `logcat`. Got {}",
+                "Expected `build`, `install`, `run`, `uninstall`, `restart`, `hot`, `devices`, `cache`, `clean`, `logcat`, `publish`, `diff`, `release-check`, `compat` or `bootstrap`. Got {}",
                 command
             )
             .into(),
@@ -70,10 +83,41 @@ fn main() {
 
     match err {
         Ok(_) => (),
-        Err(err) => cargo::exit_with_error(err, &mut *cargo_gctx.shell()),
+        Err(err) => exit_with_failure(err, subcommand_args, &cargo_gctx),
     }
 }
 
+/// Exits with the code of the kind of the failure, printing a final JSON record with
+/// `--message-format json`
+fn exit_with_failure(
+    mut err: cargo::CliError,
+    options: &ArgMatches,
+    cargo_gctx: &GlobalContext,
+) -> ! {
+    if let Some(error) = err.error.take() {
+        if let Some(exit_code) = error::failure_kind(&error).and_then(error::FailureKind::exit_code)
+        {
+            err.exit_code = exit_code;
+        }
+        let json = options
+            .try_get_many::<String>("message-format")
+            .ok()
+            .flatten()
+            .map_or(false, |mut formats| {
+                formats.any(|format| format.split(',').any(|format| format.starts_with("json")))
+            });
+        if json {
+            drop(
+                cargo_gctx
+                    .shell()
+                    .print_json(&error::failure_record(&error, err.exit_code)),
+            );
+        }
+        err.error = Some(error::unmark(error));
+    }
+    cargo::exit_with_error(err, &mut *cargo_gctx.shell())
+}
+
 fn cli() -> Command {
     Command::new("cargo-apk")
         .arg(
@@ -97,6 +141,17 @@ fn cli() -> Command {
                 .value_name("WHEN")
                 .global(true),
         )
+        .arg(
+            opt(
+                "device",
+                "Serial of the device which adb commands act on, as listed by `adb devices`. \
+                 `install` accepts several to install to each of them",
+            )
+            .short('s')
+            .value_name("SERIAL")
+            .action(ArgAction::Append)
+            .global(true),
+        )
         .arg(
             Arg::new("frozen")
                 .long("frozen")
@@ -134,10 +189,47 @@ fn cli() -> Command {
                 .action(ArgAction::SetTrue)
                 .global(true),
         )
+        .arg(
+            Arg::new("strip")
+                .long("strip")
+                .value_name("MODE")
+                .value_parser(["all", "debuginfo", "none"])
+                .help("What is stripped from the libraries, instead of the `strip` key [default: all for release builds, none otherwise]")
+                .global(true),
+        )
         .arg(
             Arg::new("nostrip")
                 .long("nostrip")
-                .help("Skip \"striop\" build step, to keep debug symbols even in release builds.")
+                .help("Alias of `--strip none`, to keep debug symbols even in release builds.")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("strip")
+                .global(true),
+        )
+        .arg(
+            Arg::new("sequential-abis")
+                .long("sequential-abis")
+                .help("Build the libraries of one build target after the other, using less memory.")
+                .action(ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            Arg::new("regenerate-debug-key")
+                .long("regenerate-debug-key")
+                .help("Back up the debug keystore and generate a new one before signing.")
+                .action(ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            Arg::new("adb-retries")
+                .long("adb-retries")
+                .value_name("N")
+                .help("Retry adb transfers failing because of the connection N times [default: 3]")
+                .global(true),
+        )
+        .arg(
+            Arg::new("split-per-abi")
+                .long("split-per-abi")
+                .help("Also build one APK per ABI, with only the libraries of that ABI.")
                 .action(ArgAction::SetTrue)
                 .global(true),
         )
@@ -146,14 +238,41 @@ fn cli() -> Command {
             cli_build(),
             cli_install(),
             cli_run(),
+            cli_uninstall(),
+            cli_restart(),
+            cli_hot(),
+            cli_devices(),
+            cli_cache(),
+            cli_clean(),
             cli_logcat(),
+            cli_publish(),
+            cli_diff(),
+            cli_release_check(),
+            cli_compat(),
+            cli_bootstrap(),
         ])
 }
 
 fn cli_apk() -> Command {
     Command::new("quad-apk")
         .about("dummy subcommand to allow for calling cargo apk instead of cargo-apk")
-        .subcommands(vec![cli_build(), cli_install(), cli_run(), cli_logcat()])
+        .subcommands(vec![
+            cli_build(),
+            cli_install(),
+            cli_run(),
+            cli_uninstall(),
+            cli_restart(),
+            cli_hot(),
+            cli_devices(),
+            cli_cache(),
+            cli_clean(),
+            cli_logcat(),
+            cli_publish(),
+            cli_diff(),
+            cli_release_check(),
+            cli_compat(),
+            cli_bootstrap(),
+        ])
 }
 
 fn cli_build() -> Command {
@@ -180,9 +299,29 @@ fn cli_build() -> Command {
         )
         .arg_release("Build artifacts in release mode, with optimizations")
         .arg_features()
+        .args(override_args())
         .arg_target_triple("Build for the target triple")
         .arg_target_dir()
         .arg(opt("out-dir", "Copy final artifacts to this directory").value_name("PATH"))
+        .arg(no_apk_arg())
+        .arg(abi_arg())
+        .arg(
+            flag(
+                "bundle",
+                "Build Android App Bundles (.aab) for stores instead of APKs",
+            )
+            .conflicts_with("no-apk"),
+        )
+        .arg(multi_opt(
+            "flavor",
+            "NAME",
+            "Build the APKs of this flavor of `[package.metadata.android.flavors]`",
+        ))
+        .arg(flag("all-flavors", "Build the APKs of every flavor").conflicts_with("flavor"))
+        .arg(flag(
+            "prune-stale",
+            "Delete APKs of targets which no longer exist instead of moving them to `old/`",
+        ))
         .arg_profile("Build artifacts with the specified profile")
         .arg_manifest_path()
         .arg_message_format()
@@ -223,9 +362,8 @@ fn cli_install() -> Command {
             "list all installed packages and their versions",
         ))
         .arg_jobs()
-        .arg(opt("force", "Force overwriting existing crates or binaries").short('f'))
         .arg_features()
-        .arg(opt("debug", "Build in debug mode instead of release mode"))
+        .arg(flag("debug", "Build in debug mode instead of release mode"))
         .arg_targets_bins_examples(
             "Install only the specified binary",
             "Install all binaries",
@@ -233,6 +371,22 @@ fn cli_install() -> Command {
             "Install all examples",
         )
         .arg_target_triple("Build for the target triple")
+        .arg_package("Package to install")
+        .args(reverse_args())
+        .args(user_args())
+        .args(override_args())
+        .arg(flag(
+            "fastdeploy",
+            "Only transfer the changed parts of the APKs, when adb and the device support it",
+        ))
+        .arg(flag(
+            "force-reinstall",
+            "Uninstall the app, losing its data, when it is installed with another signing key",
+        ))
+        .args(compat_args())
+        .arg(all_splits_arg())
+        .arg(no_apk_arg())
+        .arg(abi_arg())
         .arg(opt("root", "Directory to install packages into").value_name("DIR"))
         .arg(opt("registry", "Registry to use").value_name("REGISTRY"))
         .after_help(
@@ -277,14 +431,83 @@ continuous integration systems.",
 fn cli_run() -> Command {
     Command::new("run")
         .alias("r")
-        .trailing_var_arg(true)
         .about("Run the main binary of the local package (src/main.rs)")
-        .arg(Arg::new("args").action(ArgAction::Append))
+        .arg(
+            Arg::new("args")
+                .value_name("ARGS")
+                .help("Arguments for the app, after `--`, passed as the `args` string array extra of the intent")
+                .action(ArgAction::Append)
+                .last(true),
+        )
         .arg_targets_bin_example(
             "Name of the bin target to run",
             "Name of the example target to run",
         )
         .arg_package("Package with the target to run")
+        .args(reverse_args())
+        .args(user_args())
+        .args(override_args())
+        .arg(flag(
+            "fastdeploy",
+            "Only transfer the changed parts of the APK, when adb and the device support it",
+        ))
+        .arg(flag(
+            "force-reinstall",
+            "Uninstall the app, losing its data, when it is installed with another signing key",
+        ))
+        .args(compat_args())
+        .arg(all_splits_arg())
+        .arg(
+            flag(
+                "emulator",
+                "Build for the emulator ABI of this host only, and run on an emulator, \
+                 starting one when none is running",
+            )
+            .conflicts_with_all(["device", "abi", "target"]),
+        )
+        .arg(
+            flag(
+                "all-abis",
+                "Build every ABI of `build_targets`, instead of only the ABI of the device",
+            )
+            .conflicts_with_all(["emulator", "abi", "target"]),
+        )
+        .arg(flag(
+            "examples-sequence",
+            "Build every example, then install, run and stop them one after the other",
+        ))
+        .arg(
+            opt(
+                "each-duration",
+                "Seconds each example runs with `--examples-sequence` [default: 10]",
+            )
+            .value_name("SECS"),
+        )
+        .arg(
+            opt(
+                "screenshots",
+                "Save a screenshot of each example of `--examples-sequence` into DIR",
+            )
+            .value_name("DIR"),
+        )
+        .arg(flag(
+            "fail-fast",
+            "Stop `--examples-sequence` at the first example which fails",
+        ))
+        .arg(flag(
+            "no-logcat",
+            "Return once the app is started, instead of following its log until it exits",
+        ))
+        .arg(
+            flag(
+                "symbolicate",
+                "Print the native crashes of the app again with ndk-stack, naming the functions, \
+                 files and lines of their frames",
+            )
+            .conflicts_with("no-logcat"),
+        )
+        .arg(no_apk_arg())
+        .arg(abi_arg())
         .arg_jobs()
         .arg_release("Build artifacts in release mode, with optimizations")
         .arg_features()
@@ -306,13 +529,399 @@ run. If you're passing arguments to both Cargo and the binary, the ones after
         )
 }
 
+/// `--no-apk`, shared by `build`, `install` and `run` which all build
+fn no_apk_arg() -> Arg {
+    flag(
+        "no-apk",
+        "Only build the shared libraries, without packaging them into APKs",
+    )
+}
+
+/// `--all-splits`, shared by `install` and `run`
+fn all_splits_arg() -> Arg {
+    flag(
+        "all-splits",
+        "Install every split APK, one after the other, instead of the one of the device's ABI",
+    )
+}
+
+/// Returns the device selected with `--device`, which only `install` accepts several times
+fn selected_device(options: &ArgMatches) -> CargoResult<Option<String>> {
+    let mut devices = options.get_many::<String>("device").unwrap_or_default();
+    let device = devices.next().cloned();
+    if devices.next().is_some() {
+        return Err(format_err!(
+            "Only `install` acts on several devices, select one with `--device`"
+        ));
+    }
+    Ok(device)
+}
+
+/// `--abi`, shared by `build`, `install` and `run` which all build
+fn abi_arg() -> Arg {
+    multi_opt(
+        "abi",
+        "ABI",
+        "Build for this ABI (or rust triple) instead of the `build_targets` of the metadata",
+    )
+}
+
+/// Returns the build targets given with `--abi` or `--target`, if any
+fn build_targets_override(
+    options: &ArgMatches,
+) -> CargoResult<Option<Vec<config::AndroidBuildTarget>>> {
+    let names = ["abi", "target"]
+        .iter()
+        .filter_map(|id| options.get_many::<String>(id))
+        .flatten()
+        .cloned()
+        .collect::<Vec<_>>();
+    if names.is_empty() {
+        return Ok(None);
+    }
+    // Naming a target twice on the command line builds it once, without a warning
+    let (build_targets, _) = config::AndroidBuildTarget::from_names(&names)
+        .map_err(|err| format_err!("Invalid `--abi`: {}", err))?;
+    Ok(Some(build_targets))
+}
+
+/// Arguments overriding manifest values, shared by `build`, `install` and `run`
+fn override_args() -> [Arg; 4] {
+    [
+        opt("version-name", "Override the version name of the manifest").value_name("NAME"),
+        opt("version-code", "Override the version code of the manifest").value_name("CODE"),
+        opt("label", "Override the application label of the manifest").value_name("LABEL"),
+        flag(
+            "profileable",
+            "Let profilers attach to the app through the shell, as with `profileable = true`",
+        ),
+    ]
+}
+
+fn manifest_overrides(options: &ArgMatches) -> CargoResult<config::ManifestOverrides> {
+    let mut overrides = config::ManifestOverrides::new(
+        options.get_one::<String>("version-name"),
+        options.get_one::<String>("version-code"),
+        options.get_one::<String>("label"),
+        |var| std::env::var(var).ok(),
+    )?;
+    if options.get_flag("profileable") {
+        overrides.profileable = Some(config::Override {
+            value: true,
+            source: "cli".to_owned(),
+        });
+    }
+    Ok(overrides)
+}
+
+/// Arguments controlling `adb reverse`, shared by `install` and `run`
+fn reverse_args() -> [Arg; 2] {
+    [
+        multi_opt(
+            "reverse",
+            "PORT[:HOST_PORT]",
+            "Reverse a device port to a host port with `adb reverse` after installing",
+        ),
+        flag(
+            "no-reverse",
+            "Remove all `adb reverse` port mappings instead of setting them up",
+        ),
+    ]
+}
+
+/// Arguments checking the APKs against the device before installing them, shared by `install`
+/// and `run`
+fn compat_args() -> [Arg; 2] {
+    [
+        flag(
+            "check-device-compat",
+            "Check that the device can install the APKs before installing them",
+        ),
+        flag(
+            "force",
+            "Install the APKs even when `--check-device-compat` finds they can't be installed",
+        )
+        .requires("check-device-compat"),
+    ]
+}
+
+/// Arguments selecting the device user, shared by `install`, `run` and `uninstall`
+fn user_args() -> [Arg; 2] {
+    [
+        opt(
+            "user",
+            "Install for this device user or work profile instead of the current one",
+        )
+        .value_name("ID"),
+        flag(
+            "list-users",
+            "List the users and work profiles of the device",
+        ),
+    ]
+}
+
+fn cli_uninstall() -> Command {
+    Command::new("uninstall")
+        .about("Uninstall the APKs of the local package from the device")
+        .arg_targets_bin_example(
+            "Name of the bin target to uninstall",
+            "Name of the example target to uninstall",
+        )
+        .arg_package("Package with the targets to uninstall")
+        .args(user_args())
+        .arg_manifest_path()
+}
+
+fn cli_restart() -> Command {
+    Command::new("restart")
+        .about("Stop and start the installed app again, without building or installing it")
+        .arg_targets_bin_example(
+            "Name of the bin target to restart",
+            "Name of the example target to restart",
+        )
+        .arg_package("Package with the target to restart")
+        .args(user_args())
+        .arg(flag(
+            "clear-data",
+            "Clear the data of the app, like `pm clear`, before starting it",
+        ))
+        .arg(flag(
+            "logcat",
+            "Follow the log of the started app until interrupted",
+        ))
+        .arg_manifest_path()
+}
+
+fn cli_hot() -> Command {
+    Command::new("hot")
+        .about(
+            "Experimental: build the library of a debug build for the device, push it to the \
+             installed app and restart it, without building or installing an APK",
+        )
+        .arg_targets_bin_example(
+            "Name of the bin target to reload",
+            "Name of the example target to reload",
+        )
+        .arg_package("Package with the target to reload")
+        .args(user_args())
+        .arg(flag(
+            "logcat",
+            "Follow the log of the restarted app until interrupted",
+        ))
+        .arg_jobs()
+        .arg_release("Refused, the apps of release builds only load the library of their APK")
+        .arg_features()
+        .arg_target_dir()
+        .arg_manifest_path()
+        .arg_message_format()
+        .after_help(
+            "\
+The installed app must be a debug build made by this version of cargo-quad-apk,
+whose MainActivity loads the pushed library while it is newer than the library
+of the APK. Changes to the Java code, the manifest, the assets or the libraries
+the app depends on need the app to be installed again.
+",
+        )
+}
+
+fn cli_devices() -> Command {
+    Command::new("devices")
+        .about("List the connected devices with their Android version and ABIs")
+        .arg_package("Package whose `build_targets` are compared with the ABIs of the devices")
+        .arg_manifest_path()
+}
+
+fn cli_cache() -> Command {
+    Command::new("cache")
+        .about("Manage the cache of downloaded artifacts shared by every package")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("clean")
+                .about("Remove the cached artifacts")
+                .arg(
+                    opt(
+                        "older-than",
+                        "Only remove the artifacts not used for this long, like `30d` or `12h`",
+                    )
+                    .value_name("DURATION"),
+                ),
+        )
+}
+
+fn cli_clean() -> Command {
+    Command::new("clean")
+        .about(
+            "Remove the Android artifacts from the target directory, leaving the host builds of \
+             cargo",
+        )
+        .arg(flag(
+            "apk-only",
+            "Only remove the final APKs and bundles, in the `apk` directories",
+        ))
+        .arg_release("Only remove the artifacts of release builds")
+        .arg_target_dir()
+        .arg_manifest_path()
+}
+
 fn cli_logcat() -> Command {
     Command::new("logcat")
-        .alias("r")
         .about("Print Android log")
+        .arg(
+            flag(
+                "since-run",
+                "Only print logs since `cargo quad-apk run` last started the app",
+            )
+            .conflicts_with_all(["since-boot", "since"]),
+        )
+        .arg(flag("since-boot", "Only print logs since the device booted").conflicts_with("since"))
+        .arg(
+            opt(
+                "since",
+                "Only print logs of the given last period, like `15m` or `1h30m`",
+            )
+            .value_name("DURATION"),
+        )
+        .arg(flag(
+            "json",
+            "Print one JSON object per record, with its timestamp, pid, tid, priority, tag and message",
+        ))
+        .arg(flag(
+            "pid-of-app",
+            "Only print the logs of the running process of the app",
+        ))
+        .arg(multi_opt("tag", "TAG", "Only print the logs of this tag"))
+        .arg(
+            opt("level", "Only print the logs of this priority or above")
+                .value_name("LEVEL")
+                .value_parser(["V", "D", "I", "W", "E"])
+                .ignore_case(true),
+        )
+        .arg(flag("clear", "Clear the log buffers of the device first"))
+        .arg(flag(
+            "dump",
+            "Print the logs and exit, instead of following them",
+        ))
+        .arg(
+            flag(
+                "symbolicate",
+                "Print the native crashes again with ndk-stack, naming the functions, files and \
+                 lines of their frames from the libraries of the last build",
+            )
+            .conflicts_with("json"),
+        )
+        .arg_targets_bin_example(
+            "Name of the bin target whose app `--pid-of-app` follows",
+            "Name of the example target whose app `--pid-of-app` follows",
+        )
+        .arg_package("Package whose Android configuration is used")
+        .arg_release("Symbolicate with the libraries of the release build")
+        .arg_manifest_path()
         .arg_message_format()
 }
 
+fn cli_publish() -> Command {
+    Command::new("publish")
+        .about("Upload the APKs of the last build to the configured destination")
+        .arg(
+            opt(
+                "apk",
+                "Publish this APK instead of the ones of the last build",
+            )
+            .value_name("PATH"),
+        )
+        .arg(flag(
+            "dry-run",
+            "Print what would be published without publishing it",
+        ))
+        .arg_package("Package to publish")
+        .arg_release("Publish the artifacts of the release build")
+        .arg_manifest_path()
+}
+
+fn cli_diff() -> Command {
+    Command::new("diff")
+        .about("Compare the manifests and the contents of two APKs")
+        .arg(
+            Arg::new("apks")
+                .value_name("APK")
+                .help(
+                    "The old and the new APK. When only one is given, it is compared with the \
+                     APK of the same name from the previous build",
+                )
+                .required(true)
+                .num_args(1..=2),
+        )
+        .arg(flag("json", "Print the comparison as JSON"))
+        .arg_release("Compare with the previous release build")
+        .arg_manifest_path()
+}
+
+fn cli_bootstrap() -> Command {
+    Command::new("bootstrap")
+        .about("Download the Android command-line tools and install the SDK packages and NDK of the builds")
+        .arg(
+            opt(
+                "dir",
+                "Directory of the SDK to install into [default: $ANDROID_HOME, or where Android \
+                 Studio puts it]",
+            )
+            .value_name("PATH"),
+        )
+        .arg(flag(
+            "accept-licenses",
+            "Accept the licenses of the SDK packages without asking",
+        ))
+        .arg(opt("build-tools", "Version of the build-tools to install").value_name("VERSION"))
+        .arg(opt("ndk", "Version of the NDK to install").value_name("VERSION"))
+        .arg_package("Package whose platforms are installed")
+        .arg_manifest_path()
+}
+
+fn cli_compat() -> Command {
+    Command::new("compat")
+        .about("Check that the APKs of the last build can be installed on the device")
+        .arg(
+            opt(
+                "apk",
+                "Check this APK instead of the ones of the last build",
+            )
+            .value_name("PATH"),
+        )
+        .arg(flag(
+            "force-reinstall",
+            "Expect an install with another signing key to be uninstalled first",
+        ))
+        .arg(flag(
+            "force",
+            "Succeed even when the APKs can't be installed on the device",
+        ))
+        .arg_release("Check the APKs of the last release build")
+        .arg_package("Package whose APKs are checked")
+        .arg_manifest_path()
+}
+
+fn cli_release_check() -> Command {
+    Command::new("release-check")
+        .about("Check the APKs of the last release build against the requirements of Play")
+        .arg(
+            opt(
+                "apk",
+                "Check this APK instead of the ones of the last release build",
+            )
+            .value_name("PATH"),
+        )
+        .arg(
+            opt(
+                "baseline-version-code",
+                "versionCode of the last published release, or a file holding it, which the \
+                 APKs must be above",
+            )
+            .value_name("N|PATH"),
+        )
+        .arg_package("Package whose APKs are checked")
+        .arg_manifest_path()
+}
+
 pub fn execute_build(options: &ArgMatches, cargo_gctx: &GlobalContext) -> cargo::CliResult {
     let root_manifest = options.root_manifest(&cargo_gctx)?;
 
@@ -320,9 +929,57 @@ pub fn execute_build(options: &ArgMatches, cargo_gctx: &GlobalContext) -> cargo:
 
     let mut android_config =
         config::load(&workspace, &options.get_one::<String>("package").cloned())?;
+    if let Some(exit_code) = ops::run_with_toolchain(&workspace, &android_config)? {
+        return Err(cargo::CliError::code(exit_code));
+    }
     android_config.release = options.get_flag("release");
+    let cli_features = options.cli_features()?;
+    android_config.cargo_features =
+        ops::active_features(&workspace, &android_config, &cli_features)?;
+    android_config.no_default_features = !cli_features.uses_default_features;
+    android_config.overrides = manifest_overrides(options)?;
+    if let Some(build_targets) = build_targets_override(options)? {
+        android_config.override_build_targets(build_targets);
+    }
 
-    ops::build(&workspace, &android_config, &options)?;
+    let flavors = if options.get_flag("all-flavors") {
+        let names = android_config.flavor_names();
+        if names.is_empty() {
+            return Err(format_err!(
+                "`--all-flavors` needs flavors in `[package.metadata.android.flavors]`"
+            )
+            .into());
+        }
+        names
+    } else {
+        options
+            .get_many::<String>("flavor")
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect()
+    };
+    if flavors.is_empty() {
+        ops::build(&workspace, &android_config, &options)?;
+        return Ok(());
+    }
+    let mut flavor_configs: Vec<config::AndroidConfig> = vec![];
+    for flavor in &flavors {
+        if flavor_configs
+            .iter()
+            .any(|config| config.flavor.as_ref() == Some(flavor))
+        {
+            continue;
+        }
+        let mut flavor_config = android_config.with_flavor(flavor)?;
+        flavor_config.cargo_features = ops::active_features(
+            &workspace,
+            &flavor_config,
+            &flavor_config.flavor_features(&cli_features)?,
+        )?;
+        flavor_configs.push(flavor_config);
+    }
+    ops::build_flavors(&workspace, &flavor_configs, &options)?;
     Ok(())
 }
 
@@ -333,12 +990,65 @@ pub fn execute_install(options: &ArgMatches, cargo_gctx: &GlobalContext) -> carg
 
     let mut android_config =
         config::load(&workspace, &options.get_one::<String>("package").cloned())?;
+    if let Some(exit_code) = ops::run_with_toolchain(&workspace, &android_config)? {
+        return Err(cargo::CliError::code(exit_code));
+    }
     android_config.release = !options.get_flag("debug");
+    let cli_features = options.cli_features()?;
+    android_config.cargo_features =
+        ops::active_features(&workspace, &android_config, &cli_features)?;
+    android_config.no_default_features = !cli_features.uses_default_features;
+    android_config.overrides = manifest_overrides(options)?;
+    // `ops::install` installs to the other devices of `--device` as well
+    android_config.device = options.get_one::<String>("device").cloned();
+    if let Some(build_targets) = build_targets_override(options)? {
+        android_config.override_build_targets(build_targets);
+    }
+
+    if options.get_flag("list-users") {
+        return Ok(ops::list_users(&workspace, &android_config)?);
+    }
 
     ops::install(&workspace, &android_config, &options)?;
     Ok(())
 }
 
+/// Warns about the app arguments named like the flags of `run`. Arguments following the target
+/// name used to go to the app, they now need `--` like with `cargo run`, and flags given after
+/// `--` to cargo-quad-apk by habit would be silently passed to the app.
+fn misplaced_flag_warnings(app_args: &[String]) -> Vec<String> {
+    let cli = cli();
+    let run = cli
+        .find_subcommand("quad-apk")
+        .and_then(|quad_apk| quad_apk.find_subcommand("run"))
+        .unwrap();
+    let is_flag = |arg: &str| {
+        let name = arg.split('=').next().unwrap();
+        let (long, short) = match name.strip_prefix("--") {
+            Some(long) => (Some(long), None),
+            None => {
+                let mut chars = name.strip_prefix('-').unwrap_or_default().chars();
+                (None, chars.next().filter(|_| chars.next().is_none()))
+            }
+        };
+        cli.get_arguments().chain(run.get_arguments()).any(|known| {
+            (long.is_some() && known.get_long() == long)
+                || (short.is_some() && known.get_short() == short)
+        })
+    };
+    app_args
+        .iter()
+        .filter(|arg| is_flag(arg))
+        .map(|arg| {
+            format!(
+                "`{}` follows `--`, so it is passed to the app and not to cargo-quad-apk, put it \
+                 before `--` to apply it to the build",
+                arg
+            )
+        })
+        .collect()
+}
+
 pub fn execute_run(options: &ArgMatches, cargo_gctx: &GlobalContext) -> cargo::CliResult {
     let root_manifest = options.root_manifest(&cargo_gctx)?;
 
@@ -346,22 +1056,249 @@ pub fn execute_run(options: &ArgMatches, cargo_gctx: &GlobalContext) -> cargo::C
 
     let mut android_config =
         config::load(&workspace, &options.get_one::<String>("package").cloned())?;
+    if let Some(exit_code) = ops::run_with_toolchain(&workspace, &android_config)? {
+        return Err(cargo::CliError::code(exit_code));
+    }
     android_config.release = options.get_flag("release");
+    let cli_features = options.cli_features()?;
+    android_config.cargo_features =
+        ops::active_features(&workspace, &android_config, &cli_features)?;
+    android_config.no_default_features = !cli_features.uses_default_features;
+    android_config.overrides = manifest_overrides(options)?;
+    android_config.device = selected_device(&options)?;
+
+    let app_args = options
+        .get_many::<String>("args")
+        .unwrap_or_default()
+        .cloned()
+        .collect::<Vec<_>>();
+    for warning in misplaced_flag_warnings(&app_args) {
+        cargo_gctx.shell().warn(warning)?;
+    }
+
+    if options.get_flag("list-users") {
+        return Ok(ops::list_users(&workspace, &android_config)?);
+    }
+    if options.get_flag("emulator") {
+        ops::use_emulator(&workspace, &mut android_config)?;
+    } else if let Some(build_targets) = build_targets_override(options)? {
+        android_config.override_build_targets(build_targets);
+    } else if !options.get_flag("all-abis") {
+        ops::use_device_abi(&workspace, &mut android_config)?;
+    }
 
     ops::run(&workspace, &android_config, &options)?;
     Ok(())
 }
 
-pub fn execute_logcat(options: &ArgMatches, cargo_gctx: &GlobalContext) -> cargo::CliResult {
+pub fn execute_uninstall(options: &ArgMatches, cargo_gctx: &GlobalContext) -> cargo::CliResult {
+    let root_manifest = options.root_manifest(&cargo_gctx)?;
+
+    let workspace = Workspace::new(&root_manifest, &cargo_gctx)?;
+
+    let mut android_config =
+        config::load(&workspace, &options.get_one::<String>("package").cloned())?;
+    android_config.device = selected_device(&options)?;
+
+    if options.get_flag("list-users") {
+        return Ok(ops::list_users(&workspace, &android_config)?);
+    }
+
+    ops::uninstall(&workspace, &android_config, &options)?;
+    Ok(())
+}
+
+pub fn execute_restart(options: &ArgMatches, cargo_gctx: &GlobalContext) -> cargo::CliResult {
+    let root_manifest = options.root_manifest(&cargo_gctx)?;
+
+    let workspace = Workspace::new(&root_manifest, &cargo_gctx)?;
+
+    let mut android_config =
+        config::load(&workspace, &options.get_one::<String>("package").cloned())?;
+    android_config.device = selected_device(&options)?;
+
+    if options.get_flag("list-users") {
+        return Ok(ops::list_users(&workspace, &android_config)?);
+    }
+
+    ops::restart(&workspace, &android_config, &options)?;
+    Ok(())
+}
+
+pub fn execute_hot(options: &ArgMatches, cargo_gctx: &GlobalContext) -> cargo::CliResult {
+    let root_manifest = options.root_manifest(&cargo_gctx)?;
+
+    let workspace = Workspace::new(&root_manifest, &cargo_gctx)?;
+
+    let mut android_config =
+        config::load(&workspace, &options.get_one::<String>("package").cloned())?;
+    if let Some(exit_code) = ops::run_with_toolchain(&workspace, &android_config)? {
+        return Err(cargo::CliError::code(exit_code));
+    }
+    android_config.release = options.get_flag("release");
+    let cli_features = options.cli_features()?;
+    android_config.cargo_features =
+        ops::active_features(&workspace, &android_config, &cli_features)?;
+    android_config.no_default_features = !cli_features.uses_default_features;
+    android_config.device = selected_device(&options)?;
+
+    if options.get_flag("list-users") {
+        return Ok(ops::list_users(&workspace, &android_config)?);
+    }
+
+    ops::hot(&workspace, &mut android_config, &options)?;
+    Ok(())
+}
+
+pub fn execute_devices(options: &ArgMatches, cargo_gctx: &GlobalContext) -> cargo::CliResult {
     let root_manifest = options.root_manifest(&cargo_gctx)?;
 
     let workspace = Workspace::new(&root_manifest, &cargo_gctx)?;
 
     let android_config = config::load(&workspace, &options.get_one::<String>("package").cloned())?;
 
-    drop(writeln!(workspace.gctx().shell().err(), "Starting logcat"));
-    let adb = android_config.sdk_path.join("platform-tools/adb");
-    ProcessBuilder::new(&adb).arg("logcat").exec()?;
+    ops::list_devices(&workspace, &android_config)?;
+    Ok(())
+}
+
+pub fn execute_cache(options: &ArgMatches, cargo_gctx: &GlobalContext) -> cargo::CliResult {
+    match options.subcommand() {
+        Some(("clean", clean_options)) => {
+            ops::clean_cache(
+                cargo_gctx,
+                clean_options
+                    .get_one::<String>("older-than")
+                    .map(String::as_str),
+            )?;
+            Ok(())
+        }
+        _ => unreachable!("clap requires a subcommand of `cache`"),
+    }
+}
+
+pub fn execute_clean(options: &ArgMatches, cargo_gctx: &GlobalContext) -> cargo::CliResult {
+    let root_manifest = options.root_manifest(&cargo_gctx)?;
+
+    let workspace = Workspace::new(&root_manifest, &cargo_gctx)?;
+
+    ops::clean(&workspace, &options)?;
+    Ok(())
+}
+
+pub fn execute_logcat(options: &ArgMatches, cargo_gctx: &GlobalContext) -> cargo::CliResult {
+    let root_manifest = options.root_manifest(&cargo_gctx)?;
+
+    let workspace = Workspace::new(&root_manifest, &cargo_gctx)?;
+
+    let mut android_config =
+        config::load(&workspace, &options.get_one::<String>("package").cloned())?;
+    android_config.device = selected_device(&options)?;
+    android_config.release = options.get_flag("release");
+
+    ops::logcat(&workspace, &android_config, &options)?;
+    Ok(())
+}
+
+pub fn execute_publish(options: &ArgMatches, cargo_gctx: &GlobalContext) -> cargo::CliResult {
+    let root_manifest = options.root_manifest(&cargo_gctx)?;
+
+    let workspace = Workspace::new(&root_manifest, &cargo_gctx)?;
+
+    let mut android_config =
+        config::load(&workspace, &options.get_one::<String>("package").cloned())?;
+    android_config.release = options.get_flag("release");
 
+    ops::publish(&workspace, &android_config, &options)?;
     Ok(())
 }
+
+pub fn execute_diff(options: &ArgMatches, cargo_gctx: &GlobalContext) -> cargo::CliResult {
+    ops::diff(cargo_gctx, &options)?;
+    Ok(())
+}
+
+pub fn execute_release_check(options: &ArgMatches, cargo_gctx: &GlobalContext) -> cargo::CliResult {
+    let root_manifest = options.root_manifest(&cargo_gctx)?;
+
+    let workspace = Workspace::new(&root_manifest, &cargo_gctx)?;
+
+    let mut android_config =
+        config::load(&workspace, &options.get_one::<String>("package").cloned())?;
+    android_config.release = true;
+
+    ops::release_check(&workspace, &android_config, &options)?;
+    Ok(())
+}
+
+pub fn execute_compat(options: &ArgMatches, cargo_gctx: &GlobalContext) -> cargo::CliResult {
+    let root_manifest = options.root_manifest(&cargo_gctx)?;
+
+    let workspace = Workspace::new(&root_manifest, &cargo_gctx)?;
+
+    let mut android_config =
+        config::load(&workspace, &options.get_one::<String>("package").cloned())?;
+    android_config.release = options.get_flag("release");
+    android_config.device = selected_device(&options)?;
+
+    ops::compat(&workspace, &android_config, &options)?;
+    Ok(())
+}
+
+pub fn execute_bootstrap(options: &ArgMatches, cargo_gctx: &GlobalContext) -> cargo::CliResult {
+    let root_manifest = options.root_manifest(&cargo_gctx)?;
+
+    let workspace = Workspace::new(&root_manifest, &cargo_gctx)?;
+
+    ops::bootstrap(&workspace, &options)?;
+    Ok(())
+}
+
+#[test]
+fn run_app_args() {
+    let run = |args: &[&str]| {
+        let matches = cli()
+            .try_get_matches_from(["cargo-apk", "quad-apk", "run"].iter().chain(args))
+            .map_err(|err| err.kind())?;
+        let run = matches
+            .subcommand_matches("quad-apk")
+            .and_then(|quad_apk| quad_apk.subcommand_matches("run"))
+            .unwrap()
+            .clone();
+        Ok::<_, clap::error::ErrorKind>(run)
+    };
+    let app_args = |matches: &ArgMatches| {
+        matches
+            .get_many::<String>("args")
+            .unwrap_or_default()
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+
+    let flag_before = run(&["--release", "--bin", "app"]).unwrap();
+    assert!(flag_before.get_flag("release"));
+    assert!(app_args(&flag_before).is_empty());
+
+    // Flags after the target name are flags of cargo-quad-apk, like with `cargo run`
+    let flag_after = run(&["--bin", "app", "--release"]).unwrap();
+    assert!(flag_after.get_flag("release"));
+    assert!(app_args(&flag_after).is_empty());
+    assert_eq!(
+        run(&["--bin", "app", "level1"]).unwrap_err(),
+        clap::error::ErrorKind::UnknownArgument
+    );
+
+    let separated = run(&["--release", "--", "level1", "--release", "-v"]).unwrap();
+    assert!(separated.get_flag("release"));
+    assert_eq!(app_args(&separated), vec!["level1", "--release", "-v"]);
+
+    assert_eq!(
+        misplaced_flag_warnings(&app_args(&separated)),
+        vec![
+            "`--release` follows `--`, so it is passed to the app and not to cargo-quad-apk, put \
+             it before `--` to apply it to the build",
+            "`-v` follows `--`, so it is passed to the app and not to cargo-quad-apk, put it \
+             before `--` to apply it to the build",
+        ]
+    );
+    assert!(misplaced_flag_warnings(&["--level=1".to_owned(), "-x".to_owned()]).is_empty());
+}
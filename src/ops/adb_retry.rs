@@ -0,0 +1,263 @@
+//! Retries of adb transfers failing because of the connection, like on busy USB hubs where the
+//! device goes offline for a moment.
+//!
+//! Failures are classified by the output of adb: the transient ones are retried with a backoff,
+//! after `adb reconnect offline`, while the others fail right away as they would without retries.
+
+use anyhow::format_err;
+use cargo::util::{CargoResult, GlobalContext};
+use cargo_util::ProcessBuilder;
+use clap::ArgMatches;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// Retries after a transient failure, unless `--adb-retries` is given
+const DEFAULT_RETRIES: u32 = 3;
+
+/// Wait before the first retry, doubled for each of the following ones
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Failure {
+    /// The connection to the device failed, trying again may work
+    Transient,
+    /// The device refused the operation, like an APK signed with another key
+    Permanent,
+}
+
+/// Messages of adb and the package manager, checked in order. The permanent ones come first, as
+/// their output may also mention the connection.
+const FAILURES: &[(&str, Failure)] = &[
+    ("INSTALL_FAILED_UPDATE_INCOMPATIBLE", Failure::Permanent),
+    ("INSTALL_FAILED_OLDER_SDK", Failure::Permanent),
+    ("INSTALL_FAILED_VERSION_DOWNGRADE", Failure::Permanent),
+    ("INSTALL_FAILED_INSUFFICIENT_STORAGE", Failure::Permanent),
+    ("INSTALL_FAILED_NO_MATCHING_ABIS", Failure::Permanent),
+    ("INSTALL_PARSE_FAILED", Failure::Permanent),
+    ("no devices/emulators found", Failure::Permanent),
+    ("device unauthorized", Failure::Permanent),
+    ("more than one device/emulator", Failure::Permanent),
+    ("device offline", Failure::Transient),
+    ("device still authorizing", Failure::Transient),
+    ("error: closed", Failure::Transient),
+    ("protocol fault", Failure::Transient),
+    ("Connection reset by peer", Failure::Transient),
+    ("Broken pipe", Failure::Transient),
+    ("failed to read copy response", Failure::Transient),
+    ("failed to get feature set", Failure::Transient),
+    ("cannot connect to daemon", Failure::Transient),
+];
+
+/// Classifies a failure from the output of adb. Unknown failures are permanent.
+pub fn classify(output: &str) -> Failure {
+    FAILURES
+        .iter()
+        .find(|(message, _)| output.contains(message))
+        .map_or(Failure::Permanent, |(_, failure)| *failure)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub first_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Reads `--adb-retries`
+    pub fn from_options(options: &ArgMatches) -> CargoResult<Self> {
+        let retries = match options.get_one::<String>("adb-retries") {
+            Some(retries) => retries.parse().map_err(|_| {
+                format_err!("Invalid `--adb-retries` `{}`, expected a number", retries)
+            })?,
+            None => DEFAULT_RETRIES,
+        };
+        Ok(RetryPolicy {
+            retries,
+            first_backoff: FIRST_BACKOFF,
+        })
+    }
+}
+
+/// Runs `attempt` until it succeeds, fails permanently or runs out of retries. `reconnect` is
+/// called once, before the first retry, and `log` gets a line for each failed attempt.
+pub fn retry_with<T>(
+    policy: &RetryPolicy,
+    what: &str,
+    mut attempt: impl FnMut() -> CargoResult<T>,
+    mut reconnect: impl FnMut(),
+    mut log: impl FnMut(String),
+) -> CargoResult<T> {
+    let mut backoff = policy.first_backoff;
+    for retry in 1.. {
+        let err = match attempt() {
+            Ok(value) => return Ok(value),
+            Err(err) => err,
+        };
+        let output = format!("{:#}", err);
+        if retry > policy.retries || classify(&output) == Failure::Permanent {
+            return Err(err);
+        }
+        log(format!(
+            "{} failed ({}), retrying in {}s ({}/{})",
+            what,
+            output.lines().next().unwrap_or_default().trim(),
+            backoff.as_secs_f32(),
+            retry,
+            policy.retries
+        ));
+        if retry == 1 {
+            reconnect();
+        }
+        thread::sleep(backoff);
+        backoff *= 2;
+    }
+    unreachable!()
+}
+
+/// Runs adb operations with the retries of `--adb-retries`
+pub struct AdbRetry<'a> {
+    gctx: &'a GlobalContext,
+    adb: PathBuf,
+    policy: RetryPolicy,
+}
+
+impl<'a> AdbRetry<'a> {
+    pub fn new(gctx: &'a GlobalContext, adb: &Path, options: &ArgMatches) -> CargoResult<Self> {
+        Ok(AdbRetry {
+            gctx,
+            adb: adb.to_owned(),
+            policy: RetryPolicy::from_options(options)?,
+        })
+    }
+
+    pub fn run<T>(&self, what: &str, attempt: impl FnMut() -> CargoResult<T>) -> CargoResult<T> {
+        retry_with(
+            &self.policy,
+            what,
+            attempt,
+            || {
+                drop(
+                    ProcessBuilder::new(&self.adb)
+                        .arg("reconnect")
+                        .arg("offline")
+                        .exec_with_output(),
+                )
+            },
+            |message| drop(self.gctx.shell().warn(message)),
+        )
+    }
+}
+
+#[test]
+fn adb_failure_classification() {
+    let corpus = [
+        (
+            "adb: error: failed to get feature set: device offline",
+            Failure::Transient,
+        ),
+        ("error: closed", Failure::Transient),
+        (
+            "adb: error: failed to read copy response\n",
+            Failure::Transient,
+        ),
+        (
+            "Performing Streamed Install\nadb: error: protocol fault (couldn't read status): \
+             Connection reset by peer",
+            Failure::Transient,
+        ),
+        (
+            "adb: error: 1583 KB/s (262144 bytes in 0.161s): write failed: Broken pipe",
+            Failure::Transient,
+        ),
+        (
+            "* daemon not running; starting now at tcp:5037\nadb: cannot connect to daemon",
+            Failure::Transient,
+        ),
+        (
+            "Performing Streamed Install\nadb: failed to install app.apk: Failure \
+             [INSTALL_FAILED_UPDATE_INCOMPATIBLE: Package rust.app signatures do not match \
+             previously installed version; ignoring!]",
+            Failure::Permanent,
+        ),
+        (
+            "adb: failed to install app.apk: Failure [INSTALL_FAILED_OLDER_SDK: Requires newer \
+             sdk version #26 (current version is #24)]",
+            Failure::Permanent,
+        ),
+        (
+            "Failure [INSTALL_FAILED_INSUFFICIENT_STORAGE]",
+            Failure::Permanent,
+        ),
+        (
+            "adb: error: failed to get feature set: no devices/emulators found",
+            Failure::Permanent,
+        ),
+        (
+            "adb: error: device unauthorized.\nThis adb server's $ADB_VENDOR_KEYS is not set",
+            Failure::Permanent,
+        ),
+        (
+            "adb: error: cannot stat 'missing.png': No such file or directory",
+            Failure::Permanent,
+        ),
+    ];
+    for (output, failure) in &corpus {
+        assert_eq!(classify(output), *failure, "{}", output);
+    }
+}
+
+#[test]
+fn adb_retries() {
+    use std::cell::{Cell, RefCell};
+
+    let policy = RetryPolicy {
+        retries: 3,
+        first_backoff: Duration::from_millis(1),
+    };
+    let attempts = Cell::new(0);
+    let reconnects = Cell::new(0);
+    let log = RefCell::new(vec![]);
+    let run = |failures: Vec<&'static str>| {
+        attempts.set(0);
+        reconnects.set(0);
+        log.borrow_mut().clear();
+        retry_with(
+            &policy,
+            "adb push",
+            || {
+                attempts.set(attempts.get() + 1);
+                match failures.get(attempts.get() - 1) {
+                    Some(output) => Err(format_err!("{}", output)),
+                    None => Ok(attempts.get()),
+                }
+            },
+            || reconnects.set(reconnects.get() + 1),
+            |message| log.borrow_mut().push(message),
+        )
+    };
+
+    // Transient failures are retried after reconnecting once
+    assert_eq!(run(vec!["error: closed", "device offline"]).unwrap(), 3);
+    assert_eq!(reconnects.get(), 1);
+    assert_eq!(
+        *log.borrow(),
+        vec![
+            "adb push failed (error: closed), retrying in 0.001s (1/3)",
+            "adb push failed (device offline), retrying in 0.002s (2/3)",
+        ]
+    );
+
+    // Permanent failures are not
+    let err = run(vec!["Failure [INSTALL_FAILED_VERSION_DOWNGRADE]"]).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Failure [INSTALL_FAILED_VERSION_DOWNGRADE]"
+    );
+    assert_eq!((attempts.get(), reconnects.get()), (1, 0));
+
+    // The last failure is returned once the retries run out
+    let err = run(vec!["device offline"; 5]).unwrap_err();
+    assert_eq!(err.to_string(), "device offline");
+    assert_eq!(attempts.get(), 4);
+}
@@ -0,0 +1,452 @@
+//! `bootstrap`: installs the SDK packages the builds need, with the sdkmanager of the Android
+//! command-line tools, which are downloaded to the cache first.
+
+use super::cache::Cache;
+use super::interrupt;
+use crate::config;
+use crate::error::{FailureKind, ResultExt};
+use anyhow::format_err;
+use cargo::core::Workspace;
+use cargo::util::CargoResult;
+use cargo::GlobalContext;
+use cargo_util::ProcessBuilder;
+use clap::ArgMatches;
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+/// Archive of the command-line tools for the host, with its sha256 as published on
+/// https://developer.android.com/studio#command-line-tools-only
+const COMMAND_LINE_TOOLS: (&str, &str) = if cfg!(target_os = "windows") {
+    (
+        "https://dl.google.com/android/repository/commandlinetools-win-11076708_latest.zip",
+        "4d6931209eebb1bfb7c7e8b240a6a3cb3ab24479ea294f3539429574b1eec862",
+    )
+} else if cfg!(target_os = "macos") {
+    (
+        "https://dl.google.com/android/repository/commandlinetools-mac-11076708_latest.zip",
+        "7bc5c72ba0275c80a8f19684fb92793b83a6b5c94d4d179fc5988930282d7e64",
+    )
+} else {
+    (
+        "https://dl.google.com/android/repository/commandlinetools-linux-11076708_latest.zip",
+        "2d2d50857e4eb553af5a6dc3ad507a17adf43d115264b1afc116f95c92e5e258",
+    )
+};
+
+/// Installed without `--build-tools`, the builds use the highest installed version anyway
+const DEFAULT_BUILD_TOOLS: &str = "34.0.0";
+
+/// Installed without `--ndk`
+const DEFAULT_NDK: &str = "26.3.11579264";
+
+/// SDK packages installed by `bootstrap`
+#[derive(Debug)]
+pub struct Packages {
+    pub build_tools: String,
+    /// API levels of the platforms
+    pub platforms: BTreeSet<u32>,
+    pub ndk: String,
+}
+
+impl Packages {
+    /// Names of the packages for sdkmanager
+    fn names(&self) -> Vec<String> {
+        let mut names = vec![
+            "platform-tools".to_owned(),
+            format!("build-tools;{}", self.build_tools),
+        ];
+        names.extend(
+            self.platforms
+                .iter()
+                .map(|platform| format!("platforms;android-{}", platform)),
+        );
+        names.push(format!("ndk;{}", self.ndk));
+        names
+    }
+
+    /// A file the builds need from each package, relative to the SDK
+    fn probes(&self) -> Vec<PathBuf> {
+        let exe = if cfg!(target_os = "windows") {
+            ".exe"
+        } else {
+            ""
+        };
+        let mut probes = vec![
+            Path::new("platform-tools").join(format!("adb{}", exe)),
+            Path::new("build-tools")
+                .join(&self.build_tools)
+                .join(format!("aapt{}", exe)),
+        ];
+        probes.extend(self.platforms.iter().map(|platform| {
+            Path::new("platforms")
+                .join(format!("android-{}", platform))
+                .join("android.jar")
+        }));
+        probes.push(ndk_dir(Path::new(""), &self.ndk).join("source.properties"));
+        probes
+    }
+}
+
+/// Directory of an NDK version installed by sdkmanager
+fn ndk_dir(sdk_dir: &Path, ndk: &str) -> PathBuf {
+    sdk_dir.join("ndk").join(ndk)
+}
+
+/// Path of sdkmanager, once the command-line tools are installed where it expects them
+fn sdkmanager_path(sdk_dir: &Path) -> PathBuf {
+    let name = if cfg!(target_os = "windows") {
+        "sdkmanager.bat"
+    } else {
+        "sdkmanager"
+    };
+    sdk_dir
+        .join("cmdline-tools")
+        .join("latest")
+        .join("bin")
+        .join(name)
+}
+
+/// Returns the sdkmanager command acting on `sdk_dir`
+fn sdkmanager_command(sdk_dir: &Path, args: &[String]) -> ProcessBuilder {
+    let mut cmd = ProcessBuilder::new(sdkmanager_path(sdk_dir));
+    cmd.arg(format!("--sdk_root={}", sdk_dir.display()))
+        .args(args);
+    cmd
+}
+
+/// What `bootstrap` fetches from the network, faked by the tests
+trait Downloads {
+    /// Returns the archive of the command-line tools
+    fn command_line_tools(&self) -> CargoResult<PathBuf>;
+
+    /// Runs sdkmanager, answering yes to its license prompts when `accept_licenses`
+    fn sdkmanager(&self, cmd: &ProcessBuilder, accept_licenses: bool) -> CargoResult<()>;
+}
+
+/// Downloads to the shared cache, and runs the real sdkmanager
+struct Network<'a> {
+    gctx: &'a GlobalContext,
+}
+
+impl Downloads for Network<'_> {
+    fn command_line_tools(&self) -> CargoResult<PathBuf> {
+        let (url, sha256) = COMMAND_LINE_TOOLS;
+        Cache::open()?.download_verified(self.gctx, url, sha256)
+    }
+
+    fn sdkmanager(&self, cmd: &ProcessBuilder, accept_licenses: bool) -> CargoResult<()> {
+        if accept_licenses {
+            // One answer per license prompt, there are a handful of licenses
+            let mut cmd = cmd.clone();
+            cmd.stdin("y\n".repeat(32)).exec_with_output()?;
+            Ok(())
+        } else {
+            interrupt::exec(cmd)
+        }
+    }
+}
+
+/// Extracts the command-line tools to `cmdline-tools/latest` of the SDK, where sdkmanager
+/// expects to be. The archive has them in `cmdline-tools`.
+fn install_command_line_tools(archive: &Path, sdk_dir: &Path) -> CargoResult<()> {
+    let tools_dir = sdk_dir.join("cmdline-tools");
+    let partial_dir = tools_dir.join("latest.partial");
+    if partial_dir.exists() {
+        fs::remove_dir_all(&partial_dir)?;
+    }
+    let mut zip = zip::ZipArchive::new(File::open(archive)?)?;
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i)?;
+        let path = match entry.enclosed_name() {
+            Some(path) => path
+                .strip_prefix("cmdline-tools")
+                .unwrap_or(path)
+                .to_owned(),
+            None => continue,
+        };
+        let target = partial_dir.join(path);
+        if entry.is_dir() {
+            fs::create_dir_all(&target)?;
+            continue;
+        }
+        fs::create_dir_all(target.parent().unwrap())?;
+        std::io::copy(&mut entry, &mut File::create(&target)?)?;
+        #[cfg(unix)]
+        if let Some(mode) = entry.unix_mode() {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&target, fs::Permissions::from_mode(mode))?;
+        }
+    }
+    let latest = tools_dir.join("latest");
+    if latest.exists() {
+        fs::remove_dir_all(&latest)?;
+    }
+    fs::rename(&partial_dir, &latest)?;
+    Ok(())
+}
+
+/// Returns the probes of the packages which are missing from the SDK
+fn missing_files(sdk_dir: &Path, packages: &Packages) -> Vec<PathBuf> {
+    packages
+        .probes()
+        .into_iter()
+        .filter(|probe| !sdk_dir.join(probe).exists())
+        .collect()
+}
+
+/// Returns the shell lines setting the environment variables of the builds
+fn environment(sdk_dir: &Path, packages: &Packages) -> String {
+    let set = if cfg!(target_os = "windows") {
+        "set"
+    } else {
+        "export"
+    };
+    format!(
+        "{set} ANDROID_HOME=\"{}\"\n{set} NDK_HOME=\"{}\"\n",
+        sdk_dir.display(),
+        ndk_dir(sdk_dir, &packages.ndk).display(),
+        set = set
+    )
+}
+
+/// Installs the command-line tools unless they already are, then accepts the licenses and
+/// installs the packages with sdkmanager, and checks that they are all there
+fn install(
+    gctx: &GlobalContext,
+    downloads: &dyn Downloads,
+    sdk_dir: &Path,
+    packages: &Packages,
+    accept_licenses: bool,
+) -> CargoResult<()> {
+    if !sdkmanager_path(sdk_dir).exists() {
+        let archive = downloads.command_line_tools()?;
+        gctx.shell()
+            .status("Installing", "the Android command-line tools")?;
+        install_command_line_tools(&archive, sdk_dir)?;
+    }
+
+    if !accept_licenses && !std::io::stdin().is_terminal() {
+        return Err(format_err!(
+            "The SDK licenses must be accepted before installing the packages, give \
+             `--accept-licenses` to accept them without reading them"
+        ));
+    }
+    downloads.sdkmanager(
+        &sdkmanager_command(sdk_dir, &["--licenses".to_owned()]),
+        accept_licenses,
+    )?;
+
+    let names = packages.names();
+    gctx.shell().status("Installing", names.join(", "))?;
+    downloads.sdkmanager(&sdkmanager_command(sdk_dir, &names), accept_licenses)?;
+
+    let missing = missing_files(sdk_dir, packages);
+    if !missing.is_empty() {
+        return Err(format_err!(
+            "sdkmanager completed, but the SDK at `{}` still lacks {}",
+            sdk_dir.display(),
+            missing
+                .iter()
+                .map(|probe| probe.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the SDK `bootstrap` installs into without `--dir`
+fn default_sdk_dir() -> CargoResult<PathBuf> {
+    if let Some(sdk_dir) = std::env::var_os("ANDROID_HOME") {
+        return Ok(PathBuf::from(sdk_dir));
+    }
+    // Where Android Studio installs it
+    let dir = if cfg!(target_os = "macos") {
+        dirs::home_dir().map(|home| home.join("Library").join("Android").join("sdk"))
+    } else if cfg!(target_os = "windows") {
+        dirs::data_local_dir().map(|data| data.join("Android").join("Sdk"))
+    } else {
+        dirs::home_dir().map(|home| home.join("Android").join("Sdk"))
+    };
+    dir.ok_or_else(|| format_err!("Unable to find the home directory, give `--dir`"))
+}
+
+pub fn bootstrap(workspace: &Workspace, options: &ArgMatches) -> CargoResult<()> {
+    let gctx = workspace.gctx();
+    if gctx.offline() {
+        return Err(format_err!(
+            "`bootstrap` downloads the Android SDK, it can't run with `--offline`"
+        ));
+    }
+    let sdk_dir = match options.get_one::<String>("dir") {
+        Some(dir) => gctx.cwd().join(dir),
+        None => default_sdk_dir()?,
+    };
+    let packages = Packages {
+        build_tools: options
+            .get_one::<String>("build-tools")
+            .cloned()
+            .unwrap_or_else(|| DEFAULT_BUILD_TOOLS.to_owned()),
+        platforms: config::requested_platforms(
+            workspace,
+            &options.get_one::<String>("package").cloned(),
+        )?,
+        ndk: options
+            .get_one::<String>("ndk")
+            .cloned()
+            .unwrap_or_else(|| DEFAULT_NDK.to_owned()),
+    };
+    fs::create_dir_all(&sdk_dir)?;
+
+    install(
+        gctx,
+        &Network { gctx },
+        &sdk_dir,
+        &packages,
+        options.get_flag("accept-licenses"),
+    )
+    .failure_kind(FailureKind::Environment)?;
+
+    gctx.shell().status(
+        "Installed",
+        format!("the Android SDK and NDK to `{}`", sdk_dir.display()),
+    )?;
+    gctx.shell().note(
+        "set these environment variables for the builds, in your shell profile for instance",
+    )?;
+    drop(write!(
+        gctx.shell().out(),
+        "{}",
+        environment(&sdk_dir, &packages)
+    ));
+    Ok(())
+}
+
+#[cfg(test)]
+fn test_packages() -> Packages {
+    Packages {
+        build_tools: "34.0.0".to_owned(),
+        platforms: [31, 34].iter().copied().collect(),
+        ndk: "26.3.11579264".to_owned(),
+    }
+}
+
+#[test]
+fn sdkmanager_arguments() {
+    let cmd = sdkmanager_command(Path::new("/sdk"), &test_packages().names());
+    assert!(cmd
+        .get_program()
+        .to_string_lossy()
+        .contains("cmdline-tools/latest/bin/sdkmanager"));
+    assert_eq!(
+        cmd.get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect::<Vec<_>>(),
+        vec![
+            "--sdk_root=/sdk",
+            "platform-tools",
+            "build-tools;34.0.0",
+            "platforms;android-31",
+            "platforms;android-34",
+            "ndk;26.3.11579264",
+        ]
+    );
+    assert_eq!(
+        environment(Path::new("/sdk"), &test_packages()),
+        "export ANDROID_HOME=\"/sdk\"\nexport NDK_HOME=\"/sdk/ndk/26.3.11579264\"\n"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn bootstrap_installs_the_packages() {
+    use std::cell::RefCell;
+
+    /// Serves a command-line tools archive, and records the sdkmanager commands, installing
+    /// the probed files except those of `left_out`
+    struct FakeDownloads {
+        archive: PathBuf,
+        commands: RefCell<Vec<String>>,
+        left_out: Option<PathBuf>,
+    }
+
+    impl Downloads for FakeDownloads {
+        fn command_line_tools(&self) -> CargoResult<PathBuf> {
+            Ok(self.archive.clone())
+        }
+
+        fn sdkmanager(&self, cmd: &ProcessBuilder, accept_licenses: bool) -> CargoResult<()> {
+            assert!(accept_licenses);
+            assert!(Path::new(cmd.get_program()).exists());
+            let args = cmd
+                .get_args()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect::<Vec<_>>();
+            let sdk_dir = PathBuf::from(args[0].strip_prefix("--sdk_root=").unwrap());
+            if args[1] != "--licenses" {
+                for probe in test_packages().probes() {
+                    if Some(&probe) != self.left_out.as_ref() {
+                        fs::create_dir_all(sdk_dir.join(&probe).parent().unwrap()).unwrap();
+                        fs::write(sdk_dir.join(&probe), "").unwrap();
+                    }
+                }
+            }
+            self.commands.borrow_mut().push(args[1..].join(" "));
+            Ok(())
+        }
+    }
+
+    let root =
+        std::env::temp_dir().join(format!("cargo-quad-apk-bootstrap-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    let archive = root.join("commandlinetools.zip");
+    let mut zip = zip::ZipWriter::new(File::create(&archive).unwrap());
+    zip.start_file(
+        "cmdline-tools/bin/sdkmanager",
+        zip::write::FileOptions::default().unix_permissions(0o755),
+    )
+    .unwrap();
+    zip.write_all(b"#!/bin/sh\n").unwrap();
+    zip.finish().unwrap();
+
+    let gctx = GlobalContext::default().unwrap();
+    let downloads = FakeDownloads {
+        archive,
+        commands: RefCell::new(vec![]),
+        left_out: None,
+    };
+    let sdk_dir = root.join("sdk");
+    install(&gctx, &downloads, &sdk_dir, &test_packages(), true).unwrap();
+    assert_eq!(
+        downloads.commands.borrow().as_slice(),
+        [
+            "--licenses",
+            "platform-tools build-tools;34.0.0 platforms;android-31 platforms;android-34 \
+             ndk;26.3.11579264"
+        ]
+    );
+    assert!(missing_files(&sdk_dir, &test_packages()).is_empty());
+
+    // The command-line tools are only installed once, and packages which sdkmanager didn't
+    // install are reported
+    let downloads = FakeDownloads {
+        archive: root.join("missing.zip"),
+        commands: RefCell::new(vec![]),
+        left_out: Some(PathBuf::from("platforms/android-34/android.jar")),
+    };
+    let sdk_dir = root.join("sdk-without-34");
+    fs::create_dir_all(sdk_dir.join("cmdline-tools/latest/bin")).unwrap();
+    fs::write(sdkmanager_path(&sdk_dir), "").unwrap();
+    let err = install(&gctx, &downloads, &sdk_dir, &test_packages(), true).unwrap_err();
+    assert!(
+        err.to_string()
+            .ends_with("still lacks platforms/android-34/android.jar"),
+        "{}",
+        err
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -1,26 +1,58 @@
 // some really useful links:
 // https://stackoverflow.com/questions/59504840/create-jni-ndk-apk-only-command-line-without-gradle-ant-or-cmake/59533703#59533703
 //
+mod api_lint;
+mod apk;
+mod apk_writer;
+mod assets;
+mod attributes;
+mod build_env;
 mod compile;
+mod dex;
+mod elf;
+mod javac;
+mod keep_rules;
+mod locales;
 mod preprocessor;
+mod report;
+mod resources;
+mod signing;
 mod targets;
 pub mod tempfile;
+mod tool_output;
 mod util;
+mod xml;
 
+pub use self::util::active_features;
+
+use self::api_lint::ApiDatabase;
+use self::apk::{ApkBuilder, BuildTools, BundleTools, JavaCache, JavaTools, ProcessRunner};
+pub use self::assets::{list_source_assets, AssetManifest, MANIFEST_NAME as ASSET_MANIFEST_NAME};
+use self::build_env::BuildEnv;
 use self::compile::SharedLibraries;
-use crate::config::{AndroidConfig, AndroidTargetConfig};
+pub use self::compile::SharedLibrary;
+pub use self::report::{check_apk_features, BuildReport};
+use self::report::{ReportApk, ReportDex, ReportLibrary, ReportLink};
+use self::signing::SigningKey;
+use self::xml::Element;
+use crate::config::{
+    self, AndroidBuildTarget, AndroidConfig, AndroidFeature, AndroidIntentFilter,
+    AndroidTargetConfig, Framework,
+};
+use crate::error::{FailureKind, ResultExt};
+use crate::ops::cache::Cache;
 use anyhow::format_err;
 use cargo::{
-    core::{compiler, resolver, Target, TargetKind, Workspace},
+    core::{compiler, resolver, Target, TargetKind, Verbosity, Workspace},
     ops,
     util::CargoResult,
 };
-use cargo_util::ProcessBuilder;
+use cargo_util::{ProcessBuilder, Sha256};
 use clap::ArgMatches;
 
 use std::{
-    collections::BTreeMap,
-    fs::File,
+    collections::{btree_map::Entry, BTreeMap, BTreeSet, HashMap},
+    ffi::OsString,
     io::Write,
     path::{Path, PathBuf},
     {env, fs},
@@ -30,6 +62,41 @@ use std::{
 pub struct BuildResult {
     /// Mapping from target kind and target name to the built APK
     pub target_to_apk_map: BTreeMap<(TargetKind, String), PathBuf>,
+    /// APKs with the libraries of a single ABI, by target and ABI, when split APKs are built
+    pub split_apks: BTreeMap<(TargetKind, String), BTreeMap<&'static str, PathBuf>>,
+    /// App bundles built in place of the APKs with `--bundle`
+    pub bundles: BTreeMap<(TargetKind, String), PathBuf>,
+}
+
+impl BuildResult {
+    /// Returns the APK of a target to install on a device running `abi`: its split APK when
+    /// there is one, the APK with every ABI otherwise
+    pub fn apk_for_abi(&self, target: &(TargetKind, String), abi: &str) -> &PathBuf {
+        self.split_apks
+            .get(target)
+            .and_then(|splits| splits.get(abi))
+            .unwrap_or(&self.target_to_apk_map[target])
+    }
+
+    /// Returns the APKs of a target to install on a device running `abi`, every split APK with
+    /// `all_splits`, in the order of their versionCode as each one replaces the previous one
+    pub fn apks_to_install(
+        &self,
+        target: &(TargetKind, String),
+        abi: &str,
+        all_splits: bool,
+    ) -> Vec<&PathBuf> {
+        match self.split_apks.get(target) {
+            Some(splits) if all_splits => {
+                let mut splits = splits.iter().collect::<Vec<_>>();
+                splits.sort_by_key(|(abi, _)| {
+                    split_abi_code(AndroidBuildTarget::from_name(abi).ok())
+                });
+                splits.into_iter().map(|(_, path)| path).collect()
+            }
+            _ => vec![self.apk_for_abi(target, abi)],
+        }
+    }
 }
 
 pub fn build(
@@ -37,273 +104,774 @@ pub fn build(
     config: &AndroidConfig,
     options: &ArgMatches,
 ) -> CargoResult<BuildResult> {
+    build_flavor(workspace, config, options, &mut None)
+}
+
+/// Builds the APKs of each flavor, compiling the libraries once for the flavors built with the
+/// same libraries
+pub fn build_flavors(
+    workspace: &Workspace,
+    configs: &[AndroidConfig],
+    options: &ArgMatches,
+) -> CargoResult<()> {
+    // A group is packaged before the next one is compiled, as its libraries may be overwritten
+    for flavors in library_groups(configs) {
+        let mut libraries = None;
+        for config in flavors {
+            build_flavor(workspace, config, options, &mut libraries)?;
+        }
+    }
+    Ok(())
+}
+
+/// Splits the flavors into the groups built with the same libraries, those of the same features
+/// and package name suffix, as the package name is given to the libraries as
+/// `cargo_apk_package_name`. Groups are in the order of their first flavor.
+fn library_groups(configs: &[AndroidConfig]) -> Vec<Vec<&AndroidConfig>> {
+    let mut groups: Vec<(_, Vec<&AndroidConfig>)> = vec![];
+    for config in configs {
+        let key = (
+            config.features_fingerprint(),
+            config.flavor_package_name_suffix(),
+        );
+        match groups.iter_mut().find(|(group_key, _)| *group_key == key) {
+            Some((_, group)) => group.push(config),
+            None => groups.push((key, vec![config])),
+        }
+    }
+    groups.into_iter().map(|(_, group)| group).collect()
+}
+
+/// Builds the APKs of the flavor of `config`, with the libraries in `libraries` when another
+/// flavor compiled them already, along with the name of that flavor
+fn build_flavor(
+    workspace: &Workspace,
+    config: &AndroidConfig,
+    options: &ArgMatches,
+    libraries: &mut Option<(String, SharedLibraries)>,
+) -> CargoResult<BuildResult> {
+    if let Some(flavor) = &config.flavor {
+        workspace.gctx().shell().status("Flavor", flavor)?;
+    }
+    let no_apk = options.get_flag("no-apk");
+    let bundle = matches!(options.try_get_one::<bool>("bundle"), Ok(Some(true)));
+
+    // Building doesn't need adb, but installing the result will
+    if !no_apk && config::find_adb(&config.sdk_path).is_none() {
+        workspace.gctx().shell().warn(format!(
+            "Android SDK at `{}` has no platform-tools, the APKs can't be installed until they \
+             are installed with `sdkmanager \"platform-tools\"`",
+            config.sdk_path.display()
+        ))?;
+    }
+
+    // Told once, until the defaults change
+    if let Some(notice) = config::defaults_notice(&config.defaulted_keys) {
+        let artifacts_dir = util::root_build_directory(workspace, false)
+            .parent()
+            .unwrap()
+            .to_owned();
+        fs::create_dir_all(&artifacts_dir)?;
+        if util::write_if_changed(&artifacts_dir.join("defaults-notice.txt"), &notice)? {
+            workspace.gctx().shell().note(notice)?;
+        }
+    }
+
+    // Fail before compiling when the APKs could not be packaged anyway
+    let tools = if no_apk {
+        None
+    } else {
+        Some(BuildTools::find(config)?)
+    };
+    let bundle_tools = if bundle && !no_apk {
+        Some(BundleTools::find(workspace.gctx(), config)?)
+    } else {
+        None
+    };
+    let sign = !options.get_flag("nosign");
+    // Checked before compiling, so that a missing password doesn't fail a finished build
+    let release_key = if sign && config.release {
+        config
+            .signing
+            .as_ref()
+            .map(|signing| SigningKey::release(signing, |name| env::var_os(name).is_some()))
+            .transpose()
+            .failure_kind(FailureKind::Signing)?
+    } else {
+        None
+    };
+
     let root_source_path = workspace.root();
     let root_build_dir = util::get_root_build_directory(workspace, config);
-    let miniquad_root_path = util::find_package_root_path(workspace, config, "miniquad");
-    let java_files = util::collect_java_files(workspace, config);
-    let shared_libraries = compile::build_shared_libraries(
-        workspace,
-        config,
-        options,
-        &root_build_dir,
-        &miniquad_root_path,
+    // Apps without framework are started by NativeActivity and have no Java code
+    let miniquad_root_path = match config.framework {
+        Framework::Miniquad => Some(util::find_package_root_path(workspace, config, "miniquad")?),
+        Framework::None => {
+            native_activity_check(config)?;
+            None
+        }
+    };
+    let java_files = if no_apk || miniquad_root_path.is_none() {
+        util::JavaFiles::default()
+    } else {
+        util::collect_java_files(workspace, config)?
+    };
+    for warning in runtime_jar_api_warnings(config, &java_files) {
+        workspace.gctx().shell().warn(warning)?;
+    }
+    let api_levels = util::effective_api_levels(config)?;
+    if let Some(warning) = util::api_levels_warning(&api_levels) {
+        workspace.gctx().shell().warn(warning)?;
+    }
+    let shared_libraries = match libraries {
+        Some((flavor, shared_libraries)) => {
+            workspace.gctx().shell().status(
+                "Reusing",
+                format!(
+                    "the libraries of flavor `{}`, built with the same features",
+                    flavor
+                ),
+            )?;
+            shared_libraries.clone()
+        }
+        None => {
+            // Shared by the flavors, which are only told apart by their APKs
+            let shared_libraries = compile::build_shared_libraries(
+                workspace,
+                config,
+                options,
+                &util::root_build_directory(workspace, config.release),
+                miniquad_root_path.as_ref(),
+                &api_levels,
+            )?;
+            if let Some(flavor) = &config.flavor {
+                *libraries = Some((flavor.clone(), shared_libraries.clone()));
+            }
+            shared_libraries
+        }
+    };
+    workspace.gctx().shell().verbose(|shell| {
+        for (_, libraries) in shared_libraries.shared_libraries.iter_all() {
+            for library in libraries {
+                if let Some(build_id) = elf::Elf::read(&library.path)
+                    .ok()
+                    .and_then(|elf| elf.build_id())
+                {
+                    shell.status(
+                        "Build id",
+                        format!(
+                            "{} ({}) {}",
+                            library.filename,
+                            library.abi.android_abi(),
+                            build_id
+                        ),
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    })?;
+    workspace.gctx().shell().status(
+        "Features",
+        report::render_features(&config.cargo_features, config.no_default_features),
     )?;
-    let sign = !options.get_flag("nosign");
 
-    build_apks(
+    let tools = match tools {
+        Some(tools) => tools,
+        None => {
+            let out_dir = match options.get_one::<String>("out-dir") {
+                Some(out_dir) => workspace.gctx().cwd().join(out_dir),
+                None => root_build_dir.join("lib"),
+            };
+            output_libraries(
+                workspace,
+                config,
+                &root_build_dir,
+                &out_dir,
+                shared_libraries,
+            )?;
+            return Ok(BuildResult {
+                target_to_apk_map: BTreeMap::new(),
+                split_apks: BTreeMap::new(),
+                bundles: BTreeMap::new(),
+            });
+        }
+    };
+
+    let mut split_per_abi = config.split_apks || options.get_flag("split-per-abi");
+    if split_per_abi && bundle_tools.is_some() {
+        workspace.gctx().shell().warn(
+            "split APKs are not built with `--bundle`, stores generate the APKs of each ABI \
+             from the app bundle",
+        )?;
+        split_per_abi = false;
+    }
+    let build_result = build_apks(
+        workspace,
         config,
         root_source_path,
         &root_build_dir,
+        &tools,
+        bundle_tools.as_ref(),
         shared_libraries,
         java_files,
         sign,
-        &miniquad_root_path,
-    )
+        release_key,
+        options.get_flag("regenerate-debug-key"),
+        split_per_abi,
+        miniquad_root_path.as_ref(),
+    )?;
+
+    // APKs of renamed or removed targets would otherwise linger next to the fresh ones
+    let package = workspace
+        .members()
+        .find(|package| *package.name() == config.cargo_package_name)
+        .ok_or_else(|| format_err!("Unable to find package `{}`", config.cargo_package_name))?;
+    let targets = package
+        .targets()
+        .iter()
+        .map(|target| (target.kind().clone(), target.name().to_owned()))
+        .collect::<Vec<_>>();
+    // Only `build` knows `--prune-stale`
+    let prune = matches!(options.try_get_one::<bool>("prune-stale"), Ok(Some(true)));
+    for stale_apk in remove_stale_apks(
+        config,
+        &targets,
+        &build_result,
+        &root_build_dir.join("apk"),
+        prune,
+    )? {
+        workspace.gctx().shell().status(
+            if prune { "Removed" } else { "Moved" },
+            format!(
+                "stale APK {}{}",
+                stale_apk.display(),
+                if prune { "" } else { " to old/" }
+            ),
+        )?;
+    }
+
+    if let Some(out_dir) = options.get_one::<String>("out-dir") {
+        let out_dir = workspace.gctx().cwd().join(out_dir);
+        let apks = build_result
+            .target_to_apk_map
+            .iter()
+            .chain(
+                build_result
+                    .split_apks
+                    .iter()
+                    .flat_map(|(target, splits)| splits.values().map(move |path| (target, path))),
+            )
+            .chain(&build_result.bundles);
+        for path in copy_apks(apks, &out_dir)? {
+            workspace.gctx().shell().status("Copied", path.display())?;
+        }
+    }
+
+    Ok(build_result)
 }
 
-fn build_apks(
+/// Moves the APKs of `final_apk_dir` and its `examples` which belong to none of the `targets` to
+/// `old/`, or deletes them with `prune`. Split APKs are only kept when this build made them.
+fn remove_stale_apks(
     config: &AndroidConfig,
-    root_source_path: &Path,
-    root_build_dir: &PathBuf,
-    shared_libraries: SharedLibraries,
-    java_files: util::JavaFiles,
-    sign: bool,
-    miniquad_root_path: &PathBuf,
-) -> CargoResult<BuildResult> {
-    let main_activity_path = miniquad_root_path.join("java").join("MainActivity.java");
-    let quad_native_path = miniquad_root_path.join("java").join("QuadNative.java");
+    targets: &[(TargetKind, String)],
+    build_result: &BuildResult,
+    final_apk_dir: &Path,
+    prune: bool,
+) -> CargoResult<Vec<PathBuf>> {
+    let known_targets = |kind: TargetKind| {
+        targets
+            .iter()
+            .filter(|(target_kind, _)| *target_kind == kind)
+            .map(|(_, name)| apk_stem(config, name))
+            .chain(
+                build_result
+                    .split_apks
+                    .iter()
+                    .filter(|((split_kind, _), _)| *split_kind == kind)
+                    .flat_map(|(_, splits)| splits.values())
+                    .map(|path| path.file_stem().unwrap().to_string_lossy().into_owned()),
+            )
+            .collect::<BTreeSet<_>>()
+    };
+    let mut stale_apks =
+        util::remove_stale_apks(final_apk_dir, &known_targets(TargetKind::Bin), prune)?;
+    stale_apks.extend(util::remove_stale_apks(
+        &final_apk_dir.join("examples"),
+        &known_targets(TargetKind::ExampleBin),
+        prune,
+    )?);
+    Ok(stale_apks)
+}
 
-    // Create directory to hold final APKs which are signed using the debug key
-    let final_apk_dir = root_build_dir.join("apk");
-    fs::create_dir_all(&final_apk_dir)?;
+#[test]
+fn stale_apks_of_previous_builds() {
+    let root = std::env::temp_dir().join(format!("cargo-quad-apk-prune-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let target_directory = root.join("bin").join("app");
+    let final_apk_dir = root.join("apk");
+    fs::create_dir_all(&target_directory).unwrap();
+    fs::create_dir_all(final_apk_dir.join("examples")).unwrap();
+
+    let config = crate::config::from_metadata("");
+    let target_config = config.resolve((TargetKind::Bin, "app".to_owned())).unwrap();
+    let tools = BuildTools::find(&config).unwrap();
+    let runner = apk::MockSdk {
+        commands: Default::default(),
+    };
+    let builder = ApkBuilder {
+        config: &config,
+        target_config: &target_config,
+        target_name: "app",
+        target_directory: &target_directory,
+        tools: &tools,
+        java_tools: None,
+        java_cache: None,
+        runner: &runner,
+    };
+    let java_files = util::JavaFiles::default();
+    builder.write_manifest(&java_files).unwrap();
+    let assets = builder.stage_assets(None).unwrap();
+    let resources = builder.package_resources(&assets, &java_files).unwrap();
+    let apk = builder
+        .write_apk(resources.apk, None, &[], final_apk_dir.join("app.apk"))
+        .unwrap();
+
+    // The APKs of the renamed `demo` bin, of a removed example and of a split which this build
+    // didn't make are left over from previous builds
+    for stale in &["demo.apk", "app-armeabi-v7a.apk", "examples/quad.apk"] {
+        fs::write(final_apk_dir.join(stale), "").unwrap();
+    }
+    fs::write(final_apk_dir.join("examples/triangle.apk"), "").unwrap();
+    let targets = vec![
+        (TargetKind::Bin, "app".to_owned()),
+        (TargetKind::ExampleBin, "triangle".to_owned()),
+    ];
+    let build_result = BuildResult {
+        target_to_apk_map: vec![(targets[0].clone(), apk.0.clone())]
+            .into_iter()
+            .collect(),
+        split_apks: BTreeMap::new(),
+        bundles: BTreeMap::new(),
+    };
 
-    // Paths of created APKs
-    let mut target_to_apk_map = BTreeMap::new();
+    assert_eq!(
+        remove_stale_apks(&config, &targets, &build_result, &final_apk_dir, false).unwrap(),
+        vec![
+            PathBuf::from("app-armeabi-v7a.apk"),
+            PathBuf::from("demo.apk"),
+            PathBuf::from("quad.apk"),
+        ]
+    );
+    assert!(final_apk_dir.join("app.apk").exists());
+    assert!(final_apk_dir.join("old/demo.apk").exists());
+    assert!(final_apk_dir.join("examples/old/quad.apk").exists());
+    assert!(final_apk_dir.join("examples/triangle.apk").exists());
+
+    // With `--prune-stale` they are deleted, and the split APKs of this build are kept
+    fs::write(final_apk_dir.join("demo.apk"), "").unwrap();
+    fs::write(final_apk_dir.join("app-arm64-v8a.apk"), "").unwrap();
+    let build_result = BuildResult {
+        split_apks: vec![(
+            targets[0].clone(),
+            vec![("arm64-v8a", final_apk_dir.join("app-arm64-v8a.apk"))]
+                .into_iter()
+                .collect(),
+        )]
+        .into_iter()
+        .collect(),
+        ..build_result
+    };
+    assert_eq!(
+        remove_stale_apks(&config, &targets, &build_result, &final_apk_dir, true).unwrap(),
+        vec![PathBuf::from("demo.apk")]
+    );
+    assert!(!final_apk_dir.join("demo.apk").exists());
+    assert!(final_apk_dir.join("app-arm64-v8a.apk").exists());
 
-    // Build an APK for each cargo target
-    for (target, shared_libraries) in shared_libraries.shared_libraries.iter_all() {
-        let target_directory = util::get_target_directory(root_build_dir, target)?;
+    fs::remove_dir_all(&root).unwrap();
+}
 
-        fs::create_dir_all(&target_directory)?;
+/// Compiles the shared libraries of the targets without packaging them, for `hot`, returning
+/// the libraries the APK of each target would package
+pub fn build_libraries(
+    workspace: &Workspace,
+    config: &AndroidConfig,
+    options: &ArgMatches,
+) -> CargoResult<BTreeMap<(TargetKind, String), Vec<SharedLibrary>>> {
+    let root_build_dir = util::get_root_build_directory(workspace, config);
+    let miniquad_root_path = match config.framework {
+        Framework::Miniquad => Some(util::find_package_root_path(workspace, config, "miniquad")?),
+        Framework::None => None,
+    };
+    let api_levels = util::effective_api_levels(config)?;
+    let shared_libraries = compile::build_shared_libraries(
+        workspace,
+        config,
+        options,
+        &root_build_dir,
+        miniquad_root_path.as_ref(),
+        &api_levels,
+    )?;
+    shared_libraries
+        .shared_libraries
+        .iter_all()
+        .map(|(target, libraries)| {
+            Ok((
+                (target.kind().clone(), target.name().to_owned()),
+                packaged_libraries(target.name(), libraries)?,
+            ))
+        })
+        .collect()
+}
 
-        // Determine Target Configuration
-        let target_config = config.resolve((target.kind().to_owned(), target.name().to_owned()))?;
+/// Copies the APKs to `out_dir`, those of examples to its `examples` directory as in the build
+/// directory, and returns the paths of the copies
+fn copy_apks<'a>(
+    apks: impl IntoIterator<Item = (&'a (TargetKind, String), &'a PathBuf)>,
+    out_dir: &Path,
+) -> CargoResult<Vec<PathBuf>> {
+    let mut copies = vec![];
+    for ((kind, _), apk) in apks {
+        let dir = match kind {
+            TargetKind::ExampleBin => out_dir.join("examples"),
+            _ => out_dir.to_owned(),
+        };
+        let path = dir.join(apk.file_name().unwrap());
+        fs::create_dir_all(&dir)
+            .and_then(|()| fs::copy(apk, &path))
+            .map_err(|err| {
+                format_err!(
+                    "Unable to copy `{}` to the `--out-dir` directory `{}`: {}",
+                    apk.display(),
+                    out_dir.display(),
+                    err
+                )
+            })?;
+        copies.push(path);
+    }
+    Ok(copies)
+}
 
-        //
-        // Run commands to produce APK
-        //
-        build_manifest(
-            &target_directory,
-            &config,
-            &target_config,
-            &target,
-            &java_files,
-        )?;
+#[test]
+fn apks_copied_to_out_dir() {
+    let dir = std::env::temp_dir().join(format!("cargo-quad-apk-out-dir-{}", std::process::id()));
+    let build_dir = dir.join("apk");
+    fs::create_dir_all(build_dir.join("examples")).unwrap();
+    fs::write(build_dir.join("app.apk"), "app").unwrap();
+    fs::write(build_dir.join("examples/demo.apk"), "demo").unwrap();
+    let apks: BTreeMap<_, _> = vec![
+        (
+            (TargetKind::Bin, "app".to_owned()),
+            build_dir.join("app.apk"),
+        ),
+        (
+            (TargetKind::ExampleBin, "demo".to_owned()),
+            build_dir.join("examples/demo.apk"),
+        ),
+    ]
+    .into_iter()
+    .collect();
+
+    let out_dir = dir.join("out");
+    assert_eq!(
+        copy_apks(&apks, &out_dir).unwrap(),
+        vec![out_dir.join("app.apk"), out_dir.join("examples/demo.apk")]
+    );
+    assert_eq!(fs::read_to_string(out_dir.join("app.apk")).unwrap(), "app");
+    assert_eq!(
+        fs::read_to_string(out_dir.join("examples/demo.apk")).unwrap(),
+        "demo"
+    );
 
-        let build_tools_path = config
-            .sdk_path
-            .join("build-tools")
-            .join(&config.build_tools_version);
-        let aapt_path = build_tools_path.join("aapt");
-        let d8_path = build_tools_path.join("d8");
-        let zipalign_path = build_tools_path.join("zipalign");
-
-        // Create unaligned APK which includes resources and assets
-        let unaligned_apk_name = format!("{}_unaligned.apk", target.name());
-        let unaligned_apk_path = target_directory.join(&unaligned_apk_name);
-        if unaligned_apk_path.exists() {
-            std::fs::remove_file(unaligned_apk_path)
-                .map_err(|e| format_err!("Unable to delete APK file. {}", e))?;
-        }
-
-        let obj_dir = target_directory.join("build").join("obj");
-        fs::create_dir_all(&obj_dir)?;
-
-        let gen_dir = target_directory.join("build").join("gen");
-        fs::create_dir_all(&gen_dir)?;
-
-        let package_name = target_config.package_name.replace("-", "_");
-        let library_name = target_config
-            .package_name
-            .split(".")
-            .last()
-            .unwrap()
-            .clone();
+    // The destination is a file
+    let error = copy_apks(&apks, &out_dir.join("app.apk")).unwrap_err();
+    assert!(
+        error.to_string().starts_with("Unable to copy `"),
+        "{}",
+        error
+    );
 
-        let mut r_java_path = gen_dir.clone();
-        for file_part in package_name.split('.') {
-            r_java_path = r_java_path.join(file_part);
-        }
+    fs::remove_dir_all(&dir).unwrap();
+}
 
-        let mut java_dir = target_directory.clone();
-        for file_part in package_name.split('.') {
-            java_dir = java_dir.join(file_part);
-        }
-        fs::create_dir_all(&java_dir)?;
+/// Returns an `apksigner` command of the build-tools of the SDK
+pub fn apksigner(config: &AndroidConfig) -> CargoResult<ProcessBuilder> {
+    Ok(util::script_process(BuildTools::find(config)?.apksigner))
+}
+
+/// Returns the directory of the build artifacts for the current debug/release configuration
+pub fn root_build_directory(workspace: &Workspace, config: &AndroidConfig) -> PathBuf {
+    util::get_root_build_directory(workspace, config)
+}
 
-        let target_activity_path = java_dir.join("MainActivity.java");
+/// Returns the build directory of debug or release builds, for commands which don't load the
+/// Android configuration
+pub fn profile_build_directory(workspace: &Workspace, release: bool) -> PathBuf {
+    util::root_build_directory(workspace, release)
+}
 
-        let java_src = fs::read_to_string(&main_activity_path)
-            .expect("Something went wrong reading miniquad's MainActivity.java file");
+/// Returns the path of ndk-stack, which symbolicates native crashes
+pub fn ndk_stack(config: &AndroidConfig) -> CargoResult<PathBuf> {
+    util::find_ndk_stack(config)
+}
 
-        let java_src = preprocessor::preprocess_main_activity(
-            &java_src,
-            &package_name,
-            &library_name,
-            &java_files.main_activity_injects,
-        );
+/// Returns the directory of the libraries of the targets for a build target as linked, before
+/// the copies packaged into the APKs are stripped
+pub fn symbol_directory(root_build_dir: &Path, build_target: AndroidBuildTarget) -> PathBuf {
+    root_build_dir
+        .join("symbols")
+        .join(build_target.android_abi())
+}
 
-        fs::write(&target_activity_path, java_src)?;
+/// Returns a hint for each library of a symbol directory missing the symbols or the debug info
+/// naming the functions, files and lines of the frames of crashes
+pub fn missing_symbols_hints(symbol_dir: &Path) -> Vec<String> {
+    let mut libraries = match fs::read_dir(symbol_dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| {
+                path.extension()
+                    .map_or(false, |extension| extension == "so")
+            })
+            .collect::<Vec<_>>(),
+        Err(_) => return vec![],
+    };
+    libraries.sort();
+    libraries
+        .iter()
+        .filter_map(|path| {
+            let elf = elf::Elf::read(path).ok()?;
+            let name = path.file_name()?.to_string_lossy();
+            if !elf.has_section(".symtab") {
+                Some(format!(
+                    "`{}` has no symbols, the frames of its crashes can't be named, build with \
+                     `--nostrip`",
+                    name
+                ))
+            } else if !elf.has_section(".debug_info") {
+                Some(format!(
+                    "`{}` has no debug info, the frames of its crashes get no file and line, \
+                     set `debug = true` in the cargo profile",
+                    name
+                ))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
 
-        let target_quad_native_path = target_directory.join("quad_native").join("QuadNative.java");
-        fs::create_dir_all(target_quad_native_path.parent().unwrap())?;
-        fs::copy(&quad_native_path, &target_quad_native_path)?;
+/// Reads the report of the last build for the current debug/release configuration
+pub fn last_build_report(
+    workspace: &Workspace,
+    config: &AndroidConfig,
+) -> CargoResult<BuildReport> {
+    let root_build_dir = util::get_root_build_directory(workspace, config);
+    BuildReport::read(&root_build_dir)
+}
 
-        for (global_path, local_path) in &java_files.java_files {
-            let java_src = fs::read_to_string(global_path)
-                .expect("Something went wrong reading miniquad's MainActivity.java file");
+/// Reads the report of the build before the last one, whose APKs are kept for comparisons
+pub fn previous_build_report(workspace: &Workspace, release: bool) -> CargoResult<BuildReport> {
+    let root_build_dir = util::root_build_directory(workspace, release);
+    BuildReport::read_previous(&root_build_dir)
+}
 
-            let java_src = java_src.replace("TARGET_PACKAGE_NAME", &package_name);
-            let java_src = java_src.replace("LIBRARY_NAME", &library_name);
+fn build_apks(
+    workspace: &Workspace,
+    config: &AndroidConfig,
+    root_source_path: &Path,
+    root_build_dir: &PathBuf,
+    tools: &BuildTools,
+    bundle_tools: Option<&BundleTools>,
+    shared_libraries: SharedLibraries,
+    java_files: util::JavaFiles,
+    sign: bool,
+    release_key: Option<SigningKey>,
+    regenerate_debug_key: bool,
+    split_per_abi: bool,
+    miniquad_root_path: Option<&PathBuf>,
+) -> CargoResult<BuildResult> {
+    // Create directory to hold final APKs, signed with the release key when one is configured
+    // and the debug key otherwise
+    let final_apk_dir = root_build_dir.join("apk");
+    fs::create_dir_all(&final_apk_dir)?;
 
-            let local_path = local_path.strip_prefix("java/")?;
+    let runner = ProcessRunner;
 
-            let target_path = target_directory.join(&local_path);
-            let dir_path = local_path.parent().unwrap();
-            fs::create_dir_all(target_directory.join(dir_path))?;
+    BuildReport::keep_previous(root_build_dir)?;
 
-            fs::write(&target_path, java_src)?;
+    // Probe the packaging tools once, their versions go to the build report
+    let mut report = BuildReport {
+        overrides: config.overrides.clone(),
+        features: config.cargo_features.iter().cloned().collect(),
+        no_default_features: config.no_default_features,
+        flavor: config.flavor.clone(),
+        ..BuildReport::default()
+    };
+    let mut version_cmds = vec![
+        (
+            "aapt",
+            ProcessBuilder::new(&tools.aapt).arg("version").clone(),
+        ),
+        (
+            "d8",
+            ProcessBuilder::new(&tools.d8).arg("--version").clone(),
+        ),
+        (
+            "apksigner",
+            util::script_process(&tools.apksigner)
+                .arg("--version")
+                .clone(),
+        ),
+    ];
+    if let Ok(javac_path) = find_java_executable(apk::JAVAC_FILENAME) {
+        version_cmds.push((
+            "javac",
+            ProcessBuilder::new(javac_path).arg("-version").clone(),
+        ));
+    }
+    for (tool, cmd) in version_cmds {
+        if let Some(version) = util::tool_version(&cmd) {
+            if workspace.gctx().extra_verbose() {
+                drop(writeln!(
+                    workspace.gctx().shell().err(),
+                    "{} version: {}",
+                    tool,
+                    version
+                ));
+            }
+            report.tool_versions.insert(tool.to_owned(), version);
         }
+    }
 
-        let res_dir = target_directory.join("res").join("layout");
-        fs::create_dir_all(&res_dir)?;
-        let res_file = res_dir.join("main.xml");
-        let mut res_file = File::create(&res_file)?;
-        writeln!(
-            res_file,
-            "{}",
-            r##"<?xml version="1.0" encoding="utf-8"?>
-        <LinearLayout xmlns:android="http://schemas.android.com/apk/res/android"
-            android:orientation="vertical"
-            android:layout_width="fill_parent"
-            android:layout_height="fill_parent"
-            >
-        </LinearLayout>
-        "##
-        );
+    report_links(workspace, &mut report, &shared_libraries)?;
+
+    // Paths of created APKs
+    let mut target_to_apk_map = BTreeMap::new();
+    let mut split_apks = BTreeMap::new();
+    let mut bundles = BTreeMap::new();
+    let mut java_tools = None;
+    // Without a cache directory, javac and d8 always run
+    let java_cache = Cache::open().ok().map(|cache| JavaCache {
+        gctx: workspace.gctx(),
+        cache,
+    });
+    // Loaded when the first Java code is linted, `Some(None)` without a database in the SDK
+    let mut api_database = None;
+    let mut keystore = None;
+    // Collected once, when the first APK embeds it
+    let mut build_env = None;
+    let features_fingerprint = config.features_fingerprint();
 
-        let mut aapt_package_cmd = ProcessBuilder::new(&aapt_path);
-        aapt_package_cmd
-            .arg("package")
-            .arg("-F")
-            .arg(&unaligned_apk_name)
-            .arg("-m")
-            .arg("-J")
-            .arg("build/gen")
-            .arg("-M")
-            .arg("AndroidManifest.xml")
-            .arg("-S")
-            .arg("res")
-            .arg("-I")
-            .arg(&config.android_jar_path);
+    // Build an APK for each cargo target
+    for (target, shared_libraries) in shared_libraries.shared_libraries.iter_all() {
+        let shared_libraries = &packaged_libraries(target.name(), shared_libraries)?;
+        let target_directory = util::get_target_directory(root_build_dir, target)?;
 
-        if let Some(res_path) = target_config.res_path {
-            aapt_package_cmd.arg("-S").arg(res_path);
+        fs::create_dir_all(&target_directory)?;
+        if util::begin_generation(&target_directory)? {
+            workspace.gctx().shell().verbose(|shell| {
+                shell.status(
+                    "Regenerating",
+                    format!(
+                        "`{}`, the previous build stopped while generating its files",
+                        target_directory.display()
+                    ),
+                )
+            })?;
         }
 
-        // Link assets
-        if let Some(assets_path) = &target_config.assets_path {
-            aapt_package_cmd.arg("-A").arg(assets_path);
+        // Determine Target Configuration
+        let mut target_config =
+            config.resolve((target.kind().to_owned(), target.name().to_owned()))?;
+        // The splits would otherwise share the versionCode of the APK with every ABI
+        if split_per_abi {
+            target_config.version_code = split_version_code(target_config.version_code, None)?;
         }
-
-        aapt_package_cmd.cwd(&target_directory).exec()?;
-
-        let mut classpath = config.android_jar_path.to_str().unwrap().to_string();
-        for (comptime_jar, _) in &java_files.comptime_jar_files {
-            classpath.push_str(":");
-            classpath.push_str(comptime_jar.to_str().unwrap());
+        for warning in process_warnings(&target_config) {
+            workspace.gctx().shell().warn(warning)?;
         }
-
-        let javac_filename = if cfg!(target_os = "windows") {
-            "javac.exe"
-        } else {
-            "javac"
-        };
-        let javac_path = find_java_executable(javac_filename)?;
-
-        let rt_jar_path = find_rt_jar()?;
-
-        let mut java_cmd = ProcessBuilder::new(javac_path);
-        java_cmd
-            .arg("-source")
-            .arg("1.7")
-            .arg("-target")
-            .arg("1.7")
-            .arg("-Xlint:deprecation")
-            .arg("-bootclasspath")
-            .arg(rt_jar_path)
-            .arg("-classpath")
-            .arg(&classpath)
-            .arg("-d")
-            .arg("build/obj");
-        java_cmd.arg("quad_native/QuadNative.java");
-        for (_, java_file) in &java_files.java_files {
-            let java_file = java_file.strip_prefix("java/")?;
-            java_cmd.arg(&java_file);
-        }
-        java_cmd
-            .arg(r_java_path.join("R.java"))
-            .arg(target_activity_path);
-
-        java_cmd.cwd(&target_directory).exec()?;
-
-        let mut d8_cmd = ProcessBuilder::new(&d8_path);
-        for class_file in glob::glob(target_directory.join("**/*.class").to_str().unwrap()).unwrap()
-        {
-            let file = class_file.unwrap();
-            d8_cmd.arg(file.to_str().unwrap());
+        for (element, attributes) in &[
+            (
+                attributes::Element::Application,
+                &target_config.application_attributes,
+            ),
+            (
+                attributes::Element::Activity,
+                &target_config.activity_attributes,
+            ),
+        ] {
+            for warning in
+                attributes::check_attributes(config, &target_config, *element, attributes)?
+            {
+                workspace.gctx().shell().warn(warning)?;
+            }
         }
-        for (runtime_jar, _) in &java_files.runtime_jar_files {
-            d8_cmd.arg(&runtime_jar);
+        for warning in uses_features(config, &target_config, &java_files).1 {
+            workspace.gctx().shell().warn(warning)?;
         }
-        // otherwise "Type `java.lang.System` was not found" error
-        d8_cmd.arg("--no-desugaring");
-        d8_cmd.arg("--min-api").arg("26");
-
-        d8_cmd.cwd(&target_directory).exec()?;
-
-        ProcessBuilder::new(&aapt_path)
-            .arg("add")
-            .arg(&unaligned_apk_name)
-            .arg("classes.dex")
-            .cwd(&target_directory)
-            .exec()?;
-
-        // Add shared libraries to the APK
-        for shared_library in shared_libraries {
-            // Copy the shared library to the appropriate location in the target directory and with the appropriate name
-            // Note: that the type of slash used matters. This path is passed to aapt and the shared library
-            // will not load if backslashes are used.
-            let so_path = format!(
-                "lib/{}/{}",
-                &shared_library.abi.android_abi(),
-                shared_library.filename
+        if let Some(warning) = legacy_storage_warning(config, &target_config) {
+            workspace.gctx().shell().warn(warning)?;
+        }
+        if let Some(warning) = profileable_warning(config, &target_config) {
+            workspace.gctx().shell().warn(warning)?;
+        }
+        for warning in locales::locale_warnings(config, &target_config)? {
+            workspace.gctx().shell().warn(warning)?;
+        }
+        let dependency_res_dirs = java_files
+            .res_dirs
+            .iter()
+            .map(|(_, res_dir)| res_dir.clone())
+            .collect::<Vec<_>>();
+        let mut problems = vec![];
+        for res_path in target_config.res_paths.iter().chain(&dependency_res_dirs) {
+            problems.extend(
+                resources::validate_res_dir(res_path)?
+                    .iter()
+                    .map(|problem| problem.to_string()),
             );
+        }
+        report_resource_problems(workspace, &target_config, "Invalid resources", &problems)?;
+        let duplicates =
+            resources::duplicate_resources(&target_config.res_paths, &dependency_res_dirs)?;
+        if !duplicates.is_empty() {
+            return Err(format_err!(
+                "Resources declared more than once:\n  {}",
+                duplicates.join("\n  ")
+            ));
+        }
+        if bundle_tools.is_some() && !target_config.aapt_args.is_empty() {
+            workspace.gctx().shell().warn(format!(
+                "the aapt arguments of target '{}' are not passed to aapt2, which links the \
+                 resources of app bundles",
+                target.name()
+            ))?;
+        }
+        if config.release && target_config.test_only {
+            workspace.gctx().shell().warn(format!(
+                "release APK of target '{}' has `test_only` set, it can only be installed with \
+                 `adb install -t` and will be rejected by stores",
+                target.name()
+            ))?;
+        }
+        let missing_activities = missing_activity_classes(&target_config, &java_files);
+        if !missing_activities.is_empty() {
+            return Err(format_err!(
+                "Activities of target '{}' not found among the Java files: {}",
+                target.name(),
+                missing_activities.join(", ")
+            ));
+        }
 
-            let target_shared_object_path = target_directory.join(&so_path);
-            fs::create_dir_all(target_shared_object_path.parent().unwrap())?;
-            fs::copy(&shared_library.path, target_shared_object_path)?;
-
-            // Add to the APK
-            ProcessBuilder::new(&aapt_path)
-                .arg("add")
-                .arg(&unaligned_apk_name)
-                .arg(so_path)
-                .cwd(&target_directory)
-                .exec()?;
+        // The JDK is looked up once, when the first APK needs it
+        if miniquad_root_path.is_some() && java_tools.is_none() {
+            java_tools = Some(JavaTools::find()?);
         }
 
-        // Determine the directory in which to place the aligned and signed APK
+        // Determine the directory in which to place the aligned and signed APK or bundle
         let target_apk_directory = match target.kind() {
             TargetKind::Bin => final_apk_dir.clone(),
             TargetKind::ExampleBin => final_apk_dir.join("examples"),
@@ -311,103 +879,853 @@ fn build_apks(
         };
         fs::create_dir_all(&target_apk_directory)?;
 
-        // Align apk
-        let final_apk_path = target_apk_directory.join(format!("{}.apk", target.name()));
-        ProcessBuilder::new(&zipalign_path)
-            .arg("-f")
-            .arg("-v")
-            .arg("4")
-            .arg(&unaligned_apk_name)
-            .arg(&final_apk_path)
-            .cwd(&target_directory)
-            .exec()?;
-
-        // Find or generate a debug keystore for signing the APK
-        // We use the same debug keystore as used by the Android SDK. If it does not exist,
-        // then we create it using keytool which is part of the JRE/JDK
-        let android_directory = dirs::home_dir()
-            .ok_or_else(|| format_err!("Unable to determine home directory"))?
-            .join(".android");
-        fs::create_dir_all(&android_directory)?;
-        let keystore_path = android_directory.join("debug.keystore");
-        if !keystore_path.exists() {
-            // Generate key
-            let keytool_filename = if cfg!(target_os = "windows") {
-                "keytool.exe"
-            } else {
-                "keytool"
-            };
+        // Uploaded to Play along with the APK or bundle, to symbolicate the crashes of the stripped
+        // libraries
+        if config.release {
+            let symbols_path = target_apk_directory.join(format!(
+                "{}-native-debug-symbols.zip",
+                apk_stem(config, target.name())
+            ));
+            if write_native_debug_symbols(
+                &util::root_build_directory(workspace, config.release),
+                shared_libraries,
+                &symbols_path,
+            )? {
+                workspace
+                    .gctx()
+                    .shell()
+                    .verbose(|shell| shell.status("Symbols", symbols_path.display().to_string()))?;
+            }
+        }
 
-            let keytool_path = find_java_executable(keytool_filename)?;
-            ProcessBuilder::new(keytool_path)
-                .arg("-genkey")
-                .arg("-v")
-                .arg("-keystore")
-                .arg(&keystore_path)
-                .arg("-storepass")
-                .arg("android")
-                .arg("-alias")
-                .arg("androidebugkey")
-                .arg("-keypass")
-                .arg("android")
-                .arg("-dname")
-                .arg("CN=Android Debug,O=Android,C=US")
-                .arg("-keyalg")
-                .arg("RSA")
-                .arg("-keysize")
-                .arg("2048")
-                .arg("-validity")
-                .arg("10000")
-                .cwd(root_build_dir)
-                .exec()?;
-        }
-
-        if sign {
-            // Sign the APK with the development certificate
-            util::script_process(
-                build_tools_path.join(format!("apksigner{}", util::EXECUTABLE_SUFFIX_BAT)),
-            )
-            .arg("sign")
-            .arg("--ks")
-            .arg(keystore_path)
-            .arg("--ks-pass")
-            .arg("pass:android")
-            .arg(&final_apk_path)
-            .cwd(&target_directory)
-            .exec()?;
-        }
-        target_to_apk_map.insert(
-            (target.kind().to_owned(), target.name().to_owned()),
-            final_apk_path,
+        // The debug keystore is looked up once, when the first APK needs it. Unsigned builds
+        // and builds signed with the release key don't, unless it is to be regenerated.
+        let needs_debug_key = sign && release_key.is_none();
+        if keystore.is_none() && (needs_debug_key || regenerate_debug_key) {
+            keystore = Some(
+                debug_keystore(
+                    workspace,
+                    &runner,
+                    root_build_dir,
+                    needs_debug_key,
+                    regenerate_debug_key,
+                )
+                .failure_kind(FailureKind::Signing)?,
+            );
+        }
+        // Signed with the development certificate when there is no release key
+        let debug_key = keystore
+            .clone()
+            .filter(|_| needs_debug_key)
+            .map(SigningKey::debug);
+        let key = release_key.as_ref().or(debug_key.as_ref());
+
+        //
+        // Run commands to produce APK
+        //
+        let builder = ApkBuilder {
+            config,
+            target_config: &target_config,
+            target_name: target.name(),
+            target_directory: &target_directory,
+            tools,
+            java_tools: java_tools.as_ref(),
+            java_cache: java_cache.as_ref(),
+            runner: &runner,
+        };
+        for warning in builder.write_manifest(&java_files)? {
+            workspace.gctx().shell().warn(warning)?;
+        }
+        let java = miniquad_root_path
+            .map(|path| builder.stage_java(&path.join("java"), &java_files))
+            .transpose()?;
+        let mut generated = vec![target_directory.join("AndroidManifest.xml")];
+        if let Some(java) = &java {
+            generated.extend(java.files().map(Path::to_owned));
+        }
+        // For shrinking the Java code of release builds, which would otherwise drop or rename
+        // what the libraries call
+        if config.release && java.is_some() {
+            let keep_rules_path =
+                write_keep_rules(&target_directory, shared_libraries, &java_files.jni_keep)?;
+            workspace.gctx().shell().verbose(|shell| {
+                shell.status("Keep rules", keep_rules_path.display().to_string())
+            })?;
+            generated.push(keep_rules_path);
+        }
+        util::finish_generation(&target_directory, &generated)?;
+        if target_config.embed_build_env && build_env.is_none() {
+            build_env = Some(BuildEnv::collect(workspace, config));
+        }
+        let assets =
+            builder.stage_assets(build_env.as_ref().filter(|_| target_config.embed_build_env))?;
+        if let (Some(assets_path), true) = (&target_config.assets_path, assets.ignored > 0) {
+            workspace.gctx().shell().status(
+                "Ignored",
+                format!(
+                    "{} entries of the assets directory `{}`",
+                    assets.ignored,
+                    assets_path.display()
+                ),
+            )?;
+        }
+        let target_key = (target.kind().to_owned(), target.name().to_owned());
+
+        if let Some(bundle_tools) = bundle_tools {
+            let compiled = builder.compile_resources(&java_files)?;
+            let resources = builder.link_resources(&assets, &compiled)?;
+            report_resource_problems(
+                workspace,
+                &target_config,
+                "aapt2 left out resources",
+                &resources.aapt_warnings,
+            )?;
+            let dex = java
+                .as_ref()
+                .map(|java| -> CargoResult<apk::Dex> {
+                    let (classes, dex) = builder.compile_dex(java, &resources, &java_files)?;
+                    lint_api_levels(
+                        workspace,
+                        config,
+                        &mut api_database,
+                        target.name(),
+                        &classes,
+                    )?;
+                    lint_permissions(
+                        workspace,
+                        config,
+                        &target_config,
+                        target.name(),
+                        &target_directory,
+                        &classes,
+                        &java_files,
+                    )?;
+                    Ok(dex)
+                })
+                .transpose()?;
+            if let Some(dex) = &dex {
+                dex_statistics(workspace, target.name(), &target_directory, dex)?;
+            }
+            let module = builder.bundle_module(&resources, dex.as_ref(), shared_libraries)?;
+            let bundle_path =
+                target_apk_directory.join(format!("{}.aab", apk_stem(config, target.name())));
+            finish_bundle(&builder, bundle_tools, &module, &bundle_path, key)?;
+            bundles.insert(target_key, bundle_path);
+            continue;
+        }
+
+        let resources = builder.package_resources(&assets, &java_files)?;
+        report_resource_problems(
+            workspace,
+            &target_config,
+            "aapt left out resources",
+            &resources.aapt_warnings,
+        )?;
+        let dex = java
+            .as_ref()
+            .map(|java| -> CargoResult<apk::Dex> {
+                let (classes, dex) = builder.compile_dex(java, &resources, &java_files)?;
+                lint_api_levels(
+                    workspace,
+                    config,
+                    &mut api_database,
+                    target.name(),
+                    &classes,
+                )?;
+                lint_permissions(
+                    workspace,
+                    config,
+                    &target_config,
+                    target.name(),
+                    &target_directory,
+                    &classes,
+                    &java_files,
+                )?;
+                Ok(dex)
+            })
+            .transpose()?;
+        let dex_report = dex
+            .as_ref()
+            .map(|dex| dex_statistics(workspace, target.name(), &target_directory, dex))
+            .transpose()?;
+
+        let final_apk_path =
+            target_apk_directory.join(format!("{}.apk", apk_stem(config, target.name())));
+        finish_apk(
+            workspace,
+            &builder,
+            resources.apk,
+            dex.as_ref().map(|dex| (dex, target_directory.as_path())),
+            shared_libraries,
+            &final_apk_path,
+            key,
+        )?;
+        let mut report_apk = ReportApk::new(
+            target.kind(),
+            target.name(),
+            &final_apk_path,
+            &features_fingerprint,
         );
+        report_apk.dex.extend(dex_report);
+        report.apks.push(report_apk);
+        target_to_apk_map.insert(target_key.clone(), final_apk_path);
+
+        if !split_per_abi {
+            continue;
+        }
+        // The splits only differ by their libraries and versionCode, so the Java code is reused.
+        // The libraries aren't compiled again, `CARGO_APK_VERSION_CODE` stays the versionCode of
+        // the app.
+        let abis = config.build_targets.iter().filter(|build_target| {
+            shared_libraries
+                .iter()
+                .any(|library| library.abi.android_abi() == build_target.android_abi())
+        });
+        for &abi in abis {
+            let mut split_config = config.resolve(target_key.clone())?;
+            split_config.version_code = split_version_code(split_config.version_code, Some(abi))?;
+            let split_directory = target_directory.join("split").join(abi.android_abi());
+            fs::create_dir_all(&split_directory)?;
+            let split_builder = ApkBuilder {
+                target_config: &split_config,
+                target_directory: &split_directory,
+                ..builder
+            };
+            split_builder.write_manifest(&java_files)?;
+            let split_resources = split_builder.package_resources(&assets, &java_files)?;
+            let split_apk_path = target_apk_directory.join(format!(
+                "{}-{}.apk",
+                apk_stem(config, target.name()),
+                abi.android_abi()
+            ));
+            finish_apk(
+                workspace,
+                &split_builder,
+                split_resources.apk,
+                dex.as_ref().map(|dex| (dex, target_directory.as_path())),
+                shared_libraries
+                    .iter()
+                    .filter(|library| library.abi.android_abi() == abi.android_abi()),
+                &split_apk_path,
+                key,
+            )?;
+            let mut report_apk = ReportApk::new(
+                target.kind(),
+                target.name(),
+                &split_apk_path,
+                &features_fingerprint,
+            );
+            report_apk.abi = Some(abi.android_abi().to_owned());
+            report.apks.push(report_apk);
+            split_apks
+                .entry(target_key.clone())
+                .or_insert_with(BTreeMap::new)
+                .insert(abi.android_abi(), split_apk_path);
+        }
+    }
+
+    report.write(root_build_dir)?;
+
+    if let Some(build_env) = build_env {
+        workspace.gctx().shell().status(
+            "Build env",
+            format!("fingerprint {}", build_env.fingerprint()),
+        )?;
     }
 
-    Ok(BuildResult { target_to_apk_map })
+    Ok(BuildResult {
+        target_to_apk_map,
+        split_apks,
+        bundles,
+    })
 }
 
-/// Find an executable that is part of the Java SDK
-fn find_java_executable(name: &str) -> CargoResult<PathBuf> {
-    // Look in PATH
-    env::var_os("PATH")
-        .and_then(|paths| {
-            env::split_paths(&paths)
-                .filter_map(|path| {
-                    let filepath = path.join(name);
-                    if fs::metadata(&filepath).is_ok() {
-                        Some(filepath)
-                    } else {
-                        None
-                    }
-                })
-                .next()
-        })
-        .or_else(||
-            // Look in JAVA_HOME
-            env::var_os("JAVA_HOME").and_then(|java_home| {
-                let filepath = PathBuf::from(java_home).join("bin").join(name);
-                if filepath.exists() {
-                    Some(filepath)
-                } else {
+/// Returns the name of the APKs of a target without their extension, the name of the target
+/// followed by the flavor, so that the APKs of the flavors can be copied side by side
+fn apk_stem(config: &AndroidConfig, target_name: &str) -> String {
+    match &config.flavor {
+        Some(flavor) => format!("{}-{}", target_name, flavor),
+        None => target_name.to_owned(),
+    }
+}
+
+/// Writes and signs the APK, under a temporary name removed if the build is interrupted so that
+/// an interrupted build never leaves a plausible-looking APK behind. The dex is the one assembled
+/// in the directory given with it.
+fn finish_apk<'l>(
+    workspace: &Workspace,
+    builder: &ApkBuilder,
+    apk: apk::UnalignedApk,
+    dex: Option<(&apk::Dex, &Path)>,
+    shared_libraries: impl IntoIterator<Item = &'l SharedLibrary>,
+    final_apk_path: &Path,
+    key: Option<&SigningKey>,
+) -> CargoResult<()> {
+    let partial_apk_path = final_apk_path.with_extension("apk.partial");
+    // apksigner may write the v4 signature next to the APK
+    let partial_idsig_path = partial_apk_path.with_extension("partial.idsig");
+    tempfile::register(&partial_apk_path);
+    tempfile::register(&partial_idsig_path);
+    let apk = builder.write_apk(apk, dex, shared_libraries, partial_apk_path.clone())?;
+    // The APK is aligned as it's written, zipalign only double checks it. Pages of 16 KiB are
+    // always checked, since stores reject the APKs targeting them which aren't aligned.
+    if builder.config.page_alignment == 16
+        || workspace.gctx().shell().verbosity() == Verbosity::Verbose
+    {
+        builder.verify_alignment(&apk)?;
+    }
+    if let Some(key) = key {
+        for warning in builder.sign(&apk, key).failure_kind(FailureKind::Signing)? {
+            workspace
+                .gctx()
+                .shell()
+                .warn(format!("apksigner: {}", warning))?;
+        }
+    }
+    // The features are recorded once the APK is in place, so that a failure in between leaves
+    // an APK which isn't installed
+    report::remove_apk_features(final_apk_path)?;
+    fs::rename(&partial_apk_path, final_apk_path)?;
+    report::write_apk_features(final_apk_path, builder.config)?;
+    if partial_idsig_path.exists() {
+        fs::rename(
+            &partial_idsig_path,
+            final_apk_path.with_extension("apk.idsig"),
+        )?;
+    }
+    tempfile::unregister(&partial_apk_path);
+    tempfile::unregister(&partial_idsig_path);
+    Ok(())
+}
+
+/// Warns about the calls of the Java code of a target to APIs above `min_sdk_version`, or fails
+/// with `strict_api_lint`. The API database of the SDK is loaded on first use.
+fn lint_api_levels(
+    workspace: &Workspace,
+    config: &AndroidConfig,
+    api_database: &mut Option<Option<ApiDatabase>>,
+    target_name: &str,
+    classes: &apk::Classes,
+) -> CargoResult<()> {
+    if api_database.is_none() {
+        let database = match ApiDatabase::find(config) {
+            Some(path) => Some(ApiDatabase::load(&path)?),
+            None => {
+                workspace.gctx().shell().verbose(|shell| {
+                    shell.note(
+                        "the SDK has no api-versions.xml, the calls of the Java code to APIs \
+                         above `min_sdk_version` are not checked",
+                    )
+                })?;
+                None
+            }
+        };
+        *api_database = Some(database);
+    }
+    let database = match api_database {
+        Some(Some(database)) => database,
+        _ => return Ok(()),
+    };
+    let calls = api_lint::check_classes(database, config, &classes.0)?;
+    if calls.is_empty() {
+        return Ok(());
+    }
+    let report = api_lint::report(target_name, config.min_sdk_version, &calls);
+    if config.strict_api_lint {
+        return Err(format_err!(report));
+    }
+    workspace.gctx().shell().warn(report)
+}
+
+/// Warns about the calls of the Java code of a target to framework methods requiring a permission
+/// its manifest doesn't declare, naming the Java file contributing the calling class
+fn lint_permissions(
+    workspace: &Workspace,
+    config: &AndroidConfig,
+    target_config: &AndroidTargetConfig,
+    target_name: &str,
+    target_directory: &Path,
+    classes: &apk::Classes,
+    java_files: &util::JavaFiles,
+) -> CargoResult<()> {
+    let manifest = xml::parse(&fs::read_to_string(
+        target_directory.join("AndroidManifest.xml"),
+    )?)?;
+    let declared = manifest
+        .elements()
+        .iter()
+        .filter(|element| element.name().starts_with("uses-permission"))
+        .filter_map(|element| element.attribute("android:name"))
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    let missing = api_lint::check_permissions(config, &classes.0, &declared)?;
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    // Contributed files are staged at `java/<package path>/<class>.java`, and the injected glue
+    // is compiled into the MainActivity, along with `main_activity`
+    let mut sources = java_files
+        .java_files
+        .iter()
+        .filter_map(|(global_path, local_path)| {
+            let class = local_path.strip_prefix("java").ok()?.with_extension("");
+            let class = class.to_str()?.replace(std::path::MAIN_SEPARATOR, ".");
+            Some((class, format!("`{}`", global_path.display())))
+        })
+        .collect::<HashMap<_, _>>();
+    let main_activity_sources = config
+        .main_activity
+        .iter()
+        .chain(&java_files.main_activity_injects)
+        .map(|source| format!("`{}`", source.display()))
+        .collect::<Vec<_>>();
+    if !main_activity_sources.is_empty() {
+        sources.insert(
+            format!(
+                "{}.{}",
+                target_config.java_package(),
+                target_config.main_activity_class
+            ),
+            main_activity_sources.join(", "),
+        );
+    }
+    workspace
+        .gctx()
+        .shell()
+        .warn(api_lint::permission_report(target_name, &missing, &sources))
+}
+
+/// Writes the keep rules of the Java code reached through JNI by `shared_libraries` and listed by
+/// `jni_keep`, returning the path of the rules file
+fn write_keep_rules(
+    target_directory: &Path,
+    shared_libraries: &[SharedLibrary],
+    jni_keep: &[String],
+) -> CargoResult<PathBuf> {
+    let mut references = keep_rules::JniReferences::default();
+    for library in shared_libraries {
+        references.add_library(&library.path)?;
+    }
+    let path = target_directory.join(keep_rules::KEEP_RULES_FILENAME);
+    util::write_if_changed(&path, keep_rules::keep_rules(&references, jni_keep))?;
+    Ok(path)
+}
+
+/// Returns the libraries of a target in the order they are packaged, by ABI and filename. A library
+/// found twice, like a dependency found in two search paths, is packaged once when both copies are
+/// the same, while different libraries with the same filename fail the build, since the second
+/// would replace the first in `lib/<abi>`.
+fn packaged_libraries(
+    target_name: &str,
+    shared_libraries: &[SharedLibrary],
+) -> CargoResult<Vec<SharedLibrary>> {
+    let mut packaged = BTreeMap::new();
+    for library in shared_libraries {
+        let entry = match packaged.entry((library.abi.android_abi(), library.filename.as_str())) {
+            Entry::Vacant(entry) => {
+                entry.insert(library);
+                continue;
+            }
+            Entry::Occupied(entry) => entry,
+        };
+        let first = *entry.get();
+        if first.path != library.path
+            && Sha256::new().update_path(&first.path)?.finish_hex()
+                != Sha256::new().update_path(&library.path)?.finish_hex()
+        {
+            return Err(format_err!(
+                "Target '{}' has two different libraries packaged as `lib/{}/{}`: `{}` and `{}`",
+                target_name,
+                library.abi.android_abi(),
+                library.filename,
+                first.path.display(),
+                library.path.display()
+            ));
+        }
+        // The copy built for the target knows how it was linked
+        if first.link.is_none() {
+            *entry.into_mut() = library;
+        }
+    }
+    Ok(packaged.into_values().cloned().collect())
+}
+
+/// Writes the zip of native debug symbols of Play, with the unstripped copy of each library of the
+/// target as `<abi>/<library>`. Returns whether there were any.
+fn write_native_debug_symbols(
+    root_build_dir: &Path,
+    shared_libraries: &[SharedLibrary],
+    zip_path: &Path,
+) -> CargoResult<bool> {
+    use zip::write::{FileOptions, ZipWriter};
+
+    let libraries = shared_libraries
+        .iter()
+        .map(|library| {
+            let path = symbol_directory(root_build_dir, library.abi).join(&library.filename);
+            (library, path)
+        })
+        .filter(|(_, path)| path.exists())
+        .collect::<Vec<_>>();
+    if libraries.is_empty() {
+        if zip_path.exists() {
+            fs::remove_file(zip_path)?;
+        }
+        return Ok(false);
+    }
+    let mut zip = ZipWriter::new(fs::File::create(zip_path)?);
+    for (library, path) in libraries {
+        zip.start_file(
+            format!("{}/{}", library.abi.android_abi(), library.filename),
+            FileOptions::default(),
+        )?;
+        std::io::copy(&mut fs::File::open(&path)?, &mut zip)?;
+    }
+    zip.finish()?;
+    Ok(true)
+}
+
+/// Prints the method and field counts of the dex of a target, warning when they get close to the
+/// limit of a dex file, and returns them for the build report
+fn dex_statistics(
+    workspace: &Workspace,
+    target_name: &str,
+    target_directory: &Path,
+    dex: &apk::Dex,
+) -> CargoResult<ReportDex> {
+    let name = dex.0.display().to_string();
+    let counts = dex::read_counts(&target_directory.join(&dex.0))?;
+    workspace.gctx().shell().status(
+        "Dex",
+        format!(
+            "{} of target '{}': {} methods, {} fields, {} classes (limit {})",
+            name,
+            target_name,
+            counts.methods,
+            counts.fields,
+            counts.classes,
+            dex::REFERENCE_LIMIT
+        ),
+    )?;
+    if let Some(warning) = dex::limit_warning(&name, target_name, &counts) {
+        workspace.gctx().shell().warn(warning)?;
+    }
+    Ok(ReportDex {
+        name,
+        methods: counts.methods,
+        fields: counts.fields,
+        classes: counts.classes,
+    })
+}
+
+/// Builds and signs the app bundle, under a temporary name removed if the build is interrupted as
+/// for APKs
+fn finish_bundle(
+    builder: &ApkBuilder,
+    tools: &BundleTools,
+    module: &apk::BundleModule,
+    bundle_path: &Path,
+    key: Option<&SigningKey>,
+) -> CargoResult<()> {
+    let partial_bundle_path = bundle_path.with_extension("partial.aab");
+    tempfile::register(&partial_bundle_path);
+    builder.build_bundle(tools, module, &partial_bundle_path)?;
+    if let Some(key) = key {
+        let alias = match &key.key_alias {
+            Some(alias) => alias.clone(),
+            None => signing::debug_key_alias(&key.keystore)
+                .unwrap_or_else(|| "androiddebugkey".to_owned()),
+        };
+        builder
+            .sign_bundle(tools, &partial_bundle_path, key, &alias)
+            .failure_kind(FailureKind::Signing)?;
+    }
+    fs::rename(&partial_bundle_path, bundle_path)?;
+    tempfile::unregister(&partial_bundle_path);
+    Ok(())
+}
+
+/// Returns the digit of the versionCode of the split APKs of an ABI, higher for 64-bit ABIs so that
+/// stores pick them for devices running both. The APK with every ABI gets 0.
+fn split_abi_code(abi: Option<AndroidBuildTarget>) -> i32 {
+    match abi {
+        None => 0,
+        Some(AndroidBuildTarget::ArmV7a) => 1,
+        Some(AndroidBuildTarget::X86) => 2,
+        Some(AndroidBuildTarget::Arm64V8a) => 3,
+        Some(AndroidBuildTarget::X86_64) => 4,
+    }
+}
+
+/// Returns the versionCode of an APK built along with split APKs: the versionCode of the app
+/// followed by `split_abi_code`, so that the APK with every ABI and the splits don't share one
+fn split_version_code(version_code: i32, abi: Option<AndroidBuildTarget>) -> CargoResult<i32> {
+    let abi_code = split_abi_code(abi);
+    version_code
+        .checked_mul(10)
+        .and_then(|code| code.checked_add(abi_code))
+        // The highest versionCode Google Play accepts
+        .filter(|code| *code <= 2_100_000_000)
+        .ok_or_else(|| {
+            format_err!(
+                "versionCode {} is too large for split APKs, which append a digit for the ABI",
+                version_code
+            )
+        })
+}
+
+#[test]
+fn split_version_codes() {
+    assert_eq!(split_version_code(7, None).unwrap(), 70);
+    assert_eq!(
+        split_version_code(7, Some(AndroidBuildTarget::ArmV7a)).unwrap(),
+        71
+    );
+    assert_eq!(
+        split_version_code(7, Some(AndroidBuildTarget::Arm64V8a)).unwrap(),
+        73
+    );
+    assert_eq!(
+        split_version_code(7, Some(AndroidBuildTarget::X86_64)).unwrap(),
+        74
+    );
+    assert!(split_version_code(210_000_000, Some(AndroidBuildTarget::ArmV7a)).is_err());
+}
+
+/// Finds or generates the debug keystore, warning when the certificate used for signing expires
+/// soon
+fn debug_keystore(
+    workspace: &Workspace,
+    runner: &ProcessRunner,
+    root_build_dir: &Path,
+    sign: bool,
+    regenerate: bool,
+) -> CargoResult<PathBuf> {
+    let keystore = signing::debug_keystore(runner, root_build_dir, regenerate)?;
+    if let Some(backup) = &keystore.backup {
+        workspace.gctx().shell().status(
+            "Regenerated",
+            format!(
+                "debug keystore {}, the previous one is kept at {}",
+                keystore.path.display(),
+                backup.display()
+            ),
+        )?;
+    }
+    if sign && !keystore.generated {
+        if let Some(valid_until) = signing::certificate_valid_until(&keystore.path) {
+            if let Some(warning) =
+                signing::expiry_warning(&keystore.path, valid_until, signing::today())
+            {
+                workspace.gctx().shell().warn(warning)?;
+            }
+        }
+    }
+    Ok(keystore.path)
+}
+
+/// Adds how the library of each target was linked to the report, printing it with `-vv`
+fn report_links(
+    workspace: &Workspace,
+    report: &mut BuildReport,
+    shared_libraries: &SharedLibraries,
+) -> CargoResult<()> {
+    for (target, shared_libraries) in shared_libraries.shared_libraries.iter_all() {
+        for shared_library in shared_libraries {
+            let link = match &shared_library.link {
+                Some(link) => link,
+                None => continue,
+            };
+            if workspace.gctx().extra_verbose() {
+                drop(writeln!(
+                    workspace.gctx().shell().err(),
+                    "{} ({}) linked by {} against platform {} at {}, clang: {}",
+                    shared_library.filename,
+                    shared_library.abi.android_abi(),
+                    link.linker.display(),
+                    link.platform,
+                    link.platform_dir.display(),
+                    link.clang.display()
+                ));
+            }
+            report.links.push(ReportLink::new(
+                target.kind(),
+                target.name(),
+                shared_library.abi.android_abi(),
+                link,
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Copies the shared libraries to `<out_dir>/<abi>`, or `<out_dir>/examples/<abi>` for examples,
+/// in place of packaging them into APKs
+fn output_libraries(
+    workspace: &Workspace,
+    config: &AndroidConfig,
+    root_build_dir: &Path,
+    out_dir: &Path,
+    shared_libraries: SharedLibraries,
+) -> CargoResult<()> {
+    BuildReport::keep_previous(root_build_dir)?;
+    let mut report = BuildReport {
+        overrides: config.overrides.clone(),
+        features: config.cargo_features.iter().cloned().collect(),
+        no_default_features: config.no_default_features,
+        flavor: config.flavor.clone(),
+        ..BuildReport::default()
+    };
+    report_links(workspace, &mut report, &shared_libraries)?;
+
+    for (target, shared_libraries) in shared_libraries.shared_libraries.iter_all() {
+        let target_dir = match target.kind() {
+            TargetKind::ExampleBin => out_dir.join("examples"),
+            _ => out_dir.to_owned(),
+        };
+        for shared_library in &packaged_libraries(target.name(), shared_libraries)? {
+            let abi_dir = target_dir.join(shared_library.abi.android_abi());
+            fs::create_dir_all(&abi_dir)?;
+            let path = abi_dir.join(&shared_library.filename);
+            fs::copy(&shared_library.path, &path)?;
+            workspace.gctx().shell().status("Copied", path.display())?;
+            report.libraries.push(ReportLibrary::new(
+                target.kind(),
+                target.name(),
+                shared_library.abi.android_abi(),
+                &path,
+            ));
+        }
+    }
+
+    report.write(root_build_dir)
+}
+
+/// Returns the desugaring related arguments of d8, following the dex inputs
+fn d8_desugaring_args(config: &AndroidConfig) -> Vec<OsString> {
+    let min_api = util::EffectiveApiLevels::dex_min_api(config);
+    let mut args = match &config.desugaring {
+        Some(desugaring) => {
+            let mut args = desugaring
+                .jars
+                .iter()
+                .map(OsString::from)
+                .collect::<Vec<_>>();
+            args.push("--desugared-lib".into());
+            args.push(desugaring.config.clone().into());
+            // Desugaring needs the platform classes, which `--no-desugaring` did without
+            args.push("--lib".into());
+            args.push(config.android_jar_path.clone().into());
+            args
+        }
+        // Java 8 constructs of the jars run as they are from API 26
+        None if min_api >= 26 => vec!["--no-desugaring".into()],
+        None => vec!["--lib".into(), config.android_jar_path.clone().into()],
+    };
+    args.push("--min-api".into());
+    args.push(min_api.to_string().into());
+    args
+}
+
+/// Returns a warning for each package whose runtime jars need an API level above
+/// `min_sdk_version`, declared by `runtime_jar_min_api` in its quad.toml
+fn runtime_jar_api_warnings(config: &AndroidConfig, java_files: &util::JavaFiles) -> Vec<String> {
+    java_files
+        .runtime_jar_min_apis
+        .iter()
+        .filter(|(_, min_api)| *min_api > config.min_sdk_version)
+        .map(|(package_name, min_api)| {
+            format!(
+                "the runtime jars of package `{}` need API {} but `min_sdk_version` is {}, they \
+                 may crash on older devices",
+                package_name, min_api, config.min_sdk_version
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn d8_desugaring() {
+    let config = crate::config::from_metadata("min_sdk_version = 26");
+    assert_eq!(
+        d8_desugaring_args(&config),
+        vec!["--no-desugaring", "--min-api", "26"]
+    );
+
+    // Older devices get desugared Java 8 constructs
+    let config = crate::config::from_metadata("min_sdk_version = 21");
+    assert_eq!(
+        d8_desugaring_args(&config),
+        vec![
+            "--lib",
+            "/sdk/platforms/android-31/android.jar",
+            "--min-api",
+            "21"
+        ]
+    );
+
+    let config = crate::config::from_metadata(
+        r#"
+        min_sdk_version = 24
+        core_library_desugaring = true
+        desugar_lib_config = "desugar.json"
+        desugar_lib_jars = ["desugar_jdk_libs.jar"]
+        "#,
+    );
+    assert_eq!(
+        d8_desugaring_args(&config),
+        vec![
+            "/app/desugar_jdk_libs.jar",
+            "--desugared-lib",
+            "/app/desugar.json",
+            "--lib",
+            "/sdk/platforms/android-31/android.jar",
+            "--min-api",
+            "24",
+        ]
+    );
+}
+
+#[test]
+fn runtime_jars_above_min_sdk() {
+    let config = crate::config::from_metadata("min_sdk_version = 21");
+    let java_files = util::JavaFiles {
+        runtime_jar_min_apis: vec![("billing".to_owned(), 24), ("ads".to_owned(), 21)],
+        ..Default::default()
+    };
+    assert_eq!(
+        runtime_jar_api_warnings(&config, &java_files),
+        vec![
+            "the runtime jars of package `billing` need API 24 but `min_sdk_version` is 21, \
+             they may crash on older devices"
+        ]
+    );
+}
+
+/// Find an executable that is part of the Java SDK
+fn find_java_executable(name: &str) -> CargoResult<PathBuf> {
+    // Look in PATH
+    env::var_os("PATH")
+        .and_then(|paths| {
+            env::split_paths(&paths)
+                .filter_map(|path| {
+                    let filepath = path.join(name);
+                    if fs::metadata(&filepath).is_ok() {
+                        Some(filepath)
+                    } else {
+                        None
+                    }
+                })
+                .next()
+        })
+        .or_else(||
+            // Look in JAVA_HOME
+            env::var_os("JAVA_HOME").and_then(|java_home| {
+                let filepath = PathBuf::from(java_home).join("bin").join(name);
+                if filepath.exists() {
+                    Some(filepath)
+                } else {
                     None
                 }
             }))
@@ -417,6 +1735,7 @@ fn find_java_executable(name: &str) -> CargoResult<PathBuf> {
                 name
             )
         })
+        .failure_kind(FailureKind::Environment)
 }
 
 fn find_rt_jar() -> CargoResult<String> {
@@ -442,142 +1761,1491 @@ fn find_rt_jar() -> CargoResult<String> {
             false,
         );
 
-    if res.is_none() {
-        panic!("rt.jar cant be found, probably JRE is not installed");
+    res.ok_or_else(|| format_err!("rt.jar can't be found, probably the JRE is not installed"))
+        .failure_kind(FailureKind::Environment)
+}
+/// Returns the warnings about the `android:process` values of a target
+fn process_warnings(target_config: &AndroidTargetConfig) -> Vec<String> {
+    let mut warnings = vec![];
+
+    let processes = target_config
+        .application_process
+        .iter()
+        .map(|process| ("application", process))
+        .chain(
+            target_config
+                .activity_process
+                .iter()
+                .map(|process| ("main activity", process)),
+        )
+        .chain(
+            target_config
+                .services
+                .iter()
+                .filter_map(|service| service.process.as_ref().map(|process| ("service", process))),
+        )
+        .chain(target_config.receivers.iter().filter_map(|receiver| {
+            receiver
+                .process
+                .as_ref()
+                .map(|process| ("receiver", process))
+        }));
+    for (component, process) in processes {
+        if !process.starts_with(':') {
+            warnings.push(format!(
+                "{} process `{}` does not start with `:`, it will run in a global process \
+                 which may be shared with other applications",
+                component, process
+            ));
+        }
+    }
+
+    if target_config.activity_process.is_some() {
+        warnings.push(
+            "main activity runs in a non-default process, JNI statics initialized in other \
+             processes will not be visible to it"
+                .to_owned(),
+        );
     }
-    Ok(res.unwrap())
+
+    warnings
 }
-fn build_manifest(
-    path: &Path,
+
+fn render_manifest(
     config: &AndroidConfig,
     target_config: &AndroidTargetConfig,
-    target: &Target,
+    target_name: &str,
     java_files: &util::JavaFiles,
-) -> CargoResult<()> {
-    let file = path.join("AndroidManifest.xml");
-    let mut file = File::create(&file)?;
-
-    // Building application attributes
-    let application_attrs = format!(
-        r#"
-            android:hasCode="true" android:label="{0}"{1}{2}{3}"#,
-        target_config.package_label,
-        target_config
-            .package_icon
-            .as_ref()
-            .map_or(String::new(), |a| format!(
-                r#"
-            android:icon="{}""#,
-                a
-            )),
-        if target_config.fullscreen {
-            r#"
-            android:theme="@android:style/Theme.DeviceDefault.NoActionBar.Fullscreen""#
-        } else {
-            ""
-        },
-        target_config
-            .application_attributes
-            .as_ref()
-            .map_or(String::new(), |a| a.replace("\n", "\n            "))
-    );
-
-    // Build activity attributes
-    let activity_attrs = format!(
-        r#"
-                android:name=".MainActivity"
-                android:label="{0}"
-                android:configChanges="orientation|keyboardHidden|screenSize" {1}"#,
-        target_config.package_label,
-        target_config
-            .activity_attributes
-            .as_ref()
-            .map_or(String::new(), |a| a.replace("\n", "\n                "))
-    );
+) -> String {
+    manifest_element(config, target_config, target_name, java_files).to_document()
+}
 
-    let uses_features = target_config
-        .features
-        .iter()
-        .map(|f| {
-            format!(
-                "\n\t<uses-feature android:name=\"{}\" android:required=\"{}\" {}/>",
-                f.name,
-                f.required,
-                f.version
-                    .as_ref()
-                    .map_or(String::new(), |v| format!(r#"android:version="{}""#, v))
+/// Returns the manifest of a target: the generated one, or the manifest template of
+/// `manifest_path` with the generated elements and attributes merged into it, along with the
+/// warnings about the attributes of the template replacing generated ones
+fn manifest_document(
+    config: &AndroidConfig,
+    target_config: &AndroidTargetConfig,
+    target_name: &str,
+    java_files: &util::JavaFiles,
+) -> CargoResult<(String, Vec<String>)> {
+    let generated = manifest_element(config, target_config, target_name, java_files);
+    let template_path = match &target_config.manifest_template {
+        Some(template_path) => template_path,
+        None => return Ok((generated.to_document(), vec![])),
+    };
+    let template = fs::read_to_string(template_path).map_err(|err| {
+        format_err!(
+            "Can't read the manifest template `{}`: {}",
+            template_path.display(),
+            err
+        )
+    })?;
+    let placeholders = [
+        ("packageName", target_config.application_id()),
+        ("libraryName", target_name.to_owned()),
+    ];
+    let mut manifest = substitute_placeholders(&template, &placeholders)
+        .map_err(|err| format_err!(err))
+        .and_then(|template| xml::parse(&template))
+        .map_err(|err| {
+            format_err!(
+                "Invalid manifest template `{}`: {}",
+                template_path.display(),
+                err
             )
-        })
-        .collect::<Vec<String>>()
-        .join(", ");
-
-    let uses_permissions = target_config
-        .permissions
-        .iter()
-        .map(|f| {
+        })?;
+    if manifest.name() != "manifest" {
+        return Err(format_err!(
+            "The root element of the manifest template `{}` is `<{}>`, expected `<manifest>`",
+            template_path.display(),
+            manifest.name()
+        ));
+    }
+    let warnings = manifest
+        .merge(generated)
+        .into_iter()
+        .map(|conflict| {
             format!(
-                "\n\t<uses-permission android:name=\"{}\" {max_sdk_version}/>",
-                f.name,
-                max_sdk_version = f.max_sdk_version.map_or(String::new(), |v| format!(
-                    r#"android:maxSdkVersion="{}""#,
-                    v
-                ))
+                "the manifest template sets `{}` of `<{}>` to `{}`, replacing `{}` of the \
+                 configuration",
+                conflict.attribute, conflict.element, conflict.kept, conflict.replaced
             )
         })
-        .collect::<Vec<String>>()
-        .join(", ");
+        .collect();
+    Ok((manifest.to_document(), warnings))
+}
+
+/// Replaces the `${name}` placeholders of a manifest template with their values
+fn substitute_placeholders(
+    template: &str,
+    placeholders: &[(&str, String)],
+) -> Result<String, String> {
+    let mut substituted = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("${") {
+        substituted.push_str(&rest[..start]);
+        let len = rest[start..]
+            .find('}')
+            .ok_or_else(|| "unterminated placeholder `${`".to_owned())?;
+        let name = &rest[start + 2..start + len];
+        let value = placeholders
+            .iter()
+            .find(|(placeholder, _)| *placeholder == name)
+            .map(|(_, value)| value)
+            .ok_or_else(|| {
+                format!(
+                    "unknown placeholder `${{{}}}`, expected {}",
+                    name,
+                    placeholders
+                        .iter()
+                        .map(|(placeholder, _)| format!("`${{{}}}`", placeholder))
+                        .collect::<Vec<_>>()
+                        .join(" or ")
+                )
+            })?;
+        substituted.push_str(&xml::escape(value));
+        rest = &rest[start + len + 1..];
+    }
+    substituted.push_str(rest);
+    Ok(substituted)
+}
 
-    // <service android:name="" android:enabled="true"></service>
+/// Returns the `<manifest>` element generated from the configuration
+fn manifest_element(
+    config: &AndroidConfig,
+    target_config: &AndroidTargetConfig,
+    target_name: &str,
+    java_files: &util::JavaFiles,
+) -> Element {
+    let application = Element::new("application")
+        .attr(
+            "android:hasCode",
+            target_config.framework != Framework::None,
+        )
+        .attr("android:label", &target_config.package_label)
+        .opt_attr("android:icon", target_config.package_icon.as_ref())
+        .opt_attr(
+            "android:theme",
+            Some("@android:style/Theme.DeviceDefault.NoActionBar.Fullscreen")
+                .filter(|_| target_config.fullscreen),
+        )
+        .opt_attr(
+            "android:process",
+            target_config.application_process.as_ref(),
+        )
+        .opt_attr(
+            "android:testOnly",
+            Some(true).filter(|_| target_config.test_only),
+        )
+        .opt_attr(
+            "android:requestLegacyExternalStorage",
+            Some(true).filter(|_| legacy_external_storage(config, target_config)),
+        )
+        .opt_attr(
+            "android:localeConfig",
+            Some("@xml/locales_config")
+                .filter(|_| locales::locale_config_enabled(config, target_config)),
+        )
+        .attrs(attributes::manifest_attributes(
+            target_config,
+            attributes::Element::Application,
+        ));
+
+    let main_activity = Element::new("activity")
+        .attr("android:name", target_config.main_activity_name())
+        .attr("android:label", &target_config.package_label)
+        .attr("android:configChanges", &target_config.config_changes)
+        .opt_attr(
+            "android:windowSoftInputMode",
+            target_config.soft_input_mode.as_ref(),
+        )
+        .opt_attr("android:process", target_config.activity_process.as_ref())
+        .opt_attr(
+            "android:exported",
+            Some(true).filter(|_| main_activity_exported_by_default(config, target_config)),
+        )
+        .attrs(attributes::manifest_attributes(
+            target_config,
+            attributes::Element::Activity,
+        ))
+        .child(
+            Element::new("meta-data")
+                .attr("android:name", "android.app.lib_name")
+                .attr("android:value", target_name),
+        )
+        .child(
+            Element::new("intent-filter")
+                .child(Element::new("action").attr("android:name", "android.intent.action.MAIN"))
+                .child(
+                    Element::new("category")
+                        .attr("android:name", "android.intent.category.LAUNCHER"),
+                ),
+        )
+        .children(
+            target_config
+                .intent_filters
+                .iter()
+                .map(intent_filter_element),
+        );
 
+    let component = |tag: &'static str,
+                     name: &str,
+                     enabled: bool,
+                     process: Option<&String>,
+                     exported: Option<bool>| {
+        Element::new(tag)
+            .attr("android:name", name)
+            .attr("android:enabled", enabled)
+            .opt_attr("android:process", process)
+            .opt_attr("android:exported", exported)
+    };
+    // Android 12 requires an explicit value, only the app binds to its services by default
+    let service_exported = |exported: Option<bool>| {
+        exported.or(Some(false).filter(|_| config.target_sdk_version >= 31))
+    };
     let services = java_files
         .java_services
         .iter()
-        .map(|service| {
-            format!(
-                "\n\t<service android:name=\"{}\" android:enabled=\"{}\"></service>",
-                service, true
+        .map(|service| component("service", service, true, None, service_exported(None)))
+        .chain(target_config.services.iter().map(|s| {
+            component(
+                "service",
+                &s.name,
+                s.enabled,
+                s.process.as_ref(),
+                service_exported(s.exported),
+            )
+        }))
+        .chain(target_config.receivers.iter().map(|r| {
+            component(
+                "receiver",
+                &r.name,
+                r.enabled,
+                r.process.as_ref(),
+                r.exported,
             )
+        }));
+
+    let activities = target_config.activities.iter().map(|activity| {
+        Element::new("activity")
+            .attr("android:name", &activity.name)
+            .attr("android:exported", activity.exported)
+            .opt_attr("android:label", activity.label.as_ref())
+            .opt_attr("android:theme", activity.theme.as_ref())
+            .opt_attr("android:launchMode", activity.launch_mode.as_ref())
+            .children(activity.intent_filters.iter().map(intent_filter_element))
+    });
+
+    let (features, _) = uses_features(config, target_config, java_files);
+    Element::new("manifest")
+        .attr(
+            "xmlns:android",
+            "http://schemas.android.com/apk/res/android",
+        )
+        .attr("package", target_config.application_id())
+        .attr("android:versionCode", target_config.version_code)
+        .attr("android:versionName", &target_config.version_name)
+        .opt_attr(
+            "android:targetSandboxVersion",
+            target_config.target_sandbox_version,
+        )
+        .child(
+            Element::new("uses-sdk")
+                .attr("android:targetSdkVersion", config.target_sdk_version)
+                .attr("android:minSdkVersion", config.min_sdk_version),
+        )
+        .child(
+            Element::new("uses-feature")
+                .attr(
+                    "android:glEsVersion",
+                    format!(
+                        "0x{:04}{:04}",
+                        target_config.opengles_version_major, target_config.opengles_version_minor
+                    ),
+                )
+                .attr("android:required", true),
+        )
+        .children(features.iter().map(|feature| {
+            Element::new("uses-feature")
+                .attr("android:name", &feature.name)
+                .attr("android:required", feature.required)
+                .opt_attr("android:version", feature.version.as_ref())
+        }))
+        .children(target_config.permissions.iter().map(|permission| {
+            Element::new("uses-permission")
+                .attr("android:name", &permission.name)
+                .opt_attr("android:maxSdkVersion", permission.max_sdk_version)
+        }))
+        .child(
+            application
+                .children(
+                    Some(Element::new("profileable").attr("android:shell", true))
+                        .filter(|_| profileable(config, target_config)),
+                )
+                .children(services)
+                .child(main_activity)
+                .children(activities),
+        )
+}
+
+/// Fails when the package relies on Java code, which apps started by NativeActivity don't have
+fn native_activity_check(config: &AndroidConfig) -> CargoResult<()> {
+    let java_keys = [
+        ("java_sources", config.java_sources.is_empty()),
+        ("comptime_jars", config.comptime_jars.is_empty()),
+        ("runtime_jars", config.runtime_jars.is_empty()),
+        ("jni_keep", config.jni_keep.is_empty()),
+        ("main_activity", config.main_activity.is_none()),
+        ("core_library_desugaring", config.desugaring.is_none()),
+    ]
+    .iter()
+    .filter(|(_, unset)| !unset)
+    .map(|(key, _)| format!("`{}`", key))
+    .collect::<Vec<_>>();
+    if java_keys.is_empty() {
+        Ok(())
+    } else {
+        Err(format_err!(
+            "{} need Java code, which apps with `framework = \"none\"` don't have",
+            java_keys.join(", ")
+        ))
+    }
+}
+
+/// Whether android:requestLegacyExternalStorage is rendered. The platform ignores it for apps
+/// targeting API 30 and higher.
+fn legacy_external_storage(config: &AndroidConfig, target_config: &AndroidTargetConfig) -> bool {
+    target_config.request_legacy_external_storage && config.target_sdk_version <= 29
+}
+
+fn legacy_storage_warning(
+    config: &AndroidConfig,
+    target_config: &AndroidTargetConfig,
+) -> Option<String> {
+    if !target_config.request_legacy_external_storage || config.target_sdk_version <= 29 {
+        return None;
+    }
+    Some(format!(
+        "`request_legacy_external_storage` is ignored when targeting API {} (30 or higher), \
+         use scoped storage or the MANAGE_EXTERNAL_STORAGE permission instead",
+        config.target_sdk_version
+    ))
+}
+
+/// Whether the profileable element is rendered. The platform reads it from API 29.
+fn profileable(config: &AndroidConfig, target_config: &AndroidTargetConfig) -> bool {
+    target_config.profileable && config.target_sdk_version >= 29
+}
+
+fn profileable_warning(
+    config: &AndroidConfig,
+    target_config: &AndroidTargetConfig,
+) -> Option<String> {
+    if !target_config.profileable {
+        return None;
+    }
+    if config.target_sdk_version < 29 {
+        return Some(format!(
+            "`profileable` is ignored when targeting API {} (below 29)",
+            config.target_sdk_version
+        ));
+    }
+    // Debug builds are debuggable unless disabled, `profileable` is meant for release builds
+    if config.release && target_config.debuggable {
+        return Some(
+            "`profileable` is redundant with `android:debuggable`, debuggable apps can already \
+             be profiled"
+                .to_owned(),
+        );
+    }
+    None
+}
+
+/// Fails the build with the resource problems, or only warns with `allow_resource_warnings`
+fn report_resource_problems(
+    workspace: &Workspace,
+    target_config: &AndroidTargetConfig,
+    summary: &str,
+    problems: &[String],
+) -> CargoResult<()> {
+    if problems.is_empty() {
+        return Ok(());
+    }
+    let message = format!("{}:\n  {}", summary, problems.join("\n  "));
+    if target_config.allow_resource_warnings {
+        workspace.gctx().shell().warn(message)
+    } else {
+        Err(format_err!(
+            "{}\nFix them, or set `allow_resource_warnings = true` to build anyway",
+            message
+        ))
+    }
+}
+
+/// Whether the main activity gets `android:exported="true"` without `activity.exported` or an
+/// `android:exported` of `activity_attributes`: Android 12 requires an explicit value of the
+/// activities with intent filters, which the main activity always has
+fn main_activity_exported_by_default(
+    config: &AndroidConfig,
+    target_config: &AndroidTargetConfig,
+) -> bool {
+    let set = target_config.main_activity_exported.is_some()
+        || target_config
+            .activity_attributes
+            .iter()
+            .any(|(name, _)| name == "android:exported");
+    !set && (config.target_sdk_version >= 31 || !target_config.intent_filters.is_empty())
+}
+
+fn intent_filter_element(filter: &AndroidIntentFilter) -> Element {
+    let actions = filter
+        .actions
+        .iter()
+        .map(|action| Element::new("action").attr("android:name", action));
+    let categories = filter
+        .categories
+        .iter()
+        .map(|category| Element::new("category").attr("android:name", category));
+    let data = filter.data.iter().map(|data| {
+        Element::new("data")
+            .opt_attr("android:scheme", data.scheme.as_ref())
+            .opt_attr("android:host", data.host.as_ref())
+            .opt_attr("android:pathPrefix", data.path_prefix.as_ref())
+            .opt_attr("android:mimeType", data.mime_type.as_ref())
+    });
+    Element::new("intent-filter")
+        .opt_attr(
+            "android:autoVerify",
+            Some(true).filter(|_| filter.auto_verify),
+        )
+        .children(actions)
+        .children(categories)
+        .children(data)
+}
+
+/// Returns the uses-feature elements of a target merged by name with those the dependencies need:
+/// a feature is required when any package requires it and gets the highest version, with a
+/// warning about the differing versions
+fn uses_features(
+    config: &AndroidConfig,
+    target_config: &AndroidTargetConfig,
+    java_files: &util::JavaFiles,
+) -> (Vec<AndroidFeature>, Vec<String>) {
+    let declared = target_config
+        .features
+        .iter()
+        .map(|feature| (config.cargo_package_name.as_str(), feature))
+        .chain(
+            java_files
+                .uses_features
+                .iter()
+                .map(|(package, feature)| (package.as_str(), feature)),
+        );
+    // Merged features with the versions declared by each package
+    let mut merged: Vec<(AndroidFeature, Vec<(&str, &String)>)> = vec![];
+    for (package, feature) in declared {
+        let index = match merged.iter().position(|(f, _)| f.name == feature.name) {
+            Some(index) => index,
+            None => {
+                merged.push((
+                    AndroidFeature {
+                        version: None,
+                        ..feature.clone()
+                    },
+                    vec![],
+                ));
+                merged.len() - 1
+            }
+        };
+        let (merged_feature, versions) = &mut merged[index];
+        merged_feature.required |= feature.required;
+        versions.extend(feature.version.as_ref().map(|version| (package, version)));
+    }
+
+    let mut warnings = vec![];
+    let features = merged
+        .into_iter()
+        .map(|(mut feature, versions)| {
+            // Versions are integers, in hexadecimal for Vulkan
+            let value = |version: &str| match version.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16).ok(),
+                None => version.parse::<u64>().ok(),
+            };
+            feature.version = versions
+                .iter()
+                .max_by_key(|(_, version)| value(version))
+                .map(|(_, version)| (*version).clone());
+            if versions
+                .iter()
+                .any(|(_, version)| Some(*version) != feature.version.as_ref())
+            {
+                warnings.push(format!(
+                    "uses-feature `{}` is declared with the versions {}, the highest one, {}, is \
+                     used",
+                    feature.name,
+                    versions
+                        .iter()
+                        .map(|(package, version)| format!("{} ({})", version, package))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    feature.version.as_ref().unwrap()
+                ));
+            }
+            feature
         })
-        .collect::<Vec<String>>()
-        .join(", ");
+        .collect();
+    (features, warnings)
+}
+
+/// Returns the additional activities whose class is not part of the collected Java files. Names
+/// starting with `.` are relative to the Java package of the app.
+fn missing_activity_classes<'a>(
+    target_config: &'a AndroidTargetConfig,
+    java_files: &util::JavaFiles,
+) -> Vec<&'a str> {
+    let java_package = target_config.java_package();
+    target_config
+        .activities
+        .iter()
+        .map(|activity| activity.name.as_str())
+        .filter(|name| {
+            let class_name = match name.strip_prefix('.') {
+                Some(relative) => format!("{}.{}", java_package, relative),
+                None => (*name).to_owned(),
+            };
+            let mut class_path = PathBuf::from("java");
+            class_path.extend(class_name.split('.'));
+            class_path.set_extension("java");
+            !java_files
+                .java_files
+                .iter()
+                .any(|(_, local_path)| *local_path == class_path)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+fn render_test_manifest(metadata: &str) -> String {
+    let config = crate::config::from_metadata(metadata);
+    let target_config = config.resolve((TargetKind::Bin, "app".to_owned())).unwrap();
+    render_manifest(&config, &target_config, "app", &util::JavaFiles::default())
+}
+
+#[test]
+fn manifest_process() {
+    let manifest = render_test_manifest(
+        r#"
+        application_process = ":app"
+        activity_process = ":main"
+
+        [[service]]
+        name = ".AudioService"
+        enabled = true
+        process = ":audio"
+
+        [[receiver]]
+        name = ".BootReceiver"
+        enabled = false
+        "#,
+    );
+    assert!(manifest.contains("android:process=\":app\""));
+    assert!(manifest.contains("android:process=\":main\""));
+    assert!(manifest.contains(
+        r#"<service android:name=".AudioService" android:enabled="true" android:process=":audio" android:exported="false" />"#
+    ));
+    assert!(
+        manifest.contains(r#"<receiver android:name=".BootReceiver" android:enabled="false" />"#)
+    );
+
+    let manifest = render_test_manifest("");
+    assert!(!manifest.contains("android:process"));
+}
+
+#[test]
+fn manifest_config_changes() {
+    let manifest = render_test_manifest("target_sdk_version = 31");
+    assert!(manifest.contains(
+        r#"android:configChanges="orientation|keyboardHidden|screenSize|uiMode|density""#
+    ));
+    assert!(!manifest.contains("android:windowSoftInputMode"));
+
+    let manifest = render_test_manifest(
+        r#"
+        config_changes = ["orientation"]
+        soft_input_mode = "adjustResize"
+        "#,
+    );
+    assert!(manifest.contains(r#"android:configChanges="orientation""#));
+    assert!(manifest.contains(r#"android:windowSoftInputMode="adjustResize""#));
+}
+
+#[test]
+fn manifest_modelled_attributes() {
+    let manifest = render_test_manifest("");
+    assert!(!manifest.contains("android:allowBackup"));
+    assert!(!manifest.contains("android:screenOrientation"));
+
+    let manifest = render_test_manifest(
+        r#"
+        orientation = "landscape"
+        resizable_activity = false
+        launch_mode = "singleTask"
+        debuggable = true
+        allow_backup = false
+        hardware_accelerated = true
+
+        [activity_attributes]
+        "android:immersive" = "true"
+        "#,
+    );
+    assert!(manifest.contains(
+        r#"
+            android:debuggable="true"
+            android:allowBackup="false"
+            android:hardwareAccelerated="true""#
+    ));
+    assert!(manifest.contains(
+        r#"
+                android:screenOrientation="landscape"
+                android:resizeableActivity="false"
+                android:launchMode="singleTask"
+                android:immersive="true""#
+    ));
+}
+
+#[test]
+fn manifest_activities() {
+    let manifest = render_test_manifest(
+        r#"
+        [[activities]]
+        name = ".OAuthActivity"
+        exported = true
+        launch_mode = "singleTask"
+
+        [[activities.intent_filter]]
+        actions = ["android.intent.action.VIEW"]
+        categories = ["android.intent.category.DEFAULT", "android.intent.category.BROWSABLE"]
+        data = [{ scheme = "myapp", host = "oauth" }]
+
+        [[activities]]
+        name = "com.example.LicenseActivity"
+        label = "Licenses"
+        "#,
+    );
+    let oauth = manifest.find(r#"android:name=".OAuthActivity""#).unwrap();
+    let license = manifest
+        .find(r#"android:name="com.example.LicenseActivity""#)
+        .unwrap();
+    assert!(manifest.find(r#"android:name=".MainActivity""#).unwrap() < oauth);
+    assert!(oauth < license);
+    assert!(manifest.contains(r#"android:launchMode="singleTask""#));
+    assert!(manifest.contains(r#"<action android:name="android.intent.action.VIEW" />"#));
+    assert!(manifest.contains(r#"<category android:name="android.intent.category.BROWSABLE" />"#));
+    assert!(manifest.contains(r#"<data android:scheme="myapp" android:host="oauth" />"#));
+    assert!(manifest.contains(r#"android:label="Licenses""#));
+}
+
+#[test]
+fn manifest_exported_components() {
+    // `android:exported` of the main activity and of the services, in the parsed manifest
+    let exported = |metadata: &str| {
+        let manifest = xml::parse(&render_test_manifest(metadata)).unwrap();
+        let application = manifest
+            .elements()
+            .iter()
+            .find(|element| element.name() == "application")
+            .unwrap();
+        application
+            .elements()
+            .iter()
+            .filter(|element| matches!(element.name(), "activity" | "service"))
+            .map(|element| {
+                let value = element.attribute("android:exported").unwrap_or_default();
+                format!("{} {}", element.name(), value)
+            })
+            .collect::<Vec<_>>()
+    };
+    let service = "[[service]]\nname = \".AudioService\"\nenabled = true\n";
+
+    // Targeting Android 12 requires the values, services are only bound to by the app itself
+    assert_eq!(exported(service), vec!["service false", "activity true"]);
+    assert_eq!(
+        exported(&format!("target_sdk_version = 30\n{}", service)),
+        vec!["service ", "activity "]
+    );
+    assert_eq!(
+        exported(&format!("{}exported = true\n", service)),
+        vec!["service true", "activity true"]
+    );
+
+    // Unexported on purpose
+    assert_eq!(
+        exported("[activity]\nexported = false"),
+        vec!["activity false"]
+    );
+    // The value of `activity_attributes` is kept, without a second attribute
+    let manifest = render_test_manifest("[activity_attributes]\n\"android:exported\" = \"false\"");
+    assert_eq!(manifest.matches("android:exported").count(), 1);
+    assert!(manifest.contains(r#"android:exported="false""#));
+}
+
+#[test]
+fn manifest_main_activity_intent_filters() {
+    let manifest = render_test_manifest("target_sdk_version = 30");
+    assert!(!manifest.contains("android:exported"));
+
+    let manifest = render_test_manifest(
+        r#"
+        [[activity.intent_filter]]
+        actions = ["android.intent.action.VIEW"]
+        categories = ["android.intent.category.DEFAULT", "android.intent.category.BROWSABLE"]
+        data = [{ scheme = "https", host = "example.com", path_prefix = "/play" }]
+        auto_verify = true
+
+        [[activity.intent_filter]]
+        actions = ["android.intent.action.SEND"]
+        categories = ["android.intent.category.DEFAULT"]
+        data = [{ mime_type = "text/plain" }]
+        "#,
+    );
+    assert!(manifest.contains(
+        r#"<activity android:name=".MainActivity"
+                android:label="app"
+                android:configChanges="orientation|keyboardHidden|screenSize|uiMode|density"
+                android:exported="true">
+            <meta-data android:name="android.app.lib_name" android:value="app" />
+            <intent-filter>
+                <action android:name="android.intent.action.MAIN" />
+                <category android:name="android.intent.category.LAUNCHER" />
+            </intent-filter>
+            <intent-filter android:autoVerify="true">
+                <action android:name="android.intent.action.VIEW" />
+                <category android:name="android.intent.category.DEFAULT" />
+                <category android:name="android.intent.category.BROWSABLE" />
+                <data android:scheme="https" android:host="example.com" android:pathPrefix="/play" />
+            </intent-filter>
+            <intent-filter>
+                <action android:name="android.intent.action.SEND" />
+                <category android:name="android.intent.category.DEFAULT" />
+                <data android:mimeType="text/plain" />
+            </intent-filter>
+        </activity>"#
+    ));
+}
+
+#[test]
+fn manifest_test_only_and_sandbox_version() {
+    let manifest = render_test_manifest("");
+    assert!(!manifest.contains("android:testOnly"));
+    assert!(!manifest.contains("android:targetSandboxVersion"));
+
+    let manifest = render_test_manifest(
+        r#"
+        test_only = true
+        target_sandbox_version = 2
+        "#,
+    );
+    assert!(manifest.contains(r#"android:testOnly="true""#));
+    assert!(manifest.contains(
+        r#"android:versionName="0.1.0"
+        android:targetSandboxVersion="2">"#
+    ));
+
+    let err = crate::config::from_metadata("target_sandbox_version = 3")
+        .resolve((TargetKind::Bin, "app".to_owned()))
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("expected 1 or 2"));
+}
+
+#[test]
+fn manifest_legacy_external_storage() {
+    let metadata = |target_sdk_version: u32| {
+        format!(
+            "target_sdk_version = {}\nrequest_legacy_external_storage = true",
+            target_sdk_version
+        )
+    };
+
+    let config = crate::config::from_metadata(&metadata(29));
+    let target_config = config.resolve((TargetKind::Bin, "app".to_owned())).unwrap();
+    assert_eq!(legacy_storage_warning(&config, &target_config), None);
+    assert!(render_test_manifest(&metadata(29))
+        .contains(r#"android:requestLegacyExternalStorage="true""#));
+
+    let config = crate::config::from_metadata(&metadata(30));
+    let target_config = config.resolve((TargetKind::Bin, "app".to_owned())).unwrap();
+    assert!(legacy_storage_warning(&config, &target_config)
+        .unwrap()
+        .contains("MANAGE_EXTERNAL_STORAGE"));
+    assert!(!render_test_manifest(&metadata(30)).contains("requestLegacyExternalStorage"));
+}
 
-    // Write final AndroidManifest
-    writeln!(
-        file,
+#[test]
+fn manifest_profileable() {
+    let metadata = |target_sdk_version: u32| {
+        format!(
+            "target_sdk_version = {}\nprofileable = true",
+            target_sdk_version
+        )
+    };
+
+    let config = crate::config::from_metadata(&metadata(29));
+    let target_config = config.resolve((TargetKind::Bin, "app".to_owned())).unwrap();
+    assert_eq!(profileable_warning(&config, &target_config), None);
+    assert!(render_test_manifest(&metadata(29)).contains(
+        r#"android:label="app">
+        <profileable android:shell="true" />
+        <activity android:name=".MainActivity""#
+    ));
+    assert!(!render_test_manifest("target_sdk_version = 29").contains("<profileable"));
+
+    // Below API 29 the element is left out and the key is linted
+    let config = crate::config::from_metadata(&metadata(28));
+    let target_config = config.resolve((TargetKind::Bin, "app".to_owned())).unwrap();
+    assert!(!render_test_manifest(&metadata(28)).contains("<profileable"));
+    assert_eq!(
+        profileable_warning(&config, &target_config).unwrap(),
+        "`profileable` is ignored when targeting API 28 (below 29)"
+    );
+
+    // Debug builds are debuggable anyway
+    let config = crate::config::from_metadata(&metadata(29));
+    let target_config = config.resolve((TargetKind::Bin, "app".to_owned())).unwrap();
+    assert_eq!(profileable_warning(&config, &target_config), None);
+
+    let mut config = crate::config::from_metadata(&format!(
+        "{}\n[application_attributes]\n\"android:debuggable\" = \"true\"",
+        metadata(29)
+    ));
+    config.release = true;
+    let target_config = config.resolve((TargetKind::Bin, "app".to_owned())).unwrap();
+    assert!(profileable_warning(&config, &target_config)
+        .unwrap()
+        .contains("redundant"));
+
+    // `--profileable` enables it without the key
+    let mut config = crate::config::from_metadata("target_sdk_version = 29");
+    config.overrides.profileable = Some(crate::config::Override {
+        value: true,
+        source: "cli".to_owned(),
+    });
+    let target_config = config.resolve((TargetKind::Bin, "app".to_owned())).unwrap();
+    assert!(
+        render_manifest(&config, &target_config, "app", &util::JavaFiles::default())
+            .contains(r#"<profileable android:shell="true" />"#)
+    );
+}
+
+#[test]
+fn manifest_locale_config() {
+    let metadata = |target_sdk_version: u32| {
+        format!(
+            "android_version = 33\ntarget_sdk_version = {}\nsupported_locales = [\"en\", \"de\"]",
+            target_sdk_version
+        )
+    };
+
+    assert!(render_test_manifest(&metadata(33)).contains(
+        r#"<application android:hasCode="true"
+            android:label="app"
+            android:localeConfig="@xml/locales_config">"#
+    ));
+    assert!(!render_test_manifest(&metadata(32)).contains("android:localeConfig"));
+    assert!(!render_test_manifest("target_sdk_version = 33").contains("android:localeConfig"));
+    assert!(!render_test_manifest(
+        "android_version = 32\ntarget_sdk_version = 33\nsupported_locales = [\"en\"]"
+    )
+    .contains("android:localeConfig"));
+}
+
+#[test]
+fn manifest_escapes_values() {
+    let mut config = crate::config::from_metadata("");
+    config.overrides = crate::config::ManifestOverrides::new(
+        Some(&"1.0 \"beta\"".to_owned()),
+        None,
+        Some(&"Tom & Jerry's <App>".to_owned()),
+        |_| None,
+    )
+    .unwrap();
+    let target_config = config.resolve((TargetKind::Bin, "app".to_owned())).unwrap();
+    let manifest = render_manifest(&config, &target_config, "app", &util::JavaFiles::default());
+    assert!(manifest.contains(r#"android:versionName="1.0 &quot;beta&quot;""#));
+    assert!(manifest.contains(r#"android:label="Tom &amp; Jerry&apos;s &lt;App&gt;""#));
+}
+
+#[test]
+fn manifest_elements() {
+    let metadata = r#"
+        label = "Rock & Roll's"
+        target_sdk_version = 31
+        min_sdk_version = 26
+
+        [[feature]]
+        name = "android.hardware.camera"
+        required = false
+
+        [[feature]]
+        name = "android.hardware.vulkan.level"
+        required = true
+        version = "1"
+
+        [[permission]]
+        name = "android.permission.INTERNET"
+
+        [[permission]]
+        name = "android.permission.WRITE_EXTERNAL_STORAGE"
+        max_sdk_version = 18
+
+        [[service]]
+        name = ".AudioService"
+        enabled = true
+
+        [[service]]
+        name = ".SyncService"
+        enabled = false
+        process = ":sync"
+        "#;
+    let manifest = render_test_manifest(metadata);
+    assert_eq!(
+        manifest,
         r#"<?xml version="1.0" encoding="utf-8"?>
 <manifest xmlns:android="http://schemas.android.com/apk/res/android"
-        package="{package}"
-        android:versionCode="{version_code}"
-        android:versionName="{version_name}">
-    <uses-sdk android:targetSdkVersion="{targetSdkVersion}" android:minSdkVersion="{minSdkVersion}" />
-    <uses-feature android:glEsVersion="{glEsVersion}" android:required="true"></uses-feature>{uses_features}{uses_permissions}
-    <application {application_attrs} >
-        {services}
-        <activity {activity_attrs} >
-            <meta-data android:name="android.app.lib_name" android:value="{target_name}" />
+        package="rust.app"
+        android:versionCode="1"
+        android:versionName="0.1.0">
+    <uses-sdk android:targetSdkVersion="31" android:minSdkVersion="26" />
+    <uses-feature android:glEsVersion="0x00020000" android:required="true" />
+    <uses-feature android:name="android.hardware.camera" android:required="false" />
+    <uses-feature android:name="android.hardware.vulkan.level" android:required="true" android:version="1" />
+    <uses-permission android:name="android.permission.INTERNET" />
+    <uses-permission android:name="android.permission.WRITE_EXTERNAL_STORAGE" android:maxSdkVersion="18" />
+    <application android:hasCode="true"
+            android:label="Rock &amp; Roll&apos;s">
+        <service android:name=".AudioService" android:enabled="true" android:exported="false" />
+        <service android:name=".SyncService" android:enabled="false" android:process=":sync" android:exported="false" />
+        <activity android:name=".MainActivity"
+                android:label="Rock &amp; Roll&apos;s"
+                android:configChanges="orientation|keyboardHidden|screenSize|uiMode|density"
+                android:exported="true">
+            <meta-data android:name="android.app.lib_name" android:value="app" />
             <intent-filter>
                 <action android:name="android.intent.action.MAIN" />
                 <category android:name="android.intent.category.LAUNCHER" />
             </intent-filter>
         </activity>
     </application>
-</manifest>"#,
-        package = target_config.package_name.replace("-", "_"),
-        version_code = target_config.version_code,
-        version_name = target_config.version_name,
-        targetSdkVersion = config.target_sdk_version,
-        minSdkVersion = config.min_sdk_version,
-        glEsVersion = format!(
-            "0x{:04}{:04}",
-            target_config.opengles_version_major, target_config.opengles_version_minor
+</manifest>
+"#
+    );
+    // Stable, so that the unchanged manifest isn't written again
+    assert_eq!(render_test_manifest(metadata), manifest);
+}
+
+#[test]
+fn manifest_template() {
+    let root = std::env::temp_dir().join(format!(
+        "cargo-quad-apk-manifest-template-{}",
+        std::process::id()
+    ));
+    fs::create_dir_all(&root).unwrap();
+    let template_path = root.join("AndroidManifest.xml");
+    let config = crate::config::from_metadata(
+        r#"
+        label = "Game"
+        target_sdk_version = 31
+        min_sdk_version = 26
+        "#,
+    );
+    let mut target_config = config.resolve((TargetKind::Bin, "app".to_owned())).unwrap();
+    target_config.manifest_template = Some(template_path.clone());
+    let java_files = util::JavaFiles {
+        java_services: vec![".AudioService".to_owned()],
+        ..Default::default()
+    };
+
+    fs::write(
+        &template_path,
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<!-- Widgets aren't generated -->
+<manifest xmlns:android="http://schemas.android.com/apk/res/android" package="${packageName}">
+    <application android:label="My &amp; Game">
+        <activity android:name=".MainActivity" android:screenOrientation="landscape" />
+        <receiver android:name=".Widget" android:exported="false">
+            <meta-data android:name="android.appwidget.provider" android:resource="@xml/widget" />
+        </receiver>
+        <provider android:name="${packageName}.Files" android:authorities="${packageName}.files" />
+    </application>
+</manifest>
+"#,
+    )
+    .unwrap();
+    let (manifest, warnings) =
+        manifest_document(&config, &target_config, "app", &java_files).unwrap();
+    assert_eq!(
+        warnings,
+        vec![
+            "the manifest template sets `android:label` of `<application>` to `My & Game`, \
+             replacing `Game` of the configuration"
+        ]
+    );
+    assert_eq!(
+        manifest,
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<manifest xmlns:android="http://schemas.android.com/apk/res/android"
+        package="rust.app"
+        android:versionCode="1"
+        android:versionName="0.1.0">
+    <application android:label="My &amp; Game"
+            android:hasCode="true">
+        <activity android:name=".MainActivity"
+                android:screenOrientation="landscape"
+                android:label="Game"
+                android:configChanges="orientation|keyboardHidden|screenSize|uiMode|density"
+                android:exported="true">
+            <meta-data android:name="android.app.lib_name" android:value="app" />
+            <intent-filter>
+                <action android:name="android.intent.action.MAIN" />
+                <category android:name="android.intent.category.LAUNCHER" />
+            </intent-filter>
+        </activity>
+        <receiver android:name=".Widget"
+                android:exported="false">
+            <meta-data android:name="android.appwidget.provider" android:resource="@xml/widget" />
+        </receiver>
+        <provider android:name="rust.app.Files" android:authorities="rust.app.files" />
+        <service android:name=".AudioService" android:enabled="true" android:exported="false" />
+    </application>
+    <uses-sdk android:targetSdkVersion="31" android:minSdkVersion="26" />
+    <uses-feature android:glEsVersion="0x00020000" android:required="true" />
+</manifest>
+"#
+    );
+
+    fs::write(&template_path, "<manifest package=\"${applicationId}\" />").unwrap();
+    assert_eq!(
+        manifest_document(&config, &target_config, "app", &java_files)
+            .unwrap_err()
+            .to_string(),
+        format!(
+            "Invalid manifest template `{}`: unknown placeholder `${{applicationId}}`, expected \
+             `${{packageName}}` or `${{libraryName}}`",
+            template_path.display()
+        )
+    );
+    fs::write(&template_path, "<application />").unwrap();
+    assert!(
+        manifest_document(&config, &target_config, "app", &java_files)
+            .unwrap_err()
+            .to_string()
+            .contains("is `<application>`, expected `<manifest>`")
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn missing_activities() {
+    let config = crate::config::from_metadata(
+        r#"
+        package_name = "com.example"
+
+        [[activities]]
+        name = ".OAuthActivity"
+
+        [[activities]]
+        name = "com.example.LicenseActivity"
+
+        [[activities]]
+        name = "com.example.ads.AdActivity"
+
+        [[activities]]
+        name = ".billing.BillingActivity"
+        "#,
+    );
+    let target_config = config.resolve((TargetKind::Bin, "app".to_owned())).unwrap();
+    let java_file = |local_path: &str| {
+        (
+            PathBuf::from("/plugin").join(local_path),
+            PathBuf::from(local_path),
+        )
+    };
+    let java_files = util::JavaFiles {
+        java_files: vec![
+            java_file("java/com/example/OAuthActivity.java"),
+            // Same class name, in another package
+            java_file("java/com/example/ads/LicenseActivity.java"),
+            java_file("java/com/example/ads/AdActivity.java"),
+            java_file("java/com/example/billing/BillingActivity.java"),
+        ],
+        ..Default::default()
+    };
+    assert_eq!(
+        missing_activity_classes(&target_config, &java_files),
+        vec!["com.example.LicenseActivity"]
+    );
+
+    // Relative to the application id as a Java package
+    let mut target_config = target_config;
+    target_config.package_name = "com.other".to_owned();
+    assert_eq!(
+        missing_activity_classes(&target_config, &java_files),
+        vec![
+            ".OAuthActivity",
+            "com.example.LicenseActivity",
+            ".billing.BillingActivity"
+        ]
+    );
+}
+
+#[test]
+fn process_name_warnings() {
+    let config = crate::config::from_metadata(
+        r#"
+        [[service]]
+        name = ".AudioService"
+        enabled = true
+        process = "com.example.audio"
+        "#,
+    );
+    let target_config = config.resolve((TargetKind::Bin, "app".to_owned())).unwrap();
+    let warnings = process_warnings(&target_config);
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].contains("com.example.audio"));
+
+    let config = crate::config::from_metadata(r#"activity_process = ":main""#);
+    let target_config = config.resolve((TargetKind::Bin, "app".to_owned())).unwrap();
+    let warnings = process_warnings(&target_config);
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].contains("JNI"));
+}
+
+#[test]
+fn contributed_uses_features() {
+    let config = crate::config::from_metadata(
+        r#"
+        [[feature]]
+        name = "android.hardware.camera"
+        required = false
+
+        [[feature]]
+        name = "android.hardware.vulkan.version"
+        version = "0x400003"
+        required = false
+        "#,
+    );
+    let target_config = config.resolve((TargetKind::Bin, "app".to_owned())).unwrap();
+    let feature = |name: &str, required: bool, version: Option<&str>| AndroidFeature {
+        name: name.to_owned(),
+        required,
+        version: version.map(str::to_owned),
+    };
+    let java_files = util::JavaFiles {
+        uses_features: vec![
+            (
+                "ble_plugin".to_owned(),
+                feature("android.hardware.bluetooth_le", true, None),
+            ),
+            (
+                "ble_plugin".to_owned(),
+                feature("android.hardware.vulkan.version", false, Some("0x401000")),
+            ),
+            (
+                "camera_plugin".to_owned(),
+                feature("android.hardware.camera", true, None),
+            ),
+            (
+                "camera_plugin".to_owned(),
+                feature("android.hardware.bluetooth_le", false, None),
+            ),
+        ],
+        ..Default::default()
+    };
+
+    // Required by any package wins, and features are kept once in the order they were declared
+    let (features, warnings) = uses_features(&config, &target_config, &java_files);
+    assert_eq!(
+        features,
+        vec![
+            feature("android.hardware.camera", true, None),
+            feature("android.hardware.vulkan.version", false, Some("0x401000")),
+            feature("android.hardware.bluetooth_le", true, None),
+        ]
+    );
+    assert_eq!(
+        warnings,
+        vec![
+            "uses-feature `android.hardware.vulkan.version` is declared with the versions \
+             0x400003 (app), 0x401000 (ble_plugin), the highest one, 0x401000, is used"
+        ]
+    );
+
+    let manifest = render_manifest(&config, &target_config, "app", &java_files);
+    assert!(manifest.contains(
+        r#"
+    <uses-feature android:name="android.hardware.camera" android:required="true" />
+    <uses-feature android:name="android.hardware.vulkan.version" android:required="false" android:version="0x401000" />
+    <uses-feature android:name="android.hardware.bluetooth_le" android:required="true" />
+"#
+    ));
+    assert_eq!(manifest.matches("<uses-feature android:name=").count(), 3);
+
+    // Without contributions, the features of the app are kept as they are
+    let (features, warnings) = uses_features(&config, &target_config, &Default::default());
+    assert_eq!(features, target_config.features);
+    assert!(warnings.is_empty());
+}
+
+#[test]
+fn native_debug_symbols_zip() {
+    let root = std::env::temp_dir().join(format!("cargo-quad-apk-symbols-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let library = |abi: AndroidBuildTarget, filename: &str| SharedLibrary {
+        abi,
+        path: root.join("stripped").join(filename),
+        filename: filename.to_owned(),
+        link: None,
+    };
+    let libraries = vec![
+        library(AndroidBuildTarget::Arm64V8a, "libapp.so"),
+        library(AndroidBuildTarget::Arm64V8a, "libc++_shared.so"),
+        library(AndroidBuildTarget::X86_64, "libapp.so"),
+    ];
+    let zip_path = root.join("apk").join("app-native-debug-symbols.zip");
+    fs::create_dir_all(zip_path.parent().unwrap()).unwrap();
+
+    // Libraries linked by earlier versions have no copy
+    assert!(!write_native_debug_symbols(&root, &libraries, &zip_path).unwrap());
+    assert!(!zip_path.exists());
+
+    for abi in &[AndroidBuildTarget::Arm64V8a, AndroidBuildTarget::X86_64] {
+        let dir = symbol_directory(&root, *abi);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("libapp.so"), abi.android_abi()).unwrap();
+    }
+    assert!(write_native_debug_symbols(&root, &libraries, &zip_path).unwrap());
+    let mut archive = zip::ZipArchive::new(fs::File::open(&zip_path).unwrap()).unwrap();
+    assert_eq!(
+        archive.file_names().collect::<BTreeSet<_>>(),
+        ["arm64-v8a/libapp.so", "x86_64/libapp.so"]
+            .iter()
+            .copied()
+            .collect()
+    );
+    let mut contents = String::new();
+    std::io::Read::read_to_string(
+        &mut archive.by_name("x86_64/libapp.so").unwrap(),
+        &mut contents,
+    )
+    .unwrap();
+    assert_eq!(contents, "x86_64");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn packaged_library_collisions() {
+    let root = std::env::temp_dir().join(format!(
+        "cargo-quad-apk-packaged-libraries-{}",
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&root);
+    let library = |abi: AndroidBuildTarget, path: &str, contents: &str| {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, contents).unwrap();
+        SharedLibrary {
+            abi,
+            filename: path.file_name().unwrap().to_str().unwrap().to_owned(),
+            path,
+            link: None,
+        }
+    };
+    let names = |libraries: &[SharedLibrary]| {
+        libraries
+            .iter()
+            .map(|library| format!("{}/{}", library.abi.android_abi(), library.filename))
+            .collect::<Vec<_>>()
+    };
+
+    // Sorted by ABI, then filename
+    let libraries = vec![
+        library(AndroidBuildTarget::X86_64, "x86_64/libgame.so", "x86_64"),
+        library(AndroidBuildTarget::Arm64V8a, "arm64/libgame.so", "arm64"),
+        library(
+            AndroidBuildTarget::Arm64V8a,
+            "arm64/libc++_shared.so",
+            "c++",
         ),
-        uses_features = uses_features,
-        uses_permissions = uses_permissions,
-        application_attrs = application_attrs,
-        activity_attrs = activity_attrs,
-        target_name = target.name(),
-        services = services
-    )?;
+    ];
+    assert_eq!(
+        names(&packaged_libraries("game", &libraries).unwrap()),
+        [
+            "arm64-v8a/libc++_shared.so",
+            "arm64-v8a/libgame.so",
+            "x86_64/libgame.so"
+        ]
+    );
 
-    Ok(())
+    // The same library found in two search paths is packaged once
+    let mut libraries = vec![
+        library(AndroidBuildTarget::Arm64V8a, "deps/libdep.so", "dep"),
+        library(AndroidBuildTarget::Arm64V8a, "lib/libdep.so", "dep"),
+        library(AndroidBuildTarget::Arm64V8a, "stripped/libgame.so", "game"),
+    ];
+    libraries[1].link = Some(compile::LinkInfo {
+        platform: 21,
+        platform_dir: root.join("ndk"),
+        clang: root.join("clang"),
+        linker: root.join("ld"),
+    });
+    let packaged = packaged_libraries("game", &libraries).unwrap();
+    assert_eq!(
+        names(&packaged),
+        ["arm64-v8a/libdep.so", "arm64-v8a/libgame.so"]
+    );
+    assert_eq!(packaged[0].path, root.join("lib/libdep.so"));
+
+    // A dependency yielding the library of the bin target
+    let libraries = vec![
+        library(AndroidBuildTarget::Arm64V8a, "stripped/libgame.so", "game"),
+        library(AndroidBuildTarget::Arm64V8a, "deps/libgame.so", "cdylib"),
+    ];
+    assert_eq!(
+        packaged_libraries("game", &libraries)
+            .unwrap_err()
+            .to_string(),
+        format!(
+            "Target 'game' has two different libraries packaged as `lib/arm64-v8a/libgame.so`: \
+             `{}` and `{}`",
+            root.join("stripped/libgame.so").display(),
+            root.join("deps/libgame.so").display()
+        )
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn flavor_artifact_paths() {
+    let metadata = r#"
+        [flavors.demo]
+        package_name_suffix = ".demo"
+
+        [flavors.full]
+        features = ["full"]
+    "#;
+    let root_build_dir = PathBuf::from("/app/target/android-artifacts/release");
+    let config = crate::config::from_metadata(metadata);
+    assert_eq!(
+        util::flavor_build_directory(root_build_dir.clone(), config.flavor.as_deref()),
+        root_build_dir
+    );
+    assert_eq!(apk_stem(&config, "app"), "app");
+
+    let demo = config.with_flavor("demo").unwrap();
+    assert_eq!(
+        util::flavor_build_directory(root_build_dir.clone(), demo.flavor.as_deref()),
+        root_build_dir.join("flavors/demo")
+    );
+    assert_eq!(apk_stem(&demo, "app"), "app-demo");
+}
+
+#[test]
+fn flavor_library_groups() {
+    let metadata = r#"
+        [flavors.demo]
+        package_name_suffix = ".demo"
+
+        [flavors.trial]
+        package_name_suffix = ".demo"
+        label = "Trial"
+
+        [flavors.full]
+        features = ["full"]
+
+        [flavors.store]
+        features = ["full"]
+        version_name_suffix = "-store"
+    "#;
+    let config = crate::config::from_metadata(metadata);
+    let flavor = |name: &str, features: &[&str]| {
+        let mut config = config.with_flavor(name).unwrap();
+        config.cargo_features = features.iter().map(|f| f.to_string()).collect();
+        config
+    };
+    let configs = vec![
+        flavor("full", &["full"]),
+        flavor("demo", &[]),
+        flavor("store", &["full"]),
+        flavor("trial", &[]),
+    ];
+    let names = |groups: Vec<Vec<&AndroidConfig>>| {
+        groups
+            .iter()
+            .map(|group| {
+                group
+                    .iter()
+                    .map(|config| config.flavor.clone().unwrap())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>()
+    };
+    // The libraries are shared by the flavors of the same features and package name
+    assert_eq!(
+        names(library_groups(&configs)),
+        vec![vec!["full", "store"], vec!["demo", "trial"]]
+    );
+
+    // The package name is compiled into the libraries
+    let mut configs = configs;
+    configs.push(flavor("demo", &["full"]));
+    assert_eq!(
+        names(library_groups(&configs)),
+        vec![vec!["full", "store"], vec!["demo", "trial"], vec!["demo"]]
+    );
+}
+
+#[test]
+fn apks_to_install() {
+    let apk = |name: &str| PathBuf::from(format!("/apk/{}.apk", name));
+    let app = (TargetKind::Bin, "app".to_owned());
+    let example = (TargetKind::ExampleBin, "demo".to_owned());
+    let build_result = BuildResult {
+        target_to_apk_map: vec![(app.clone(), apk("app")), (example.clone(), apk("demo"))]
+            .into_iter()
+            .collect(),
+        split_apks: vec![(
+            app.clone(),
+            vec![
+                ("arm64-v8a", apk("app-arm64-v8a")),
+                ("armeabi-v7a", apk("app-armeabi-v7a")),
+            ]
+            .into_iter()
+            .collect(),
+        )]
+        .into_iter()
+        .collect(),
+        bundles: BTreeMap::new(),
+    };
+
+    assert_eq!(
+        build_result.apks_to_install(&app, "arm64-v8a", false),
+        vec![&apk("app-arm64-v8a")]
+    );
+    // No split for the ABI, or no splits at all
+    assert_eq!(
+        build_result.apks_to_install(&app, "x86_64", false),
+        vec![&apk("app")]
+    );
+    assert_eq!(
+        build_result.apks_to_install(&example, "arm64-v8a", true),
+        vec![&apk("demo")]
+    );
+    assert_eq!(
+        build_result.apks_to_install(&app, "x86_64", true),
+        vec![&apk("app-armeabi-v7a"), &apk("app-arm64-v8a")]
+    );
 }
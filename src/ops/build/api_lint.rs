@@ -0,0 +1,972 @@
+//! Lint of the calls of the Java code to APIs newer than `min_sdk_version`.
+//!
+//! javac compiles against the android.jar of `android_version`, so a call to a method added after
+//! `min_sdk_version` only fails on old devices, with a `NoSuchMethodError`. The method and field
+//! references of the compiled classes are looked up in the API database of the SDK,
+//! `api-versions.xml`, like the NewApi check of Android lint. Guards are detected per method: a
+//! method reading `Build.VERSION.SDK_INT` is assumed to check the API level around its calls.
+//!
+//! The same references are matched against the framework methods requiring a permission, as a
+//! call without its `uses-permission` only fails at runtime with a `SecurityException`. Only the
+//! common cases are known, and the permission requested by other means goes unnoticed.
+
+use super::util;
+use crate::config::AndroidConfig;
+use anyhow::format_err;
+use cargo::util::CargoResult;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const CLASS_MAGIC: u32 = 0xcafe_babe;
+const SDK_INT_CLASS: &str = "android/os/Build$VERSION";
+
+/// Framework method requiring a permission
+struct PermissionApi {
+    /// Internal name of the class declaring the method
+    class: &'static str,
+    method: &'static str,
+    /// Permission required, by the lowest `target_sdk_version` requiring it
+    permissions: &'static [(u32, &'static str)],
+}
+
+const BLUETOOTH: &[(u32, &str)] = &[
+    (1, "android.permission.BLUETOOTH"),
+    (31, "android.permission.BLUETOOTH_CONNECT"),
+];
+const BLUETOOTH_SCAN: &[(u32, &str)] = &[
+    (1, "android.permission.BLUETOOTH_ADMIN"),
+    (31, "android.permission.BLUETOOTH_SCAN"),
+];
+const CAMERA: &[(u32, &str)] = &[(1, "android.permission.CAMERA")];
+const FINE_LOCATION: &[(u32, &str)] = &[(1, "android.permission.ACCESS_FINE_LOCATION")];
+const RECORD_AUDIO: &[(u32, &str)] = &[(1, "android.permission.RECORD_AUDIO")];
+const VIBRATE: &[(u32, &str)] = &[(1, "android.permission.VIBRATE")];
+
+const PERMISSION_APIS: &[PermissionApi] = &[
+    PermissionApi {
+        class: "android/os/Vibrator",
+        method: "vibrate",
+        permissions: VIBRATE,
+    },
+    PermissionApi {
+        class: "android/os/VibratorManager",
+        method: "vibrate",
+        permissions: VIBRATE,
+    },
+    PermissionApi {
+        class: "android/bluetooth/BluetoothAdapter",
+        method: "getBondedDevices",
+        permissions: BLUETOOTH,
+    },
+    PermissionApi {
+        class: "android/bluetooth/BluetoothDevice",
+        method: "connectGatt",
+        permissions: BLUETOOTH,
+    },
+    PermissionApi {
+        class: "android/bluetooth/BluetoothAdapter",
+        method: "startDiscovery",
+        permissions: BLUETOOTH_SCAN,
+    },
+    PermissionApi {
+        class: "android/bluetooth/le/BluetoothLeScanner",
+        method: "startScan",
+        permissions: BLUETOOTH_SCAN,
+    },
+    PermissionApi {
+        class: "android/hardware/Camera",
+        method: "open",
+        permissions: CAMERA,
+    },
+    PermissionApi {
+        class: "android/hardware/camera2/CameraManager",
+        method: "openCamera",
+        permissions: CAMERA,
+    },
+    PermissionApi {
+        class: "android/media/AudioRecord",
+        method: "startRecording",
+        permissions: RECORD_AUDIO,
+    },
+    PermissionApi {
+        class: "android/media/MediaRecorder",
+        method: "setAudioSource",
+        permissions: RECORD_AUDIO,
+    },
+    PermissionApi {
+        class: "android/location/LocationManager",
+        method: "requestLocationUpdates",
+        permissions: FINE_LOCATION,
+    },
+    PermissionApi {
+        class: "android/location/LocationManager",
+        method: "getLastKnownLocation",
+        permissions: FINE_LOCATION,
+    },
+    // Notifications are only shown without asking below Android 13
+    PermissionApi {
+        class: "android/app/NotificationManager",
+        method: "notify",
+        permissions: &[(33, "android.permission.POST_NOTIFICATIONS")],
+    },
+];
+
+impl PermissionApi {
+    /// Returns the permission the method requires from an app targeting `target_sdk_version`
+    fn permission(&self, target_sdk_version: u32) -> Option<&'static str> {
+        self.permissions
+            .iter()
+            .rev()
+            .find(|(since, _)| *since <= target_sdk_version)
+            .map(|(_, permission)| *permission)
+    }
+}
+
+/// API levels of the classes and members of the platform, out of `api-versions.xml`
+#[derive(Debug, Default)]
+pub struct ApiDatabase {
+    classes: HashMap<String, ApiClass>,
+}
+
+#[derive(Debug, Default)]
+struct ApiClass {
+    since: u32,
+    /// Superclass and interfaces
+    supertypes: Vec<String>,
+    /// API level of the methods, by name and descriptor, and of the fields, by name
+    members: HashMap<String, u32>,
+}
+
+impl ApiDatabase {
+    /// Returns the path of the API database of the SDK: the one of the platform of
+    /// `android_version`, or the one of platform-tools
+    pub fn find(config: &AndroidConfig) -> Option<PathBuf> {
+        let platform = config
+            .android_jar_path
+            .parent()
+            .map(|dir| dir.join("data").join("api-versions.xml"));
+        let platform_tools = config
+            .sdk_path
+            .join("platform-tools")
+            .join("api")
+            .join("api-versions.xml");
+        platform
+            .into_iter()
+            .chain(Some(platform_tools))
+            .find(|path| path.exists())
+    }
+
+    pub fn load(path: &Path) -> CargoResult<ApiDatabase> {
+        let xml = fs::read_to_string(path)
+            .map_err(|err| format_err!("Unable to read `{}`: {}", path.display(), err))?;
+        Ok(ApiDatabase::parse(&xml))
+    }
+
+    /// Parses `api-versions.xml`, which has an element per line. Members without `since` have
+    /// the API level of their class.
+    fn parse(xml: &str) -> ApiDatabase {
+        let mut database = ApiDatabase::default();
+        let mut class: Option<(String, ApiClass)> = None;
+        for line in xml.lines().map(str::trim) {
+            let element = line
+                .strip_prefix('<')
+                .and_then(|line| line.split(|c: char| c.is_whitespace() || c == '>').next())
+                .unwrap_or_default();
+            let name = attribute(line, "name");
+            let since = attribute(line, "since").and_then(|since| since.parse().ok());
+            match (element, name, &mut class) {
+                ("class", Some(name), _) => {
+                    let since = since.unwrap_or(1);
+                    let api_class = ApiClass {
+                        since,
+                        ..ApiClass::default()
+                    };
+                    if line.ends_with("/>") {
+                        database.classes.insert(name, api_class);
+                    } else {
+                        class = Some((name, api_class));
+                    }
+                }
+                ("extends", Some(name), Some((_, api_class)))
+                | ("implements", Some(name), Some((_, api_class))) => {
+                    api_class.supertypes.push(name);
+                }
+                ("method", Some(name), Some((_, api_class)))
+                | ("field", Some(name), Some((_, api_class))) => {
+                    let since = since.unwrap_or(api_class.since);
+                    api_class.members.insert(name, since);
+                }
+                ("/class", _, _) => {
+                    if let Some((name, api_class)) = class.take() {
+                        database.classes.insert(name, api_class);
+                    }
+                }
+                _ => {}
+            }
+        }
+        database
+    }
+
+    /// Returns the API level of `member` looked up from `class`, and the class declaring it
+    fn member_since<'a>(&'a self, class: &'a str, member: &str) -> Option<(&'a str, u32)> {
+        let api_class = self.classes.get(class)?;
+        if let Some(&since) = api_class.members.get(member) {
+            return Some((class, since));
+        }
+        api_class
+            .supertypes
+            .iter()
+            .find_map(|supertype| self.member_since(supertype, member))
+    }
+}
+
+/// Returns the value of the attribute `name` of an XML element, unescaped
+fn attribute(element: &str, name: &str) -> Option<String> {
+    let start = element.find(&format!(" {}=\"", name))? + name.len() + 3;
+    let end = start + element[start..].find('"')?;
+    Some(
+        element[start..end]
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&amp;", "&"),
+    )
+}
+
+/// Field or method referenced by the bytecode
+#[derive(Debug, Clone, PartialEq)]
+struct MemberRef {
+    /// Internal name of the class the reference names, like `android/app/Activity`
+    class: String,
+    name: String,
+    descriptor: String,
+    is_method: bool,
+}
+
+impl MemberRef {
+    /// Key of the member in the API database
+    fn key(&self) -> String {
+        if self.is_method {
+            format!("{}{}", self.name, self.descriptor)
+        } else {
+            self.name.clone()
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Method {
+    name: String,
+    references: Vec<MemberRef>,
+}
+
+impl Method {
+    /// Whether the method reads `Build.VERSION.SDK_INT`, assumed to guard its calls
+    fn checks_sdk_int(&self) -> bool {
+        self.references
+            .iter()
+            .any(|reference| reference.class == SDK_INT_CLASS && reference.name == "SDK_INT")
+    }
+}
+
+/// What the lint needs of a class file
+#[derive(Debug)]
+struct ClassFile {
+    name: String,
+    supertypes: Vec<String>,
+    methods: Vec<Method>,
+}
+
+/// Big endian reader of a class file
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> CargoResult<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position + len)
+            .ok_or_else(|| format_err!("truncated at offset {}", self.position))?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> CargoResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> CargoResult<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> CargoResult<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+/// Entry of the constant pool, only those the lint reads are kept
+#[derive(Debug, Clone)]
+enum Constant {
+    Utf8(String),
+    Class(u16),
+    NameAndType(u16, u16),
+    Member {
+        class: u16,
+        name_and_type: u16,
+        is_method: bool,
+    },
+    Other,
+}
+
+struct ConstantPool(Vec<Constant>);
+
+impl ConstantPool {
+    fn get(&self, index: u16) -> CargoResult<&Constant> {
+        self.0
+            .get(index as usize)
+            .ok_or_else(|| format_err!("invalid constant pool index {}", index))
+    }
+
+    fn utf8(&self, index: u16) -> CargoResult<&str> {
+        match self.get(index)? {
+            Constant::Utf8(value) => Ok(value),
+            _ => Err(format_err!("constant {} is not a string", index)),
+        }
+    }
+
+    fn class_name(&self, index: u16) -> CargoResult<&str> {
+        match self.get(index)? {
+            Constant::Class(name) => self.utf8(*name),
+            _ => Err(format_err!("constant {} is not a class", index)),
+        }
+    }
+
+    /// Returns the member referenced by the constant at `index`, if it is a field or a method
+    fn member(&self, index: u16) -> CargoResult<Option<MemberRef>> {
+        let (class, name_and_type, is_method) = match self.get(index)? {
+            Constant::Member {
+                class,
+                name_and_type,
+                is_method,
+            } => (*class, *name_and_type, *is_method),
+            _ => return Ok(None),
+        };
+        let (name, descriptor) = match self.get(name_and_type)? {
+            Constant::NameAndType(name, descriptor) => (*name, *descriptor),
+            _ => {
+                return Err(format_err!(
+                    "constant {} is not a name and type",
+                    name_and_type
+                ))
+            }
+        };
+        Ok(Some(MemberRef {
+            class: self.class_name(class)?.to_owned(),
+            name: self.utf8(name)?.to_owned(),
+            descriptor: self.utf8(descriptor)?.to_owned(),
+            is_method,
+        }))
+    }
+}
+
+fn parse_class(bytes: &[u8]) -> CargoResult<ClassFile> {
+    let mut reader = Reader { bytes, position: 0 };
+    if reader.u32()? != CLASS_MAGIC {
+        return Err(format_err!("bad magic"));
+    }
+    // Minor and major versions
+    reader.take(4)?;
+
+    let count = reader.u16()?;
+    // Entries are numbered from 1, and longs and doubles take two
+    let mut constants = vec![Constant::Other];
+    while constants.len() < count as usize {
+        let tag = reader.u8()?;
+        let constant = match tag {
+            1 => {
+                let len = reader.u16()? as usize;
+                Constant::Utf8(String::from_utf8_lossy(reader.take(len)?).into_owned())
+            }
+            7 => Constant::Class(reader.u16()?),
+            9 | 10 | 11 => Constant::Member {
+                class: reader.u16()?,
+                name_and_type: reader.u16()?,
+                is_method: tag != 9,
+            },
+            12 => Constant::NameAndType(reader.u16()?, reader.u16()?),
+            3 | 4 | 17 | 18 => {
+                reader.take(4)?;
+                Constant::Other
+            }
+            5 | 6 => {
+                reader.take(8)?;
+                constants.push(Constant::Other);
+                Constant::Other
+            }
+            8 | 16 | 19 | 20 => {
+                reader.take(2)?;
+                Constant::Other
+            }
+            15 => {
+                reader.take(3)?;
+                Constant::Other
+            }
+            _ => return Err(format_err!("unknown constant tag {}", tag)),
+        };
+        constants.push(constant);
+    }
+    let pool = ConstantPool(constants);
+
+    // Access flags
+    reader.take(2)?;
+    let name = pool.class_name(reader.u16()?)?.to_owned();
+    let mut supertypes = vec![];
+    let super_class = reader.u16()?;
+    if super_class != 0 {
+        supertypes.push(pool.class_name(super_class)?.to_owned());
+    }
+    for _ in 0..reader.u16()? {
+        supertypes.push(pool.class_name(reader.u16()?)?.to_owned());
+    }
+
+    // Fields, whose attributes are skipped
+    for _ in 0..reader.u16()? {
+        reader.take(6)?;
+        for _ in 0..reader.u16()? {
+            reader.take(2)?;
+            let len = reader.u32()? as usize;
+            reader.take(len)?;
+        }
+    }
+
+    let mut methods = vec![];
+    for _ in 0..reader.u16()? {
+        reader.take(2)?;
+        let name = pool.utf8(reader.u16()?)?.to_owned();
+        reader.take(2)?;
+        let mut references = vec![];
+        for _ in 0..reader.u16()? {
+            let attribute_name = pool.utf8(reader.u16()?)?.to_owned();
+            let len = reader.u32()? as usize;
+            let attribute = reader.take(len)?;
+            if attribute_name == "Code" {
+                let mut code_reader = Reader {
+                    bytes: attribute,
+                    position: 4,
+                };
+                let code_len = code_reader.u32()? as usize;
+                for index in code_references(code_reader.take(code_len)?)? {
+                    references.extend(pool.member(index)?);
+                }
+            }
+        }
+        methods.push(Method { name, references });
+    }
+
+    Ok(ClassFile {
+        name,
+        supertypes,
+        methods,
+    })
+}
+
+/// Returns the constant pool indexes of the fields and methods accessed by the bytecode
+fn code_references(code: &[u8]) -> CargoResult<Vec<u16>> {
+    let mut reader = Reader {
+        bytes: code,
+        position: 0,
+    };
+    let mut references = vec![];
+    while reader.position < code.len() {
+        let opcode = reader.u8()?;
+        let operands = match opcode {
+            // getstatic, putstatic, getfield, putfield, invokevirtual, invokespecial, invokestatic
+            0xb2..=0xb8 => {
+                references.push(reader.u16()?);
+                0
+            }
+            // invokeinterface
+            0xb9 => {
+                references.push(reader.u16()?);
+                2
+            }
+            0x10 | 0x12 | 0x15..=0x19 | 0x36..=0x3a | 0xa9 | 0xbc => 1,
+            0x11 | 0x13 | 0x14 | 0x84 | 0x99..=0xa8 | 0xbb | 0xbd | 0xc0 | 0xc1 | 0xc6 | 0xc7 => 2,
+            0xc5 => 3,
+            0xba | 0xc8 | 0xc9 => 4,
+            // wide, widening the index of a load, store or iinc
+            0xc4 => {
+                if reader.u8()? == 0x84 {
+                    4
+                } else {
+                    2
+                }
+            }
+            // tableswitch and lookupswitch, padded to 4 bytes
+            0xaa | 0xab => {
+                let padding = (4 - reader.position % 4) % 4;
+                reader.take(padding + 4)?;
+                if opcode == 0xaa {
+                    let low = reader.u32()? as i32;
+                    let high = reader.u32()? as i32;
+                    (high - low + 1).max(0) as usize * 4
+                } else {
+                    reader.u32()? as usize * 8
+                }
+            }
+            _ => 0,
+        };
+        reader.take(operands)?;
+    }
+    Ok(references)
+}
+
+/// Call of the Java code to an API above `min_sdk_version`
+#[derive(Debug, PartialEq)]
+pub struct NewApiCall {
+    /// Method making the call, like `rust.app.MainActivity.onCreate`
+    pub caller: String,
+    /// Member called, like `android.app.Activity.isInMultiWindowMode()Z`
+    pub member: String,
+    pub since: u32,
+}
+
+/// Returns the calls of `classes` to APIs above `min_sdk_version`, except those of the methods
+/// reading `Build.VERSION.SDK_INT` and of those `allow` lists by class or by method
+fn new_api_calls(
+    database: &ApiDatabase,
+    classes: &[ClassFile],
+    min_sdk_version: u32,
+    allow: &[String],
+) -> Vec<NewApiCall> {
+    let app_classes = classes
+        .iter()
+        .map(|class| (class.name.as_str(), class))
+        .collect::<HashMap<_, _>>();
+    // Looks members up from the classes of the app, which extend those of the platform
+    fn lookup<'a>(
+        database: &'a ApiDatabase,
+        app_classes: &HashMap<&str, &'a ClassFile>,
+        class: &'a str,
+        member: &str,
+    ) -> Option<(&'a str, u32)> {
+        match app_classes.get(class) {
+            Some(app_class) => app_class
+                .supertypes
+                .iter()
+                .find_map(|supertype| lookup(database, app_classes, supertype, member)),
+            None => database.member_since(class, member),
+        }
+    }
+
+    let mut calls = vec![];
+    for class in classes {
+        let class_name = class.name.replace('/', ".");
+        for method in &class.methods {
+            let caller = format!("{}.{}", class_name, method.name);
+            let allowed = allow
+                .iter()
+                .any(|allowed| *allowed == class_name || *allowed == caller);
+            if allowed || method.checks_sdk_int() {
+                continue;
+            }
+            for reference in &method.references {
+                let key = reference.key();
+                let found = lookup(database, &app_classes, &reference.class, &key);
+                if let Some((declaring_class, since)) = found {
+                    let member = format!("{}.{}", declaring_class.replace('/', "."), key);
+                    let call = NewApiCall {
+                        caller: caller.clone(),
+                        member,
+                        since,
+                    };
+                    if since > min_sdk_version && !calls.contains(&call) {
+                        calls.push(call);
+                    }
+                }
+            }
+        }
+    }
+    calls
+}
+
+/// Call of the Java code to a framework method requiring a permission the manifest lacks
+#[derive(Debug, PartialEq)]
+pub struct MissingPermission {
+    /// Top-level class making the call, like `rust.app.MainActivity`
+    pub class: String,
+    /// Method making the call, like `rust.app.MainActivity.onCreate`
+    pub caller: String,
+    /// Method called, like `android.os.Vibrator.vibrate`
+    pub method: String,
+    pub permission: &'static str,
+}
+
+/// Returns the calls of `classes` to framework methods requiring a permission missing from
+/// `declared`
+fn missing_permissions(
+    classes: &[ClassFile],
+    target_sdk_version: u32,
+    declared: &[String],
+) -> Vec<MissingPermission> {
+    let mut missing = vec![];
+    for class in classes {
+        let class_name = class.name.replace('/', ".");
+        for method in &class.methods {
+            for reference in method
+                .references
+                .iter()
+                .filter(|reference| reference.is_method)
+            {
+                let permission = PERMISSION_APIS
+                    .iter()
+                    .find(|api| api.class == reference.class && api.method == reference.name)
+                    .and_then(|api| api.permission(target_sdk_version));
+                let permission = match permission {
+                    Some(permission) if !declared.iter().any(|name| name == permission) => {
+                        permission
+                    }
+                    _ => continue,
+                };
+                let call = MissingPermission {
+                    class: class_name.split('$').next().unwrap().to_owned(),
+                    caller: format!("{}.{}", class_name, method.name),
+                    method: format!("{}.{}", reference.class.replace('/', "."), reference.name),
+                    permission,
+                };
+                if !missing.contains(&call) {
+                    missing.push(call);
+                }
+            }
+        }
+    }
+    missing
+}
+
+fn read_classes(obj_dir: &Path) -> CargoResult<Vec<ClassFile>> {
+    let mut classes = vec![];
+    for path in util::find_files(obj_dir, "class")? {
+        let bytes = fs::read(&path)
+            .map_err(|err| format_err!("Unable to read `{}`: {}", path.display(), err))?;
+        classes.push(
+            parse_class(&bytes)
+                .map_err(|err| format_err!("Invalid class file `{}`: {}", path.display(), err))?,
+        );
+    }
+    Ok(classes)
+}
+
+/// Scans the classes compiled in `obj_dir` for calls to framework methods requiring a
+/// permission which isn't in `declared`
+pub fn check_permissions(
+    config: &AndroidConfig,
+    obj_dir: &Path,
+    declared: &[String],
+) -> CargoResult<Vec<MissingPermission>> {
+    Ok(missing_permissions(
+        &read_classes(obj_dir)?,
+        config.target_sdk_version,
+        declared,
+    ))
+}
+
+/// Returns the report of the calls of a target to methods requiring a missing permission, with
+/// the source of the calling classes found in `sources`
+pub fn permission_report(
+    target_name: &str,
+    missing: &[MissingPermission],
+    sources: &HashMap<String, String>,
+) -> String {
+    let mut report = format!(
+        "Java code of target '{}' calls methods requiring permissions the manifest doesn't \
+         declare, which fail with a SecurityException:",
+        target_name
+    );
+    for call in missing {
+        report.push_str(&format!("\n  {} calls {}", call.caller, call.method));
+        if let Some(source) = sources.get(&call.class) {
+            report.push_str(&format!(" (in {})", source));
+        }
+        report.push_str(&format!(
+            ", which requires `<uses-permission android:name=\"{}\" />`",
+            call.permission
+        ));
+    }
+    report.push_str("\nAdd them with `[[package.metadata.android.permission]]`.");
+    report
+}
+
+/// Scans the classes compiled in `obj_dir` for calls to APIs above `min_sdk_version`
+pub fn check_classes(
+    database: &ApiDatabase,
+    config: &AndroidConfig,
+    obj_dir: &Path,
+) -> CargoResult<Vec<NewApiCall>> {
+    let classes = read_classes(obj_dir)?;
+    let mut calls = new_api_calls(
+        database,
+        &classes,
+        config.min_sdk_version,
+        &config.api_lint_allow,
+    );
+    // Core library desugaring backports the Java library to the older devices
+    if config.desugaring.is_some() {
+        calls.retain(|call| !call.member.starts_with("java."));
+    }
+    Ok(calls)
+}
+
+/// Returns the report of the calls of a target to APIs above `min_sdk_version`
+pub fn report(target_name: &str, min_sdk_version: u32, calls: &[NewApiCall]) -> String {
+    let mut report = format!(
+        "Java code of target '{}' calls APIs above `min_sdk_version` {} without checking \
+         `Build.VERSION.SDK_INT`:",
+        target_name, min_sdk_version
+    );
+    for call in calls {
+        report.push_str(&format!(
+            "\n  {} calls {}, added in API {}",
+            call.caller, call.member, call.since
+        ));
+    }
+    report.push_str(
+        "\nCheck `Build.VERSION.SDK_INT` in the calling methods, or list them in `api_lint_allow`.",
+    );
+    report
+}
+
+#[cfg(test)]
+const TEST_DATABASE: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<api version="3" min="1">
+	<class name="android/app/Activity" since="1">
+		<extends name="android/view/ContextThemeWrapper"/>
+		<method name="&lt;init&gt;()V"/>
+		<method name="finish()V"/>
+		<method name="isInMultiWindowMode()Z" since="24"/>
+		<method name="setShowWhenLocked(Z)V" since="27"/>
+	</class>
+	<class name="android/os/Build$VERSION" since="4">
+		<extends name="java/lang/Object"/>
+		<field name="SDK_INT"/>
+	</class>
+	<class name="android/view/ContextThemeWrapper" since="1">
+		<extends name="java/lang/Object"/>
+		<method name="applyOverrideConfiguration(Landroid/content/res/Configuration;)V" since="17"/>
+	</class>
+	<class name="java/lang/Object" since="1">
+		<method name="&lt;init&gt;()V"/>
+	</class>
+</api>
+"#;
+
+#[test]
+fn api_database() {
+    let database = ApiDatabase::parse(TEST_DATABASE);
+    assert_eq!(
+        database.member_since("android/app/Activity", "isInMultiWindowMode()Z"),
+        Some(("android/app/Activity", 24))
+    );
+    // Members without `since` have the level of their class, and are found in supertypes
+    assert_eq!(
+        database.member_since("android/os/Build$VERSION", "SDK_INT"),
+        Some(("android/os/Build$VERSION", 4))
+    );
+    assert_eq!(
+        database.member_since(
+            "android/app/Activity",
+            "applyOverrideConfiguration(Landroid/content/res/Configuration;)V"
+        ),
+        Some(("android/view/ContextThemeWrapper", 17))
+    );
+    assert_eq!(
+        database.member_since("android/app/Activity", "missing()V"),
+        None
+    );
+    assert_eq!(
+        database.member_since("rust/app/MainActivity", "finish()V"),
+        None
+    );
+}
+
+#[test]
+fn class_file_references() {
+    let class = parse_class(include_bytes!(
+        "../../../tests/fixtures/api_lint/MainActivity.class"
+    ))
+    .unwrap();
+    assert_eq!(class.name, "rust/app/MainActivity");
+    assert_eq!(class.supertypes, vec!["android/app/Activity"]);
+    let method = |name: &str| {
+        class
+            .methods
+            .iter()
+            .find(|method| method.name == name)
+            .unwrap()
+    };
+    let references = |name: &str| {
+        method(name)
+            .references
+            .iter()
+            .map(|reference| format!("{}.{}", reference.class, reference.key()))
+            .collect::<Vec<_>>()
+    };
+
+    // The references after the tableswitch are found
+    assert_eq!(
+        references("unguarded"),
+        vec![
+            "rust/app/MainActivity.finish()V",
+            "rust/app/MainActivity.isInMultiWindowMode()Z"
+        ]
+    );
+    assert_eq!(
+        references("guarded"),
+        vec![
+            "android/os/Build$VERSION.SDK_INT",
+            "rust/app/MainActivity.setShowWhenLocked(Z)V"
+        ]
+    );
+    assert!(method("guarded").checks_sdk_int());
+    assert!(!method("allowed").checks_sdk_int());
+
+    assert!(parse_class(b"\xca\xfe\xba\xbe\0\0\0\x34\0\x10\x01").is_err());
+    assert!(parse_class(b"PK\x03\x04").is_err());
+}
+
+#[test]
+fn new_api_calls_above_min_sdk() {
+    let database = ApiDatabase::parse(TEST_DATABASE);
+    let classes = vec![parse_class(include_bytes!(
+        "../../../tests/fixtures/api_lint/MainActivity.class"
+    ))
+    .unwrap()];
+    let calls = |min_sdk_version: u32, allow: &[&str]| {
+        let allow = allow.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        new_api_calls(&database, &classes, min_sdk_version, &allow)
+    };
+
+    // The guarded call is left out, the inherited members are resolved through Activity
+    assert_eq!(
+        calls(21, &[]),
+        vec![
+            NewApiCall {
+                caller: "rust.app.MainActivity.unguarded".to_owned(),
+                member: "android.app.Activity.isInMultiWindowMode()Z".to_owned(),
+                since: 24,
+            },
+            NewApiCall {
+                caller: "rust.app.MainActivity.allowed".to_owned(),
+                member: "android.app.Activity.setShowWhenLocked(Z)V".to_owned(),
+                since: 27,
+            },
+        ]
+    );
+    assert_eq!(calls(24, &[]).len(), 1);
+    assert_eq!(calls(27, &[]), vec![]);
+
+    // Allowed by method, or by class
+    assert_eq!(calls(21, &["rust.app.MainActivity.allowed"]).len(), 1);
+    assert_eq!(calls(21, &["rust.app.MainActivity"]), vec![]);
+
+    assert_eq!(
+        report("app", 21, &calls(21, &["rust.app.MainActivity.allowed"])),
+        "Java code of target 'app' calls APIs above `min_sdk_version` 21 without checking \
+         `Build.VERSION.SDK_INT`:\n  rust.app.MainActivity.unguarded calls \
+         android.app.Activity.isInMultiWindowMode()Z, added in API 24\nCheck \
+         `Build.VERSION.SDK_INT` in the calling methods, or list them in `api_lint_allow`."
+    );
+}
+
+#[test]
+fn permissions_of_framework_methods() {
+    let calling = |api: &PermissionApi| {
+        vec![ClassFile {
+            name: "com/example/plugin/Haptics$1".to_owned(),
+            supertypes: vec!["java/lang/Object".to_owned()],
+            methods: vec![Method {
+                name: "run".to_owned(),
+                references: vec![
+                    MemberRef {
+                        class: api.class.to_owned(),
+                        name: api.method.to_owned(),
+                        descriptor: "()V".to_owned(),
+                        is_method: true,
+                    },
+                    // Fields named like a method are left out
+                    MemberRef {
+                        class: api.class.to_owned(),
+                        name: api.method.to_owned(),
+                        descriptor: "I".to_owned(),
+                        is_method: false,
+                    },
+                ],
+            }],
+        }]
+    };
+
+    for api in PERMISSION_APIS {
+        let permission = api.permission(34).unwrap();
+        assert_eq!(
+            missing_permissions(&calling(api), 34, &[]),
+            vec![MissingPermission {
+                class: "com.example.plugin.Haptics".to_owned(),
+                caller: "com.example.plugin.Haptics$1.run".to_owned(),
+                method: format!("{}.{}", api.class.replace('/', "."), api.method),
+                permission,
+            }],
+            "{}.{}",
+            api.class,
+            api.method
+        );
+        assert_eq!(
+            missing_permissions(&calling(api), 34, &[permission.to_owned()]),
+            vec![],
+            "{}.{}",
+            api.class,
+            api.method
+        );
+    }
+
+    // Permissions depending on the targeted API level
+    let api = |method: &str| {
+        PERMISSION_APIS
+            .iter()
+            .find(|api| api.method == method)
+            .unwrap()
+    };
+    assert_eq!(
+        api("startDiscovery").permission(30),
+        Some("android.permission.BLUETOOTH_ADMIN")
+    );
+    assert_eq!(
+        api("startDiscovery").permission(31),
+        Some("android.permission.BLUETOOTH_SCAN")
+    );
+    assert_eq!(
+        api("getBondedDevices").permission(30),
+        Some("android.permission.BLUETOOTH")
+    );
+    assert_eq!(api("notify").permission(32), None);
+    assert!(missing_permissions(&calling(api("notify")), 32, &[]).is_empty());
+
+    let missing = missing_permissions(&calling(api("vibrate")), 34, &[]);
+    let sources = vec![(
+        "com.example.plugin.Haptics".to_owned(),
+        "`/plugin/java/Haptics.java`".to_owned(),
+    )]
+    .into_iter()
+    .collect();
+    assert_eq!(
+        permission_report("app", &missing, &sources),
+        "Java code of target 'app' calls methods requiring permissions the manifest doesn't \
+         declare, which fail with a SecurityException:\n  com.example.plugin.Haptics$1.run calls \
+         android.os.Vibrator.vibrate (in `/plugin/java/Haptics.java`), which requires \
+         `<uses-permission android:name=\"android.permission.VIBRATE\" />`\nAdd them with \
+         `[[package.metadata.android.permission]]`."
+    );
+}
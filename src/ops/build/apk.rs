@@ -0,0 +1,2340 @@
+//! Assembly of the APK of a cargo target.
+//!
+//! The assembly is split into stages which `build_apks` runs in order, each taking the artifacts
+//! of the previous stages and returning its own. External tools are run through a
+//! `CommandRunner`, so that the commands of a build can be recorded instead of executed.
+//!
+//! App bundles replace the aapt stages with those of aapt2, which links the resources in the
+//! protobuf format of bundletool, and the module given to bundletool is assembled in place of the
+//! APK.
+
+use super::apk_writer::{self, ApkEntry};
+use super::assets::{self, AssetManifest, SourceAssets};
+use super::build_env::{self, BuildEnv};
+use super::compile::SharedLibrary;
+use super::signing::SigningKey;
+use super::{
+    find_java_executable, find_rt_jar, javac, locales, preprocessor, resources, tool_output, util,
+};
+use crate::config::{AndroidConfig, AndroidTargetConfig};
+use crate::error::{FailureKind, ResultExt};
+use crate::ops::cache::Cache;
+use crate::ops::{external_assets, interrupt};
+use anyhow::format_err;
+use cargo::util::{CargoResult, GlobalContext};
+use cargo_util::{ProcessBuilder, ProcessError, Sha256};
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+/// Runs the external commands of a build
+pub trait CommandRunner {
+    fn run(&self, cmd: &ProcessBuilder) -> CargoResult<()>;
+
+    /// Runs a command, returning its stderr
+    fn run_with_stderr(&self, cmd: &ProcessBuilder) -> CargoResult<String> {
+        self.run(cmd).map(|()| String::new())
+    }
+
+    /// Runs a command without printing its output, returning its stdout followed by its stderr
+    fn run_with_output(&self, cmd: &ProcessBuilder) -> CargoResult<String> {
+        self.run(cmd).map(|()| String::new())
+    }
+}
+
+/// Runs commands as child processes
+pub struct ProcessRunner;
+
+impl CommandRunner for ProcessRunner {
+    fn run(&self, cmd: &ProcessBuilder) -> CargoResult<()> {
+        interrupt::exec(cmd)
+    }
+
+    fn run_with_stderr(&self, cmd: &ProcessBuilder) -> CargoResult<String> {
+        interrupt::exec_with_stderr(cmd)
+    }
+
+    fn run_with_output(&self, cmd: &ProcessBuilder) -> CargoResult<String> {
+        let output = interrupt::exec_with_output(cmd)?;
+        Ok(format!(
+            "{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        ))
+    }
+}
+
+/// Packaging tools of the SDK
+pub struct BuildTools {
+    pub aapt: PathBuf,
+    pub aapt2: PathBuf,
+    pub d8: PathBuf,
+    pub zipalign: PathBuf,
+    pub apksigner: PathBuf,
+    /// Major version of the build tools, when their directory is named after it
+    pub major_version: Option<u32>,
+}
+
+impl BuildTools {
+    pub fn find(config: &AndroidConfig) -> CargoResult<BuildTools> {
+        let build_tools_path = config.build_tools_path()?;
+        Ok(BuildTools {
+            aapt: build_tools_path.join("aapt"),
+            aapt2: build_tools_path.join("aapt2"),
+            d8: build_tools_path.join("d8"),
+            zipalign: build_tools_path.join("zipalign"),
+            apksigner: build_tools_path.join(format!("apksigner{}", util::EXECUTABLE_SUFFIX_BAT)),
+            major_version: config
+                .build_tools_version
+                .as_ref()
+                .and_then(|version| version.split('.').next()?.parse().ok()),
+        })
+    }
+}
+
+/// Tools of the JDK used to compile the Java sources
+pub struct JavaTools {
+    pub javac: PathBuf,
+    pub boot_classpath: BootClasspath,
+}
+
+/// Classes the Java sources are compiled against, which depend on the version of the JDK
+pub enum BootClasspath {
+    /// rt.jar of the JRE, for JDK 8 and older
+    RtJar(String),
+    /// android.jar, for JDK 9 and later which have no rt.jar
+    AndroidJar,
+}
+
+impl JavaTools {
+    pub fn find() -> CargoResult<JavaTools> {
+        let javac = find_java_executable(JAVAC_FILENAME)?;
+        let version = util::tool_version(ProcessBuilder::new(&javac).arg("-version"));
+        // JDKs whose version can't be told are assumed to be recent
+        let boot_classpath = match version.as_deref().and_then(javac_major_version) {
+            Some(major) if major < 9 => BootClasspath::RtJar(find_rt_jar()?),
+            _ => BootClasspath::AndroidJar,
+        };
+        Ok(JavaTools {
+            javac,
+            boot_classpath,
+        })
+    }
+}
+
+/// Returns the major version of the JDK out of `javac -version`, like 8 for `javac 1.8.0_392`
+/// and 17 for `javac 17.0.15`
+fn javac_major_version(version: &str) -> Option<u32> {
+    let version = version.trim().strip_prefix("javac ")?;
+    let mut parts = version.split(|c: char| !c.is_ascii_digit());
+    match parts.next()?.parse().ok()? {
+        1 => parts.next()?.parse().ok(),
+        major => Some(major),
+    }
+}
+
+/// Tools building and signing the app bundles
+pub struct BundleTools {
+    pub java: PathBuf,
+    pub jarsigner: PathBuf,
+    pub bundletool: PathBuf,
+}
+
+/// bundletool downloaded to the cache when `bundletool_path` isn't set, with the sha256 of the
+/// jar of the release, as it's run right away
+// FIXME: the digest must be the sha256 of the released jar, it couldn't be computed offline
+const BUNDLETOOL: (&str, &str) = (
+    "https://github.com/google/bundletool/releases/download/1.17.2/bundletool-all-1.17.2.jar",
+    "0000000000000000000000000000000000000000000000000000000000000000",
+);
+
+impl BundleTools {
+    pub fn find(gctx: &GlobalContext, config: &AndroidConfig) -> CargoResult<BundleTools> {
+        let bundletool = match &config.bundletool_path {
+            Some(path) if path.is_file() => path.clone(),
+            Some(path) => {
+                return Err(format_err!(
+                    "The bundletool jar `{}` of `bundletool_path` does not exist",
+                    path.display()
+                ))
+                .failure_kind(FailureKind::Environment)
+            }
+            None => Cache::open()
+                .and_then(|cache| {
+                    let (url, sha256) = BUNDLETOOL;
+                    cache.download_verified(gctx, url, sha256)
+                })
+                .map_err(|err| {
+                    format_err!(
+                        "Unable to download bundletool, set `bundletool_path` to a bundletool \
+                         jar: {}",
+                        err
+                    )
+                })
+                .failure_kind(FailureKind::Environment)?,
+        };
+        Ok(BundleTools {
+            java: find_java_executable(JAVA_FILENAME)?,
+            jarsigner: find_java_executable(JARSIGNER_FILENAME)?,
+            bundletool,
+        })
+    }
+}
+
+const JAVA_FILENAME: &str = if cfg!(target_os = "windows") {
+    "java.exe"
+} else {
+    "java"
+};
+
+const JARSIGNER_FILENAME: &str = if cfg!(target_os = "windows") {
+    "jarsigner.exe"
+} else {
+    "jarsigner"
+};
+
+pub const JAVAC_FILENAME: &str = if cfg!(target_os = "windows") {
+    "javac.exe"
+} else {
+    "javac"
+};
+
+/// Java sources written to the target directory, relative to it unless generated from miniquad
+pub struct StagedJava {
+    /// `HotReload.java` of debug builds, `QuadNative.java` and the Java files of the dependencies
+    sources: Vec<PathBuf>,
+    main_activity: PathBuf,
+}
+
+impl StagedJava {
+    /// The staged Java files, relative to the target directory or absolute
+    pub fn files(&self) -> impl Iterator<Item = &Path> {
+        Some(self.main_activity.as_path())
+            .into_iter()
+            .chain(self.sources.iter().map(PathBuf::as_path))
+    }
+}
+
+/// Assets directory given to aapt
+pub struct StagedAssets {
+    dir: Option<PathBuf>,
+    /// Whether the assets were listed, and must be packaged with `assets::IGNORE_ASSETS`
+    listed: bool,
+    /// Number of entries of the assets directory left out by the ignore rules
+    pub ignored: usize,
+}
+
+/// APK with the manifest, resources and assets, relative to the target directory
+pub struct UnalignedApk(PathBuf);
+
+/// Output of aapt for the resources of the target
+pub struct PackagedResources {
+    pub apk: UnalignedApk,
+    /// `R.java` generated by aapt
+    r_java: PathBuf,
+    /// Warnings of aapt about resources it left out
+    pub aapt_warnings: Vec<String>,
+}
+
+/// Archives of the resources compiled by aapt2, relative to the target directory
+pub struct CompiledResources {
+    archives: Vec<PathBuf>,
+    /// Archives of the resources of the app, overriding those of the dependencies
+    overlays: Vec<PathBuf>,
+}
+
+/// Base module of an app bundle, relative to the target directory
+pub struct BundleModule(PathBuf);
+
+/// Directory of the compiled classes
+pub struct Classes(pub PathBuf);
+
+/// `classes.dex`, relative to the target directory
+pub struct Dex(pub PathBuf);
+
+/// Aligned APK at its final location
+pub struct AlignedApk(pub PathBuf);
+
+/// Shared cache of the classes and dex of the Java code, which outlives `cargo clean`
+pub struct JavaCache<'a> {
+    pub gctx: &'a GlobalContext,
+    pub cache: Cache,
+}
+
+/// Builds the APK of a cargo target in its own directory
+pub struct ApkBuilder<'a> {
+    pub config: &'a AndroidConfig,
+    pub target_config: &'a AndroidTargetConfig,
+    pub target_name: &'a str,
+    /// Directory in which the APK is assembled
+    pub target_directory: &'a Path,
+    pub tools: &'a BuildTools,
+    /// `None` for apps started by NativeActivity, which have no Java code
+    pub java_tools: Option<&'a JavaTools>,
+    /// `None` to always run javac and d8
+    pub java_cache: Option<&'a JavaCache<'a>>,
+    pub runner: &'a dyn CommandRunner,
+}
+
+impl ApkBuilder<'_> {
+    /// Directory of the Java package of the app below `dir`
+    fn package_dir(&self, dir: &Path) -> PathBuf {
+        let mut package_dir = dir.to_owned();
+        for file_part in self.target_config.java_package().split('.') {
+            package_dir = package_dir.join(file_part);
+        }
+        package_dir
+    }
+
+    fn run(&self, cmd: &mut ProcessBuilder) -> CargoResult<()> {
+        self.runner.run(cmd.cwd(self.target_directory))
+    }
+
+    fn run_with_output(&self, cmd: &mut ProcessBuilder) -> CargoResult<String> {
+        self.runner.run_with_output(cmd.cwd(self.target_directory))
+    }
+
+    /// Writes the AndroidManifest.xml, returning the warnings about its template
+    pub fn write_manifest(&self, java_files: &util::JavaFiles) -> CargoResult<Vec<String>> {
+        let (manifest, warnings) = super::manifest_document(
+            self.config,
+            self.target_config,
+            self.target_name,
+            java_files,
+        )?;
+        util::write_if_changed(&self.target_directory.join("AndroidManifest.xml"), manifest)?;
+        Ok(warnings)
+    }
+
+    /// Writes the MainActivity of miniquad, or the one of `main_activity`, and the Java files of
+    /// the dependencies, with the package and library names of the target
+    pub fn stage_java(
+        &self,
+        miniquad_java_dir: &Path,
+        java_files: &util::JavaFiles,
+    ) -> CargoResult<StagedJava> {
+        self.check_layout_stub_references(java_files)?;
+        let package_name = self.target_config.java_package();
+        let library_name = &self.target_config.library_name;
+
+        let java_dir = self.package_dir(self.target_directory);
+        fs::create_dir_all(&java_dir)?;
+        // javac wants public classes in a file of the same name
+        let main_activity =
+            java_dir.join(format!("{}.java", self.target_config.main_activity_class));
+
+        let mut java_src = match &self.config.main_activity {
+            Some(path) => self.read_main_activity(path, java_files)?,
+            None => fs::read_to_string(miniquad_java_dir.join("MainActivity.java"))
+                .expect("Something went wrong reading miniquad's MainActivity.java file"),
+        };
+        let mut sources = vec![];
+        // Debug builds load the library pushed by `cargo quad-apk hot` when there is one
+        let hot_reload = if self.config.release {
+            None
+        } else {
+            preprocessor::hot_reload(
+                &java_src,
+                &package_name,
+                &self.target_config.application_id(),
+            )
+        };
+        if let Some((main_activity_src, hot_reload_src)) = hot_reload {
+            java_src = main_activity_src;
+            let hot_reload = java_dir.join("HotReload.java");
+            util::write_if_changed(&hot_reload, hot_reload_src)?;
+            sources.push(hot_reload.strip_prefix(self.target_directory)?.to_owned());
+        }
+        // The APK only has a stub, the app finds the pushed assets through the MainActivity
+        if self.target_config.debug_assets_external {
+            java_src = preprocessor::external_assets(&java_src).ok_or_else(|| {
+                format_err!(
+                    "`debug_assets_external` needs the `//% MAIN_ACTIVITY_ON_CREATE` marker in \
+                     the MainActivity, to tell the app where the assets are pushed"
+                )
+            })?;
+        }
+        let java_src = preprocessor::preprocess_main_activity(
+            &java_src,
+            &package_name,
+            library_name,
+            &java_files.main_activity_injects,
+        );
+        util::write_if_changed(&main_activity, java_src)?;
+
+        let quad_native = PathBuf::from("quad_native/QuadNative.java");
+        let target_quad_native_path = self.target_directory.join(&quad_native);
+        fs::create_dir_all(target_quad_native_path.parent().unwrap())?;
+        util::write_if_changed(
+            &target_quad_native_path,
+            fs::read(miniquad_java_dir.join("QuadNative.java"))?,
+        )?;
+
+        sources.push(quad_native);
+        for (global_path, local_path) in &java_files.java_files {
+            let java_src = fs::read_to_string(global_path)
+                .expect("Something went wrong reading miniquad's MainActivity.java file");
+
+            let java_src = java_src.replace("TARGET_PACKAGE_NAME", &package_name);
+            let java_src = java_src.replace("LIBRARY_NAME", library_name);
+
+            let local_path = local_path.strip_prefix("java/")?;
+
+            let target_path = self.target_directory.join(&local_path);
+            fs::create_dir_all(target_path.parent().unwrap())?;
+
+            util::write_if_changed(&target_path, java_src)?;
+            sources.push(local_path.to_owned());
+        }
+
+        Ok(StagedJava {
+            sources,
+            main_activity,
+        })
+    }
+
+    /// Reads the MainActivity of `main_activity`. The code injected by the dependencies goes to
+    /// its `//%` markers, like those of miniquad's MainActivity, and it fails without the markers
+    /// of the sections the injects use.
+    fn read_main_activity(&self, path: &Path, java_files: &util::JavaFiles) -> CargoResult<String> {
+        let java_src = fs::read_to_string(path).map_err(|err| {
+            format_err!(
+                "Unable to read `main_activity` `{}`: {}",
+                path.display(),
+                err
+            )
+        })?;
+        let missing = preprocessor::missing_markers(&java_src, &java_files.main_activity_injects);
+        if !missing.is_empty() {
+            let injects = java_files
+                .main_activity_injects
+                .iter()
+                .map(|inject| format!("`{}`", inject.display()))
+                .collect::<Vec<_>>();
+            let missing = missing
+                .iter()
+                .map(|marker| format!("`{}`", marker))
+                .collect::<Vec<_>>();
+            return Err(format_err!(
+                "Dependencies inject code into the MainActivity with {}, but `main_activity` \
+                 `{}` replaces it without the markers where the code goes. Add the {} lines \
+                 of miniquad's MainActivity to it.",
+                injects.join(", "),
+                path.display(),
+                missing.join(", ")
+            ));
+        }
+        Ok(java_src)
+    }
+
+    /// Fails when the Java code references `R.layout.main`, the empty layout every APK used to
+    /// have, and `res` doesn't provide it
+    fn check_layout_stub_references(&self, java_files: &util::JavaFiles) -> CargoResult<()> {
+        let (app_dirs, dependency_dirs) = self.res_dirs(java_files);
+        let provided = app_dirs
+            .iter()
+            .chain(&dependency_dirs)
+            .any(|res_dir| provides_main_layout(res_dir));
+        if provided {
+            return Ok(());
+        }
+        let sources = java_files.main_activity_injects.iter().chain(
+            java_files
+                .java_files
+                .iter()
+                .map(|(global_path, _)| global_path),
+        );
+        for source in sources {
+            if references_main_layout(&fs::read_to_string(source)?) {
+                return Err(format_err!(
+                    "`{}` references `R.layout.main`, the empty layout which is no longer \
+                     generated for every APK. Add your own layout as `layout/main.xml` in the \
+                     directory of the `res` key of `[package.metadata.android]`.",
+                    source.display()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the assets against the `verify_assets` lists, and copies them to the target
+    /// directory along with their manifest when `generate_asset_manifest` is set
+    pub fn stage_assets(&self, build_env: Option<&BuildEnv>) -> CargoResult<StagedAssets> {
+        let target_config = self.target_config;
+        let mut source_assets = match &target_config.assets_path {
+            Some(assets_path) => {
+                assets::list_source_assets(assets_path, &target_config.assets_exclude)?
+            }
+            None => SourceAssets::default(),
+        };
+        if !target_config.assets_subset.is_empty() {
+            source_assets.retain_subset(&target_config.assets_subset);
+        }
+        let ignored = source_assets.ignored;
+        if ignored == 0
+            && !target_config.generate_asset_manifest
+            && target_config.verify_assets.is_empty()
+            && !target_config.debug_assets_external
+            && build_env.is_none()
+        {
+            return Ok(StagedAssets {
+                dir: target_config.assets_path.clone(),
+                listed: false,
+                ignored,
+            });
+        }
+        let assets = source_assets.assets;
+
+        if !target_config.verify_assets.is_empty() {
+            let mut expected = BTreeSet::new();
+            for list in &target_config.verify_assets {
+                expected.extend(assets::read_expected_list(list)?);
+            }
+            let packaged = assets.iter().map(|(path, _)| path.clone()).collect();
+            if let Some(mismatch) = assets::compare_assets(&packaged, &expected) {
+                return Err(format_err!(
+                    "Assets of target '{}' don't match the `verify_assets` lists:{}",
+                    self.target_name,
+                    mismatch.render()
+                ));
+            }
+        }
+
+        // Install pushes the assets to the device, the APK only gets a stub
+        if target_config.debug_assets_external {
+            let stub_dir = self.target_directory.join("external-assets-stub");
+            util::clean_dir(&stub_dir)?;
+            fs::write(
+                stub_dir.join(external_assets::STUB_NAME),
+                external_assets::STUB_CONTENTS,
+            )?;
+            if let Some(build_env) = build_env {
+                fs::write(stub_dir.join(build_env::FILE_NAME), build_env.to_json())?;
+            }
+            return Ok(StagedAssets {
+                dir: Some(stub_dir),
+                listed: true,
+                ignored,
+            });
+        }
+
+        if ignored == 0 && !target_config.generate_asset_manifest && build_env.is_none() {
+            return Ok(StagedAssets {
+                dir: target_config.assets_path.clone(),
+                listed: true,
+                ignored,
+            });
+        }
+
+        // Staged so that the generated files aren't written among the sources of the package
+        let staged_dir = self.target_directory.join("assets");
+        util::clean_dir(&staged_dir)?;
+        for (path, file) in &assets {
+            let staged_path = staged_dir.join(path);
+            fs::create_dir_all(staged_path.parent().unwrap())?;
+            fs::copy(file, staged_path)?;
+        }
+        if target_config.generate_asset_manifest {
+            let manifest = AssetManifest::of(&assets)?;
+            fs::write(
+                staged_dir.join(assets::MANIFEST_NAME),
+                serde_json::to_string_pretty(&manifest)?,
+            )?;
+        }
+        // Written after the manifest, which only lists the assets of the package
+        if let Some(build_env) = build_env {
+            fs::write(staged_dir.join(build_env::FILE_NAME), build_env.to_json())?;
+        }
+
+        Ok(StagedAssets {
+            dir: Some(staged_dir),
+            listed: true,
+            ignored,
+        })
+    }
+
+    /// Returns the resource directories of the app, from `res`, and those of its dependencies,
+    /// from the `res_dir` of their quad.toml
+    fn res_dirs<'a>(&'a self, java_files: &'a util::JavaFiles) -> (Vec<&'a Path>, Vec<&'a Path>) {
+        (
+            self.target_config
+                .res_paths
+                .iter()
+                .map(PathBuf::as_path)
+                .collect(),
+            java_files
+                .res_dirs
+                .iter()
+                .map(|(_, res_dir)| res_dir.as_path())
+                .collect(),
+        )
+    }
+
+    /// Creates the unaligned APK with the manifest, resources and assets, along with `R.java`
+    pub fn package_resources(
+        &self,
+        assets: &StagedAssets,
+        java_files: &util::JavaFiles,
+    ) -> CargoResult<PackagedResources> {
+        let unaligned_apk = PathBuf::from(format!("{}_unaligned.apk", self.target_name));
+        let unaligned_apk_path = self.target_directory.join(&unaligned_apk);
+        if unaligned_apk_path.exists() {
+            fs::remove_file(unaligned_apk_path)
+                .map_err(|e| format_err!("Unable to delete APK file. {}", e))?;
+        }
+
+        let r_java = self.remove_r_java()?;
+        let generated_res = self.write_generated_res()?;
+        // aapt keeps the first resource found, so the app's directories go first
+        let (app_dirs, dependency_dirs) = self.res_dirs(java_files);
+        let res_dirs = app_dirs
+            .into_iter()
+            .chain(dependency_dirs)
+            .collect::<Vec<_>>();
+
+        let mut aapt_package_cmd = ProcessBuilder::new(&self.tools.aapt);
+        aapt_package_cmd.args(&aapt_package_args(
+            self.config,
+            self.target_config,
+            &unaligned_apk,
+            generated_res,
+            &res_dirs,
+            assets,
+        ));
+        let stderr = self
+            .runner
+            .run_with_stderr(aapt_package_cmd.cwd(self.target_directory))?;
+
+        Ok(PackagedResources {
+            apk: UnalignedApk(unaligned_apk),
+            r_java,
+            aapt_warnings: resources::aapt_warnings(&stderr),
+        })
+    }
+
+    /// Removes the `R.java` of a previous build, which the resources may no longer generate, and
+    /// returns its path
+    fn remove_r_java(&self) -> CargoResult<PathBuf> {
+        let gen_dir = self.target_directory.join("build").join("gen");
+        fs::create_dir_all(&gen_dir)?;
+        let r_java = self.package_dir(&gen_dir).join("R.java");
+        if r_java.exists() {
+            fs::remove_file(&r_java)?;
+        }
+        Ok(r_java)
+    }
+
+    /// Writes the resources generated for the target to its `res` directory, and returns whether
+    /// there are any
+    fn write_generated_res(&self) -> CargoResult<bool> {
+        let res_dir = self.target_directory.join("res");
+        // Every APK used to have an empty layout, which could be left by an earlier build
+        let layout_stub = res_dir.join("layout").join("main.xml");
+        if layout_stub.exists() {
+            fs::remove_file(&layout_stub)?;
+            let _ = fs::remove_dir(res_dir.join("layout"));
+        }
+        locales::write_locales_config(self.config, self.target_config, &res_dir)?;
+        Ok(res_dir.exists() && !util::find_files(&res_dir, "xml")?.is_empty())
+    }
+
+    /// Compiles the resources of the target, those of `res` and those of the dependencies with
+    /// aapt2, for app bundles, each directory to its own archive
+    pub fn compile_resources(
+        &self,
+        java_files: &util::JavaFiles,
+    ) -> CargoResult<CompiledResources> {
+        let generated_res = self.write_generated_res()?;
+        util::clean_dir(&self.target_directory.join("build").join("compiled"))?;
+
+        let compile = |res_dir: &Path, name: &str, index: usize| -> CargoResult<PathBuf> {
+            let archive = match index {
+                0 => PathBuf::from(format!("build/compiled/{}.zip", name)),
+                index => PathBuf::from(format!("build/compiled/{}_{}.zip", name, index)),
+            };
+            self.run(
+                ProcessBuilder::new(&self.tools.aapt2)
+                    .arg("compile")
+                    .arg("--dir")
+                    .arg(res_dir)
+                    .arg("-o")
+                    .arg(&archive),
+            )?;
+            Ok(archive)
+        };
+        let mut archives = vec![];
+        if generated_res {
+            archives.push(compile(Path::new("res"), "res", 0)?);
+        }
+        let (app_dirs, dependency_dirs) = self.res_dirs(java_files);
+        for (index, res_dir) in dependency_dirs.iter().enumerate() {
+            archives.push(compile(res_dir, "dependency_res", index)?);
+        }
+        let mut app_archives = vec![];
+        for (index, res_dir) in app_dirs.iter().enumerate() {
+            app_archives.push(compile(res_dir, "package_res", index)?);
+        }
+        // The app's resources only need to override anything when there are dependencies
+        if dependency_dirs.is_empty() {
+            archives.append(&mut app_archives);
+        }
+        Ok(CompiledResources {
+            archives,
+            overlays: app_archives,
+        })
+    }
+
+    /// Links the compiled resources with the manifest and assets in the protobuf format of
+    /// bundletool, along with `R.java`
+    pub fn link_resources(
+        &self,
+        assets: &StagedAssets,
+        compiled: &CompiledResources,
+    ) -> CargoResult<PackagedResources> {
+        let linked = PathBuf::from(format!("{}_resources.apk", self.target_name));
+        let r_java = self.remove_r_java()?;
+
+        let mut aapt2_link_cmd = ProcessBuilder::new(&self.tools.aapt2);
+        aapt2_link_cmd.args(&aapt2_link_args(
+            self.config,
+            self.target_config,
+            &linked,
+            assets,
+            compiled,
+        ));
+        let stderr = self
+            .runner
+            .run_with_stderr(aapt2_link_cmd.cwd(self.target_directory))?;
+
+        Ok(PackagedResources {
+            apk: UnalignedApk(linked),
+            r_java,
+            aapt_warnings: resources::aapt_warnings(&stderr),
+        })
+    }
+
+    /// Compiles the Java sources and converts them to `classes.dex`, or copies both out of the
+    /// Java cache when a build, of this package or another, compiled the same inputs. The cache
+    /// is best effort, a build failing to read or write it runs javac and d8 instead.
+    pub fn compile_dex(
+        &self,
+        java: &StagedJava,
+        resources: &PackagedResources,
+        java_files: &util::JavaFiles,
+    ) -> CargoResult<(Classes, Dex)> {
+        let java_cache = match self.java_cache {
+            Some(java_cache) => java_cache,
+            None => {
+                let classes = self.compile_java(java, resources, java_files)?;
+                let dex = self.d8(&classes, java_files)?;
+                return Ok((classes, dex));
+            }
+        };
+        let obj_dir = self.target_directory.join("build").join("obj");
+        let dex = Dex(PathBuf::from("classes.dex"));
+        let key = self.java_cache_key(java, resources, java_files)?;
+        let cached = java_cache
+            .cache
+            .read_java(java_cache.gctx, &key, |entry| {
+                util::clean_dir(&obj_dir)?;
+                javac::forget(&obj_dir)?;
+                util::copy_dir(&entry.join("obj"), &obj_dir)?;
+                fs::copy(entry.join(&dex.0), self.target_directory.join(&dex.0))?;
+                Ok(())
+            })
+            .unwrap_or(None);
+        if cached.is_some() {
+            return Ok((Classes(obj_dir), dex));
+        }
+
+        let classes = self.compile_java(java, resources, java_files)?;
+        let dex = self.d8(&classes, java_files)?;
+        let _ = java_cache.cache.store_java(
+            java_cache.gctx,
+            &key,
+            &format!("classes of `{}`", self.target_config.package_name),
+            |entry| {
+                util::copy_dir(&classes.0, &entry.join("obj"))?;
+                fs::copy(self.target_directory.join(&dex.0), entry.join(&dex.0))?;
+                Ok(())
+            },
+        );
+        Ok((classes, dex))
+    }
+
+    /// Returns the key of the Java cache for the inputs of javac and d8: the options of both, the
+    /// sources, which hold the code of miniquad, the package name and the injects, and the jars
+    fn java_cache_key(
+        &self,
+        java: &StagedJava,
+        resources: &PackagedResources,
+        java_files: &util::JavaFiles,
+    ) -> CargoResult<String> {
+        let mut hasher = Sha256::new();
+        let mut update = |bytes: &[u8]| {
+            hasher.update(bytes);
+            hasher.update(b"\0");
+        };
+        update(b"java-cache-1");
+        let javac_cmd = self.javac_command(java_files);
+        update(javac_cmd.get_program().to_string_lossy().as_bytes());
+        for arg in javac_cmd.get_args() {
+            update(arg.to_string_lossy().as_bytes());
+        }
+        for source in self.java_sources(java, resources) {
+            // The target directory of the sources generated from miniquad is left out, so that
+            // other packages share the entry
+            let path = self.target_directory.join(&source);
+            let relative = path.strip_prefix(self.target_directory).unwrap_or(&source);
+            update(relative.to_string_lossy().as_bytes());
+            update(
+                &fs::read(&path)
+                    .map_err(|err| format_err!("Unable to read `{}`: {}", path.display(), err))?,
+            );
+        }
+        update(self.tools.d8.to_string_lossy().as_bytes());
+        for arg in super::d8_desugaring_args(self.config) {
+            update(arg.to_string_lossy().as_bytes());
+        }
+        let jars = java_files
+            .comptime_jar_files
+            .iter()
+            .chain(&java_files.runtime_jar_files);
+        for (jar, _) in jars {
+            update(
+                &fs::read(jar)
+                    .map_err(|err| format_err!("Unable to read `{}`: {}", jar.display(), err))?,
+            );
+        }
+        Ok(hasher.finish_hex())
+    }
+
+    pub fn compile_java(
+        &self,
+        java: &StagedJava,
+        resources: &PackagedResources,
+        java_files: &util::JavaFiles,
+    ) -> CargoResult<Classes> {
+        // Stale classes of a previous build are removed by the incremental javac step
+        let obj_dir = self.target_directory.join("build").join("obj");
+        fs::create_dir_all(&obj_dir)?;
+
+        let java_cmd = self.javac_command(java_files);
+        let java_sources = self.java_sources(java, resources);
+        javac::compile(self.runner, &java_cmd, &obj_dir, &java_sources)?;
+        Ok(Classes(obj_dir))
+    }
+
+    /// Returns javac with every option, without the sources
+    fn javac_command(&self, java_files: &util::JavaFiles) -> ProcessBuilder {
+        let mut classpath = self.config.android_jar_path.to_str().unwrap().to_string();
+        // The classes of the sources javac isn't given again, as only the changed ones are
+        classpath.push_str(":build/obj");
+        for (comptime_jar, _) in &java_files.comptime_jar_files {
+            classpath.push_str(":");
+            classpath.push_str(comptime_jar.to_str().unwrap());
+        }
+
+        let java_tools = self
+            .java_tools
+            .expect("the JDK is looked up for apps with Java code");
+        let mut java_cmd = ProcessBuilder::new(&java_tools.javac);
+        match &java_tools.boot_classpath {
+            BootClasspath::RtJar(rt_jar) => java_cmd
+                .arg("-source")
+                .arg("1.7")
+                .arg("-target")
+                .arg("1.7")
+                .arg("-Xlint:deprecation")
+                .arg("-bootclasspath")
+                .arg(rt_jar),
+            // Java 8 is the oldest these JDKs compile for, `-Xlint:-options` silences the
+            // warnings of those which deprecate it
+            BootClasspath::AndroidJar => java_cmd
+                .arg("-source")
+                .arg("8")
+                .arg("-target")
+                .arg("8")
+                .arg("-Xlint:deprecation")
+                .arg("-Xlint:-options")
+                .arg("-bootclasspath")
+                .arg(&self.config.android_jar_path),
+        };
+        java_cmd
+            .arg("-classpath")
+            .arg(&classpath)
+            .arg("-d")
+            .arg("build/obj")
+            .cwd(self.target_directory);
+        java_cmd
+    }
+
+    /// Returns the sources given to javac, relative to the target directory unless generated
+    fn java_sources(&self, java: &StagedJava, resources: &PackagedResources) -> Vec<PathBuf> {
+        let mut java_sources = java.sources.clone();
+        // aapt writes no `R.java` when there are no resources
+        if resources.r_java.exists() {
+            java_sources.push(resources.r_java.clone());
+        }
+        java_sources.push(java.main_activity.clone());
+        java_sources
+    }
+
+    /// Converts the classes and the runtime jars to `classes.dex`
+    pub fn d8(&self, classes: &Classes, java_files: &util::JavaFiles) -> CargoResult<Dex> {
+        let mut d8_cmd = ProcessBuilder::new(&self.tools.d8);
+        for class_file in util::find_files(&classes.0, "class")? {
+            d8_cmd.arg(class_file);
+        }
+        for (runtime_jar, _) in &java_files.runtime_jar_files {
+            d8_cmd.arg(&runtime_jar);
+        }
+        d8_cmd.args(&super::d8_desugaring_args(self.config));
+        self.run(&mut d8_cmd)?;
+        Ok(Dex(PathBuf::from("classes.dex")))
+    }
+
+    /// Writes the APK at `final_apk_path` with the dex assembled in `dex_directory`, which the
+    /// split APKs share with the APK of every ABI, and the libraries, aligned
+    pub fn write_apk<'l>(
+        &self,
+        apk: UnalignedApk,
+        dex: Option<(&Dex, &Path)>,
+        shared_libraries: impl IntoIterator<Item = &'l SharedLibrary>,
+        final_apk_path: PathBuf,
+    ) -> CargoResult<AlignedApk> {
+        let mut entries = vec![];
+        if let Some((dex, dex_directory)) = dex {
+            entries.push(ApkEntry {
+                name: dex.0.display().to_string(),
+                path: dex_directory.join(&dex.0),
+            });
+        }
+        for shared_library in shared_libraries {
+            // The type of slash matters, the library doesn't load if its entry has backslashes
+            entries.push(ApkEntry {
+                name: format!(
+                    "lib/{}/{}",
+                    shared_library.abi.android_abi(),
+                    shared_library.filename
+                ),
+                path: shared_library.path.clone(),
+            });
+        }
+        apk_writer::write_apk(
+            &self.target_directory.join(&apk.0),
+            &entries,
+            self.config.page_alignment,
+            &final_apk_path,
+        )?;
+        Ok(AlignedApk(final_apk_path))
+    }
+
+    /// Checks the alignment of the APK with zipalign, native libraries included. The check is
+    /// run again verbosely when it fails, to name the entries which aren't aligned.
+    pub fn verify_alignment(&self, apk: &AlignedApk) -> CargoResult<()> {
+        let mut zipalign_cmd = ProcessBuilder::new(&self.tools.zipalign);
+        zipalign_cmd.arg("-c");
+        // `-P` is only known to the zipalign of build-tools 35 and later. Older ones still check
+        // the libraries on pages of 4 KiB, which pages of 16 KiB are aligned on too.
+        let page_flag = self.config.page_alignment != 4
+            && self
+                .tools
+                .major_version
+                .map_or(false, |version| version >= 35);
+        if page_flag {
+            zipalign_cmd
+                .arg("-P")
+                .arg(self.config.page_alignment.to_string());
+        } else {
+            zipalign_cmd.arg("-p");
+        }
+        let mut verbose_cmd = zipalign_cmd.clone();
+        let err = match self.run_with_output(zipalign_cmd.arg("4").arg(&apk.0)) {
+            Ok(_) => return Ok(()),
+            Err(err) => err,
+        };
+        let details = match self.run_with_output(verbose_cmd.arg("-v").arg("4").arg(&apk.0)) {
+            Ok(output) => output,
+            Err(err) => err
+                .downcast_ref::<ProcessError>()
+                .and_then(|err| err.stdout.as_ref())
+                .map(|stdout| String::from_utf8_lossy(stdout).into_owned())
+                .unwrap_or_default(),
+        };
+        let verdict = tool_output::zipalign_verdict(&details);
+        Err(format_err!(
+            "`{}` isn't aligned on pages of {} KiB: {}",
+            apk.0.display(),
+            self.config.page_alignment,
+            if verdict.is_empty() {
+                err.to_string()
+            } else {
+                verdict.join("\n")
+            }
+        ))
+    }
+
+    /// Writes the base module of the app bundle: the linked resources in the layout bundletool
+    /// expects, with the dex and the libraries
+    pub fn bundle_module<'l>(
+        &self,
+        resources: &PackagedResources,
+        dex: Option<&Dex>,
+        shared_libraries: impl IntoIterator<Item = &'l SharedLibrary>,
+    ) -> CargoResult<BundleModule> {
+        use zip::write::{FileOptions, ZipWriter};
+
+        let module = BundleModule(PathBuf::from("base.zip"));
+        let mut linked =
+            zip::ZipArchive::new(File::open(self.target_directory.join(&resources.apk.0))?)?;
+        let mut writer = ZipWriter::new(File::create(self.target_directory.join(&module.0))?);
+        for i in 0..linked.len() {
+            let entry = linked.by_index_raw(i)?;
+            let name = bundle_module_path(entry.name());
+            writer.raw_copy_file_rename(entry, name)?;
+        }
+        let mut files = vec![];
+        if let Some(dex) = dex {
+            files.push((
+                format!("dex/{}", dex.0.display()),
+                self.target_directory.join(&dex.0),
+            ));
+        }
+        for shared_library in shared_libraries {
+            files.push((
+                format!(
+                    "lib/{}/{}",
+                    shared_library.abi.android_abi(),
+                    shared_library.filename
+                ),
+                shared_library.path.clone(),
+            ));
+        }
+        for (name, path) in files {
+            writer.start_file(name, FileOptions::default())?;
+            std::io::copy(&mut File::open(path)?, &mut writer)?;
+        }
+        writer.finish()?;
+        Ok(module)
+    }
+
+    /// Builds the app bundle of the base module with bundletool
+    pub fn build_bundle(
+        &self,
+        tools: &BundleTools,
+        module: &BundleModule,
+        bundle_path: &Path,
+    ) -> CargoResult<()> {
+        let mut modules = OsString::from("--modules=");
+        modules.push(&module.0);
+        let mut output = OsString::from("--output=");
+        output.push(bundle_path);
+        self.run(
+            ProcessBuilder::new(&tools.java)
+                .arg("-jar")
+                .arg(&tools.bundletool)
+                .arg("build-bundle")
+                .arg(modules)
+                .arg(output)
+                .arg("--overwrite"),
+        )
+    }
+
+    /// Signs the app bundle in place with `key`. Bundles are signed like JARs, by jarsigner,
+    /// which needs the alias of the key.
+    pub fn sign_bundle(
+        &self,
+        tools: &BundleTools,
+        bundle_path: &Path,
+        key: &SigningKey,
+        key_alias: &str,
+    ) -> CargoResult<()> {
+        let mut sign_cmd = ProcessBuilder::new(&tools.jarsigner);
+        sign_cmd
+            .arg("-keystore")
+            .arg(&key.keystore)
+            .args(&jarsigner_password("-storepass", &key.store_password));
+        if let Some(key_password) = &key.key_password {
+            sign_cmd.args(&jarsigner_password("-keypass", key_password));
+        }
+        self.run(sign_cmd.arg(bundle_path).arg(key_alias))
+    }
+
+    /// Signs the APK in place with `key`, returning the warnings of apksigner
+    pub fn sign(&self, apk: &AlignedApk, key: &SigningKey) -> CargoResult<Vec<String>> {
+        let mut sign_cmd = util::script_process(&self.tools.apksigner);
+        sign_cmd.arg("sign").arg("--ks").arg(&key.keystore);
+        if let Some(alias) = &key.key_alias {
+            sign_cmd.arg("--ks-key-alias").arg(alias);
+        }
+        sign_cmd.arg("--ks-pass").arg(&key.store_password);
+        if let Some(key_password) = &key.key_password {
+            sign_cmd.arg("--key-pass").arg(key_password);
+        }
+        let output = self.run_with_output(sign_cmd.arg(&apk.0))?;
+        Ok(tool_output::apksigner_warnings(&output))
+    }
+}
+
+/// Returns the arguments of `aapt package` creating the unaligned APK. The arguments of
+/// `aapt_args` are passed as they are, spaces included.
+fn aapt_package_args(
+    config: &AndroidConfig,
+    target_config: &AndroidTargetConfig,
+    unaligned_apk: &Path,
+    generated_res: bool,
+    res_dirs: &[&Path],
+    assets: &StagedAssets,
+) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec![
+        "package".into(),
+        "-F".into(),
+        unaligned_apk.into(),
+        "-m".into(),
+        "-J".into(),
+        "build/gen".into(),
+        "-M".into(),
+        "AndroidManifest.xml".into(),
+    ];
+    // aapt needs `-S` left out when nothing was generated
+    if generated_res {
+        args.push("-S".into());
+        args.push("res".into());
+    }
+    args.push("-I".into());
+    args.push(config.android_jar_path.clone().into());
+
+    for res_dir in res_dirs {
+        args.push("-S".into());
+        args.push(res_dir.into());
+    }
+    // Resources of a directory after the first are only added with this
+    if generated_res as usize + res_dirs.len() > 1 {
+        args.push("--auto-add-overlay".into());
+    }
+
+    // R is generated in the Java package, which differs from the application id when the
+    // latter has Java keywords
+    let java_package = target_config.java_package();
+    if java_package != target_config.application_id() {
+        args.push("--custom-package".into());
+        args.push(java_package.into());
+    }
+
+    // Link assets
+    if let Some(assets_path) = &assets.dir {
+        if assets.listed {
+            args.push("--ignore-assets".into());
+            args.push(assets::IGNORE_ASSETS.into());
+        }
+        args.push("-A".into());
+        args.push(assets_path.into());
+    }
+
+    // Sets `android:debuggable`, unless the manifest already has it
+    if target_config.debuggable {
+        args.push("--debug-mode".into());
+    }
+    args.extend(target_config.aapt_args.iter().map(OsString::from));
+    args
+}
+
+/// Returns the arguments of `aapt2 link` linking the resources of an app bundle
+fn aapt2_link_args(
+    config: &AndroidConfig,
+    target_config: &AndroidTargetConfig,
+    linked: &Path,
+    assets: &StagedAssets,
+    compiled: &CompiledResources,
+) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec![
+        "link".into(),
+        "--proto-format".into(),
+        "-o".into(),
+        linked.into(),
+        "--manifest".into(),
+        "AndroidManifest.xml".into(),
+        "--java".into(),
+        "build/gen".into(),
+        "-I".into(),
+        config.android_jar_path.clone().into(),
+        "--auto-add-overlay".into(),
+    ];
+
+    let java_package = target_config.java_package();
+    if java_package != target_config.application_id() {
+        args.push("--custom-package".into());
+        args.push(java_package.into());
+    }
+    if let Some(assets_path) = &assets.dir {
+        args.push("-A".into());
+        args.push(assets_path.into());
+    }
+    if target_config.debuggable {
+        args.push("--debug-mode".into());
+    }
+    args.extend(compiled.archives.iter().map(OsString::from));
+    for overlay in &compiled.overlays {
+        args.push("-R".into());
+        args.push(overlay.into());
+    }
+    args
+}
+
+/// Returns the path in the base module of a file linked by aapt2: the manifest goes to
+/// `manifest/`, and files other than the resources and assets to `root/`
+/// Whether `main.xml` is in one of the `layout` directories of `res_dir`, with or without
+/// qualifiers like `layout-land`
+fn provides_main_layout(res_dir: &Path) -> bool {
+    let Ok(entries) = fs::read_dir(res_dir) else {
+        return false;
+    };
+    entries.filter_map(Result::ok).any(|entry| {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        (name == "layout" || name.starts_with("layout-")) && entry.path().join("main.xml").is_file()
+    })
+}
+
+/// Whether the Java source uses the `R.layout.main` identifier, and not just one starting with
+/// it like `R.layout.main_menu`
+fn references_main_layout(java_src: &str) -> bool {
+    const LAYOUT: &str = "R.layout.main";
+    java_src.match_indices(LAYOUT).any(|(start, _)| {
+        let before = java_src[..start].chars().next_back();
+        let after = java_src[start + LAYOUT.len()..].chars().next();
+        let is_identifier = |c: char| c.is_alphanumeric() || c == '_' || c == '$';
+        !before.map_or(false, is_identifier) && !after.map_or(false, is_identifier)
+    })
+}
+
+fn bundle_module_path(linked_path: &str) -> String {
+    if linked_path == "AndroidManifest.xml" {
+        format!("manifest/{}", linked_path)
+    } else if linked_path == "resources.pb"
+        || linked_path.starts_with("res/")
+        || linked_path.starts_with("assets/")
+    {
+        linked_path.to_owned()
+    } else {
+        format!("root/{}", linked_path)
+    }
+}
+
+/// Returns a password option of jarsigner, from a password in the syntax of apksigner
+fn jarsigner_password(option: &str, password: &str) -> [OsString; 2] {
+    match password.strip_prefix("env:") {
+        Some(variable) => [format!("{}:env", option).into(), variable.into()],
+        None => [
+            option.into(),
+            password.strip_prefix("pass:").unwrap_or(password).into(),
+        ],
+    }
+}
+
+#[test]
+fn aapt_package_arguments() {
+    let config = crate::config::from_metadata(
+        r#"
+        package_name = "rust.app"
+        debug_aapt_args = ["--rename-instrumentation-target-package", "rust.app test"]
+        release_aapt_args = ["--max-res-version", "29"]
+        "#,
+    );
+    let target = (cargo::core::TargetKind::Bin, "app".to_owned());
+    let assets = StagedAssets {
+        dir: Some(PathBuf::from("assets")),
+        listed: false,
+        ignored: 0,
+    };
+    let args = |config: &AndroidConfig| {
+        aapt_package_args(
+            config,
+            &config.resolve(target.clone()).unwrap(),
+            Path::new("app_unaligned.apk"),
+            true,
+            &[],
+            &assets,
+        )
+    };
+
+    let debug_args = args(&config);
+    assert_eq!(
+        debug_args[12..],
+        [
+            "-A",
+            "assets",
+            "--debug-mode",
+            "--rename-instrumentation-target-package",
+            "rust.app test",
+        ]
+    );
+
+    let mut config = config;
+    config.release = true;
+    assert_eq!(
+        args(&config)[12..],
+        ["-A", "assets", "--max-res-version", "29"]
+    );
+
+    // The app's directories come first, as aapt keeps the first resource it finds
+    let args = aapt_package_args(
+        &config,
+        &config.resolve(target.clone()).unwrap(),
+        Path::new("app_unaligned.apk"),
+        true,
+        &[Path::new("/app/res"), Path::new("/deps/ui/res")],
+        &assets,
+    );
+    assert_eq!(
+        args[10..17],
+        [
+            "-I",
+            "/sdk/platforms/android-31/android.jar",
+            "-S",
+            "/app/res",
+            "-S",
+            "/deps/ui/res",
+            "--auto-add-overlay"
+        ]
+    );
+}
+
+#[test]
+fn main_layout_references() {
+    assert!(references_main_layout("setContentView(R.layout.main);"));
+    assert!(references_main_layout("int id = R.layout.main"));
+    assert!(!references_main_layout(
+        "setContentView(R.layout.main_menu);"
+    ));
+    assert!(!references_main_layout(
+        "setContentView(R.layout.mainView);"
+    ));
+    assert!(!references_main_layout("setContentView(MyR.layout.main);"));
+}
+
+#[test]
+fn javac_versions() {
+    assert_eq!(javac_major_version("javac 1.8.0_392"), Some(8));
+    assert_eq!(javac_major_version("javac 1.7.0_80\n"), Some(7));
+    assert_eq!(javac_major_version("javac 9"), Some(9));
+    assert_eq!(javac_major_version("javac 17.0.15"), Some(17));
+    assert_eq!(javac_major_version("javac 21-ea"), Some(21));
+    assert_eq!(javac_major_version("Picked up JAVA_TOOL_OPTIONS"), None);
+}
+
+/// Records the commands instead of running them, creating the outputs of aapt and javac which
+/// the following stages read
+#[cfg(test)]
+pub(super) struct MockSdk {
+    pub(super) commands: std::cell::RefCell<Vec<String>>,
+}
+
+#[cfg(test)]
+impl CommandRunner for MockSdk {
+    fn run(&self, cmd: &ProcessBuilder) -> CargoResult<()> {
+        let cwd = cmd.get_cwd().unwrap();
+        let args = cmd.get_args().collect::<Vec<_>>();
+        let program = Path::new(cmd.get_program()).file_name().unwrap();
+        // `R.java` is only generated for resources
+        if program == "aapt" && args[0] == "package" {
+            use zip::write::{FileOptions, ZipWriter};
+
+            if args.iter().any(|arg| *arg == "-S") {
+                fs::create_dir_all(cwd.join("build/gen/rust/app")).unwrap();
+                fs::write(cwd.join("build/gen/rust/app/R.java"), "package rust.app;\n").unwrap();
+            }
+            let mut packaged = ZipWriter::new(File::create(cwd.join(&args[2])).unwrap());
+            packaged
+                .start_file("AndroidManifest.xml", FileOptions::default())
+                .unwrap();
+            packaged.finish().unwrap();
+        }
+        if program == "aapt2" && args[0] == "link" {
+            use zip::write::{FileOptions, ZipWriter};
+
+            if args
+                .iter()
+                .any(|arg| arg.to_string_lossy().ends_with(".zip"))
+            {
+                fs::create_dir_all(cwd.join("build/gen/rust/app")).unwrap();
+                fs::write(cwd.join("build/gen/rust/app/R.java"), "package rust.app;\n").unwrap();
+            }
+            let mut linked = ZipWriter::new(File::create(cwd.join(&args[3])).unwrap());
+            for name in &["AndroidManifest.xml", "resources.pb", "res/layout/main.xml"] {
+                linked.start_file(*name, FileOptions::default()).unwrap();
+            }
+            linked.finish().unwrap();
+        }
+        if program == "javac" {
+            fs::create_dir_all(cwd.join("build/obj/rust/app")).unwrap();
+            fs::write(cwd.join("build/obj/rust/app/MainActivity.class"), "").unwrap();
+        }
+        if program == "d8" {
+            fs::write(cwd.join("classes.dex"), "dex").unwrap();
+        }
+        let command = std::iter::once(cmd.get_program())
+            .chain(cmd.get_args())
+            .map(|arg| arg.to_string_lossy())
+            .collect::<Vec<_>>();
+        self.commands.borrow_mut().push(command.join(" "));
+        Ok(())
+    }
+}
+
+#[test]
+fn apk_stages_command_sequence() {
+    use crate::config::AndroidBuildTarget;
+
+    let root = std::env::temp_dir().join(format!("cargo-quad-apk-stages-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let target_directory = root.join("bin").join("app");
+    let miniquad_java_dir = root.join("miniquad").join("java");
+    fs::create_dir_all(&target_directory).unwrap();
+    fs::create_dir_all(&miniquad_java_dir).unwrap();
+    fs::write(
+        miniquad_java_dir.join("MainActivity.java"),
+        "package TARGET_PACKAGE_NAME;\npublic class MainActivity {}\n",
+    )
+    .unwrap();
+    fs::write(
+        miniquad_java_dir.join("QuadNative.java"),
+        "package quad_native;\npublic class QuadNative {}\n",
+    )
+    .unwrap();
+    let library = root.join("libapp.so");
+    fs::write(&library, "").unwrap();
+    // Left by an earlier build
+    fs::create_dir_all(target_directory.join("res/layout")).unwrap();
+    fs::write(
+        target_directory.join("res/layout/main.xml"),
+        "<LinearLayout/>",
+    )
+    .unwrap();
+
+    let config = crate::config::from_metadata("");
+    let target_config = config
+        .resolve((cargo::core::TargetKind::Bin, "app".to_owned()))
+        .unwrap();
+    let tools = BuildTools::find(&config).unwrap();
+    let java_tools = JavaTools {
+        javac: PathBuf::from("javac"),
+        boot_classpath: BootClasspath::RtJar("rt.jar".to_owned()),
+    };
+    let runner = MockSdk {
+        commands: Default::default(),
+    };
+    let builder = ApkBuilder {
+        config: &config,
+        target_config: &target_config,
+        target_name: "app",
+        target_directory: &target_directory,
+        tools: &tools,
+        java_tools: Some(&java_tools),
+        java_cache: None,
+        runner: &runner,
+    };
+    let java_files = util::JavaFiles::default();
+
+    builder.write_manifest(&java_files).unwrap();
+    let java = builder.stage_java(&miniquad_java_dir, &java_files).unwrap();
+    let assets = builder.stage_assets(None).unwrap();
+    let resources = builder.package_resources(&assets, &java_files).unwrap();
+    let classes = builder
+        .compile_java(&java, &resources, &java_files)
+        .unwrap();
+    let dex = builder.d8(&classes, &java_files).unwrap();
+    let apk = builder
+        .write_apk(
+            resources.apk,
+            Some((&dex, &target_directory)),
+            &[SharedLibrary {
+                abi: AndroidBuildTarget::Arm64V8a,
+                path: library,
+                filename: "libapp.so".to_owned(),
+                link: None,
+            }],
+            root.join("app.apk"),
+        )
+        .unwrap();
+    builder.verify_alignment(&apk).unwrap();
+    builder
+        .sign(&apk, &SigningKey::debug(root.join("debug.keystore")))
+        .unwrap();
+
+    let commands = runner
+        .commands
+        .into_inner()
+        .into_iter()
+        .map(|cmd| cmd.replace(root.to_str().unwrap(), "<root>"))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        commands,
+        vec![
+            "/sdk/build-tools/31.0.0/aapt package -F app_unaligned.apk -m -J build/gen \
+             -M AndroidManifest.xml -I /sdk/platforms/android-31/android.jar --debug-mode",
+            "javac -source 1.7 -target 1.7 -Xlint:deprecation -bootclasspath rt.jar \
+             -classpath /sdk/platforms/android-31/android.jar:build/obj -d build/obj \
+             quad_native/QuadNative.java <root>/bin/app/rust/app/MainActivity.java",
+            "/sdk/build-tools/31.0.0/d8 <root>/bin/app/build/obj/rust/app/MainActivity.class \
+             --lib /sdk/platforms/android-31/android.jar --min-api 18",
+            "/sdk/build-tools/31.0.0/zipalign -c -p 4 <root>/app.apk",
+            "/sdk/build-tools/31.0.0/apksigner sign --ks <root>/debug.keystore \
+             --ks-pass pass:android <root>/app.apk",
+        ]
+    );
+    assert!(target_directory.join("AndroidManifest.xml").exists());
+    assert!(!target_directory.join("res/layout").exists());
+    // The dex and the library are written into the APK, without a copy in the target directory
+    let mut archive = zip::ZipArchive::new(File::open(root.join("app.apk")).unwrap()).unwrap();
+    assert_eq!(
+        archive.file_names().collect::<BTreeSet<_>>(),
+        [
+            "AndroidManifest.xml",
+            "classes.dex",
+            "lib/arm64-v8a/libapp.so"
+        ]
+        .iter()
+        .copied()
+        .collect()
+    );
+    assert_eq!(
+        archive
+            .by_name("lib/arm64-v8a/libapp.so")
+            .unwrap()
+            .data_start()
+            % 4096,
+        0
+    );
+    assert!(!target_directory.join("lib").exists());
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn java_cache_survives_clean() {
+    let root =
+        std::env::temp_dir().join(format!("cargo-quad-apk-java-cache-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let miniquad_java_dir = root.join("miniquad").join("java");
+    fs::create_dir_all(&miniquad_java_dir).unwrap();
+    fs::write(
+        miniquad_java_dir.join("MainActivity.java"),
+        "package TARGET_PACKAGE_NAME;\npublic class MainActivity {}\n",
+    )
+    .unwrap();
+    fs::write(
+        miniquad_java_dir.join("QuadNative.java"),
+        "package quad_native;\npublic class QuadNative {}\n",
+    )
+    .unwrap();
+
+    let config = crate::config::from_metadata("");
+    let target_config = config
+        .resolve((cargo::core::TargetKind::Bin, "app".to_owned()))
+        .unwrap();
+    let tools = BuildTools::find(&config).unwrap();
+    let java_tools = JavaTools {
+        javac: PathBuf::from("javac"),
+        boot_classpath: BootClasspath::RtJar("rt.jar".to_owned()),
+    };
+    let gctx = GlobalContext::default().unwrap();
+    let java_cache = JavaCache {
+        gctx: &gctx,
+        cache: Cache::new(root.join("cache")),
+    };
+    let target_directory = root.join("target").join("bin").join("app");
+    // Builds the dex, returning the commands run
+    let build = || {
+        fs::create_dir_all(&target_directory).unwrap();
+        let runner = MockSdk {
+            commands: Default::default(),
+        };
+        let builder = ApkBuilder {
+            config: &config,
+            target_config: &target_config,
+            target_name: "app",
+            target_directory: &target_directory,
+            tools: &tools,
+            java_tools: Some(&java_tools),
+            java_cache: Some(&java_cache),
+            runner: &runner,
+        };
+        let java_files = util::JavaFiles::default();
+        builder.write_manifest(&java_files).unwrap();
+        let java = builder.stage_java(&miniquad_java_dir, &java_files).unwrap();
+        let assets = builder.stage_assets(None).unwrap();
+        let resources = builder.package_resources(&assets, &java_files).unwrap();
+        let (classes, dex) = builder.compile_dex(&java, &resources, &java_files).unwrap();
+        assert!(classes.0.join("rust/app/MainActivity.class").exists());
+        assert_eq!(
+            fs::read_to_string(target_directory.join(&dex.0)).unwrap(),
+            "dex"
+        );
+        runner
+            .commands
+            .into_inner()
+            .into_iter()
+            .map(|cmd| cmd.split(' ').next().unwrap().to_owned())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        build(),
+        vec![
+            "/sdk/build-tools/31.0.0/aapt",
+            "javac",
+            "/sdk/build-tools/31.0.0/d8"
+        ]
+    );
+    // `cargo clean`
+    fs::remove_dir_all(root.join("target")).unwrap();
+    assert_eq!(build(), vec!["/sdk/build-tools/31.0.0/aapt"]);
+
+    // A change of the Java code compiles it again
+    fs::write(
+        miniquad_java_dir.join("QuadNative.java"),
+        "package quad_native;\npublic class QuadNative { int version; }\n",
+    )
+    .unwrap();
+    assert_eq!(
+        build(),
+        vec![
+            "/sdk/build-tools/31.0.0/aapt",
+            "javac",
+            "/sdk/build-tools/31.0.0/d8"
+        ]
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn native_activity_command_sequence() {
+    use crate::config::AndroidBuildTarget;
+
+    let root = std::env::temp_dir().join(format!(
+        "cargo-quad-apk-native-activity-{}",
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&root);
+    let target_directory = root.join("bin").join("app");
+    fs::create_dir_all(&target_directory).unwrap();
+    let library = root.join("libapp.so");
+    fs::write(&library, "").unwrap();
+
+    let config = crate::config::from_metadata(r#"framework = "none""#);
+    let target_config = config
+        .resolve((cargo::core::TargetKind::Bin, "app".to_owned()))
+        .unwrap();
+    let tools = BuildTools::find(&config).unwrap();
+    let runner = MockSdk {
+        commands: Default::default(),
+    };
+    let builder = ApkBuilder {
+        config: &config,
+        target_config: &target_config,
+        target_name: "app",
+        target_directory: &target_directory,
+        tools: &tools,
+        java_tools: None,
+        java_cache: None,
+        runner: &runner,
+    };
+    let java_files = util::JavaFiles::default();
+
+    builder.write_manifest(&java_files).unwrap();
+    let manifest = fs::read_to_string(target_directory.join("AndroidManifest.xml")).unwrap();
+    assert!(
+        manifest.contains(r#"android:hasCode="false""#),
+        "{}",
+        manifest
+    );
+    assert!(manifest.contains(r#"android:name="android.app.NativeActivity""#));
+    assert!(manifest
+        .contains(r#"<meta-data android:name="android.app.lib_name" android:value="app" />"#));
+
+    // Packaged without javac nor d8
+    let assets = builder.stage_assets(None).unwrap();
+    let resources = builder.package_resources(&assets, &java_files).unwrap();
+    let apk = builder
+        .write_apk(
+            resources.apk,
+            None,
+            &[SharedLibrary {
+                abi: AndroidBuildTarget::Arm64V8a,
+                path: library,
+                filename: "libapp.so".to_owned(),
+                link: None,
+            }],
+            root.join("app.apk"),
+        )
+        .unwrap();
+    builder
+        .sign(&apk, &SigningKey::debug(root.join("debug.keystore")))
+        .unwrap();
+
+    let commands = runner
+        .commands
+        .into_inner()
+        .into_iter()
+        .map(|cmd| cmd.replace(root.to_str().unwrap(), "<root>"))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        commands,
+        vec![
+            "/sdk/build-tools/31.0.0/aapt package -F app_unaligned.apk -m -J build/gen \
+             -M AndroidManifest.xml -I /sdk/platforms/android-31/android.jar --debug-mode",
+            "/sdk/build-tools/31.0.0/apksigner sign --ks <root>/debug.keystore \
+             --ks-pass pass:android <root>/app.apk",
+        ]
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn java_keyword_package() {
+    let root = std::env::temp_dir().join(format!("cargo-quad-apk-keyword-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let target_directory = root.join("bin").join("app");
+    let miniquad_java_dir = root.join("miniquad").join("java");
+    fs::create_dir_all(&target_directory).unwrap();
+    fs::create_dir_all(&miniquad_java_dir).unwrap();
+    fs::write(
+        miniquad_java_dir.join("MainActivity.java"),
+        "package TARGET_PACKAGE_NAME;\npublic class MainActivity {}\n",
+    )
+    .unwrap();
+    fs::write(miniquad_java_dir.join("QuadNative.java"), "").unwrap();
+
+    let config = crate::config::from_metadata(r#"package_name = "com.example.native""#);
+    let target_config = config
+        .resolve((cargo::core::TargetKind::Bin, "app".to_owned()))
+        .unwrap();
+    let tools = BuildTools::find(&config).unwrap();
+    let java_tools = JavaTools {
+        javac: PathBuf::from("javac"),
+        boot_classpath: BootClasspath::RtJar("rt.jar".to_owned()),
+    };
+    let runner = MockSdk {
+        commands: Default::default(),
+    };
+    let builder = ApkBuilder {
+        config: &config,
+        target_config: &target_config,
+        target_name: "app",
+        target_directory: &target_directory,
+        tools: &tools,
+        java_tools: Some(&java_tools),
+        java_cache: None,
+        runner: &runner,
+    };
+    let java_files = util::JavaFiles::default();
+
+    // The manifest keeps the application id and names the MainActivity by its Java package
+    builder.write_manifest(&java_files).unwrap();
+    let manifest = fs::read_to_string(target_directory.join("AndroidManifest.xml")).unwrap();
+    assert!(manifest.contains(r#"package="com.example.native""#));
+    assert!(manifest.contains(r#"android:name="com.example.native_.MainActivity""#));
+
+    let java = builder.stage_java(&miniquad_java_dir, &java_files).unwrap();
+    let main_activity = target_directory.join("com/example/native_/MainActivity.java");
+    assert_eq!(
+        fs::read_to_string(&main_activity).unwrap(),
+        "package com.example.native_;\npublic class MainActivity {}\n"
+    );
+    assert_eq!(java.main_activity, main_activity);
+
+    let assets = builder.stage_assets(None).unwrap();
+    let resources = builder.package_resources(&assets, &java_files).unwrap();
+    assert_eq!(
+        resources.r_java,
+        target_directory.join("build/gen/com/example/native_/R.java")
+    );
+    assert!(
+        runner.commands.borrow()[0].ends_with("--custom-package com.example.native_ --debug-mode")
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn hot_reload_in_debug_builds_only() {
+    let root =
+        std::env::temp_dir().join(format!("cargo-quad-apk-hot-reload-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let miniquad_java_dir = root.join("miniquad").join("java");
+    fs::create_dir_all(&miniquad_java_dir).unwrap();
+    fs::write(
+        miniquad_java_dir.join("MainActivity.java"),
+        "package TARGET_PACKAGE_NAME;\n\
+         public class MainActivity {\n\
+         \x20   static { System.loadLibrary(\"LIBRARY_NAME\"); }\n\
+         }\n",
+    )
+    .unwrap();
+    fs::write(miniquad_java_dir.join("QuadNative.java"), "").unwrap();
+
+    let mut config = crate::config::from_metadata("");
+    let target_config = config
+        .resolve((cargo::core::TargetKind::Bin, "app".to_owned()))
+        .unwrap();
+    let tools = BuildTools::find(&config).unwrap();
+    let runner = MockSdk {
+        commands: Default::default(),
+    };
+    for release in [false, true] {
+        config.release = release;
+        let target_directory = root.join(if release { "release" } else { "debug" });
+        fs::create_dir_all(&target_directory).unwrap();
+        let builder = ApkBuilder {
+            config: &config,
+            target_config: &target_config,
+            target_name: "app",
+            target_directory: &target_directory,
+            tools: &tools,
+            java_tools: None,
+            java_cache: None,
+            runner: &runner,
+        };
+
+        let java = builder
+            .stage_java(&miniquad_java_dir, &util::JavaFiles::default())
+            .unwrap();
+        let main_activity = fs::read_to_string(&java.main_activity).unwrap();
+        let hot_reload = Path::new("rust/app/HotReload.java");
+        if release {
+            assert!(main_activity.contains(r#"System.loadLibrary("app")"#));
+            assert!(!java.sources.iter().any(|source| source == hot_reload));
+            assert!(!target_directory.join(hot_reload).exists());
+        } else {
+            assert!(main_activity.contains(r#"HotReload.loadLibrary("app")"#));
+            assert_eq!(java.sources[0], hot_reload);
+            let hot_reload = fs::read_to_string(target_directory.join(hot_reload)).unwrap();
+            assert!(hot_reload.contains(r#""/rust.app/files/hot/""#));
+        }
+    }
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn user_res_command_sequence() {
+    let root = std::env::temp_dir().join(format!("cargo-quad-apk-user-res-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let target_directory = root.join("bin").join("app");
+    let miniquad_java_dir = root.join("miniquad").join("java");
+    fs::create_dir_all(&target_directory).unwrap();
+    fs::create_dir_all(&miniquad_java_dir).unwrap();
+    fs::create_dir_all(root.join("res/values")).unwrap();
+    fs::write(miniquad_java_dir.join("MainActivity.java"), "").unwrap();
+    fs::write(miniquad_java_dir.join("QuadNative.java"), "").unwrap();
+    let inject = root.join("inject.java");
+    fs::write(&inject, "setContentView(R.layout.main);\n").unwrap();
+
+    let config = crate::config::from_metadata("");
+    let mut target_config = config
+        .resolve((cargo::core::TargetKind::Bin, "app".to_owned()))
+        .unwrap();
+    target_config.res_paths = vec![root.join("res")];
+    let tools = BuildTools::find(&config).unwrap();
+    let java_tools = JavaTools {
+        javac: PathBuf::from("javac"),
+        boot_classpath: BootClasspath::AndroidJar,
+    };
+    let runner = MockSdk {
+        commands: Default::default(),
+    };
+    let builder = ApkBuilder {
+        config: &config,
+        target_config: &target_config,
+        target_name: "app",
+        target_directory: &target_directory,
+        tools: &tools,
+        java_tools: Some(&java_tools),
+        java_cache: None,
+        runner: &runner,
+    };
+    let java_files = util::JavaFiles {
+        main_activity_injects: vec![inject.clone()],
+        ..Default::default()
+    };
+
+    // The layout which used to be generated must now come from `res`
+    let err = builder
+        .stage_java(&miniquad_java_dir, &java_files)
+        .err()
+        .unwrap();
+    assert!(
+        err.to_string().starts_with(&format!(
+            "`{}` references `R.layout.main`, the empty layout which is no longer generated",
+            inject.display()
+        )),
+        "{}",
+        err
+    );
+    fs::create_dir_all(root.join("res/layout-land")).unwrap();
+    fs::write(root.join("res/layout-land/main.xml"), "<FrameLayout/>").unwrap();
+    let java = builder.stage_java(&miniquad_java_dir, &java_files).unwrap();
+
+    // Only the resources of `res` are packaged, and their `R.java` compiled, by a JDK without
+    // rt.jar
+    let assets = builder.stage_assets(None).unwrap();
+    let resources = builder.package_resources(&assets, &java_files).unwrap();
+    builder
+        .compile_java(&java, &resources, &java_files)
+        .unwrap();
+    let commands = runner
+        .commands
+        .into_inner()
+        .into_iter()
+        .map(|cmd| cmd.replace(root.to_str().unwrap(), "<root>"))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        commands,
+        vec![
+            "/sdk/build-tools/31.0.0/aapt package -F app_unaligned.apk -m -J build/gen \
+             -M AndroidManifest.xml -I /sdk/platforms/android-31/android.jar -S <root>/res \
+             --debug-mode",
+            "javac -source 8 -target 8 -Xlint:deprecation -Xlint:-options \
+             -bootclasspath /sdk/platforms/android-31/android.jar \
+             -classpath /sdk/platforms/android-31/android.jar:build/obj -d build/obj \
+             quad_native/QuadNative.java <root>/bin/app/build/gen/rust/app/R.java \
+             <root>/bin/app/rust/app/MainActivity.java",
+        ]
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn split_apk_command_sequence() {
+    use crate::config::AndroidBuildTarget;
+
+    let root = std::env::temp_dir().join(format!("cargo-quad-apk-split-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let target_directory = root.join("bin").join("app");
+    let split_directory = target_directory.join("split").join("arm64-v8a");
+    fs::create_dir_all(&split_directory).unwrap();
+    fs::write(target_directory.join("classes.dex"), "dex").unwrap();
+    let libraries = [AndroidBuildTarget::ArmV7a, AndroidBuildTarget::Arm64V8a]
+        .iter()
+        .map(|&abi| {
+            let path = root.join(format!("libapp-{}.so", abi.android_abi()));
+            fs::write(&path, "").unwrap();
+            SharedLibrary {
+                abi,
+                path,
+                filename: "libapp.so".to_owned(),
+                link: None,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let config = crate::config::from_metadata("version_code = 7\npage_alignment = 16");
+    let mut target_config = config
+        .resolve((cargo::core::TargetKind::Bin, "app".to_owned()))
+        .unwrap();
+    target_config.version_code = 73;
+    let tools = BuildTools::find(&config).unwrap();
+    let runner = MockSdk {
+        commands: Default::default(),
+    };
+    let builder = ApkBuilder {
+        config: &config,
+        target_config: &target_config,
+        target_name: "app",
+        target_directory: &split_directory,
+        tools: &tools,
+        java_tools: None,
+        java_cache: None,
+        runner: &runner,
+    };
+    let java_files = util::JavaFiles::default();
+
+    // The dex of the APK with every ABI is reused, and only the libraries of the ABI are added
+    builder.write_manifest(&java_files).unwrap();
+    let assets = builder.stage_assets(None).unwrap();
+    let resources = builder.package_resources(&assets, &java_files).unwrap();
+    let apk = builder
+        .write_apk(
+            resources.apk,
+            Some((&Dex(PathBuf::from("classes.dex")), &target_directory)),
+            libraries
+                .iter()
+                .filter(|library| library.abi.android_abi() == "arm64-v8a"),
+            root.join("app-arm64-v8a.apk"),
+        )
+        .unwrap();
+    builder.verify_alignment(&apk).unwrap();
+    assert_eq!(
+        runner.commands.borrow().last().unwrap(),
+        &format!(
+            "/sdk/build-tools/31.0.0/zipalign -c -p 4 {}",
+            root.join("app-arm64-v8a.apk").display()
+        )
+    );
+    let tools_35 = BuildTools {
+        major_version: Some(35),
+        ..BuildTools::find(&config).unwrap()
+    };
+    ApkBuilder {
+        tools: &tools_35,
+        ..builder
+    }
+    .verify_alignment(&apk)
+    .unwrap();
+    assert_eq!(
+        runner.commands.borrow().last().unwrap(),
+        &format!(
+            "/sdk/build-tools/31.0.0/zipalign -c -P 16 4 {}",
+            root.join("app-arm64-v8a.apk").display()
+        )
+    );
+
+    let mut archive =
+        zip::ZipArchive::new(File::open(root.join("app-arm64-v8a.apk")).unwrap()).unwrap();
+    assert_eq!(
+        archive.file_names().collect::<BTreeSet<_>>(),
+        [
+            "AndroidManifest.xml",
+            "classes.dex",
+            "lib/arm64-v8a/libapp.so"
+        ]
+        .iter()
+        .copied()
+        .collect()
+    );
+    let mut dex = String::new();
+    std::io::Read::read_to_string(&mut archive.by_name("classes.dex").unwrap(), &mut dex).unwrap();
+    assert_eq!(dex, "dex");
+    assert_eq!(
+        archive
+            .by_name("lib/arm64-v8a/libapp.so")
+            .unwrap()
+            .data_start()
+            % 16384,
+        0
+    );
+    assert!(!split_directory.join("classes.dex").exists());
+    assert!(
+        fs::read_to_string(split_directory.join("AndroidManifest.xml"))
+            .unwrap()
+            .contains(r#"android:versionCode="73""#)
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn bundle_command_sequence() {
+    use crate::config::AndroidBuildTarget;
+
+    let root = std::env::temp_dir().join(format!("cargo-quad-apk-bundle-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let target_directory = root.join("bin").join("app");
+    fs::create_dir_all(&target_directory).unwrap();
+    fs::write(target_directory.join("classes.dex"), "dex").unwrap();
+    let library = root.join("libapp.so");
+    fs::write(&library, "").unwrap();
+
+    let config = crate::config::from_metadata(r#"res = "res""#);
+    let target_config = config
+        .resolve((cargo::core::TargetKind::Bin, "app".to_owned()))
+        .unwrap();
+    let tools = BuildTools::find(&config).unwrap();
+    let bundle_tools = BundleTools {
+        java: PathBuf::from("java"),
+        jarsigner: PathBuf::from("jarsigner"),
+        bundletool: PathBuf::from("bundletool.jar"),
+    };
+    let runner = MockSdk {
+        commands: Default::default(),
+    };
+    let builder = ApkBuilder {
+        config: &config,
+        target_config: &target_config,
+        target_name: "app",
+        target_directory: &target_directory,
+        tools: &tools,
+        java_tools: None,
+        java_cache: None,
+        runner: &runner,
+    };
+
+    builder.write_manifest(&util::JavaFiles::default()).unwrap();
+    let assets = builder.stage_assets(None).unwrap();
+    let compiled = builder
+        .compile_resources(&util::JavaFiles::default())
+        .unwrap();
+    let resources = builder.link_resources(&assets, &compiled).unwrap();
+    let module = builder
+        .bundle_module(
+            &resources,
+            Some(&Dex(PathBuf::from("classes.dex"))),
+            &[SharedLibrary {
+                abi: AndroidBuildTarget::Arm64V8a,
+                path: library,
+                filename: "libapp.so".to_owned(),
+                link: None,
+            }],
+        )
+        .unwrap();
+    let bundle_path = root.join("apk").join("app.aab");
+    builder
+        .build_bundle(&bundle_tools, &module, &bundle_path)
+        .unwrap();
+    builder
+        .sign_bundle(
+            &bundle_tools,
+            &bundle_path,
+            &SigningKey {
+                keystore: root.join("release.keystore"),
+                key_alias: Some("upload".to_owned()),
+                store_password: "env:STORE_PASSWORD".to_owned(),
+                key_password: Some("pass:secret".to_owned()),
+            },
+            "upload",
+        )
+        .unwrap();
+
+    let commands = runner
+        .commands
+        .into_inner()
+        .into_iter()
+        .map(|cmd| cmd.replace(root.to_str().unwrap(), "<root>"))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        commands,
+        vec![
+            "/sdk/build-tools/31.0.0/aapt2 compile --dir /app/res \
+             -o build/compiled/package_res.zip",
+            "/sdk/build-tools/31.0.0/aapt2 link --proto-format -o app_resources.apk \
+             --manifest AndroidManifest.xml --java build/gen \
+             -I /sdk/platforms/android-31/android.jar --auto-add-overlay \
+             --debug-mode build/compiled/package_res.zip",
+            "java -jar bundletool.jar build-bundle --modules=base.zip \
+             --output=<root>/apk/app.aab --overwrite",
+            "jarsigner -keystore <root>/release.keystore -storepass:env STORE_PASSWORD \
+             -keypass secret <root>/apk/app.aab upload",
+        ]
+    );
+
+    // The files of the module are where bundletool expects them
+    let mut module =
+        zip::ZipArchive::new(File::open(target_directory.join("base.zip")).unwrap()).unwrap();
+    let mut names = (0..module.len())
+        .map(|i| module.by_index(i).unwrap().name().to_owned())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "dex/classes.dex",
+            "lib/arm64-v8a/libapp.so",
+            "manifest/AndroidManifest.xml",
+            "res/layout/main.xml",
+            "resources.pb",
+        ]
+    );
+    assert_eq!(
+        bundle_module_path("kotlin/kotlin.kotlin_builtins"),
+        "root/kotlin/kotlin.kotlin_builtins"
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn staged_assets_leave_out_ignored_files() {
+    let root = std::env::temp_dir().join(format!(
+        "cargo-quad-apk-staged-assets-{}",
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&root);
+    let project = root.join("project");
+    let target_directory = root.join("bin").join("app");
+    fs::create_dir_all(&target_directory).unwrap();
+    for path in &[
+        "Cargo.toml",
+        "src/main.rs",
+        "src/.main.rs.swp",
+        "data/level.json",
+        "data/notes.txt",
+        ".git/HEAD",
+        "target/debug/app",
+    ] {
+        let path = project.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, "").unwrap();
+    }
+    fs::write(project.join(".gitignore"), "*.swp\n").unwrap();
+    fs::write(project.join(".apkignore"), "src/\n").unwrap();
+
+    let config = crate::config::from_metadata(&format!(
+        "assets = {:?}\nassets_exclude = [\"*.txt\"]",
+        project.display().to_string()
+    ));
+    let target_config = config
+        .resolve((cargo::core::TargetKind::Bin, "app".to_owned()))
+        .unwrap();
+    let tools = BuildTools::find(&config).unwrap();
+    let runner = MockSdk {
+        commands: Default::default(),
+    };
+    let builder = ApkBuilder {
+        config: &config,
+        target_config: &target_config,
+        target_name: "app",
+        target_directory: &target_directory,
+        tools: &tools,
+        java_tools: None,
+        java_cache: None,
+        runner: &runner,
+    };
+
+    let assets = builder.stage_assets(None).unwrap();
+    let staged_dir = target_directory.join("assets");
+    assert_eq!(assets.dir.as_ref(), Some(&staged_dir));
+    assert!(assets.listed);
+    // .gitignore, .apkignore, the 2 files of src, data/notes.txt and target
+    assert_eq!(assets.ignored, 6);
+    let staged = assets::list_assets(&staged_dir)
+        .unwrap()
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect::<Vec<_>>();
+    assert_eq!(staged, ["Cargo.toml", "data/level.json"]);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn user_main_activity() {
+    let root = std::env::temp_dir().join(format!(
+        "cargo-quad-apk-main-activity-{}",
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&root);
+    let target_directory = root.join("bin").join("app");
+    // miniquad's MainActivity is left out, it isn't read
+    let miniquad_java_dir = root.join("miniquad").join("java");
+    fs::create_dir_all(&target_directory).unwrap();
+    fs::create_dir_all(&miniquad_java_dir).unwrap();
+    fs::write(miniquad_java_dir.join("QuadNative.java"), "").unwrap();
+    let main_activity_path = root.join("android/java/GameActivity.java");
+    fs::create_dir_all(main_activity_path.parent().unwrap()).unwrap();
+    fs::write(
+        &main_activity_path,
+        "package TARGET_PACKAGE_NAME;\n\
+         public class GameActivity extends android.app.Activity {\n\
+         \x20   public String library() { return \"LIBRARY_NAME\"; }\n\
+         }\n",
+    )
+    .unwrap();
+
+    let mut config = crate::config::from_metadata(r#"package_name = "com.example.game""#);
+    config.main_activity = Some(main_activity_path.clone());
+    let target_config = config
+        .resolve((cargo::core::TargetKind::Bin, "app".to_owned()))
+        .unwrap();
+    assert_eq!(target_config.main_activity_name(), ".GameActivity");
+    let tools = BuildTools::find(&config).unwrap();
+    let runner = MockSdk {
+        commands: Default::default(),
+    };
+    let builder = ApkBuilder {
+        config: &config,
+        target_config: &target_config,
+        target_name: "app",
+        target_directory: &target_directory,
+        tools: &tools,
+        java_tools: None,
+        java_cache: None,
+        runner: &runner,
+    };
+
+    // Copied with its placeholders replaced, in a file named after its class
+    let java = builder
+        .stage_java(&miniquad_java_dir, &util::JavaFiles::default())
+        .unwrap();
+    let main_activity = target_directory.join("com/example/game/GameActivity.java");
+    assert_eq!(java.main_activity, main_activity);
+    assert_eq!(
+        fs::read_to_string(&main_activity).unwrap(),
+        "package com.example.game;\n\
+         public class GameActivity extends android.app.Activity {\n\
+         \x20   public String library() { return \"game\"; }\n\
+         }\n"
+    );
+    builder.write_manifest(&util::JavaFiles::default()).unwrap();
+    let manifest = fs::read_to_string(target_directory.join("AndroidManifest.xml")).unwrap();
+    assert!(manifest.contains(r#"<activity android:name=".GameActivity""#));
+
+    // Code injected by dependencies needs the markers
+    let inject = root.join("plugin/inject.java");
+    fs::create_dir_all(inject.parent().unwrap()).unwrap();
+    fs::write(
+        &inject,
+        "//% MAIN_ACTIVITY_BODY\npublic void plugin() {}\n//% END\n",
+    )
+    .unwrap();
+    let java_files = util::JavaFiles {
+        main_activity_injects: vec![inject.clone()],
+        ..Default::default()
+    };
+    let err = builder
+        .stage_java(&miniquad_java_dir, &java_files)
+        .err()
+        .unwrap();
+    assert!(
+        err.to_string().starts_with(&format!(
+            "Dependencies inject code into the MainActivity with `{}`, but `main_activity` `{}` \
+             replaces it without the markers",
+            inject.display(),
+            main_activity_path.display()
+        )),
+        "{}",
+        err
+    );
+    // Other markers don't make up for the one of the injected section
+    fs::write(
+        &main_activity_path,
+        "package TARGET_PACKAGE_NAME;\n\
+         //% IMPORTS\n\
+         public class GameActivity extends android.app.Activity {\n\
+         }\n",
+    )
+    .unwrap();
+    let err = builder
+        .stage_java(&miniquad_java_dir, &java_files)
+        .err()
+        .unwrap();
+    assert!(
+        err.to_string()
+            .ends_with("Add the `//% MAIN_ACTIVITY_BODY` lines of miniquad's MainActivity to it."),
+        "{}",
+        err
+    );
+    fs::write(
+        &main_activity_path,
+        "package TARGET_PACKAGE_NAME;\n\
+         public class GameActivity extends android.app.Activity {\n\
+         //% MAIN_ACTIVITY_BODY\n\
+         }\n",
+    )
+    .unwrap();
+    builder.stage_java(&miniquad_java_dir, &java_files).unwrap();
+    assert_eq!(
+        fs::read_to_string(&main_activity).unwrap(),
+        "package com.example.game;\n\
+         public class GameActivity extends android.app.Activity {\n\
+         public void plugin() {}\n\n\
+         }\n"
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
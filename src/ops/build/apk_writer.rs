@@ -0,0 +1,184 @@
+//! Assembly of the APK out of the archive written by `aapt package`.
+//!
+//! The dex and the native libraries are written into the archive directly instead of with
+//! `aapt add` and a `zipalign` pass. Uncompressed entries are aligned as zipalign would align
+//! them, and the native libraries are stored uncompressed and aligned on the pages of the devices
+//! so that they can be mapped from the APK.
+
+use anyhow::format_err;
+use cargo::util::CargoResult;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use zip::write::{FileOptions, ZipWriter};
+use zip::{CompressionMethod, ZipArchive};
+
+/// Alignment of the uncompressed entries, `zipalign 4`
+pub const ALIGNMENT: u16 = 4;
+
+/// File added to the APK under `name`
+pub struct ApkEntry {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+/// Whether the entry `name` is a native library, stored uncompressed
+fn is_native_library(name: &str) -> bool {
+    name.starts_with("lib/") && name.ends_with(".so")
+}
+
+/// Writes the APK at `output`: the entries of the archive at `base`, followed by `entries`, which
+/// replace the entries of `base` with the same name. Native libraries are aligned on
+/// `page_alignment` KiB.
+pub fn write_apk(
+    base: &Path,
+    entries: &[ApkEntry],
+    page_alignment: u32,
+    output: &Path,
+) -> CargoResult<()> {
+    let native_library_alignment = (page_alignment * 1024) as u16;
+    let open_error = |path: &Path, err: &dyn std::fmt::Display| {
+        format_err!("Unable to read `{}`: {}", path.display(), err)
+    };
+    let mut base_archive = ZipArchive::new(File::open(base).map_err(|err| open_error(base, &err))?)
+        .map_err(|err| open_error(base, &err))?;
+    let file = File::create(output)
+        .map_err(|err| format_err!("Unable to create `{}`: {}", output.display(), err))?;
+    let mut writer = ZipWriter::new(file);
+
+    for i in 0..base_archive.len() {
+        let mut entry = base_archive.by_index_raw(i)?;
+        if entries.iter().any(|added| added.name == entry.name()) {
+            continue;
+        }
+        if entry.compression() == CompressionMethod::Stored {
+            // Copied raw, the data of a stored entry is its contents
+            let name = entry.name().to_owned();
+            let options = FileOptions::default()
+                .compression_method(CompressionMethod::Stored)
+                .last_modified_time(entry.last_modified());
+            let mut contents = Vec::with_capacity(entry.size() as usize);
+            entry.read_to_end(&mut contents)?;
+            writer.start_file_aligned(name, options, ALIGNMENT)?;
+            io::Write::write_all(&mut writer, &contents)?;
+        } else {
+            writer.raw_copy_file(entry)?;
+        }
+    }
+
+    for entry in entries {
+        let mut source = File::open(&entry.path).map_err(|err| open_error(&entry.path, &err))?;
+        if is_native_library(&entry.name) {
+            let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+            writer.start_file_aligned(entry.name.clone(), options, native_library_alignment)?;
+        } else {
+            writer.start_file(entry.name.clone(), FileOptions::default())?;
+        }
+        io::copy(&mut source, &mut writer)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+#[test]
+fn native_libraries_are_aligned() {
+    use std::fs;
+    use std::io::Write;
+
+    let root =
+        std::env::temp_dir().join(format!("cargo-quad-apk-apk-writer-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+
+    // Stands for the output of `aapt package`, with a stored `resources.arsc` and a stale dex
+    let base = root.join("app_unaligned.apk");
+    let mut base_writer = ZipWriter::new(File::create(&base).unwrap());
+    base_writer
+        .start_file("AndroidManifest.xml", FileOptions::default())
+        .unwrap();
+    base_writer.write_all(b"<manifest/>").unwrap();
+    base_writer
+        .start_file(
+            "resources.arsc",
+            FileOptions::default().compression_method(CompressionMethod::Stored),
+        )
+        .unwrap();
+    base_writer.write_all(b"arsc").unwrap();
+    base_writer
+        .start_file("classes.dex", FileOptions::default())
+        .unwrap();
+    base_writer.write_all(b"stale").unwrap();
+    base_writer.finish().unwrap();
+
+    let dex = root.join("classes.dex");
+    fs::write(&dex, "dex\n035\0").unwrap();
+    let library = root.join("libapp.so");
+    fs::write(&library, vec![0x7f; 1000]).unwrap();
+    let entries = vec![
+        ApkEntry {
+            name: "classes.dex".to_owned(),
+            path: dex,
+        },
+        ApkEntry {
+            name: "lib/arm64-v8a/libapp.so".to_owned(),
+            path: library.clone(),
+        },
+        ApkEntry {
+            name: "lib/armeabi-v7a/libapp.so".to_owned(),
+            path: library,
+        },
+    ];
+    let apk = root.join("app.apk");
+    write_apk(&base, &entries, 16, &apk).unwrap();
+
+    let mut archive = ZipArchive::new(File::open(&apk).unwrap()).unwrap();
+    assert_eq!(
+        archive
+            .file_names()
+            .collect::<std::collections::BTreeSet<_>>(),
+        [
+            "AndroidManifest.xml",
+            "classes.dex",
+            "lib/arm64-v8a/libapp.so",
+            "lib/armeabi-v7a/libapp.so",
+            "resources.arsc",
+        ]
+        .iter()
+        .copied()
+        .collect()
+    );
+    for name in &["lib/arm64-v8a/libapp.so", "lib/armeabi-v7a/libapp.so"] {
+        let mut library = archive.by_name(name).unwrap();
+        assert_eq!(library.compression(), CompressionMethod::Stored);
+        assert_eq!(library.data_start() % 16384, 0);
+        let mut contents = vec![];
+        library.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, vec![0x7f; 1000]);
+    }
+    let resources = archive.by_name("resources.arsc").unwrap();
+    assert_eq!(resources.compression(), CompressionMethod::Stored);
+    assert_eq!(resources.data_start() % ALIGNMENT as u64, 0);
+    drop(resources);
+    let mut dex = String::new();
+    archive
+        .by_name("classes.dex")
+        .unwrap()
+        .read_to_string(&mut dex)
+        .unwrap();
+    assert_eq!(dex, "dex\n035\0");
+    let mut manifest = String::new();
+    archive
+        .by_name("AndroidManifest.xml")
+        .unwrap()
+        .read_to_string(&mut manifest)
+        .unwrap();
+    assert_eq!(manifest, "<manifest/>");
+
+    // Pages of 4 KiB
+    write_apk(&base, &entries, 4, &apk).unwrap();
+    let mut archive = ZipArchive::new(File::open(&apk).unwrap()).unwrap();
+    let library = archive.by_name("lib/arm64-v8a/libapp.so").unwrap();
+    assert_eq!(library.data_start() % 4096, 0);
+
+    fs::remove_dir_all(&root).unwrap();
+}
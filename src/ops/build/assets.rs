@@ -0,0 +1,557 @@
+//! Manifest and verification of the assets packaged into an APK.
+//!
+//! With `generate_asset_manifest`, `assets/.manifest.json` lists every packaged asset with its
+//! size and XXH64 hash, so that the app can check what it was shipped with. With
+//! `verify_assets`, the assets are compared against expected lists before packaging.
+//!
+//! The assets of the package leave out the files ignored by the `.gitignore` and `.apkignore`
+//! files of the assets directory and by the `assets_exclude` globs, so that an assets directory at
+//! the root of the project doesn't package its repository or build outputs.
+
+use anyhow::format_err;
+use cargo::util::CargoResult;
+use ignore::overrides::OverrideBuilder;
+use ignore::WalkBuilder;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Name of the manifest within the assets directory
+pub const MANIFEST_NAME: &str = ".manifest.json";
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct AssetManifest {
+    /// Version of the format
+    pub version: u32,
+    /// Assets sorted by path
+    pub assets: Vec<AssetEntry>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct AssetEntry {
+    /// Path within the assets directory, with `/` separators
+    pub path: String,
+    pub size: u64,
+    /// XXH64 of the contents with seed 0, as 16 hexadecimal digits
+    pub xxhash64: String,
+}
+
+impl AssetManifest {
+    pub fn new(assets_dir: &Path) -> CargoResult<AssetManifest> {
+        AssetManifest::of(&list_assets(assets_dir)?)
+    }
+
+    /// Returns the manifest of listed assets, as their path and their full path
+    pub fn of(listed: &[(String, PathBuf)]) -> CargoResult<AssetManifest> {
+        let mut assets = vec![];
+        for (path, file) in listed {
+            let contents = fs::read(file)?;
+            assets.push(AssetEntry {
+                path: path.clone(),
+                size: contents.len() as u64,
+                xxhash64: format!("{:016x}", xxh64(&contents)),
+            });
+        }
+        Ok(AssetManifest { version: 1, assets })
+    }
+}
+
+/// `--ignore-assets` pattern of aapt for listed assets. The default pattern also ignores hidden
+/// files, which would leave out the manifest, and directories starting with `_`.
+pub const IGNORE_ASSETS: &str = "!.svn:!.git:!.ds_store:!*.scc:!CVS:!thumbs.db:!picasa.ini:!*~";
+
+/// Whether aapt leaves out a file or directory with `IGNORE_ASSETS`
+fn is_ignored(name: &str) -> bool {
+    let name = name.to_lowercase();
+    [
+        ".svn",
+        ".git",
+        ".ds_store",
+        "cvs",
+        "thumbs.db",
+        "picasa.ini",
+    ]
+    .contains(&name.as_str())
+        || name.ends_with(".scc")
+        || name.ends_with('~')
+}
+
+/// Returns the files of an assets directory which aapt packages with `IGNORE_ASSETS`, as their
+/// path within it and their full path, sorted by path
+pub fn list_assets(assets_dir: &Path) -> CargoResult<Vec<(String, PathBuf)>> {
+    let mut assets = vec![];
+    let entries = WalkDir::new(assets_dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() == 0 || !is_ignored(&entry.file_name().to_string_lossy())
+        });
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let path = asset_path(assets_dir, entry.path())?;
+        if path != MANIFEST_NAME {
+            assets.push((path, entry.path().to_owned()));
+        }
+    }
+    assets.sort();
+    Ok(assets)
+}
+
+/// Name of the files with ignore rules of the assets only, in the syntax of `.gitignore`
+pub const APKIGNORE_NAME: &str = ".apkignore";
+
+/// Assets of the package, without the ignored files
+#[derive(Debug, Default)]
+pub struct SourceAssets {
+    /// Path within the assets directory and full path, sorted by path
+    pub assets: Vec<(String, PathBuf)>,
+    /// Number of files and directories left out by the ignore rules
+    pub ignored: usize,
+}
+
+impl SourceAssets {
+    /// Keeps the assets of `subset`, files and directories of the assets directory, counting
+    /// the other assets as ignored
+    pub fn retain_subset(&mut self, subset: &[String]) {
+        let before = self.assets.len();
+        self.assets.retain(|(path, _)| {
+            subset.iter().any(|entry| {
+                let entry = entry.trim_end_matches('/');
+                path == entry
+                    || path
+                        .strip_prefix(entry)
+                        .map_or(false, |rest| rest.starts_with('/'))
+            })
+        });
+        self.ignored += before - self.assets.len();
+    }
+}
+
+/// Directories never packaged: repositories, and build outputs of cargo and of this tool
+fn is_always_excluded(path: &Path, name: &str) -> bool {
+    name == ".git"
+        || name == "android-artifacts"
+        || (name == "target"
+            && path
+                .parent()
+                .map_or(false, |dir| dir.join("Cargo.toml").exists()))
+}
+
+/// Whether a file holds ignore rules rather than an asset
+fn is_ignore_file(name: &str) -> bool {
+    name == ".gitignore" || name == APKIGNORE_NAME
+}
+
+/// Returns the files of an assets directory of the package, like `list_assets` but without the
+/// files ignored by the `.gitignore` and `.apkignore` files, the `exclude` globs and
+/// `is_always_excluded`. The ignore files above the directory don't apply, they may be outside of
+/// the project and usually ignore generated assets which are to be packaged.
+pub fn list_source_assets(assets_dir: &Path, exclude: &[String]) -> CargoResult<SourceAssets> {
+    let mut overrides = OverrideBuilder::new(assets_dir);
+    for glob in exclude {
+        overrides
+            .add(&format!("!{}", glob))
+            .map_err(|err| format_err!("Invalid `assets_exclude` glob `{}`: {}", glob, err))?;
+    }
+    let entries = WalkBuilder::new(assets_dir)
+        .standard_filters(false)
+        .git_ignore(true)
+        .require_git(false)
+        .parents(false)
+        .add_custom_ignore_filename(APKIGNORE_NAME)
+        .overrides(overrides.build()?)
+        .filter_entry(|entry| {
+            let name = entry.file_name().to_string_lossy();
+            entry.depth() == 0 || !(is_ignored(&name) || is_always_excluded(entry.path(), &name))
+        })
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build();
+    let mut assets = vec![];
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy();
+        if !entry
+            .file_type()
+            .map_or(false, |file_type| file_type.is_file())
+            || is_ignore_file(&name)
+        {
+            continue;
+        }
+        let path = asset_path(assets_dir, entry.path())?;
+        if path != MANIFEST_NAME {
+            assets.push((path, entry.path().to_owned()));
+        }
+    }
+    assets.sort();
+
+    // Counted apart, the walk above doesn't tell what it skipped. Excluded directories count as
+    // one entry without being walked.
+    let listed = assets
+        .iter()
+        .map(|(path, _)| path.as_str())
+        .collect::<BTreeSet<_>>();
+    let (mut excluded, mut unlisted) = (0, 0);
+    let entries = WalkDir::new(assets_dir).into_iter().filter_entry(|entry| {
+        let name = entry.file_name().to_string_lossy();
+        if entry.depth() == 0 || is_ignored(&name) {
+            return entry.depth() == 0;
+        }
+        if is_always_excluded(entry.path(), &name) {
+            excluded += 1;
+            return false;
+        }
+        true
+    });
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            let path = asset_path(assets_dir, entry.path())?;
+            if path != MANIFEST_NAME && !listed.contains(path.as_str()) {
+                unlisted += 1;
+            }
+        }
+    }
+    Ok(SourceAssets {
+        assets,
+        ignored: excluded + unlisted,
+    })
+}
+
+/// Returns the path of a file within the assets directory, with `/` separators
+fn asset_path(assets_dir: &Path, file: &Path) -> CargoResult<String> {
+    Ok(file
+        .strip_prefix(assets_dir)?
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/"))
+}
+
+/// Reads the asset paths of an expected list, one per line. Blank lines and lines starting
+/// with `#` are ignored.
+pub fn read_expected_list(list: &Path) -> CargoResult<BTreeSet<String>> {
+    let content = fs::read_to_string(list)
+        .map_err(|err| format_err!("Unable to read `{}`: {}", list.display(), err))?;
+    Ok(parse_expected_list(&content))
+}
+
+fn parse_expected_list(content: &str) -> BTreeSet<String> {
+    content
+        .lines()
+        .map(|line| line.trim().trim_start_matches("./"))
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect()
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct AssetMismatch {
+    /// Expected assets which are not packaged
+    pub missing: Vec<String>,
+    /// Packaged assets which are not expected
+    pub extra: Vec<String>,
+}
+
+/// Compares the packaged assets with the expected ones, `None` when they match
+pub fn compare_assets(
+    packaged: &BTreeSet<String>,
+    expected: &BTreeSet<String>,
+) -> Option<AssetMismatch> {
+    let mismatch = AssetMismatch {
+        missing: expected.difference(packaged).cloned().collect(),
+        extra: packaged.difference(expected).cloned().collect(),
+    };
+    if mismatch == AssetMismatch::default() {
+        None
+    } else {
+        Some(mismatch)
+    }
+}
+
+impl AssetMismatch {
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        if !self.missing.is_empty() {
+            out.push_str(&format!("\n  missing: {}", self.missing.join(", ")));
+        }
+        if !self.extra.is_empty() {
+            out.push_str(&format!("\n  extra: {}", self.extra.join(", ")));
+        }
+        out
+    }
+}
+
+const PRIME64_1: u64 = 0x9E37_79B1_85EB_CA87;
+const PRIME64_2: u64 = 0xC2B2_AE3D_27D4_EB4F;
+const PRIME64_3: u64 = 0x1656_67B1_9E37_79F9;
+const PRIME64_4: u64 = 0x85EB_CA77_C2B2_AE63;
+const PRIME64_5: u64 = 0x27D4_EB2F_1656_67C5;
+
+/// XXH64 of `data` with seed 0
+pub fn xxh64(data: &[u8]) -> u64 {
+    fn round(acc: u64, input: u64) -> u64 {
+        acc.wrapping_add(input.wrapping_mul(PRIME64_2))
+            .rotate_left(31)
+            .wrapping_mul(PRIME64_1)
+    }
+    fn merge(acc: u64, value: u64) -> u64 {
+        (acc ^ round(0, value))
+            .wrapping_mul(PRIME64_1)
+            .wrapping_add(PRIME64_4)
+    }
+    let read_u64 = |bytes: &[u8]| {
+        let mut word = [0; 8];
+        word.copy_from_slice(&bytes[..8]);
+        u64::from_le_bytes(word)
+    };
+
+    let mut remaining = data;
+    let mut hash = if data.len() >= 32 {
+        let mut v = [
+            PRIME64_1.wrapping_add(PRIME64_2),
+            PRIME64_2,
+            0,
+            0u64.wrapping_sub(PRIME64_1),
+        ];
+        while remaining.len() >= 32 {
+            for (i, lane) in v.iter_mut().enumerate() {
+                *lane = round(*lane, read_u64(&remaining[i * 8..]));
+            }
+            remaining = &remaining[32..];
+        }
+        let mut hash = v[0]
+            .rotate_left(1)
+            .wrapping_add(v[1].rotate_left(7))
+            .wrapping_add(v[2].rotate_left(12))
+            .wrapping_add(v[3].rotate_left(18));
+        for lane in &v {
+            hash = merge(hash, *lane);
+        }
+        hash
+    } else {
+        PRIME64_5
+    };
+    hash = hash.wrapping_add(data.len() as u64);
+
+    while remaining.len() >= 8 {
+        hash ^= round(0, read_u64(remaining));
+        hash = hash
+            .rotate_left(27)
+            .wrapping_mul(PRIME64_1)
+            .wrapping_add(PRIME64_4);
+        remaining = &remaining[8..];
+    }
+    if remaining.len() >= 4 {
+        let mut word = [0; 4];
+        word.copy_from_slice(&remaining[..4]);
+        hash ^= (u32::from_le_bytes(word) as u64).wrapping_mul(PRIME64_1);
+        hash = hash
+            .rotate_left(23)
+            .wrapping_mul(PRIME64_2)
+            .wrapping_add(PRIME64_3);
+        remaining = &remaining[4..];
+    }
+    for &byte in remaining {
+        hash ^= (byte as u64).wrapping_mul(PRIME64_5);
+        hash = hash.rotate_left(11).wrapping_mul(PRIME64_1);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(PRIME64_2);
+    hash ^= hash >> 29;
+    hash = hash.wrapping_mul(PRIME64_3);
+    hash ^ (hash >> 32)
+}
+
+#[test]
+fn xxh64_reference_values() {
+    assert_eq!(xxh64(b""), 0xEF46_DB37_51D8_E999);
+    assert_eq!(xxh64(b"a"), 0xD24E_C4F1_A98C_6E5B);
+    assert_eq!(xxh64(b"abc"), 0x44BC_2CF5_AD77_0999);
+    assert_eq!(
+        xxh64(b"Nobody inspects the spammish repetition"),
+        0xFBCE_A83C_8A37_8BF1
+    );
+}
+
+#[test]
+fn asset_manifest_json() {
+    let root = std::env::temp_dir().join(format!("cargo-quad-apk-assets-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("textures")).unwrap();
+    fs::write(root.join("textures").join("a.png"), "abc").unwrap();
+    fs::write(root.join("level.txt"), "").unwrap();
+    // A manifest from a previous build is not listed
+    fs::write(root.join(MANIFEST_NAME), "{}").unwrap();
+    fs::create_dir_all(root.join(".git")).unwrap();
+    fs::write(root.join(".git").join("HEAD"), "").unwrap();
+    fs::write(root.join("level.txt~"), "").unwrap();
+
+    let manifest = AssetManifest::new(&root).unwrap();
+    let json = serde_json::to_string_pretty(&manifest).unwrap();
+    assert_eq!(
+        json,
+        r#"{
+  "version": 1,
+  "assets": [
+    {
+      "path": "level.txt",
+      "size": 0,
+      "xxhash64": "ef46db3751d8e999"
+    },
+    {
+      "path": "textures/a.png",
+      "size": 3,
+      "xxhash64": "44bc2cf5ad770999"
+    }
+  ]
+}"#
+    );
+    assert_eq!(
+        serde_json::from_str::<AssetManifest>(&json).unwrap(),
+        manifest
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn compare_asset_lists() {
+    let expected = parse_expected_list(
+        "# Loaded by the title screen\n\
+         textures/a.png\n\
+         ./sounds/click.ogg\n\
+         \n\
+         level.txt\n",
+    );
+    let packaged = ["level.txt", "textures/a.png", "textures/unused.png"]
+        .iter()
+        .map(|path| path.to_string())
+        .collect::<BTreeSet<_>>();
+
+    let mismatch = compare_assets(&packaged, &expected).unwrap();
+    assert_eq!(mismatch.missing, vec!["sounds/click.ogg"]);
+    assert_eq!(mismatch.extra, vec!["textures/unused.png"]);
+    assert_eq!(
+        mismatch.render(),
+        "\n  missing: sounds/click.ogg\n  extra: textures/unused.png"
+    );
+
+    assert_eq!(compare_assets(&packaged, &packaged), None);
+    assert_eq!(
+        compare_assets(&BTreeSet::new(), &expected).unwrap().missing,
+        vec!["level.txt", "sounds/click.ogg", "textures/a.png"]
+    );
+}
+
+#[test]
+fn ignored_source_assets() {
+    let root = std::env::temp_dir().join(format!(
+        "cargo-quad-apk-source-assets-{}",
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&root);
+    // Ignoring the generated assets for git, above the assets directory
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join(".gitignore"), "*.json\n").unwrap();
+    let dir = root.join("assets");
+    for (path, contents) in &[
+        ("Cargo.toml", "[package]"),
+        (".gitignore", "*.log\n/build/\n"),
+        (".apkignore", "*.psd\n!keep.psd\n"),
+        ("main.rs.swp", ""),
+        ("textures/stone.png", ""),
+        ("textures/stone.psd", ""),
+        ("textures/keep.psd", ""),
+        ("textures/.gitignore", "draft_*\n"),
+        ("textures/draft_wall.png", ""),
+        ("levels/1.json", ""),
+        ("levels/target/2.json", ""),
+        ("debug.log", ""),
+        ("build/out.bin", ""),
+        (".git/HEAD", ""),
+        ("target/debug/app", ""),
+        ("target/android-artifacts/debug/apk/app.apk", ""),
+        ("thumbs.db", ""),
+    ] {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    let listed = |exclude: &[&str]| {
+        let exclude = exclude
+            .iter()
+            .map(|glob| glob.to_string())
+            .collect::<Vec<_>>();
+        let source = list_source_assets(&dir, &exclude).unwrap();
+        let paths = source
+            .assets
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect::<Vec<_>>();
+        (paths, source.ignored)
+    };
+    // `target` is only a build output next to a Cargo.toml
+    assert_eq!(
+        listed(&[]),
+        (
+            vec![
+                "Cargo.toml".to_owned(),
+                "levels/1.json".to_owned(),
+                "levels/target/2.json".to_owned(),
+                "main.rs.swp".to_owned(),
+                "textures/keep.psd".to_owned(),
+                "textures/stone.png".to_owned(),
+            ],
+            // The ignore files, debug.log, build/out.bin, textures/stone.psd,
+            // textures/draft_wall.png, target
+            8
+        )
+    );
+    assert_eq!(
+        listed(&["*.swp", "Cargo.toml", "levels/**"]),
+        (
+            vec![
+                "textures/keep.psd".to_owned(),
+                "textures/stone.png".to_owned(),
+            ],
+            12
+        )
+    );
+    assert!(list_source_assets(&dir, &["[".to_owned()]).is_err());
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn assets_subset() {
+    let mut source = SourceAssets {
+        assets: [
+            "intro.ogg",
+            "levels/1.json",
+            "levels/10.json",
+            "levels_extra/1.json",
+            "textures/stone.png",
+        ]
+        .iter()
+        .map(|path| (path.to_string(), PathBuf::from("/app/assets").join(path)))
+        .collect(),
+        ignored: 2,
+    };
+    source.retain_subset(&["levels/".to_owned(), "intro.ogg".to_owned()]);
+    assert_eq!(
+        source
+            .assets
+            .iter()
+            .map(|(path, _)| path.as_str())
+            .collect::<Vec<_>>(),
+        vec!["intro.ogg", "levels/1.json", "levels/10.json"]
+    );
+    assert_eq!(source.ignored, 4);
+}
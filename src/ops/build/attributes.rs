@@ -0,0 +1,679 @@
+//! Validation of `application_attributes` and `activity_attributes`.
+//!
+//! The attributes are copied into the manifest, where aapt rejects the attributes unknown to the
+//! platform the app is compiled against without naming the key they come from, and where Android
+//! silently ignores misspelled names. They are checked against a table of the attributes of the
+//! `<application>` and `<activity>` elements with the API level which added them. The keys
+//! modelling some of these attributes, like `orientation` or `allow_backup`, are rendered before
+//! them.
+
+use super::locales;
+use crate::config::{AndroidConfig, AndroidTargetConfig};
+use anyhow::format_err;
+use cargo::util::CargoResult;
+
+/// Element of the manifest the attributes are added to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Element {
+    Application,
+    Activity,
+}
+
+impl Element {
+    /// Key of the configuration holding the attributes of the element
+    fn key(self) -> &'static str {
+        match self {
+            Element::Application => "application_attributes",
+            Element::Activity => "activity_attributes",
+        }
+    }
+}
+
+use self::Element::{Activity, Application};
+
+/// Attributes of the `android` namespace: the element, the name, the API level of the platform
+/// which added it and the target API level from which Android ignores it, if any
+const KNOWN_ATTRIBUTES: &[(Element, &str, u32, Option<u32>)] = &[
+    (Application, "allowAudioPlaybackCapture", 29, None),
+    (Application, "allowBackup", 4, None),
+    (Application, "allowClearUserData", 1, None),
+    (Application, "allowNativeHeapPointerTagging", 30, None),
+    (Application, "allowTaskReparenting", 1, None),
+    (Application, "appCategory", 26, None),
+    (Application, "appComponentFactory", 28, None),
+    (Application, "backupAgent", 8, None),
+    (Application, "backupInForeground", 24, None),
+    (Application, "banner", 21, None),
+    (Application, "dataExtractionRules", 31, None),
+    (Application, "debuggable", 1, None),
+    (Application, "description", 1, None),
+    (Application, "directBootAware", 24, None),
+    (Application, "enableOnBackInvokedCallback", 33, None),
+    (Application, "enabled", 1, None),
+    (Application, "extractNativeLibs", 23, None),
+    (Application, "fullBackupContent", 23, None),
+    (Application, "fullBackupOnly", 23, None),
+    (Application, "gwpAsanMode", 30, None),
+    (Application, "hardwareAccelerated", 11, None),
+    (Application, "hasFragileUserData", 29, None),
+    (Application, "icon", 1, None),
+    (Application, "isGame", 21, None),
+    (Application, "killAfterRestore", 8, None),
+    (Application, "label", 1, None),
+    (Application, "largeHeap", 11, None),
+    (Application, "localeConfig", 33, None),
+    (Application, "logo", 11, None),
+    (Application, "manageSpaceActivity", 1, None),
+    (Application, "memtagMode", 31, None),
+    (Application, "name", 1, None),
+    (Application, "networkSecurityConfig", 24, None),
+    (Application, "permission", 1, None),
+    (Application, "persistent", 1, None),
+    (Application, "preserveLegacyExternalStorage", 30, None),
+    (Application, "process", 1, None),
+    (Application, "requestLegacyExternalStorage", 29, Some(30)),
+    (Application, "requestRawExternalStorageAccess", 30, None),
+    (Application, "requiredAccountType", 18, None),
+    (Application, "resizeableActivity", 24, None),
+    (Application, "restoreAnyVersion", 8, None),
+    (Application, "restrictedAccountType", 18, None),
+    (Application, "roundIcon", 25, None),
+    (Application, "supportsRtl", 17, None),
+    (Application, "taskAffinity", 1, None),
+    (Application, "testOnly", 4, None),
+    (Application, "theme", 1, None),
+    (Application, "uiOptions", 14, None),
+    (Application, "useEmbeddedDex", 29, None),
+    (Application, "usesCleartextTraffic", 23, None),
+    (Application, "vmSafeMode", 8, None),
+    (Application, "zygotePreloadName", 29, None),
+    (Activity, "allowEmbedded", 20, None),
+    (Activity, "allowTaskReparenting", 1, None),
+    (Activity, "alwaysRetainTaskState", 1, None),
+    (Activity, "autoRemoveFromRecents", 21, None),
+    (Activity, "banner", 21, None),
+    (Activity, "clearTaskOnLaunch", 1, None),
+    (Activity, "colorMode", 26, None),
+    (Activity, "configChanges", 1, None),
+    (Activity, "directBootAware", 24, None),
+    (Activity, "documentLaunchMode", 21, None),
+    (Activity, "enabled", 1, None),
+    (Activity, "excludeFromRecents", 1, None),
+    (Activity, "exported", 1, None),
+    (Activity, "finishOnTaskLaunch", 1, None),
+    (Activity, "hardwareAccelerated", 11, None),
+    (Activity, "icon", 1, None),
+    (Activity, "immersive", 18, None),
+    (Activity, "label", 1, None),
+    (Activity, "launchMode", 1, None),
+    (Activity, "lockTaskMode", 23, None),
+    (Activity, "maxAspectRatio", 26, None),
+    (Activity, "maxRecents", 21, None),
+    (Activity, "minAspectRatio", 29, None),
+    (Activity, "multiprocess", 1, None),
+    (Activity, "name", 1, None),
+    (Activity, "noHistory", 3, None),
+    (Activity, "parentActivityName", 16, None),
+    (Activity, "permission", 1, None),
+    (Activity, "persistableMode", 21, None),
+    (Activity, "preferMinimalPostProcessing", 30, None),
+    (Activity, "process", 1, None),
+    (Activity, "relinquishTaskIdentity", 21, None),
+    (Activity, "resizeableActivity", 24, None),
+    (Activity, "rotationAnimation", 26, None),
+    (Activity, "screenOrientation", 1, None),
+    (Activity, "showForAllUsers", 23, None),
+    (Activity, "showWhenLocked", 27, None),
+    (Activity, "stateNotNeeded", 1, None),
+    (Activity, "supportsPictureInPicture", 24, None),
+    (Activity, "taskAffinity", 1, None),
+    (Activity, "theme", 1, None),
+    (Activity, "turnScreenOn", 27, None),
+    (Activity, "uiOptions", 14, None),
+    (Activity, "windowSoftInputMode", 3, None),
+];
+
+/// Returns the attributes of `element` set from the keys modelling them, with those keys and the
+/// values of the attributes
+fn structured_attributes(
+    target_config: &AndroidTargetConfig,
+    element: Element,
+) -> Vec<(&'static str, &'static str, String)> {
+    let attributes = match element {
+        Application => vec![
+            (
+                "android:debuggable",
+                "debuggable",
+                target_config.debuggable_attribute.map(|v| v.to_string()),
+            ),
+            (
+                "android:allowBackup",
+                "allow_backup",
+                target_config.allow_backup.map(|v| v.to_string()),
+            ),
+            (
+                "android:hardwareAccelerated",
+                "hardware_accelerated",
+                target_config.hardware_accelerated.map(|v| v.to_string()),
+            ),
+        ],
+        Activity => vec![
+            (
+                "android:screenOrientation",
+                "orientation",
+                target_config.orientation.clone(),
+            ),
+            (
+                "android:resizeableActivity",
+                "resizable_activity",
+                target_config.resizable_activity.map(|v| v.to_string()),
+            ),
+            (
+                "android:launchMode",
+                "launch_mode",
+                target_config.launch_mode.clone(),
+            ),
+            (
+                "android:exported",
+                "activity.exported",
+                target_config.main_activity_exported.map(|v| v.to_string()),
+            ),
+        ],
+    };
+    attributes
+        .into_iter()
+        .filter_map(|(name, key, value)| Some((name, key, value?)))
+        .collect()
+}
+
+/// Returns the attributes added to `element`: those of the keys modelling them, then those of
+/// `application_attributes` or `activity_attributes`
+pub fn manifest_attributes(
+    target_config: &AndroidTargetConfig,
+    element: Element,
+) -> Vec<(String, String)> {
+    let attributes = match element {
+        Application => &target_config.application_attributes,
+        Activity => &target_config.activity_attributes,
+    };
+    structured_attributes(target_config, element)
+        .into_iter()
+        .map(|(name, _, value)| (name.to_owned(), value))
+        .chain(attributes.iter().cloned())
+        .collect()
+}
+
+/// Returns the attributes of `element` the manifest sets from other keys, with those keys
+fn generated_attributes(
+    config: &AndroidConfig,
+    target_config: &AndroidTargetConfig,
+    element: Element,
+) -> Vec<(&'static str, &'static str)> {
+    let attributes = match element {
+        Application => vec![
+            ("android:hasCode", "framework", true),
+            ("android:label", "label", true),
+            ("android:icon", "icon", target_config.package_icon.is_some()),
+            ("android:theme", "fullscreen", target_config.fullscreen),
+            (
+                "android:process",
+                "application_process",
+                target_config.application_process.is_some(),
+            ),
+            ("android:testOnly", "test_only", target_config.test_only),
+            (
+                "android:requestLegacyExternalStorage",
+                "request_legacy_external_storage",
+                super::legacy_external_storage(config, target_config),
+            ),
+            (
+                "android:localeConfig",
+                "supported_locales",
+                locales::locale_config_enabled(config, target_config),
+            ),
+        ],
+        Activity => vec![
+            ("android:name", "framework", true),
+            ("android:label", "label", true),
+            ("android:configChanges", "config_changes", true),
+            (
+                "android:windowSoftInputMode",
+                "soft_input_mode",
+                target_config.soft_input_mode.is_some(),
+            ),
+            (
+                "android:process",
+                "activity_process",
+                target_config.activity_process.is_some(),
+            ),
+        ],
+    };
+    attributes
+        .into_iter()
+        .filter(|(_, _, generated)| *generated)
+        .map(|(name, key, _)| (name, key))
+        .chain(
+            structured_attributes(target_config, element)
+                .into_iter()
+                .map(|(name, key, _)| (name, key)),
+        )
+        .collect()
+}
+
+/// Whether `name` is an XML name without a namespace prefix
+fn is_local_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+/// Returns the API level of the platform of `android.jar`, `None` for preview platforms
+fn compile_sdk_version(config: &AndroidConfig) -> Option<u32> {
+    config
+        .android_jar_path
+        .parent()?
+        .file_name()?
+        .to_str()?
+        .strip_prefix("android-")?
+        .parse()
+        .ok()
+}
+
+/// Number of single character edits turning `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + if a == *b { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Checks the attributes added to `element`, failing on those which break the manifest or which
+/// aapt rejects, and returning the warnings about those which Android ignores
+pub fn check_attributes(
+    config: &AndroidConfig,
+    target_config: &AndroidTargetConfig,
+    element: Element,
+    attributes: &[(String, String)],
+) -> CargoResult<Vec<String>> {
+    let key = element.key();
+    let generated = generated_attributes(config, target_config, element);
+    let compile_sdk_version = compile_sdk_version(config);
+    let mut warnings = vec![];
+    for (name, structured_key, _) in structured_attributes(target_config, element) {
+        let since = KNOWN_ATTRIBUTES
+            .iter()
+            .find(|(known_element, known_name, _, _)| {
+                *known_element == element && Some(*known_name) == name.strip_prefix("android:")
+            })
+            .map_or(1, |(_, _, since, _)| *since);
+        if let Some(compile_sdk_version) = compile_sdk_version {
+            if since > compile_sdk_version {
+                return Err(format_err!(
+                    "`{}` sets `{}`, which was added in API {}, the app is compiled against \
+                     android-{}, raise `android_version` to {} or higher",
+                    structured_key,
+                    name,
+                    since,
+                    compile_sdk_version,
+                    since
+                ));
+            }
+        }
+    }
+    for (name, _) in attributes {
+        let (prefix, local_name) = match name.split_once(':') {
+            Some((prefix, local_name)) => (Some(prefix), local_name),
+            None => (None, name.as_str()),
+        };
+        if !is_local_name(local_name) || prefix.map_or(false, |prefix| !is_local_name(prefix)) {
+            return Err(format_err!(
+                "Invalid attribute name `{}` in `{}`, expected a name like \
+                 `android:hardwareAccelerated`",
+                name,
+                key
+            ));
+        }
+        if let Some((_, generated_key)) = generated.iter().find(|(attribute, _)| attribute == name)
+        {
+            return Err(format_err!(
+                "`{}` in `{}` is already set from `{}`",
+                name,
+                key,
+                generated_key
+            ));
+        }
+        match prefix {
+            Some("android") => {}
+            Some(prefix) => {
+                return Err(format_err!(
+                    "Attribute `{}` in `{}` has the namespace prefix `{}`, only `android:` is \
+                     declared in the manifest",
+                    name,
+                    key,
+                    prefix
+                ))
+            }
+            None => {
+                if !config.allow_unknown_attributes {
+                    warnings.push(format!(
+                        "attribute `{}` in `{}` has no `android:` prefix, Android ignores it",
+                        name, key
+                    ));
+                }
+                continue;
+            }
+        }
+
+        let known = KNOWN_ATTRIBUTES
+            .iter()
+            .find(|(known_element, known_name, _, _)| {
+                *known_element == element && *known_name == local_name
+            });
+        match known {
+            Some(&(_, _, since, ignored_from)) => {
+                if let Some(compile_sdk_version) = compile_sdk_version {
+                    if since > compile_sdk_version {
+                        return Err(format_err!(
+                            "`{}` in `{}` was added in API {}, the app is compiled against \
+                             android-{}, raise `android_version` to {} or higher",
+                            name,
+                            key,
+                            since,
+                            compile_sdk_version,
+                            since
+                        ));
+                    }
+                }
+                if let Some(ignored_from) = ignored_from {
+                    if config.target_sdk_version >= ignored_from {
+                        warnings.push(format!(
+                            "`{}` in `{}` is ignored when targeting API {} ({} or higher)",
+                            name, key, config.target_sdk_version, ignored_from
+                        ));
+                    }
+                }
+            }
+            None if config.allow_unknown_attributes => {}
+            None => {
+                let suggestion = KNOWN_ATTRIBUTES
+                    .iter()
+                    .filter(|(known_element, _, _, _)| *known_element == element)
+                    .map(|(_, known_name, _, _)| {
+                        let distance = edit_distance(
+                            &local_name.to_ascii_lowercase(),
+                            &known_name.to_ascii_lowercase(),
+                        );
+                        (distance, known_name)
+                    })
+                    .filter(|(distance, _)| *distance <= 2)
+                    .min()
+                    .map_or(String::new(), |(_, known_name)| {
+                        format!(", did you mean `android:{}`?", known_name)
+                    });
+                warnings.push(format!(
+                    "unknown attribute `{}` in `{}`{} Set `allow_unknown_attributes = true` \
+                     if the attribute is right",
+                    name,
+                    key,
+                    if suggestion.is_empty() {
+                        ".".to_owned()
+                    } else {
+                        suggestion
+                    }
+                ));
+            }
+        }
+    }
+    Ok(warnings)
+}
+
+#[cfg(test)]
+fn check(
+    metadata: &str,
+    element: Element,
+    attributes: &[(&str, &str)],
+) -> CargoResult<Vec<String>> {
+    use cargo::core::TargetKind;
+
+    let config = crate::config::from_metadata(metadata);
+    let target_config = config.resolve((TargetKind::Bin, "app".to_owned())).unwrap();
+    let attributes = attributes
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect::<Vec<_>>();
+    check_attributes(&config, &target_config, element, &attributes)
+}
+
+#[test]
+fn known_attributes() {
+    assert!(check(
+        "",
+        Application,
+        &[
+            ("android:vmSafeMode", "true"),
+            ("android:extractNativeLibs", "false"),
+            ("android:hardwareAccelerated", "true"),
+        ]
+    )
+    .unwrap()
+    .is_empty());
+    assert!(check(
+        "",
+        Activity,
+        &[
+            ("android:exported", "true"),
+            ("android:screenOrientation", "landscape")
+        ]
+    )
+    .unwrap()
+    .is_empty());
+
+    // Unknown to aapt of android-31
+    assert_eq!(
+        check("", Application, &[("android:localeConfig", "@xml/locales")])
+            .unwrap_err()
+            .to_string(),
+        "`android:localeConfig` in `application_attributes` was added in API 33, the app is \
+         compiled against android-31, raise `android_version` to 33 or higher"
+    );
+    assert_eq!(
+        check(
+            "android_version = 33",
+            Application,
+            &[("android:localeConfig", "@xml/locales")]
+        )
+        .unwrap(),
+        Vec::<String>::new()
+    );
+
+    // Ignored when targeting API 30 or higher
+    assert_eq!(
+        check(
+            "",
+            Application,
+            &[("android:requestLegacyExternalStorage", "true")]
+        )
+        .unwrap(),
+        vec![
+            "`android:requestLegacyExternalStorage` in `application_attributes` is ignored when \
+             targeting API 31 (30 or higher)"
+                .to_owned()
+        ]
+    );
+    assert!(check(
+        "target_sdk_version = 29",
+        Application,
+        &[("android:requestLegacyExternalStorage", "true")]
+    )
+    .unwrap()
+    .is_empty());
+}
+
+#[test]
+fn unknown_attributes() {
+    assert_eq!(
+        check("", Application, &[("android:debugable", "true")]).unwrap(),
+        vec![
+            "unknown attribute `android:debugable` in `application_attributes`, did you mean \
+             `android:debuggable`? Set `allow_unknown_attributes = true` if the attribute is \
+             right"
+                .to_owned()
+        ]
+    );
+    assert_eq!(
+        check("", Activity, &[("android:screenorientation", "portrait")]).unwrap()[0],
+        "unknown attribute `android:screenorientation` in `activity_attributes`, did you mean \
+         `android:screenOrientation`? Set `allow_unknown_attributes = true` if the attribute is \
+         right"
+    );
+    assert_eq!(
+        check("", Activity, &[("android:somethingNew", "1")]).unwrap()[0],
+        "unknown attribute `android:somethingNew` in `activity_attributes`. Set \
+         `allow_unknown_attributes = true` if the attribute is right"
+    );
+    // Attributes of the other element
+    assert!(
+        check("", Activity, &[("android:vmSafeMode", "true")]).unwrap()[0]
+            .starts_with("unknown attribute `android:vmSafeMode` in `activity_attributes`")
+    );
+    assert_eq!(
+        check("", Application, &[("hardwareAccelerated", "true")]).unwrap(),
+        vec![
+            "attribute `hardwareAccelerated` in `application_attributes` has no `android:` \
+             prefix, Android ignores it"
+                .to_owned()
+        ]
+    );
+    assert!(check(
+        "allow_unknown_attributes = true",
+        Application,
+        &[
+            ("android:somethingNew", "1"),
+            ("hardwareAccelerated", "true")
+        ]
+    )
+    .unwrap()
+    .is_empty());
+}
+
+#[test]
+fn malformed_attributes() {
+    let error = |name: &str| {
+        check("", Application, &[(name, "true")])
+            .unwrap_err()
+            .to_string()
+    };
+    for name in &[
+        "",
+        "android:",
+        ":debuggable",
+        "android:debuggable=\"true\"",
+        "android:hardware accelerated",
+        "android:1st",
+        "android:a:b",
+        "<android:debuggable",
+    ] {
+        assert_eq!(
+            error(name),
+            format!(
+                "Invalid attribute name `{}` in `application_attributes`, expected a name like \
+                 `android:hardwareAccelerated`",
+                name
+            )
+        );
+    }
+    assert_eq!(
+        error("tools:ignore"),
+        "Attribute `tools:ignore` in `application_attributes` has the namespace prefix `tools`, \
+         only `android:` is declared in the manifest"
+    );
+    assert_eq!(
+        error("android:label"),
+        "`android:label` in `application_attributes` is already set from `label`"
+    );
+    assert_eq!(
+        check(
+            "fullscreen = true",
+            Application,
+            &[("android:theme", "@style/App")]
+        )
+        .unwrap_err()
+        .to_string(),
+        "`android:theme` in `application_attributes` is already set from `fullscreen`"
+    );
+    assert!(check("", Application, &[("android:theme", "@style/App")]).is_ok());
+    assert_eq!(
+        check("", Activity, &[("android:configChanges", "orientation")])
+            .unwrap_err()
+            .to_string(),
+        "`android:configChanges` in `activity_attributes` is already set from `config_changes`"
+    );
+    assert_eq!(
+        check(
+            "[activity]\nexported = false",
+            Activity,
+            &[("android:exported", "false")]
+        )
+        .unwrap_err()
+        .to_string(),
+        "`android:exported` in `activity_attributes` is already set from `activity.exported`"
+    );
+}
+
+#[test]
+fn modelled_attributes() {
+    assert_eq!(
+        check(
+            "allow_backup = false",
+            Application,
+            &[("android:allowBackup", "true")]
+        )
+        .unwrap_err()
+        .to_string(),
+        "`android:allowBackup` in `application_attributes` is already set from `allow_backup`"
+    );
+    assert_eq!(
+        check(
+            "orientation = \"portrait\"",
+            Activity,
+            &[("android:screenOrientation", "user")]
+        )
+        .unwrap_err()
+        .to_string(),
+        "`android:screenOrientation` in `activity_attributes` is already set from `orientation`"
+    );
+    assert!(check(
+        "allow_backup = false",
+        Activity,
+        &[("android:immersive", "true")]
+    )
+    .is_ok());
+
+    assert_eq!(
+        check(
+            "android_version = 23\nresizable_activity = true",
+            Activity,
+            &[]
+        )
+        .unwrap_err()
+        .to_string(),
+        "`resizable_activity` sets `android:resizeableActivity`, which was added in API 24, the \
+         app is compiled against android-23, raise `android_version` to 24 or higher"
+    );
+    assert!(check(
+        "android_version = 24\nresizable_activity = true",
+        Activity,
+        &[]
+    )
+    .unwrap()
+    .is_empty());
+}
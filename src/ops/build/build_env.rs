@@ -0,0 +1,149 @@
+//! Record of how an APK was built, for the bug reports of test builds.
+//!
+//! With `embed_build_env`, `assets/.build-env.json` holds the versions of the toolchain, the git
+//! commit and the enabled features, and the build ends with a short fingerprint of it which
+//! testers can read from the app and report. Nothing identifying the machine or its user is
+//! recorded, the host is only described by its OS.
+
+use crate::config::AndroidConfig;
+use cargo::core::Workspace;
+use cargo::util::CargoResult;
+use cargo_util::{ProcessBuilder, Sha256};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// Name of the file within the assets directory
+pub const FILE_NAME: &str = ".build-env.json";
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct BuildEnv {
+    /// Version of the format
+    pub version: u32,
+    pub cargo_quad_apk: String,
+    /// Output of `rustc -V`
+    pub rustc: Option<String>,
+    /// `Pkg.Revision` of the NDK
+    pub ndk: Option<String>,
+    /// Commit of the workspace, with `-dirty` appended when there are uncommitted changes
+    pub git_commit: Option<String>,
+    /// `debug` or `release`
+    pub profile: String,
+    pub features: Vec<String>,
+    pub no_default_features: bool,
+    /// OS of the host, like `linux`, `macos` or `windows`
+    pub host_os: String,
+}
+
+impl BuildEnv {
+    pub fn collect(workspace: &Workspace, config: &AndroidConfig) -> BuildEnv {
+        let rustc = std::env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+        BuildEnv {
+            version: 1,
+            cargo_quad_apk: env!("CARGO_PKG_VERSION").to_owned(),
+            rustc: super::util::tool_version(ProcessBuilder::new(rustc).arg("-V")),
+            ndk: fs::read_to_string(config.ndk_path.join("source.properties"))
+                .ok()
+                .and_then(|properties| ndk_revision(&properties)),
+            git_commit: git_commit(workspace.root()),
+            profile: if config.release { "release" } else { "debug" }.to_owned(),
+            features: config.cargo_features.iter().cloned().collect(),
+            no_default_features: config.no_default_features,
+            host_os: std::env::consts::OS.to_owned(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// First 16 hexadecimal digits of the SHA-256 of the JSON
+    pub fn fingerprint(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.to_json().as_bytes());
+        hasher.finish_hex()[..16].to_owned()
+    }
+}
+
+/// Reads `Pkg.Revision` from the `source.properties` of the NDK
+fn ndk_revision(properties: &str) -> Option<String> {
+    properties.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        if key.trim() == "Pkg.Revision" {
+            Some(value.trim().to_owned())
+        } else {
+            None
+        }
+    })
+}
+
+/// Returns the commit checked out in `dir`, `None` outside of a git repository
+fn git_commit(dir: &Path) -> Option<String> {
+    let git = |args: &[&str]| ProcessBuilder::new("git").args(args).cwd(dir).output().ok();
+    let output = git(&["rev-parse", "HEAD"]).filter(|output| output.status.success())?;
+    let commit = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    let dirty = git(&["diff", "--quiet", "HEAD"]).map_or(false, |output| !output.status.success());
+    Some(if dirty {
+        format!("{}-dirty", commit)
+    } else {
+        commit
+    })
+}
+
+#[test]
+fn build_env_json() {
+    let env = BuildEnv {
+        version: 1,
+        cargo_quad_apk: "0.1.0".to_owned(),
+        rustc: Some("rustc 1.79.0 (129f3b996 2024-06-10)".to_owned()),
+        ndk: ndk_revision("Pkg.Desc = Android NDK\nPkg.Revision = 25.2.9519653\n"),
+        git_commit: Some("4f1c2e0d9b8a7c6e5f4d3c2b1a0f9e8d7c6b5a49-dirty".to_owned()),
+        profile: "debug".to_owned(),
+        features: vec!["audio".to_owned()],
+        no_default_features: true,
+        host_os: "linux".to_owned(),
+    };
+    let json = env.to_json();
+    assert_eq!(
+        json,
+        r#"{
+  "version": 1,
+  "cargo_quad_apk": "0.1.0",
+  "rustc": "rustc 1.79.0 (129f3b996 2024-06-10)",
+  "ndk": "25.2.9519653",
+  "git_commit": "4f1c2e0d9b8a7c6e5f4d3c2b1a0f9e8d7c6b5a49-dirty",
+  "profile": "debug",
+  "features": [
+    "audio"
+  ],
+  "no_default_features": true,
+  "host_os": "linux"
+}"#
+    );
+    assert_eq!(serde_json::from_str::<BuildEnv>(&json).unwrap(), env);
+    assert_eq!(env.fingerprint().len(), 16);
+    assert_eq!(env.fingerprint(), env.fingerprint());
+
+    assert_eq!(ndk_revision("Pkg.Desc = Android NDK\n"), None);
+}
+
+#[test]
+fn build_env_has_no_host_details() {
+    let mut config = crate::config::from_metadata("");
+    config.ndk_path = std::env::temp_dir();
+    let gctx = cargo::util::GlobalContext::default().unwrap();
+    let manifest = Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml");
+    let workspace = Workspace::new(&manifest, &gctx).unwrap();
+
+    let json = BuildEnv::collect(&workspace, &config).to_json();
+    for private in &[
+        dirs::home_dir().unwrap(),
+        std::env::temp_dir(),
+        Path::new(env!("CARGO_MANIFEST_DIR")).to_owned(),
+    ] {
+        assert!(!json.contains(private.to_str().unwrap()), "{}", json);
+    }
+    if let Ok(user) = std::env::var("USER") {
+        assert!(!json.contains(&format!("\"{}\"", user)), "{}", json);
+    }
+}
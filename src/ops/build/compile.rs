@@ -1,12 +1,16 @@
 use super::tempfile::TempFile;
-use super::util;
+use super::util::{self, EffectiveApiLevels};
 use crate::config::AndroidBuildTarget;
 use crate::config::AndroidConfig;
+use crate::config::AndroidTargetConfig;
+use crate::config::Strip;
+use crate::error::{FailureKind, ResultExt};
 use anyhow::format_err;
 use cargo::core::compiler::Executor;
-use cargo::core::compiler::{CompileKind, CompileMode, CompileTarget};
+use cargo::core::compiler::{CompileKind, CompileMode, CompileTarget, Unit};
 use cargo::core::manifest::TargetSourcePath;
 use cargo::core::{PackageId, Target, TargetKind, Workspace};
+use cargo::ops::{CompileFilter, FilterRule, LibRule};
 use cargo::util::command_prelude::{ArgMatchesExt, ProfileChecking};
 use cargo::util::CargoResult;
 use cargo_util::{paths::dylib_path, ProcessBuilder};
@@ -15,99 +19,390 @@ use multimap::MultiMap;
 use std::collections::{HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::fs::File;
 use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
+#[derive(Debug, Clone)]
 pub struct SharedLibrary {
     pub abi: AndroidBuildTarget,
     pub path: PathBuf,
     pub filename: String,
+    /// How the library was linked, `None` for the libraries it depends on
+    pub link: Option<LinkInfo>,
 }
 
+/// NDK files a library of a cargo target was linked with, for the build report
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkInfo {
+    /// Platform of the version specific libraries, lower than the platform of the clang
+    /// wrapper when the NDK has no libraries for it
+    pub platform: u32,
+    /// Directory of the version specific libraries
+    pub platform_dir: PathBuf,
+    /// Clang wrapper of the build target, the `CC` of the build scripts
+    pub clang: PathBuf,
+    pub linker: PathBuf,
+}
+
+impl LinkInfo {
+    fn new(
+        config: &AndroidConfig,
+        build_target: AndroidBuildTarget,
+        api_levels: &EffectiveApiLevels,
+    ) -> CargoResult<LinkInfo> {
+        let tool_root = util::llvm_toolchain_root(config);
+        let version_independent_libraries_path =
+            version_independent_libraries_path(&tool_root.join("sysroot"), build_target);
+        let platform = util::find_ndk_platform(api_levels.ndk_platform, |platform| {
+            version_independent_libraries_path.join(platform.to_string())
+        })?;
+        Ok(LinkInfo {
+            platform,
+            platform_dir: version_independent_libraries_path.join(platform.to_string()),
+            clang: util::find_clang(config, build_target, api_levels),
+            // NDK r23 renamed <ndk_llvm_triple>-ld to ld
+            linker: tool_root.join("bin").join("ld"),
+        })
+    }
+}
+
+/// Returns the directory of the libraries of the sysroot shared by every platform
+fn version_independent_libraries_path(sysroot: &Path, build_target: AndroidBuildTarget) -> PathBuf {
+    sysroot
+        .join("usr")
+        .join("lib")
+        .join(build_target.ndk_triple())
+}
+
+#[derive(Clone)]
 pub struct SharedLibraries {
     pub shared_libraries: MultiMap<Target, SharedLibrary>,
 }
 
+/// Files and tools of the build of one build target
+struct AbiBuild {
+    build_target: AndroidBuildTarget,
+    api_levels: EffectiveApiLevels,
+    /// Directory that contains the files specific to this build target
+    build_target_dir: PathBuf,
+    /// Environment variables exposing the C and C++ tools of the NDK to build scripts
+    tools_env: Vec<(&'static str, OsString)>,
+}
+
+/// Returns the name of the variable `name` for `build_target` alone, like
+/// `CC_aarch64_linux_android`, which the cc and cmake crates look up before `name`
+fn target_env_name(name: &str, build_target: AndroidBuildTarget) -> String {
+    format!("{}_{}", name, build_target.rust_triple().replace('-', "_"))
+}
+
+/// Environment of the build scripts, which cargo runs with its own environment rather than
+/// through the executor. The variables are set for the duration of a build only, their previous
+/// values are restored on drop.
+#[derive(Default)]
+struct BuildScriptEnv {
+    previous: Vec<(String, Option<OsString>)>,
+}
+
+impl BuildScriptEnv {
+    fn set(&mut self, name: &str, value: impl AsRef<OsStr>) {
+        self.remember(name);
+        std::env::set_var(name, value);
+    }
+
+    fn remove(&mut self, name: &str) {
+        self.remember(name);
+        std::env::remove_var(name);
+    }
+
+    fn remember(&mut self, name: &str) {
+        if !self.previous.iter().any(|(previous, _)| previous == name) {
+            self.previous
+                .push((name.to_owned(), std::env::var_os(name)));
+        }
+    }
+}
+
+impl Drop for BuildScriptEnv {
+    fn drop(&mut self) {
+        for (name, value) in self.previous.drain(..).rev() {
+            match value {
+                Some(value) => std::env::set_var(name, value),
+                None => std::env::remove_var(name),
+            }
+        }
+    }
+}
+
+#[test]
+fn build_script_env_restored() {
+    let set = "CARGO_QUAD_APK_TEST_SET";
+    let removed = "CARGO_QUAD_APK_TEST_REMOVED";
+    std::env::remove_var(set);
+    std::env::set_var(removed, "host");
+    {
+        let mut env = BuildScriptEnv::default();
+        env.set(set, "arm64");
+        env.set(set, "x86_64");
+        env.remove(removed);
+        assert_eq!(std::env::var_os(set), Some("x86_64".into()));
+        assert_eq!(std::env::var_os(removed), None);
+    }
+    assert_eq!(std::env::var_os(set), None);
+    assert_eq!(std::env::var_os(removed), Some("host".into()));
+    std::env::remove_var(removed);
+}
+
 /// For each build target and cargo binary or example target, produce a shared library
+///
+/// Every build target is built by a single cargo invocation, whose jobs are spread over the
+/// build targets. Build scripts find the tools of their target through variables named after it,
+/// see `target_env_name`. `--sequential-abis` builds the targets one after the other instead,
+/// exposing the tools under their plain names too.
 pub fn build_shared_libraries(
     workspace: &Workspace,
     config: &AndroidConfig,
     options: &ArgMatches,
     root_build_dir: &PathBuf,
-    miniquad_root_path: &PathBuf,
+    miniquad_root_path: Option<&PathBuf>,
+    api_levels: &[(AndroidBuildTarget, EffectiveApiLevels)],
 ) -> CargoResult<SharedLibraries> {
     let shared_libraries: Arc<Mutex<MultiMap<Target, SharedLibrary>>> =
         Arc::new(Mutex::new(MultiMap::new()));
-    for &build_target in config.build_targets.iter() {
-        // Directory that will contain files specific to this build target
+    let mut build_script_env = BuildScriptEnv::default();
+    let mut abi_builds = vec![];
+    for &(build_target, api_levels) in api_levels.iter() {
         let build_target_dir = root_build_dir.join(build_target.android_abi());
         fs::create_dir_all(&build_target_dir).unwrap();
 
-        // Set environment variables needed for use with the cc crate
-        std::env::set_var("CC", util::find_clang(config, build_target)?);
-        std::env::set_var("CXX", util::find_clang_cpp(config, build_target)?);
-        std::env::set_var("AR", util::find_ar(config, build_target)?);
-
-        // Use libc++. It is current default C++ runtime
-        std::env::set_var("CXXSTDLIB", "c++");
-
-        // Generate cmake toolchain and set environment variables to allow projects which use the cmake crate to build correctly
-        let cmake_toolchain_path = write_cmake_toolchain(config, &build_target_dir, build_target)?;
-        std::env::set_var("CMAKE_TOOLCHAIN_FILE", cmake_toolchain_path);
-        std::env::set_var("CMAKE_GENERATOR", r#"Unix Makefiles"#);
-        std::env::set_var("CMAKE_MAKE_PROGRAM", util::make_path(config));
-
-        // Configure compilation options so that we will build the desired build_target
+        // Generate cmake toolchain to allow projects which use the cmake crate to build correctly
+        let cmake_toolchain_path =
+            write_cmake_toolchain(config, &build_target_dir, build_target, &api_levels)?;
+        let tools_env = vec![
+            // Used by the cc crate
+            (
+                "CC",
+                util::find_clang(config, build_target, &api_levels).into(),
+            ),
+            (
+                "CXX",
+                util::find_clang_cpp(config, build_target, &api_levels)?.into(),
+            ),
+            ("AR", util::find_ar(config, build_target)?.into()),
+            // Use libc++. It is current default C++ runtime
+            ("CXXSTDLIB", "c++".into()),
+            ("CMAKE_TOOLCHAIN_FILE", cmake_toolchain_path.into()),
+        ];
+        for (name, value) in &tools_env {
+            // Variables set by the user for the target take precedence, as they did over the
+            // plain names
+            let name = target_env_name(name, build_target);
+            if std::env::var_os(&name).is_none() {
+                build_script_env.set(&name, value);
+            }
+        }
+        abi_builds.push(AbiBuild {
+            build_target,
+            api_levels,
+            build_target_dir,
+            tools_env,
+        });
+    }
+    build_script_env.set("CMAKE_GENERATOR", r#"Unix Makefiles"#);
+    build_script_env.set("CMAKE_MAKE_PROGRAM", util::make_path(config));
+
+    // Create executor
+    let abi_builds = Arc::new(abi_builds);
+    let executor: Arc<dyn Executor> = Arc::new(SharedLibraryExecutor {
+        config: Arc::new(config.clone()),
+        abi_builds: Arc::clone(&abi_builds),
+        shared_libraries: shared_libraries.clone(),
+        miniquad_root_path: miniquad_root_path.cloned(),
+        strip: strip_mode(config, options)?,
+    });
+
+    // Compile all targets for the requested build targets
+    let compile = |build_targets: &[AndroidBuildTarget]| -> CargoResult<()> {
         let mut opts = options.compile_options(
             workspace.gctx(),
             CompileMode::Build,
             Some(&workspace),
             ProfileChecking::Custom,
         )?;
-        opts.build_config.requested_kinds = vec![CompileKind::Target(CompileTarget::new(
-            build_target.rust_triple(),
-        )?)];
-
-        // Create executor
-        let config = Arc::new(config.clone());
-        let nostrip = options.get_flag("nostrip");
-        let executor: Arc<dyn Executor> = Arc::new(SharedLibraryExecutor {
-            config: Arc::clone(&config),
-            build_target_dir: build_target_dir.clone(),
-            build_target,
-            shared_libraries: shared_libraries.clone(),
-            miniquad_root_path: miniquad_root_path.clone(),
-            nostrip,
-        });
-
-        // Compile all targets for the requested build target
-        cargo::ops::compile_with_exec(workspace, &opts, &executor)?;
+        opts.cli_features = config.flavor_features(&opts.cli_features)?;
+        opts.build_config.requested_kinds = build_targets
+            .iter()
+            .map(|build_target| {
+                Ok(CompileKind::Target(CompileTarget::new(
+                    build_target.rust_triple(),
+                )?))
+            })
+            .collect::<CargoResult<_>>()?;
+        // `run --examples-sequence` runs every example
+        if let Ok(Some(true)) = options.try_get_one::<bool>("examples-sequence") {
+            opts.filter = CompileFilter::new(
+                LibRule::False,
+                FilterRule::none(),
+                FilterRule::none(),
+                FilterRule::All,
+                FilterRule::none(),
+            );
+        }
+        cargo::ops::compile_with_exec(workspace, &opts, &executor)
+            .failure_kind(FailureKind::Compilation)?;
+        Ok(())
+    };
+    if options.get_flag("sequential-abis") {
+        for abi_build in abi_builds.iter() {
+            // For the build scripts which only read the plain names
+            for (name, value) in &abi_build.tools_env {
+                build_script_env.set(name, value);
+            }
+            compile(&[abi_build.build_target])?;
+        }
+    } else {
+        // The plain names of the user, like a `CC` for the host, would otherwise reach the build
+        // scripts which only read those, with tools for another target than the one they build
+        if let Some(abi_build) = abi_builds.first() {
+            for (name, _) in &abi_build.tools_env {
+                build_script_env.remove(name);
+            }
+        }
+        let build_targets = abi_builds
+            .iter()
+            .map(|abi_build| abi_build.build_target)
+            .collect::<Vec<_>>();
+        compile(&build_targets)?;
     }
 
     // Remove the set of targets from the reference counted mutex
     let mut shared_libraries = shared_libraries.lock().unwrap();
-    let shared_libraries = std::mem::replace(&mut *shared_libraries, MultiMap::new());
+    let mut shared_libraries = std::mem::replace(&mut *shared_libraries, MultiMap::new());
+
+    // The build targets complete in any order, the libraries are listed in the order of
+    // `build_targets` so that the APKs are the same from one build to the next
+    let abi_position = |abi: AndroidBuildTarget| {
+        abi_builds
+            .iter()
+            .position(|abi_build| abi_build.build_target == abi)
+    };
+    for (_, libraries) in shared_libraries.iter_all_mut() {
+        libraries.sort_by(|a, b| {
+            (abi_position(a.abi), &a.filename).cmp(&(abi_position(b.abi), &b.filename))
+        });
+    }
 
     Ok(SharedLibraries { shared_libraries })
 }
 
+/// Returns the value of the `--target` argument of a rustc invocation
+fn target_arg(args: &[OsString]) -> Option<&OsStr> {
+    args.iter()
+        .position(|arg| arg == "--target")
+        .and_then(|position| args.get(position + 1))
+        .map(OsString::as_os_str)
+}
+
+#[test]
+fn rustc_target_arg() {
+    let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+    assert_eq!(
+        target_arg(&args(&[
+            "--crate-name",
+            "app",
+            "--target",
+            "aarch64-linux-android",
+            "-C",
+            "opt-level=3",
+        ])),
+        Some(OsStr::new("aarch64-linux-android"))
+    );
+    assert_eq!(
+        target_arg(&args(&["--crate-name", "build_script_build"])),
+        None
+    );
+    assert_eq!(
+        target_env_name("CC", AndroidBuildTarget::ArmV7a),
+        "CC_armv7_linux_androideabi"
+    );
+    assert_eq!(
+        target_env_name("CMAKE_TOOLCHAIN_FILE", AndroidBuildTarget::X86_64),
+        "CMAKE_TOOLCHAIN_FILE_x86_64_linux_android"
+    );
+}
+
+/// Returns what is stripped from the libraries: `--strip` or `--nostrip`, then the `strip` key,
+/// then everything for release builds and nothing for debug builds
+fn strip_mode(config: &AndroidConfig, options: &ArgMatches) -> CargoResult<Strip> {
+    if options.get_flag("nostrip") {
+        return Ok(Strip::None);
+    }
+    if let Some(name) = options.get_one::<String>("strip") {
+        return Strip::from_name(name);
+    }
+    Ok(config.strip.unwrap_or(if config.release {
+        Strip::All
+    } else {
+        Strip::None
+    }))
+}
+
 /// Executor which builds binary and example targets as static libraries
 struct SharedLibraryExecutor {
     config: Arc<AndroidConfig>,
-    build_target_dir: PathBuf,
-    build_target: AndroidBuildTarget,
+    /// Build targets of the invocation, whose units run concurrently
+    abi_builds: Arc<Vec<AbiBuild>>,
 
-    miniquad_root_path: PathBuf,
-    nostrip: bool,
+    /// Root of the miniquad package whose glue is injected, `None` with `framework = "none"`
+    miniquad_root_path: Option<PathBuf>,
+    /// What the post-link strip step removes from the libraries of the targets
+    strip: Strip,
 
     // Shared libraries built by the executor are added to this multimap
     shared_libraries: Arc<Mutex<MultiMap<Target, SharedLibrary>>>,
 }
 
+impl SharedLibraryExecutor {
+    /// Returns whether a unit is a bin or example target built into a shared library
+    fn builds_library(target: &Target, mode: CompileMode) -> bool {
+        mode == CompileMode::Build
+            && (target.kind() == &TargetKind::Bin || target.kind() == &TargetKind::ExampleBin)
+    }
+}
+
 impl Executor for SharedLibraryExecutor {
+    /// cargo doesn't know about the `APK_CFGS` values given to rustc, so the libraries are
+    /// rebuilt when they differ from the values of their last build
+    fn force_rebuild(&self, unit: &Unit) -> bool {
+        if !Self::builds_library(&unit.target, unit.mode) {
+            return false;
+        }
+        let abi_build = match unit.kind {
+            CompileKind::Target(compile_target) => self.abi_builds.iter().find(|abi_build| {
+                compile_target.rustc_target() == abi_build.build_target.rust_triple()
+            }),
+            CompileKind::Host => None,
+        };
+        let abi_build = match abi_build {
+            Some(abi_build) => abi_build,
+            None => return false,
+        };
+        let target_config = match self
+            .config
+            .resolve((unit.target.kind().to_owned(), unit.target.name().to_owned()))
+        {
+            Ok(target_config) => target_config,
+            // Left to the build to report
+            Err(_) => return true,
+        };
+        let values = apk_cfg_values(&target_config, abi_build.build_target, self.config.release);
+        apk_cfgs_outdated(
+            &apk_cfgs_stamp(&abi_build.build_target_dir, &unit.target),
+            &values,
+        )
+    }
+
     fn exec(
         &self,
         cmd: &ProcessBuilder,
@@ -117,10 +412,21 @@ impl Executor for SharedLibraryExecutor {
         on_stdout_line: &mut dyn FnMut(&str) -> CargoResult<()>,
         on_stderr_line: &mut dyn FnMut(&str) -> CargoResult<()>,
     ) -> CargoResult<()> {
-        if mode == CompileMode::Build
-            && (target.kind() == &TargetKind::Bin || target.kind() == &TargetKind::ExampleBin)
-        {
+        if Self::builds_library(target, mode) {
             let mut new_args = cmd.get_args().cloned().collect::<Vec<_>>();
+            let abi_build = target_arg(&new_args)
+                .and_then(|triple| {
+                    self.abi_builds
+                        .iter()
+                        .find(|abi_build| triple == abi_build.build_target.rust_triple())
+                })
+                .ok_or_else(|| {
+                    format_err!(
+                        "Unable to determine the build target of target '{}'",
+                        target.name()
+                    )
+                })?;
+            let build_target = abi_build.build_target;
 
             let target_config = self
                 .config
@@ -136,89 +442,92 @@ impl Executor for SharedLibraryExecutor {
                 return Ok(());
             };
 
-            let original_src_filepath = path.canonicalize()?;
-
-            //
-            // Generate source file that will be built
-            //
-            // Determine the name of the temporary file
-            let tmp_lib_filepath = original_src_filepath.parent().unwrap().join(format!(
-                "__cargo_apk_{}.tmp",
-                original_src_filepath
-                    .file_stem()
-                    .map(|s| s.to_string_lossy().into_owned())
-                    .unwrap_or_else(String::new)
-            ));
-
-            // Create the temporary file
-            let original_contents = fs::read_to_string(original_src_filepath).unwrap();
-
-            let extra_code = format!(
-                "mod cargo_apk_glue_code {{ {} }}",
-                fs::read_to_string(
-                    self.miniquad_root_path
-                        .join("src")
-                        .join("native")
-                        .join("android")
-                        .join("mod_inject.rs"),
-                )
-                .unwrap()
-            );
-
-            // check "Resolving Native Method Names"
-            // https://docs.oracle.com/javase/1.5.0/docs/guide/jni/spec/design.html
-            let package_name = target_config
-                .package_name
-                .replace("_", "_1")
-                .replace("-", "_1")
-                .replace(".", "_");
-
-            let extra_code =
-                extra_code.replace("JAVA_CLASS_PATH", &format!("Java_{}", package_name));
-
-            let tmp_file = TempFile::new(tmp_lib_filepath.clone(), |lib_src_file| {
-                writeln!( lib_src_file, "{}\n{}", original_contents, extra_code)?;
-
-                Ok(())
-            }).map_err(|e| format_err!(
-                "Unable to create temporary source file `{}`. Source directory must be writable. Cargo-apk creates temporary source files as part of the build process. {}.", tmp_lib_filepath.to_string_lossy(), e)
-            )?;
-
-            //
-            // Replace source argument
-            //
-            let filename = path.file_name().unwrap().to_owned();
-            let source_arg = new_args.iter_mut().find_map(|arg| {
-                let path_arg = Path::new(&arg);
-                let tmp = path_arg.file_name().unwrap();
+            // The glue of miniquad is built from a copy of the source with the glue appended,
+            // NativeActivity apps export `ANativeActivity_onCreate` themselves
+            let _glue_source = if let Some(miniquad_root_path) = &self.miniquad_root_path {
+                let original_src_filepath = path.canonicalize()?;
+
+                //
+                // Generate source file that will be built
+                //
+                // Determine the name of the temporary file, one per build target as they are
+                // built concurrently
+                let tmp_lib_filepath = original_src_filepath.parent().unwrap().join(format!(
+                    "__cargo_apk_{}_{}.tmp",
+                    original_src_filepath
+                        .file_stem()
+                        .map(|s| s.to_string_lossy().into_owned())
+                        .unwrap_or_else(String::new),
+                    build_target.android_abi()
+                ));
 
-                if filename == tmp {
-                    Some(arg)
+                // Create the temporary file
+                let original_contents = fs::read_to_string(original_src_filepath).unwrap();
+
+                let extra_code = format!(
+                    "mod cargo_apk_glue_code {{ {} }}",
+                    fs::read_to_string(
+                        miniquad_root_path
+                            .join("src")
+                            .join("native")
+                            .join("android")
+                            .join("mod_inject.rs"),
+                    )
+                    .unwrap()
+                );
+
+                let extra_code = extra_code.replace(
+                    "JAVA_CLASS_PATH",
+                    &jni_prefix(&target_config.java_package()),
+                );
+
+                let tmp_file = TempFile::new(tmp_lib_filepath.clone(), |lib_src_file| {
+                    writeln!( lib_src_file, "{}\n{}", original_contents, extra_code)?;
+
+                    Ok(())
+                }).map_err(|e| format_err!(
+                    "Unable to create temporary source file `{}`. Source directory must be writable. Cargo-apk creates temporary source files as part of the build process. {}.", tmp_lib_filepath.to_string_lossy(), e)
+                )?;
+
+                //
+                // Replace source argument
+                //
+                let filename = path.file_name().unwrap().to_owned();
+                let source_arg = new_args.iter_mut().find_map(|arg| {
+                    let path_arg = Path::new(&arg);
+                    let tmp = path_arg.file_name().unwrap();
+
+                    if filename == tmp {
+                        Some(arg)
+                    } else {
+                        None
+                    }
+                });
+
+                if let Some(source_arg) = source_arg {
+                    // Build a new relative path to the temporary source file and use it as the source argument
+                    // Using an absolute path causes compatibility issues in some cases under windows
+                    // If a UNC path is used then relative paths used in "include* macros" may not work if
+                    // the relative path includes "/" instead of "\"
+                    let path_arg = Path::new(&source_arg);
+                    let mut path_arg = path_arg.to_path_buf();
+                    path_arg.set_file_name(tmp_file.path.file_name().unwrap());
+                    *source_arg = path_arg.into_os_string();
                 } else {
-                    None
+                    return Err(format_err!(
+                        "Unable to replace source argument when building target '{}'",
+                        target.name()
+                    ));
                 }
-            });
-
-            if let Some(source_arg) = source_arg {
-                // Build a new relative path to the temporary source file and use it as the source argument
-                // Using an absolute path causes compatibility issues in some cases under windows
-                // If a UNC path is used then relative paths used in "include* macros" may not work if
-                // the relative path includes "/" instead of "\"
-                let path_arg = Path::new(&source_arg);
-                let mut path_arg = path_arg.to_path_buf();
-                path_arg.set_file_name(tmp_file.path.file_name().unwrap());
-                *source_arg = path_arg.into_os_string();
+                Some(tmp_file)
             } else {
-                return Err(format_err!(
-                    "Unable to replace source argument when building target '{}'",
-                    target.name()
-                ));
-            }
+                None
+            };
 
             //
             // Create output directory inside the build target directory
             //
-            let build_path = self.build_target_dir.join("build");
+            let build_path = abi_build.build_target_dir.join("build");
             fs::create_dir_all(&build_path).unwrap();
 
             //
@@ -245,24 +554,15 @@ impl Executor for SharedLibraryExecutor {
             }
 
             // Determine paths
-            let tool_root = util::llvm_toolchain_root(&self.config);
-
-            // NDK r23 renamed <ndk_llvm_triple>-ld to ld
-            let linker_path = tool_root.join("bin").join("ld");
-
-            let sysroot = tool_root.join("sysroot");
-            let version_independent_libraries_path = sysroot
-                .join("usr")
-                .join("lib")
-                .join(&self.build_target.ndk_triple());
-            let version_specific_libraries_path =
-                util::find_ndk_path(self.config.min_sdk_version, |platform| {
-                    version_independent_libraries_path.join(platform.to_string())
-                })?;
+            let link = LinkInfo::new(&self.config, build_target, &abi_build.api_levels)?;
+            let sysroot = util::llvm_toolchain_root(&self.config).join("sysroot");
+            let version_independent_libraries_path =
+                version_independent_libraries_path(&sysroot, build_target);
+            let version_specific_libraries_path = link.platform_dir.clone();
 
             // Add linker arguments
             // Specify linker
-            new_args.push(build_arg("-Clinker=", linker_path));
+            new_args.push(build_arg("-Clinker=", &link.linker));
 
             // Set linker flavor
             new_args.push("-Clinker-flavor=ld".into());
@@ -286,49 +586,87 @@ impl Executor for SharedLibraryExecutor {
             let libgcc_dir = build_path.join("_libgcc_");
             fs::create_dir_all(&libgcc_dir)?;
             let libgcc = libgcc_dir.join("libgcc.a");
-            std::fs::write(&libgcc, "INPUT(-lunwind)")?;
+            util::write_if_changed(&libgcc, "INPUT(-lunwind)")?;
             new_args.push(build_arg("-Clink-arg=-L", libgcc_dir));
-            let libunwind_dir = util::find_libunwind_dir(&self.config, self.build_target)?;
+            let libunwind_dir = util::find_libunwind_dir(&self.config, build_target)?;
             new_args.push(build_arg("-Clink-arg=-L", libunwind_dir));
 
-            // Strip symbols for release builds
-            if self.nostrip == false {
-                if self.config.release {
-                    new_args.push("-Clink-arg=-strip-all".into());
-                }
-            }
+            // Identify the library by a build id, kept by the strip step below
+            new_args.push("-Clink-arg=--build-id=sha1".into());
 
             // Require position independent code
             new_args.push("-Crelocation-model=pic".into());
 
+            // Describe the APK to the code, see `APK_CFGS`
+            let apk_cfgs = apk_cfg_values(&target_config, build_target, self.config.release);
+            let check_cfg = new_args.iter().any(|arg| arg == "--check-cfg");
+            new_args.extend(apk_cfg_args(&apk_cfgs, check_cfg));
+
             // Create new command
             let mut cmd = cmd.clone();
             cmd.args_replace(&new_args);
+            for (name, value) in &apk_cfgs {
+                cmd.env(name, value);
+            }
 
             //
             // Execute the command
             //
             cmd.exec_with_streaming(on_stdout_line, on_stderr_line, false)
                 .map(drop)?;
+            record_apk_cfgs(
+                &apk_cfgs_stamp(&abi_build.build_target_dir, target),
+                &apk_cfgs,
+            )?;
 
             // Execute the command again with the print flag to determine the name of the produced shared library and then add it to the list of shared librares to be added to the APK
             let stdout = cmd.arg("--print").arg("file-names").exec_with_output()?;
             let stdout = String::from_utf8(stdout.stdout).unwrap();
             let library_path = build_path.join(stdout.lines().next().unwrap());
 
+            // Keep a copy of the library with every symbol for symbolication, which the next
+            // build of the target overwrites in the build directory
+            let symbols_dir =
+                super::symbol_directory(abi_build.build_target_dir.parent().unwrap(), build_target);
+            fs::create_dir_all(&symbols_dir)?;
+            fs::copy(
+                &library_path,
+                symbols_dir.join(format!("lib{}.so", target.name())),
+            )?;
+
+            // Strip a copy of the library into the APK
+            let apk_library_path = match self.strip.llvm_strip_flag() {
+                Some(flag) => {
+                    let stripped_dir = abi_build.build_target_dir.join("stripped");
+                    fs::create_dir_all(&stripped_dir)?;
+                    let stripped_path = stripped_dir.join(library_path.file_name().unwrap());
+                    ProcessBuilder::new(util::find_llvm_strip(&self.config)?)
+                        .arg(flag)
+                        .arg("--keep-section=.note.gnu.build-id")
+                        .arg("-o")
+                        .arg(&stripped_path)
+                        .arg(&library_path)
+                        .exec()
+                        .failure_kind(FailureKind::Compilation)?;
+                    stripped_path
+                }
+                None => library_path.clone(),
+            };
+
             let mut shared_libraries = self.shared_libraries.lock().unwrap();
             shared_libraries.insert(
                 target.clone(),
                 SharedLibrary {
-                    abi: self.build_target,
-                    path: library_path.clone(),
+                    abi: build_target,
+                    path: apk_library_path,
                     filename: format!("lib{}.so", target.name()),
+                    link: Some(link),
                 },
             );
 
             // If the target uses the C++ standard library, add the appropriate shared library
             // to the list of shared libraries to be added to the APK
-            let readelf_path = util::find_readelf(&self.config, self.build_target)?;
+            let readelf_path = util::find_readelf(&self.config, build_target)?;
 
             // Gets libraries search paths from compiler
             let mut libs_search_paths =
@@ -338,7 +676,7 @@ impl Executor for SharedLibraryExecutor {
             libs_search_paths.push(version_independent_libraries_path);
 
             // Add target/ARCH/PROFILE/deps directory for searching dylib/cdylib
-            libs_search_paths.push(self.build_target_dir.join("deps"));
+            libs_search_paths.push(abi_build.build_target_dir.join("deps"));
 
             // FIXME: Add extra libraries search paths (from "LD_LIBRARY_PATH")
             libs_search_paths.extend(dylib_path());
@@ -378,9 +716,10 @@ impl Executor for SharedLibraryExecutor {
                     shared_libraries.insert(
                         target.clone(),
                         SharedLibrary {
-                            abi: self.build_target,
+                            abi: build_target,
                             path,
                             filename: dylib.clone(),
+                            link: None,
                         },
                     );
                 } else {
@@ -421,6 +760,187 @@ impl Executor for SharedLibraryExecutor {
     }
 }
 
+/// Returns the prefix of the JNI symbols of the classes of a Java package, with `_` escaped as
+/// `_1` and `.` replaced by `_`, see "Resolving Native Method Names" in
+/// https://docs.oracle.com/javase/1.5.0/docs/guide/jni/spec/design.html
+fn jni_prefix(java_package: &str) -> String {
+    format!("Java_{}", java_package.replace("_", "_1").replace(".", "_"))
+}
+
+#[test]
+fn jni_symbol_prefix() {
+    assert_eq!(jni_prefix("rust.app"), "Java_rust_app");
+    assert_eq!(jni_prefix("rust.my_app"), "Java_rust_my_1app");
+    // Java packages of application ids with keywords, like `com.example.native`
+    assert_eq!(
+        jni_prefix("com.example.native_"),
+        "Java_com_example_native_1"
+    );
+}
+
+/// Values describing the APK which the code of the app can read at compile time, with `env!` and
+/// as a cfg of the same name in lowercase, like `#[cfg(cargo_apk_abi = "arm64-v8a")]`.
+/// `CARGO_APK_RELEASE` is the name-only `cargo_apk_release` cfg, only set for release builds.
+pub const APK_CFGS: &[(&str, &str)] = &[
+    (
+        "CARGO_APK_PACKAGE_NAME",
+        "package name of the APK, like `rust.app`",
+    ),
+    (
+        "CARGO_APK_VERSION_CODE",
+        "versionCode of the app, split APKs add the digit of their ABI to it",
+    ),
+    ("CARGO_APK_ABI", "ABI of the library, like `arm64-v8a`"),
+    (
+        "CARGO_APK_RELEASE",
+        "`true` for release builds and `false` otherwise",
+    ),
+];
+
+/// Returns the value of each variable of `APK_CFGS` for a target built for `build_target`
+fn apk_cfg_values(
+    target_config: &AndroidTargetConfig,
+    build_target: AndroidBuildTarget,
+    release: bool,
+) -> Vec<(&'static str, String)> {
+    let values = [
+        target_config.package_name.clone(),
+        target_config.version_code.to_string(),
+        build_target.android_abi().to_owned(),
+        release.to_string(),
+    ];
+    APK_CFGS.iter().map(|&(name, _)| name).zip(values).collect()
+}
+
+/// Returns the file recording the `APK_CFGS` values a target was last built with
+fn apk_cfgs_stamp(build_target_dir: &Path, target: &Target) -> PathBuf {
+    let kind = match target.kind() {
+        TargetKind::ExampleBin => "example",
+        _ => "bin",
+    };
+    build_target_dir
+        .join("apk-cfgs")
+        .join(format!("{}-{}", kind, target.name()))
+}
+
+fn render_apk_cfgs(values: &[(&str, String)]) -> String {
+    values
+        .iter()
+        .map(|(name, value)| format!("{}={}\n", name, value))
+        .collect()
+}
+
+/// Returns whether the values differ from those recorded in `stamp`, or none were recorded
+fn apk_cfgs_outdated(stamp: &Path, values: &[(&str, String)]) -> bool {
+    fs::read_to_string(stamp).ok() != Some(render_apk_cfgs(values))
+}
+
+/// Records the values a target was built with in `stamp`
+fn record_apk_cfgs(stamp: &Path, values: &[(&str, String)]) -> CargoResult<()> {
+    fs::create_dir_all(stamp.parent().unwrap())?;
+    util::write_if_changed(stamp, render_apk_cfgs(values))?;
+    Ok(())
+}
+
+/// Returns the `--cfg` arguments of rustc for the values of `APK_CFGS`. Their `--check-cfg`
+/// declarations are only added when cargo checks the cfgs, as a declaration alone would turn
+/// the checking on without the features of the package being declared.
+fn apk_cfg_args(values: &[(&str, String)], check_cfg: bool) -> Vec<OsString> {
+    let mut args = Vec::new();
+    for (name, value) in values {
+        let cfg = name.to_lowercase();
+        let (cfg_arg, check_cfg_arg) = if *name == "CARGO_APK_RELEASE" {
+            (
+                (value == "true").then(|| cfg.clone()),
+                format!("cfg({})", cfg),
+            )
+        } else {
+            (
+                Some(format!("{}={:?}", cfg, value)),
+                format!("cfg({}, values(any()))", cfg),
+            )
+        };
+        if let Some(cfg_arg) = cfg_arg {
+            args.push("--cfg".into());
+            args.push(cfg_arg.into());
+        }
+        if check_cfg {
+            args.push("--check-cfg".into());
+            args.push(check_cfg_arg.into());
+        }
+    }
+    args
+}
+
+#[test]
+fn apk_cfg_arguments() {
+    let config = crate::config::from_metadata("");
+    let target_config = config.resolve((TargetKind::Bin, "app".to_owned())).unwrap();
+    let values = apk_cfg_values(&target_config, AndroidBuildTarget::Arm64V8a, false);
+    assert_eq!(
+        values,
+        vec![
+            ("CARGO_APK_PACKAGE_NAME", "rust.app".to_owned()),
+            ("CARGO_APK_VERSION_CODE", "1".to_owned()),
+            ("CARGO_APK_ABI", "arm64-v8a".to_owned()),
+            ("CARGO_APK_RELEASE", "false".to_owned()),
+        ]
+    );
+    assert_eq!(
+        apk_cfg_args(&values, false),
+        vec![
+            "--cfg",
+            "cargo_apk_package_name=\"rust.app\"",
+            "--cfg",
+            "cargo_apk_version_code=\"1\"",
+            "--cfg",
+            "cargo_apk_abi=\"arm64-v8a\"",
+        ]
+    );
+
+    let values = apk_cfg_values(&target_config, AndroidBuildTarget::X86_64, true);
+    let args = apk_cfg_args(&values, true);
+    assert_eq!(
+        &args[args.len() - 6..],
+        &[
+            "--check-cfg",
+            "cfg(cargo_apk_abi, values(any()))",
+            "--cfg",
+            "cargo_apk_release",
+            "--check-cfg",
+            "cfg(cargo_apk_release)",
+        ][..]
+    );
+}
+
+#[test]
+fn apk_cfgs_rebuild() {
+    let dir = std::env::temp_dir().join(format!("cargo-quad-apk-cfgs-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let stamp = dir.join("apk-cfgs/bin-app");
+    let mut config = crate::config::from_metadata("");
+    let values = |config: &AndroidConfig| {
+        let target_config = config.resolve((TargetKind::Bin, "app".to_owned())).unwrap();
+        apk_cfg_values(&target_config, AndroidBuildTarget::Arm64V8a, config.release)
+    };
+
+    // Never built with them
+    assert!(apk_cfgs_outdated(&stamp, &values(&config)));
+    record_apk_cfgs(&stamp, &values(&config)).unwrap();
+    assert!(!apk_cfgs_outdated(&stamp, &values(&config)));
+
+    // A new versionCode rebuilds the library
+    config = crate::config::from_metadata("version_code = 2");
+    assert!(apk_cfgs_outdated(&stamp, &values(&config)));
+    record_apk_cfgs(&stamp, &values(&config)).unwrap();
+    assert!(!apk_cfgs_outdated(&stamp, &values(&config)));
+
+    config.release = true;
+    assert!(apk_cfgs_outdated(&stamp, &values(&config)));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
 /// List all linked shared libraries
 fn list_needed_dylibs(readelf_path: &Path, library_path: &Path) -> CargoResult<HashSet<String>> {
     let readelf_output = ProcessBuilder::new(readelf_path)
@@ -512,23 +1032,64 @@ fn write_cmake_toolchain(
     config: &AndroidConfig,
     build_target_dir: &PathBuf,
     build_target: AndroidBuildTarget,
+    api_levels: &EffectiveApiLevels,
 ) -> CargoResult<PathBuf> {
     let toolchain_path = build_target_dir.join("cargo-apk.toolchain.cmake");
-    let mut toolchain_file = File::create(&toolchain_path).unwrap();
-    writeln!(
-        toolchain_file,
-        r#"set(ANDROID_PLATFORM android-{min_sdk_version})
+    // Rewriting it would make cmake configure the crates using it again
+    let toolchain = format!(
+        r#"set(ANDROID_PLATFORM android-{ndk_platform})
 set(ANDROID_ABI {abi})
 string(REPLACE "--target={build_target}" "" CMAKE_C_FLAGS "${{CMAKE_C_FLAGS}}")
 string(REPLACE "--target={build_target}" "" CMAKE_CXX_FLAGS "${{CMAKE_CXX_FLAGS}}")
 unset(CMAKE_C_COMPILER CACHE)
 unset(CMAKE_CXX_COMPILER CACHE)
 include("{ndk_path}/build/cmake/android.toolchain.cmake")"#,
-        min_sdk_version = config.min_sdk_version,
+        ndk_platform = api_levels.ndk_platform,
         ndk_path = config.ndk_path.to_string_lossy().replace("\\", "/"), // Use forward slashes even on windows to avoid path escaping issues.
         build_target = build_target.rust_triple(),
         abi = build_target.android_abi(),
-    )?;
+    );
+    util::write_if_changed(&toolchain_path, format!("{}\n", toolchain))?;
 
     Ok(toolchain_path)
 }
+
+#[test]
+fn link_info_platform_fallback() {
+    let ndk = std::env::temp_dir().join(format!("cargo-quad-apk-link-{}", std::process::id()));
+    let mut config = crate::config::from_metadata("");
+    config.ndk_path = ndk.clone();
+    let sysroot = util::llvm_toolchain_root(&config).join("sysroot");
+    let libraries = version_independent_libraries_path(&sysroot, AndroidBuildTarget::ArmV7a);
+    for platform in &["21", "24", "28"] {
+        fs::create_dir_all(libraries.join(platform)).unwrap();
+    }
+    let api_levels = EffectiveApiLevels {
+        requested_min: 26,
+        ndk_platform: 26,
+        dex_min_api: 26,
+    };
+
+    // The NDK has no libraries of platform 26 for 32-bit ARM
+    let link = LinkInfo::new(&config, AndroidBuildTarget::ArmV7a, &api_levels).unwrap();
+    assert_eq!(link.platform, 24);
+    assert_eq!(link.platform_dir, libraries.join("24"));
+    assert!(link
+        .clang
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .starts_with("armv7a-linux-androideabi26-clang"));
+    assert!(link.linker.ends_with("bin/ld"));
+
+    let report = super::report::ReportLink::new(&TargetKind::Bin, "app", "armeabi-v7a", &link);
+    let json = serde_json::to_value(&report).unwrap();
+    assert_eq!(json["platform"], 24);
+    assert_eq!(json["abi"], "armeabi-v7a");
+    assert_eq!(
+        json["platform_dir"].as_str().map(PathBuf::from),
+        Some(libraries.join("24"))
+    );
+
+    fs::remove_dir_all(&ndk).unwrap();
+}
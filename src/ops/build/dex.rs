@@ -0,0 +1,134 @@
+//! Statistics of the dex files of an APK, read from their header.
+//!
+//! A dex file can reference at most 65536 methods and 65536 fields. Past that, d8 either fails or
+//! spreads the classes over several dex files (multidex), which the packaging doesn't support
+//! yet, so the counts are reported after each build and a warning is printed when they get close.
+
+use anyhow::format_err;
+use cargo::util::CargoResult;
+use std::fs;
+use std::path::Path;
+
+/// Number of method or field references a dex file can hold
+pub const REFERENCE_LIMIT: u32 = 65536;
+
+/// Number of references from which the build warns about the limit
+const WARNING_THRESHOLD: u32 = 60000;
+
+const HEADER_SIZE: usize = 0x70;
+const ENDIAN_CONSTANT: u32 = 0x1234_5678;
+const FIELD_IDS_SIZE_OFFSET: usize = 0x50;
+const METHOD_IDS_SIZE_OFFSET: usize = 0x58;
+const CLASS_DEFS_SIZE_OFFSET: usize = 0x60;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DexCounts {
+    /// Methods referenced by the dex, defined in it or called from it
+    pub methods: u32,
+    /// Fields referenced by the dex
+    pub fields: u32,
+    /// Classes defined in the dex
+    pub classes: u32,
+}
+
+/// Reads the counts of the dex file at `path`
+pub fn read_counts(path: &Path) -> CargoResult<DexCounts> {
+    let bytes = fs::read(path)
+        .map_err(|err| format_err!("Unable to read `{}`: {}", path.display(), err))?;
+    parse_header(&bytes)
+        .map_err(|err| format_err!("Invalid dex file `{}`: {}", path.display(), err))
+}
+
+/// Parses the counts out of the header of a dex file
+fn parse_header(bytes: &[u8]) -> CargoResult<DexCounts> {
+    if bytes.len() < HEADER_SIZE {
+        return Err(format_err!(
+            "{} bytes is too short for the header",
+            bytes.len()
+        ));
+    }
+    // `dex\n`, a version of 3 digits, and a NUL
+    let magic = &bytes[..8];
+    if &magic[..4] != b"dex\n" || !magic[4..7].iter().all(u8::is_ascii_digit) || magic[7] != 0 {
+        return Err(format_err!(
+            "bad magic {:?}",
+            String::from_utf8_lossy(magic)
+        ));
+    }
+    let u32_at = |offset: usize| {
+        u32::from_le_bytes([
+            bytes[offset],
+            bytes[offset + 1],
+            bytes[offset + 2],
+            bytes[offset + 3],
+        ])
+    };
+    if u32_at(0x28) != ENDIAN_CONSTANT {
+        return Err(format_err!("unsupported byte order"));
+    }
+    Ok(DexCounts {
+        methods: u32_at(METHOD_IDS_SIZE_OFFSET),
+        fields: u32_at(FIELD_IDS_SIZE_OFFSET),
+        classes: u32_at(CLASS_DEFS_SIZE_OFFSET),
+    })
+}
+
+/// Returns the warning for a dex of `target_name` whose methods or fields get close to
+/// `REFERENCE_LIMIT`
+pub fn limit_warning(dex_name: &str, target_name: &str, counts: &DexCounts) -> Option<String> {
+    let (count, what) = if counts.methods >= counts.fields {
+        (counts.methods, "methods")
+    } else {
+        (counts.fields, "fields")
+    };
+    if count < WARNING_THRESHOLD {
+        return None;
+    }
+    Some(format!(
+        "{} of target '{}' references {} {}, close to the {} a dex file can reference. \
+         Multidex engages past that limit, and isn't supported yet.",
+        dex_name, target_name, count, what, REFERENCE_LIMIT
+    ))
+}
+
+#[test]
+fn dex_header() {
+    // One class, `A`, with the field `A.value` and references to the constructors of `A` and
+    // `Object`
+    let fixture = include_bytes!("../../../tests/fixtures/classes.dex");
+    assert_eq!(
+        parse_header(fixture).unwrap(),
+        DexCounts {
+            methods: 2,
+            fields: 1,
+            classes: 1,
+        }
+    );
+
+    assert!(parse_header(&fixture[..0x40]).is_err());
+    let mut not_dex = fixture.to_vec();
+    not_dex[..4].copy_from_slice(b"dey\n");
+    assert!(parse_header(&not_dex).is_err());
+    let mut big_endian = fixture.to_vec();
+    big_endian[0x28..0x2c].copy_from_slice(&ENDIAN_CONSTANT.to_be_bytes());
+    assert!(parse_header(&big_endian).is_err());
+}
+
+#[test]
+fn dex_limit_warning() {
+    let counts = |methods, fields| DexCounts {
+        methods,
+        fields,
+        classes: 100,
+    };
+    assert_eq!(
+        limit_warning("classes.dex", "app", &counts(59999, 20000)),
+        None
+    );
+    assert!(limit_warning("classes.dex", "app", &counts(60000, 20000))
+        .unwrap()
+        .starts_with("classes.dex of target 'app' references 60000 methods, close to the 65536"));
+    assert!(limit_warning("classes.dex", "app", &counts(100, 64000))
+        .unwrap()
+        .contains("references 64000 fields"));
+}
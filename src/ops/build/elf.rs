@@ -0,0 +1,241 @@
+//! Sections and build id of the shared libraries, read from their ELF section headers.
+//!
+//! The libraries are linked with a build id, which survives stripping, so that a stripped library
+//! of an APK can be matched with the unstripped copy in the target directory by symbol servers.
+
+use anyhow::format_err;
+use cargo::util::CargoResult;
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+
+const SHT_NOTE: u32 = 7;
+const NT_GNU_BUILD_ID: u32 = 3;
+
+/// Section of an ELF file
+#[derive(Debug)]
+pub struct Section {
+    pub name: String,
+    pub kind: u32,
+    offset: usize,
+    size: usize,
+}
+
+/// Little endian ELF file, the byte order of every Android ABI
+pub struct Elf {
+    bytes: Vec<u8>,
+    pub sections: Vec<Section>,
+}
+
+impl Elf {
+    pub fn read(path: &Path) -> CargoResult<Elf> {
+        let bytes = fs::read(path)
+            .map_err(|err| format_err!("Unable to read `{}`: {}", path.display(), err))?;
+        Elf::parse(bytes)
+            .map_err(|err| format_err!("Invalid ELF file `{}`: {}", path.display(), err))
+    }
+
+    fn parse(bytes: Vec<u8>) -> CargoResult<Elf> {
+        if bytes.len() < 0x34 || &bytes[..4] != b"\x7fELF" {
+            return Err(format_err!("bad magic"));
+        }
+        if bytes[5] != 1 {
+            return Err(format_err!("unsupported byte order"));
+        }
+        let is_64 = match bytes[4] {
+            1 => false,
+            2 => true,
+            class => return Err(format_err!("unknown class {}", class)),
+        };
+        let reader = Reader { bytes: &bytes };
+        let (section_offset, entry_size, count, names_index) = if is_64 {
+            (
+                reader.u64(0x28)?,
+                reader.u16(0x3a)?,
+                reader.u16(0x3c)?,
+                reader.u16(0x3e)?,
+            )
+        } else {
+            (
+                reader.u32(0x20)? as u64,
+                reader.u16(0x2e)?,
+                reader.u16(0x30)?,
+                reader.u16(0x32)?,
+            )
+        };
+
+        // Name offset, type, offset and size of each section
+        let mut headers = vec![];
+        for index in 0..count as usize {
+            let header = section_offset as usize + index * entry_size as usize;
+            let (offset, size) = if is_64 {
+                (reader.u64(header + 0x18)?, reader.u64(header + 0x20)?)
+            } else {
+                (
+                    reader.u32(header + 0x10)? as u64,
+                    reader.u32(header + 0x14)? as u64,
+                )
+            };
+            headers.push((
+                reader.u32(header)? as usize,
+                reader.u32(header + 4)?,
+                offset as usize,
+                size as usize,
+            ));
+        }
+        let names_offset = headers
+            .get(names_index as usize)
+            .map(|&(_, _, offset, _)| offset)
+            .ok_or_else(|| format_err!("no section names"))?;
+        let sections = headers
+            .into_iter()
+            .map(|(name, kind, offset, size)| {
+                Ok(Section {
+                    name: reader.c_string(names_offset + name)?,
+                    kind,
+                    offset,
+                    size,
+                })
+            })
+            .collect::<CargoResult<_>>()?;
+        Ok(Elf { bytes, sections })
+    }
+
+    pub fn has_section(&self, name: &str) -> bool {
+        self.sections.iter().any(|section| section.name == name)
+    }
+
+    /// Returns the contents of the section named `name`, if the file has it
+    pub fn section_bytes(&self, name: &str) -> Option<&[u8]> {
+        let section = self.sections.iter().find(|section| section.name == name)?;
+        self.bytes
+            .get(section.offset..section.offset.checked_add(section.size)?)
+    }
+
+    /// Returns the GNU build id, in hexadecimal, if the file has one
+    pub fn build_id(&self) -> Option<String> {
+        self.sections
+            .iter()
+            .filter(|section| section.kind == SHT_NOTE)
+            .find_map(|section| {
+                let notes = self
+                    .bytes
+                    .get(section.offset..section.offset.checked_add(section.size)?)?;
+                build_id_note(notes)
+            })
+    }
+}
+
+/// Finds the build id among the notes of a note section, each a header of name size,
+/// description size and type followed by the name and the description, aligned on 4 bytes
+fn build_id_note(mut notes: &[u8]) -> Option<String> {
+    let u32_at = |bytes: &[u8], offset: usize| {
+        Some(u32::from_le_bytes(
+            bytes.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    };
+    let align = |size: usize| (size + 3) & !3;
+    while notes.len() >= 12 {
+        let name_size = u32_at(notes, 0)? as usize;
+        let description_size = u32_at(notes, 4)? as usize;
+        let kind = u32_at(notes, 8)?;
+        let name = notes.get(12..12 + name_size)?;
+        let description_start = 12 + align(name_size);
+        let description = notes.get(description_start..description_start + description_size)?;
+        if kind == NT_GNU_BUILD_ID && name == b"GNU\0" {
+            return Some(
+                description
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect(),
+            );
+        }
+        notes = notes.get(description_start + align(description_size)..)?;
+    }
+    None
+}
+
+/// Reads the fields of the headers, failing on truncated files
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn get(&self, offset: usize, size: usize) -> CargoResult<&[u8]> {
+        offset
+            .checked_add(size)
+            .and_then(|end| self.bytes.get(offset..end))
+            .ok_or_else(|| format_err!("truncated at offset {:#x}", offset))
+    }
+
+    fn u16(&self, offset: usize) -> CargoResult<u16> {
+        Ok(u16::from_le_bytes(self.get(offset, 2)?.try_into().unwrap()))
+    }
+
+    fn u32(&self, offset: usize) -> CargoResult<u32> {
+        Ok(u32::from_le_bytes(self.get(offset, 4)?.try_into().unwrap()))
+    }
+
+    fn u64(&self, offset: usize) -> CargoResult<u64> {
+        Ok(u64::from_le_bytes(self.get(offset, 8)?.try_into().unwrap()))
+    }
+
+    fn c_string(&self, offset: usize) -> CargoResult<String> {
+        let bytes = self
+            .bytes
+            .get(offset..)
+            .ok_or_else(|| format_err!("truncated at offset {:#x}", offset))?;
+        let end = bytes
+            .iter()
+            .position(|&byte| byte == 0)
+            .ok_or_else(|| format_err!("unterminated string at offset {:#x}", offset))?;
+        Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+    }
+}
+
+#[test]
+fn stripped_libraries() {
+    let fixture = |strip: &str| {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/elf")
+            .join(format!("libanswer_{}.so", strip));
+        Elf::read(&path).unwrap()
+    };
+
+    let none = fixture("none");
+    assert!(none.has_section(".debug_info"));
+    assert!(none.has_section(".symtab"));
+    assert_eq!(
+        none.build_id().as_deref(),
+        Some("bd2279f72c03c10a4ed0e54c1ce3b080fef95b7c")
+    );
+
+    // `strip = "debuginfo"` keeps the symbol table and the build id
+    let debuginfo = fixture("debuginfo");
+    assert!(!debuginfo.has_section(".debug_info"));
+    assert!(!debuginfo.has_section(".debug_line"));
+    assert!(debuginfo.has_section(".symtab"));
+    assert!(debuginfo.has_section(".note.gnu.build-id"));
+    assert_eq!(
+        debuginfo.build_id().as_deref(),
+        Some("e19f86e612bfdfdb9e69c0556318c06f96023e8a")
+    );
+
+    // `strip = "all"` keeps the build id only
+    let all = fixture("all");
+    assert!(!all.has_section(".debug_info"));
+    assert!(!all.has_section(".symtab"));
+    assert!(all.section_bytes(".dynstr").is_some());
+    assert!(all.section_bytes(".symtab").is_none());
+    assert_eq!(
+        all.build_id().as_deref(),
+        Some("d3599d6da586bb8ebfd3acf072409bff446b3608")
+    );
+
+    let bytes =
+        fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/elf/libanswer_all.so"))
+            .unwrap();
+    assert!(Elf::parse(bytes[..0x20].to_vec()).is_err());
+    assert!(Elf::parse(bytes[..0x200].to_vec()).is_err());
+    assert!(Elf::parse(b"dex\n035\0".to_vec()).is_err());
+}
@@ -0,0 +1,364 @@
+//! Incremental compilation of the Java sources of an APK.
+//!
+//! javac is only given the sources whose contents changed since the previous build, or whose
+//! class files went missing. The hash of every source and the class files it produced are kept
+//! in a state file next to the class files, so that the classes of removed sources, and any
+//! class no source of the build produced, can be deleted before d8 runs over the whole output
+//! directory.
+//!
+//! The output directory is on the classpath, so that the changed sources compile against the
+//! classes of the unchanged ones. Dependencies between sources are not tracked, a change to a
+//! source does not recompile the sources using it. The sources everything else refers to are
+//! the generated `R.java` and the files of `quad_native/`, so a change to any of them recompiles
+//! everything. A change of the javac options (classpath included) recompiles everything as well.
+
+use super::apk::CommandRunner;
+use super::util;
+use anyhow::format_err;
+use cargo::util::CargoResult;
+use cargo_util::{ProcessBuilder, Sha256};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct JavacState {
+    /// Hash of the javac command without the sources
+    options: String,
+    /// Compiled sources, keyed by their path as given to javac
+    sources: BTreeMap<PathBuf, CompiledSource>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CompiledSource {
+    hash: String,
+    /// Class files produced from the source, relative to the output directory
+    classes: Vec<PathBuf>,
+}
+
+fn state_path(obj_dir: &Path) -> PathBuf {
+    obj_dir.with_file_name("javac-state.json")
+}
+
+/// Whether a change to the source must recompile every source
+fn is_shared_source(source: &Path) -> bool {
+    source.starts_with("quad_native") || source.file_name() == Some("R.java".as_ref())
+}
+
+/// Compiles `sources` into `obj_dir`, skipping the ones which didn't change since the last build.
+///
+/// `javac_cmd` must have all its options set, including `-d obj_dir` and its working directory,
+/// relative sources are resolved against it.
+pub fn compile(
+    runner: &dyn CommandRunner,
+    javac_cmd: &ProcessBuilder,
+    obj_dir: &Path,
+    sources: &[PathBuf],
+) -> CargoResult<()> {
+    let cwd = javac_cmd.get_cwd().unwrap_or_else(|| Path::new("."));
+    let options = {
+        let mut hasher = Sha256::new();
+        hasher.update(javac_cmd.get_program().to_string_lossy().as_bytes());
+        for arg in javac_cmd.get_args() {
+            hasher.update(b"\0");
+            hasher.update(arg.to_string_lossy().as_bytes());
+        }
+        hasher.finish_hex()
+    };
+
+    let mut state = fs::read_to_string(state_path(obj_dir))
+        .ok()
+        .and_then(|content| serde_json::from_str::<JavacState>(&content).ok())
+        .filter(|state| state.options == options)
+        .unwrap_or_default();
+    if state.sources.is_empty() {
+        util::clean_dir(obj_dir)?;
+    }
+    state.options = options;
+
+    let mut hashes = BTreeMap::new();
+    for source in sources {
+        let hash = Sha256::new()
+            .update_path(cwd.join(source))
+            .map_err(|err| format_err!("Unable to read `{}`: {}", source.display(), err))?
+            .finish_hex();
+        hashes.insert(source.clone(), hash);
+    }
+
+    let mut changed = sources
+        .iter()
+        .filter(|source| match state.sources.get(*source) {
+            Some(compiled) => {
+                compiled.hash != hashes[*source]
+                    || compiled
+                        .classes
+                        .iter()
+                        .any(|class| !obj_dir.join(class).exists())
+            }
+            None => true,
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    if changed.iter().any(|source| is_shared_source(source)) {
+        util::clean_dir(obj_dir)?;
+        state.sources.clear();
+        changed = sources.to_vec();
+    }
+
+    // Only the classes of the unchanged sources are kept, the classes of changed sources are
+    // written again by javac. Classes of removed sources, or of no source of this build at all,
+    // like those of a previous package name, would otherwise end up in the dex.
+    state
+        .sources
+        .retain(|source, _| hashes.contains_key(source) && !changed.contains(source));
+    let kept_classes = state
+        .sources
+        .values()
+        .flat_map(|compiled| compiled.classes.iter().cloned())
+        .collect::<BTreeSet<_>>();
+    for class in class_mtimes(obj_dir)?.into_keys() {
+        if !kept_classes.contains(&class) {
+            fs::remove_file(obj_dir.join(&class))?;
+        }
+    }
+
+    if !changed.is_empty() {
+        let before = class_mtimes(obj_dir)?;
+        let mut cmd = javac_cmd.clone();
+        runner.run(cmd.args(&changed))?;
+
+        // Classes named after a source are attributed to it, others (like secondary top level
+        // classes) to every source of this javac run
+        let written = class_mtimes(obj_dir)?
+            .into_iter()
+            .filter(|(class, mtime)| before.get(class) != Some(mtime))
+            .map(|(class, _)| class)
+            .collect::<Vec<_>>();
+        for source in &changed {
+            let stem = source.file_stem();
+            let classes = written
+                .iter()
+                .filter(|class| {
+                    let outer = class
+                        .file_stem()
+                        .and_then(|s| s.to_str())
+                        .and_then(|s| s.split('$').next());
+                    let owner = changed
+                        .iter()
+                        .find(|source| source.file_stem().and_then(|s| s.to_str()) == outer);
+                    owner.map_or(true, |owner| owner.file_stem() == stem)
+                })
+                .cloned()
+                .collect();
+            state.sources.insert(
+                source.clone(),
+                CompiledSource {
+                    hash: hashes[source].clone(),
+                    classes,
+                },
+            );
+        }
+    }
+
+    fs::write(state_path(obj_dir), serde_json::to_string_pretty(&state)?)?;
+    Ok(())
+}
+
+/// Forgets the sources the classes of `obj_dir` were compiled from, once they are replaced by
+/// classes of another build, so that the next compilation compiles every source
+pub fn forget(obj_dir: &Path) -> CargoResult<()> {
+    let state_path = state_path(obj_dir);
+    if state_path.exists() {
+        fs::remove_file(state_path)?;
+    }
+    Ok(())
+}
+
+/// Returns the modification time of every class file, keyed by its path relative to `obj_dir`
+fn class_mtimes(obj_dir: &Path) -> CargoResult<BTreeMap<PathBuf, SystemTime>> {
+    let mut mtimes = BTreeMap::new();
+    for class in util::find_files(obj_dir, "class")? {
+        let mtime = fs::metadata(&class)?.modified()?;
+        mtimes.insert(class.strip_prefix(obj_dir)?.to_owned(), mtime);
+    }
+    Ok(mtimes)
+}
+
+#[cfg(unix)]
+#[test]
+fn only_changed_sources_are_recompiled() {
+    use super::apk::ProcessRunner;
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = std::env::temp_dir().join(format!("cargo-quad-apk-javac-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let obj_dir = root.join("build").join("obj");
+    let log = root.join("javac.log");
+
+    // Writes an empty class file for every source and records the sources it was given
+    let javac = root.join("javac");
+    fs::create_dir_all(&root).unwrap();
+    fs::write(
+        &javac,
+        format!(
+            r#"#!/bin/sh
+out=
+sources=
+while [ $# -gt 0 ]; do
+    case "$1" in
+        -d) out="$2"; shift ;;
+        *.java)
+            sources="$sources $1"
+            pkg=$(sed -n 's/^package \(.*\);$/\1/p' "$1" | tr . /)
+            mkdir -p "$out/$pkg"
+            touch "$out/$pkg/$(basename "$1" .java).class" ;;
+    esac
+    shift
+done
+echo "$sources" >> {}
+"#,
+            log.display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&javac, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let write_source = |path: &str, contents: &str| {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    };
+    write_source("quad_native/QuadNative.java", "package quad_native;\n");
+    write_source("a/A.java", "package a;\n");
+    write_source("b/B.java", "package b;\n");
+
+    let mut javac_cmd = ProcessBuilder::new(&javac);
+    javac_cmd.arg("-d").arg(&obj_dir).cwd(&root);
+    let sources = |names: &[&str]| names.iter().map(PathBuf::from).collect::<Vec<_>>();
+    let all = sources(&["quad_native/QuadNative.java", "a/A.java", "b/B.java"]);
+    let javac_runs = || {
+        fs::read_to_string(&log)
+            .unwrap_or_default()
+            .lines()
+            .map(|line| line.trim().to_owned())
+            .collect::<Vec<_>>()
+    };
+
+    compile(&ProcessRunner, &javac_cmd, &obj_dir, &all).unwrap();
+    assert_eq!(
+        javac_runs(),
+        vec!["quad_native/QuadNative.java a/A.java b/B.java"]
+    );
+
+    // Nothing changed
+    compile(&ProcessRunner, &javac_cmd, &obj_dir, &all).unwrap();
+    assert_eq!(javac_runs().len(), 1);
+
+    write_source("a/A.java", "package a;\nclass A {}\n");
+    compile(&ProcessRunner, &javac_cmd, &obj_dir, &all).unwrap();
+    assert_eq!(javac_runs()[1], "a/A.java");
+
+    // A missing class file recompiles its source
+    fs::remove_file(obj_dir.join("b/B.class")).unwrap();
+    compile(&ProcessRunner, &javac_cmd, &obj_dir, &all).unwrap();
+    assert_eq!(javac_runs()[2], "b/B.java");
+
+    // The classes of removed sources are deleted without running javac
+    compile(
+        &ProcessRunner,
+        &javac_cmd,
+        &obj_dir,
+        &sources(&["quad_native/QuadNative.java", "a/A.java"]),
+    )
+    .unwrap();
+    assert_eq!(javac_runs().len(), 3);
+    assert!(!obj_dir.join("b/B.class").exists());
+    assert!(obj_dir.join("a/A.class").exists());
+
+    // A renamed source replaces the classes of the old name, and classes which no source of the
+    // build produced, like those of a previous package name, are deleted too
+    fs::rename(root.join("a/A.java"), root.join("a/Renamed.java")).unwrap();
+    fs::create_dir_all(obj_dir.join("rust/old_name")).unwrap();
+    fs::write(obj_dir.join("rust/old_name/MainActivity.class"), "").unwrap();
+    compile(
+        &ProcessRunner,
+        &javac_cmd,
+        &obj_dir,
+        &sources(&["quad_native/QuadNative.java", "a/Renamed.java"]),
+    )
+    .unwrap();
+    assert_eq!(javac_runs()[3], "a/Renamed.java");
+    assert_eq!(
+        class_mtimes(&obj_dir)
+            .unwrap()
+            .into_keys()
+            .collect::<Vec<_>>(),
+        sources(&["a/Renamed.class", "quad_native/QuadNative.class"])
+    );
+    fs::rename(root.join("a/Renamed.java"), root.join("a/A.java")).unwrap();
+
+    // Shared sources recompile everything
+    write_source(
+        "quad_native/QuadNative.java",
+        "package quad_native;\nclass QuadNative {}\n",
+    );
+    compile(&ProcessRunner, &javac_cmd, &obj_dir, &all).unwrap();
+    assert_eq!(
+        javac_runs()[4],
+        "quad_native/QuadNative.java a/A.java b/B.java"
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+#[ignore = "requires a JDK"]
+fn changed_sources_compile_against_unchanged_classes() {
+    use super::apk::ProcessRunner;
+
+    let root =
+        std::env::temp_dir().join(format!("cargo-quad-apk-real-javac-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let obj_dir = root.join("build").join("obj");
+    let write_source = |path: &str, contents: &str| {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    };
+    write_source(
+        "quad_native/QuadNative.java",
+        "package quad_native;\npublic class QuadNative {\n    public static void init() {}\n}\n",
+    );
+    write_source(
+        "rust/app/MainActivity.java",
+        "package rust.app;\npublic class MainActivity {\n    void onCreate() { quad_native.QuadNative.init(); }\n}\n",
+    );
+
+    // Like the command of the APK builder, relative to the target directory. An explicit
+    // classpath leaves out the sources of the working directory.
+    let mut javac_cmd = ProcessBuilder::new("javac");
+    javac_cmd
+        .arg("-classpath")
+        .arg("android.jar:build/obj")
+        .arg("-d")
+        .arg("build/obj")
+        .cwd(&root);
+    let sources = vec![
+        PathBuf::from("quad_native/QuadNative.java"),
+        PathBuf::from("rust/app/MainActivity.java"),
+    ];
+    compile(&ProcessRunner, &javac_cmd, &obj_dir, &sources).unwrap();
+
+    // An edited inject only changes the MainActivity, compiled alone against QuadNative
+    write_source(
+        "rust/app/MainActivity.java",
+        "package rust.app;\npublic class MainActivity {\n    void onCreate() { quad_native.QuadNative.init(); }\n    void onResume() {}\n}\n",
+    );
+    compile(&ProcessRunner, &javac_cmd, &obj_dir, &sources).unwrap();
+    assert!(obj_dir.join("rust/app/MainActivity.class").exists());
+    assert!(obj_dir.join("quad_native/QuadNative.class").exists());
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,302 @@
+//! Keep rules for the Java code the shared libraries reach through JNI, which R8 or ProGuard
+//! can't see when shrinking and obfuscating the Java code.
+//!
+//! The libraries name the Java classes they look up and the signatures of the members they call
+//! with strings, and export the native methods of the Java classes as `Java_` functions.
+
+use super::elf::Elf;
+use cargo::util::CargoResult;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+/// Name of the generated rules file, in the directory of the target
+pub const KEEP_RULES_FILENAME: &str = "cargo-apk-keep.pro";
+
+/// Packages of the platform, which aren't part of the app and so are never shrunk
+const PLATFORM_PACKAGES: &[&str] = &["android.", "dalvik.", "java.", "javax."];
+
+/// Java classes and methods referenced by libraries, with their names as in Java source
+#[derive(Debug, Default, PartialEq)]
+pub struct JniReferences {
+    /// Classes looked up by name or named in member signatures, all of their members are kept
+    pub classes: BTreeSet<String>,
+    /// Native methods implemented by the libraries, by class
+    pub native_methods: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl JniReferences {
+    /// Collects the references of the strings of a library, in `.rodata` for the names and
+    /// signatures given to JNI and in `.dynstr` for the exported native methods
+    pub fn add_library(&mut self, path: &Path) -> CargoResult<()> {
+        let elf = Elf::read(path)?;
+        for section in &[".rodata", ".dynstr"] {
+            if let Some(bytes) = elf.section_bytes(section) {
+                self.add_strings(bytes);
+            }
+        }
+        Ok(())
+    }
+
+    /// Collects the references of the printable strings of `bytes`. Strings of Rust code
+    /// aren't NUL terminated, so the references are also looked for inside longer strings.
+    pub fn add_strings(&mut self, bytes: &[u8]) {
+        for string in bytes
+            .split(|byte| !byte.is_ascii_graphic())
+            .filter_map(|string| std::str::from_utf8(string).ok())
+        {
+            if let Some(start) = string.find("Java_") {
+                if let Some((class, method)) = native_method(&string[start..]) {
+                    self.native_methods.entry(class).or_default().insert(method);
+                }
+            }
+            for (start, _) in string.match_indices('(') {
+                self.classes.extend(
+                    signature_classes(&string[start..])
+                        .into_iter()
+                        .map(java_name),
+                );
+            }
+            if is_class_name(string) {
+                self.classes.insert(java_name(string));
+            }
+        }
+        self.classes.retain(|class| !is_platform_class(class));
+        self.native_methods
+            .retain(|class, _| !is_platform_class(class));
+    }
+}
+
+/// Returns the class and the method of an exported native method, `Java_` followed by the
+/// mangled class and method names, and by `__` and the mangled argument types when overloaded
+fn native_method(symbol: &str) -> Option<(String, String)> {
+    let mut parts = vec![String::new()];
+    let mut chars = symbol.strip_prefix("Java_")?.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '_' => match chars.clone().next() {
+                Some('1') => {
+                    chars.next();
+                    parts.last_mut()?.push('_');
+                }
+                Some('0') => {
+                    chars.next();
+                    let code: String = chars.by_ref().take(4).collect();
+                    let c = u32::from_str_radix(&code, 16)
+                        .ok()
+                        .and_then(char::from_u32)?;
+                    parts.last_mut()?.push(c);
+                }
+                // The argument types of an overloaded method
+                Some('_') => break,
+                _ => parts.push(String::new()),
+            },
+            c if c.is_ascii_alphanumeric() => parts.last_mut()?.push(c),
+            _ => return None,
+        }
+    }
+    let method = parts.pop()?;
+    if parts.is_empty() || method.is_empty() || parts.iter().any(String::is_empty) {
+        return None;
+    }
+    Some((parts.join("."), method))
+}
+
+/// Returns the classes named by a method signature at the start of `string`, such as
+/// `(Lcom/example/Event;I)V`, or nothing when it isn't one
+fn signature_classes(string: &str) -> Vec<&str> {
+    fn field_type<'a>(string: &'a str, classes: &mut Vec<&'a str>) -> Option<&'a str> {
+        let string = string.trim_start_matches('[');
+        match string.chars().next()? {
+            'B' | 'C' | 'D' | 'F' | 'I' | 'J' | 'S' | 'Z' => Some(&string[1..]),
+            'L' => {
+                let end = string.find(';')?;
+                let class = &string[1..end];
+                if !is_class_name(class) && !is_identifier(class) {
+                    return None;
+                }
+                classes.push(class);
+                Some(&string[end + 1..])
+            }
+            _ => None,
+        }
+    }
+
+    let mut classes = vec![];
+    let mut rest = match string.strip_prefix('(') {
+        Some(rest) => rest,
+        None => return vec![],
+    };
+    loop {
+        if let Some(after) = rest.strip_prefix(')') {
+            rest = after;
+            break;
+        }
+        rest = match field_type(rest, &mut classes) {
+            Some(rest) => rest,
+            None => return vec![],
+        };
+    }
+    if rest.starts_with('V') || field_type(rest, &mut classes).is_some() {
+        classes
+    } else {
+        vec![]
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .map_or(false, |c| c.is_ascii_alphabetic() || c == '_' || c == '$')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+}
+
+/// Whether `name` is the binary name of a class in a package, as given to `FindClass`
+fn is_class_name(name: &str) -> bool {
+    name.contains('/') && name.split('/').all(is_identifier)
+}
+
+fn java_name(binary_name: &str) -> String {
+    binary_name.replace('/', ".")
+}
+
+fn is_platform_class(class: &str) -> bool {
+    PLATFORM_PACKAGES
+        .iter()
+        .any(|package| class.starts_with(package))
+}
+
+/// Returns the keep rules of the references, and of the classes and members of `allowlist`,
+/// `com.example.Bridge` keeping a whole class and `com.example.Bridge#onEvent` the fields and
+/// methods named `onEvent`
+pub fn keep_rules(references: &JniReferences, allowlist: &[String]) -> String {
+    let mut members: BTreeMap<&str, BTreeSet<String>> = BTreeMap::new();
+    let mut whole_classes: BTreeSet<&str> = references.classes.iter().map(String::as_str).collect();
+    for entry in allowlist {
+        match entry.split_once('#') {
+            Some((class, member)) => {
+                let rules = members.entry(class).or_default();
+                rules.insert(format!("*** {}(...);", member));
+                rules.insert(format!("*** {};", member));
+            }
+            None => {
+                whole_classes.insert(entry);
+            }
+        }
+    }
+    for (class, methods) in &references.native_methods {
+        members.entry(class).or_default().extend(
+            methods
+                .iter()
+                .map(|method| format!("native *** {}(...);", method)),
+        );
+    }
+
+    let mut rules = String::from(
+        "# Generated by cargo-quad-apk from the JNI references of the libraries and `jni_keep`\n",
+    );
+    for class in &whole_classes {
+        rules.push_str(&format!("-keep class {} {{ *; }}\n", class));
+    }
+    for (class, members) in members
+        .iter()
+        .filter(|(class, _)| !whole_classes.contains(*class))
+    {
+        rules.push_str(&format!("-keep class {} {{\n", class));
+        for member in members {
+            rules.push_str(&format!("    {}\n", member));
+        }
+        rules.push_str("}\n");
+    }
+    rules
+}
+
+#[test]
+fn jni_references_of_a_library() {
+    let mut references = JniReferences::default();
+    references
+        .add_library(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/elf/libjni.so"))
+        .unwrap();
+    assert_eq!(
+        references.classes.iter().collect::<Vec<_>>(),
+        vec!["com.example.game.Bridge", "com.example.game.Event"]
+    );
+    assert_eq!(
+        references.native_methods.iter().collect::<Vec<_>>(),
+        vec![
+            (
+                &"com.example.game.Bridge".to_owned(),
+                &vec!["nativeInit".to_owned()].into_iter().collect()
+            ),
+            (
+                &"com.example.game.Native_Lib".to_owned(),
+                &vec!["onFrame".to_owned()].into_iter().collect()
+            ),
+        ]
+    );
+
+    // Strings of Rust code follow each other without a NUL
+    let mut references = JniReferences::default();
+    references.add_strings(b"getAssets()Landroid/content/res/AssetManager;onEvent(Lrust/app/Ev;)Z");
+    assert_eq!(
+        references.classes.into_iter().collect::<Vec<_>>(),
+        vec!["rust.app.Ev"]
+    );
+
+    assert_eq!(
+        native_method("Java_Main_run"),
+        Some(("Main".to_owned(), "run".to_owned()))
+    );
+    assert_eq!(
+        native_method("Java_a_b_1c_00040d_f__Ljava_lang_String_2"),
+        Some(("a.b_c@d".to_owned(), "f".to_owned()))
+    );
+    assert_eq!(native_method("Java_run"), None);
+    assert_eq!(native_method("Java_a.b"), None);
+    assert!(signature_classes("(I").is_empty());
+    assert!(signature_classes("(Lfoo bar;)V").is_empty());
+    assert_eq!(signature_classes("([[La/B;)[Lc/D;"), vec!["a/B", "c/D"]);
+}
+
+#[test]
+fn keep_rules_of_references() {
+    let references = JniReferences {
+        classes: vec!["com.example.game.Bridge".to_owned()]
+            .into_iter()
+            .collect(),
+        native_methods: vec![
+            (
+                "com.example.game.Bridge".to_owned(),
+                vec!["nativeInit".to_owned()].into_iter().collect(),
+            ),
+            (
+                "com.example.game.Native".to_owned(),
+                vec!["onFrame".to_owned(), "onPause".to_owned()]
+                    .into_iter()
+                    .collect(),
+            ),
+        ]
+        .into_iter()
+        .collect(),
+    };
+    assert_eq!(
+        keep_rules(
+            &references,
+            &[
+                "com.example.Reflected".to_owned(),
+                "com.example.game.Native#callback".to_owned()
+            ]
+        ),
+        "\
+# Generated by cargo-quad-apk from the JNI references of the libraries and `jni_keep`
+-keep class com.example.Reflected { *; }
+-keep class com.example.game.Bridge { *; }
+-keep class com.example.game.Native {
+    *** callback(...);
+    *** callback;
+    native *** onFrame(...);
+    native *** onPause(...);
+}
+"
+    );
+}
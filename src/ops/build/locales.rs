@@ -0,0 +1,254 @@
+//! Per-app language settings of Android 13.
+//!
+//! With `supported_locales`, the locales are listed in `res/xml/locales_config.xml` and the
+//! manifest references it with `android:localeConfig`, so that the system settings offer them.
+
+use crate::config::{AndroidConfig, AndroidTargetConfig};
+use cargo::util::CargoResult;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+/// SDK version from which `android:localeConfig` is read, and known to the `android.jar` the
+/// manifest is linked against
+const LOCALE_CONFIG_MIN_SDK: u32 = 33;
+
+/// Whether `res/xml/locales_config.xml` is generated and referenced by the manifest
+pub fn locale_config_enabled(config: &AndroidConfig, target_config: &AndroidTargetConfig) -> bool {
+    !target_config.supported_locales.is_empty()
+        && config.target_sdk_version >= LOCALE_CONFIG_MIN_SDK
+        && config.android_version >= LOCALE_CONFIG_MIN_SDK
+}
+
+pub fn render_locales_config(locales: &[String]) -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <locale-config xmlns:android=\"http://schemas.android.com/apk/res/android\">\n",
+    );
+    for locale in locales {
+        xml.push_str(&format!("    <locale android:name=\"{}\"/>\n", locale));
+    }
+    xml.push_str("</locale-config>\n");
+    xml
+}
+
+/// Writes `xml/locales_config.xml` into the generated resources directory when enabled, and
+/// removes the one of a previous build otherwise
+pub fn write_locales_config(
+    config: &AndroidConfig,
+    target_config: &AndroidTargetConfig,
+    res_dir: &Path,
+) -> CargoResult<()> {
+    let xml_dir = res_dir.join("xml");
+    let path = xml_dir.join("locales_config.xml");
+    if locale_config_enabled(config, target_config) {
+        fs::create_dir_all(&xml_dir)?;
+        fs::write(
+            &path,
+            render_locales_config(&target_config.supported_locales),
+        )?;
+    } else if path.exists() {
+        fs::remove_file(&path)?;
+    }
+    Ok(())
+}
+
+/// Converts the language and region qualifiers of a resource directory name, like
+/// `values-zh-rCN` or `values-b+sr+Latn`, to a BCP-47 tag
+fn qualifier_locale(dir_name: &str) -> Option<String> {
+    let qualifiers = dir_name.strip_prefix("values-")?;
+    if let Some(tag) = qualifiers.strip_prefix("b+") {
+        let tag = tag.split('-').next().unwrap();
+        return Some(tag.replace('+', "-"));
+    }
+    let mut parts = qualifiers.split('-');
+    let language = parts.next()?;
+    // `car` is the UI mode qualifier, the only other one made of 2 or 3 lowercase letters
+    if !(2..=3).contains(&language.len())
+        || !language.chars().all(|c| c.is_ascii_lowercase())
+        || language == "car"
+    {
+        return None;
+    }
+    let region = parts.next().and_then(|region| {
+        let code = region.strip_prefix('r')?;
+        let valid = (code.len() == 2 && code.chars().all(|c| c.is_ascii_uppercase()))
+            || (code.len() == 3 && code.chars().all(|c| c.is_ascii_digit()));
+        if valid {
+            Some(code)
+        } else {
+            None
+        }
+    });
+    Some(match region {
+        Some(region) => format!("{}-{}", language, region),
+        None => language.to_owned(),
+    })
+}
+
+/// Returns the locales of the `values-*` directories of `res_path` with a `strings.xml`
+pub fn string_locales(res_path: &Path) -> CargoResult<BTreeSet<String>> {
+    let mut locales = BTreeSet::new();
+    if !res_path.is_dir() {
+        return Ok(locales);
+    }
+    for entry in fs::read_dir(res_path)? {
+        let entry = entry?;
+        if !entry.path().join("strings.xml").is_file() {
+            continue;
+        }
+        if let Some(locale) = qualifier_locale(&entry.file_name().to_string_lossy()) {
+            locales.insert(locale);
+        }
+    }
+    Ok(locales)
+}
+
+/// Returns the warnings about `supported_locales` of a target
+pub fn locale_warnings(
+    config: &AndroidConfig,
+    target_config: &AndroidTargetConfig,
+) -> CargoResult<Vec<String>> {
+    let mut warnings = vec![];
+    if target_config.supported_locales.is_empty() {
+        return Ok(warnings);
+    }
+    if config.target_sdk_version < LOCALE_CONFIG_MIN_SDK {
+        warnings.push(format!(
+            "`supported_locales` is ignored when targeting API {} (below {})",
+            config.target_sdk_version, LOCALE_CONFIG_MIN_SDK
+        ));
+        return Ok(warnings);
+    }
+    if config.android_version < LOCALE_CONFIG_MIN_SDK {
+        warnings.push(format!(
+            "`supported_locales` is ignored when compiling against API {} (below {})",
+            config.android_version, LOCALE_CONFIG_MIN_SDK
+        ));
+        return Ok(warnings);
+    }
+
+    let supported = target_config
+        .supported_locales
+        .iter()
+        .map(|locale| locale.to_lowercase())
+        .collect::<BTreeSet<_>>();
+    for res_path in &target_config.res_paths {
+        let missing = string_locales(res_path)?
+            .into_iter()
+            .filter(|locale| !supported.contains(&locale.to_lowercase()))
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            warnings.push(format!(
+                "locales with strings in `{}` are missing from `supported_locales`, they won't \
+                 be offered in the language settings: {}",
+                res_path.display(),
+                missing.join(", ")
+            ));
+        }
+    }
+    Ok(warnings)
+}
+
+#[test]
+fn locales_config_xml() {
+    let locales = ["en", "de", "zh-CN"]
+        .iter()
+        .map(|locale| locale.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        render_locales_config(&locales),
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<locale-config xmlns:android="http://schemas.android.com/apk/res/android">
+    <locale android:name="en"/>
+    <locale android:name="de"/>
+    <locale android:name="zh-CN"/>
+</locale-config>
+"#
+    );
+}
+
+#[test]
+fn resource_qualifier_locales() {
+    assert_eq!(qualifier_locale("values-de"), Some("de".to_owned()));
+    assert_eq!(qualifier_locale("values-zh-rCN"), Some("zh-CN".to_owned()));
+    assert_eq!(
+        qualifier_locale("values-es-r419"),
+        Some("es-419".to_owned())
+    );
+    assert_eq!(qualifier_locale("values-fr-night"), Some("fr".to_owned()));
+    assert_eq!(
+        qualifier_locale("values-b+sr+Latn"),
+        Some("sr-Latn".to_owned())
+    );
+    assert_eq!(qualifier_locale("values"), None);
+    assert_eq!(qualifier_locale("values-night"), None);
+    assert_eq!(qualifier_locale("values-v21"), None);
+    assert_eq!(qualifier_locale("values-car"), None);
+    assert_eq!(qualifier_locale("drawable-de"), None);
+}
+
+#[test]
+fn missing_supported_locales() {
+    let root = std::env::temp_dir().join(format!("cargo-quad-apk-locales-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    for dir in &[
+        "values",
+        "values-de",
+        "values-zh-rCN",
+        "values-fr",
+        "values-it",
+    ] {
+        fs::create_dir_all(root.join("res").join(dir)).unwrap();
+        fs::write(root.join("res").join(dir).join("strings.xml"), "").unwrap();
+    }
+    // Only directories with strings count
+    fs::create_dir_all(root.join("res").join("values-ja")).unwrap();
+    fs::write(root.join("res").join("values-ja").join("colors.xml"), "").unwrap();
+
+    let metadata = |android_version: u32, target_sdk_version: u32| {
+        format!(
+            "android_version = {}\ntarget_sdk_version = {}\nres = {:?}\n\
+             supported_locales = [\"en\", \"de\", \"zh-cn\"]",
+            android_version,
+            target_sdk_version,
+            root.join("res")
+        )
+    };
+    let config = crate::config::from_metadata(&metadata(33, 33));
+    let target_config = config
+        .resolve((cargo::core::TargetKind::Bin, "app".to_owned()))
+        .unwrap();
+    assert!(locale_config_enabled(&config, &target_config));
+    assert_eq!(
+        locale_warnings(&config, &target_config).unwrap(),
+        vec![format!(
+            "locales with strings in `{}` are missing from `supported_locales`, they won't be \
+             offered in the language settings: fr, it",
+            root.join("res").display()
+        )]
+    );
+
+    let config = crate::config::from_metadata(&metadata(33, 32));
+    let target_config = config
+        .resolve((cargo::core::TargetKind::Bin, "app".to_owned()))
+        .unwrap();
+    assert!(!locale_config_enabled(&config, &target_config));
+    assert_eq!(
+        locale_warnings(&config, &target_config).unwrap(),
+        vec!["`supported_locales` is ignored when targeting API 32 (below 33)"]
+    );
+
+    // The `android.jar` of older platforms doesn't know `android:localeConfig`
+    let config = crate::config::from_metadata(&metadata(32, 33));
+    let target_config = config
+        .resolve((cargo::core::TargetKind::Bin, "app".to_owned()))
+        .unwrap();
+    assert!(!locale_config_enabled(&config, &target_config));
+    assert_eq!(
+        locale_warnings(&config, &target_config).unwrap(),
+        vec!["`supported_locales` is ignored when compiling against API 32 (below 33)"]
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
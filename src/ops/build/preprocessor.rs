@@ -1,3 +1,4 @@
+use crate::ops::external_assets;
 use std::{fmt::Write, fs, path::PathBuf};
 
 #[derive(Debug, Default)]
@@ -34,6 +35,33 @@ impl Inject {
     }
 }
 
+impl Inject {
+    /// Returns the code of each section, along with the marker of the MainActivity it goes to
+    fn sections(&self) -> [(&'static str, &str); 6] {
+        let m = &self.main_activity;
+        [
+            ("//% IMPORTS", &self.imports),
+            ("//% MAIN_ACTIVITY_BODY", &m.body),
+            ("//% MAIN_ACTIVITY_ON_RESUME", &m.on_resume),
+            ("//% MAIN_ACTIVITY_ON_PAUSE", &m.on_pause),
+            ("//% MAIN_ACTIVITY_ON_CREATE", &m.on_create),
+            (
+                "//% MAIN_ACTIVITY_ON_ACTIVITY_RESULT",
+                &m.on_activity_result,
+            ),
+        ]
+    }
+
+    fn read(inject_files: &[PathBuf]) -> Inject {
+        let mut inject = Inject::default();
+        for file in inject_files {
+            let src = fs::read_to_string(file).unwrap();
+            inject.add(parse_inject_template(&src));
+        }
+        inject
+    }
+}
+
 fn parse_inject_template(file: &str) -> Inject {
     let mut res = Inject::default();
     let mut target = None;
@@ -134,6 +162,81 @@ if (requestCode == FILE_REQUEST_CODE) {}
     );
 }
 
+/// Loads the library of debug builds, preferring the one `cargo quad-apk hot` pushes into the
+/// data directory of the app while it is newer than the library of the installed APK, so that
+/// installing the app again brings back the packaged library.
+const HOT_RELOAD_JAVA: &str = r#"package TARGET_PACKAGE_NAME;
+
+import android.os.Process;
+import android.util.Log;
+import dalvik.system.BaseDexClassLoader;
+import java.io.File;
+
+// Generated by cargo-quad-apk for debug builds only
+class HotReload {
+    static void loadLibrary(String name) {
+        File hot = new File("/data/user/" + Process.myUid() / 100000
+                + "/APPLICATION_ID/files/hot/" + System.mapLibraryName(name));
+        String packaged = ((BaseDexClassLoader) HotReload.class.getClassLoader()).findLibrary(name);
+        // Libraries loaded from the APK itself are named like `base.apk!/lib/x86/libapp.so`
+        if (hot.exists() && packaged != null
+                && hot.lastModified() > new File(packaged.split("!/")[0]).lastModified()) {
+            Log.i("HotReload", "Loading " + hot);
+            System.load(hot.getAbsolutePath());
+        } else {
+            System.loadLibrary(name);
+        }
+    }
+}
+"#;
+
+/// The way miniquad's MainActivity loads the library
+const LOAD_LIBRARY: &str = r#"System.loadLibrary("LIBRARY_NAME")"#;
+
+/// Makes a MainActivity load its library through `HotReload`, returning it along with the source
+/// of `HotReload`, or `None` when the MainActivity doesn't load its library like miniquad's
+pub fn hot_reload(
+    java_src: &str,
+    package_name: &str,
+    application_id: &str,
+) -> Option<(String, String)> {
+    if !java_src.contains(LOAD_LIBRARY) {
+        return None;
+    }
+    let java_src = java_src.replace(LOAD_LIBRARY, r#"HotReload.loadLibrary("LIBRARY_NAME")"#);
+    let hot_reload = HOT_RELOAD_JAVA
+        .replace("TARGET_PACKAGE_NAME", package_name)
+        .replace("APPLICATION_ID", application_id);
+    Some((java_src, hot_reload))
+}
+
+/// Sets the directory `install` pushes the assets to, the same as `external_assets::device_dir`,
+/// before the library starts
+const EXTERNAL_ASSETS_ON_CREATE: &str = r#"        // Generated by cargo-quad-apk for debug_assets_external
+        try {
+            android.system.Os.setenv("DIR_ENV", getExternalFilesDir("assets").getAbsolutePath(), true);
+        } catch (android.system.ErrnoException e) {
+            throw new RuntimeException(e);
+        }
+"#;
+
+/// Marker of the MainActivity where the code run when it's created goes
+const ON_CREATE_MARKER: &str = "//% MAIN_ACTIVITY_ON_CREATE";
+
+/// Makes a MainActivity point the app at the assets of `debug_assets_external` builds, through
+/// `external_assets::DIR_ENV`. Returns `None` when it has no `//% MAIN_ACTIVITY_ON_CREATE` marker.
+pub fn external_assets(java_src: &str) -> Option<String> {
+    if !java_src.contains(ON_CREATE_MARKER) {
+        return None;
+    }
+    let glue = EXTERNAL_ASSETS_ON_CREATE.replace("DIR_ENV", external_assets::DIR_ENV);
+    // The marker stays for the code injected by the dependencies, at the indentation of the glue
+    Some(java_src.replace(
+        ON_CREATE_MARKER,
+        &format!("{}\n        {}", glue.trim(), ON_CREATE_MARKER),
+    ))
+}
+
 pub fn preprocess_main_activity(
     java_src: &str,
     package_name: &str,
@@ -143,23 +246,115 @@ pub fn preprocess_main_activity(
     let res = java_src.replace("TARGET_PACKAGE_NAME", package_name);
     let res = res.replace("LIBRARY_NAME", &library_name);
 
-    let mut inject = Inject::default();
-
-    for file in inject_files {
-        let src = fs::read_to_string(file).unwrap();
-        inject.add(parse_inject_template(&src));
+    let inject = Inject::read(inject_files);
+    let mut res = res;
+    for (marker, code) in inject.sections().iter() {
+        res = res.replace(marker, code);
     }
+    res
+}
+
+/// Returns the markers which a MainActivity lacks while the injects have code for them
+pub fn missing_markers(java_src: &str, inject_files: &[PathBuf]) -> Vec<&'static str> {
+    Inject::read(inject_files)
+        .sections()
+        .iter()
+        .filter(|(marker, code)| !code.is_empty() && !java_src.contains(marker))
+        .map(|(marker, _)| *marker)
+        .collect()
+}
 
-    let m = &inject.main_activity;
-    let res = res.replace("//% IMPORTS", &inject.imports);
-    let res = res.replace("//% MAIN_ACTIVITY_BODY", &m.body);
-    let res = res.replace("//% MAIN_ACTIVITY_ON_RESUME", &m.on_resume);
-    let res = res.replace("//% MAIN_ACTIVITY_ON_PAUSE", &m.on_pause);
-    let res = res.replace("//% MAIN_ACTIVITY_ON_CREATE", &m.on_create);
-    let res = res.replace(
-        "//% MAIN_ACTIVITY_ON_ACTIVITY_RESULT",
-        &m.on_activity_result,
+#[test]
+fn hot_reload_glue() {
+    let java_src = "package TARGET_PACKAGE_NAME;\n\
+                    public class MainActivity {\n\
+                    \x20   static {\n\
+                    \x20       System.loadLibrary(\"LIBRARY_NAME\");\n\
+                    \x20   }\n\
+                    }\n";
+    let (main_activity, hot_reload_src) =
+        hot_reload(java_src, "rust.native_", "rust.native").unwrap();
+    assert_eq!(
+        preprocess_main_activity(&main_activity, "rust.native_", "native", &[]),
+        "package rust.native_;\n\
+         public class MainActivity {\n\
+         \x20   static {\n\
+         \x20       HotReload.loadLibrary(\"native\");\n\
+         \x20   }\n\
+         }\n"
     );
+    assert!(hot_reload_src.starts_with("package rust.native_;\n"));
+    assert!(hot_reload_src.contains("\"/rust.native/files/hot/\""));
 
-    res
+    // Nothing to redirect in a MainActivity loading its library another way
+    assert!(hot_reload("public class MainActivity {}\n", "rust.app", "rust.app").is_none());
+}
+
+#[test]
+fn external_assets_glue() {
+    let java_src = "public class MainActivity {\n\
+                    \x20   public void onCreate(Bundle savedInstanceState) {\n\
+                    \x20       //% MAIN_ACTIVITY_ON_CREATE\n\
+                    \x20   }\n\
+                    }\n";
+    let main_activity = external_assets(java_src).unwrap();
+    let main_activity = preprocess_main_activity(&main_activity, "rust.app", "app", &[]);
+    assert_eq!(
+        main_activity,
+        "public class MainActivity {\n\
+         \x20   public void onCreate(Bundle savedInstanceState) {\n\
+         \x20       // Generated by cargo-quad-apk for debug_assets_external\n\
+         \x20       try {\n\
+         \x20           android.system.Os.setenv(\"CARGO_APK_ASSETS_DIR\", \
+         getExternalFilesDir(\"assets\").getAbsolutePath(), true);\n\
+         \x20       } catch (android.system.ErrnoException e) {\n\
+         \x20           throw new RuntimeException(e);\n\
+         \x20       }\n\
+         \x20       \n\
+         \x20   }\n\
+         }\n"
+    );
+    // The directory `install` pushes to, as Context.getExternalFilesDir("assets") of user 0
+    assert_eq!(
+        external_assets::device_dir("rust.app", Some(0)),
+        "/storage/emulated/0/Android/data/rust.app/files/assets"
+    );
+
+    assert!(external_assets("public class MainActivity {}\n").is_none());
+}
+
+#[test]
+fn missing_inject_markers() {
+    let dir = std::env::temp_dir().join(format!("cargo-quad-apk-markers-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let inject = dir.join("inject.java");
+    fs::write(
+        &inject,
+        "//% IMPORTS\n\
+         import a.A;\n\
+         //% END\n\
+         //% MAIN_ACTIVITY_ON_CREATE\n\
+         A.init();\n\
+         //% END\n",
+    )
+    .unwrap();
+    let injects = vec![inject];
+
+    assert_eq!(
+        missing_markers("//% IMPORTS\nclass MainActivity {}\n", &injects),
+        vec!["//% MAIN_ACTIVITY_ON_CREATE"]
+    );
+    assert_eq!(
+        missing_markers("class MainActivity {}\n", &injects),
+        vec!["//% IMPORTS", "//% MAIN_ACTIVITY_ON_CREATE"]
+    );
+    // The markers of the sections which the injects leave empty are optional
+    assert!(missing_markers(
+        "//% IMPORTS\nclass MainActivity {\n//% MAIN_ACTIVITY_ON_CREATE\n}\n",
+        &injects
+    )
+    .is_empty());
+
+    fs::remove_dir_all(&dir).unwrap();
 }
@@ -0,0 +1,331 @@
+use super::compile::LinkInfo;
+use super::util;
+use crate::config::{AndroidConfig, ManifestOverrides};
+use anyhow::format_err;
+use cargo::core::TargetKind;
+use cargo::util::CargoResult;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Summary of a build written as JSON next to the build artifacts, so that other commands and
+/// tools can find out what was produced and how.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BuildReport {
+    /// Version reported by each packaging tool, keyed by tool name
+    pub tool_versions: BTreeMap<String, String>,
+
+    /// APKs produced by the build
+    pub apks: Vec<ReportApk>,
+
+    /// Shared libraries copied out by a `--no-apk` build
+    #[serde(default)]
+    pub libraries: Vec<ReportLibrary>,
+
+    /// NDK files the library of each cargo target was linked with, for each ABI
+    #[serde(default)]
+    pub links: Vec<ReportLink>,
+
+    /// Manifest values overridden from the command line or the environment, with their source
+    #[serde(default)]
+    pub overrides: ManifestOverrides,
+
+    /// Cargo features enabled on the package
+    #[serde(default)]
+    pub features: Vec<String>,
+
+    /// Whether the default features were disabled
+    #[serde(default)]
+    pub no_default_features: bool,
+
+    /// Flavor selected with `--flavor`
+    #[serde(default)]
+    pub flavor: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReportApk {
+    /// `bin` or `example`
+    pub kind: String,
+    /// Name of the cargo target
+    pub name: String,
+    /// Path to the final APK
+    pub path: PathBuf,
+    /// ABI of the libraries of a split APK, none for the APK with every ABI
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub abi: Option<String>,
+    /// Hash of the features the APK was built with, see `AndroidConfig::features_fingerprint`
+    #[serde(default)]
+    pub features_fingerprint: String,
+    /// Dex files of the APK, none for apps without Java code
+    #[serde(default)]
+    pub dex: Vec<ReportDex>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReportDex {
+    /// Name of the dex file in the APK, like `classes.dex`
+    pub name: String,
+    pub methods: u32,
+    pub fields: u32,
+    pub classes: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReportLibrary {
+    /// `bin` or `example`
+    pub kind: String,
+    /// Name of the cargo target
+    pub name: String,
+    /// Android ABI of the library, like `arm64-v8a`
+    pub abi: String,
+    /// Path to the copied library
+    pub path: PathBuf,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ReportLink {
+    /// `bin` or `example`
+    pub kind: String,
+    /// Name of the cargo target
+    pub name: String,
+    /// Android ABI of the library, like `arm64-v8a`
+    pub abi: String,
+    /// Platform of the NDK libraries linked, which is lower than the platform of the clang
+    /// wrapper when the NDK has no libraries for it
+    pub platform: u32,
+    /// Directory of these libraries
+    pub platform_dir: PathBuf,
+    /// Clang wrapper of the ABI
+    pub clang: PathBuf,
+    pub linker: PathBuf,
+}
+
+fn kind_name(kind: &TargetKind) -> String {
+    match kind {
+        TargetKind::ExampleBin => "example",
+        _ => "bin",
+    }
+    .to_owned()
+}
+
+impl ReportApk {
+    pub fn new(
+        kind: &TargetKind,
+        name: &str,
+        path: &Path,
+        features_fingerprint: &str,
+    ) -> ReportApk {
+        ReportApk {
+            kind: kind_name(kind),
+            name: name.to_owned(),
+            path: path.to_owned(),
+            abi: None,
+            features_fingerprint: features_fingerprint.to_owned(),
+            dex: vec![],
+        }
+    }
+}
+
+impl ReportLibrary {
+    pub fn new(kind: &TargetKind, name: &str, abi: &str, path: &Path) -> ReportLibrary {
+        ReportLibrary {
+            kind: kind_name(kind),
+            name: name.to_owned(),
+            abi: abi.to_owned(),
+            path: path.to_owned(),
+        }
+    }
+}
+
+impl ReportLink {
+    pub fn new(kind: &TargetKind, name: &str, abi: &str, link: &LinkInfo) -> ReportLink {
+        ReportLink {
+            kind: kind_name(kind),
+            name: name.to_owned(),
+            abi: abi.to_owned(),
+            platform: link.platform,
+            platform_dir: link.platform_dir.clone(),
+            clang: link.clang.clone(),
+            linker: link.linker.clone(),
+        }
+    }
+}
+
+/// Returns the path of the build report within the root build directory
+pub fn report_path(root_build_dir: &Path) -> PathBuf {
+    root_build_dir.join("build-report.json")
+}
+
+fn previous_dir(root_build_dir: &Path) -> PathBuf {
+    root_build_dir.join("previous")
+}
+
+impl BuildReport {
+    pub fn write(&self, root_build_dir: &Path) -> CargoResult<()> {
+        fs::write(
+            report_path(root_build_dir),
+            serde_json::to_string_pretty(self)?,
+        )?;
+        Ok(())
+    }
+
+    /// Keeps a copy of the report and the APKs of the last build in the `previous` directory,
+    /// before a new build overwrites them
+    pub fn keep_previous(root_build_dir: &Path) -> CargoResult<()> {
+        let mut report = match BuildReport::read(root_build_dir) {
+            Ok(report) => report,
+            Err(_) => return Ok(()),
+        };
+        let previous_dir = previous_dir(root_build_dir);
+        util::clean_dir(&previous_dir)?;
+
+        report.apks.retain(|apk| apk.path.exists());
+        for apk in &mut report.apks {
+            let previous_path = previous_dir.join(apk.path.file_name().unwrap());
+            fs::copy(&apk.path, &previous_path)?;
+            apk.path = previous_path;
+        }
+        report.write(&previous_dir)
+    }
+
+    /// Reads the report of the build before the last one, see `keep_previous`
+    pub fn read_previous(root_build_dir: &Path) -> CargoResult<BuildReport> {
+        BuildReport::read(&previous_dir(root_build_dir)).map_err(|_| {
+            format_err!(
+                "No previous build found in '{}', build the APKs twice or give both APKs",
+                root_build_dir.to_string_lossy()
+            )
+        })
+    }
+
+    pub fn read(root_build_dir: &Path) -> CargoResult<BuildReport> {
+        let path = report_path(root_build_dir);
+        let content = fs::read_to_string(&path).map_err(|_| {
+            format_err!(
+                "Unable to read the build report '{}', build the APKs first",
+                path.to_string_lossy()
+            )
+        })?;
+        Ok(serde_json::from_str(&content)?)
+    }
+}
+
+/// Features an APK was built with, written next to it, so that installing checks the APK on disk
+/// rather than the last build
+#[derive(Debug, Serialize, Deserialize)]
+struct ApkFeatures {
+    /// See `AndroidConfig::features_fingerprint`
+    fingerprint: String,
+    features: Vec<String>,
+    no_default_features: bool,
+}
+
+fn apk_features_path(apk_path: &Path) -> PathBuf {
+    apk_path.with_extension("apk.features")
+}
+
+/// Removes the features recorded for an APK about to be replaced
+pub fn remove_apk_features(apk_path: &Path) -> CargoResult<()> {
+    let path = apk_features_path(apk_path);
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Records the features of `config` as those the APK was built with
+pub fn write_apk_features(apk_path: &Path, config: &AndroidConfig) -> CargoResult<()> {
+    let features = ApkFeatures {
+        fingerprint: config.features_fingerprint(),
+        features: config.cargo_features.iter().cloned().collect(),
+        no_default_features: config.no_default_features,
+    };
+    util::write_if_changed(
+        &apk_features_path(apk_path),
+        serde_json::to_string_pretty(&features)?,
+    )?;
+    Ok(())
+}
+
+/// Fails when the APK on disk wasn't recorded as built with the features of `config`
+pub fn check_apk_features(apk_path: &Path, config: &AndroidConfig) -> CargoResult<()> {
+    let recorded = fs::read_to_string(apk_features_path(apk_path))
+        .ok()
+        .and_then(|content| serde_json::from_str::<ApkFeatures>(&content).ok());
+    let built_with = match recorded {
+        Some(recorded) if recorded.fingerprint == config.features_fingerprint() => return Ok(()),
+        Some(recorded) => render_features(&recorded.features, recorded.no_default_features),
+        None => "unknown".to_owned(),
+    };
+    Err(format_err!(
+        "'{}' was built with the features ({}) instead of the current ones ({}), refusing to \
+         install it",
+        apk_path.display(),
+        built_with,
+        render_features(&config.cargo_features, config.no_default_features)
+    ))
+}
+
+/// Renders a set of features for messages and the build summary
+pub fn render_features<'a>(
+    features: impl IntoIterator<Item = &'a String>,
+    no_default_features: bool,
+) -> String {
+    let features = features.into_iter().map(String::as_str).collect::<Vec<_>>();
+    let mut rendered = if features.is_empty() {
+        "none".to_owned()
+    } else {
+        features.join(", ")
+    };
+    if no_default_features {
+        rendered.push_str(", without default features");
+    }
+    rendered
+}
+
+#[test]
+fn apk_features_on_disk() {
+    let dir = std::env::temp_dir().join(format!("cargo-quad-apk-features-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let apk_path = dir.join("app.apk");
+    fs::write(&apk_path, "apk").unwrap();
+
+    let mut config = crate::config::from_metadata("");
+    config.cargo_features.insert("default".to_owned());
+    // An APK from before features were recorded
+    assert!(check_apk_features(&apk_path, &config)
+        .unwrap_err()
+        .to_string()
+        .contains("with the features (unknown) instead of the current ones (default)"));
+
+    write_apk_features(&apk_path, &config).unwrap();
+    check_apk_features(&apk_path, &config).unwrap();
+
+    // Toggling a feature refuses the APK cached on disk, until it's built again
+    config.cargo_features.insert("full".to_owned());
+    assert_eq!(
+        check_apk_features(&apk_path, &config)
+            .unwrap_err()
+            .to_string(),
+        format!(
+            "'{}' was built with the features (default) instead of the current ones (default, \
+             full), refusing to install it",
+            apk_path.display()
+        )
+    );
+    write_apk_features(&apk_path, &config).unwrap();
+    check_apk_features(&apk_path, &config).unwrap();
+
+    config.cargo_features.clear();
+    config.no_default_features = true;
+    assert!(check_apk_features(&apk_path, &config).is_err());
+
+    // An APK being replaced is refused until its features are recorded
+    remove_apk_features(&apk_path).unwrap();
+    assert!(check_apk_features(&apk_path, &config).is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,505 @@
+//! Validation of the resource directories of the package.
+//!
+//! aapt skips resources with invalid names or in directories it doesn't understand with a
+//! warning at best, and the build then succeeds with resources missing, which the app only
+//! notices with a `Resources$NotFoundException`. The directories are checked against the naming
+//! rules of Android before packaging, and the warnings of aapt about them are promoted to errors.
+//!
+//! The resource directories of the app and of its dependencies are merged, the app's overriding
+//! the dependencies'. A resource declared twice otherwise is reported with both of its files, as
+//! aapt either keeps one of them silently or fails with only one of them named.
+
+use super::xml;
+use anyhow::format_err;
+use cargo::util::CargoResult;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Resource types of the directories in `res`
+const RESOURCE_TYPES: &[&str] = &[
+    "anim",
+    "animator",
+    "color",
+    "drawable",
+    "font",
+    "interpolator",
+    "layout",
+    "menu",
+    "mipmap",
+    "navigation",
+    "raw",
+    "transition",
+    "values",
+    "xml",
+];
+
+/// Qualifiers made of a fixed word
+const WORD_QUALIFIERS: &[&str] = &[
+    "ldrtl",
+    "ldltr",
+    "small",
+    "normal",
+    "large",
+    "xlarge",
+    "long",
+    "notlong",
+    "round",
+    "notround",
+    "widecg",
+    "nowidecg",
+    "highdr",
+    "lowdr",
+    "port",
+    "land",
+    "square",
+    "car",
+    "desk",
+    "television",
+    "appliance",
+    "watch",
+    "vrheadset",
+    "night",
+    "notnight",
+    "ldpi",
+    "mdpi",
+    "tvdpi",
+    "hdpi",
+    "xhdpi",
+    "xxhdpi",
+    "xxxhdpi",
+    "nodpi",
+    "anydpi",
+    "notouch",
+    "finger",
+    "stylus",
+    "keysexposed",
+    "keyshidden",
+    "keyssoft",
+    "nokeys",
+    "qwerty",
+    "12key",
+    "navexposed",
+    "navhidden",
+    "nonav",
+    "dpad",
+    "trackball",
+    "wheel",
+];
+
+/// Lint messages of aapt about resources which it then leaves out
+const AAPT_WARNINGS: &[&str] = &[
+    "invalid resource directory name",
+    "Invalid file name",
+    "is not a valid resource name",
+];
+
+#[derive(Debug, PartialEq)]
+pub struct ResourceProblem {
+    pub path: PathBuf,
+    pub rule: &'static str,
+}
+
+impl fmt::Display for ResourceProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.rule)
+    }
+}
+
+/// Whether `qualifier` is a configuration qualifier of a resource directory name
+fn is_qualifier(qualifier: &str) -> bool {
+    let number_after = |prefix: &str, suffix: &str| {
+        qualifier
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix(suffix))
+            .map_or(false, |digits| {
+                !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+            })
+    };
+    let lowercase = |s: &str, lengths: &[usize]| {
+        lengths.contains(&s.len()) && s.chars().all(|c| c.is_ascii_lowercase())
+    };
+
+    WORD_QUALIFIERS.contains(&qualifier)
+        // Language, region and BCP-47 tags
+        || lowercase(qualifier, &[2, 3])
+        || qualifier.strip_prefix('r').map_or(false, |region| {
+            (region.len() == 2 && region.chars().all(|c| c.is_ascii_uppercase()))
+                || (region.len() == 3 && region.chars().all(|c| c.is_ascii_digit()))
+        })
+        || qualifier.strip_prefix("b+").map_or(false, |tag| {
+            tag.split('+')
+                .all(|subtag| !subtag.is_empty() && subtag.chars().all(|c| c.is_ascii_alphanumeric()))
+        })
+        || number_after("mcc", "")
+        || number_after("mnc", "")
+        || number_after("sw", "dp")
+        || number_after("w", "dp")
+        || number_after("h", "dp")
+        || number_after("", "dpi")
+        || number_after("v", "")
+}
+
+/// Returns the rule broken by the name of a directory of `res`, if any
+fn directory_problem(name: &str) -> Option<&'static str> {
+    let mut parts = name.split('-');
+    if !RESOURCE_TYPES.contains(&parts.next().unwrap()) {
+        return Some("unknown resource type, expected a directory like `drawable` or `values-fr`");
+    }
+    if !parts.all(is_qualifier) {
+        return Some("invalid configuration qualifier in the directory name");
+    }
+    None
+}
+
+/// Returns the rule broken by the name of a resource file, if any
+fn file_problem(file_name: &str) -> Option<&'static str> {
+    let name = file_name.split('.').next().unwrap();
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+    {
+        Some("file names may only contain lowercase letters, digits and underscores")
+    } else if name.starts_with(|c: char| c.is_ascii_digit()) {
+        Some("file names can't start with a digit")
+    } else {
+        None
+    }
+}
+
+/// Checks every directory and file of a resource directory against the naming rules of
+/// Android. Hidden files are left out, as aapt ignores them.
+pub fn validate_res_dir(res_path: &Path) -> std::io::Result<Vec<ResourceProblem>> {
+    let is_hidden = |name: &str| name.starts_with('.');
+    let sorted_entries = |dir: &Path| -> std::io::Result<Vec<_>> {
+        let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        Ok(entries)
+    };
+
+    let mut problems = vec![];
+    for entry in sorted_entries(res_path)? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if is_hidden(&name) {
+            continue;
+        }
+        let path = entry.path();
+        if !entry.file_type()?.is_dir() {
+            problems.push(ResourceProblem {
+                path,
+                rule: "resources must be in a resource type directory, like `drawable`",
+            });
+            continue;
+        }
+        if let Some(rule) = directory_problem(&name) {
+            problems.push(ResourceProblem { path, rule });
+            continue;
+        }
+        let values = name == "values" || name.starts_with("values-");
+        for entry in sorted_entries(&path)? {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            if is_hidden(&file_name) {
+                continue;
+            }
+            let rule = if entry.file_type()?.is_dir() {
+                Some("resource directories can't have subdirectories")
+            } else if values {
+                // Only the resources declared inside are named
+                None
+            } else {
+                file_problem(&file_name)
+            };
+            if let Some(rule) = rule {
+                problems.push(ResourceProblem {
+                    path: entry.path(),
+                    rule,
+                });
+            }
+        }
+    }
+    Ok(problems)
+}
+
+/// Returns the resources declared by a resource directory with the file declaring them, named
+/// like `drawable-hdpi/icon` for files and `values-fr/string/app_name` for the resources of the
+/// values files. Files and directories left out by aapt are left out.
+fn declared_resources(res_path: &Path) -> CargoResult<Vec<(String, PathBuf)>> {
+    let sorted_entries = |dir: &Path| -> CargoResult<Vec<_>> {
+        let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+        entries.retain(|entry| !entry.file_name().to_string_lossy().starts_with('.'));
+        entries.sort_by_key(|entry| entry.file_name());
+        Ok(entries)
+    };
+
+    let mut resources = vec![];
+    for dir in sorted_entries(res_path)? {
+        let dir_name = dir.file_name().to_string_lossy().into_owned();
+        if !dir.file_type()?.is_dir() || directory_problem(&dir_name).is_some() {
+            continue;
+        }
+        let values = dir_name == "values" || dir_name.starts_with("values-");
+        for file in sorted_entries(&dir.path())? {
+            let path = file.path();
+            if file.file_type()?.is_dir() {
+                continue;
+            }
+            let file_name = file.file_name().to_string_lossy().into_owned();
+            if !values {
+                let name = file_name.split('.').next().unwrap();
+                resources.push((format!("{}/{}", dir_name, name), path));
+                continue;
+            }
+            if !file_name.ends_with(".xml") {
+                continue;
+            }
+            let root = xml::parse_skipping_text(&fs::read_to_string(&path)?)
+                .map_err(|err| format_err!("Invalid values file `{}`: {}", path.display(), err))?;
+            for element in root.elements() {
+                let resource_type = match element.name() {
+                    "item" => match element.attribute("type") {
+                        Some(resource_type) => resource_type,
+                        None => continue,
+                    },
+                    "string-array" | "integer-array" => "array",
+                    "declare-styleable" => "styleable",
+                    "eat-comment" | "skip" | "public" | "public-group" | "java-symbol" => continue,
+                    resource_type => resource_type,
+                };
+                if let Some(name) = element.attribute("name") {
+                    resources.push((
+                        format!("{}/{}/{}", dir_name, resource_type, name),
+                        path.clone(),
+                    ));
+                }
+            }
+        }
+    }
+    Ok(resources)
+}
+
+/// Returns the resources declared twice by the resource directories of the app, or twice by
+/// those of the dependencies, naming both files. Resources of the app override those of the
+/// dependencies, so declaring one in both is fine.
+pub fn duplicate_resources(
+    app_dirs: &[PathBuf],
+    dependency_dirs: &[PathBuf],
+) -> CargoResult<Vec<String>> {
+    let mut duplicates = vec![];
+    for dirs in &[app_dirs, dependency_dirs] {
+        let mut declared: HashMap<String, PathBuf> = HashMap::new();
+        for dir in dirs.iter() {
+            for (name, path) in declared_resources(dir)? {
+                match declared.get(&name) {
+                    Some(first) => duplicates.push(format!(
+                        "`{}` is declared by both `{}` and `{}`",
+                        name,
+                        first.display(),
+                        path.display()
+                    )),
+                    None => {
+                        declared.insert(name, path);
+                    }
+                }
+            }
+        }
+    }
+    Ok(duplicates)
+}
+
+/// Returns the lines of the output of aapt which warn about resources left out
+pub fn aapt_warnings(stderr: &str) -> Vec<String> {
+    stderr
+        .lines()
+        .filter(|line| AAPT_WARNINGS.iter().any(|warning| line.contains(warning)))
+        .map(|line| line.trim().to_owned())
+        .collect()
+}
+
+#[test]
+fn resource_qualifiers() {
+    for name in &[
+        "drawable",
+        "drawable-hdpi",
+        "values-fr",
+        "values-zh-rCN",
+        "values-es-r419",
+        "values-b+sr+Latn",
+        "layout-sw600dp-land",
+        "mipmap-anydpi-v26",
+        "values-night-v29",
+        "drawable-640dpi",
+        "values-mcc310-mnc004",
+        "xml",
+        "raw",
+    ] {
+        assert_eq!(directory_problem(name), None, "{}", name);
+    }
+    assert!(directory_problem("drawble")
+        .unwrap()
+        .contains("unknown resource type"));
+    assert!(directory_problem("Drawable")
+        .unwrap()
+        .contains("unknown resource type"));
+    for name in &[
+        "drawable-HDPI",
+        "values-fr-CA",
+        "layout-sw600",
+        "values-",
+        "drawable-v",
+    ] {
+        assert!(
+            directory_problem(name).unwrap().contains("qualifier"),
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn resource_file_names() {
+    assert_eq!(file_problem("icon.png"), None);
+    assert_eq!(file_problem("ic_launcher_2.9.png"), None);
+    assert_eq!(file_problem("_private.xml"), None);
+    assert!(file_problem("Icon.png").unwrap().contains("lowercase"));
+    assert!(file_problem("my-icon.png").unwrap().contains("lowercase"));
+    assert!(file_problem("my icon.png").unwrap().contains("lowercase"));
+    assert!(file_problem("9patch.png").unwrap().contains("digit"));
+    assert!(file_problem(".png").is_some());
+}
+
+#[test]
+fn validate_resource_tree() {
+    let root = std::env::temp_dir().join(format!("cargo-quad-apk-res-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let res = root.join("res");
+    for (dir, file) in &[
+        ("drawable-hdpi", "icon.png"),
+        ("drawable-hdpi", "Icon2.png"),
+        ("drawable", "app-logo.png"),
+        ("values-fr", "Strings.xml"),
+        ("values", ".DS_Store"),
+        ("drawable/nested", "icon.png"),
+        ("drawble", "icon.png"),
+        ("mipmap-hdpi-US", "icon.png"),
+        (".git", "HEAD"),
+    ] {
+        fs::create_dir_all(res.join(dir)).unwrap();
+        fs::write(res.join(dir).join(file), "").unwrap();
+    }
+    fs::write(res.join("icon.png"), "").unwrap();
+
+    let problems = validate_res_dir(&res).unwrap();
+    let rendered = problems
+        .iter()
+        .map(|problem| {
+            problem
+                .to_string()
+                .replace(&format!("{}/", res.display()), "")
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        rendered,
+        vec![
+            "drawable/app-logo.png: file names may only contain lowercase letters, digits and \
+             underscores",
+            "drawable/nested: resource directories can't have subdirectories",
+            "drawable-hdpi/Icon2.png: file names may only contain lowercase letters, digits and \
+             underscores",
+            "drawble: unknown resource type, expected a directory like `drawable` or `values-fr`",
+            "icon.png: resources must be in a resource type directory, like `drawable`",
+            "mipmap-hdpi-US: invalid configuration qualifier in the directory name",
+        ]
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn aapt_resource_warnings() {
+    let stderr = "\
+res/drawble: invalid resource directory name
+res/drawable/Icon.png: Invalid file name: must contain only [a-z0-9_.]
+Warning: AndroidManifest.xml already defines debuggable (in http://schemas.android.com/apk/res/android); using existing value in manifest.
+";
+    assert_eq!(
+        aapt_warnings(stderr),
+        vec![
+            "res/drawble: invalid resource directory name",
+            "res/drawable/Icon.png: Invalid file name: must contain only [a-z0-9_.]",
+        ]
+    );
+    assert!(aapt_warnings("").is_empty());
+}
+
+#[test]
+fn duplicate_resources_across_dirs() {
+    let root = std::env::temp_dir().join(format!("cargo-quad-apk-dup-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let strings = |names: &[&str]| {
+        let mut document = String::from("<resources>\n");
+        for name in names {
+            document.push_str(&format!("    <string name=\"{}\">Text</string>\n", name));
+        }
+        document + "</resources>\n"
+    };
+    for (path, contents) in &[
+        (
+            "app/res/values/strings.xml",
+            strings(&["app_name", "welcome"]),
+        ),
+        ("app/res/drawable/icon.png", String::new()),
+        ("app/extra/values/strings.xml", strings(&["welcome"])),
+        ("app/extra/values-fr/strings.xml", strings(&["app_name"])),
+        (
+            "dep/res/values/strings.xml",
+            strings(&["app_name", "dep_name"]),
+        ),
+        ("dep/res/drawable/icon.png", String::new()),
+        (
+            "dep/res/values/ids.xml",
+            "<resources><item type=\"id\" name=\"button\" /><eat-comment /></resources>".to_owned(),
+        ),
+        ("other/res/drawable-hdpi/icon.webp", String::new()),
+        ("other/res/drawable/icon.xml", String::new()),
+        ("other/res/layout/main.xml", "<LinearLayout />".to_owned()),
+        (
+            "other/res/values/ids.xml",
+            "<resources><item type=\"id\" name=\"button\"/><item name=\"unnamed\"/></resources>"
+                .to_owned(),
+        ),
+    ] {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    let duplicates = duplicate_resources(
+        &[root.join("app/res"), root.join("app/extra")],
+        &[root.join("dep/res"), root.join("other/res")],
+    )
+    .unwrap()
+    .into_iter()
+    .map(|duplicate| duplicate.replace(&format!("{}/", root.display()), ""))
+    .collect::<Vec<_>>();
+    assert_eq!(
+        duplicates,
+        vec![
+            "`values/string/welcome` is declared by both `app/res/values/strings.xml` and \
+             `app/extra/values/strings.xml`",
+            "`drawable/icon` is declared by both `dep/res/drawable/icon.png` and \
+             `other/res/drawable/icon.xml`",
+            "`values/id/button` is declared by both `dep/res/values/ids.xml` and \
+             `other/res/values/ids.xml`",
+        ]
+    );
+
+    fs::write(root.join("dep/res/values/ids.xml"), "<resources>").unwrap();
+    let error = duplicate_resources(&[], &[root.join("dep/res")])
+        .unwrap_err()
+        .to_string();
+    assert!(error.starts_with("Invalid values file `"), "{}", error);
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,666 @@
+//! Keys signing the APKs: the debug keystore, and the validity of its certificate, or the release
+//! keystore of `[package.metadata.android.signing]`.
+//!
+//! The debug keystore is shared with the Android SDK in `~/.android/debug.keystore`. Keystores
+//! made by other tooling may have a certificate valid for a year only, after which devices reject
+//! the APKs signed with it, so its expiry date is checked before signing.
+
+use super::apk::CommandRunner;
+use super::find_java_executable;
+use crate::config::SigningConfig;
+use anyhow::format_err;
+use cargo::util::CargoResult;
+use cargo_util::ProcessBuilder;
+use std::fmt;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::SystemTime;
+
+/// Days before the expiry of the certificate from which a warning is printed
+const EXPIRY_WARNING_DAYS: i64 = 30;
+
+const KEYTOOL_FILENAME: &str = if cfg!(target_os = "windows") {
+    "keytool.exe"
+} else {
+    "keytool"
+};
+
+/// Key given to `apksigner sign`
+#[derive(Debug, PartialEq)]
+pub struct SigningKey {
+    pub keystore: PathBuf,
+    pub key_alias: Option<String>,
+    /// Password in the syntax of apksigner, `pass:<password>` or `env:<variable>`
+    pub store_password: String,
+    pub key_password: Option<String>,
+}
+
+impl SigningKey {
+    pub fn debug(keystore: PathBuf) -> SigningKey {
+        SigningKey {
+            keystore,
+            key_alias: None,
+            store_password: "pass:android".to_owned(),
+            key_password: None,
+        }
+    }
+
+    /// Returns the release key, failing when the keystore or the environment variables of the
+    /// passwords are missing. apksigner reads the passwords from the environment itself, so that
+    /// they don't appear in its command line.
+    pub fn release(
+        signing: &SigningConfig,
+        is_set: impl Fn(&str) -> bool,
+    ) -> CargoResult<SigningKey> {
+        if !signing.keystore.is_file() {
+            return Err(format_err!(
+                "The release keystore `{}` of `[package.metadata.android.signing]` does not exist",
+                signing.keystore.display()
+            ));
+        }
+        let password = |variable: &str, what: &str| {
+            if is_set(variable) {
+                Ok(format!("env:{}", variable))
+            } else {
+                Err(format_err!(
+                    "The password of the release {} is read from the environment variable `{}`, \
+                     which is not set. Set it, or build with `--nosign` to sign the APKs later.",
+                    what,
+                    variable
+                ))
+            }
+        };
+        Ok(SigningKey {
+            keystore: signing.keystore.clone(),
+            key_alias: Some(signing.key_alias.clone()),
+            store_password: password(&signing.store_password_env, "keystore")?,
+            key_password: signing
+                .key_password_env
+                .as_ref()
+                .map(|variable| password(variable, "key"))
+                .transpose()?,
+        })
+    }
+}
+
+pub struct DebugKeystore {
+    pub path: PathBuf,
+    /// Whether the keystore was generated by this build
+    pub generated: bool,
+    /// Where the previous keystore was moved when it was regenerated
+    pub backup: Option<PathBuf>,
+}
+
+/// Find or generate a debug keystore for signing the APK
+/// We use the same debug keystore as used by the Android SDK. If it does not exist, or
+/// `regenerate` is set, then we create it using keytool which is part of the JRE/JDK
+pub fn debug_keystore(
+    runner: &dyn CommandRunner,
+    root_build_dir: &Path,
+    regenerate: bool,
+) -> CargoResult<DebugKeystore> {
+    let android_directory = dirs::home_dir()
+        .ok_or_else(|| format_err!("Unable to determine home directory"))?
+        .join(".android");
+    keystore_in(
+        &android_directory,
+        runner,
+        root_build_dir,
+        regenerate,
+        || find_java_executable(KEYTOOL_FILENAME),
+    )
+}
+
+/// Finds or generates `debug.keystore` in `android_directory`. Builds starting at the same
+/// time, like CI jobs sharing a home directory, are serialized by an exclusive lock on
+/// `debug.keystore.lock`, and the keystore is generated under a temporary name before being
+/// renamed into place, so that no build sees it half-written.
+fn keystore_in(
+    android_directory: &Path,
+    runner: &dyn CommandRunner,
+    root_build_dir: &Path,
+    regenerate: bool,
+    keytool_path: impl FnOnce() -> CargoResult<PathBuf>,
+) -> CargoResult<DebugKeystore> {
+    fs::create_dir_all(android_directory)?;
+    let keystore_path = android_directory.join("debug.keystore");
+
+    // Released when dropped
+    let lock = File::create(android_directory.join("debug.keystore.lock"))?;
+    lock.lock().map_err(|err| {
+        format_err!(
+            "Unable to lock the debug keystore `{}`: {}",
+            keystore_path.display(),
+            err
+        )
+    })?;
+
+    let mut backup = None;
+    if regenerate && keystore_path.exists() {
+        let backup_path = backup_path(&keystore_path);
+        fs::rename(&keystore_path, &backup_path)?;
+        backup = Some(backup_path);
+    }
+
+    // Checked with the lock held, another build may have generated it meanwhile
+    let generated = !keystore_path.exists();
+    if generated {
+        // Generate key
+        let temp_path = android_directory.join(format!("debug.keystore.{}.tmp", process::id()));
+        if temp_path.exists() {
+            fs::remove_file(&temp_path)?;
+        }
+        let result = runner.run(
+            ProcessBuilder::new(keytool_path()?)
+                .arg("-genkey")
+                .arg("-v")
+                .arg("-keystore")
+                .arg(&temp_path)
+                .arg("-storepass")
+                .arg("android")
+                .arg("-alias")
+                .arg("androidebugkey")
+                .arg("-keypass")
+                .arg("android")
+                .arg("-dname")
+                .arg("CN=Android Debug,O=Android,C=US")
+                .arg("-keyalg")
+                .arg("RSA")
+                .arg("-keysize")
+                .arg("2048")
+                .arg("-validity")
+                .arg("10000")
+                .cwd(root_build_dir),
+        );
+        if let Err(err) = result {
+            let _ = fs::remove_file(&temp_path);
+            return Err(err);
+        }
+        fs::rename(&temp_path, &keystore_path)?;
+    } else if let Some(problem) = keystore_problem(&fs::read(&keystore_path)?) {
+        return Err(format_err!(
+            "the debug keystore `{}` is corrupt ({}), APKs can't be signed with it. Regenerate \
+             it with `--regenerate-debug-key`, the current one is then kept as a backup.",
+            keystore_path.display(),
+            problem
+        ));
+    }
+    Ok(DebugKeystore {
+        path: keystore_path,
+        generated,
+        backup,
+    })
+}
+
+/// Returns why `contents` can't be a JKS or PKCS12 keystore, as written by keytool. Catches
+/// empty and truncated files left by an interrupted keytool.
+fn keystore_problem(contents: &[u8]) -> Option<&'static str> {
+    const JKS_MAGIC: [u8; 4] = [0xfe, 0xed, 0xfe, 0xed];
+
+    match contents.first() {
+        None => Some("empty file"),
+        Some(_) if contents.starts_with(&JKS_MAGIC) => {
+            // Magic, version, entry count and the final SHA-1 digest at least
+            if contents.len() < 4 + 4 + 4 + 20 {
+                Some("truncated JKS keystore")
+            } else {
+                None
+            }
+        }
+        // PKCS12 is a DER sequence, whose length tells the size of the file
+        Some(0x30) => match der_length(&contents[1..]) {
+            Some((length, header)) if 1 + header + length == contents.len() => None,
+            Some((length, header)) if 1 + header + length > contents.len() => {
+                Some("truncated PKCS12 keystore")
+            }
+            _ => Some("invalid PKCS12 keystore"),
+        },
+        Some(_) => Some("neither a JKS nor a PKCS12 keystore"),
+    }
+}
+
+/// Decodes a DER length, returning it along with the number of bytes it was encoded with
+fn der_length(bytes: &[u8]) -> Option<(usize, usize)> {
+    let first = *bytes.first()?;
+    if first < 0x80 {
+        return Some((first as usize, 1));
+    }
+    let count = (first & 0x7f) as usize;
+    if count == 0 || count > 4 || bytes.len() < 1 + count {
+        return None;
+    }
+    let length = bytes[1..=count]
+        .iter()
+        .fold(0, |length, &byte| (length << 8) | byte as usize);
+    Some((length, 1 + count))
+}
+
+/// Returns the first of `debug.keystore.bak`, `debug.keystore.bak.1`, ... which doesn't exist
+fn backup_path(keystore_path: &Path) -> PathBuf {
+    let file_name = keystore_path.file_name().unwrap().to_string_lossy();
+    (0..)
+        .map(|index| {
+            let name = match index {
+                0 => format!("{}.bak", file_name),
+                _ => format!("{}.bak.{}", file_name, index),
+            };
+            keystore_path.with_file_name(name)
+        })
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+/// Calendar date in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Date {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+}
+
+impl Date {
+    /// Returns the number of days since 1970-01-01
+    fn days_since_epoch(self) -> i64 {
+        let year = if self.month <= 2 {
+            self.year - 1
+        } else {
+            self.year
+        };
+        let era = if year >= 0 { year } else { year - 399 } / 400;
+        let year_of_era = year - era * 400;
+        let month = self.month as i64;
+        let day_of_year =
+            (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        era * 146097 + day_of_era - 719468
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+/// Returns the date until which the certificate of the debug key is valid, `None` when keytool
+/// can't be run or its output isn't understood
+pub fn certificate_valid_until(keystore: &Path) -> Option<Date> {
+    parse_valid_until(&list_debug_keystore(keystore)?)
+}
+
+/// Returns the alias of the debug key, which keystores of other tooling may name differently,
+/// `None` when keytool can't be run or its output isn't understood
+pub fn debug_key_alias(keystore: &Path) -> Option<String> {
+    parse_alias(&list_debug_keystore(keystore)?)
+}
+
+/// Returns the output of `keytool -list -v` for the debug keystore
+fn list_debug_keystore(keystore: &Path) -> Option<String> {
+    let keytool_path = find_java_executable(KEYTOOL_FILENAME).ok()?;
+    let output = ProcessBuilder::new(keytool_path)
+        // The dates are printed in the language of the JVM
+        .arg("-J-Duser.language=en")
+        .arg("-J-Duser.country=US")
+        .arg("-list")
+        .arg("-v")
+        .arg("-keystore")
+        .arg(keystore)
+        .arg("-storepass")
+        .arg("android")
+        .exec_with_output()
+        .ok()?;
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Parses the alias of the first key listed by `keytool -list -v`
+fn parse_alias(output: &str) -> Option<String> {
+    output
+        .lines()
+        .find_map(|line| line.strip_prefix("Alias name:"))
+        .map(|alias| alias.trim().to_owned())
+}
+
+/// Parses the end of the validity of the first certificate listed by `keytool -list -v`, from a
+/// line like `Valid from: Tue Mar 05 10:12:13 UTC 2024 until: Wed Mar 05 10:12:13 UTC 2025`
+fn parse_valid_until(output: &str) -> Option<Date> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let line = output
+        .lines()
+        .find(|line| line.trim_start().starts_with("Valid from:"))?;
+    let until = &line[line.find("until:")? + "until:".len()..];
+    // Day of the week, month, day, time, time zone and year
+    let fields = until.split_whitespace().collect::<Vec<_>>();
+    if fields.len() != 6 {
+        return None;
+    }
+    let month = MONTHS.iter().position(|month| *month == fields[1])? as u32 + 1;
+    Some(Date {
+        year: fields[5].parse().ok()?,
+        month,
+        day: fields[2].parse().ok()?,
+    })
+}
+
+/// Returns the number of days since 1970-01-01 of the current date
+pub fn today() -> i64 {
+    let seconds = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    (seconds / 86400) as i64
+}
+
+/// Returns a warning when the certificate of the keystore has expired or expires within
+/// `EXPIRY_WARNING_DAYS` of `today`
+pub fn expiry_warning(keystore: &Path, valid_until: Date, today: i64) -> Option<String> {
+    let days_left = valid_until.days_since_epoch() - today;
+    if days_left < 0 {
+        Some(format!(
+            "the certificate of the debug keystore `{}` expired on {}, devices refuse to install \
+             APKs signed with it. Regenerate it with `--regenerate-debug-key`.",
+            keystore.display(),
+            valid_until
+        ))
+    } else if days_left <= EXPIRY_WARNING_DAYS {
+        Some(format!(
+            "the certificate of the debug keystore `{}` expires in {} day{} on {}, devices will \
+             then refuse to install APKs signed with it. Regenerate it with \
+             `--regenerate-debug-key`.",
+            keystore.display(),
+            days_left,
+            if days_left == 1 { "" } else { "s" },
+            valid_until
+        ))
+    } else {
+        None
+    }
+}
+
+#[test]
+fn keytool_validity() {
+    // JDK 8
+    let jdk8 = "\
+Keystore type: JKS
+Keystore provider: SUN
+
+Your keystore contains 1 entry
+
+Alias name: androiddebugkey
+Creation date: Mar 5, 2024
+Entry type: PrivateKeyEntry
+Certificate chain length: 1
+Certificate[1]:
+Owner: C=US, O=Android, CN=Android Debug
+Issuer: C=US, O=Android, CN=Android Debug
+Serial number: 1
+Valid from: Tue Mar 05 10:12:13 UTC 2024 until: Wed Mar 05 10:12:13 UTC 2025
+Certificate fingerprints:
+\t SHA1: 5A:2B:...
+";
+    assert_eq!(
+        parse_valid_until(jdk8),
+        Some(Date {
+            year: 2025,
+            month: 3,
+            day: 5
+        })
+    );
+
+    // JDK 17, with a PKCS12 keystore
+    let jdk17 = "\
+Keystore type: PKCS12
+Keystore provider: SUN
+
+Alias name: androidebugkey
+Creation date: Jan 10, 2020
+Entry type: PrivateKeyEntry
+Certificate chain length: 1
+Certificate[1]:
+Owner: CN=Android Debug, O=Android, C=US
+Issuer: CN=Android Debug, O=Android, C=US
+Serial number: 3c8e1f0d
+Valid from: Fri Jan 10 09:30:00 CET 2020 until: Tue May 28 09:30:00 CEST 2047
+Certificate fingerprints:
+\t SHA256: 1F:...
+Signature algorithm name: SHA256withRSA
+";
+    assert_eq!(
+        parse_valid_until(jdk17),
+        Some(Date {
+            year: 2047,
+            month: 5,
+            day: 28
+        })
+    );
+
+    assert_eq!(parse_alias(jdk8).as_deref(), Some("androiddebugkey"));
+    assert_eq!(parse_alias(jdk17).as_deref(), Some("androidebugkey"));
+
+    assert_eq!(
+        parse_valid_until("keytool error: java.io.IOException"),
+        None
+    );
+    assert_eq!(parse_alias("keytool error: java.io.IOException"), None);
+    assert_eq!(
+        parse_valid_until("Valid from: Tue Mar 05 10:12:13 UTC 2024 until: soon"),
+        None
+    );
+}
+
+#[test]
+fn certificate_expiry() {
+    let keystore = Path::new("/home/user/.android/debug.keystore");
+    let valid_until = Date {
+        year: 2025,
+        month: 3,
+        day: 5,
+    };
+    assert_eq!(valid_until.days_since_epoch(), 20152);
+    assert_eq!(
+        Date {
+            year: 1970,
+            month: 1,
+            day: 1
+        }
+        .days_since_epoch(),
+        0
+    );
+
+    assert_eq!(expiry_warning(keystore, valid_until, 20152 - 31), None);
+    assert_eq!(
+        expiry_warning(keystore, valid_until, 20152 - 30).unwrap(),
+        "the certificate of the debug keystore `/home/user/.android/debug.keystore` expires in \
+         30 days on 2025-03-05, devices will then refuse to install APKs signed with it. \
+         Regenerate it with `--regenerate-debug-key`."
+    );
+    assert!(expiry_warning(keystore, valid_until, 20151)
+        .unwrap()
+        .contains("expires in 1 day on"));
+    assert!(expiry_warning(keystore, valid_until, 20152)
+        .unwrap()
+        .contains("expires in 0 days"));
+    assert_eq!(
+        expiry_warning(keystore, valid_until, 20153).unwrap(),
+        "the certificate of the debug keystore `/home/user/.android/debug.keystore` expired on \
+         2025-03-05, devices refuse to install APKs signed with it. Regenerate it with \
+         `--regenerate-debug-key`."
+    );
+}
+
+#[test]
+fn keystore_backup_path() {
+    let root = std::env::temp_dir().join(format!("cargo-quad-apk-signing-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    let keystore = root.join("debug.keystore");
+
+    assert_eq!(backup_path(&keystore), root.join("debug.keystore.bak"));
+    fs::write(root.join("debug.keystore.bak"), "").unwrap();
+    assert_eq!(backup_path(&keystore), root.join("debug.keystore.bak.1"));
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+/// Runs keytool by writing a PKCS12-shaped keystore to the `-keystore` path, slowly
+#[cfg(test)]
+struct StubKeytool {
+    runs: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+}
+
+#[cfg(test)]
+impl CommandRunner for StubKeytool {
+    fn run(&self, cmd: &ProcessBuilder) -> CargoResult<()> {
+        use std::io::Write;
+
+        self.runs.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let args = cmd.get_args().collect::<Vec<_>>();
+        let keystore =
+            Path::new(args[args.iter().position(|arg| *arg == "-keystore").unwrap() + 1]);
+        assert_ne!(keystore.file_name().unwrap(), "debug.keystore");
+        let mut file = File::create(keystore)?;
+        for chunk in &[[0x30, 0x04], [1, 2], [3, 4]] {
+            file.write_all(chunk)?;
+            file.flush()?;
+            // Another build seeing the keystore now would find it truncated
+            assert!(!keystore.with_file_name("debug.keystore").exists());
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn concurrent_keystore_generation() {
+    let root = std::env::temp_dir().join(format!(
+        "cargo-quad-apk-keystore-lock-{}",
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&root);
+    let runs = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+    let threads = (0..2)
+        .map(|_| {
+            let root = root.clone();
+            let runs = runs.clone();
+            std::thread::spawn(move || {
+                keystore_in(&root, &StubKeytool { runs }, &root, false, || {
+                    Ok(PathBuf::from("keytool"))
+                })
+                .unwrap()
+            })
+        })
+        .collect::<Vec<_>>();
+    let keystores = threads
+        .into_iter()
+        .map(|thread| thread.join().unwrap())
+        .collect::<Vec<_>>();
+
+    // Only the first build generated it, the second waited for it
+    assert_eq!(runs.load(std::sync::atomic::Ordering::SeqCst), 1);
+    assert_eq!(
+        keystores
+            .iter()
+            .filter(|keystore| keystore.generated)
+            .count(),
+        1
+    );
+    assert_eq!(
+        fs::read(root.join("debug.keystore")).unwrap(),
+        [0x30, 0x04, 1, 2, 3, 4]
+    );
+    let mut files = fs::read_dir(&root)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    files.sort();
+    assert_eq!(files, vec!["debug.keystore", "debug.keystore.lock"]);
+
+    // A truncated keystore is reported, and regenerating it keeps it as a backup
+    fs::write(root.join("debug.keystore"), [0x30, 0x04, 1]).unwrap();
+    let stub = StubKeytool { runs: runs.clone() };
+    let keytool = || Ok(PathBuf::from("keytool"));
+    assert!(keystore_in(&root, &stub, &root, false, keytool)
+        .err()
+        .unwrap()
+        .to_string()
+        .contains("is corrupt (truncated PKCS12 keystore)"));
+    let keystore = keystore_in(&root, &stub, &root, true, keytool).unwrap();
+    assert!(keystore.generated);
+    assert_eq!(keystore.backup, Some(root.join("debug.keystore.bak")));
+    assert_eq!(runs.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn corrupt_keystores() {
+    assert_eq!(keystore_problem(&[]), Some("empty file"));
+    assert_eq!(
+        keystore_problem(b"keytool error"),
+        Some("neither a JKS nor a PKCS12 keystore")
+    );
+    assert_eq!(
+        keystore_problem(&[0xfe, 0xed, 0xfe, 0xed, 0, 0, 0, 2]),
+        Some("truncated JKS keystore")
+    );
+    assert_eq!(keystore_problem(&[0xfe, 0xed, 0xfe, 0xed].repeat(10)), None);
+
+    // Long form length of 0x0102 bytes
+    let mut pkcs12 = vec![0x30, 0x82, 0x01, 0x02];
+    pkcs12.resize(4 + 0x0102, 0);
+    assert_eq!(keystore_problem(&pkcs12), None);
+    assert_eq!(
+        keystore_problem(&pkcs12[..100]),
+        Some("truncated PKCS12 keystore")
+    );
+    pkcs12.push(0);
+    assert_eq!(keystore_problem(&pkcs12), Some("invalid PKCS12 keystore"));
+}
+
+#[test]
+fn release_signing_key() {
+    let keystore =
+        std::env::temp_dir().join(format!("cargo-quad-apk-release-{}.jks", std::process::id()));
+    fs::write(&keystore, "").unwrap();
+    let mut signing = SigningConfig {
+        keystore: keystore.clone(),
+        key_alias: "upload".to_owned(),
+        store_password_env: "STORE_PASSWORD".to_owned(),
+        key_password_env: None,
+    };
+
+    assert_eq!(
+        SigningKey::release(&signing, |_| true).unwrap(),
+        SigningKey {
+            keystore: keystore.clone(),
+            key_alias: Some("upload".to_owned()),
+            store_password: "env:STORE_PASSWORD".to_owned(),
+            key_password: None,
+        }
+    );
+
+    signing.key_password_env = Some("KEY_PASSWORD".to_owned());
+    let key = SigningKey::release(&signing, |_| true).unwrap();
+    assert_eq!(key.key_password.as_deref(), Some("env:KEY_PASSWORD"));
+
+    let err = SigningKey::release(&signing, |variable| variable == "STORE_PASSWORD").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "The password of the release key is read from the environment variable `KEY_PASSWORD`, \
+         which is not set. Set it, or build with `--nosign` to sign the APKs later."
+    );
+    assert!(SigningKey::release(&signing, |_| false)
+        .unwrap_err()
+        .to_string()
+        .contains("`STORE_PASSWORD`"));
+
+    fs::remove_file(&keystore).unwrap();
+    assert!(SigningKey::release(&signing, |_| true)
+        .unwrap_err()
+        .to_string()
+        .contains("does not exist"));
+}
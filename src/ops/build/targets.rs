@@ -1,6 +1,92 @@
 use crate::config::AndroidBuildTarget;
+use anyhow::format_err;
+use cargo::util::CargoResult;
+
+/// Short names accepted in `build_targets`, besides the rust triples and the ABIs
+const ALIASES: &[(&str, AndroidBuildTarget)] = &[
+    ("arm", AndroidBuildTarget::ArmV7a),
+    ("arm64", AndroidBuildTarget::Arm64V8a),
+    ("x86", AndroidBuildTarget::X86),
+    ("x64", AndroidBuildTarget::X86_64),
+];
 
 impl AndroidBuildTarget {
+    pub const ALL: [AndroidBuildTarget; 4] = [
+        AndroidBuildTarget::ArmV7a,
+        AndroidBuildTarget::Arm64V8a,
+        AndroidBuildTarget::X86,
+        AndroidBuildTarget::X86_64,
+    ];
+
+    /// Parses an entry of `build_targets`, either a rust triple, an ABI or one of their aliases
+    pub fn from_name(name: &str) -> CargoResult<AndroidBuildTarget> {
+        let target = AndroidBuildTarget::ALL
+            .iter()
+            .copied()
+            .find(|target| name == target.rust_triple() || name == target.android_abi())
+            .or_else(|| {
+                ALIASES
+                    .iter()
+                    .find(|(alias, _)| *alias == name)
+                    .map(|&(_, target)| target)
+            });
+        target.ok_or_else(|| {
+            let mut message = format!(
+                "unknown build target `{}`, expected a rust triple or an ABI:\n",
+                name
+            );
+            for target in &AndroidBuildTarget::ALL {
+                message.push_str(&format!(
+                    "\n    {:<26}{}",
+                    target.rust_triple(),
+                    target.android_abi()
+                ));
+            }
+            message.push_str(&format!(
+                "\n\nor one of the aliases {}",
+                ALIASES
+                    .iter()
+                    .map(|(alias, _)| *alias)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+            format_err!(message)
+        })
+    }
+
+    /// Parses the entries of `build_targets`, keeping the first of those naming the same target.
+    /// Returns the targets with a warning for each target named more than once.
+    pub fn from_names(names: &[String]) -> CargoResult<(Vec<AndroidBuildTarget>, Vec<String>)> {
+        let mut targets: Vec<(AndroidBuildTarget, Vec<&str>)> = Vec::new();
+        for name in names {
+            let target = AndroidBuildTarget::from_name(name)?;
+            match targets.iter_mut().find(|(known, _)| *known == target) {
+                Some((_, target_names)) => target_names.push(name),
+                None => targets.push((target, vec![name])),
+            }
+        }
+        let warnings = targets
+            .iter()
+            .filter(|(_, target_names)| target_names.len() > 1)
+            .map(|(target, target_names)| {
+                format!(
+                    "`build_targets` names {} {} times ({}), building it once",
+                    target.android_abi(),
+                    target_names.len(),
+                    target_names
+                        .iter()
+                        .map(|name| format!("`{}`", name))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+            .collect();
+        Ok((
+            targets.into_iter().map(|(target, _)| target).collect(),
+            warnings,
+        ))
+    }
+
     /// Identifier used in the NDK to refer to the ABI
     pub fn android_abi(self) -> &'static str {
         match self {
@@ -51,3 +137,91 @@ impl AndroidBuildTarget {
         }
     }
 }
+
+#[test]
+fn build_target_names() {
+    use AndroidBuildTarget::*;
+
+    let names = [
+        ("armv7-linux-androideabi", ArmV7a),
+        ("armeabi-v7a", ArmV7a),
+        ("arm", ArmV7a),
+        ("aarch64-linux-android", Arm64V8a),
+        ("arm64-v8a", Arm64V8a),
+        ("arm64", Arm64V8a),
+        ("i686-linux-android", X86),
+        ("x86", X86),
+        ("x86_64-linux-android", X86_64),
+        ("x86_64", X86_64),
+        ("x64", X86_64),
+    ];
+    for &(name, target) in &names {
+        assert_eq!(
+            AndroidBuildTarget::from_name(name).unwrap(),
+            target,
+            "{}",
+            name
+        );
+    }
+    // Every triple and ABI is accepted
+    for target in &AndroidBuildTarget::ALL {
+        for name in &[target.rust_triple(), target.android_abi()] {
+            assert!(names.contains(&(*name, *target)), "{}", name);
+        }
+    }
+
+    for name in &[
+        "aarch64",
+        "ARM64",
+        "armv7a-linux-androideabi",
+        "arm64-v8a ",
+        "",
+    ] {
+        assert!(AndroidBuildTarget::from_name(name).is_err(), "{}", name);
+    }
+    assert_eq!(
+        AndroidBuildTarget::from_name("aarch64-linux-gnu")
+            .unwrap_err()
+            .to_string(),
+        "unknown build target `aarch64-linux-gnu`, expected a rust triple or an ABI:\n\
+         \n    armv7-linux-androideabi   armeabi-v7a\
+         \n    aarch64-linux-android     arm64-v8a\
+         \n    i686-linux-android        x86\
+         \n    x86_64-linux-android      x86_64\
+         \n\nor one of the aliases arm, arm64, x86, x64"
+    );
+}
+
+#[test]
+fn duplicate_build_targets() {
+    use AndroidBuildTarget::*;
+
+    let names = |names: &[&str]| {
+        names
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        AndroidBuildTarget::from_names(&names(&["arm64-v8a", "x86", "armeabi-v7a"])).unwrap(),
+        (vec![Arm64V8a, X86, ArmV7a], vec![])
+    );
+    assert_eq!(
+        AndroidBuildTarget::from_names(&names(&[
+            "aarch64-linux-android",
+            "x64",
+            "arm64",
+            "arm64-v8a",
+        ]))
+        .unwrap(),
+        (
+            vec![Arm64V8a, X86_64],
+            vec![
+                "`build_targets` names arm64-v8a 3 times (`aarch64-linux-android`, `arm64`, \
+                 `arm64-v8a`), building it once"
+                    .to_owned()
+            ]
+        )
+    );
+    assert!(AndroidBuildTarget::from_names(&names(&["arm64", "mips"])).is_err());
+}
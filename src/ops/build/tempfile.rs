@@ -1,6 +1,33 @@
 use cargo::util::CargoResult;
+use std::collections::BTreeSet;
 use std::fs::{self, File};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Files removed when the build is interrupted: the temporary files and the APKs being written
+static REGISTRY: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// Registers a file to remove if the build is interrupted before it is unregistered
+pub fn register(path: &Path) {
+    REGISTRY.lock().unwrap().insert(path.to_owned());
+}
+
+pub fn unregister(path: &Path) {
+    REGISTRY.lock().unwrap().remove(path);
+}
+
+/// Removes the registered files which exist, returning them
+pub fn remove_registered() -> Vec<PathBuf> {
+    // Not unwrapped, as this runs while another thread may have panicked with the lock held
+    let paths = match REGISTRY.lock() {
+        Ok(mut registry) => std::mem::take(&mut *registry),
+        Err(poisoned) => std::mem::take(&mut *poisoned.into_inner()),
+    };
+    paths
+        .into_iter()
+        .filter(|path| fs::remove_file(path).is_ok())
+        .collect()
+}
 
 /// Temporary file implementation that allows creating a file with a specified path which
 /// will be deleted when dropped.
@@ -16,6 +43,7 @@ impl TempFile {
     where
         F: FnOnce(&mut File) -> CargoResult<()>,
     {
+        register(&path);
         let tmp_file = TempFile { path };
 
         // Write the contents to the the temp file
@@ -28,6 +56,7 @@ impl TempFile {
 
 impl Drop for TempFile {
     fn drop(&mut self) {
+        unregister(&self.path);
         fs::remove_file(&self.path).unwrap_or_else(|e| {
             eprintln!(
                 "Unable to remove temporary file: {}. {}",
@@ -37,3 +66,32 @@ impl Drop for TempFile {
         })
     }
 }
+
+#[test]
+fn registered_temp_files_cleanup() {
+    let root = std::env::temp_dir().join(format!("cargo-quad-apk-tempfile-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+
+    let glue = TempFile::new(root.join("glue.c"), |_| Ok(())).unwrap();
+    let apk = root.join("app.apk.partial");
+    fs::write(&apk, "").unwrap();
+    register(&apk);
+    // Registered before being written, the interruption came first
+    register(&root.join("never-written.apk.partial"));
+    let finished = root.join("finished.apk.partial");
+    fs::write(&finished, "").unwrap();
+    register(&finished);
+    unregister(&finished);
+
+    let mut removed = remove_registered();
+    removed.sort();
+    assert_eq!(removed, vec![apk.clone(), root.join("glue.c")]);
+    assert!(!apk.exists() && !glue.path.exists());
+    assert!(finished.exists());
+    assert!(remove_registered().is_empty());
+
+    // Dropping a removed temp file only complains
+    drop(glue);
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,121 @@
+//! Filtering of the output of the packaging tools, which is captured instead of printed.
+
+/// Warnings the JVM running apksigner prints about itself, in which nothing is about the APK
+const JVM_NOISE: &[&str] = &[
+    // JDK 24 and later, for the native library of Conscrypt
+    "A restricted method in java.lang.System has been called",
+    "has been called by",
+    "--enable-native-access",
+    "Restricted methods will be blocked in a future release",
+    // JDK 9 to 16
+    "An illegal reflective access operation has occurred",
+    "Illegal reflective access by",
+    "Please consider reporting this to the maintainers of",
+    "--illegal-access=warn",
+    "All illegal access operations will be denied in a future release",
+];
+
+/// Returns the warnings of the output of `apksigner sign`, without their `WARNING: ` prefix.
+/// The JVM's own warnings are left out, other lines are kept as they are.
+pub fn apksigner_warnings(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty())
+        // `_JAVA_OPTIONS` and `JAVA_TOOL_OPTIONS` echoed by the JVM
+        .filter(|line| !line.starts_with("Picked up "))
+        .filter_map(|line| match line.strip_prefix("WARNING: ") {
+            Some(warning) if JVM_NOISE.iter().any(|noise| warning.contains(noise)) => None,
+            Some(warning) => Some(warning.to_owned()),
+            None => Some(line.to_owned()),
+        })
+        .collect()
+}
+
+/// Returns the lines of the output of `zipalign -c -v` worth showing: the entries which aren't
+/// aligned and the verdict, without the header and the line of every aligned entry
+pub fn zipalign_verdict(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .filter(|line| !line.starts_with("Verifying alignment of "))
+        .filter(|line| !(line.ends_with("(OK)") || line.ends_with("(OK - compressed)")))
+        .map(str::to_owned)
+        .collect()
+}
+
+#[test]
+fn apksigner_output() {
+    // build-tools 30.0.3 on JDK 11
+    let output = "\
+WARNING: An illegal reflective access operation has occurred
+WARNING: Illegal reflective access by org.conscrypt.Platform (file:/sdk/build-tools/30.0.3/lib/apksigner.jar) to method sun.security.x509.AlgorithmId.get(java.lang.String)
+WARNING: Please consider reporting this to the maintainers of org.conscrypt.Platform
+WARNING: Use --illegal-access=warn to enable warnings of further illegal reflective access operations
+WARNING: All illegal access operations will be denied in a future release
+";
+    assert!(apksigner_warnings(output).is_empty());
+
+    // build-tools 35.0.0 on JDK 24
+    let output = "\
+Picked up JAVA_TOOL_OPTIONS: -Dfile.encoding=UTF8
+WARNING: A restricted method in java.lang.System has been called
+WARNING: java.lang.System::loadLibrary has been called by org.conscrypt.NativeLibraryUtil in an unnamed module (file:/sdk/build-tools/35.0.0/lib/apksigner.jar)
+WARNING: Use --enable-native-access=ALL-UNNAMED to avoid a warning for callers in this module
+WARNING: Restricted methods will be blocked in a future release unless native access is enabled
+
+WARNING: META-INF/versions/9/module-info.class not protected by signature. Unauthorized modifications to this JAR entry will not be detected. Delete or move the entry outside of META-INF/.
+";
+    assert_eq!(
+        apksigner_warnings(output),
+        vec![
+            "META-INF/versions/9/module-info.class not protected by signature. Unauthorized \
+             modifications to this JAR entry will not be detected. Delete or move the entry \
+             outside of META-INF/."
+        ]
+    );
+
+    // Unknown lines are kept verbatim
+    assert_eq!(
+        apksigner_warnings("WARNING: v4 signature skipped\nKey is only 1024 bits long\n"),
+        vec!["v4 signature skipped", "Key is only 1024 bits long"]
+    );
+    assert!(apksigner_warnings("").is_empty());
+}
+
+#[test]
+fn zipalign_output() {
+    // build-tools 30.0.3
+    let output = "\
+Verifying alignment of app.apk (4)...
+      50 AndroidManifest.xml (OK - compressed)
+    1048 classes.dex (OK - compressed)
+    4096 lib/arm64-v8a/libapp.so (OK)
+ 1843200 resources.arsc (OK)
+Verification succesful
+";
+    assert_eq!(zipalign_verdict(output), vec!["Verification succesful"]);
+
+    // build-tools 35.0.0, checking pages of 16 KiB with `-P 16`
+    let output = "\
+Verifying alignment of /target/android-artifacts/release/apk/app.apk.partial (4)...
+      50 AndroidManifest.xml (OK - compressed)
+   16384 lib/arm64-v8a/libapp.so (OK)
+ 4210788 lib/x86_64/libapp.so (BAD - 4100)
+ 8405092 resources.arsc (OK)
+Verification FAILED
+";
+    assert_eq!(
+        zipalign_verdict(output),
+        vec![
+            "4210788 lib/x86_64/libapp.so (BAD - 4100)",
+            "Verification FAILED"
+        ]
+    );
+
+    assert_eq!(
+        zipalign_verdict("Unable to open 'app.apk' as zip archive\n"),
+        vec!["Unable to open 'app.apk' as zip archive"]
+    );
+}
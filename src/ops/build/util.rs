@@ -1,12 +1,15 @@
-use crate::config::{AndroidBuildTarget, AndroidConfig};
+use crate::config::{AndroidBuildTarget, AndroidConfig, AndroidFeature, TomlFeature};
+use crate::error::{FailureKind, ResultExt};
 use anyhow::format_err;
+use cargo::core::resolver::{features::FeaturesFor, CliFeatures};
 use cargo::core::{Target, TargetKind, Workspace};
 use cargo::util::CargoResult;
-use cargo_util::ProcessBuilder;
+use cargo_util::{ProcessBuilder, Sha256};
 use std::{
+    collections::{BTreeMap, BTreeSet},
     ffi::OsStr,
-    fs::File,
-    io::Read,
+    fs::{self, File},
+    io::{Read, Write},
     path::{Path, PathBuf},
 };
 
@@ -15,12 +18,30 @@ use serde::Deserialize;
 /// Returns the directory in which all cargo apk artifacts for the current
 /// debug/release configuration should be produced.
 pub fn get_root_build_directory(workspace: &Workspace, config: &AndroidConfig) -> PathBuf {
+    flavor_build_directory(
+        root_build_directory(workspace, config.release),
+        config.flavor.as_deref(),
+    )
+}
+
+/// Returns the directory of the APKs and generated files of a flavor within the root build
+/// directory, the root build directory itself without flavor. The libraries are compiled in the
+/// root build directory for every flavor.
+pub fn flavor_build_directory(root_build_dir: PathBuf, flavor: Option<&str>) -> PathBuf {
+    match flavor {
+        Some(flavor) => root_build_dir.join("flavors").join(flavor),
+        None => root_build_dir,
+    }
+}
+
+/// Same as `get_root_build_directory`, for commands which don't need the android config
+pub fn root_build_directory(workspace: &Workspace, release: bool) -> PathBuf {
     let android_artifacts_dir = workspace
         .target_dir()
         .join("android-artifacts")
         .into_path_unlocked();
 
-    if config.release {
+    if release {
         android_artifacts_dir.join("release")
     } else {
         android_artifacts_dir.join("debug")
@@ -55,76 +76,220 @@ pub fn llvm_toolchain_root(config: &AndroidConfig) -> PathBuf {
 }
 
 // Helper function for looking for a path based on the platform version
-// Calls a closure for each attempt and then return the PathBuf for the first file that exists.
+// Calls a closure for each attempt and then returns the platform of the first file that exists.
 // Uses approach that NDK build tools use which is described at:
 // https://developer.android.com/ndk/guides/application_mk
 // " - The platform version matching APP_PLATFORM.
 //   - The next available API level below APP_PLATFORM. For example, android-19 will be used when
 //     APP_PLATFORM is android-20, since there were no new native APIs in android-20.
 //   - The minimum API level supported by the NDK."
-pub fn find_ndk_path<F>(platform: u32, path_builder: F) -> CargoResult<PathBuf>
+pub fn find_ndk_platform<F>(platform: u32, path_builder: F) -> CargoResult<u32>
 where
     F: Fn(u32) -> PathBuf,
 {
-    let mut tmp_platform = platform;
-
     // Look for the file which matches the specified platform
     // If that doesn't exist, look for a lower version
-    while tmp_platform > 1 {
-        let path = path_builder(tmp_platform);
-        if path.exists() {
-            return Ok(path);
-        }
+    // If that doesn't exist... Look for a higher one. This would be the minimum API level
+    // supported by the NDK
+    (1..=platform)
+        .rev()
+        .chain(platform + 1..100)
+        .find(|&platform| path_builder(platform).exists())
+        .ok_or_else(|| format_err!("Unable to find NDK file"))
+        .failure_kind(FailureKind::Environment)
+}
+
+/// API levels used by the steps of a build for an ABI, computed once per build so that the
+/// compiler, the linker, CMake and d8 can't settle on different values
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EffectiveApiLevels {
+    /// `min_sdk_version`, claimed by the manifest
+    pub requested_min: u32,
+    /// Platform of the NDK clang wrappers and sysroot libraries the native code is built for
+    pub ndk_platform: u32,
+    /// `--min-api` of d8
+    pub dex_min_api: u32,
+}
 
-        tmp_platform -= 1;
+impl EffectiveApiLevels {
+    pub fn new(config: &AndroidConfig, build_target: AndroidBuildTarget) -> CargoResult<Self> {
+        let bin_folder = llvm_toolchain_root(config).join("bin");
+        let ndk_platform = find_ndk_platform(config.min_sdk_version, |platform| {
+            bin_folder.join(format!(
+                "{}{}-clang{}",
+                build_target.ndk_llvm_triple(),
+                platform,
+                EXECUTABLE_SUFFIX_CMD
+            ))
+        })
+        .map_err(|_| FailureKind::Environment.mark(format_err!("Unable to find NDK clang")))?;
+        Ok(EffectiveApiLevels {
+            requested_min: config.min_sdk_version,
+            ndk_platform,
+            dex_min_api: Self::dex_min_api(config),
+        })
     }
 
-    // If that doesn't exist... Look for a higher one. This would be the minimum API level supported by the NDK
-    tmp_platform = platform;
-    while tmp_platform < 100 {
-        let path = path_builder(tmp_platform);
-        if path.exists() {
-            return Ok(path);
-        }
+    /// `--min-api` of d8, so that the dex runs on every device the manifest claims. d8
+    /// desugars the Java 8 constructs below API 26.
+    pub fn dex_min_api(config: &AndroidConfig) -> u32 {
+        config.min_sdk_version
+    }
+
+    /// Whether the native code may use APIs missing on devices at `min_sdk_version`. Falling
+    /// back to a lower platform is safe, and 64-bit ABIs only exist from API 21.
+    fn exceeds_requested_min(&self, build_target: AndroidBuildTarget) -> bool {
+        let first_platform = match build_target {
+            AndroidBuildTarget::Arm64V8a | AndroidBuildTarget::X86_64 => 21,
+            AndroidBuildTarget::ArmV7a | AndroidBuildTarget::X86 => 1,
+        };
+        self.ndk_platform > self.requested_min.max(first_platform)
+    }
+}
+
+/// Computes the API levels of every build target of the build
+pub fn effective_api_levels(
+    config: &AndroidConfig,
+) -> CargoResult<Vec<(AndroidBuildTarget, EffectiveApiLevels)>> {
+    config
+        .build_targets
+        .iter()
+        .map(|&build_target| Ok((build_target, EffectiveApiLevels::new(config, build_target)?)))
+        .collect()
+}
+
+/// Returns a single warning for the build targets whose native code is built for a platform
+/// above `min_sdk_version`, as the NDK doesn't have the requested one
+pub fn api_levels_warning(levels: &[(AndroidBuildTarget, EffectiveApiLevels)]) -> Option<String> {
+    let exceeding = levels
+        .iter()
+        .filter(|(build_target, levels)| levels.exceeds_requested_min(*build_target))
+        .collect::<Vec<_>>();
+    let requested_min = exceeding.first()?.1.requested_min;
+    Some(format!(
+        "`min_sdk_version` is {} but the NDK has no platform that old, the native code is built \
+         for {}. It may fail to load on devices below that API level, raise `min_sdk_version` or \
+         use an older NDK.",
+        requested_min,
+        exceeding
+            .iter()
+            .map(|(build_target, levels)| format!(
+                "{} with API {}",
+                build_target.android_abi(),
+                levels.ndk_platform
+            ))
+            .collect::<Vec<_>>()
+            .join(", ")
+    ))
+}
 
-        tmp_platform += 1;
+#[test]
+fn effective_api_levels_of_ndk() {
+    let root = std::env::temp_dir().join(format!("cargo-quad-apk-ndk-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let mut config = crate::config::from_metadata(
+        r#"build_targets = ["armv7-linux-androideabi", "aarch64-linux-android"]"#,
+    );
+    config.ndk_path = root.clone();
+    let bin_folder = llvm_toolchain_root(&config).join("bin");
+    fs::create_dir_all(&bin_folder).unwrap();
+    // An NDK supporting API 21 to 33, without the platforms 25 and 26
+    for platform in (21..=33).filter(|platform| ![25, 26].contains(platform)) {
+        for triple in &["armv7a-linux-androideabi", "aarch64-linux-android"] {
+            fs::write(
+                bin_folder.join(format!(
+                    "{}{}-clang{}",
+                    triple, platform, EXECUTABLE_SUFFIX_CMD
+                )),
+                "",
+            )
+            .unwrap();
+        }
     }
+    let levels = |config: &AndroidConfig| {
+        effective_api_levels(config)
+            .unwrap()
+            .into_iter()
+            .map(|(_, levels)| levels)
+            .collect::<Vec<_>>()
+    };
+
+    // The default min_sdk_version of 18 is below the oldest platform of the NDK
+    let armv7 = EffectiveApiLevels {
+        requested_min: 18,
+        ndk_platform: 21,
+        dex_min_api: 18,
+    };
+    assert_eq!(levels(&config), vec![armv7, armv7]);
+    assert_eq!(
+        api_levels_warning(&effective_api_levels(&config).unwrap()).unwrap(),
+        "`min_sdk_version` is 18 but the NDK has no platform that old, the native code is built \
+         for armeabi-v7a with API 21. It may fail to load on devices below that API level, raise \
+         `min_sdk_version` or use an older NDK."
+    );
+
+    // A missing platform falls back to the one below, which is safe
+    config.min_sdk_version = 26;
+    assert_eq!(
+        levels(&config)[0],
+        EffectiveApiLevels {
+            requested_min: 26,
+            ndk_platform: 24,
+            dex_min_api: 26,
+        }
+    );
+    assert_eq!(
+        api_levels_warning(&effective_api_levels(&config).unwrap()),
+        None
+    );
+
+    // Platforms newer than the NDK use its newest one
+    config.min_sdk_version = 34;
+    assert_eq!(levels(&config)[1].ndk_platform, 33);
+    assert_eq!(levels(&config)[1].dex_min_api, 34);
+    assert_eq!(
+        api_levels_warning(&effective_api_levels(&config).unwrap()),
+        None
+    );
 
-    Err(format_err!("Unable to find NDK file"))
+    fs::remove_dir_all(&root).unwrap();
+    assert!(effective_api_levels(&config)
+        .unwrap_err()
+        .to_string()
+        .contains("Unable to find NDK clang"));
 }
 
 // Returns path to clang executable/script that should be used to build the target
 pub fn find_clang(
     config: &AndroidConfig,
     build_target: AndroidBuildTarget,
-) -> CargoResult<PathBuf> {
-    let bin_folder = llvm_toolchain_root(config).join("bin");
-    find_ndk_path(config.min_sdk_version, |platform| {
-        bin_folder.join(format!(
-            "{}{}-clang{}",
-            build_target.ndk_llvm_triple(),
-            platform,
-            EXECUTABLE_SUFFIX_CMD
-        ))
-    })
-    .map_err(|_| format_err!("Unable to find NDK clang"))
+    api_levels: &EffectiveApiLevels,
+) -> PathBuf {
+    llvm_toolchain_root(config).join("bin").join(format!(
+        "{}{}-clang{}",
+        build_target.ndk_llvm_triple(),
+        api_levels.ndk_platform,
+        EXECUTABLE_SUFFIX_CMD
+    ))
 }
 
 // Returns path to clang++ executable/script that should be used to build the target
 pub fn find_clang_cpp(
     config: &AndroidConfig,
     build_target: AndroidBuildTarget,
+    api_levels: &EffectiveApiLevels,
 ) -> CargoResult<PathBuf> {
-    let bin_folder = llvm_toolchain_root(config).join("bin");
-    find_ndk_path(config.min_sdk_version, |platform| {
-        bin_folder.join(format!(
-            "{}{}-clang++{}",
-            build_target.ndk_llvm_triple(),
-            platform,
-            EXECUTABLE_SUFFIX_CMD
-        ))
-    })
-    .map_err(|_| format_err!("Unable to find NDK clang++"))
+    let clang_cpp = llvm_toolchain_root(config).join("bin").join(format!(
+        "{}{}-clang++{}",
+        build_target.ndk_llvm_triple(),
+        api_levels.ndk_platform,
+        EXECUTABLE_SUFFIX_CMD
+    ));
+    if clang_cpp.exists() {
+        Ok(clang_cpp)
+    } else {
+        Err(FailureKind::Environment.mark(format_err!("Unable to find NDK clang++")))
+    }
 }
 
 // Returns path to ar.
@@ -136,10 +301,10 @@ pub fn find_ar(config: &AndroidConfig, build_target: AndroidBuildTarget) -> Carg
     if ar_path.exists() {
         Ok(ar_path)
     } else {
-        Err(format_err!(
+        Err(FailureKind::Environment.mark(format_err!(
             "Unable to find ar at `{}`",
             ar_path.to_string_lossy()
-        ))
+        )))
     }
 }
 
@@ -155,10 +320,40 @@ pub fn find_readelf(
     if readelf_path.exists() {
         Ok(readelf_path)
     } else {
-        Err(format_err!(
+        Err(FailureKind::Environment.mark(format_err!(
             "Unable to find readelf at `{}`",
             readelf_path.to_string_lossy()
-        ))
+        )))
+    }
+}
+
+// Returns path to ndk-stack
+pub fn find_ndk_stack(config: &AndroidConfig) -> CargoResult<PathBuf> {
+    let ndk_stack_path = config
+        .ndk_path
+        .join(format!("ndk-stack{}", EXECUTABLE_SUFFIX_CMD));
+    if ndk_stack_path.exists() {
+        Ok(ndk_stack_path)
+    } else {
+        Err(FailureKind::Environment.mark(format_err!(
+            "Unable to find ndk-stack at `{}`",
+            ndk_stack_path.to_string_lossy()
+        )))
+    }
+}
+
+// Returns path to llvm-strip
+pub fn find_llvm_strip(config: &AndroidConfig) -> CargoResult<PathBuf> {
+    let strip_path = llvm_toolchain_root(config)
+        .join("bin")
+        .join(format!("llvm-strip{}", EXECUTABLE_SUFFIX_EXE));
+    if strip_path.exists() {
+        Ok(strip_path)
+    } else {
+        Err(FailureKind::Environment.mark(format_err!(
+            "Unable to find llvm-strip at `{}`",
+            strip_path.to_string_lossy()
+        )))
     }
 }
 
@@ -183,26 +378,26 @@ pub fn find_libunwind_dir(
     if libunwind_dir.join("libunwind.a").exists() {
         Ok(libunwind_dir)
     } else {
-        Err(format_err!(
+        Err(FailureKind::Environment.mark(format_err!(
             "Unable to find libunwind.a at `{}`",
             libunwind_dir.to_string_lossy()
-        ))
+        )))
     }
 }
 
-pub fn find_package_root_path(
-    workspace: &Workspace,
+/// Resolves the workspace for the first build target.
+///
+/// The resolve goes through the workspace's `GlobalContext`, so `--frozen`, `--locked` and
+/// `--offline` apply to it exactly as they do to the compilation itself: a lock file which
+/// would need to be updated is reported as an error instead of being rewritten.
+fn resolve_workspace<'a>(
+    workspace: &Workspace<'a>,
     config: &AndroidConfig,
-    package_name: &str,
-) -> PathBuf {
-    use cargo::{
-        core::{compiler, resolver},
-        ops,
-    };
+    cli_features: &cargo::core::resolver::CliFeatures,
+) -> CargoResult<cargo::ops::WorkspaceResolve<'a>> {
+    use cargo::core::{compiler, resolver};
 
-    let specs = cargo::ops::Packages::Default
-        .to_package_id_specs(&workspace)
-        .unwrap();
+    let specs = cargo::ops::Packages::Default.to_package_id_specs(&workspace)?;
     // assuming all the build targets use the same miniquad version
     // which should be always true
     let first_build_target = config
@@ -210,32 +405,67 @@ pub fn find_package_root_path(
         .iter()
         .next()
         .expect("Should be at least one build target");
-    let requested_kinds = vec![compiler::CompileKind::Target(
-        compiler::CompileTarget::new(first_build_target.rust_triple()).unwrap(),
-    )];
+    let requested_kinds = vec![compiler::CompileKind::Target(compiler::CompileTarget::new(
+        first_build_target.rust_triple(),
+    )?)];
 
-    let mut target_data = compiler::RustcTargetData::new(&workspace, &requested_kinds[..]).unwrap();
-    let cli_features = resolver::CliFeatures::new_all(false);
-    let ws_resolve = cargo::ops::resolve_ws_with_opts(
+    let mut target_data = compiler::RustcTargetData::new(&workspace, &requested_kinds[..])?;
+    cargo::ops::resolve_ws_with_opts(
         &workspace,
         &mut target_data,
         &requested_kinds,
-        &cli_features,
+        cli_features,
         &specs,
         resolver::HasDevUnits::No,
         resolver::ForceAllTargets::No,
     )
-    .unwrap();
+}
 
-    let miniquad_pkg = ws_resolve
+/// Returns the cargo features enabled on the package being built, including the default
+/// features unless they were disabled on the command line.
+pub fn active_features(
+    workspace: &Workspace,
+    config: &AndroidConfig,
+    cli_features: &CliFeatures,
+) -> CargoResult<BTreeSet<String>> {
+    let ws_resolve = resolve_workspace(workspace, config, cli_features)?;
+
+    let package = workspace
+        .members()
+        .find(|package| *package.name() == config.cargo_package_name)
+        .ok_or_else(|| format_err!("Unable to find package `{}`", config.cargo_package_name))?;
+
+    Ok(ws_resolve
+        .resolved_features
+        .activated_features(package.package_id(), FeaturesFor::NormalOrDev)
+        .into_iter()
+        .map(|feature| feature.to_string())
+        .collect())
+}
+
+pub fn find_package_root_path(
+    workspace: &Workspace,
+    config: &AndroidConfig,
+    package_name: &str,
+) -> CargoResult<PathBuf> {
+    let ws_resolve = resolve_workspace(workspace, config, &CliFeatures::new_all(false))?;
+
+    let package = ws_resolve
         .pkg_set
         .packages()
-        .find(|package| package.name() == package_name).expect("cargo quad can't build a non-miniquad package, but no miniquad is found in the dependencies tree!");
+        .find(|package| package.name() == package_name)
+        .ok_or_else(|| {
+            format_err!(
+                "`{}` is not in the dependency tree. Apps without miniquad can set \
+                 `framework = \"none\"` to be started by NativeActivity instead",
+                package_name
+            )
+        })?;
 
-    miniquad_pkg.root().to_path_buf()
+    Ok(package.root().to_path_buf())
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct JavaFiles {
     /// Optional file with a template to be injected into miniquad's MainActivity
     pub main_activity_injects: Vec<PathBuf>,
@@ -255,6 +485,19 @@ pub struct JavaFiles {
     /// List of services being appended to "metadata.android.service" with
     /// "enabled: true" value
     pub java_services: Vec<String>,
+
+    /// uses-feature elements needed by the dependencies, with the name of their package
+    pub uses_features: Vec<(String, AndroidFeature)>,
+
+    /// Lowest API level the runtime jars of a package run on, with the name of the package
+    pub runtime_jar_min_apis: Vec<(String, u32)>,
+
+    /// Java classes and members the libraries of the packages reach through JNI without
+    /// naming them
+    pub jni_keep: Vec<String>,
+
+    /// Resource directories of the packages, with the name of their package
+    pub res_dirs: Vec<(String, PathBuf)>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -263,14 +506,21 @@ struct QuadToml {
     java_files: Option<Vec<String>>,
     comptime_jar_files: Option<Vec<String>>,
     runtime_jar_files: Option<Vec<String>>,
+    /// Lowest API level the runtime jars run on
+    runtime_jar_min_api: Option<u32>,
     java_services: Option<Vec<String>>,
-    // a special field being filled while toml parsing
+    features: Option<Vec<TomlFeature>>,
+    jni_keep: Option<Vec<String>>,
+    res_dir: Option<String>,
+    // special fields being filled while toml parsing
     // do not really belong to a toml and this struct!
     #[serde(skip)]
     package_root: PathBuf,
+    #[serde(skip)]
+    package_name: String,
 }
 
-fn read_quad_toml(path: &Path) -> Option<QuadToml> {
+fn read_quad_toml(package_name: &str, path: &Path) -> Option<QuadToml> {
     let quad_toml_path = path.join("quad.toml");
     if !quad_toml_path.exists() {
         return None;
@@ -287,83 +537,734 @@ fn read_quad_toml(path: &Path) -> Option<QuadToml> {
         .unwrap_or_else(|err| panic!("{:?} toml file malformed, {:?}", path, err));
 
     config.package_root = path.to_owned();
+    config.package_name = package_name.to_owned();
 
     Some(config)
 }
 
-pub fn collect_java_files(workspace: &Workspace, config: &AndroidConfig) -> JavaFiles {
-    use cargo::{
-        core::{compiler, resolver},
-        ops,
+pub fn collect_java_files(workspace: &Workspace, config: &AndroidConfig) -> CargoResult<JavaFiles> {
+    let ws_resolve = resolve_workspace(workspace, config, &CliFeatures::new_all(false))?;
+
+    let quad_tomls = ws_resolve
+        .pkg_set
+        .packages()
+        .filter_map(|package| read_quad_toml(package.name().as_str(), package.root()))
+        .collect();
+    let mut collector = JavaFilesCollector::default();
+    collector.add_quad_tomls(quad_tomls, root_quad_toml(config))?;
+
+    add_java_sources(&mut collector, config)?;
+
+    Ok(collector.files)
+}
+
+/// Adds the app's own Java sources, which are not laid out like the `java/` folder of a
+/// quad.toml, so they are placed according to their package declaration instead. The
+/// `main_activity` is staged as the MainActivity, not as one of them.
+fn add_java_sources(collector: &mut JavaFilesCollector, config: &AndroidConfig) -> CargoResult<()> {
+    let package_root = config.manifest_path.parent().unwrap();
+    for source_dir in &config.java_sources {
+        let source_dir = absolute_path(package_root, source_dir);
+        if !source_dir.is_dir() {
+            return Err(format_err!(
+                "Java source directory `{}` does not exist",
+                source_dir.display()
+            ));
+        }
+        for java_file in find_files(&source_dir, "java")? {
+            if config.main_activity.as_ref() == Some(&java_file) {
+                continue;
+            }
+            let java_src = fs::read_to_string(&java_file)?;
+            let mut local_path = PathBuf::from("java");
+            for package_part in java_package(&java_src).iter().flat_map(|p| p.split('.')) {
+                local_path.push(package_part);
+            }
+            local_path.push(java_file.file_name().unwrap());
+            if collector.contribute("java", &config.cargo_package_name, &java_file, &local_path)? {
+                collector.files.java_files.push((java_file, local_path));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn absolute_path(root: &Path, path: &str) -> PathBuf {
+    let mut res = root.to_owned();
+    for path_part in path.split("/") {
+        res = res.join(path_part);
+    }
+    res
+}
+
+/// A file contributed by a package, at a destination which other packages may contribute to
+struct Contribution {
+    package_name: String,
+    path: PathBuf,
+    hash: String,
+}
+
+/// Merges the Java files, jars and services contributed by packages, rejecting different
+/// files contributed at the same destination
+#[derive(Default)]
+struct JavaFilesCollector {
+    files: JavaFiles,
+    /// Contributions keyed by kind and destination
+    contributions: BTreeMap<(&'static str, PathBuf), Contribution>,
+}
+
+impl JavaFilesCollector {
+    /// Adds the quad.toml of the dependencies in the order of their package names, so that the
+    /// order of the Java files doesn't depend on the resolver, then the one of the app
+    fn add_quad_tomls(&mut self, mut quad_tomls: Vec<QuadToml>, root: QuadToml) -> CargoResult<()> {
+        quad_tomls.sort_by(|a, b| a.package_name.cmp(&b.package_name));
+        for toml in quad_tomls.into_iter().chain(Some(root)) {
+            let root = &toml.package_root;
+            self.files
+                .main_activity_injects
+                .extend(toml.main_activity_inject.map(|f| absolute_path(root, &f)));
+
+            let files = [
+                ("java", &toml.java_files),
+                ("comptime jar", &toml.comptime_jar_files),
+                ("runtime jar", &toml.runtime_jar_files),
+            ];
+            for (kind, files) in files.iter() {
+                for file in files.iter().flatten() {
+                    let path = absolute_path(root, file);
+                    let local_path = PathBuf::from(file);
+                    // Jars end up side by side, so only their file names can collide
+                    let destination = match *kind {
+                        "java" => local_path.clone(),
+                        _ => PathBuf::from(local_path.file_name().unwrap_or_default()),
+                    };
+                    if self.contribute(kind, &toml.package_name, &path, &destination)? {
+                        let list = match *kind {
+                            "java" => &mut self.files.java_files,
+                            "comptime jar" => &mut self.files.comptime_jar_files,
+                            _ => &mut self.files.runtime_jar_files,
+                        };
+                        list.push((path, local_path));
+                    }
+                }
+            }
+
+            if let (Some(min_api), Some(_)) = (toml.runtime_jar_min_api, &toml.runtime_jar_files) {
+                self.files
+                    .runtime_jar_min_apis
+                    .push((toml.package_name.clone(), min_api));
+            }
+
+            for service in toml.java_services.iter().flatten() {
+                if !self.files.java_services.contains(service) {
+                    self.files.java_services.push(service.clone());
+                }
+            }
+
+            if let Some(res_dir) = &toml.res_dir {
+                let res_dir = absolute_path(root, res_dir);
+                if !res_dir.is_dir() {
+                    return Err(format_err!(
+                        "`res_dir` of the quad.toml of `{}` is not a directory: `{}`",
+                        toml.package_name,
+                        res_dir.display()
+                    ));
+                }
+                self.files
+                    .res_dirs
+                    .push((toml.package_name.clone(), res_dir));
+            }
+
+            for entry in toml.jni_keep.iter().flatten() {
+                if !self.files.jni_keep.contains(entry) {
+                    self.files.jni_keep.push(entry.clone());
+                }
+            }
+
+            for feature in toml.features.into_iter().flatten() {
+                self.files
+                    .uses_features
+                    .push((toml.package_name.clone(), AndroidFeature::from(feature)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Records a file contributed at a destination. Returns false when the same content was
+    /// already contributed there, and fails when different content was.
+    fn contribute(
+        &mut self,
+        kind: &'static str,
+        package_name: &str,
+        path: &Path,
+        destination: &Path,
+    ) -> CargoResult<bool> {
+        let hash = Sha256::new()
+            .update_path(path)
+            .map_err(|err| {
+                format_err!(
+                    "Unable to read `{}` of package `{}`: {}",
+                    path.display(),
+                    package_name,
+                    err
+                )
+            })?
+            .finish_hex();
+
+        let key = (kind, destination.to_owned());
+        match self.contributions.get(&key) {
+            Some(existing) if existing.hash == hash => Ok(false),
+            Some(existing) => Err(format_err!(
+                "Packages `{}` and `{}` both contribute the {} file `{}` with different \
+                 contents:\n  {}\n  {}",
+                existing.package_name,
+                package_name,
+                kind,
+                destination.display(),
+                existing.path.display(),
+                path.display()
+            )),
+            None => {
+                self.contributions.insert(
+                    key,
+                    Contribution {
+                        package_name: package_name.to_owned(),
+                        path: path.to_owned(),
+                        hash,
+                    },
+                );
+                Ok(true)
+            }
+        }
+    }
+}
+
+#[test]
+fn duplicate_java_contributions() {
+    let root = std::env::temp_dir().join(format!("cargo-quad-apk-dup-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let quad_toml = |package_name: &str, files: &[(&str, &str)], services: &[&str]| {
+        let package_root = root.join(package_name);
+        for (file, contents) in files {
+            let path = package_root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        let list = |extension: &str| {
+            Some(
+                files
+                    .iter()
+                    .map(|(file, _)| file.to_string())
+                    .filter(|file| file.ends_with(extension))
+                    .collect(),
+            )
+        };
+        QuadToml {
+            main_activity_inject: None,
+            java_files: list(".java"),
+            comptime_jar_files: None,
+            runtime_jar_files: list(".jar"),
+            runtime_jar_min_api: None,
+            java_services: Some(services.iter().map(|s| s.to_string()).collect()),
+            features: None,
+            jni_keep: None,
+            res_dir: None,
+            package_root,
+            package_name: package_name.to_owned(),
+        }
+    };
+    let app = || quad_toml("app", &[], &[]);
+    let local_paths = |files: &[(PathBuf, PathBuf)]| {
+        files
+            .iter()
+            .map(|(path, _)| path.strip_prefix(&root).unwrap().to_owned())
+            .collect::<Vec<_>>()
     };
 
-    let specs = cargo::ops::Packages::Default
-        .to_package_id_specs(&workspace)
+    // Identical files are kept once, in the order of the package names
+    let mut collector = JavaFilesCollector::default();
+    collector
+        .add_quad_tomls(
+            vec![
+                quad_toml(
+                    "plugin_b",
+                    &[
+                        ("java/util/Base64.java", "class Base64 {}"),
+                        ("java/b/B.java", "class B {}"),
+                        ("libs/gson.jar", "gson"),
+                    ],
+                    &[".BService"],
+                ),
+                quad_toml(
+                    "plugin_a",
+                    &[
+                        ("java/util/Base64.java", "class Base64 {}"),
+                        ("libs/gson.jar", "gson"),
+                    ],
+                    &[".BService", ".AService"],
+                ),
+            ],
+            app(),
+        )
         .unwrap();
-    // assuming all the build targets use the same miniquad version
-    // which should be always true
-    let first_build_target = config
-        .build_targets
-        .iter()
-        .next()
-        .expect("Should be at least one build target");
-    let requested_kinds = vec![compiler::CompileKind::Target(
-        compiler::CompileTarget::new(first_build_target.rust_triple()).unwrap(),
-    )];
+    assert_eq!(
+        local_paths(&collector.files.java_files),
+        vec![
+            PathBuf::from("plugin_a/java/util/Base64.java"),
+            PathBuf::from("plugin_b/java/b/B.java"),
+        ]
+    );
+    assert_eq!(
+        local_paths(&collector.files.runtime_jar_files),
+        vec![PathBuf::from("plugin_a/libs/gson.jar")]
+    );
+    assert_eq!(
+        collector.files.java_services,
+        vec![".BService", ".AService"]
+    );
 
-    let mut target_data = compiler::RustcTargetData::new(&workspace, &requested_kinds[..]).unwrap();
-    let cli_features = resolver::CliFeatures::new_all(false);
-    let ws_resolve = cargo::ops::resolve_ws_with_opts(
-        &workspace,
-        &mut target_data,
-        &requested_kinds,
-        &cli_features,
-        &specs,
-        resolver::HasDevUnits::No,
-        resolver::ForceAllTargets::No,
-    )
-    .unwrap();
+    // Different contents at the same destination name both packages
+    let err = JavaFilesCollector::default()
+        .add_quad_tomls(
+            vec![
+                quad_toml(
+                    "plugin_d",
+                    &[("java/util/Base64.java", "class Base64 { int v2; }")],
+                    &[],
+                ),
+                quad_toml(
+                    "plugin_c",
+                    &[("java/util/Base64.java", "class Base64 {}")],
+                    &[],
+                ),
+            ],
+            app(),
+        )
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(
+        err.starts_with(
+            "Packages `plugin_c` and `plugin_d` both contribute the java file \
+             `java/util/Base64.java` with different contents"
+        ),
+        "{}",
+        err
+    );
+
+    // Jars collide on their file name
+    let err = JavaFilesCollector::default()
+        .add_quad_tomls(
+            vec![
+                quad_toml("plugin_e", &[("libs/v1/gson.jar", "gson 1")], &[]),
+                quad_toml("plugin_f", &[("jars/gson.jar", "gson 2")], &[]),
+            ],
+            app(),
+        )
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(
+        err.starts_with(
+            "Packages `plugin_e` and `plugin_f` both contribute the runtime jar file `gson.jar`"
+        ),
+        "{}",
+        err
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+/// Returns the files with the given extension below a directory, sorted so that tools get
+/// their inputs in the same order on every build
+pub fn find_files(dir: &Path, extension: &str) -> CargoResult<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in walkdir::WalkDir::new(dir) {
+        let entry = entry.map_err(|err| {
+            let path = err.path().unwrap_or(dir).to_owned();
+            format_err!("Unable to read `{}`: {}", path.display(), err)
+        })?;
+        if entry.file_type().is_file() && entry.path().extension() == Some(OsStr::new(extension)) {
+            files.push(entry.into_path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Moves the APKs of `dir` whose name is not one of `known_targets` to `dir/old`, or deletes them
+/// when `prune` is set. Other files are left alone. Returns the names of the stale APKs.
+pub fn remove_stale_apks(
+    dir: &Path,
+    known_targets: &BTreeSet<String>,
+    prune: bool,
+) -> CargoResult<Vec<PathBuf>> {
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+
+    let mut stale_apks = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_apk = path.extension() == Some(OsStr::new("apk")) && path.is_file();
+        let known = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .map_or(false, |stem| known_targets.contains(stem));
+        if is_apk && !known {
+            stale_apks.push(path);
+        }
+    }
+    stale_apks.sort();
+
+    for apk in &stale_apks {
+        if prune {
+            fs::remove_file(apk)?;
+        } else {
+            let old_dir = dir.join("old");
+            fs::create_dir_all(&old_dir)?;
+            fs::rename(apk, old_dir.join(apk.file_name().unwrap()))?;
+        }
+    }
+    Ok(stale_apks
+        .into_iter()
+        .map(|apk| PathBuf::from(apk.file_name().unwrap()))
+        .collect())
+}
+
+#[test]
+fn stale_apks_after_rename() {
+    let apk_dir = std::env::temp_dir().join(format!("cargo-quad-apk-stale-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&apk_dir);
+    let files = [
+        "demo.apk",
+        "game.apk",
+        "game.apk.idsig",
+        "build-report.json",
+        "examples/triangle.apk",
+        "examples/quad.apk",
+    ];
+    for file in &files {
+        let path = apk_dir.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, "").unwrap();
+    }
+    let known = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
 
-    let mut res = JavaFiles {
-        main_activity_injects: vec![],
-        java_files: vec![],
-        comptime_jar_files: vec![],
-        runtime_jar_files: vec![],
-        java_services: vec![],
+    // The `demo` bin was renamed to `game`
+    assert_eq!(
+        remove_stale_apks(&apk_dir, &known(&["game"]), false).unwrap(),
+        vec![PathBuf::from("demo.apk")]
+    );
+    assert!(apk_dir.join("old/demo.apk").exists());
+    assert!(!apk_dir.join("demo.apk").exists());
+    assert!(apk_dir.join("game.apk.idsig").exists());
+    assert!(apk_dir.join("build-report.json").exists());
+
+    // The `quad` example was removed
+    let examples_dir = apk_dir.join("examples");
+    assert_eq!(
+        remove_stale_apks(&examples_dir, &known(&["triangle"]), true).unwrap(),
+        vec![PathBuf::from("quad.apk")]
+    );
+    assert!(!examples_dir.join("quad.apk").exists());
+    assert!(!examples_dir.join("old").exists());
+    assert!(examples_dir.join("triangle.apk").exists());
+
+    fs::remove_dir_all(&apk_dir).unwrap();
+}
+
+/// Removes the contents of a directory, creating it if needed
+pub fn clean_dir(dir: &Path) -> CargoResult<()> {
+    if dir.exists() {
+        fs::remove_dir_all(dir)
+            .map_err(|err| format_err!("Unable to clean `{}`: {}", dir.display(), err))?;
+    }
+    fs::create_dir_all(dir)?;
+    Ok(())
+}
+
+/// Copies the files of a directory and its subdirectories into `to`, creating it if needed
+pub fn copy_dir(from: &Path, to: &Path) -> CargoResult<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let path = entry?.path();
+        let target = to.join(path.file_name().unwrap());
+        if path.is_dir() {
+            copy_dir(&path, &target)?;
+        } else {
+            fs::copy(&path, &target)?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn stale_classes_are_not_collected() {
+    let obj_dir = std::env::temp_dir()
+        .join(format!("cargo-quad-apk-obj-{}", std::process::id()))
+        .join("build")
+        .join("obj");
+    let class_files = |names: &[&str]| {
+        names
+            .iter()
+            .map(|name| obj_dir.join(name))
+            .collect::<Vec<_>>()
     };
+    let write_classes = |names: &[&str]| {
+        for path in class_files(names) {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+    };
+
+    // A build with the old package name
+    clean_dir(&obj_dir).unwrap();
+    write_classes(&[
+        "rust/old_name/MainActivity.class",
+        "quad_native/QuadNative.class",
+    ]);
 
-    let absolute_path = |root: &PathBuf, path: &str| {
-        let mut res = root.clone();
-        for path_part in path.split("/") {
-            res = res.join(path_part);
+    // The package got renamed
+    clean_dir(&obj_dir).unwrap();
+    write_classes(&[
+        "rust/new_name/R.class",
+        "rust/new_name/MainActivity.class",
+        "quad_native/QuadNative.class",
+    ]);
+    fs::write(obj_dir.join("notes.txt"), "").unwrap();
+
+    assert_eq!(
+        find_files(&obj_dir, "class").unwrap(),
+        class_files(&[
+            "quad_native/QuadNative.class",
+            "rust/new_name/MainActivity.class",
+            "rust/new_name/R.class",
+        ])
+    );
+
+    fs::remove_dir_all(obj_dir.parent().unwrap().parent().unwrap()).unwrap();
+}
+
+/// Writes `contents` to `path` unless the file already holds them, and returns whether it was
+/// written. Generated files keep their modification time across builds this way, so that the
+/// tools reading them, like cmake, javac or aapt, don't consider their outputs stale.
+pub fn write_if_changed(path: &Path, contents: impl AsRef<[u8]>) -> std::io::Result<bool> {
+    let contents = contents.as_ref();
+    if fs::read(path).map_or(false, |existing| existing == contents) {
+        return Ok(false);
+    }
+    write_atomically(path, contents)?;
+    Ok(true)
+}
+
+/// Writes `contents` to a temporary file next to `path`, then renames it to `path`, so that a
+/// build killed while writing never leaves a truncated file behind
+pub fn write_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(path.file_name().unwrap_or_default());
+    tmp_name.push(format!(".{}.tmp", std::process::id()));
+    let tmp_path = path.with_file_name(tmp_name);
+    let written = (|| {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)
+    })();
+    if written.is_err() {
+        drop(fs::remove_file(&tmp_path));
+    }
+    written
+}
+
+/// Name of the file of a target directory listing its generated files with their hash, written
+/// once they all are
+const GENERATION_MARKER: &str = ".generated.json";
+
+/// Prepares a target directory for the generation of its files. When the last generation didn't
+/// complete, because the marker is missing or a generated file doesn't match its hash, the
+/// directory is emptied so that everything is built again from scratch. Returns whether it was.
+pub fn begin_generation(dir: &Path) -> CargoResult<bool> {
+    let marker = dir.join(GENERATION_MARKER);
+    let complete = match fs::read(&marker) {
+        Ok(contents) => {
+            serde_json::from_slice::<BTreeMap<PathBuf, String>>(&contents).map_or(false, |hashes| {
+                hashes.iter().all(|(path, hash)| {
+                    Sha256::new()
+                        .update_path(dir.join(path))
+                        .map_or(false, |sha256| sha256.finish_hex() == *hash)
+                })
+            })
         }
-        res
+        // A new directory has nothing to clean
+        Err(_) => fs::read_dir(dir).map_or(true, |mut entries| entries.next().is_none()),
     };
-    ws_resolve
-        .pkg_set
-        .packages()
-        .filter_map(|package| read_quad_toml(package.root()))
-        .for_each(|toml| {
-            let root = toml.package_root.clone();
-            let to_absolute = |x: &Option<Vec<String>>| {
-                x.iter()
-                    .flatten()
-                    .map(|f| (absolute_path(&root, &f), PathBuf::from(f)))
-                    .collect::<Vec<_>>()
-            };
-
-            res.main_activity_injects
-                .extend(toml.main_activity_inject.map(|f| absolute_path(&root, &f)));
-            res.java_files.extend(to_absolute(&toml.java_files));
-            res.comptime_jar_files
-                .extend(to_absolute(&toml.comptime_jar_files));
-            res.runtime_jar_files
-                .extend(to_absolute(&toml.runtime_jar_files));
-            if let Some(ref java_services) = toml.java_services {
-                res.java_services.extend(java_services.iter().cloned());
-            }
-        });
-    res
+    if !complete {
+        clean_dir(dir)?;
+        return Ok(true);
+    }
+    // Missing until the generation completes, in case it is interrupted
+    if marker.exists() {
+        fs::remove_file(&marker)?;
+    }
+    Ok(false)
+}
+
+/// Records that the files of a target directory are all generated, with their hash
+pub fn finish_generation(dir: &Path, files: &[PathBuf]) -> CargoResult<()> {
+    let mut hashes = BTreeMap::new();
+    for file in files {
+        let sha256 = Sha256::new().update_path(dir.join(file))?.finish_hex();
+        hashes.insert(file.strip_prefix(dir).unwrap_or(file).to_owned(), sha256);
+    }
+    write_atomically(
+        &dir.join(GENERATION_MARKER),
+        serde_json::to_string_pretty(&hashes)?.as_bytes(),
+    )?;
+    Ok(())
+}
+
+#[test]
+fn interrupted_generation_is_rebuilt() {
+    let dir =
+        std::env::temp_dir().join(format!("cargo-quad-apk-generation-{}", std::process::id()));
+    clean_dir(&dir).unwrap();
+    let manifest = dir.join("AndroidManifest.xml");
+    let main_activity = PathBuf::from("rust/app/MainActivity.java");
+
+    assert!(!begin_generation(&dir).unwrap());
+    fs::create_dir_all(dir.join("rust/app")).unwrap();
+    write_if_changed(&manifest, "<manifest package=\"rust.app\"/>\n").unwrap();
+    write_if_changed(&dir.join(&main_activity), "package rust.app;\n").unwrap();
+    finish_generation(&dir, &[manifest.clone(), main_activity.clone()]).unwrap();
+    assert_eq!(
+        fs::read_dir(&dir).unwrap().count(),
+        3,
+        "no temporary file is left behind"
+    );
+
+    // The next build keeps the directory, and removes the marker until it completes
+    fs::write(dir.join("classes.dex"), "dex\n035\0").unwrap();
+    assert!(!begin_generation(&dir).unwrap());
+    assert!(dir.join("classes.dex").exists());
+    assert!(!dir.join(GENERATION_MARKER).exists());
+    finish_generation(&dir, &[manifest.clone(), main_activity.clone()]).unwrap();
+
+    // A manifest truncated by a killed build is detected
+    fs::write(&manifest, "<manifest pack").unwrap();
+    assert!(begin_generation(&dir).unwrap());
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+    // So is a generation interrupted before the marker was written
+    write_if_changed(&manifest, "<manifest package=\"rust.app\"/>\n").unwrap();
+    assert!(begin_generation(&dir).unwrap());
+    assert!(!manifest.exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn generated_files_are_written_if_changed() {
+    let dir = std::env::temp_dir().join(format!("cargo-quad-apk-write-{}", std::process::id()));
+    clean_dir(&dir).unwrap();
+    let path = dir.join("AndroidManifest.xml");
+    let modified = || fs::metadata(&path).unwrap().modified().unwrap();
+
+    assert!(write_if_changed(&path, "<manifest/>").unwrap());
+    let first_write = modified();
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    assert!(!write_if_changed(&path, "<manifest/>").unwrap());
+    assert_eq!(modified(), first_write);
+
+    assert!(write_if_changed(&path, "<manifest package=\"rust.app\"/>").unwrap());
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        "<manifest package=\"rust.app\"/>"
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Synthesizes a quad.toml for the jars listed in the app's own android metadata
+fn root_quad_toml(config: &AndroidConfig) -> QuadToml {
+    QuadToml {
+        main_activity_inject: None,
+        java_files: None,
+        comptime_jar_files: Some(config.comptime_jars.clone()),
+        runtime_jar_files: Some(config.runtime_jars.clone()),
+        runtime_jar_min_api: None,
+        java_services: None,
+        features: None,
+        jni_keep: Some(config.jni_keep.clone()),
+        res_dir: None,
+        package_root: config.manifest_path.parent().unwrap().to_owned(),
+        package_name: config.cargo_package_name.clone(),
+    }
+}
+
+/// Returns the package declared by a Java source file, if any
+fn java_package(java_src: &str) -> Option<&str> {
+    java_src.lines().find_map(|line| {
+        line.trim()
+            .strip_prefix("package ")?
+            .trim()
+            .strip_suffix(';')
+            .map(str::trim)
+    })
+}
+
+#[test]
+fn java_package_declaration() {
+    assert_eq!(
+        java_package("// Helper\npackage com.example.helper;\n\npublic class Helper {}\n"),
+        Some("com.example.helper")
+    );
+    assert_eq!(
+        java_package("package TARGET_PACKAGE_NAME ;\n"),
+        Some("TARGET_PACKAGE_NAME")
+    );
+    assert_eq!(java_package("public class Helper {}\n"), None);
+}
+
+/// Runs a tool's version command and returns the version it reports, if any.
+pub fn tool_version(cmd: &ProcessBuilder) -> Option<String> {
+    let output = cmd.output().ok()?;
+    parse_tool_version(
+        &String::from_utf8_lossy(&output.stdout),
+        &String::from_utf8_lossy(&output.stderr),
+    )
+}
+
+/// Extracts the version from the output of a version command. Some tools print it on stdout
+/// and others (like older `javac`) on stderr, so the first non-empty line of either is used.
+fn parse_tool_version(stdout: &str, stderr: &str) -> Option<String> {
+    stdout
+        .lines()
+        .chain(stderr.lines())
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .map(str::to_owned)
+}
+
+#[test]
+fn parse_tool_versions() {
+    assert_eq!(
+        parse_tool_version("Android Asset Packaging Tool, v0.2-7140770\n", "").as_deref(),
+        Some("Android Asset Packaging Tool, v0.2-7140770")
+    );
+    assert_eq!(
+        parse_tool_version("8.0.46\nbuild engineering\n", "").as_deref(),
+        Some("8.0.46")
+    );
+    assert_eq!(
+        parse_tool_version("", "javac 1.8.0_392\n").as_deref(),
+        Some("javac 1.8.0_392")
+    );
+    assert_eq!(
+        parse_tool_version("\njavac 17.0.9\n", "Picked up _JAVA_OPTIONS\n").as_deref(),
+        Some("javac 17.0.9")
+    );
+    assert_eq!(parse_tool_version("", "  \n"), None);
 }
 
 /// Returns a ProcessBuilder which runs the specified command. Uses "cmd" on windows in order to
@@ -410,3 +1311,40 @@ pub const EXECUTABLE_SUFFIX_BAT: &str = ".bat";
 
 #[cfg(not(target_os = "windows"))]
 pub const EXECUTABLE_SUFFIX_BAT: &str = "";
+
+#[test]
+fn main_activity_among_java_sources() {
+    let root = std::env::temp_dir().join(format!(
+        "cargo-quad-apk-main-activity-sources-{}",
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&root);
+    // The layout of the README
+    let java_dir = root.join("android/java");
+    fs::create_dir_all(&java_dir).unwrap();
+    fs::write(
+        java_dir.join("MainActivity.java"),
+        "package TARGET_PACKAGE_NAME;\npublic class MainActivity {}\n",
+    )
+    .unwrap();
+    fs::write(
+        java_dir.join("Helper.java"),
+        "package com.example.helper;\npublic class Helper {}\n",
+    )
+    .unwrap();
+
+    let mut config = crate::config::from_metadata(r#"java_sources = ["android/java"]"#);
+    config.manifest_path = root.join("Cargo.toml");
+    config.main_activity = Some(java_dir.join("MainActivity.java"));
+    let mut collector = JavaFilesCollector::default();
+    add_java_sources(&mut collector, &config).unwrap();
+    assert_eq!(
+        collector.files.java_files,
+        vec![(
+            java_dir.join("Helper.java"),
+            PathBuf::from("java/com/example/helper/Helper.java")
+        )]
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,585 @@
+//! Writing of the generated XML files, like the AndroidManifest.xml.
+//!
+//! Elements are built as a tree and written with the values of their attributes escaped, so that
+//! labels or attributes of the configuration can hold any character. The output only depends on
+//! the tree: children are written in the order they were added, one per line and indented by 4
+//! spaces per level, and attributes in the order they were set.
+//!
+//! Hand-written documents, like the manifest templates, are parsed into the same tree. Only what
+//! manifests use is supported: elements and their attributes, with comments and the XML
+//! declaration skipped. The values files of resources are parsed with their text and CDATA
+//! sections skipped, to read which resources they declare.
+
+use anyhow::format_err;
+use cargo::util::CargoResult;
+
+/// Escapes a value for use in an XML attribute
+pub fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Reverts `escape`, and the character references
+fn unescape(value: &str) -> Result<String, String> {
+    let mut unescaped = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find(';')
+            .ok_or_else(|| format!("unterminated reference in `{}`", value))?;
+        let reference = &rest[start + 1..start + end];
+        let c = match reference {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => match reference.strip_prefix('#') {
+                Some(code) => match code.strip_prefix('x') {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => code.parse().ok(),
+                }
+                .and_then(std::char::from_u32),
+                None => None,
+            },
+        };
+        unescaped.push(c.ok_or_else(|| format!("unknown reference `&{};`", reference))?);
+        rest = &rest[start + end + 1..];
+    }
+    unescaped.push_str(rest);
+    Ok(unescaped)
+}
+
+/// Element of an XML document, with its attributes and child elements
+#[derive(Debug, Clone, PartialEq)]
+pub struct Element {
+    name: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<Element>,
+}
+
+/// Elements a manifest has once at most in their parent, which are merged whatever their
+/// attributes
+const SINGLE_ELEMENTS: &[&str] = &["uses-sdk", "application", "profileable"];
+
+/// Attribute set by a template which differs from the generated one, which it replaces
+#[derive(Debug, PartialEq)]
+pub struct Conflict {
+    pub element: String,
+    pub attribute: String,
+    pub kept: String,
+    pub replaced: String,
+}
+
+impl Element {
+    pub fn new(name: impl Into<String>) -> Element {
+        Element {
+            name: name.into(),
+            attributes: vec![],
+            children: vec![],
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Child elements, in document order
+    pub fn elements(&self) -> &[Element] {
+        &self.children
+    }
+
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(attribute, _)| attribute == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Sets the attribute `name`, whose value is escaped when written
+    pub fn attr(mut self, name: impl Into<String>, value: impl ToString) -> Element {
+        self.attributes.push((name.into(), value.to_string()));
+        self
+    }
+
+    /// Sets the attribute `name` when there is a value
+    pub fn opt_attr(self, name: &str, value: Option<impl ToString>) -> Element {
+        match value {
+            Some(value) => self.attr(name, value),
+            None => self,
+        }
+    }
+
+    /// Sets the attributes, by name
+    pub fn attrs(mut self, attributes: impl IntoIterator<Item = (String, String)>) -> Element {
+        self.attributes.extend(attributes);
+        self
+    }
+
+    pub fn child(mut self, child: Element) -> Element {
+        self.children.push(child);
+        self
+    }
+
+    pub fn children(mut self, children: impl IntoIterator<Item = Element>) -> Element {
+        self.children.extend(children);
+        self
+    }
+
+    /// Whether `other` is the same element as this one, for the merge: elements of the same name
+    /// identified by the same `android:name`, single elements, or else equal elements
+    fn is_same_as(&self, other: &Element) -> bool {
+        if self.name != other.name {
+            return false;
+        }
+        let key = |element: &Element| {
+            element
+                .attribute("android:name")
+                .or_else(|| element.attribute("android:glEsVersion"))
+                .map(str::to_owned)
+        };
+        match (key(self), key(other)) {
+            (None, None) => SINGLE_ELEMENTS.contains(&self.name.as_str()) || self == other,
+            (key, other_key) => key == other_key,
+        }
+    }
+
+    /// Merges `other` into this element: its attributes are added, and its children merged into
+    /// the same child of this element or else appended. The attributes this element sets to a
+    /// different value are kept and returned as conflicts.
+    pub fn merge(&mut self, other: Element) -> Vec<Conflict> {
+        let mut conflicts = vec![];
+        for (name, value) in other.attributes {
+            match self.attribute(&name) {
+                Some(kept) if kept != value => conflicts.push(Conflict {
+                    element: self.name.clone(),
+                    attribute: name,
+                    kept: kept.to_owned(),
+                    replaced: value,
+                }),
+                Some(_) => {}
+                None => self.attributes.push((name, value)),
+            }
+        }
+        for child in other.children {
+            match self
+                .children
+                .iter_mut()
+                .find(|existing| existing.is_same_as(&child))
+            {
+                Some(existing) => conflicts.extend(existing.merge(child)),
+                None => self.children.push(child),
+            }
+        }
+        conflicts
+    }
+
+    /// Returns the document with this element as its root, after the XML declaration
+    pub fn to_document(&self) -> String {
+        let mut document = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n".to_owned();
+        self.write(&mut document, 0);
+        document
+    }
+
+    /// Writes the element at `depth`. Elements without children are written on a single line,
+    /// the others with each attribute after the first on its own line.
+    fn write(&self, out: &mut String, depth: usize) {
+        let indent = "    ".repeat(depth);
+        out.push_str(&indent);
+        out.push('<');
+        out.push_str(&self.name);
+        for (i, (name, value)) in self.attributes.iter().enumerate() {
+            if i > 0 && !self.children.is_empty() {
+                out.push('\n');
+                out.push_str(&indent);
+                out.push_str("        ");
+            } else {
+                out.push(' ');
+            }
+            out.push_str(name);
+            out.push_str("=\"");
+            out.push_str(&escape(value));
+            out.push('"');
+        }
+        if self.children.is_empty() {
+            out.push_str(" />\n");
+            return;
+        }
+        out.push_str(">\n");
+        for child in &self.children {
+            child.write(out, depth + 1);
+        }
+        out.push_str(&indent);
+        out.push_str("</");
+        out.push_str(&self.name);
+        out.push_str(">\n");
+    }
+}
+
+/// Parses a document, returning its root element
+pub fn parse(document: &str) -> CargoResult<Element> {
+    parse_document(document, false)
+}
+
+/// Parses a document whose elements may hold text, like the values files of resources,
+/// returning its root element without the text
+pub fn parse_skipping_text(document: &str) -> CargoResult<Element> {
+    parse_document(document, true)
+}
+
+fn parse_document(document: &str, skip_text: bool) -> CargoResult<Element> {
+    let mut parser = Parser {
+        document,
+        pos: 0,
+        skip_text,
+    };
+    let parsed = parser.skip_misc().and_then(|()| {
+        let root = parser.element()?;
+        parser.skip_misc()?;
+        if parser.pos < document.len() {
+            return Err("unexpected content after the root element".to_owned());
+        }
+        Ok(root)
+    });
+    parsed.map_err(|message| {
+        let line = document[..parser.pos].matches('\n').count() + 1;
+        format_err!("{} at line {}", message, line)
+    })
+}
+
+struct Parser<'a> {
+    document: &'a str,
+    pos: usize,
+    /// Whether text is skipped rather than refused
+    skip_text: bool,
+}
+
+impl Parser<'_> {
+    fn rest(&self) -> &str {
+        &self.document[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    /// Skips `start`, then everything up to `end`
+    fn skip_delimited(&mut self, start: &str, end: &str) -> Result<(), String> {
+        match self.rest()[start.len()..].find(end) {
+            Some(len) => {
+                self.pos += start.len() + len + end.len();
+                Ok(())
+            }
+            None => Err(format!("`{}` without `{}`", start, end)),
+        }
+    }
+
+    /// Skips the whitespace, comments, processing instructions and document type declarations
+    fn skip_misc(&mut self) -> Result<(), String> {
+        loop {
+            self.skip_whitespace();
+            if self.rest().starts_with("<!--") {
+                self.skip_delimited("<!--", "-->")?;
+            } else if self.rest().starts_with("<![CDATA[") {
+                self.skip_delimited("<![CDATA[", "]]>")?;
+            } else if self.rest().starts_with("<?") {
+                self.skip_delimited("<?", "?>")?;
+            } else if self.rest().starts_with("<!") {
+                self.skip_delimited("<!", ">")?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        if self.rest().starts_with(expected) {
+            self.pos += expected.len();
+            Ok(())
+        } else {
+            Err(format!("expected `{}`", expected))
+        }
+    }
+
+    fn name(&mut self) -> Result<&str, String> {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| c.is_whitespace() || "/>=<\"'".contains(c))
+            .unwrap_or(rest.len());
+        if len == 0 {
+            return Err("expected a name".to_owned());
+        }
+        self.pos += len;
+        Ok(&self.document[self.pos - len..self.pos])
+    }
+
+    fn element(&mut self) -> Result<Element, String> {
+        self.expect("<")?;
+        let mut element = Element::new(self.name()?);
+        loop {
+            self.skip_whitespace();
+            if self.rest().starts_with("/>") {
+                self.pos += 2;
+                return Ok(element);
+            }
+            if self.rest().starts_with('>') {
+                self.pos += 1;
+                break;
+            }
+            let name = self.name()?.to_owned();
+            self.skip_whitespace();
+            self.expect("=")?;
+            self.skip_whitespace();
+            let quote = match self.rest().chars().next() {
+                Some(quote) if quote == '"' || quote == '\'' => quote,
+                _ => return Err(format!("expected the quoted value of `{}`", name)),
+            };
+            self.pos += 1;
+            let len = self
+                .rest()
+                .find(quote)
+                .ok_or_else(|| format!("unterminated value of `{}`", name))?;
+            let value = unescape(&self.rest()[..len])?;
+            self.pos += len + 1;
+            if element.attribute(&name).is_some() {
+                return Err(format!("duplicate attribute `{}`", name));
+            }
+            element.attributes.push((name, value));
+        }
+        loop {
+            self.skip_misc()?;
+            if self.rest().starts_with("</") {
+                self.pos += 2;
+                let name = self.name()?;
+                if name != element.name {
+                    return Err(format!("`</{}>` closes `<{}>`", name, element.name));
+                }
+                self.skip_whitespace();
+                self.expect(">")?;
+                return Ok(element);
+            }
+            if self.rest().starts_with('<') {
+                element.children.push(self.element()?);
+            } else if self.rest().is_empty() {
+                return Err(format!("`<{}>` is not closed", element.name));
+            } else if self.skip_text {
+                let rest = self.rest();
+                self.pos += rest.find('<').unwrap_or(rest.len());
+            } else {
+                return Err(format!(
+                    "text in `<{}>`, only elements are supported",
+                    element.name
+                ));
+            }
+        }
+    }
+}
+
+#[test]
+fn escaped_values() {
+    assert_eq!(
+        escape("Tom & \"Jerry\" <3 'em>"),
+        "Tom &amp; &quot;Jerry&quot; &lt;3 &apos;em&gt;"
+    );
+    assert_eq!(
+        Element::new("item")
+            .attr("android:label", "Rock & Roll's")
+            .to_document(),
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <item android:label=\"Rock &amp; Roll&apos;s\" />\n"
+    );
+}
+
+#[test]
+fn written_elements() {
+    let document = Element::new("manifest")
+        .attr("package", "com.example")
+        .attr("android:versionCode", 2)
+        .child(Element::new("uses-sdk"))
+        .child(
+            Element::new("application")
+                .attr("android:label", "app")
+                .opt_attr("android:icon", None::<String>)
+                .opt_attr("android:theme", Some("@style/App"))
+                .children(vec![
+                    Element::new("service")
+                        .attr("android:name", ".A")
+                        .attr("android:enabled", true),
+                    Element::new("service").attr("android:name", ".B"),
+                ]),
+        )
+        .to_document();
+    assert_eq!(
+        document,
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<manifest package="com.example"
+        android:versionCode="2">
+    <uses-sdk />
+    <application android:label="app"
+            android:theme="@style/App">
+        <service android:name=".A" android:enabled="true" />
+        <service android:name=".B" />
+    </application>
+</manifest>
+"#
+    );
+}
+
+#[test]
+fn parsed_documents() {
+    let document = r#"<?xml version="1.0" encoding="utf-8"?>
+<!-- A template -->
+<manifest xmlns:android="http://schemas.android.com/apk/res/android"
+        package="com.example">
+    <application android:label='Rock &amp; Roll&apos;s &#233;&#x21;'>
+        <provider android:name=".Files" android:authorities="com.example.files" />
+    </application>
+</manifest>
+"#;
+    let manifest = parse(document).unwrap();
+    assert_eq!(manifest.name(), "manifest");
+    assert_eq!(manifest.attribute("package"), Some("com.example"));
+    assert_eq!(
+        manifest.children[0].attribute("android:label"),
+        Some("Rock & Roll's é!")
+    );
+    assert_eq!(
+        parse(&manifest.to_document()).unwrap(),
+        manifest,
+        "written documents are parsed back"
+    );
+
+    let error = |document: &str| parse(document).unwrap_err().to_string();
+    assert_eq!(
+        error("<manifest>\n  <application>\n</manifest>"),
+        "`</manifest>` closes `<application>` at line 3"
+    );
+    assert_eq!(
+        error("<manifest>\n  hello\n</manifest>"),
+        "text in `<manifest>`, only elements are supported at line 2"
+    );
+    assert_eq!(
+        error("<manifest package=\"a\" package=\"b\" />"),
+        "duplicate attribute `package` at line 1"
+    );
+    assert_eq!(
+        error("<manifest label=\"&nbsp;\" />"),
+        "unknown reference `&nbsp;` at line 1"
+    );
+    assert_eq!(error("<manifest>"), "`<manifest>` is not closed at line 1");
+    assert_eq!(
+        error("<manifest /><manifest />"),
+        "unexpected content after the root element at line 1"
+    );
+}
+
+#[test]
+fn parsed_values() {
+    let values = parse_skipping_text(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<resources>
+    <string name="app_name">Rock &amp; Roll</string>
+    <string name="welcome">Hello <b>there</b>, <![CDATA[<i>you</i>]]></string>
+    <!-- <string name="commented">out</string> -->
+    <plurals name="lives">
+        <item quantity="one">%d life</item>
+        <item quantity="other">%d lives</item>
+    </plurals>
+</resources>
+"#,
+    )
+    .unwrap();
+    let names = values
+        .elements()
+        .iter()
+        .map(|element| (element.name(), element.attribute("name").unwrap()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        vec![
+            ("string", "app_name"),
+            ("string", "welcome"),
+            ("plurals", "lives")
+        ]
+    );
+    assert_eq!(values.elements()[1].elements()[0].name(), "b");
+    assert!(parse("<resources><string>text</string></resources>").is_err());
+}
+
+#[test]
+fn merged_elements() {
+    let mut template = parse(
+        r#"<manifest package="com.example.custom">
+            <application android:label="Custom">
+                <activity android:name=".MainActivity">
+                    <intent-filter>
+                        <action android:name="android.intent.action.VIEW" />
+                    </intent-filter>
+                </activity>
+                <provider android:name=".Files" />
+            </application>
+        </manifest>"#,
+    )
+    .unwrap();
+    let launcher = Element::new("intent-filter")
+        .child(Element::new("action").attr("android:name", "android.intent.action.MAIN"));
+    let generated = Element::new("manifest")
+        .attr("package", "com.example")
+        .attr("android:versionCode", 2)
+        .child(Element::new("uses-sdk").attr("android:minSdkVersion", 26))
+        .child(
+            Element::new("application")
+                .attr("android:label", "Custom")
+                .attr("android:hasCode", true)
+                .child(Element::new("service").attr("android:name", ".Audio"))
+                .child(
+                    Element::new("activity")
+                        .attr("android:name", ".MainActivity")
+                        .child(Element::new("meta-data").attr("android:name", "lib_name"))
+                        .child(launcher.clone()),
+                ),
+        );
+
+    assert_eq!(
+        template.merge(generated.clone()),
+        vec![Conflict {
+            element: "manifest".to_owned(),
+            attribute: "package".to_owned(),
+            kept: "com.example.custom".to_owned(),
+            replaced: "com.example".to_owned(),
+        }]
+    );
+    assert_eq!(
+        template.to_document(),
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<manifest package="com.example.custom"
+        android:versionCode="2">
+    <application android:label="Custom"
+            android:hasCode="true">
+        <activity android:name=".MainActivity">
+            <intent-filter>
+                <action android:name="android.intent.action.VIEW" />
+            </intent-filter>
+            <meta-data android:name="lib_name" />
+            <intent-filter>
+                <action android:name="android.intent.action.MAIN" />
+            </intent-filter>
+        </activity>
+        <provider android:name=".Files" />
+        <service android:name=".Audio" />
+    </application>
+    <uses-sdk android:minSdkVersion="26" />
+</manifest>
+"#
+    );
+    // Merging again changes nothing
+    let merged = template.clone();
+    template.merge(generated);
+    assert_eq!(template, merged);
+}
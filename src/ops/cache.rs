@@ -0,0 +1,721 @@
+//! Cache of downloaded artifacts, shared by the builds of every package on the machine.
+//!
+//! Entries are stored under the sha256 of their content, `<root>/<2 first digits>/<sha256>`, with
+//! a `.json` sidecar recording where the content came from and when it was last used. Writers of
+//! an entry hold its `.lock` file, so parallel builds fetch an artifact once and never see it half
+//! written: the content is written to a `.partial` file and only renamed into place once its hash
+//! is checked.
+//!
+//! Downloads are also looked up by URL: `<root>/urls/<sha256 of the URL>` holds the sha256 of the
+//! content downloaded from it.
+//!
+//! The Java code compiled by builds is kept in `<root>/java/<key>`, a directory with the classes
+//! and the dex, keyed by the hash of the inputs of javac and d8 rather than of its content. These
+//! entries outlive `cargo clean`, and the least recently used are removed once they take more than
+//! `JAVA_SIZE_LIMIT`.
+
+use super::build::tempfile;
+use super::logcat::parse_duration;
+use anyhow::format_err;
+use cargo::util::{CargoResult, Filesystem, GlobalContext};
+use cargo_util::Sha256;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Environment variable overriding the directory of the cache
+pub const CACHE_DIR_VAR: &str = "CARGO_APK_CACHE_DIR";
+
+/// Directory of the entries of compiled Java code
+const JAVA_DIR: &str = "java";
+
+/// Size of the compiled Java code kept in the cache
+pub const JAVA_SIZE_LIMIT: u64 = 256 << 20;
+
+/// Returns the directory of the cache, `CARGO_APK_CACHE_DIR` or `cargo-quad-apk` in the cache
+/// directory of the platform
+pub fn cache_dir() -> CargoResult<PathBuf> {
+    if let Some(dir) = std::env::var_os(CACHE_DIR_VAR) {
+        return Ok(PathBuf::from(dir));
+    }
+    dirs::cache_dir()
+        .map(|dir| dir.join("cargo-quad-apk"))
+        .ok_or_else(|| {
+            format_err!(
+                "Unable to find the cache directory of the platform, set `{}`",
+                CACHE_DIR_VAR
+            )
+        })
+}
+
+/// Sidecar of an entry
+#[derive(Debug, Serialize, Deserialize)]
+struct EntryMetadata {
+    sha256: String,
+    size: u64,
+    /// URL or description of where the content came from
+    source: String,
+    /// Seconds since the Unix epoch
+    stored: u64,
+    last_used: u64,
+}
+
+pub struct Cache {
+    root: PathBuf,
+}
+
+/// Entries removed by `Cache::clean`
+#[derive(Debug, Default, PartialEq)]
+pub struct Cleaned {
+    pub entries: usize,
+    pub bytes: u64,
+}
+
+impl Cache {
+    /// Returns the cache of `cache_dir()`
+    pub fn open() -> CargoResult<Cache> {
+        Ok(Cache::new(cache_dir()?))
+    }
+
+    pub fn new(root: PathBuf) -> Cache {
+        Cache { root }
+    }
+
+    /// Returns the path of the entry with the content of `sha256`, writing the content with
+    /// `write` first when the cache doesn't have it yet. Fails without storing anything when the
+    /// written content has another hash.
+    pub fn get_or_store(
+        &self,
+        gctx: &GlobalContext,
+        sha256: &str,
+        source: &str,
+        write: impl FnOnce(&mut File) -> CargoResult<()>,
+    ) -> CargoResult<PathBuf> {
+        if sha256.len() != 64 || !sha256.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f')) {
+            return Err(format_err!(
+                "Invalid sha256 `{}`, expected 64 lowercase hexadecimal digits",
+                sha256
+            ));
+        }
+        let dir = self.root.join(&sha256[..2]);
+        let _lock = Filesystem::new(dir.clone()).open_rw_exclusive_create(
+            format!("{}.lock", sha256),
+            gctx,
+            "cache entry",
+        )?;
+        let path = dir.join(sha256);
+        let metadata_path = dir.join(format!("{}.json", sha256));
+        let now = unix_time(SystemTime::now());
+
+        let metadata = match read_metadata(&metadata_path) {
+            Some(metadata) if path.exists() => EntryMetadata {
+                last_used: now,
+                ..metadata
+            },
+            _ => {
+                let partial_path = dir.join(format!("{}.partial", sha256));
+                tempfile::register(&partial_path);
+                let stored = (|| {
+                    write(&mut File::create(&partial_path)?)?;
+                    let actual = Sha256::new().update_path(&partial_path)?.finish_hex();
+                    if actual != sha256 {
+                        return Err(format_err!(
+                            "Content of `{}` has the sha256 {} instead of {}",
+                            source,
+                            actual,
+                            sha256
+                        ));
+                    }
+                    fs::rename(&partial_path, &path)?;
+                    Ok(())
+                })();
+                if stored.is_err() {
+                    let _ = fs::remove_file(&partial_path);
+                }
+                tempfile::unregister(&partial_path);
+                stored?;
+                EntryMetadata {
+                    sha256: sha256.to_owned(),
+                    size: path.metadata()?.len(),
+                    source: source.to_owned(),
+                    stored: now,
+                    last_used: now,
+                }
+            }
+        };
+        fs::write(&metadata_path, serde_json::to_string_pretty(&metadata)?)?;
+        Ok(path)
+    }
+
+    /// Stores `contents`, returning its sha256 and the path of the entry
+    pub fn store(
+        &self,
+        gctx: &GlobalContext,
+        source: &str,
+        contents: &[u8],
+    ) -> CargoResult<(String, PathBuf)> {
+        let sha256 = Sha256::new().update(contents).finish_hex();
+        let path =
+            self.get_or_store(gctx, &sha256, source, |file| Ok(file.write_all(contents)?))?;
+        Ok((sha256, path))
+    }
+
+    /// Returns the path of the entry with the content of `url`, downloading it the first time or
+    /// after the entry was cleaned
+    pub fn download(&self, gctx: &GlobalContext, url: &str) -> CargoResult<PathBuf> {
+        let urls = self.root.join("urls");
+        let url_hash = Sha256::new().update(url.as_bytes()).finish_hex();
+        // Held while downloading, so that parallel builds download a URL once
+        let _lock = Filesystem::new(urls.clone()).open_rw_exclusive_create(
+            format!("{}.lock", url_hash),
+            gctx,
+            "download",
+        )?;
+        let index_path = urls.join(&url_hash);
+        if let Ok(sha256) = fs::read_to_string(&index_path) {
+            return self.get_or_store(gctx, sha256.trim(), url, |file| fetch(gctx, url, file));
+        }
+
+        let partial_path = urls.join(format!("{}.partial", url_hash));
+        tempfile::register(&partial_path);
+        let downloaded = fetch(gctx, url, &mut File::create(&partial_path)?)
+            .and_then(|()| Ok(Sha256::new().update_path(&partial_path)?.finish_hex()))
+            .and_then(|sha256| {
+                let path = self.get_or_store(gctx, &sha256, url, |file| {
+                    std::io::copy(&mut File::open(&partial_path)?, file)?;
+                    Ok(())
+                })?;
+                fs::write(&index_path, &sha256)?;
+                Ok(path)
+            });
+        let _ = fs::remove_file(&partial_path);
+        tempfile::unregister(&partial_path);
+        downloaded
+    }
+
+    /// Returns the path of the entry with the content of `url`, which must have the hash
+    /// `sha256`, downloading it the first time or after the entry was cleaned
+    pub fn download_verified(
+        &self,
+        gctx: &GlobalContext,
+        url: &str,
+        sha256: &str,
+    ) -> CargoResult<PathBuf> {
+        self.get_or_store(gctx, sha256, url, |file| fetch(gctx, url, file))
+    }
+
+    /// Removes the entries last used before `now - older_than`, every entry without `older_than`.
+    /// Entries being written are waited for.
+    pub fn clean(
+        &self,
+        gctx: &GlobalContext,
+        older_than: Option<Duration>,
+        now: SystemTime,
+    ) -> CargoResult<Cleaned> {
+        let limit = older_than.map_or(u64::MAX, |older_than| {
+            unix_time(now).saturating_sub(older_than.as_secs())
+        });
+        let mut cleaned = Cleaned::default();
+        let java_dir = self.root.join(JAVA_DIR);
+        for dir in read_dir_sorted(&self.root)? {
+            if dir == java_dir {
+                continue;
+            }
+            for metadata_path in read_dir_sorted(&dir)? {
+                if metadata_path.extension().map_or(true, |ext| ext != "json") {
+                    continue;
+                }
+                let sha256 = metadata_path
+                    .file_stem()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned();
+                // The lock file is kept, a writer may be waiting on it
+                let _lock = Filesystem::new(dir.clone()).open_rw_exclusive_create(
+                    format!("{}.lock", sha256),
+                    gctx,
+                    "cache entry",
+                )?;
+                // Entries whose sidecar is unreadable are removed as well
+                let metadata = read_metadata(&metadata_path);
+                if metadata.as_ref().map_or(false, |m| m.last_used >= limit) {
+                    continue;
+                }
+                let path = dir.join(&sha256);
+                if let Ok(file_metadata) = path.metadata() {
+                    cleaned.bytes += file_metadata.len();
+                    fs::remove_file(&path)?;
+                }
+                fs::remove_file(&metadata_path)?;
+                cleaned.entries += 1;
+            }
+        }
+        for (key, metadata) in self.java_entries()? {
+            if metadata.as_ref().map_or(false, |m| m.last_used >= limit) {
+                continue;
+            }
+            cleaned.bytes += self.remove_java_entry(gctx, &key)?;
+            cleaned.entries += 1;
+        }
+        Ok(cleaned)
+    }
+
+    /// Runs `read` on the directory of the compiled Java code of `key`, if stored, marking it
+    /// used. The entry can't be removed while it is read.
+    pub fn read_java<T>(
+        &self,
+        gctx: &GlobalContext,
+        key: &str,
+        read: impl FnOnce(&Path) -> CargoResult<T>,
+    ) -> CargoResult<Option<T>> {
+        let dir = self.root.join(JAVA_DIR);
+        let _lock = Filesystem::new(dir.clone()).open_rw_exclusive_create(
+            format!("{}.lock", key),
+            gctx,
+            "cache entry",
+        )?;
+        let path = dir.join(key);
+        let metadata_path = dir.join(format!("{}.json", key));
+        let metadata = match read_metadata(&metadata_path) {
+            Some(metadata) if path.is_dir() => metadata,
+            _ => return Ok(None),
+        };
+        let read = read(&path)?;
+        let metadata = EntryMetadata {
+            last_used: unix_time(SystemTime::now()),
+            ..metadata
+        };
+        fs::write(&metadata_path, serde_json::to_string_pretty(&metadata)?)?;
+        Ok(Some(read))
+    }
+
+    /// Stores the compiled Java code of `key`, which `write` writes into the directory it is
+    /// given, then removes the least recently used entries past `JAVA_SIZE_LIMIT`
+    pub fn store_java(
+        &self,
+        gctx: &GlobalContext,
+        key: &str,
+        source: &str,
+        write: impl FnOnce(&Path) -> CargoResult<()>,
+    ) -> CargoResult<()> {
+        let dir = self.root.join(JAVA_DIR);
+        {
+            let _lock = Filesystem::new(dir.clone()).open_rw_exclusive_create(
+                format!("{}.lock", key),
+                gctx,
+                "cache entry",
+            )?;
+            let path = dir.join(key);
+            let partial_path = dir.join(format!("{}.partial", key));
+            let stored = (|| -> CargoResult<()> {
+                // Left by an interrupted build
+                if partial_path.exists() {
+                    fs::remove_dir_all(&partial_path)?;
+                }
+                fs::create_dir_all(&partial_path)?;
+                write(&partial_path)?;
+                if path.exists() {
+                    fs::remove_dir_all(&path)?;
+                }
+                fs::rename(&partial_path, &path)?;
+                Ok(())
+            })();
+            if stored.is_err() {
+                let _ = fs::remove_dir_all(&partial_path);
+            }
+            stored?;
+            let now = unix_time(SystemTime::now());
+            let metadata = EntryMetadata {
+                sha256: key.to_owned(),
+                size: dir_size(&path)?,
+                source: source.to_owned(),
+                stored: now,
+                last_used: now,
+            };
+            fs::write(
+                dir.join(format!("{}.json", key)),
+                serde_json::to_string_pretty(&metadata)?,
+            )?;
+        }
+        // Without holding the lock of the new entry, so that two builds evicting each other's
+        // entries don't wait on each other
+        self.trim_java(gctx, JAVA_SIZE_LIMIT)?;
+        Ok(())
+    }
+
+    /// Removes the least recently used entries of compiled Java code until the others take at
+    /// most `limit` bytes. The most recently used entry is always kept.
+    fn trim_java(&self, gctx: &GlobalContext, limit: u64) -> CargoResult<Cleaned> {
+        let mut entries = self.java_entries()?;
+        entries.sort_by_key(|(_, metadata)| {
+            std::cmp::Reverse(metadata.as_ref().map_or(0, |m| m.last_used))
+        });
+        let mut kept = 0;
+        let mut cleaned = Cleaned::default();
+        for (index, (key, metadata)) in entries.into_iter().enumerate() {
+            let size = metadata.as_ref().map_or(u64::MAX, |m| m.size);
+            if index == 0 || kept + size <= limit {
+                kept += size;
+                continue;
+            }
+            cleaned.bytes += self.remove_java_entry(gctx, &key)?;
+            cleaned.entries += 1;
+        }
+        Ok(cleaned)
+    }
+
+    /// Returns the keys of the entries of compiled Java code, with their sidecar if readable
+    fn java_entries(&self) -> CargoResult<Vec<(String, Option<EntryMetadata>)>> {
+        Ok(read_dir_sorted(&self.root.join(JAVA_DIR))?
+            .into_iter()
+            .filter(|path| path.extension().map_or(false, |ext| ext == "json"))
+            .map(|metadata_path| {
+                let key = metadata_path
+                    .file_stem()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned();
+                (key, read_metadata(&metadata_path))
+            })
+            .collect())
+    }
+
+    /// Removes an entry of compiled Java code, returning its size
+    fn remove_java_entry(&self, gctx: &GlobalContext, key: &str) -> CargoResult<u64> {
+        let dir = self.root.join(JAVA_DIR);
+        // The lock file is kept, a reader may be waiting on it
+        let _lock = Filesystem::new(dir.clone()).open_rw_exclusive_create(
+            format!("{}.lock", key),
+            gctx,
+            "cache entry",
+        )?;
+        let path = dir.join(key);
+        let mut size = 0;
+        if path.is_dir() {
+            size = dir_size(&path)?;
+            fs::remove_dir_all(&path)?;
+        }
+        let metadata_path = dir.join(format!("{}.json", key));
+        if metadata_path.exists() {
+            fs::remove_file(&metadata_path)?;
+        }
+        Ok(size)
+    }
+}
+
+/// Removes the entries of the cache last used before `--older-than`, or all of them
+pub fn clean(gctx: &GlobalContext, older_than: Option<&str>) -> CargoResult<()> {
+    let older_than = older_than.map(parse_duration).transpose()?;
+    let cache = Cache::open()?;
+    let cleaned = cache.clean(gctx, older_than, SystemTime::now())?;
+    gctx.shell().status(
+        "Removed",
+        format!(
+            "{} cache entries, {:.1} MB, from {}",
+            cleaned.entries,
+            cleaned.bytes as f64 / (1 << 20) as f64,
+            cache.root.display()
+        ),
+    )?;
+    Ok(())
+}
+
+#[cfg(feature = "download")]
+fn fetch(gctx: &GlobalContext, url: &str, file: &mut File) -> CargoResult<()> {
+    use cargo::util::Progress;
+    use curl::easy::Easy;
+
+    gctx.shell().status("Downloading", url)?;
+    let mut easy = Easy::new();
+    easy.url(url)?;
+    easy.follow_location(true)?;
+    easy.fail_on_error(true)?;
+    easy.progress(true)?;
+    let mut progress = Progress::new("Downloading", gctx);
+    let file_name = url.rsplit('/').next().unwrap_or(url).to_owned();
+    let mut written = Ok(());
+    {
+        let mut transfer = easy.transfer();
+        transfer.write_function(|data| match file.write_all(data) {
+            Ok(()) => Ok(data.len()),
+            Err(err) => {
+                written = Err(err);
+                Ok(0)
+            }
+        })?;
+        transfer.progress_function(|download_total, download_now, _, _| {
+            if download_total > 0.0 {
+                drop(progress.tick(download_now as usize, download_total as usize, &file_name));
+            }
+            true
+        })?;
+        let performed = transfer.perform();
+        drop(transfer);
+        written?;
+        performed.map_err(|err| format_err!("Unable to download `{}`: {}", url, err))?;
+    }
+    progress.clear();
+    Ok(())
+}
+
+#[cfg(not(feature = "download"))]
+fn fetch(_: &GlobalContext, url: &str, _: &mut File) -> CargoResult<()> {
+    Err(format_err!(
+        "cargo-quad-apk was built without the `download` feature, so `{}` can't be downloaded",
+        url
+    ))
+}
+
+fn read_metadata(path: &Path) -> Option<EntryMetadata> {
+    serde_json::from_str(&fs::read_to_string(path).ok()?).ok()
+}
+
+fn read_dir_sorted(dir: &Path) -> CargoResult<Vec<PathBuf>> {
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<CargoResult<Vec<_>>>()?;
+    paths.sort();
+    Ok(paths)
+}
+
+/// Returns the size of the files of a directory and its subdirectories
+fn dir_size(dir: &Path) -> CargoResult<u64> {
+    let mut size = 0;
+    for path in read_dir_sorted(dir)? {
+        size += if path.is_dir() {
+            dir_size(&path)?
+        } else {
+            path.metadata()?.len()
+        };
+    }
+    Ok(size)
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+fn test_cache(name: &str) -> Cache {
+    let root = std::env::temp_dir().join(format!(
+        "cargo-quad-apk-cache-{}-{}",
+        name,
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&root);
+    Cache::new(root)
+}
+
+#[test]
+fn concurrent_writers() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let cache = Arc::new(test_cache("concurrent"));
+    let contents = b"classes of desugar_jdk_libs".repeat(1000);
+    let sha256 = Sha256::new().update(&contents).finish_hex();
+    let writes = Arc::new(AtomicUsize::new(0));
+
+    let threads = (0..8)
+        .map(|_| {
+            let (cache, contents, sha256, writes) = (
+                Arc::clone(&cache),
+                contents.clone(),
+                sha256.clone(),
+                Arc::clone(&writes),
+            );
+            std::thread::spawn(move || {
+                let gctx = GlobalContext::default().unwrap();
+                cache
+                    .get_or_store(&gctx, &sha256, "https://example.com/lib.jar", |file| {
+                        writes.fetch_add(1, Ordering::SeqCst);
+                        // Written slowly, so that the other writers have to wait
+                        for chunk in contents.chunks(1000) {
+                            file.write_all(chunk)?;
+                            std::thread::sleep(Duration::from_millis(1));
+                        }
+                        Ok(())
+                    })
+                    .unwrap()
+            })
+        })
+        .collect::<Vec<_>>();
+    let paths = threads
+        .into_iter()
+        .map(|thread| thread.join().unwrap())
+        .collect::<Vec<_>>();
+
+    assert_eq!(writes.load(Ordering::SeqCst), 1);
+    assert!(paths.iter().all(|path| *path == paths[0]));
+    assert_eq!(paths[0], cache.root.join(&sha256[..2]).join(&sha256));
+    assert_eq!(fs::read(&paths[0]).unwrap(), contents);
+    let metadata = read_metadata(&paths[0].with_extension("json")).unwrap();
+    assert_eq!(metadata.source, "https://example.com/lib.jar");
+    assert_eq!(metadata.size, contents.len() as u64);
+
+    // Content with another hash is never stored
+    let gctx = GlobalContext::default().unwrap();
+    let other = "0".repeat(64);
+    let error = cache
+        .get_or_store(&gctx, &other, "corrupted.jar", |file| {
+            Ok(file.write_all(b"corrupted")?)
+        })
+        .unwrap_err();
+    assert!(error.to_string().contains("instead of"), "{}", error);
+    assert!(!cache.root.join("00").join(&other).exists());
+    assert!(!cache
+        .root
+        .join("00")
+        .join(format!("{}.partial", other))
+        .exists());
+    assert!(cache.get_or_store(&gctx, "ABC", "x", |_| Ok(())).is_err());
+
+    fs::remove_dir_all(&cache.root).unwrap();
+}
+
+#[test]
+fn age_based_cleanup() {
+    let cache = test_cache("clean");
+    let gctx = GlobalContext::default().unwrap();
+    let (old, old_path) = cache.store(&gctx, "old.jar", b"old").unwrap();
+    let (recent, recent_path) = cache.store(&gctx, "recent.jar", b"recent").unwrap();
+    // The old entry was last used 40 days ago
+    let old_metadata_path = old_path.with_extension("json");
+    let mut metadata = read_metadata(&old_metadata_path).unwrap();
+    metadata.last_used -= 40 * 24 * 60 * 60;
+    fs::write(
+        &old_metadata_path,
+        serde_json::to_string(&metadata).unwrap(),
+    )
+    .unwrap();
+
+    let month = parse_duration("30d").unwrap();
+    assert_eq!(
+        cache.clean(&gctx, Some(month), SystemTime::now()).unwrap(),
+        Cleaned {
+            entries: 1,
+            bytes: 3,
+        }
+    );
+    assert!(!old_path.exists() && !old_metadata_path.exists());
+    assert!(recent_path.exists());
+    assert_ne!(old, recent);
+
+    // Using an entry again keeps it
+    cache.store(&gctx, "recent.jar", b"recent").unwrap();
+    let in_20_days = SystemTime::now() + Duration::from_secs(20 * 24 * 60 * 60);
+    assert_eq!(
+        cache.clean(&gctx, Some(month), in_20_days).unwrap(),
+        Cleaned::default()
+    );
+    assert_eq!(
+        cache.clean(&gctx, None, SystemTime::now()).unwrap(),
+        Cleaned {
+            entries: 1,
+            bytes: 6,
+        }
+    );
+    assert!(!recent_path.exists());
+
+    fs::remove_dir_all(&cache.root).unwrap();
+}
+
+#[test]
+fn java_entries_are_bounded() {
+    let cache = test_cache("java");
+    let gctx = GlobalContext::default().unwrap();
+    let store = |key: &str, size: usize| {
+        cache
+            .store_java(&gctx, key, "classes of `rust.app`", |dir| {
+                fs::create_dir_all(dir.join("obj/rust/app"))?;
+                fs::write(dir.join("obj/rust/app/MainActivity.class"), vec![0; size])?;
+                fs::write(dir.join("classes.dex"), "dex")?;
+                Ok(())
+            })
+            .unwrap()
+    };
+    let read = |key: &str| {
+        cache
+            .read_java(&gctx, key, |dir| Ok(fs::read(dir.join("classes.dex"))?))
+            .unwrap()
+    };
+    let age = |key: &str, seconds: u64| {
+        let path = cache.root.join(JAVA_DIR).join(format!("{}.json", key));
+        let mut metadata = read_metadata(&path).unwrap();
+        metadata.last_used -= seconds;
+        fs::write(&path, serde_json::to_string(&metadata).unwrap()).unwrap();
+    };
+
+    assert_eq!(read("a"), None);
+    store("a", 100);
+    store("b", 200);
+    store("c", 300);
+    assert_eq!(read("a").unwrap(), b"dex");
+    age("a", 10);
+    age("b", 30);
+    age("c", 20);
+
+    // `b` is the least recently used
+    assert_eq!(
+        cache.trim_java(&gctx, 450).unwrap(),
+        Cleaned {
+            entries: 1,
+            bytes: 203,
+        }
+    );
+    assert_eq!(read("b"), None);
+    assert!(read("c").is_some());
+    // The most recently used entry is kept whatever its size
+    assert_eq!(cache.trim_java(&gctx, 0).unwrap().entries, 1);
+    assert!(read("c").is_some());
+    assert_eq!(read("a"), None);
+
+    // Content entries and Java entries are both cleaned
+    cache.store(&gctx, "old.jar", b"old").unwrap();
+    assert_eq!(
+        cache.clean(&gctx, None, SystemTime::now()).unwrap(),
+        Cleaned {
+            entries: 2,
+            bytes: 306,
+        }
+    );
+    assert_eq!(read("c"), None);
+
+    fs::remove_dir_all(&cache.root).unwrap();
+}
+
+#[cfg(feature = "download")]
+#[test]
+fn downloads_are_looked_up_by_url() {
+    let cache = test_cache("download");
+    let gctx = GlobalContext::default().unwrap();
+    let served = cache.root.join("served.jar");
+    fs::create_dir_all(&cache.root).unwrap();
+    fs::write(&served, "bundletool").unwrap();
+    let url = format!("file://{}", served.display());
+
+    let path = cache.download(&gctx, &url).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "bundletool");
+    assert_eq!(
+        read_metadata(&path.with_extension("json")).unwrap().source,
+        url
+    );
+    // Found without downloading it again
+    fs::write(&served, "changed").unwrap();
+    assert_eq!(cache.download(&gctx, &url).unwrap(), path);
+    assert_eq!(fs::read_to_string(&path).unwrap(), "bundletool");
+
+    // Downloaded again once cleaned, and the content must not have changed
+    cache.clean(&gctx, None, SystemTime::now()).unwrap();
+    let error = cache.download(&gctx, &url).unwrap_err();
+    assert!(error.to_string().contains("instead of"), "{}", error);
+    fs::write(&served, "bundletool").unwrap();
+    assert_eq!(cache.download(&gctx, &url).unwrap(), path);
+
+    fs::remove_dir_all(&cache.root).unwrap();
+}
@@ -0,0 +1,79 @@
+//! `clean`: removes the artifacts of cargo-quad-apk from the target directory, leaving the builds
+//! of cargo for the host alone.
+
+use super::build;
+use anyhow::format_err;
+use cargo::core::Workspace;
+use cargo::util::CargoResult;
+use clap::ArgMatches;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub fn clean(workspace: &Workspace, options: &ArgMatches) -> CargoResult<()> {
+    let profiles: &[bool] = if options.get_flag("release") {
+        &[true]
+    } else {
+        &[false, true]
+    };
+    let apk_only = options.get_flag("apk-only");
+
+    let (mut files, mut bytes) = (0, 0);
+    for &release in profiles {
+        let profile_dir = build::profile_build_directory(workspace, release);
+        let dirs = if apk_only {
+            apk_dirs(&profile_dir)?
+        } else {
+            vec![profile_dir]
+        };
+        for dir in dirs.iter().filter(|dir| dir.exists()) {
+            let (dir_files, dir_bytes) = count_files(dir)?;
+            fs::remove_dir_all(dir)
+                .map_err(|err| format_err!("Unable to remove `{}`: {}", dir.display(), err))?;
+            files += dir_files;
+            bytes += dir_bytes;
+        }
+    }
+
+    workspace.gctx().shell().status(
+        "Removed",
+        format!(
+            "{} files, {:.1} MB total",
+            files,
+            bytes as f64 / (1 << 20) as f64
+        ),
+    )?;
+    Ok(())
+}
+
+/// Returns the directories of the final APKs of a profile, the one of each flavor included
+fn apk_dirs(profile_dir: &Path) -> CargoResult<Vec<PathBuf>> {
+    let mut dirs = vec![profile_dir.join("apk")];
+    let flavors_dir = profile_dir.join("flavors");
+    if flavors_dir.is_dir() {
+        let mut flavor_dirs = vec![];
+        for entry in fs::read_dir(&flavors_dir)? {
+            flavor_dirs.push(entry?.path().join("apk"));
+        }
+        flavor_dirs.sort();
+        dirs.extend(flavor_dirs);
+    }
+    Ok(dirs)
+}
+
+/// Returns the number and the size of the files of a directory and its subdirectories
+fn count_files(dir: &Path) -> CargoResult<(u64, u64)> {
+    let (mut files, mut bytes) = (0, 0);
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            let (dir_files, dir_bytes) = count_files(&entry.path())?;
+            files += dir_files;
+            bytes += dir_bytes;
+        } else {
+            files += 1;
+            bytes += entry.metadata()?.len();
+        }
+    }
+    Ok((files, bytes))
+}
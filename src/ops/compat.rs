@@ -0,0 +1,803 @@
+//! Report of whether an APK can be installed on the connected device, before installing it.
+//!
+//! The facts of the device are gathered over adb, those of the APK from the APK itself, then
+//! every rule of `RULES` is evaluated on them, like the rules of `release-check`.
+
+use super::device::{self, DeviceProperties};
+use super::diff::axml;
+use super::{build, install};
+use crate::config::AndroidConfig;
+use crate::error::FailureKind;
+use anyhow::format_err;
+use cargo::core::Workspace;
+use cargo::util::CargoResult;
+use clap::ArgMatches;
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Density buckets of the resource qualifiers, with the highest dpi each one covers
+const DENSITY_BUCKETS: &[(&str, u32)] = &[
+    ("ldpi", 120),
+    ("mdpi", 160),
+    ("tvdpi", 213),
+    ("hdpi", 240),
+    ("xhdpi", 320),
+    ("xxhdpi", 480),
+    ("xxxhdpi", u32::MAX),
+];
+
+/// IDs of the APK Signature Scheme v2 and v3 blocks of the APK Signing Block
+const SIGNATURE_SCHEME_IDS: &[u32] = &[0x7109_871a, 0xf053_68c0];
+
+/// What the rules know of the device
+#[derive(Debug, Clone, Default)]
+pub struct DeviceFacts {
+    pub api_level: Option<u32>,
+    /// ABIs of the device, in its order of preference
+    pub abis: Vec<String>,
+    /// Density of the screen in dpi
+    pub density: Option<u32>,
+    /// Free space of `/data`
+    pub free_space: Option<u64>,
+    /// Signatures of the package installed with the same name, `None` when it isn't installed
+    pub installed_signatures: Option<Vec<String>>,
+}
+
+/// What the rules know of the APK
+#[derive(Debug, Clone, Default)]
+pub struct ApkFacts {
+    pub package: String,
+    pub min_sdk_version: Option<u32>,
+    /// ABIs of the native libraries
+    pub abis: BTreeSet<String>,
+    /// Density qualifiers of the resource directories, like `xhdpi` or `anydpi`
+    pub densities: BTreeSet<String>,
+    /// Hashes of the signer certificates, as printed by `dumpsys package`
+    pub signatures: Vec<String>,
+    pub size: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct CompatOptions {
+    /// An install with another signing key is uninstalled, with `--force-reinstall`
+    pub force_reinstall: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Pass(String),
+    /// Installs, but not as well as it could
+    Warn(String),
+    /// Installing will fail
+    Fail(String),
+    /// Not enough information to evaluate the rule
+    Skip(String),
+}
+
+pub struct Rule {
+    pub name: &'static str,
+    pub check: fn(&DeviceFacts, &ApkFacts, &CompatOptions) -> Outcome,
+}
+
+pub const RULES: &[Rule] = &[
+    Rule {
+        name: "api-level",
+        check: api_level,
+    },
+    Rule {
+        name: "abis",
+        check: abis,
+    },
+    Rule {
+        name: "storage",
+        check: storage,
+    },
+    Rule {
+        name: "density",
+        check: density,
+    },
+    Rule {
+        name: "signature",
+        check: signature,
+    },
+];
+
+fn api_level(device: &DeviceFacts, apk: &ApkFacts, _: &CompatOptions) -> Outcome {
+    let api_level = match device.api_level {
+        Some(api_level) => api_level,
+        None => return Outcome::Skip("the API level of the device is unknown".to_owned()),
+    };
+    match apk.min_sdk_version {
+        Some(min_sdk) if api_level < min_sdk => Outcome::Fail(format!(
+            "the device runs API {} but the APK requires {}, installing fails with \
+             INSTALL_FAILED_OLDER_SDK, lower `min_sdk_version`",
+            api_level, min_sdk
+        )),
+        Some(min_sdk) => Outcome::Pass(format!(
+            "the device runs API {}, the APK requires {}",
+            api_level, min_sdk
+        )),
+        None => Outcome::Pass(format!(
+            "the device runs API {}, the APK has no minSdkVersion",
+            api_level
+        )),
+    }
+}
+
+fn abis(device: &DeviceFacts, apk: &ApkFacts, _: &CompatOptions) -> Outcome {
+    if apk.abis.is_empty() {
+        return Outcome::Pass("no native libraries".to_owned());
+    }
+    if device.abis.is_empty() {
+        return Outcome::Skip("the ABIs of the device are unknown".to_owned());
+    }
+    let apk_abis = apk.abis.iter().cloned().collect::<Vec<_>>().join(", ");
+    match device.abis.iter().find(|abi| apk.abis.contains(*abi)) {
+        Some(abi) => Outcome::Pass(format!(
+            "the device loads the {} libraries, the APK has {}",
+            abi, apk_abis
+        )),
+        None => Outcome::Fail(format!(
+            "the device supports {} but the APK only has {}, installing fails with \
+             INSTALL_FAILED_NO_MATCHING_ABIS, add one to `build_targets`",
+            device.abis.join(", "),
+            apk_abis
+        )),
+    }
+}
+
+fn storage(device: &DeviceFacts, apk: &ApkFacts, _: &CompatOptions) -> Outcome {
+    let free_space = match device.free_space {
+        Some(free_space) => free_space,
+        None => return Outcome::Skip("the free space of /data is unknown".to_owned()),
+    };
+    let sizes = format!(
+        "{} free on /data for {}",
+        install::format_size(free_space),
+        install::format_size(apk.size)
+    );
+    if free_space < apk.size {
+        Outcome::Fail(format!(
+            "{}, installing fails with INSTALL_FAILED_INSUFFICIENT_STORAGE",
+            sizes
+        ))
+    } else if free_space < apk.size * 2 {
+        Outcome::Warn(format!(
+            "{}, the package manager may lack the room to extract it",
+            sizes
+        ))
+    } else {
+        Outcome::Pass(sizes)
+    }
+}
+
+fn density(device: &DeviceFacts, apk: &ApkFacts, _: &CompatOptions) -> Outcome {
+    let density = match device.density {
+        Some(density) => density,
+        None => return Outcome::Skip("the screen density of the device is unknown".to_owned()),
+    };
+    let bucket = density_bucket(density);
+    let bundled = apk
+        .densities
+        .iter()
+        .filter(|qualifier| *qualifier != "nodpi")
+        .cloned()
+        .collect::<Vec<_>>();
+    if bundled.is_empty() {
+        Outcome::Pass("no density-specific resources".to_owned())
+    } else if bundled
+        .iter()
+        .any(|qualifier| qualifier == bucket || qualifier == "anydpi")
+    {
+        Outcome::Pass(format!(
+            "{} resources for the {} dpi screen",
+            bucket, density
+        ))
+    } else {
+        Outcome::Warn(format!(
+            "no {} resources for the {} dpi screen, Android scales those of {}",
+            bucket,
+            density,
+            bundled.join(", ")
+        ))
+    }
+}
+
+fn signature(device: &DeviceFacts, apk: &ApkFacts, options: &CompatOptions) -> Outcome {
+    let installed = match &device.installed_signatures {
+        Some(installed) => installed,
+        None => return Outcome::Pass(format!("`{}` is not installed", apk.package)),
+    };
+    if installed.is_empty() {
+        return Outcome::Skip(format!(
+            "the signatures of the installed `{}` are unknown",
+            apk.package
+        ));
+    }
+    if apk.signatures.is_empty() {
+        return Outcome::Skip("the APK has no v2 or v3 signature to compare".to_owned());
+    }
+    if apk.signatures.iter().any(|hash| installed.contains(hash)) {
+        Outcome::Pass(format!(
+            "`{}` is installed with the same signing key",
+            apk.package
+        ))
+    } else if options.force_reinstall {
+        Outcome::Warn(format!(
+            "`{}` is installed with another signing key, `--force-reinstall` uninstalls it \
+             along with its data",
+            apk.package
+        ))
+    } else {
+        Outcome::Fail(format!(
+            "`{}` is installed with another signing key, updating fails with \
+             INSTALL_FAILED_UPDATE_INCOMPATIBLE, uninstall it or give `--force-reinstall`",
+            apk.package
+        ))
+    }
+}
+
+/// Returns the density qualifier of the resources Android prefers for a screen density
+fn density_bucket(density: u32) -> &'static str {
+    DENSITY_BUCKETS
+        .iter()
+        .find(|(_, highest)| density <= *highest)
+        .unwrap()
+        .0
+}
+
+/// Evaluates every rule, in the order of the table
+pub fn evaluate(
+    device: &DeviceFacts,
+    apk: &ApkFacts,
+    options: &CompatOptions,
+) -> Vec<(&'static str, Outcome)> {
+    RULES
+        .iter()
+        .map(|rule| (rule.name, (rule.check)(device, apk, options)))
+        .collect()
+}
+
+/// Lists the outcome of each rule, with its explanation
+fn render_outcomes(outcomes: &[(&str, Outcome)]) -> String {
+    let width = outcomes
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0);
+    outcomes
+        .iter()
+        .map(|(name, outcome)| {
+            let (status, explanation) = match outcome {
+                Outcome::Pass(explanation) => ("pass", explanation),
+                Outcome::Warn(explanation) => ("warn", explanation),
+                Outcome::Fail(explanation) => ("FAIL", explanation),
+                Outcome::Skip(explanation) => ("skip", explanation),
+            };
+            format!(
+                "  {}  {:width$}  {}\n",
+                status,
+                name,
+                explanation,
+                width = width
+            )
+        })
+        .collect()
+}
+
+impl DeviceFacts {
+    /// Gathers the facts of the selected device, with the install of `package` if any
+    fn read(config: &AndroidConfig, package: &str) -> CargoResult<DeviceFacts> {
+        let DeviceProperties {
+            api_level,
+            abis,
+            density,
+            ..
+        } = device::device_properties(config)?;
+        let adb = config.adb_command()?;
+        let output = adb
+            .clone()
+            .arg("shell")
+            .arg("dumpsys")
+            .arg("package")
+            .arg(package)
+            .exec_with_output()?;
+        Ok(DeviceFacts {
+            api_level,
+            abis,
+            density,
+            free_space: install::data_free_space(&adb),
+            installed_signatures: parse_installed_signatures(
+                &String::from_utf8_lossy(&output.stdout),
+                package,
+            ),
+        })
+    }
+}
+
+/// Finds the hashes of the signatures of a package in the output of `dumpsys package <package>`,
+/// `None` when it is not installed
+fn parse_installed_signatures(dumpsys: &str, package: &str) -> Option<Vec<String>> {
+    let mut lines = install::package_section(dumpsys, package).peekable();
+    lines.peek()?;
+    let signatures = lines
+        .filter_map(|line| line.trim().strip_prefix("signatures=PackageSignatures{"))
+        .next()
+        .and_then(|signatures| {
+            // `{9fe5e2b version:2, signatures:[22a0b0b8], past signatures:[]}` since Android 9,
+            // `{3b0e8f25 [1f9ae3c2]}` before
+            let list = match signatures.split_once("signatures:[") {
+                Some((_, list)) => list,
+                None => signatures.split_once('[')?.1,
+            };
+            Some(
+                list.split(']')
+                    .next()?
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|hash| !hash.is_empty())
+                    .map(str::to_owned)
+                    .collect(),
+            )
+        })
+        .unwrap_or_default();
+    Some(signatures)
+}
+
+impl ApkFacts {
+    /// Reads the facts stored in the APK itself: its manifest, files, signatures and size
+    fn read<R: Read + Seek>(mut reader: R) -> CargoResult<ApkFacts> {
+        let signatures = signing_certificates(&mut reader)?
+            .iter()
+            .map(|certificate| format!("{:x}", java_hash_code(certificate)))
+            .collect();
+        let size = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+        let mut archive = zip::ZipArchive::new(reader)?;
+
+        let mut facts = ApkFacts {
+            signatures,
+            size,
+            ..ApkFacts::default()
+        };
+        for name in archive.file_names() {
+            let mut parts = name.split('/');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some("lib"), Some(abi), Some(file)) if !file.is_empty() => {
+                    facts.abis.insert(abi.to_owned());
+                }
+                // Like `res/drawable-xhdpi-v4/icon.png`
+                (Some("res"), Some(directory), Some(_)) => facts.densities.extend(
+                    directory
+                        .split('-')
+                        .skip(1)
+                        .filter(|qualifier| qualifier.ends_with("dpi"))
+                        .map(str::to_owned),
+                ),
+                _ => {}
+            }
+        }
+
+        let mut manifest_data = vec![];
+        archive
+            .by_name("AndroidManifest.xml")?
+            .read_to_end(&mut manifest_data)?;
+        for element in axml::parse(&manifest_data)? {
+            match element.name.as_str() {
+                "manifest" => facts.package = element.attribute("package").unwrap_or("").to_owned(),
+                "uses-sdk" => {
+                    facts.min_sdk_version = element
+                        .attribute("minSdkVersion")
+                        .and_then(|v| v.parse().ok())
+                }
+                _ => {}
+            }
+        }
+        Ok(facts)
+    }
+}
+
+/// Returns the hash of a byte array in Java, `Arrays.hashCode`, which `dumpsys package` prints
+/// for the certificates of the signatures
+fn java_hash_code(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(1i32, |hash, &byte| {
+        hash.wrapping_mul(31).wrapping_add(byte as i8 as i32)
+    }) as u32
+}
+
+/// Reads the DER certificates of the signers of the APK Signature Scheme v2 and v3 blocks, none
+/// when the APK only has a v1 signature or none at all
+fn signing_certificates<R: Read + Seek>(reader: &mut R) -> CargoResult<Vec<Vec<u8>>> {
+    let invalid = || format_err!("Invalid APK Signing Block");
+
+    // The End of Central Directory record is followed by a comment of at most 64 KiB
+    let size = reader.seek(SeekFrom::End(0))?;
+    let tail_size = size.min(22 + 0xffff);
+    reader.seek(SeekFrom::Start(size - tail_size))?;
+    let mut tail = vec![0; tail_size as usize];
+    reader.read_exact(&mut tail)?;
+    let eocd = match (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|&i| tail[i..i + 4] == [0x50, 0x4b, 0x05, 0x06])
+    {
+        Some(eocd) => eocd,
+        None => {
+            return Err(format_err!(
+                "Invalid APK, it has no End of Central Directory"
+            ))
+        }
+    };
+    let central_directory = u32::from_le_bytes(tail[eocd + 16..eocd + 20].try_into()?) as u64;
+
+    if central_directory > size {
+        return Err(format_err!(
+            "Invalid APK, its Central Directory is past its end"
+        ));
+    }
+    // The block ends with its size and magic, right before the Central Directory
+    if central_directory < 32 {
+        return Ok(vec![]);
+    }
+    reader.seek(SeekFrom::Start(central_directory - 24))?;
+    let mut footer = [0; 24];
+    reader.read_exact(&mut footer)?;
+    if &footer[8..] != b"APK Sig Block 42" {
+        return Ok(vec![]);
+    }
+    let block_size = u64::from_le_bytes(footer[..8].try_into()?);
+    if block_size < 24 || block_size + 8 > central_directory {
+        return Err(invalid());
+    }
+    reader.seek(SeekFrom::Start(central_directory - block_size - 8))?;
+    let mut block = vec![0; block_size as usize + 8];
+    reader.read_exact(&mut block)?;
+    let mut pairs = &block[8..block.len() - 24];
+
+    let mut certificates = vec![];
+    while !pairs.is_empty() {
+        let pair_size = u64::from_le_bytes(take(&mut pairs, 8).ok_or_else(invalid)?.try_into()?);
+        let mut pair = take(&mut pairs, pair_size as usize).ok_or_else(invalid)?;
+        let id = u32::from_le_bytes(take(&mut pair, 4).ok_or_else(invalid)?.try_into()?);
+        if !SIGNATURE_SCHEME_IDS.contains(&id) {
+            continue;
+        }
+        let mut signers = length_prefixed(&mut pair).ok_or_else(invalid)?;
+        while !signers.is_empty() {
+            let mut signer = length_prefixed(&mut signers).ok_or_else(invalid)?;
+            let mut signed_data = length_prefixed(&mut signer).ok_or_else(invalid)?;
+            let _digests = length_prefixed(&mut signed_data).ok_or_else(invalid)?;
+            let mut signer_certificates = length_prefixed(&mut signed_data).ok_or_else(invalid)?;
+            while !signer_certificates.is_empty() {
+                let certificate = length_prefixed(&mut signer_certificates).ok_or_else(invalid)?;
+                if !certificates.iter().any(|known| known == certificate) {
+                    certificates.push(certificate.to_vec());
+                }
+            }
+        }
+    }
+    Ok(certificates)
+}
+
+/// Splits the first `len` bytes off `data`
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if data.len() < len {
+        return None;
+    }
+    let (head, rest) = data.split_at(len);
+    *data = rest;
+    Some(head)
+}
+
+/// Splits a value prefixed by its 32-bit length off `data`
+fn length_prefixed<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = u32::from_le_bytes(take(data, 4)?.try_into().ok()?);
+    take(data, len as usize)
+}
+
+/// Checks that an APK can be installed on the selected device, printing the outcome of each
+/// rule. Fails on a rule which makes the install fail, unless `force`.
+pub fn check_device_compat(
+    workspace: &Workspace,
+    config: &AndroidConfig,
+    apk: &Path,
+    options: &CompatOptions,
+    force: bool,
+) -> CargoResult<()> {
+    let file = File::open(apk)
+        .map_err(|err| format_err!("Unable to open '{}': {}", apk.display(), err))?;
+    let apk_facts = ApkFacts::read(file)?;
+    let device_facts = DeviceFacts::read(config, &apk_facts.package)
+        .map_err(|err| FailureKind::Device.mark(err))?;
+    let outcomes = evaluate(&device_facts, &apk_facts, options);
+
+    let mut shell = workspace.gctx().shell();
+    shell.status("Checking", format!("{} for the device", apk.display()))?;
+    drop(write!(shell.out(), "{}", render_outcomes(&outcomes)));
+    let blockers = outcomes
+        .iter()
+        .filter(|(_, outcome)| matches!(outcome, Outcome::Fail(_)))
+        .count();
+    if blockers == 0 {
+        return Ok(());
+    }
+    if force {
+        shell.warn(format!(
+            "installing '{}' despite {} failed compatibility checks, as `--force` is given",
+            apk.display(),
+            blockers
+        ))?;
+        return Ok(());
+    }
+    Err(FailureKind::Device.mark(format_err!(
+        "'{}' can't be installed on the device, {} compatibility checks failed. Give `--force` \
+         to try anyway.",
+        apk.display(),
+        blockers
+    )))
+}
+
+/// `compat`: checks the APKs given with `--apk`, or else those of the last build
+pub fn compat(
+    workspace: &Workspace,
+    config: &AndroidConfig,
+    options: &ArgMatches,
+) -> CargoResult<()> {
+    device::check_device_selected(config)?;
+    let apks: Vec<PathBuf> = match options.get_one::<String>("apk") {
+        Some(apk) => vec![workspace.gctx().cwd().join(apk)],
+        None => build::last_build_report(workspace, config)
+            .map_err(|_| {
+                format_err!(
+                    "No build found, run `cargo quad-apk build{}` first",
+                    if config.release { " --release" } else { "" }
+                )
+            })?
+            .apks
+            .into_iter()
+            .map(|apk| apk.path)
+            .collect(),
+    };
+    if apks.is_empty() {
+        return Err(format_err!("No APKs to check."));
+    }
+
+    let compat_options = CompatOptions {
+        force_reinstall: options.get_flag("force-reinstall"),
+    };
+    for apk in &apks {
+        check_device_compat(
+            workspace,
+            config,
+            apk,
+            &compat_options,
+            options.get_flag("force"),
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+fn compatible_facts() -> (DeviceFacts, ApkFacts) {
+    let device = DeviceFacts {
+        api_level: Some(34),
+        abis: vec!["arm64-v8a".to_owned(), "armeabi-v7a".to_owned()],
+        density: Some(420),
+        free_space: Some(8 << 30),
+        installed_signatures: Some(vec!["22a0b0b8".to_owned()]),
+    };
+    let apk = ApkFacts {
+        package: "rust.app".to_owned(),
+        min_sdk_version: Some(21),
+        abis: ["arm64-v8a", "armeabi-v7a"]
+            .iter()
+            .map(|abi| abi.to_string())
+            .collect(),
+        densities: ["mdpi", "xhdpi", "xxhdpi"]
+            .iter()
+            .map(|qualifier| qualifier.to_string())
+            .collect(),
+        signatures: vec!["22a0b0b8".to_owned()],
+        size: 20 << 20,
+    };
+    (device, apk)
+}
+
+#[cfg(test)]
+fn check(device: &DeviceFacts, apk: &ApkFacts, rule: &str) -> Outcome {
+    let rule = RULES.iter().find(|r| r.name == rule).unwrap();
+    (rule.check)(device, apk, &CompatOptions::default())
+}
+
+#[test]
+fn compatible_device() {
+    let (device, apk) = compatible_facts();
+    let outcomes = evaluate(&device, &apk, &CompatOptions::default());
+    assert_eq!(outcomes.len(), RULES.len());
+    assert!(outcomes
+        .iter()
+        .all(|(_, outcome)| matches!(outcome, Outcome::Pass(_))));
+}
+
+#[test]
+fn api_level_rule() {
+    let (mut device, apk) = compatible_facts();
+    device.api_level = Some(19);
+    assert!(matches!(
+        check(&device, &apk, "api-level"),
+        Outcome::Fail(_)
+    ));
+    device.api_level = None;
+    assert!(matches!(
+        check(&device, &apk, "api-level"),
+        Outcome::Skip(_)
+    ));
+}
+
+#[test]
+fn abis_rule() {
+    let (mut device, mut apk) = compatible_facts();
+    device.abis = vec!["x86_64".to_owned(), "x86".to_owned()];
+    assert_eq!(
+        check(&device, &apk, "abis"),
+        Outcome::Fail(
+            "the device supports x86_64, x86 but the APK only has arm64-v8a, armeabi-v7a, \
+             installing fails with INSTALL_FAILED_NO_MATCHING_ABIS, add one to `build_targets`"
+                .to_owned()
+        )
+    );
+    apk.abis.clear();
+    assert!(matches!(check(&device, &apk, "abis"), Outcome::Pass(_)));
+}
+
+#[test]
+fn storage_rule() {
+    let (mut device, apk) = compatible_facts();
+    device.free_space = Some(10 << 20);
+    assert!(matches!(check(&device, &apk, "storage"), Outcome::Fail(_)));
+    device.free_space = Some(30 << 20);
+    assert!(matches!(check(&device, &apk, "storage"), Outcome::Warn(_)));
+    device.free_space = None;
+    assert!(matches!(check(&device, &apk, "storage"), Outcome::Skip(_)));
+}
+
+#[test]
+fn density_rule() {
+    let (mut device, mut apk) = compatible_facts();
+    assert_eq!(
+        check(&device, &apk, "density"),
+        Outcome::Pass("xxhdpi resources for the 420 dpi screen".to_owned())
+    );
+    device.density = Some(640);
+    assert_eq!(
+        check(&device, &apk, "density"),
+        Outcome::Warn(
+            "no xxxhdpi resources for the 640 dpi screen, Android scales those of mdpi, xhdpi, \
+             xxhdpi"
+                .to_owned()
+        )
+    );
+    apk.densities.insert("anydpi".to_owned());
+    assert!(matches!(check(&device, &apk, "density"), Outcome::Pass(_)));
+    apk.densities = Some("nodpi".to_owned()).into_iter().collect();
+    assert_eq!(
+        check(&device, &apk, "density"),
+        Outcome::Pass("no density-specific resources".to_owned())
+    );
+    assert_eq!(density_bucket(160), "mdpi");
+    assert_eq!(density_bucket(161), "tvdpi");
+}
+
+#[test]
+fn signature_rule() {
+    let (mut device, apk) = compatible_facts();
+    device.installed_signatures = Some(vec!["5f1d3c2a".to_owned()]);
+    assert!(matches!(
+        check(&device, &apk, "signature"),
+        Outcome::Fail(_)
+    ));
+    let options = CompatOptions {
+        force_reinstall: true,
+    };
+    assert!(matches!(
+        signature(&device, &apk, &options),
+        Outcome::Warn(_)
+    ));
+    device.installed_signatures = Some(vec![]);
+    assert!(matches!(
+        check(&device, &apk, "signature"),
+        Outcome::Skip(_)
+    ));
+    device.installed_signatures = None;
+    assert_eq!(
+        check(&device, &apk, "signature"),
+        Outcome::Pass("`rust.app` is not installed".to_owned())
+    );
+}
+
+#[test]
+fn installed_signatures() {
+    // Android 9 and later
+    let dumpsys = "Packages:
+  Package [rust.app] (9e31c7f):
+    userId=10153
+    versionCode=3 minSdk=26 targetSdk=31
+    signatures=PackageSignatures{9fe5e2b version:2, signatures:[22a0b0b8], past signatures:[]}
+";
+    assert_eq!(
+        parse_installed_signatures(dumpsys, "rust.app"),
+        Some(vec!["22a0b0b8".to_owned()])
+    );
+
+    // Android 5
+    let dumpsys = "Packages:
+  Package [rust.app] (2a6b3c1c):
+    userId=10061 gids=[]
+    signatures=PackageSignatures{3b0e8f25 [1f9ae3c2, 7c01d2e4]}
+";
+    assert_eq!(
+        parse_installed_signatures(dumpsys, "rust.app"),
+        Some(vec!["1f9ae3c2".to_owned(), "7c01d2e4".to_owned()])
+    );
+
+    assert_eq!(
+        parse_installed_signatures("Unable to find package: rust.app\n", "rust.app"),
+        None
+    );
+}
+
+#[test]
+fn apk_signing_block_certificates() {
+    fn prefixed(data: &[u8]) -> Vec<u8> {
+        let mut prefixed = (data.len() as u32).to_le_bytes().to_vec();
+        prefixed.extend_from_slice(data);
+        prefixed
+    }
+    let certificate = b"DER certificate".to_vec();
+    let signed_data = [
+        prefixed(&[]),
+        prefixed(&prefixed(&certificate)),
+        prefixed(&[]),
+    ]
+    .concat();
+    let signers = prefixed(&prefixed(&prefixed(&signed_data)));
+    let mut pair = 0x7109_871au32.to_le_bytes().to_vec();
+    pair.extend(signers);
+    let pairs = [(pair.len() as u64).to_le_bytes().to_vec(), pair].concat();
+    let block_size = (pairs.len() + 24) as u64;
+    let block = [
+        block_size.to_le_bytes().to_vec(),
+        pairs,
+        block_size.to_le_bytes().to_vec(),
+        b"APK Sig Block 42".to_vec(),
+    ]
+    .concat();
+
+    // The entries are not read, only the End of Central Directory pointing after the block
+    let mut apk = vec![0; 10];
+    apk.extend(block);
+    let mut eocd = vec![0x50, 0x4b, 0x05, 0x06];
+    eocd.extend([0; 12]);
+    eocd.extend((apk.len() as u32).to_le_bytes());
+    eocd.extend([0; 2]);
+    apk.extend(&eocd);
+
+    assert_eq!(
+        signing_certificates(&mut std::io::Cursor::new(&apk)).unwrap(),
+        vec![certificate]
+    );
+    // Only signed with v1, or not signed
+    let mut unsigned = vec![0; 40];
+    unsigned.extend(&eocd[..16]);
+    unsigned.extend(40u32.to_le_bytes());
+    unsigned.extend([0; 2]);
+    assert_eq!(
+        signing_certificates(&mut std::io::Cursor::new(&unsigned)).unwrap(),
+        Vec::<Vec<u8>>::new()
+    );
+
+    assert_eq!(java_hash_code(&[1, 2, 3]), 30817);
+    assert_eq!(format!("{:x}", java_hash_code(&[0xff; 8])), "f5b99e81");
+}
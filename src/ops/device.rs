@@ -0,0 +1,658 @@
+use crate::config::AndroidConfig;
+use crate::error::{FailureKind, ResultExt};
+use anyhow::format_err;
+use cargo::core::Workspace;
+use cargo::util::CargoResult;
+use cargo_util::ProcessBuilder;
+use clap::ArgMatches;
+use std::collections::HashMap;
+use std::io::Write;
+
+/// Returns the user selected with `--user`, if any
+pub fn selected_user(options: &ArgMatches) -> CargoResult<Option<u32>> {
+    options
+        .get_one::<String>("user")
+        .map(|user| {
+            user.parse()
+                .map_err(|_| format_err!("Invalid user id `{}`, expected a number", user))
+        })
+        .transpose()
+}
+
+/// Fails when several devices are connected and none was selected with `--device` or the
+/// `ANDROID_SERIAL` environment variable of adb, listing their serials. adb would otherwise fail
+/// with "more than one device/emulator" without telling which ones.
+pub fn check_device_selected(config: &AndroidConfig) -> CargoResult<()> {
+    if config.device.is_some() || std::env::var_os("ANDROID_SERIAL").is_some() {
+        return Ok(());
+    }
+    // Failures are left to the commands which need the device
+    let output = match ProcessBuilder::new(config.adb()?)
+        .arg("devices")
+        .exec_with_output()
+    {
+        Ok(output) => output,
+        Err(_) => return Ok(()),
+    };
+    let devices = parse_devices(&String::from_utf8_lossy(&output.stdout));
+    if devices.len() > 1 {
+        return Err(FailureKind::Device.mark(format_err!(
+            "{} devices are connected, select one with `--device <SERIAL>`:\n{}",
+            devices.len(),
+            devices
+                .iter()
+                .map(|device| format!("  {}\t{}", device.serial, device.state))
+                .collect::<Vec<_>>()
+                .join("\n")
+        )));
+    }
+    Ok(())
+}
+
+/// Restricts the build of `run` to the ABI of the selected device among the `build_targets`.
+/// They are left as they are, with a note, when no device answers or it runs none of them.
+/// Several connected devices are left to the usual error of `run`.
+pub fn use_device_abi(workspace: &Workspace, config: &mut AndroidConfig) -> CargoResult<()> {
+    if config.build_targets.len() < 2 || check_device_selected(config).is_err() {
+        return Ok(());
+    }
+    let device_abis = match abi_list(config) {
+        Ok(device_abis) => device_abis,
+        Err(_) => {
+            workspace
+                .gctx()
+                .shell()
+                .note("no device is connected, building every ABI of `build_targets`")?;
+            return Ok(());
+        }
+    };
+    let build_abis = config
+        .build_targets
+        .iter()
+        .map(|target| target.android_abi())
+        .collect::<Vec<_>>();
+    match preferred_abi(&device_abis, &build_abis) {
+        Ok(abi) => {
+            let target = config
+                .build_targets
+                .iter()
+                .copied()
+                .find(|target| target.android_abi() == abi)
+                .unwrap();
+            workspace.gctx().shell().status(
+                "Building",
+                format!(
+                    "{} only, the ABI of the device, `--all-abis` builds every one",
+                    abi
+                ),
+            )?;
+            config.override_build_targets(vec![target]);
+        }
+        Err(_) => {
+            workspace.gctx().shell().note(format!(
+                "the device runs none of the ABIs of `build_targets` ({}), building all of them",
+                device_abis.join(", ")
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+/// Device listed by `adb devices`
+#[derive(Debug, PartialEq)]
+pub(super) struct ConnectedDevice {
+    pub serial: String,
+    /// `device` once it accepts commands, `unauthorized`, `offline`...
+    pub state: String,
+    /// Only listed by `adb devices -l`
+    model: Option<String>,
+}
+
+/// Parses the devices listed by `adb devices`, with the details of `-l` if given
+pub(super) fn parse_devices(output: &str) -> Vec<ConnectedDevice> {
+    output
+        .lines()
+        .skip_while(|line| !line.starts_with("List of devices attached"))
+        .skip(1)
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let serial = fields.next()?.to_owned();
+            let state = fields.next()?.to_owned();
+            let model = fields
+                .find_map(|field| field.strip_prefix("model:"))
+                .map(|model| model.replace('_', " "));
+            Some(ConnectedDevice {
+                serial,
+                state,
+                model,
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn connected_devices() {
+    let output = "* daemon not running; starting now at tcp:5037\n\
+                  * daemon started successfully\n\
+                  List of devices attached\n\
+                  emulator-5554\tdevice\n\
+                  R58M12ABCDE\tunauthorized\n\
+                  \n";
+    assert_eq!(
+        parse_devices(output),
+        vec![
+            ConnectedDevice {
+                serial: "emulator-5554".to_owned(),
+                state: "device".to_owned(),
+                model: None,
+            },
+            ConnectedDevice {
+                serial: "R58M12ABCDE".to_owned(),
+                state: "unauthorized".to_owned(),
+                model: None,
+            },
+        ]
+    );
+    assert!(parse_devices("List of devices attached\n\n").is_empty());
+    assert!(parse_devices("").is_empty());
+
+    let output = "List of devices attached\n\
+                  emulator-5554          device product:sdk_gphone64_x86_64 \
+                  model:sdk_gphone64_x86_64 device:emu64x transport_id:1\n\
+                  R58M12ABCDE            unauthorized usb:1-1 transport_id:2\n";
+    let devices = parse_devices(output);
+    assert_eq!(devices[0].model.as_deref(), Some("sdk gphone64 x86 64"));
+    assert_eq!(devices[1].state, "unauthorized");
+    assert_eq!(devices[1].model, None);
+}
+
+/// Prints the connected devices with their model, Android version and ABIs, warning about those
+/// which can't run any of the `build_targets`
+pub fn list_devices(workspace: &Workspace, config: &AndroidConfig) -> CargoResult<()> {
+    let output = ProcessBuilder::new(config.adb()?)
+        .arg("devices")
+        .arg("-l")
+        .exec_with_output()
+        .failure_kind(FailureKind::Device)?;
+    let devices = parse_devices(&String::from_utf8_lossy(&output.stdout));
+    if devices.is_empty() {
+        return Err(FailureKind::Device.mark(format_err!("No device is connected")));
+    }
+
+    let build_abis = config
+        .build_targets
+        .iter()
+        .map(|target| target.android_abi())
+        .collect::<Vec<_>>();
+    let mut rows = vec![];
+    let mut warnings = vec![];
+    for device in devices {
+        // Devices which aren't authorized or are offline can't be queried
+        let (version, abis) = if device.state == "device" {
+            let mut device_config = config.clone();
+            device_config.device = Some(device.serial.clone());
+            let properties = device_properties(&device_config).failure_kind(FailureKind::Device)?;
+            warnings.extend(abi_mismatch_warning(
+                &device.serial,
+                &properties.abis,
+                &build_abis,
+            ));
+            (properties.release, properties.abis.join(","))
+        } else {
+            ("-".to_owned(), format!("({})", device.state))
+        };
+        rows.push([
+            device.serial,
+            device.model.unwrap_or_else(|| "-".to_owned()),
+            version,
+            abis,
+        ]);
+    }
+
+    let mut shell = workspace.gctx().shell();
+    drop(write!(shell.out(), "{}", render_devices(&rows)));
+    for warning in warnings {
+        shell.warn(warning)?;
+    }
+    Ok(())
+}
+
+/// Renders the rows of serial, model, Android version and ABIs as a table
+fn render_devices(rows: &[[String; 4]]) -> String {
+    let header = ["SERIAL", "MODEL", "ANDROID", "ABIS"];
+    let width = |column: usize| {
+        rows.iter()
+            .map(|row| row[column].len())
+            .chain(Some(header[column].len()))
+            .max()
+            .unwrap()
+    };
+    let widths = [width(0), width(1), width(2)];
+    let line = |row: [&str; 4]| {
+        format!(
+            "{:<w0$} {:<w1$} {:<w2$} {}\n",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2]
+        )
+    };
+    let mut table = line(header);
+    for row in rows {
+        table.push_str(&line([&row[0], &row[1], &row[2], &row[3]]));
+    }
+    table
+}
+
+/// Returns a warning when a device supports none of the ABIs of the `build_targets`, as
+/// installing then fails with `INSTALL_FAILED_NO_MATCHING_ABIS`
+fn abi_mismatch_warning(
+    serial: &str,
+    device_abis: &[String],
+    build_abis: &[&str],
+) -> Option<String> {
+    if preferred_abi(device_abis, build_abis).is_ok() {
+        return None;
+    }
+    Some(format!(
+        "device {} supports the ABIs {} but `build_targets` only has {}, installing would fail \
+         with INSTALL_FAILED_NO_MATCHING_ABIS",
+        serial,
+        device_abis.join(", "),
+        build_abis.join(", ")
+    ))
+}
+
+#[test]
+fn device_table() {
+    let rows = [
+        [
+            "emulator-5554".to_owned(),
+            "sdk gphone64 x86 64".to_owned(),
+            "14".to_owned(),
+            "x86_64,arm64-v8a".to_owned(),
+        ],
+        [
+            "R58M12ABCDE".to_owned(),
+            "-".to_owned(),
+            "-".to_owned(),
+            "(unauthorized)".to_owned(),
+        ],
+    ];
+    assert_eq!(
+        render_devices(&rows),
+        "SERIAL        MODEL               ANDROID ABIS\n\
+         emulator-5554 sdk gphone64 x86 64 14      x86_64,arm64-v8a\n\
+         R58M12ABCDE   -                   -       (unauthorized)\n"
+    );
+
+    let abis = vec!["x86_64".to_owned(), "x86".to_owned()];
+    assert_eq!(
+        abi_mismatch_warning("emulator-5554", &abis, &["x86_64"]),
+        None
+    );
+    assert_eq!(
+        abi_mismatch_warning("emulator-5554", &abis, &["arm64-v8a", "armeabi-v7a"]).unwrap(),
+        "device emulator-5554 supports the ABIs x86_64, x86 but `build_targets` only has \
+         arm64-v8a, armeabi-v7a, installing would fail with INSTALL_FAILED_NO_MATCHING_ABIS"
+    );
+}
+
+/// Returns the value of a system property of the device
+pub(super) fn getprop(config: &AndroidConfig, property: &str) -> CargoResult<String> {
+    let output = config
+        .adb_command()?
+        .arg("shell")
+        .arg("getprop")
+        .arg(property)
+        .exec_with_output()?;
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Properties of a device, listed by `devices` and checked by `compat`
+#[derive(Debug, Default, PartialEq)]
+pub(super) struct DeviceProperties {
+    /// Android version, like `14`
+    pub release: String,
+    pub api_level: Option<u32>,
+    /// ABIs of the device, in its order of preference
+    pub abis: Vec<String>,
+    /// Density of the screen in dpi
+    pub density: Option<u32>,
+}
+
+/// Reads the properties of the device with a single `getprop`
+pub(super) fn device_properties(config: &AndroidConfig) -> CargoResult<DeviceProperties> {
+    let output = config
+        .adb_command()?
+        .arg("shell")
+        .arg("getprop")
+        .exec_with_output()?;
+    let properties = parse_properties(&String::from_utf8_lossy(&output.stdout));
+    if properties.abis.is_empty() {
+        return Err(format_err!("Unable to determine the ABIs of the device"));
+    }
+    Ok(properties)
+}
+
+/// Parses the `[name]: [value]` lines of `getprop`
+fn parse_properties(getprop: &str) -> DeviceProperties {
+    let properties = getprop
+        .lines()
+        .filter_map(|line| {
+            let (name, value) = line.trim().split_once("]: [")?;
+            Some((name.strip_prefix('[')?, value.strip_suffix(']')?))
+        })
+        .collect::<HashMap<_, _>>();
+    let get = |name: &str| properties.get(name).copied().unwrap_or_default().trim();
+
+    let mut abis = parse_abi_list(get("ro.product.cpu.abilist"));
+    // Devices older than Android 5 only have the primary ABI
+    if abis.is_empty() {
+        abis = parse_abi_list(get("ro.product.cpu.abi"));
+    }
+    DeviceProperties {
+        release: get("ro.build.version.release").to_owned(),
+        api_level: get("ro.build.version.sdk").parse().ok(),
+        abis,
+        // The emulator sets the density in a property of its own
+        density: ["ro.sf.lcd_density", "qemu.sf.lcd_density"]
+            .iter()
+            .find_map(|name| get(name).parse().ok()),
+    }
+}
+
+#[test]
+fn getprop_properties() {
+    let getprop = "\
+[dalvik.vm.heapsize]: [512m]
+[qemu.sf.lcd_density]: [420]
+[ro.build.version.release]: [14]
+[ro.build.version.sdk]: [34]
+[ro.product.cpu.abilist]: [x86_64,arm64-v8a]
+[ro.product.cpu.abi]: [x86_64]
+";
+    assert_eq!(
+        parse_properties(getprop),
+        DeviceProperties {
+            release: "14".to_owned(),
+            api_level: Some(34),
+            abis: vec!["x86_64".to_owned(), "arm64-v8a".to_owned()],
+            density: Some(420),
+        }
+    );
+
+    // Android 4.4
+    let getprop = "[ro.build.version.sdk]: [19]\n[ro.product.cpu.abi]: [armeabi-v7a]\n\
+                   [ro.sf.lcd_density]: [320]\n";
+    let properties = parse_properties(getprop);
+    assert_eq!(properties.abis, vec!["armeabi-v7a".to_owned()]);
+    assert_eq!(properties.density, Some(320));
+    assert_eq!(parse_properties(""), DeviceProperties::default());
+}
+
+/// Returns the API level of the connected device
+pub fn api_level(config: &AndroidConfig) -> CargoResult<u32> {
+    let output = config
+        .adb_command()?
+        .arg("shell")
+        .arg("getprop")
+        .arg("ro.build.version.sdk")
+        .exec_with_output()?;
+    let api_level = String::from_utf8_lossy(&output.stdout);
+    api_level.trim().parse().map_err(|_| {
+        format_err!(
+            "Unable to determine the device API level from `{}`",
+            api_level
+        )
+    })
+}
+
+/// Returns the ABIs supported by the connected device, in its order of preference
+pub fn abi_list(config: &AndroidConfig) -> CargoResult<Vec<String>> {
+    let mut abis = parse_abi_list(&getprop(config, "ro.product.cpu.abilist")?);
+    // Devices older than Android 5 only have the primary ABI
+    if abis.is_empty() {
+        abis = parse_abi_list(&getprop(config, "ro.product.cpu.abi")?);
+    }
+    if abis.is_empty() {
+        return Err(format_err!("Unable to determine the ABIs of the device"));
+    }
+    Ok(abis)
+}
+
+/// Parses the comma separated list of `ro.product.cpu.abilist`
+fn parse_abi_list(abilist: &str) -> Vec<String> {
+    abilist
+        .trim()
+        .split(',')
+        .map(str::trim)
+        .filter(|abi| !abi.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Returns the ABI among `available` which the device runs best: 64-bit ABIs come before
+/// 32-bit ones, then the order of the device
+pub fn preferred_abi<'a>(device_abis: &[String], available: &[&'a str]) -> CargoResult<&'a str> {
+    device_abis
+        .iter()
+        .enumerate()
+        .filter_map(|(index, abi)| {
+            let abi = available.iter().find(|available| *available == abi)?;
+            Some((!abi.contains("64"), index, *abi))
+        })
+        .min()
+        .map(|(_, _, abi)| abi)
+        .ok_or_else(|| {
+            format_err!(
+                "The device supports the ABIs {} but the APK only has libraries for {}, add a \
+                 supported target to `build_targets`",
+                device_abis.join(", "),
+                available.join(", ")
+            )
+        })
+}
+
+#[test]
+fn device_abi_selection() {
+    let abis = parse_abi_list("arm64-v8a,armeabi-v7a,armeabi\n");
+    assert_eq!(abis, vec!["arm64-v8a", "armeabi-v7a", "armeabi"]);
+    assert!(parse_abi_list("\n").is_empty());
+
+    assert_eq!(
+        preferred_abi(&abis, &["armeabi-v7a", "arm64-v8a", "x86"]).unwrap(),
+        "arm64-v8a"
+    );
+    assert_eq!(
+        preferred_abi(&abis, &["armeabi-v7a", "x86"]).unwrap(),
+        "armeabi-v7a"
+    );
+
+    // 64-bit wins even when an emulator lists a 32-bit ABI first
+    let emulator = parse_abi_list("x86,x86_64,arm64-v8a");
+    assert_eq!(
+        preferred_abi(&emulator, &["x86", "x86_64"]).unwrap(),
+        "x86_64"
+    );
+    assert_eq!(
+        preferred_abi(&emulator, &["x86", "arm64-v8a"]).unwrap(),
+        "arm64-v8a"
+    );
+
+    assert_eq!(
+        preferred_abi(&abis, &["x86", "x86_64"])
+            .unwrap_err()
+            .to_string(),
+        "The device supports the ABIs arm64-v8a, armeabi-v7a, armeabi but the APK only has \
+         libraries for x86, x86_64, add a supported target to `build_targets`"
+    );
+}
+
+/// Returns the current time of the connected device, in seconds since the epoch
+pub fn device_time(config: &AndroidConfig) -> CargoResult<u64> {
+    let output = config
+        .adb_command()?
+        .arg("shell")
+        .arg("date")
+        .arg("+%s")
+        .exec_with_output()?;
+    let time = String::from_utf8_lossy(&output.stdout);
+    time.trim()
+        .parse()
+        .map_err(|_| format_err!("Unable to read the device time from `{}`", time.trim()))
+}
+
+/// Returns the time at which the connected device booted, in seconds since the epoch. The time
+/// since boot is the monotonic uptime of the device, read together with its clock, which is only
+/// used to give the boot time the way `logcat -T` expects it.
+pub fn boot_time(config: &AndroidConfig) -> CargoResult<u64> {
+    let output = config
+        .adb_command()?
+        .arg("shell")
+        .arg("date +%s && cat /proc/uptime")
+        .exec_with_output()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    parse_boot_time(&output).ok_or_else(|| {
+        format_err!(
+            "Unable to read the device time and uptime from `{}`",
+            output.trim()
+        )
+    })
+}
+
+/// Parses the output of `date +%s` followed by `/proc/uptime`, like `1700003600\n3600.25 7000.50`,
+/// into the boot time
+fn parse_boot_time(output: &str) -> Option<u64> {
+    let mut lines = output.lines();
+    let now: u64 = lines.next()?.trim().parse().ok()?;
+    let uptime = parse_uptime(lines.next()?)?;
+    Some(now.saturating_sub(uptime))
+}
+
+/// Parses the whole seconds of `/proc/uptime`, like `3600.25 7000.50`
+fn parse_uptime(uptime: &str) -> Option<u64> {
+    uptime
+        .split_whitespace()
+        .next()?
+        .split('.')
+        .next()?
+        .parse()
+        .ok()
+}
+
+#[test]
+fn parse_proc_uptime() {
+    assert_eq!(parse_uptime("3600.25 7000.50\n"), Some(3600));
+    assert_eq!(parse_uptime("12 30"), Some(12));
+    assert_eq!(parse_uptime(""), None);
+
+    assert_eq!(
+        parse_boot_time("1700003600\r\n3600.25 7000.50\r\n"),
+        Some(1_700_000_000)
+    );
+    assert_eq!(parse_boot_time("1700003600\n"), None);
+    assert_eq!(parse_boot_time("date: bad format\n3600.25 7000.50"), None);
+}
+
+/// Prints the users of the connected device, as reported by `pm list users`
+pub fn list_users(workspace: &Workspace, config: &AndroidConfig) -> CargoResult<()> {
+    check_device_selected(config)?;
+    let output = config
+        .adb_command()?
+        .arg("shell")
+        .arg("pm")
+        .arg("list")
+        .arg("users")
+        .exec_with_output()
+        .failure_kind(FailureKind::Device)?;
+    let users = parse_users(&String::from_utf8_lossy(&output.stdout));
+
+    let name_width = users
+        .iter()
+        .map(|user| user.name.len())
+        .chain(Some("NAME".len()))
+        .max()
+        .unwrap();
+    let mut shell = workspace.gctx().shell();
+    drop(writeln!(
+        shell.out(),
+        "{:<6} {:<width$} RUNNING",
+        "ID",
+        "NAME",
+        width = name_width
+    ));
+    for user in users {
+        drop(writeln!(
+            shell.out(),
+            "{:<6} {:<width$} {}",
+            user.id,
+            user.name,
+            if user.running { "yes" } else { "no" },
+            width = name_width
+        ));
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+struct DeviceUser {
+    id: u32,
+    name: String,
+    running: bool,
+}
+
+/// Parses the `UserInfo{<id>:<name>:<flags>} [running]` lines of `pm list users`
+fn parse_users(output: &str) -> Vec<DeviceUser> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            let info = line.strip_prefix("UserInfo{")?;
+            let (info, rest) = info.split_at(info.find('}')?);
+            let mut parts = info.splitn(2, ':');
+            let id = parts.next()?.parse().ok()?;
+            // Names may contain `:`, flags are always the last part
+            let name = parts.next()?;
+            let name = name.rsplitn(2, ':').nth(1).unwrap_or(name);
+            Some(DeviceUser {
+                id,
+                name: name.to_owned(),
+                running: rest.contains("running"),
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn parse_pm_list_users() {
+    let output = "Users:\n\
+                  \tUserInfo{0:Owner:c13} running\n\
+                  \tUserInfo{10:Work profile:1030}\n\
+                  \tUserInfo{11:Test: QA:10} running\n";
+    assert_eq!(
+        parse_users(output),
+        vec![
+            DeviceUser {
+                id: 0,
+                name: "Owner".to_owned(),
+                running: true
+            },
+            DeviceUser {
+                id: 10,
+                name: "Work profile".to_owned(),
+                running: false
+            },
+            DeviceUser {
+                id: 11,
+                name: "Test: QA".to_owned(),
+                running: true
+            },
+        ]
+    );
+    assert_eq!(parse_users(""), vec![]);
+}
@@ -0,0 +1,393 @@
+pub(super) mod axml;
+
+use crate::ops::build;
+use anyhow::format_err;
+use cargo::core::Workspace;
+use cargo::util::command_prelude::ArgMatchesExt;
+use cargo::util::CargoResult;
+use cargo::GlobalContext;
+use clap::ArgMatches;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub fn diff(gctx: &GlobalContext, options: &ArgMatches) -> CargoResult<()> {
+    let apks = options
+        .get_many::<String>("apks")
+        .unwrap_or_default()
+        .map(|apk| gctx.cwd().join(apk))
+        .collect::<Vec<_>>();
+    let (old, new) = match apks.as_slice() {
+        [old, new] => (old.clone(), new.clone()),
+        [new] => {
+            // Compare with the APK of the same name from the previous build
+            let workspace = Workspace::new(&options.root_manifest(gctx)?, gctx)?;
+            let report = build::previous_build_report(&workspace, options.get_flag("release"))?;
+            let old = report
+                .apks
+                .into_iter()
+                .map(|apk| apk.path)
+                .find(|path| path.file_name() == new.file_name())
+                .ok_or_else(|| {
+                    format_err!(
+                        "The previous build has no APK named '{}'",
+                        new.file_name().unwrap_or_default().to_string_lossy()
+                    )
+                })?;
+            (old, new.clone())
+        }
+        _ => return Err(format_err!("Expected one or two APKs to compare")),
+    };
+
+    let diff = ApkDiff::new(
+        &old,
+        &new,
+        &ApkContents::read(open(&old)?)?,
+        &ApkContents::read(open(&new)?)?,
+    );
+    let mut shell = gctx.shell();
+    if options.get_flag("json") {
+        drop(writeln!(
+            shell.out(),
+            "{}",
+            serde_json::to_string_pretty(&diff)?
+        ));
+    } else {
+        drop(write!(shell.out(), "{}", diff.render()));
+    }
+    Ok(())
+}
+
+fn open(apk: &Path) -> CargoResult<File> {
+    File::open(apk).map_err(|err| format_err!("Unable to open '{}': {}", apk.display(), err))
+}
+
+/// What is compared of an APK
+#[derive(Debug, Default)]
+struct ApkContents {
+    size: u64,
+    manifest: ManifestSummary,
+    /// Zip entries, keyed by name
+    entries: BTreeMap<String, Entry>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Entry {
+    size: u64,
+    crc32: u32,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+struct ManifestSummary {
+    package: Option<String>,
+    version_code: Option<String>,
+    version_name: Option<String>,
+    min_sdk_version: Option<String>,
+    target_sdk_version: Option<String>,
+    permissions: BTreeSet<String>,
+}
+
+impl ApkContents {
+    fn read<R: Read + Seek>(mut reader: R) -> CargoResult<ApkContents> {
+        let size = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+        let mut archive = zip::ZipArchive::new(reader)?;
+
+        let mut entries = BTreeMap::new();
+        for i in 0..archive.len() {
+            let file = archive.by_index_raw(i)?;
+            if file.is_file() {
+                entries.insert(
+                    file.name().to_owned(),
+                    Entry {
+                        size: file.size(),
+                        crc32: file.crc32(),
+                    },
+                );
+            }
+        }
+
+        let mut manifest_data = vec![];
+        archive
+            .by_name("AndroidManifest.xml")?
+            .read_to_end(&mut manifest_data)?;
+        let mut manifest = ManifestSummary::default();
+        for element in axml::parse(&manifest_data)? {
+            let attribute = |name| element.attribute(name).map(str::to_owned);
+            match element.name.as_str() {
+                "manifest" => {
+                    manifest.package = attribute("package");
+                    manifest.version_code = attribute("versionCode");
+                    manifest.version_name = attribute("versionName");
+                }
+                "uses-sdk" => {
+                    manifest.min_sdk_version = attribute("minSdkVersion");
+                    manifest.target_sdk_version = attribute("targetSdkVersion");
+                }
+                "uses-permission" => manifest.permissions.extend(attribute("name")),
+                _ => {}
+            }
+        }
+
+        Ok(ApkContents {
+            size,
+            manifest,
+            entries,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ApkDiff {
+    old: PathBuf,
+    new: PathBuf,
+    old_size: u64,
+    new_size: u64,
+    old_manifest: ManifestSummary,
+    new_manifest: ManifestSummary,
+    added_permissions: Vec<String>,
+    removed_permissions: Vec<String>,
+    /// Changed entries grouped by `lib`, `assets`, `res`, `dex` and `other`
+    entries: BTreeMap<&'static str, EntriesDiff>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct EntriesDiff {
+    added: Vec<EntryChange>,
+    removed: Vec<EntryChange>,
+    changed: Vec<EntryChange>,
+    /// Difference of the uncompressed sizes
+    size_delta: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct EntryChange {
+    name: String,
+    old_size: Option<u64>,
+    new_size: Option<u64>,
+}
+
+fn category(entry: &str) -> &'static str {
+    if entry.starts_with("lib/") {
+        "lib"
+    } else if entry.starts_with("assets/") {
+        "assets"
+    } else if entry.starts_with("res/") {
+        "res"
+    } else if entry.ends_with(".dex") && !entry.contains('/') {
+        "dex"
+    } else {
+        "other"
+    }
+}
+
+impl ApkDiff {
+    fn new(old_path: &Path, new_path: &Path, old: &ApkContents, new: &ApkContents) -> ApkDiff {
+        let mut entries = BTreeMap::<_, EntriesDiff>::new();
+        let names = old
+            .entries
+            .keys()
+            .chain(new.entries.keys())
+            .collect::<BTreeSet<_>>();
+        for name in names {
+            let old_entry = old.entries.get(name);
+            let new_entry = new.entries.get(name);
+            if old_entry == new_entry {
+                continue;
+            }
+
+            let category = entries.entry(category(name)).or_default();
+            let change = EntryChange {
+                name: name.clone(),
+                old_size: old_entry.map(|entry| entry.size),
+                new_size: new_entry.map(|entry| entry.size),
+            };
+            category.size_delta +=
+                change.new_size.unwrap_or(0) as i64 - change.old_size.unwrap_or(0) as i64;
+            match (old_entry, new_entry) {
+                (None, _) => category.added.push(change),
+                (_, None) => category.removed.push(change),
+                _ => category.changed.push(change),
+            }
+        }
+
+        ApkDiff {
+            old: old_path.to_owned(),
+            new: new_path.to_owned(),
+            old_size: old.size,
+            new_size: new.size,
+            old_manifest: old.manifest.clone(),
+            new_manifest: new.manifest.clone(),
+            added_permissions: new
+                .manifest
+                .permissions
+                .difference(&old.manifest.permissions)
+                .cloned()
+                .collect(),
+            removed_permissions: old
+                .manifest
+                .permissions
+                .difference(&new.manifest.permissions)
+                .cloned()
+                .collect(),
+            entries,
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut out = format!("{} -> {}\n", self.old.display(), self.new.display());
+
+        out.push_str("manifest:\n");
+        let fields = [
+            (
+                "package",
+                &self.old_manifest.package,
+                &self.new_manifest.package,
+            ),
+            (
+                "versionCode",
+                &self.old_manifest.version_code,
+                &self.new_manifest.version_code,
+            ),
+            (
+                "versionName",
+                &self.old_manifest.version_name,
+                &self.new_manifest.version_name,
+            ),
+            (
+                "minSdkVersion",
+                &self.old_manifest.min_sdk_version,
+                &self.new_manifest.min_sdk_version,
+            ),
+            (
+                "targetSdkVersion",
+                &self.old_manifest.target_sdk_version,
+                &self.new_manifest.target_sdk_version,
+            ),
+        ];
+        for (name, old, new) in fields.iter() {
+            if old != new {
+                out.push_str(&format!(
+                    "  {}: {} -> {}\n",
+                    name,
+                    old.as_deref().unwrap_or("-"),
+                    new.as_deref().unwrap_or("-")
+                ));
+            }
+        }
+        for permission in &self.added_permissions {
+            out.push_str(&format!("  + uses-permission {}\n", permission));
+        }
+        for permission in &self.removed_permissions {
+            out.push_str(&format!("  - uses-permission {}\n", permission));
+        }
+
+        for (category, diff) in &self.entries {
+            out.push_str(&format!(
+                "{}: {} added, {} removed, {} changed ({:+} bytes)\n",
+                category,
+                diff.added.len(),
+                diff.removed.len(),
+                diff.changed.len(),
+                diff.size_delta
+            ));
+            for (sign, changes) in [
+                ("+", &diff.added),
+                ("-", &diff.removed),
+                ("~", &diff.changed),
+            ]
+            .iter()
+            {
+                for change in changes.iter() {
+                    let size = |size: Option<u64>| size.map_or("-".to_owned(), |s| s.to_string());
+                    out.push_str(&format!(
+                        "  {} {} ({} -> {} bytes)\n",
+                        sign,
+                        change.name,
+                        size(change.old_size),
+                        size(change.new_size)
+                    ));
+                }
+            }
+        }
+
+        out.push_str(&format!(
+            "total: {} -> {} bytes ({:+})\n",
+            self.old_size,
+            self.new_size,
+            self.new_size as i64 - self.old_size as i64
+        ));
+        out
+    }
+}
+
+#[test]
+fn diff_apks() {
+    use axml::TestValue::{Int, Str};
+    use std::io::Cursor;
+    use zip::write::{FileOptions, ZipWriter};
+
+    let apk = |version_code: i32, permissions: &[&str], entries: &[(&str, &[u8])]| {
+        let mut elements: Vec<(&str, &[(&str, axml::TestValue)])> = vec![];
+        let manifest_attributes = [
+            ("package", Str("rust.app")),
+            ("versionCode", Int(version_code)),
+        ];
+        elements.push(("manifest", &manifest_attributes));
+        let permission_attributes = permissions
+            .iter()
+            .map(|permission| [("name", Str(permission))])
+            .collect::<Vec<_>>();
+        for attributes in &permission_attributes {
+            elements.push(("uses-permission", attributes));
+        }
+
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        zip.start_file("AndroidManifest.xml", FileOptions::default())
+            .unwrap();
+        zip.write_all(&axml::encode(&elements)).unwrap();
+        for (name, contents) in entries {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(contents).unwrap();
+        }
+        ApkContents::read(Cursor::new(zip.finish().unwrap().into_inner())).unwrap()
+    };
+
+    let old = apk(
+        1,
+        &["android.permission.INTERNET"],
+        &[
+            ("classes.dex", b"dex"),
+            ("lib/arm64-v8a/libapp.so", b"app"),
+            ("assets/old.png", b"png"),
+        ],
+    );
+    let new = apk(
+        2,
+        &["android.permission.CAMERA", "android.permission.INTERNET"],
+        &[
+            ("classes.dex", b"dex"),
+            ("lib/arm64-v8a/libapp.so", b"app v2"),
+            ("lib/arm64-v8a/libc++_shared.so", b"c++"),
+        ],
+    );
+    let diff = ApkDiff::new(Path::new("old.apk"), Path::new("new.apk"), &old, &new);
+
+    assert_eq!(diff.added_permissions, vec!["android.permission.CAMERA"]);
+    assert!(diff.removed_permissions.is_empty());
+    assert!(!diff.entries.contains_key("dex"));
+    let lib = &diff.entries["lib"];
+    assert_eq!(lib.added[0].name, "lib/arm64-v8a/libc++_shared.so");
+    assert_eq!(lib.changed[0].name, "lib/arm64-v8a/libapp.so");
+    assert_eq!(lib.size_delta, 6);
+    assert_eq!(diff.entries["assets"].removed[0].name, "assets/old.png");
+    assert_eq!(diff.entries["assets"].size_delta, -3);
+
+    let report = diff.render();
+    assert!(report.contains("versionCode: 1 -> 2"));
+    assert!(report.contains("+ uses-permission android.permission.CAMERA"));
+    assert!(report.contains("lib: 1 added, 0 removed, 1 changed (+6 bytes)"));
+    assert!(report.contains("~ lib/arm64-v8a/libapp.so (3 -> 6 bytes)"));
+}
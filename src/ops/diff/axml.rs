@@ -0,0 +1,323 @@
+//! Minimal reader of the binary XML format in which AndroidManifest.xml is stored inside APKs.
+//! Only the elements and their attributes are read, which is enough to compare and check
+//! manifests.
+
+use anyhow::format_err;
+use cargo::util::CargoResult;
+
+const RES_STRING_POOL_TYPE: u16 = 0x0001;
+const RES_XML_TYPE: u16 = 0x0003;
+const RES_XML_START_ELEMENT_TYPE: u16 = 0x0102;
+const RES_XML_RESOURCE_MAP_TYPE: u16 = 0x0180;
+
+const UTF8_FLAG: u32 = 1 << 8;
+const NO_ENTRY: u32 = 0xFFFF_FFFF;
+
+const TYPE_REFERENCE: u8 = 0x01;
+const TYPE_STRING: u8 = 0x03;
+const TYPE_INT_DEC: u8 = 0x10;
+const TYPE_INT_BOOLEAN: u8 = 0x12;
+
+/// Attributes which some tools store with an empty name, only identified by their resource id
+const ATTRIBUTE_IDS: &[(u32, &str)] = &[
+    (0x0101_0003, "name"),
+    (0x0101_000f, "debuggable"),
+    (0x0101_020c, "minSdkVersion"),
+    (0x0101_021b, "versionCode"),
+    (0x0101_021c, "versionName"),
+    (0x0101_0270, "targetSdkVersion"),
+    (0x0101_0272, "testOnly"),
+];
+
+#[derive(Debug, PartialEq)]
+pub struct Element {
+    pub name: String,
+    /// Attributes without their namespace, with their values formatted as strings
+    pub attributes: Vec<(String, String)>,
+}
+
+impl Element {
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(attribute, _)| attribute == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn u8(&self, offset: usize) -> CargoResult<u8> {
+        self.0
+            .get(offset)
+            .copied()
+            .ok_or_else(|| format_err!("Truncated binary XML"))
+    }
+
+    fn u16(&self, offset: usize) -> CargoResult<u16> {
+        Ok(u16::from_le_bytes([self.u8(offset)?, self.u8(offset + 1)?]))
+    }
+
+    fn u32(&self, offset: usize) -> CargoResult<u32> {
+        Ok(u32::from_le_bytes([
+            self.u8(offset)?,
+            self.u8(offset + 1)?,
+            self.u8(offset + 2)?,
+            self.u8(offset + 3)?,
+        ]))
+    }
+}
+
+/// Returns the elements of a binary XML document, in document order
+pub fn parse(data: &[u8]) -> CargoResult<Vec<Element>> {
+    let reader = Reader(data);
+    if reader.u16(0)? != RES_XML_TYPE {
+        return Err(format_err!("Not a binary XML document"));
+    }
+
+    let mut strings = vec![];
+    let mut resource_ids = vec![];
+    let mut elements = vec![];
+    let mut offset = reader.u16(2)? as usize;
+    while offset + 8 <= data.len() {
+        let chunk_type = reader.u16(offset)?;
+        let header_size = reader.u16(offset + 2)? as usize;
+        let size = reader.u32(offset + 4)? as usize;
+        if size < 8 || offset + size > data.len() {
+            return Err(format_err!(
+                "Invalid chunk in binary XML at offset {}",
+                offset
+            ));
+        }
+
+        match chunk_type {
+            RES_STRING_POOL_TYPE => strings = parse_string_pool(&data[offset..offset + size])?,
+            RES_XML_RESOURCE_MAP_TYPE => {
+                resource_ids = (offset + header_size..offset + size)
+                    .step_by(4)
+                    .map(|id_offset| reader.u32(id_offset))
+                    .collect::<CargoResult<_>>()?
+            }
+            RES_XML_START_ELEMENT_TYPE => {
+                let string = |index: u32| strings.get(index as usize).cloned().unwrap_or_default();
+                let ext = offset + header_size;
+                let attribute_start = reader.u16(ext + 8)? as usize;
+                let attribute_size = reader.u16(ext + 10)? as usize;
+                let attribute_count = reader.u16(ext + 12)? as usize;
+
+                let mut attributes = vec![];
+                for i in 0..attribute_count {
+                    let attribute = ext + attribute_start + i * attribute_size;
+                    let name_index = reader.u32(attribute + 4)?;
+                    let raw_value = reader.u32(attribute + 8)?;
+                    let data_type = reader.u8(attribute + 15)?;
+                    let data = reader.u32(attribute + 16)?;
+
+                    let mut name = string(name_index);
+                    if name.is_empty() {
+                        let id = resource_ids.get(name_index as usize);
+                        if let Some((_, id_name)) =
+                            ATTRIBUTE_IDS.iter().find(|(i, _)| Some(i) == id)
+                        {
+                            name = (*id_name).to_owned();
+                        }
+                    }
+                    let value = match (raw_value, data_type) {
+                        (NO_ENTRY, TYPE_STRING) => string(data),
+                        (NO_ENTRY, TYPE_INT_DEC) => (data as i32).to_string(),
+                        (NO_ENTRY, TYPE_INT_BOOLEAN) => (data != 0).to_string(),
+                        (NO_ENTRY, TYPE_REFERENCE) => format!("@0x{:08x}", data),
+                        (NO_ENTRY, _) => format!("0x{:x}", data),
+                        (raw_value, _) => string(raw_value),
+                    };
+                    attributes.push((name, value));
+                }
+
+                elements.push(Element {
+                    name: string(reader.u32(ext + 4)?),
+                    attributes,
+                });
+            }
+            _ => {}
+        }
+        offset += size;
+    }
+
+    Ok(elements)
+}
+
+fn parse_string_pool(chunk: &[u8]) -> CargoResult<Vec<String>> {
+    let reader = Reader(chunk);
+    let header_size = reader.u16(2)? as usize;
+    let string_count = reader.u32(8)? as usize;
+    let utf8 = reader.u32(16)? & UTF8_FLAG != 0;
+    let strings_start = reader.u32(20)? as usize;
+
+    (0..string_count)
+        .map(|i| {
+            let mut offset = strings_start + reader.u32(header_size + i * 4)? as usize;
+            if utf8 {
+                // Length in characters, then in bytes, each on one or two bytes
+                let read_len = |offset: &mut usize| -> CargoResult<usize> {
+                    let first = reader.u8(*offset)? as usize;
+                    *offset += 1;
+                    if first & 0x80 == 0 {
+                        return Ok(first);
+                    }
+                    let second = reader.u8(*offset)? as usize;
+                    *offset += 1;
+                    Ok(((first & 0x7f) << 8) | second)
+                };
+                read_len(&mut offset)?;
+                let len = read_len(&mut offset)?;
+                let bytes = chunk
+                    .get(offset..offset + len)
+                    .ok_or_else(|| format_err!("Truncated binary XML string pool"))?;
+                Ok(String::from_utf8_lossy(bytes).into_owned())
+            } else {
+                let mut len = reader.u16(offset)? as usize;
+                offset += 2;
+                if len & 0x8000 != 0 {
+                    len = ((len & 0x7fff) << 16) | reader.u16(offset)? as usize;
+                    offset += 2;
+                }
+                let units = (0..len)
+                    .map(|unit| reader.u16(offset + unit * 2))
+                    .collect::<CargoResult<Vec<_>>>()?;
+                Ok(String::from_utf16_lossy(&units))
+            }
+        })
+        .collect()
+}
+
+/// Value of an attribute of `encode`
+#[cfg(test)]
+pub enum TestValue<'a> {
+    Str(&'a str),
+    Int(i32),
+}
+
+/// Encodes elements in the binary XML format, with a UTF-16 string pool
+#[cfg(test)]
+pub fn encode(elements: &[(&str, &[(&str, TestValue)])]) -> Vec<u8> {
+    let mut strings: Vec<String> = vec![];
+    let mut index = |s: &str| -> u32 {
+        match strings.iter().position(|existing| existing == s) {
+            Some(i) => i as u32,
+            None => {
+                strings.push(s.to_owned());
+                strings.len() as u32 - 1
+            }
+        }
+    };
+
+    let mut nodes = vec![];
+    for (name, attributes) in elements {
+        let name = index(name);
+        let mut chunk = vec![];
+        chunk.extend(&RES_XML_START_ELEMENT_TYPE.to_le_bytes());
+        chunk.extend(&16u16.to_le_bytes());
+        chunk.extend(&((36 + attributes.len() * 20) as u32).to_le_bytes());
+        chunk.extend(&[0; 8]); // line number and comment
+        chunk.extend(&NO_ENTRY.to_le_bytes()); // namespace
+        chunk.extend(&name.to_le_bytes());
+        chunk.extend(&20u16.to_le_bytes());
+        chunk.extend(&20u16.to_le_bytes());
+        chunk.extend(&(attributes.len() as u16).to_le_bytes());
+        chunk.extend(&[0; 6]); // id, class and style indices
+        for (attribute, value) in attributes.iter() {
+            let (raw_value, data_type, data) = match value {
+                TestValue::Str(s) => {
+                    let i = index(s);
+                    (i, TYPE_STRING, i)
+                }
+                TestValue::Int(value) => (NO_ENTRY, TYPE_INT_DEC, *value as u32),
+            };
+            chunk.extend(&NO_ENTRY.to_le_bytes());
+            chunk.extend(&index(attribute).to_le_bytes());
+            chunk.extend(&raw_value.to_le_bytes());
+            chunk.extend(&8u16.to_le_bytes());
+            chunk.push(0);
+            chunk.push(data_type);
+            chunk.extend(&data.to_le_bytes());
+        }
+        nodes.push(chunk);
+    }
+
+    let mut string_data = vec![];
+    let mut offsets = vec![];
+    for s in &strings {
+        offsets.push(string_data.len() as u32);
+        let units = s.encode_utf16().collect::<Vec<_>>();
+        string_data.extend(&(units.len() as u16).to_le_bytes());
+        for unit in units {
+            string_data.extend(&unit.to_le_bytes());
+        }
+        string_data.extend(&[0, 0]);
+    }
+    while string_data.len() % 4 != 0 {
+        string_data.push(0);
+    }
+    let strings_start = 28 + offsets.len() as u32 * 4;
+    let mut pool = vec![];
+    pool.extend(&RES_STRING_POOL_TYPE.to_le_bytes());
+    pool.extend(&28u16.to_le_bytes());
+    pool.extend(&(strings_start + string_data.len() as u32).to_le_bytes());
+    pool.extend(&(offsets.len() as u32).to_le_bytes());
+    pool.extend(&0u32.to_le_bytes()); // style count
+    pool.extend(&0u32.to_le_bytes()); // flags
+    pool.extend(&strings_start.to_le_bytes());
+    pool.extend(&0u32.to_le_bytes()); // styles start
+    for offset in offsets {
+        pool.extend(&offset.to_le_bytes());
+    }
+    pool.extend(string_data);
+
+    let body = pool
+        .into_iter()
+        .chain(nodes.into_iter().flatten())
+        .collect::<Vec<_>>();
+    let mut document = vec![];
+    document.extend(&RES_XML_TYPE.to_le_bytes());
+    document.extend(&8u16.to_le_bytes());
+    document.extend(&(8 + body.len() as u32).to_le_bytes());
+    document.extend(body);
+    document
+}
+
+#[test]
+fn parse_binary_manifest() {
+    let manifest = encode(&[
+        (
+            "manifest",
+            &[
+                ("package", TestValue::Str("rust.app")),
+                ("versionCode", TestValue::Int(3)),
+            ],
+        ),
+        (
+            "uses-permission",
+            &[("name", TestValue::Str("android.permission.CAMERA"))],
+        ),
+    ]);
+
+    assert_eq!(
+        parse(&manifest).unwrap(),
+        vec![
+            Element {
+                name: "manifest".to_owned(),
+                attributes: vec![
+                    ("package".to_owned(), "rust.app".to_owned()),
+                    ("versionCode".to_owned(), "3".to_owned()),
+                ],
+            },
+            Element {
+                name: "uses-permission".to_owned(),
+                attributes: vec![("name".to_owned(), "android.permission.CAMERA".to_owned())],
+            },
+        ]
+    );
+    assert!(parse(b"<manifest />").is_err());
+    assert!(parse(&manifest[..40]).is_err());
+}
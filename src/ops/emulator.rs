@@ -0,0 +1,162 @@
+//! `run --emulator`: builds for the ABI the emulator runs at native speed on this host, and
+//! installs on a running emulator, starting one of the AVDs when none is running.
+
+use super::device::{getprop, parse_devices};
+use crate::config::{AndroidBuildTarget, AndroidConfig};
+use crate::error::{FailureKind, ResultExt};
+use anyhow::format_err;
+use cargo::core::Workspace;
+use cargo::util::CargoResult;
+use cargo_util::ProcessBuilder;
+use std::env;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Time given to a started emulator to show up in `adb devices` and finish booting
+const BOOT_TIMEOUT: Duration = Duration::from_secs(300);
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Returns the ABI of the system images the emulator runs without translation on this host
+pub fn host_emulator_target() -> AndroidBuildTarget {
+    if cfg!(target_arch = "aarch64") {
+        AndroidBuildTarget::Arm64V8a
+    } else {
+        AndroidBuildTarget::X86_64
+    }
+}
+
+/// Restricts the build to the emulator ABI and selects a running emulator, starting one when
+/// none is running
+pub fn use_emulator(workspace: &Workspace, config: &mut AndroidConfig) -> CargoResult<()> {
+    config.override_build_targets(vec![host_emulator_target()]);
+    let serial = match running_emulator(config)? {
+        Some(serial) => {
+            workspace
+                .gctx()
+                .shell()
+                .verbose(|shell| shell.status("Emulator", format!("reusing `{}`", serial)))?;
+            serial
+        }
+        None => start_emulator(workspace, config)?,
+    };
+    config.device = Some(serial);
+    Ok(())
+}
+
+/// Returns the serial of a running emulator ready to accept commands, if any
+fn running_emulator(config: &AndroidConfig) -> CargoResult<Option<String>> {
+    let output = ProcessBuilder::new(config.adb()?)
+        .arg("devices")
+        .exec_with_output()
+        .failure_kind(FailureKind::Device)?;
+    Ok(parse_devices(&String::from_utf8_lossy(&output.stdout))
+        .into_iter()
+        .find(|device| device.serial.starts_with("emulator-") && device.state == "device")
+        .map(|device| device.serial))
+}
+
+/// Returns the path of the `emulator` tool of the SDK
+fn emulator_path(config: &AndroidConfig) -> CargoResult<PathBuf> {
+    let path = config
+        .sdk_path
+        .join("emulator")
+        .join(format!("emulator{}", env::consts::EXE_SUFFIX));
+    if !path.exists() {
+        return Err(FailureKind::Environment.mark(format_err!(
+            "Android SDK at `{}` has no emulator, install it with `sdkmanager \"emulator\"`",
+            config.sdk_path.display()
+        )));
+    }
+    Ok(path)
+}
+
+/// Parses the AVD names printed by `emulator -list-avds`, which newer emulators mix with
+/// `INFO    | ...` lines
+fn parse_avds(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.contains('|'))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Starts the first AVD in the background, leaving it running after the command, and waits for
+/// it to boot. Returns its serial.
+fn start_emulator(workspace: &Workspace, config: &AndroidConfig) -> CargoResult<String> {
+    let emulator = emulator_path(config)?;
+    let output = ProcessBuilder::new(&emulator)
+        .arg("-list-avds")
+        .exec_with_output()
+        .failure_kind(FailureKind::Environment)?;
+    let avd = parse_avds(&String::from_utf8_lossy(&output.stdout))
+        .into_iter()
+        .next()
+        .ok_or_else(|| {
+            FailureKind::Environment.mark(format_err!(
+                "No emulator is running and no AVD exists, create one with `avdmanager create \
+                 avd` using a `{}` system image",
+                host_emulator_target().android_abi()
+            ))
+        })?;
+
+    workspace
+        .gctx()
+        .shell()
+        .status("Starting", format!("emulator `{}`", avd))?;
+    let mut child = Command::new(&emulator)
+        .arg("-avd")
+        .arg(&avd)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|err| format_err!("Unable to start `{}`: {}", emulator.display(), err))
+        .failure_kind(FailureKind::Environment)?;
+
+    let deadline = Instant::now() + BOOT_TIMEOUT;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Err(FailureKind::Device.mark(format_err!(
+                "The emulator `{}` exited while booting ({})",
+                avd,
+                status
+            )));
+        }
+        if let Some(serial) = running_emulator(config)? {
+            let mut emulator_config = config.clone();
+            emulator_config.device = Some(serial.clone());
+            // Commands fail while the package manager isn't started
+            if getprop(&emulator_config, "sys.boot_completed")
+                .map(|value| value.trim() == "1")
+                .unwrap_or(false)
+            {
+                return Ok(serial);
+            }
+        }
+        if Instant::now() > deadline {
+            return Err(FailureKind::Device.mark(format_err!(
+                "The emulator `{}` didn't boot within {} seconds",
+                avd,
+                BOOT_TIMEOUT.as_secs()
+            )));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[test]
+fn avd_list() {
+    let output = "INFO    | Storing crashdata in: /tmp/android/emu-crash.db\n\
+                  Pixel_7_API_34\n\
+                  Small_Phone_API_24\n\
+                  \n";
+    assert_eq!(
+        parse_avds(output),
+        vec!["Pixel_7_API_34".to_owned(), "Small_Phone_API_24".to_owned()]
+    );
+    assert!(parse_avds("").is_empty());
+}
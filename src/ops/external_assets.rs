@@ -0,0 +1,252 @@
+//! Assets of debug builds with `debug_assets_external`, pushed to the external files directory
+//! of the app instead of being packaged into the APK.
+//!
+//! The device keeps the asset manifest of the last sync next to the assets, so that only the
+//! changed files are pushed and the removed ones deleted.
+
+use crate::ops::adb_retry::AdbRetry;
+use crate::ops::build::{list_source_assets, AssetManifest, ASSET_MANIFEST_NAME};
+use cargo::util::CargoResult;
+use cargo_util::ProcessBuilder;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the file packaged in place of the assets
+pub const STUB_NAME: &str = "external_assets.txt";
+
+/// Contents of the stub, for whoever looks into the APK
+pub const STUB_CONTENTS: &str = "The assets of this debug build are in the `assets` directory \
+                                 of the external files directory of the app.\n";
+
+/// Environment variable the MainActivity sets to the directory of the assets on the device, for
+/// the asset loader of the app
+pub const DIR_ENV: &str = "CARGO_APK_ASSETS_DIR";
+
+/// Returns the directory of the assets on the device
+pub fn device_dir(application_id: &str, user: Option<u32>) -> String {
+    let storage = match user {
+        Some(user) => format!("/storage/emulated/{}", user),
+        None => "/sdcard".to_owned(),
+    };
+    format!("{}/Android/data/{}/files/assets", storage, application_id)
+}
+
+/// Directory holding the synced assets
+pub trait AssetStore {
+    /// Reads a file of the directory, `None` when it doesn't exist
+    fn read(&self, path: &str) -> Option<Vec<u8>>;
+    fn push(&self, local: &Path, path: &str) -> CargoResult<()>;
+    fn remove(&self, path: &str) -> CargoResult<()>;
+}
+
+/// Assets directory on the connected device
+pub struct AdbAssetStore<'a> {
+    /// `adb` for the selected device
+    pub adb: ProcessBuilder,
+    pub dir: String,
+    pub retry: &'a AdbRetry<'a>,
+}
+
+impl AdbAssetStore<'_> {
+    fn device_path(&self, path: &str) -> String {
+        format!("{}/{}", self.dir, path)
+    }
+}
+
+/// Quotes an argument for the device shell
+pub(super) fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+impl AssetStore for AdbAssetStore<'_> {
+    fn read(&self, path: &str) -> Option<Vec<u8>> {
+        self.adb
+            .clone()
+            .arg("exec-out")
+            .arg(format!("cat {}", shell_quote(&self.device_path(path))))
+            .exec_with_output()
+            .ok()
+            .map(|output| output.stdout)
+    }
+
+    fn push(&self, local: &Path, path: &str) -> CargoResult<()> {
+        self.retry.run("adb push", || {
+            self.adb
+                .clone()
+                .arg("push")
+                .arg(local)
+                .arg(self.device_path(path))
+                .exec_with_output()
+        })?;
+        Ok(())
+    }
+
+    fn remove(&self, path: &str) -> CargoResult<()> {
+        self.adb
+            .clone()
+            .arg("shell")
+            .arg(format!("rm -f {}", shell_quote(&self.device_path(path))))
+            .exec_with_output()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct SyncPlan {
+    /// Assets which are new or changed
+    pub push: Vec<String>,
+    /// Assets which no longer exist
+    pub remove: Vec<String>,
+}
+
+/// Compares the manifest of the last sync with the one of the current assets
+fn plan_sync(previous: Option<&AssetManifest>, current: &AssetManifest) -> SyncPlan {
+    let previous = previous
+        .map(|manifest| {
+            manifest
+                .assets
+                .iter()
+                .map(|asset| (asset.path.as_str(), asset))
+                .collect::<BTreeMap<_, _>>()
+        })
+        .unwrap_or_default();
+
+    let mut plan = SyncPlan::default();
+    for asset in &current.assets {
+        if previous.get(asset.path.as_str()) != Some(&asset) {
+            plan.push.push(asset.path.clone());
+        }
+    }
+    let current_paths = current
+        .assets
+        .iter()
+        .map(|asset| asset.path.as_str())
+        .collect::<Vec<_>>();
+    plan.remove = previous
+        .keys()
+        .filter(|path| !current_paths.contains(path))
+        .map(|path| path.to_string())
+        .collect();
+    plan
+}
+
+/// Brings `store` up to date with `assets_dir`, without the ignored files and those of the
+/// `exclude` globs. The manifest written to `manifest_path` is pushed last, so an interrupted
+/// sync is completed by the next one.
+pub fn sync_assets(
+    assets_dir: &Path,
+    exclude: &[String],
+    manifest_path: &Path,
+    store: &dyn AssetStore,
+) -> CargoResult<SyncPlan> {
+    let current = AssetManifest::of(&list_source_assets(assets_dir, exclude)?.assets)?;
+    let previous = store
+        .read(ASSET_MANIFEST_NAME)
+        .and_then(|content| serde_json::from_slice::<AssetManifest>(&content).ok());
+    let plan = plan_sync(previous.as_ref(), &current);
+
+    for path in &plan.push {
+        store.push(&assets_dir.join(path), path)?;
+    }
+    for path in &plan.remove {
+        store.remove(path)?;
+    }
+    fs::write(manifest_path, serde_json::to_string_pretty(&current)?)?;
+    store.push(manifest_path, ASSET_MANIFEST_NAME)?;
+    Ok(plan)
+}
+
+/// Local directory standing in for the device
+#[cfg(test)]
+struct DirStore(PathBuf);
+
+#[cfg(test)]
+impl AssetStore for DirStore {
+    fn read(&self, path: &str) -> Option<Vec<u8>> {
+        fs::read(self.0.join(path)).ok()
+    }
+
+    fn push(&self, local: &Path, path: &str) -> CargoResult<()> {
+        let destination = self.0.join(path);
+        fs::create_dir_all(destination.parent().unwrap())?;
+        fs::copy(local, destination)?;
+        Ok(())
+    }
+
+    fn remove(&self, path: &str) -> CargoResult<()> {
+        fs::remove_file(self.0.join(path))?;
+        Ok(())
+    }
+}
+
+#[test]
+fn sync_changed_and_removed_assets() {
+    let root = std::env::temp_dir().join(format!(
+        "cargo-quad-apk-external-assets-{}",
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&root);
+    let assets = root.join("assets");
+    let device = root.join("device");
+    fs::create_dir_all(assets.join("levels")).unwrap();
+    fs::create_dir_all(&device).unwrap();
+    fs::write(assets.join("levels").join("1.txt"), "first").unwrap();
+    fs::write(assets.join("levels").join("2.txt"), "second").unwrap();
+    fs::write(assets.join("font.ttf"), "font").unwrap();
+    let store = DirStore(device.clone());
+    let manifest_path = root.join("external-assets.json");
+
+    // Everything is pushed the first time
+    let plan = sync_assets(&assets, &[], &manifest_path, &store).unwrap();
+    assert_eq!(plan.push, vec!["font.ttf", "levels/1.txt", "levels/2.txt"]);
+    assert!(plan.remove.is_empty());
+    assert_eq!(
+        fs::read_to_string(device.join("levels").join("2.txt")).unwrap(),
+        "second"
+    );
+
+    // Nothing changed
+    let plan = sync_assets(&assets, &[], &manifest_path, &store).unwrap();
+    assert_eq!(plan, SyncPlan::default());
+
+    // A changed file of the same size is detected by its hash
+    fs::write(assets.join("levels").join("1.txt"), "FIRST").unwrap();
+    fs::remove_file(assets.join("font.ttf")).unwrap();
+    fs::write(assets.join("icon.png"), "icon").unwrap();
+    let plan = sync_assets(&assets, &[], &manifest_path, &store).unwrap();
+    assert_eq!(plan.push, vec!["icon.png", "levels/1.txt"]);
+    assert_eq!(plan.remove, vec!["font.ttf"]);
+    assert_eq!(
+        fs::read_to_string(device.join("levels").join("1.txt")).unwrap(),
+        "FIRST"
+    );
+    assert!(!device.join("font.ttf").exists());
+    assert_eq!(
+        serde_json::from_slice::<AssetManifest>(
+            &fs::read(device.join(ASSET_MANIFEST_NAME)).unwrap()
+        )
+        .unwrap(),
+        AssetManifest::new(&assets).unwrap()
+    );
+
+    // A device without the manifest, like after reinstalling the app, gets everything again
+    fs::remove_file(device.join(ASSET_MANIFEST_NAME)).unwrap();
+    let plan = sync_assets(&assets, &[], &manifest_path, &store).unwrap();
+    assert_eq!(plan.push, vec!["icon.png", "levels/1.txt", "levels/2.txt"]);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn external_assets_device_dir() {
+    assert_eq!(
+        device_dir("rust.app", None),
+        "/sdcard/Android/data/rust.app/files/assets"
+    );
+    assert_eq!(
+        device_dir("rust.app", Some(10)),
+        "/storage/emulated/10/Android/data/rust.app/files/assets"
+    );
+    assert_eq!(shell_quote("it's here"), r"'it'\''s here'");
+}
@@ -0,0 +1,251 @@
+//! `hot`: pushes the rebuilt library of a debug build into the data directory of the installed
+//! app and restarts it, without packaging, signing or installing an APK. Experimental.
+//!
+//! The MainActivity of debug builds loads its library through the generated `HotReload` class,
+//! which prefers `files/hot/lib<name>.so` while it is newer than the library of the installed
+//! APK, so that installing the app again brings back the packaged library.
+
+use crate::config::{AndroidConfig, AndroidTargetConfig, Framework};
+use crate::error::{FailureKind, ResultExt};
+use crate::ops::adb_retry::AdbRetry;
+use crate::ops::external_assets::shell_quote;
+use crate::ops::{build, device, logcat, restart, run};
+use anyhow::format_err;
+use cargo::core::Workspace;
+use cargo::util::CargoResult;
+use cargo_util::ProcessBuilder;
+use clap::ArgMatches;
+use std::path::Path;
+
+/// Directory the library is pushed to, as adb can't write into the data directory of the app
+const STAGING_DIR: &str = "/data/local/tmp";
+
+/// Device running the app
+pub trait HotDevice {
+    fn push(&self, local: &Path, remote: &str) -> CargoResult<()>;
+    /// Runs a shell command as the app with `run-as`, in its data directory
+    fn run_as(&self, command: &str) -> CargoResult<()>;
+    fn shell(&self, command: &str) -> CargoResult<()>;
+    fn stop_app(&self) -> CargoResult<()>;
+    /// Starts the app, returning the device time it was started at
+    fn start_app(&self) -> CargoResult<u64>;
+}
+
+/// The selected device, through adb
+struct AdbHotDevice<'a> {
+    workspace: &'a Workspace<'a>,
+    config: &'a AndroidConfig,
+    options: &'a ArgMatches,
+    target_config: &'a AndroidTargetConfig,
+    /// `adb` for the selected device
+    adb: ProcessBuilder,
+    retry: AdbRetry<'a>,
+    user: Option<u32>,
+}
+
+impl HotDevice for AdbHotDevice<'_> {
+    fn push(&self, local: &Path, remote: &str) -> CargoResult<()> {
+        self.retry.run("adb push", || {
+            self.adb
+                .clone()
+                .arg("push")
+                .arg(local)
+                .arg(remote)
+                .exec_with_output()
+        })?;
+        Ok(())
+    }
+
+    fn run_as(&self, command: &str) -> CargoResult<()> {
+        let user = match self.user {
+            Some(user) => format!(" --user {}", user),
+            None => String::new(),
+        };
+        let application_id = self.target_config.application_id();
+        self.shell(&format!(
+            "run-as{} {} sh -c {}",
+            user,
+            application_id,
+            shell_quote(command)
+        ))
+        .map_err(|err| {
+            format_err!(
+                "`run-as {}` failed, `hot` needs the debug build of the app installed, with \
+                 `cargo quad-apk run` or `cargo quad-apk install --debug`: {}",
+                application_id,
+                err
+            )
+        })
+    }
+
+    fn shell(&self, command: &str) -> CargoResult<()> {
+        self.adb
+            .clone()
+            .arg("shell")
+            .arg(command)
+            .exec_with_output()?;
+        Ok(())
+    }
+
+    fn stop_app(&self) -> CargoResult<()> {
+        run::stop_app(self.config, self.options, self.target_config)
+    }
+
+    fn start_app(&self) -> CargoResult<u64> {
+        run::start_app(
+            self.workspace,
+            self.config,
+            self.options,
+            self.target_config,
+            &[],
+        )
+    }
+}
+
+pub fn hot(
+    workspace: &Workspace,
+    config: &mut AndroidConfig,
+    options: &ArgMatches,
+) -> CargoResult<()> {
+    if config.release {
+        return Err(format_err!(
+            "`hot` only works with debug builds, release builds always load the library of \
+             their APK"
+        ));
+    }
+    if config.framework == Framework::None {
+        return Err(format_err!(
+            "`hot` needs `framework = \"miniquad\"`, apps started by NativeActivity always load \
+             the library of their APK"
+        ));
+    }
+    let target = restart::app_target(workspace, config, options)?;
+    let target_config = config.resolve(target.clone())?;
+    let application_id = target_config.application_id();
+
+    device::check_device_selected(config)?;
+    let user = device::selected_user(options)?;
+    restart::check_installed(config, user, &application_id)?;
+    device::use_device_abi(workspace, config)?;
+
+    let config = &*config;
+    let libraries = build::build_libraries(workspace, config, options)?;
+    let library = libraries
+        .get(&target)
+        .and_then(|libraries| libraries.iter().find(|library| library.link.is_some()))
+        .ok_or_else(|| format_err!("No library was built for `{}`", target.1))?;
+
+    let device = AdbHotDevice {
+        workspace,
+        config,
+        options,
+        target_config: &target_config,
+        adb: config.adb_command()?,
+        retry: AdbRetry::new(workspace.gctx(), &config.adb()?, options)?,
+        user,
+    };
+    let started_at = push_and_restart(&device, &library.path, &target_config.library_name)
+        .failure_kind(FailureKind::Device)?;
+    workspace.gctx().shell().status(
+        "Reloaded",
+        format!(
+            "`{}` with the {} library",
+            application_id,
+            library.abi.android_abi()
+        ),
+    )?;
+
+    if options.get_flag("logcat") {
+        logcat::app_logcat(workspace, config, &application_id, started_at, None)?;
+    }
+    Ok(())
+}
+
+/// Pushes `library` as the hot library of the app, named after the library its MainActivity
+/// loads, and restarts the app. Returns the device time the app was started at.
+fn push_and_restart(
+    device: &dyn HotDevice,
+    library: &Path,
+    library_name: &str,
+) -> CargoResult<u64> {
+    let filename = format!("lib{}.so", library_name);
+    let staged = format!("{}/cargo-quad-apk-hot-{}", STAGING_DIR, filename);
+    device.push(library, &staged)?;
+    // Renamed once complete, an interrupted copy would leave a truncated library newer than the
+    // one of the APK
+    device.run_as(&format!(
+        "mkdir -p files/hot && cp {staged} files/hot/{filename}.tmp && \
+         mv files/hot/{filename}.tmp files/hot/{filename}",
+        staged = shell_quote(&staged),
+        filename = filename
+    ))?;
+    device.shell(&format!("rm -f {}", shell_quote(&staged)))?;
+    device.stop_app()?;
+    device.start_app()
+}
+
+/// Device recording the commands
+#[cfg(test)]
+#[derive(Default)]
+struct RecordingDevice {
+    commands: std::cell::RefCell<Vec<String>>,
+}
+
+#[cfg(test)]
+impl HotDevice for RecordingDevice {
+    fn push(&self, local: &Path, remote: &str) -> CargoResult<()> {
+        self.commands
+            .borrow_mut()
+            .push(format!("push {} {}", local.display(), remote));
+        Ok(())
+    }
+
+    fn run_as(&self, command: &str) -> CargoResult<()> {
+        self.commands
+            .borrow_mut()
+            .push(format!("run-as {}", command));
+        Ok(())
+    }
+
+    fn shell(&self, command: &str) -> CargoResult<()> {
+        self.commands
+            .borrow_mut()
+            .push(format!("shell {}", command));
+        Ok(())
+    }
+
+    fn stop_app(&self) -> CargoResult<()> {
+        self.commands.borrow_mut().push("stop".to_owned());
+        Ok(())
+    }
+
+    fn start_app(&self) -> CargoResult<u64> {
+        self.commands.borrow_mut().push("start".to_owned());
+        Ok(1700000000)
+    }
+}
+
+#[test]
+fn hot_library_pushed_before_restart() {
+    let device = RecordingDevice::default();
+    let started_at = push_and_restart(
+        &device,
+        Path::new("target/android-artifacts/debug/arm64-v8a/libapp.so"),
+        "game",
+    )
+    .unwrap();
+    assert_eq!(started_at, 1700000000);
+    assert_eq!(
+        device.commands.into_inner(),
+        vec![
+            "push target/android-artifacts/debug/arm64-v8a/libapp.so \
+             /data/local/tmp/cargo-quad-apk-hot-libgame.so",
+            "run-as mkdir -p files/hot && \
+             cp '/data/local/tmp/cargo-quad-apk-hot-libgame.so' files/hot/libgame.so.tmp && \
+             mv files/hot/libgame.so.tmp files/hot/libgame.so",
+            "shell rm -f '/data/local/tmp/cargo-quad-apk-hot-libgame.so'",
+            "stop",
+            "start",
+        ]
+    );
+}
@@ -1,33 +1,871 @@
 use super::BuildResult;
-use crate::config::AndroidConfig;
-use crate::ops::build;
-use cargo::core::Workspace;
+use crate::config::{AndroidConfig, AndroidTargetConfig};
+use crate::error::{FailureKind, ResultExt};
+use crate::ops::adb_retry::{self, AdbRetry};
+use crate::ops::{build, compat, device, external_assets, interrupt};
+use anyhow::format_err;
+use cargo::core::{TargetKind, Workspace};
 use cargo::util::CargoResult;
 use cargo_util::ProcessBuilder;
 use clap::ArgMatches;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::thread;
 
 pub fn install(
     workspace: &Workspace,
     config: &AndroidConfig,
     options: &ArgMatches,
 ) -> CargoResult<BuildResult> {
+    let build_result = build_for_install(workspace, config, options)?;
+    let all_splits = options.get_flag("all-splits");
+    for device_config in device_configs(config, options) {
+        // Each device gets the split APKs of its own ABI
+        let mut installer =
+            Installer::new(workspace, &device_config, options).failure_kind(FailureKind::Device)?;
+        for target in build_result.target_to_apk_map.keys() {
+            for apk_path in build_result.apks_to_install(target, installer.abi, all_splits) {
+                installer
+                    .install(target, apk_path)
+                    .failure_kind(FailureKind::Device)?;
+            }
+        }
+
+        reverse_ports(workspace, &device_config, options)?;
+    }
+
+    Ok(build_result)
+}
+
+/// Returns the configuration for each device given with `--device`, or `config` when at most
+/// one was given
+fn device_configs(config: &AndroidConfig, options: &ArgMatches) -> Vec<AndroidConfig> {
+    let serials = options
+        .get_many::<String>("device")
+        .unwrap_or_default()
+        .collect::<Vec<_>>();
+    if serials.len() < 2 {
+        return vec![config.clone()];
+    }
+    serials
+        .into_iter()
+        .map(|serial| {
+            let mut device_config = config.clone();
+            device_config.device = Some(serial.clone());
+            device_config
+        })
+        .collect()
+}
+
+/// Builds the APKs to install, checking that they can be
+pub fn build_for_install(
+    workspace: &Workspace,
+    config: &AndroidConfig,
+    options: &ArgMatches,
+) -> CargoResult<BuildResult> {
+    if options.get_flag("no-apk") {
+        return Err(format_err!(
+            "`--no-apk` only builds the shared libraries, there is no APK to install"
+        ));
+    }
+
+    // Fail before building when the APKs could not be installed anyway
+    config.adb()?;
     let build_result = build::build(workspace, config, options)?;
+    // Never install an APK left over from a build with other features
+    let split_apks = build_result
+        .split_apks
+        .values()
+        .flat_map(|splits| splits.values());
+    for apk_path in build_result.target_to_apk_map.values().chain(split_apks) {
+        build::check_apk_features(apk_path, config)?;
+    }
+    Ok(build_result)
+}
+
+/// Installs APKs to the connected device, for the selected user
+pub struct Installer<'a> {
+    workspace: &'a Workspace<'a>,
+    config: &'a AndroidConfig,
+    /// `adb` for the selected device
+    adb: ProcessBuilder,
+    retry: AdbRetry<'a>,
+    user: Option<u32>,
+    install_existing: bool,
+    fastdeploy: bool,
+    /// Uninstall the app and install it again when it was signed with another key
+    force_reinstall: bool,
+    /// Check the APKs against the device before installing them, with `--check-device-compat`
+    check_compat: bool,
+    /// Install the APKs even when the check fails
+    force: bool,
+    /// ABI of the device among the build targets, whose split APKs are installed if any
+    pub abi: &'static str,
+}
 
-    let adb = config.sdk_path.join("platform-tools/adb");
+impl<'a> Installer<'a> {
+    pub fn new(
+        workspace: &'a Workspace<'a>,
+        config: &'a AndroidConfig,
+        options: &ArgMatches,
+    ) -> CargoResult<Self> {
+        device::check_device_selected(config)?;
+        let adb = config.adb()?;
+        let user = device::selected_user(options)?;
+        // `adb install --user` is not reliable before Android 7, the package is installed for
+        // the default user and then made available to the selected one instead
+        let install_existing = match user {
+            Some(_) => device::api_level(config)? < 24,
+            None => false,
+        };
 
-    for apk_path in build_result.target_to_apk_map.values() {
+        // The APKs hold the libraries of every build target, the device loads those of its
+        // best ABI, which is also the one of the split APK to install
+        let abis = config
+            .build_targets
+            .iter()
+            .map(|target| target.android_abi())
+            .collect::<Vec<_>>();
+        let abi = device::preferred_abi(&device::abi_list(config)?, &abis)?;
+        workspace.gctx().shell().status(
+            "Selected",
+            match &config.device {
+                Some(serial) => format!("{} libraries for device {}", abi, serial),
+                None => format!("{} libraries for the device", abi),
+            },
+        )?;
+
+        let fastdeploy = options.get_flag("fastdeploy") && adb_supports_fastdeploy(&adb);
+        Ok(Installer {
+            workspace,
+            config,
+            retry: AdbRetry::new(workspace.gctx(), &adb, options)?,
+            adb: config.adb_command()?,
+            user,
+            install_existing,
+            fastdeploy,
+            force_reinstall: options.get_flag("force-reinstall"),
+            check_compat: options.get_flag("check-device-compat"),
+            force: options.get_flag("force"),
+            abi,
+        })
+    }
+
+    pub fn install(&mut self, target: &(TargetKind, String), apk_path: &Path) -> CargoResult<()> {
+        let (workspace, config, adb, user) = (self.workspace, self.config, &self.adb, self.user);
+        let retry = &self.retry;
         drop(writeln!(
             workspace.gctx().shell().err(),
             "Installing apk '{}' to the device",
             apk_path.file_name().unwrap().to_string_lossy()
         ));
 
-        ProcessBuilder::new(&adb)
-            .arg("install")
-            .arg("-r")
-            .arg(apk_path)
-            .exec()?;
+        if self.check_compat {
+            let compat_options = compat::CompatOptions {
+                force_reinstall: self.force_reinstall,
+            };
+            compat::check_device_compat(workspace, config, apk_path, &compat_options, self.force)?;
+        }
+        check_free_space(adb, apk_path)?;
+
+        let target_config = config.resolve(target.clone())?;
+        let install_user = if self.install_existing { None } else { user };
+        let install = |fastdeploy: bool| {
+            let mut install_cmd = adb.clone();
+            install_cmd
+                .arg("install")
+                .args(&install_args(&target_config, install_user, fastdeploy))
+                .arg(apk_path);
+            retry.run("adb install", || retryable(exec_streaming(&install_cmd)?))
+        };
+        let (mut success, mut output) = install(self.fastdeploy)?;
+        // The package manager of some devices rejects the option which adb passes through
+        if !success && self.fastdeploy && is_unknown_option(&output) {
+            self.fastdeploy = false;
+            let (retry_success, retry_output) = install(false)?;
+            success = retry_success;
+            output = retry_output;
+        }
+        let application_id = target_config.application_id();
+        if !success
+            && self.force_reinstall
+            && install_failure(&output) == Some(InstallFailure::UpdateIncompatible)
+        {
+            workspace.gctx().shell().warn(format!(
+                "`{}` is installed with another signing key, uninstalling it along with its data",
+                application_id
+            ))?;
+            adb.clone().arg("uninstall").arg(&application_id).exec()?;
+            let (retry_success, retry_output) = install(self.fastdeploy)?;
+            success = retry_success;
+            output = retry_output;
+        }
+        if !success {
+            let apk = apk_path.display();
+            return Err(match install_failure(&output) {
+                Some(InstallFailure::UpdateIncompatible) => format_err!(
+                    "Unable to install '{}': `{}` is already installed on the device, signed with \
+                     another key. Uninstall it with `cargo quad-apk uninstall`, or pass \
+                     `--force-reinstall` to uninstall it automatically. Either way the data of the \
+                     app is lost.",
+                    apk,
+                    application_id
+                ),
+                Some(InstallFailure::VersionDowngrade) => format_err!(
+                    "Unable to install '{}': the device has a newer versionCode of `{}` than {}. \
+                     Raise `version_code`, or uninstall the app with `cargo quad-apk uninstall`.",
+                    apk,
+                    application_id,
+                    target_config.version_code
+                ),
+                Some(InstallFailure::InsufficientStorage) => format_err!(
+                    "The device does not have enough free storage to install '{}' ({}). \
+                     Free some space on the device or uninstall previous versions of the app.",
+                    apk,
+                    format_size(apk_path.metadata()?.len())
+                ),
+                Some(InstallFailure::Other(code)) => {
+                    format_err!("Unable to install '{}' to the device: {}", apk, code)
+                }
+                None => format_err!("Unable to install '{}' to the device", apk),
+            });
+        }
+
+        if let (Some(user), true) = (user, self.install_existing) {
+            let package_name = target_config.package_name.replace("-", "_");
+            adb.clone()
+                .arg("shell")
+                .arg("pm")
+                .arg("install-existing")
+                .arg("--user")
+                .arg(user.to_string())
+                .arg(package_name)
+                .exec()?;
+        }
+
+        verify_installed_version(workspace, config, target.clone(), user)?;
+
+        if let (true, Some(assets_path)) = (
+            target_config.debug_assets_external,
+            &target_config.assets_path,
+        ) {
+            push_external_assets(
+                workspace,
+                config,
+                adb,
+                retry,
+                &target_config,
+                assets_path,
+                user,
+            )?;
+        }
+        Ok(())
     }
+}
 
-    Ok(build_result)
+/// Pushes the assets which changed since the last install to the external files directory of
+/// the app, for `debug_assets_external`
+fn push_external_assets(
+    workspace: &Workspace,
+    config: &AndroidConfig,
+    adb: &ProcessBuilder,
+    retry: &AdbRetry,
+    target_config: &AndroidTargetConfig,
+    assets_path: &Path,
+    user: Option<u32>,
+) -> CargoResult<()> {
+    let application_id = target_config.application_id();
+    let store = external_assets::AdbAssetStore {
+        adb: adb.clone(),
+        dir: external_assets::device_dir(&application_id, user),
+        retry,
+    };
+    let manifest_dir = build::root_build_directory(workspace, config).join("external-assets");
+    fs::create_dir_all(&manifest_dir)?;
+    let plan = external_assets::sync_assets(
+        assets_path,
+        &target_config.assets_exclude,
+        &manifest_dir.join(format!("{}.json", application_id)),
+        &store,
+    )?;
+    workspace.gctx().shell().status(
+        "Synced",
+        format!(
+            "assets to {} ({} pushed, {} removed)",
+            store.dir,
+            plan.push.len(),
+            plan.remove.len()
+        ),
+    )?;
+    Ok(())
+}
+
+/// Returns the options of `adb install` for an APK of the given target
+fn install_args(
+    target_config: &AndroidTargetConfig,
+    user: Option<u32>,
+    fastdeploy: bool,
+) -> Vec<String> {
+    let mut args = vec!["-r".to_owned()];
+    // Otherwise the package manager refuses APKs with android:testOnly
+    if target_config.test_only {
+        args.push("-t".to_owned());
+    }
+    if let Some(user) = user {
+        args.push("--user".to_owned());
+        args.push(user.to_string());
+    }
+    if fastdeploy {
+        args.push("--fastdeploy".to_owned());
+    }
+    args
+}
+
+#[test]
+fn install_args_for_target() {
+    let target = (TargetKind::Bin, "app".to_owned());
+
+    let target_config = crate::config::from_metadata("")
+        .resolve(target.clone())
+        .unwrap();
+    assert_eq!(install_args(&target_config, None, false), vec!["-r"]);
+    assert_eq!(
+        install_args(&target_config, None, true),
+        vec!["-r", "--fastdeploy"]
+    );
+
+    let target_config = crate::config::from_metadata("test_only = true")
+        .resolve(target)
+        .unwrap();
+    assert_eq!(install_args(&target_config, None, false), vec!["-r", "-t"]);
+    assert_eq!(
+        install_args(&target_config, Some(10), false),
+        vec!["-r", "-t", "--user", "10"]
+    );
+}
+
+/// Turns the failure of a streamed adb command into an error when it may not happen again, so
+/// that it is retried
+fn retryable((success, output): (bool, String)) -> CargoResult<(bool, String)> {
+    if !success && adb_retry::classify(&output) == adb_retry::Failure::Transient {
+        Err(format_err!("{}", output.trim()))
+    } else {
+        Ok((success, output))
+    }
+}
+
+/// Runs a command with its output forwarded as it comes, so that the transfer progress of adb
+/// shows up, and returns whether it succeeded along with its output
+fn exec_streaming(cmd: &ProcessBuilder) -> CargoResult<(bool, String)> {
+    let mut command = cmd.build_command();
+    command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    let mut child = command
+        .spawn()
+        .map_err(|err| format_err!("Unable to run `{}`: {}", cmd, err))?;
+
+    fn forward(mut from: impl Read, mut to: impl Write) -> Vec<u8> {
+        let mut captured = vec![];
+        let mut buffer = [0; 4096];
+        while let Ok(len @ 1..) = from.read(&mut buffer) {
+            drop(to.write_all(&buffer[..len]));
+            drop(to.flush());
+            captured.extend_from_slice(&buffer[..len]);
+        }
+        captured
+    }
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let stdout = thread::spawn(move || forward(stdout, io::stdout()));
+    let stderr = thread::spawn(move || forward(stderr, io::stderr()));
+
+    let status = interrupt::wait(&mut child)?;
+    let mut output = stdout.join().unwrap_or_default();
+    output.extend(stderr.join().unwrap_or_default());
+    Ok((
+        status.success(),
+        String::from_utf8_lossy(&output).into_owned(),
+    ))
+}
+
+/// Whether adb supports `install --fastdeploy`, which appeared with adb 1.0.41
+fn adb_supports_fastdeploy(adb: &Path) -> bool {
+    ProcessBuilder::new(adb)
+        .arg("--version")
+        .exec_with_output()
+        .ok()
+        .and_then(|output| parse_adb_version(&String::from_utf8_lossy(&output.stdout)))
+        .map_or(false, |version| version >= (1, 0, 41))
+}
+
+/// Parses the version of the first line of `adb --version`
+fn parse_adb_version(output: &str) -> Option<(u32, u32, u32)> {
+    let version = output
+        .lines()
+        .next()?
+        .strip_prefix("Android Debug Bridge version ")?;
+    let mut parts = version.trim().split('.').map(|part| part.parse().ok());
+    Some((parts.next()??, parts.next()??, parts.next()??))
+}
+
+/// Reason of a failed `adb install`, as reported by the package manager
+#[derive(Debug, PartialEq)]
+enum InstallFailure {
+    /// The installed app is signed with another key
+    UpdateIncompatible,
+    /// The installed app has a higher versionCode
+    VersionDowngrade,
+    InsufficientStorage,
+    /// Any other `INSTALL_FAILED_*` or `INSTALL_PARSE_FAILED_*` code
+    Other(String),
+}
+
+/// Returns the failure code of the output of `adb install`, like `Failure
+/// [INSTALL_FAILED_UPDATE_INCOMPATIBLE: Package rust.app signatures do not match ...]`
+fn install_failure(output: &str) -> Option<InstallFailure> {
+    let start = output
+        .find("INSTALL_FAILED_")
+        .or_else(|| output.find("INSTALL_PARSE_FAILED_"))?;
+    let code = output[start..]
+        .split(|c: char| !(c.is_ascii_uppercase() || c == '_'))
+        .next()
+        .unwrap();
+    Some(match code {
+        "INSTALL_FAILED_UPDATE_INCOMPATIBLE" => InstallFailure::UpdateIncompatible,
+        "INSTALL_FAILED_VERSION_DOWNGRADE" => InstallFailure::VersionDowngrade,
+        "INSTALL_FAILED_INSUFFICIENT_STORAGE" => InstallFailure::InsufficientStorage,
+        code => InstallFailure::Other(code.to_owned()),
+    })
+}
+
+#[test]
+fn install_failures() {
+    assert_eq!(
+        install_failure(
+            "Performing Streamed Install\nadb: failed to install app.apk: Failure \
+             [INSTALL_FAILED_UPDATE_INCOMPATIBLE: Package rust.app signatures do not match \
+             newer version; ignoring!]"
+        ),
+        Some(InstallFailure::UpdateIncompatible)
+    );
+    assert_eq!(
+        install_failure(
+            "adb: failed to install app.apk: Failure [INSTALL_FAILED_VERSION_DOWNGRADE]"
+        ),
+        Some(InstallFailure::VersionDowngrade)
+    );
+    assert_eq!(
+        install_failure("Failure [INSTALL_FAILED_INSUFFICIENT_STORAGE]"),
+        Some(InstallFailure::InsufficientStorage)
+    );
+    assert_eq!(
+        install_failure(
+            "adb: failed to install app.apk: Failure [INSTALL_FAILED_NO_MATCHING_ABIS: Failed \
+             to extract native libraries, res=-113]"
+        ),
+        Some(InstallFailure::Other(
+            "INSTALL_FAILED_NO_MATCHING_ABIS".to_owned()
+        ))
+    );
+    assert_eq!(
+        install_failure("Failure [INSTALL_PARSE_FAILED_NO_CERTIFICATES: No signature found]"),
+        Some(InstallFailure::Other(
+            "INSTALL_PARSE_FAILED_NO_CERTIFICATES".to_owned()
+        ))
+    );
+    assert_eq!(install_failure("adb: device offline"), None);
+}
+
+/// Whether a failed `adb install` rejected one of its options
+fn is_unknown_option(output: &str) -> bool {
+    output.to_lowercase().contains("unknown option")
+}
+
+#[test]
+fn fastdeploy_support() {
+    let adb = "Android Debug Bridge version 1.0.41
+Version 33.0.3-8952118
+Installed as /opt/android-sdk/platform-tools/adb
+";
+    assert_eq!(parse_adb_version(adb), Some((1, 0, 41)));
+    assert_eq!(
+        parse_adb_version("Android Debug Bridge version 1.0.36\nRevision 1-debian\n"),
+        Some((1, 0, 36))
+    );
+    assert_eq!(parse_adb_version("adb: unknown command --version"), None);
+
+    assert!(is_unknown_option(
+        "Performing Push Install\nadb: failed to install app.apk: Exception occurred while \
+         executing 'install':\njava.lang.IllegalArgumentException: Unknown option --fastdeploy"
+    ));
+    assert!(is_unknown_option("Error: Unknown option: --fastdeploy"));
+    assert!(!is_unknown_option(
+        "adb: failed to install app.apk: Failure [INSTALL_FAILED_INSUFFICIENT_STORAGE]"
+    ));
+}
+
+/// Fails when the free space of `/data` on the device is clearly not enough for the APK.
+/// The check is skipped when the free space can't be determined.
+fn check_free_space(adb: &ProcessBuilder, apk_path: &Path) -> CargoResult<()> {
+    let available = match data_free_space(adb) {
+        Some(available) => available,
+        None => return Ok(()),
+    };
+
+    let apk_size = apk_path.metadata()?.len();
+    if available < apk_size {
+        return Err(format_err!(
+            "The device only has {} free on /data, which is not enough to install '{}' ({})",
+            format_size(available),
+            apk_path.display(),
+            format_size(apk_size)
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the free space of `/data` on the device, `None` when it can't be determined
+pub(super) fn data_free_space(adb: &ProcessBuilder) -> Option<u64> {
+    let output = adb
+        .clone()
+        .arg("shell")
+        .arg("df")
+        .arg("/data")
+        .exec_with_output()
+        .ok()?;
+    parse_df_available(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Parses the available bytes of the single filesystem listed by `df`
+fn parse_df_available(df: &str) -> Option<u64> {
+    let mut lines = df.lines().filter(|line| !line.trim().is_empty());
+    let header = lines.next()?;
+
+    // Android 2: `/data: 1607296K total, 123528K used, 1483768K available (block size 4096)`
+    if let Some((available, _)) = header.split_once(" available") {
+        return parse_size(available.rsplit(' ').next()?);
+    }
+
+    // Long filesystem names may wrap the values onto the next line
+    let values = lines.flat_map(str::split_whitespace).collect::<Vec<_>>();
+    let column = header
+        .split_whitespace()
+        .position(|column| matches!(column, "Available" | "Avail" | "Free"))?;
+    let available = values.get(column)?;
+    if header.contains("1K-blocks") {
+        available.parse::<u64>().ok().map(|blocks| blocks * 1024)
+    } else {
+        parse_size(available)
+    }
+}
+
+/// Parses sizes like `7.9G`, `512K` or `4096`
+fn parse_size(size: &str) -> Option<u64> {
+    let (number, unit) = match size.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(i) => size.split_at(i),
+        None => (size, ""),
+    };
+    let multiplier: u64 = match unit {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return None,
+    };
+    number
+        .parse::<f64>()
+        .ok()
+        .map(|number| (number * multiplier as f64) as u64)
+}
+
+pub(super) fn format_size(bytes: u64) -> String {
+    format!("{:.1} MB", bytes as f64 / (1 << 20) as f64)
+}
+
+#[test]
+fn df_available_space() {
+    // toybox, Android 7 and later
+    let df = "Filesystem       1K-blocks    Used Available Use% Mounted on
+/dev/block/dm-5  115164900 9384352 105649476   9% /data
+";
+    assert_eq!(parse_df_available(df), Some(105649476 * 1024));
+
+    let df = "Filesystem                                                1K-blocks    Used Available Use% Mounted on
+/dev/block/platform/soc/7824900.sdhci/by-name/userdata
+                                                           24511184 2236548  22143564  10% /data
+";
+    assert_eq!(parse_df_available(df), Some(22143564 * 1024));
+
+    // toolbox, Android 4 to 6
+    let df = "Filesystem               Size     Used     Free   Blksize
+/data                   12.5G     4.6G     7.9G   4096
+";
+    assert_eq!(
+        parse_df_available(df),
+        Some((7.9 * (1u64 << 30) as f64) as u64)
+    );
+
+    let df = "/data: 1607296K total, 123528K used, 1483768K available (block size 4096)\n";
+    assert_eq!(parse_df_available(df), Some(1483768 * 1024));
+
+    assert_eq!(parse_df_available("df: /data: Permission denied\n"), None);
+    assert_eq!(parse_df_available(""), None);
+    assert_eq!(format_size(400 << 20), "400.0 MB");
+}
+
+/// Checks that the device reports the version of the APK which was just installed
+fn verify_installed_version(
+    workspace: &Workspace,
+    config: &AndroidConfig,
+    target: (TargetKind, String),
+    user: Option<u32>,
+) -> CargoResult<()> {
+    let adb = config.adb_command()?;
+    let target_config = config.resolve(target)?;
+    let package_name = target_config.package_name.replace("-", "_");
+
+    let output = adb
+        .clone()
+        .arg("shell")
+        .arg("dumpsys")
+        .arg("package")
+        .arg(&package_name)
+        .exec_with_output()?;
+    let installed =
+        parse_installed_version(&String::from_utf8_lossy(&output.stdout), &package_name)
+            .ok_or_else(|| {
+                format_err!(
+                    "Unable to find the installed version of `{}` on the device",
+                    package_name
+                )
+            })?;
+
+    if installed.version_code != target_config.version_code
+        || installed.version_name.as_deref() != Some(target_config.version_name.as_str())
+    {
+        return Err(format_err!(
+            "The device reports `{}` versionCode {} (versionName {}) after installing \
+             versionCode {} (versionName {}). \
+             An installation for another user or a work profile may be shadowing this one, \
+             use `--list-users` to list the users of the device and `--user` to target one.",
+            package_name,
+            installed.version_code,
+            installed.version_name.as_deref().unwrap_or("unknown"),
+            target_config.version_code,
+            target_config.version_name
+        ));
+    }
+
+    if let Some(user) = user {
+        if !installed.installed_users.contains(&user) {
+            return Err(format_err!(
+                "`{}` is not installed for user {} according to the device",
+                package_name,
+                user
+            ));
+        }
+    }
+
+    workspace.gctx().shell().status(
+        "Installed",
+        format!(
+            "{} versionCode {} (versionName {})",
+            package_name, target_config.version_code, target_config.version_name
+        ),
+    )?;
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+struct InstalledVersion {
+    version_code: i32,
+    version_name: Option<String>,
+    /// Users for which the package is installed
+    installed_users: Vec<u32>,
+}
+
+/// Returns the lines of the section of a package in the output of `dumpsys package <package>`
+pub(super) fn package_section<'a>(
+    dumpsys: &'a str,
+    package_name: &str,
+) -> impl Iterator<Item = &'a str> {
+    let header = format!("Package [{}]", package_name);
+    dumpsys
+        .lines()
+        .skip_while(move |line| !line.contains(&header))
+        .skip(1)
+        // The next package section starts with another header
+        .take_while(|line| !line.trim_start().starts_with("Package ["))
+}
+
+/// Finds the version of a package in the output of `dumpsys package <package>`
+fn parse_installed_version(dumpsys: &str, package_name: &str) -> Option<InstalledVersion> {
+    let lines = package_section(dumpsys, package_name);
+
+    let mut version_code = None;
+    let mut version_name = None;
+    let mut installed_users = vec![];
+    for line in lines {
+        // User 10: ceDataInode=1234 installed=true hidden=false suspended=false ...
+        if let Some(user) = line.trim().strip_prefix("User ") {
+            if let Some((id, state)) = user.split_once(':') {
+                if state.contains("installed=true") {
+                    installed_users.extend(id.parse::<u32>().ok());
+                }
+            }
+        }
+        for token in line.split_whitespace() {
+            if let Some(code) = token.strip_prefix("versionCode=") {
+                version_code = version_code.or_else(|| code.parse().ok());
+            }
+        }
+        if let Some(name) = line.trim().strip_prefix("versionName=") {
+            version_name = version_name.or_else(|| Some(name.to_owned()));
+        }
+    }
+
+    version_code.map(|version_code| InstalledVersion {
+        version_code,
+        version_name,
+        installed_users,
+    })
+}
+
+#[test]
+fn parse_dumpsys_versions() {
+    // Android 10 and later
+    let dumpsys = r#"Activity Resolver Table:
+  Non-Data Actions:
+      android.intent.action.MAIN:
+        1c0b5b2 rust.app/.MainActivity filter 6f0e103
+
+Packages:
+  Package [rust.app] (9e31c7f):
+    userId=10153
+    pkg=Package{bb3e94c rust.app}
+    codePath=/data/app/~~Qk1dQ==/rust.app-L5w==
+    resourcePath=/data/app/~~Qk1dQ==/rust.app-L5w==
+    versionCode=3 minSdk=26 targetSdk=31
+    versionName=1.2.0
+    splits=[base]
+    User 0: ceDataInode=59764 installed=true hidden=false suspended=false stopped=false
+    User 10: ceDataInode=0 installed=false hidden=false suspended=false stopped=true
+    User 11: ceDataInode=60211 installed=true hidden=false suspended=false stopped=false
+"#;
+    assert_eq!(
+        parse_installed_version(dumpsys, "rust.app"),
+        Some(InstalledVersion {
+            version_code: 3,
+            version_name: Some("1.2.0".to_owned()),
+            installed_users: vec![0, 11],
+        })
+    );
+
+    // Android 5
+    let dumpsys = r#"Packages:
+  Package [rust.app] (2a6b3c1c):
+    userId=10061 gids=[]
+    pkg=Package{3b0e8f25 rust.app}
+    versionCode=1 targetSdk=21
+    versionName=0.1.0
+    splits=[base]
+  Package [rust.app.example.other] (1f2e3d4c):
+    versionCode=7 targetSdk=21
+    versionName=7.0
+"#;
+    assert_eq!(
+        parse_installed_version(dumpsys, "rust.app"),
+        Some(InstalledVersion {
+            version_code: 1,
+            version_name: Some("0.1.0".to_owned()),
+            installed_users: vec![],
+        })
+    );
+
+    assert_eq!(parse_installed_version("Packages:\n", "rust.app"), None);
+}
+
+/// Sets up `adb reverse` for the `dev_ports` of debug builds and the `--reverse` mappings, so
+/// that the app can reach servers running on the host through `localhost`.
+/// Failures only produce warnings, as old devices do not support `adb reverse`.
+pub fn reverse_ports(
+    workspace: &Workspace,
+    config: &AndroidConfig,
+    options: &ArgMatches,
+) -> CargoResult<()> {
+    let adb = config.adb_command()?;
+    let mut shell = workspace.gctx().shell();
+
+    if options.get_flag("no-reverse") {
+        if let Err(err) = adb
+            .clone()
+            .arg("reverse")
+            .arg("--remove-all")
+            .exec_with_output()
+        {
+            shell.warn(format!("Unable to remove port reversals. {}", err))?;
+        }
+        return Ok(());
+    }
+
+    let dev_ports = if config.release {
+        &[][..]
+    } else {
+        &config.dev_ports[..]
+    };
+    let mut mappings = dev_ports
+        .iter()
+        .map(|&port| (port, port))
+        .collect::<Vec<_>>();
+    for mapping in options.get_many::<String>("reverse").unwrap_or_default() {
+        mappings.push(parse_port_mapping(mapping)?);
+    }
+
+    for (device_port, host_port) in mappings {
+        let device = format!("tcp:{}", device_port);
+        let host = format!("tcp:{}", host_port);
+        match adb
+            .clone()
+            .arg("reverse")
+            .arg(&device)
+            .arg(&host)
+            .exec_with_output()
+        {
+            Ok(_) => shell.status("Reversed", format!("device {} to host {}", device, host))?,
+            Err(err) => shell.warn(format!(
+                "Unable to reverse device {} to host {}. {}",
+                device, host, err
+            ))?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Parses a `PORT[:HOST_PORT]` mapping into the device and host ports
+fn parse_port_mapping(mapping: &str) -> CargoResult<(u16, u16)> {
+    let parse = |port: &str| {
+        port.parse::<u16>()
+            .map_err(|_| format_err!("Invalid port `{}` in `--reverse {}`", port, mapping))
+    };
+
+    match mapping.split_once(':') {
+        Some((device_port, host_port)) => Ok((parse(device_port)?, parse(host_port)?)),
+        None => {
+            let port = parse(mapping)?;
+            Ok((port, port))
+        }
+    }
+}
+
+#[test]
+fn port_mappings() {
+    assert_eq!(parse_port_mapping("8080").unwrap(), (8080, 8080));
+    assert_eq!(parse_port_mapping("8080:3000").unwrap(), (8080, 3000));
+    assert!(parse_port_mapping("http").is_err());
+    assert!(parse_port_mapping("8080:").is_err());
+    assert!(parse_port_mapping("70000").is_err());
 }
@@ -0,0 +1,261 @@
+//! Clean shutdown on Ctrl-C.
+//!
+//! The child processes started through `exec` and `wait` are tracked, so that the handler can
+//! stop them, remove the registered temporary files, like the glue sources of the build and the
+//! APKs being signed, and exit with the conventional status 130. Only Unix has the handler,
+//! Ctrl-C stops the process right away elsewhere as before.
+
+use super::build::tempfile;
+use anyhow::format_err;
+use cargo::util::CargoResult;
+use cargo_util::{ProcessBuilder, ProcessError};
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, BufReader, Read};
+use std::process::{self, Child, ChildStdout, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// Process ids of the running children
+static CHILDREN: Mutex<BTreeSet<u32>> = Mutex::new(BTreeSet::new());
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Installs the Ctrl-C handler, before anything is started
+pub fn install_handler() {
+    #[cfg(unix)]
+    unix::install();
+}
+
+/// Runs a command like `ProcessBuilder::exec`, tracking the child
+pub fn exec(cmd: &ProcessBuilder) -> CargoResult<()> {
+    let mut child = cmd
+        .build_command()
+        .spawn()
+        .map_err(|err| format_err!("could not execute process {}: {}", cmd, err))?;
+    let status = wait(&mut child)?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(ProcessError::new(
+            &format!("process didn't exit successfully: {}", cmd),
+            Some(status),
+            None,
+        )
+        .into())
+    }
+}
+
+/// Runs a command like `exec`, returning its stderr, which is still printed as it comes
+pub fn exec_with_stderr(cmd: &ProcessBuilder) -> CargoResult<String> {
+    let mut child = cmd
+        .build_command()
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| format_err!("could not execute process {}: {}", cmd, err))?;
+    let stderr = child.stderr.take().unwrap();
+    let reader = thread::spawn(move || {
+        let mut captured = String::new();
+        for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+            eprintln!("{}", line);
+            captured.push_str(&line);
+            captured.push('\n');
+        }
+        captured
+    });
+    let status = wait(&mut child)?;
+    let captured = reader.join().unwrap();
+    if status.success() {
+        Ok(captured)
+    } else {
+        Err(ProcessError::new(
+            &format!("process didn't exit successfully: {}", cmd),
+            Some(status),
+            None,
+        )
+        .into())
+    }
+}
+
+/// Runs a command like `exec`, with its stdout and stderr captured instead of printed. The output
+/// is part of the error when the command fails.
+pub fn exec_with_output(cmd: &ProcessBuilder) -> CargoResult<Output> {
+    let mut child = cmd
+        .build_command()
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| format_err!("could not execute process {}: {}", cmd, err))?;
+    let read_all = |mut pipe: Box<dyn Read + Send>| {
+        thread::spawn(move || {
+            let mut captured = vec![];
+            drop(pipe.read_to_end(&mut captured));
+            captured
+        })
+    };
+    let stdout = read_all(Box::new(child.stdout.take().unwrap()));
+    let stderr = read_all(Box::new(child.stderr.take().unwrap()));
+    let status = wait(&mut child)?;
+    let output = Output {
+        status,
+        stdout: stdout.join().unwrap(),
+        stderr: stderr.join().unwrap(),
+    };
+    if status.success() {
+        Ok(output)
+    } else {
+        Err(ProcessError::new(
+            &format!("process didn't exit successfully: {}", cmd),
+            Some(status),
+            Some(&output),
+        )
+        .into())
+    }
+}
+
+/// Runs a command like `exec`, with its stdout given to `read` on another thread. Returns what
+/// `read` returns once the command has exited.
+pub fn exec_with_stdout<T: Send + 'static>(
+    cmd: &ProcessBuilder,
+    read: impl FnOnce(ChildStdout) -> T + Send + 'static,
+) -> CargoResult<T> {
+    let mut child = cmd
+        .build_command()
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|err| format_err!("could not execute process {}: {}", cmd, err))?;
+    let stdout = child.stdout.take().unwrap();
+    let reader = thread::spawn(move || read(stdout));
+    let status = wait(&mut child)?;
+    let read = reader.join().unwrap();
+    if status.success() {
+        Ok(read)
+    } else {
+        Err(ProcessError::new(
+            &format!("process didn't exit successfully: {}", cmd),
+            Some(status),
+            None,
+        )
+        .into())
+    }
+}
+
+/// Runs a command like `exec_with_stdout`, until it exits or `running` returns false, which is
+/// checked every `interval`. The command is then killed, which isn't reported as a failure.
+pub fn exec_with_stdout_while<T: Send + 'static>(
+    cmd: &ProcessBuilder,
+    read: impl FnOnce(ChildStdout) -> T + Send + 'static,
+    mut running: impl FnMut() -> bool,
+    interval: Duration,
+) -> CargoResult<T> {
+    let mut child = cmd
+        .build_command()
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|err| format_err!("could not execute process {}: {}", cmd, err))?;
+    let stdout = child.stdout.take().unwrap();
+    let reader = thread::spawn(move || read(stdout));
+    CHILDREN.lock().unwrap().insert(child.id());
+    let status = loop {
+        match child.try_wait() {
+            Ok(None) if running() => thread::sleep(interval),
+            Ok(None) => {
+                drop(child.kill());
+                drop(child.wait());
+                break Ok(None);
+            }
+            Ok(Some(status)) => break Ok(Some(status)),
+            Err(err) => break Err(err),
+        }
+    };
+    CHILDREN.lock().unwrap().remove(&child.id());
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        loop {
+            thread::park();
+        }
+    }
+    let read = reader.join().unwrap();
+    match status? {
+        Some(status) if !status.success() => Err(ProcessError::new(
+            &format!("process didn't exit successfully: {}", cmd),
+            Some(status),
+            None,
+        )
+        .into()),
+        _ => Ok(read),
+    }
+}
+
+/// Waits for a child, which is stopped if Ctrl-C is hit meanwhile
+pub fn wait(child: &mut Child) -> io::Result<ExitStatus> {
+    CHILDREN.lock().unwrap().insert(child.id());
+    let status = child.wait();
+    CHILDREN.lock().unwrap().remove(&child.id());
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        // The child failed because of the interruption, which the handler reports by exiting
+        loop {
+            thread::park();
+        }
+    }
+    status
+}
+
+fn shutdown() -> ! {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+    let children = match CHILDREN.lock() {
+        Ok(children) => children.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    };
+    // Forwarded as is, so that `adb logcat` and the like stop cleanly
+    #[cfg(unix)]
+    for pid in children {
+        unix::interrupt(pid);
+    }
+    tempfile::remove_registered();
+    process::exit(130);
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::sync::atomic::{AtomicI32, Ordering};
+    use std::thread;
+
+    /// Write end of the pipe through which the signal handler wakes the shutdown thread
+    static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+    extern "C" fn on_sigint(_: libc::c_int) {
+        // Only async-signal-safe calls are allowed here
+        let byte = 0u8;
+        unsafe {
+            libc::write(
+                PIPE.load(Ordering::SeqCst),
+                &byte as *const u8 as *const _,
+                1,
+            );
+        }
+    }
+
+    pub fn install() {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return;
+        }
+        PIPE.store(fds[1], Ordering::SeqCst);
+        thread::spawn(move || {
+            let mut byte = 0u8;
+            // Retried when interrupted by the signal itself
+            while unsafe { libc::read(fds[0], &mut byte as *mut u8 as *mut _, 1) } != 1 {}
+            super::shutdown();
+        });
+        unsafe {
+            libc::signal(libc::SIGINT, on_sigint as libc::sighandler_t);
+        }
+    }
+
+    pub fn interrupt(pid: u32) {
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGINT);
+        }
+    }
+}
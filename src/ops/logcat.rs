@@ -0,0 +1,581 @@
+use crate::config::AndroidConfig;
+use crate::error::{FailureKind, ResultExt};
+use crate::ops::state::DeviceState;
+use crate::ops::symbolicate::Symbolicator;
+use crate::ops::{device, interrupt, restart};
+use anyhow::format_err;
+use cargo::core::Workspace;
+use cargo::util::CargoResult;
+use cargo_util::ProcessBuilder;
+use clap::ArgMatches;
+use serde::Serialize;
+use std::io::{BufRead, BufReader, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub fn logcat(
+    workspace: &Workspace,
+    config: &AndroidConfig,
+    options: &ArgMatches,
+) -> CargoResult<()> {
+    device::check_device_selected(config)?;
+    let adb = config.adb_command()?;
+
+    // Device time from which logs are printed, if limited
+    let since = if options.get_flag("since-run") {
+        Some(
+            DeviceState::load(workspace)
+                .last_run_started_at
+                .ok_or_else(|| {
+                    format_err!("The app wasn't started with `cargo quad-apk run` yet")
+                })?,
+        )
+    } else if options.get_flag("since-boot") {
+        Some(device::boot_time(config).failure_kind(FailureKind::Device)?)
+    } else if let Some(since) = options.get_one::<String>("since") {
+        let duration = parse_duration(since)?;
+        Some(
+            device::device_time(config)
+                .failure_kind(FailureKind::Device)?
+                .saturating_sub(duration.as_secs()),
+        )
+    } else {
+        None
+    };
+
+    let pid = if options.get_flag("pid-of-app") {
+        let target_config = config.resolve(restart::app_target(workspace, config, options)?)?;
+        let application_id = target_config.application_id();
+        Some(app_pid(config, &application_id)?.ok_or_else(|| {
+            FailureKind::Device.mark(format_err!(
+                "`{}` is not running, start it with `cargo quad-apk run`",
+                application_id
+            ))
+        })?)
+    } else {
+        None
+    };
+
+    if options.get_flag("clear") {
+        adb.clone()
+            .arg("logcat")
+            .arg("-c")
+            .exec()
+            .failure_kind(FailureKind::Device)?;
+    }
+
+    drop(writeln!(workspace.gctx().shell().err(), "Starting logcat"));
+    let json = options.get_flag("json");
+    let mut logcat_cmd = adb.clone();
+    // The default format depends on the device and may lack timestamps. The long format
+    // separates the records, whose messages span several lines, for `--json`.
+    logcat_cmd
+        .arg("logcat")
+        .arg("-v")
+        .arg(if json { "long" } else { "threadtime" });
+    if options.get_flag("dump") {
+        logcat_cmd.arg("-d");
+    }
+    if let Some(since) = since {
+        logcat_cmd.arg("-T").arg(logcat_time(since));
+    }
+    if let Some(pid) = pid {
+        logcat_cmd.arg(format!("--pid={}", pid));
+    }
+    let tags = options
+        .get_many::<String>("tag")
+        .unwrap_or_default()
+        .cloned()
+        .collect::<Vec<_>>();
+    logcat_cmd.args(&filterspecs(
+        &tags,
+        options.get_one::<String>("level").map(String::as_str),
+    ));
+    if options.get_flag("symbolicate") {
+        let mut symbolicator = Symbolicator::new(workspace, config)?;
+        interrupt::exec_with_stdout(&logcat_cmd, move |adb_stdout| {
+            let stdout = std::io::stdout();
+            for line in BufReader::new(adb_stdout).lines().map_while(Result::ok) {
+                drop(writeln!(stdout.lock(), "{}", line));
+                symbolicator.push_line(&line);
+            }
+            symbolicator.finish();
+        })
+        .failure_kind(FailureKind::Device)?;
+    } else if json {
+        interrupt::exec_with_stdout(&logcat_cmd, |adb_stdout| {
+            let stdout = std::io::stdout();
+            LogcatStream::new().read(BufReader::new(adb_stdout), |record| {
+                let mut out = stdout.lock();
+                drop(serde_json::to_writer(&mut out, &record));
+                drop(writeln!(out));
+            })
+        })
+        .failure_kind(FailureKind::Device)?;
+    } else {
+        interrupt::exec(&logcat_cmd).failure_kind(FailureKind::Device)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the filterspecs of logcat printing the records of `tags` only, or of every tag without
+/// any, at `level` or above
+fn filterspecs(tags: &[String], level: Option<&str>) -> Vec<String> {
+    let level = level.unwrap_or("V").to_uppercase();
+    if tags.is_empty() {
+        return match level.as_str() {
+            "V" => vec![],
+            _ => vec![format!("*:{}", level)],
+        };
+    }
+    tags.iter()
+        .map(|tag| format!("{}:{}", tag, level))
+        .chain(Some("*:S".to_owned()))
+        .collect()
+}
+
+/// Time given to a started app to show up in `pidof`
+const APP_START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Tags printed whatever process logs them: the panics of the Rust code, printed by miniquad and
+/// its redirection of stdout and stderr, the crashes of the Java code, and the native crashes
+/// dumped by crash_dump
+const APP_TAGS: &[&str] = &["SAPP", "RustStdoutStderr", "AndroidRuntime", "DEBUG"];
+
+/// Follows the log of the process of the app started at `started_at`, the device time, until it
+/// exits or Ctrl-C is hit. Its native crashes are given to `symbolicator`, if any.
+pub fn app_logcat(
+    workspace: &Workspace,
+    config: &AndroidConfig,
+    application_id: &str,
+    started_at: u64,
+    mut symbolicator: Option<Symbolicator>,
+) -> CargoResult<()> {
+    let deadline = Instant::now() + APP_START_TIMEOUT;
+    let pid = loop {
+        if let Some(pid) = app_pid(config, application_id)? {
+            break pid;
+        }
+        if Instant::now() > deadline {
+            return Err(FailureKind::Device.mark(format_err!(
+                "`{}` has no running process to follow the log of, it may have crashed, see \
+                 `cargo quad-apk logcat --since-run`",
+                application_id
+            )));
+        }
+        thread::sleep(Duration::from_millis(200));
+    };
+
+    drop(writeln!(
+        workspace.gctx().shell().err(),
+        "Starting logcat of process {}",
+        pid
+    ));
+    let mut logcat_cmd = config.adb_command()?;
+    logcat_cmd
+        .arg("logcat")
+        .arg("-v")
+        .arg("threadtime")
+        .arg("-T")
+        .arg(logcat_time(started_at));
+    let app_pid_filter = pid.clone();
+    // The log of the last second is still printed once the process is gone, for crashes
+    let mut gone_checks = 0;
+    interrupt::exec_with_stdout_while(
+        &logcat_cmd,
+        move |adb_stdout| {
+            let stdout = std::io::stdout();
+            for line in BufReader::new(adb_stdout).lines().map_while(Result::ok) {
+                if is_app_line(&line, &app_pid_filter) {
+                    drop(writeln!(stdout.lock(), "{}", line));
+                    if let Some(symbolicator) = &mut symbolicator {
+                        symbolicator.push_line(&line);
+                    }
+                }
+            }
+            if let Some(symbolicator) = &mut symbolicator {
+                symbolicator.finish();
+            }
+        },
+        || {
+            if app_pid(config, application_id).ok().flatten().as_ref() != Some(&pid) {
+                gone_checks += 1;
+            }
+            gone_checks < 2
+        },
+        Duration::from_secs(1),
+    )
+    .failure_kind(FailureKind::Device)?;
+    if gone_checks >= 2 {
+        workspace
+            .gctx()
+            .shell()
+            .status("Exited", format!("`{}`", application_id))?;
+    }
+    Ok(())
+}
+
+/// Returns the pid of the main process of the app, if running
+fn app_pid(config: &AndroidConfig, application_id: &str) -> CargoResult<Option<String>> {
+    let output = config
+        .adb_command()?
+        .arg("shell")
+        .arg("pidof")
+        .arg(application_id)
+        .output()
+        .map_err(|err| FailureKind::Device.mark(err.into()))?;
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .map(str::to_owned))
+}
+
+/// Returns the pid, the tag and the message of a line of `logcat -v threadtime`, like
+/// `10-18 12:00:00.123  1234  1250 E SAPP    : message`
+pub(super) fn threadtime_fields(line: &str) -> Option<(&str, &str, &str)> {
+    let mut rest = line.trim_start();
+    let mut fields = [""; 5];
+    for field in &mut fields {
+        let end = rest.find(char::is_whitespace)?;
+        *field = &rest[..end];
+        rest = rest[end..].trim_start();
+    }
+    let (tag, message) = rest.split_at(rest.find(':')?);
+    Some((fields[2], tag.trim_end(), message[1..].trim_start()))
+}
+
+/// Whether a line of `logcat -v threadtime` is logged by the process `pid` or has one of the
+/// `APP_TAGS`
+fn is_app_line(line: &str, pid: &str) -> bool {
+    threadtime_fields(line).map_or(false, |(line_pid, tag, _)| {
+        line_pid == pid || APP_TAGS.contains(&tag)
+    })
+}
+
+/// Record of the log, as printed by `--json`
+#[derive(Debug, PartialEq, Serialize)]
+pub struct LogRecord {
+    /// Device time, like `01-15 10:23:45.123`
+    pub timestamp: String,
+    pub pid: u32,
+    pub tid: u32,
+    /// `verbose`, `debug`, `info`, `warn`, `error`, `fatal` or `silent`
+    pub priority: &'static str,
+    pub tag: String,
+    /// Lines of the message, joined with `\n`
+    pub message: String,
+}
+
+/// Parser of the output of `logcat -v long`, where each record is a header line like
+/// `[ 01-15 10:23:45.123  1234: 5678 I/Tag ]` followed by the lines of the message and an empty
+/// line. Messages may have empty lines too, so a record ends at the next header.
+#[derive(Default)]
+pub struct LogcatStream {
+    record: Option<LogRecord>,
+    /// Whether a line of the message of `record` was read
+    message_started: bool,
+    /// Empty lines since the last line of the message of `record`
+    empty_lines: usize,
+}
+
+impl LogcatStream {
+    pub fn new() -> LogcatStream {
+        LogcatStream::default()
+    }
+
+    /// Parses a line, without its line ending, returning the record it ends if any. Lines which
+    /// aren't valid UTF-8 are decoded lossily.
+    pub fn push_line(&mut self, line: &[u8]) -> Option<LogRecord> {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches('\r');
+        if let Some(record) = parse_header(line) {
+            self.message_started = false;
+            self.empty_lines = 0;
+            return std::mem::replace(&mut self.record, Some(record));
+        }
+        // Printed when the output moves on to another buffer
+        if line.starts_with("--------- beginning of ") {
+            return None;
+        }
+        if let Some(record) = &mut self.record {
+            if line.is_empty() {
+                self.empty_lines += 1;
+            } else {
+                // The empty lines after the message are the separator, those followed by more
+                // lines are part of it
+                let line_breaks = self.empty_lines + usize::from(self.message_started);
+                record
+                    .message
+                    .extend(std::iter::repeat('\n').take(line_breaks));
+                record.message.push_str(line);
+                self.message_started = true;
+                self.empty_lines = 0;
+            }
+        }
+        // Lines before the first header are lost
+        None
+    }
+
+    /// Returns the last record, at the end of the output
+    pub fn finish(&mut self) -> Option<LogRecord> {
+        self.message_started = false;
+        self.empty_lines = 0;
+        self.record.take()
+    }
+
+    /// Parses the whole output of `reader`, giving each record to `on_record` as soon as it ends
+    pub fn read(mut self, mut reader: impl BufRead, mut on_record: impl FnMut(LogRecord)) {
+        let mut line = vec![];
+        loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            if line.last() == Some(&b'\n') {
+                line.pop();
+            }
+            if let Some(record) = self.push_line(&line) {
+                on_record(record);
+            }
+        }
+        if let Some(record) = self.finish() {
+            on_record(record);
+        }
+    }
+}
+
+/// Parses the header line of a record of `logcat -v long`
+fn parse_header(line: &str) -> Option<LogRecord> {
+    let header = line.strip_prefix("[ ")?.strip_suffix(" ]")?.trim();
+    let mut fields = header.splitn(3, ' ');
+    let date = fields.next()?;
+    let time = fields.next()?;
+    let (pid, rest) = fields.next()?.trim_start().split_once(':')?;
+    let (tid, rest) = rest.trim_start().split_once(' ')?;
+    let (priority, tag) = rest.trim_start().split_once('/')?;
+    // Thread ids are printed in hexadecimal by old devices
+    let tid = match tid.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+        None => tid.parse().ok()?,
+    };
+    let priority = match priority {
+        "V" => "verbose",
+        "D" => "debug",
+        "I" => "info",
+        "W" => "warn",
+        "E" => "error",
+        "F" => "fatal",
+        "S" => "silent",
+        _ => return None,
+    };
+    Some(LogRecord {
+        timestamp: format!("{} {}", date, time),
+        pid: pid.trim().parse().ok()?,
+        tid,
+        priority,
+        tag: tag.trim_end().to_owned(),
+        message: String::new(),
+    })
+}
+
+/// Formats a time in seconds since the epoch the way `logcat -T` expects it
+fn logcat_time(epoch_seconds: u64) -> String {
+    format!("{}.000", epoch_seconds)
+}
+
+/// Parses relative times like `90s`, `15m`, `1h30m` or `2d`
+pub fn parse_duration(duration: &str) -> CargoResult<Duration> {
+    let invalid = || {
+        format_err!(
+            "Invalid duration `{}`, expected a number followed by `s`, `m`, `h` or `d`, like `1h30m`",
+            duration
+        )
+    };
+
+    let mut seconds = 0;
+    let mut number = String::new();
+    for c in duration.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        let value: u64 = number.parse().map_err(|_| invalid())?;
+        seconds += value * unit;
+        number.clear();
+    }
+    if !number.is_empty() || seconds == 0 {
+        return Err(invalid());
+    }
+
+    Ok(Duration::from_secs(seconds))
+}
+
+#[test]
+fn logcat_time_formats() {
+    assert_eq!(logcat_time(1_700_000_000), "1700000000.000");
+
+    assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+    assert_eq!(parse_duration("15m").unwrap(), Duration::from_secs(15 * 60));
+    assert_eq!(
+        parse_duration("1h30m").unwrap(),
+        Duration::from_secs(90 * 60)
+    );
+    assert_eq!(
+        parse_duration("2d").unwrap(),
+        Duration::from_secs(2 * 24 * 60 * 60)
+    );
+    assert!(parse_duration("1").is_err());
+    assert!(parse_duration("h").is_err());
+    assert!(parse_duration("1w").is_err());
+    assert!(parse_duration("").is_err());
+}
+
+#[test]
+fn logcat_long_records() {
+    let mut records = vec![];
+    LogcatStream::new().read(
+        &include_bytes!("../../tests/fixtures/logcat/long.txt")[..],
+        |record| records.push(record),
+    );
+    let record = |timestamp: &str, pid, tid, priority, tag: &str, message: &str| LogRecord {
+        timestamp: timestamp.to_owned(),
+        pid,
+        tid,
+        priority,
+        tag: tag.to_owned(),
+        message: message.to_owned(),
+    };
+    assert_eq!(records.len(), 5);
+    assert_eq!(
+        records[0],
+        record(
+            "01-15 10:23:45.123",
+            1234,
+            5678,
+            "info",
+            "RustStdoutStderr",
+            "hello from rust"
+        )
+    );
+    assert_eq!(
+        records[1],
+        record(
+            "01-15 10:23:45.130",
+            1234,
+            5690,
+            "error",
+            "AndroidRuntime",
+            "FATAL EXCEPTION: main\n\
+             Process: rust.app, PID: 1234\n\
+             java.lang.RuntimeException: boom\n\
+             \tat rust.app.MainActivity.onCreate(MainActivity.java:42)\n\
+             \tat android.app.Activity.performCreate(Activity.java:8000)"
+        )
+    );
+    assert_eq!(
+        records[2],
+        record(
+            "01-15 10:23:46.001",
+            987,
+            987,
+            "warn",
+            "Some Tag",
+            "first paragraph\n\nsecond paragraph after a blank line"
+        )
+    );
+    assert_eq!(
+        records[3],
+        record(
+            "01-15 10:23:46.500",
+            42,
+            42,
+            "debug",
+            "binary",
+            "bytes \u{fffd}\u{fffd} end"
+        )
+    );
+    assert_eq!(records[4].priority, "verbose");
+    assert!(records[4].message.ends_with(&"x".repeat(200)));
+    assert!(!records[4].message.contains('\n'));
+
+    assert_eq!(
+        serde_json::to_string(&records[0]).unwrap(),
+        r#"{"timestamp":"01-15 10:23:45.123","pid":1234,"tid":5678,"priority":"info","tag":"RustStdoutStderr","message":"hello from rust"}"#
+    );
+}
+
+#[test]
+fn logcat_malformed_lines() {
+    let mut stream = LogcatStream::new();
+    // Garbage before the first header is dropped
+    assert_eq!(stream.push_line(b"\x00\x01garbage"), None);
+    assert_eq!(stream.push_line(b"[ not a header ]"), None);
+    assert_eq!(stream.finish(), None);
+
+    // Malformed headers are part of the message
+    assert_eq!(
+        stream.push_line(b"[ 01-15 10:23:45.123  1234: 5678 I/tag ]"),
+        None
+    );
+    assert_eq!(
+        stream.push_line(b"[ 01-15 10:23:45.123  x: 5678 I/tag ]"),
+        None
+    );
+    assert_eq!(
+        stream.push_line(b"[ 01-15 10:23:45.123  1: 2 Q/tag ]"),
+        None
+    );
+    assert_eq!(stream.push_line(b""), None);
+    let record = stream.finish().unwrap();
+    assert_eq!(
+        record.message,
+        "[ 01-15 10:23:45.123  x: 5678 I/tag ]\n[ 01-15 10:23:45.123  1: 2 Q/tag ]"
+    );
+    assert_eq!(stream.finish(), None);
+}
+
+#[test]
+fn app_lines() {
+    let pid = "1234";
+    assert!(is_app_line(
+        "10-18 12:00:00.123  1234  1250 I miniquad: started",
+        pid
+    ));
+    assert!(is_app_line(
+        "10-18 12:00:00.456  1234  1250 E SAPP    : panicked at src/main.rs:4:5",
+        pid
+    ));
+    assert!(is_app_line(
+        "10-18 12:00:01.000  1298  1298 E AndroidRuntime: FATAL EXCEPTION: main",
+        pid
+    ));
+    assert!(!is_app_line(
+        "10-18 12:00:01.000   567   890 I ActivityManager: Start proc 1234:rust.app",
+        pid
+    ));
+    assert!(!is_app_line("--------- beginning of main", pid));
+    assert!(!is_app_line("", pid));
+}
+
+#[test]
+fn logcat_filterspecs() {
+    assert!(filterspecs(&[], None).is_empty());
+    assert_eq!(filterspecs(&[], Some("w")), vec!["*:W"]);
+    assert_eq!(
+        filterspecs(&["SAPP".to_owned(), "miniquad".to_owned()], None),
+        vec!["SAPP:V", "miniquad:V", "*:S"]
+    );
+    assert_eq!(
+        filterspecs(&["SAPP".to_owned()], Some("E")),
+        vec!["SAPP:E", "*:S"]
+    );
+}
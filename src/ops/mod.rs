@@ -1,8 +1,44 @@
+mod adb_retry;
+mod bootstrap;
 mod build;
+mod cache;
+mod clean;
+mod compat;
+mod device;
+mod diff;
+mod emulator;
+mod external_assets;
+mod hot;
 mod install;
+mod interrupt;
+mod logcat;
+mod publish;
+mod release_check;
+mod restart;
 mod run;
+mod state;
+mod symbolicate;
+mod toolchain;
+mod uninstall;
 
+pub use self::bootstrap::bootstrap;
+pub use self::build::active_features;
 pub use self::build::build;
+pub use self::build::build_flavors;
 pub use self::build::BuildResult;
+pub use self::cache::clean as clean_cache;
+pub use self::clean::clean;
+pub use self::compat::compat;
+pub use self::device::{list_devices, list_users, use_device_abi};
+pub use self::diff::diff;
+pub use self::emulator::use_emulator;
+pub use self::hot::hot;
 pub use self::install::install;
+pub use self::interrupt::install_handler as install_interrupt_handler;
+pub use self::logcat::logcat;
+pub use self::publish::publish;
+pub use self::release_check::release_check;
+pub use self::restart::restart;
 pub use self::run::run;
+pub use self::toolchain::run_with_toolchain;
+pub use self::uninstall::uninstall;
@@ -0,0 +1,393 @@
+use crate::config::{AndroidConfig, PublishConfig};
+use crate::ops::build;
+use anyhow::format_err;
+use cargo::core::Workspace;
+use cargo::util::{CargoResult, GlobalContext};
+use clap::ArgMatches;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Number of times an upload is retried after the server answered with a 5xx status
+const MAX_RETRIES: u32 = 3;
+
+/// HTTP destination of the APKs
+trait Uploader {
+    /// Uploads the APK once and returns the HTTP status of the response
+    fn upload(&self, apk: &Path) -> CargoResult<u32>;
+    /// Waits before retrying an upload
+    fn sleep(&self, backoff: Duration);
+}
+
+/// The destination of the publish configuration, through curl
+struct HttpUploader<'a> {
+    gctx: &'a GlobalContext,
+    publish_config: &'a PublishConfig,
+}
+
+impl Uploader for HttpUploader<'_> {
+    fn upload(&self, apk: &Path) -> CargoResult<u32> {
+        upload(self.gctx, self.publish_config, apk)
+    }
+
+    fn sleep(&self, backoff: Duration) {
+        std::thread::sleep(backoff);
+    }
+}
+
+pub fn publish(
+    workspace: &Workspace,
+    config: &AndroidConfig,
+    options: &ArgMatches,
+) -> CargoResult<()> {
+    let publish_config = config.publish.as_ref().ok_or_else(|| {
+        format_err!("Nothing to publish to, add a `[package.metadata.android.publish]` table")
+    })?;
+    let dry_run = options.get_flag("dry-run");
+
+    // Publish either the given APK or all the APKs of the last build
+    let apks = match options.get_one::<String>("apk") {
+        Some(apk) => vec![workspace.gctx().cwd().join(apk)],
+        None => build::last_build_report(workspace, config)?
+            .apks
+            .into_iter()
+            .map(|apk| apk.path)
+            .collect(),
+    };
+    if apks.is_empty() {
+        return Err(format_err!("No APKs to publish."));
+    }
+
+    let uploader = HttpUploader {
+        gctx: workspace.gctx(),
+        publish_config,
+    };
+    publish_apks(
+        workspace.gctx(),
+        config,
+        publish_config,
+        &apks,
+        dry_run,
+        &uploader,
+    )
+}
+
+/// Copies or uploads the APKs to the destination of `publish_config`, or only prints what would
+/// be published with `dry_run`
+fn publish_apks(
+    gctx: &GlobalContext,
+    config: &AndroidConfig,
+    publish_config: &PublishConfig,
+    apks: &[PathBuf],
+    dry_run: bool,
+    uploader: &dyn Uploader,
+) -> CargoResult<()> {
+    for apk in apks {
+        if !apk.exists() {
+            return Err(format_err!(
+                "APK '{}' does not exist",
+                apk.to_string_lossy()
+            ));
+        }
+
+        match publish_config {
+            PublishConfig::Copy { path } => {
+                let directory = config.manifest_path.parent().unwrap().join(path);
+                let destination = directory.join(apk.file_name().unwrap());
+                if dry_run {
+                    gctx.shell().status(
+                        "Would copy",
+                        format!(
+                            "{} to {}",
+                            apk.to_string_lossy(),
+                            destination.to_string_lossy()
+                        ),
+                    )?;
+                    continue;
+                }
+
+                fs::create_dir_all(&directory)?;
+                fs::copy(apk, &destination)?;
+                gctx.shell()
+                    .status("Copied", destination.to_string_lossy())?;
+            }
+            PublishConfig::Http { url, .. } => {
+                if dry_run {
+                    gctx.shell().status(
+                        "Would upload",
+                        format!("{} to {}", apk.to_string_lossy(), url),
+                    )?;
+                    continue;
+                }
+
+                upload_with_retries(gctx, uploader, apk)?;
+                gctx.shell()
+                    .status("Uploaded", format!("{} to {}", apk.to_string_lossy(), url))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Uploads the APK, retrying with an exponential backoff while the server answers with 5xx
+fn upload_with_retries(
+    gctx: &GlobalContext,
+    uploader: &dyn Uploader,
+    apk: &Path,
+) -> CargoResult<()> {
+    let mut attempt = 0;
+    loop {
+        let status = uploader.upload(apk)?;
+        match status {
+            200..=299 => return Ok(()),
+            500..=599 if attempt < MAX_RETRIES => {
+                let backoff = Duration::from_secs(1 << attempt);
+                gctx.shell().warn(format!(
+                    "Upload failed with HTTP status {}, retrying in {}s",
+                    status,
+                    backoff.as_secs()
+                ))?;
+                uploader.sleep(backoff);
+                attempt += 1;
+            }
+            _ => {
+                return Err(format_err!(
+                    "Upload of '{}' failed with HTTP status {}",
+                    apk.to_string_lossy(),
+                    status
+                ))
+            }
+        }
+    }
+}
+
+/// Uploads the APK once and returns the HTTP status of the response
+#[cfg(feature = "publish-http")]
+fn upload(gctx: &GlobalContext, publish_config: &PublishConfig, apk: &Path) -> CargoResult<u32> {
+    use cargo::util::Progress;
+    use curl::easy::{Easy, Form, List, ReadError};
+    use std::io::Read;
+
+    let (url, method, token_env, form_field) = match publish_config {
+        PublishConfig::Http {
+            url,
+            method,
+            token_env,
+            form_field,
+        } => (url, method, token_env, form_field),
+        _ => unreachable!("Only HTTP destinations are uploaded"),
+    };
+
+    let mut easy = Easy::new();
+    easy.url(url)?;
+
+    let mut headers = List::new();
+    if let Some(token_env) = token_env {
+        let token = std::env::var(token_env).map_err(|_| {
+            format_err!(
+                "The `{}` environment variable holding the upload token is not set",
+                token_env
+            )
+        })?;
+        headers.append(&format!("Authorization: Bearer {}", token))?;
+    }
+    easy.http_headers(headers)?;
+
+    // PUT sends the APK as the request body, POST as a multipart form field
+    let mut file = fs::File::open(apk)?;
+    match method.as_deref().unwrap_or("POST") {
+        "PUT" => {
+            easy.upload(true)?;
+            easy.in_filesize(file.metadata()?.len())?;
+        }
+        "POST" => {
+            let mut form = Form::new();
+            form.part(form_field.as_deref().unwrap_or("file"))
+                .file(apk)
+                .add()?;
+            easy.httppost(form)?;
+        }
+        method => {
+            return Err(format_err!(
+                "Unsupported publish method `{}`, expected `PUT` or `POST`",
+                method
+            ))
+        }
+    }
+
+    let mut progress = Progress::new("Uploading", gctx);
+    let file_name = apk.file_name().unwrap().to_string_lossy().into_owned();
+    easy.progress(true)?;
+    // A read error aborts the transfer, instead of ending the body as if the file ended there
+    let mut read_error = None;
+    let performed = {
+        let mut transfer = easy.transfer();
+        transfer.read_function(|buf| {
+            file.read(buf).map_err(|err| {
+                read_error = Some(err);
+                ReadError::Abort
+            })
+        })?;
+        transfer.progress_function(|_, _, upload_total, upload_now| {
+            if upload_total > 0.0 {
+                drop(progress.tick(upload_now as usize, upload_total as usize, &file_name));
+            }
+            true
+        })?;
+        transfer.perform()
+    };
+    progress.clear();
+    if let Some(err) = read_error {
+        return Err(format_err!("Unable to read `{}`: {}", apk.display(), err));
+    }
+    performed?;
+
+    Ok(easy.response_code()?)
+}
+
+#[cfg(not(feature = "publish-http"))]
+fn upload(_: &GlobalContext, _: &PublishConfig, _: &Path) -> CargoResult<u32> {
+    Err(format_err!(
+        "cargo-quad-apk was built without HTTP support, rebuild it with the `publish-http` feature"
+    ))
+}
+
+/// Uploader answering with a status per upload, and recording the uploads and the waits
+#[cfg(test)]
+struct RecordingUploader {
+    statuses: std::cell::RefCell<Vec<u32>>,
+    calls: std::cell::RefCell<Vec<String>>,
+}
+
+#[cfg(test)]
+impl RecordingUploader {
+    fn new(statuses: &[u32]) -> RecordingUploader {
+        RecordingUploader {
+            statuses: std::cell::RefCell::new(statuses.iter().rev().cloned().collect()),
+            calls: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Uploader for RecordingUploader {
+    fn upload(&self, apk: &Path) -> CargoResult<u32> {
+        self.calls.borrow_mut().push(format!(
+            "upload {}",
+            apk.file_name().unwrap().to_string_lossy()
+        ));
+        Ok(self.statuses.borrow_mut().pop().expect("no more statuses"))
+    }
+
+    fn sleep(&self, backoff: Duration) {
+        self.calls
+            .borrow_mut()
+            .push(format!("sleep {}s", backoff.as_secs()));
+    }
+}
+
+#[test]
+fn upload_retries_server_errors() {
+    let gctx = GlobalContext::default().unwrap();
+    let apk = Path::new("app.apk");
+
+    // Retried with an exponential backoff until the server accepts it
+    let uploader = RecordingUploader::new(&[503, 500, 201]);
+    upload_with_retries(&gctx, &uploader, apk).unwrap();
+    assert_eq!(
+        uploader.calls.into_inner(),
+        vec![
+            "upload app.apk",
+            "sleep 1s",
+            "upload app.apk",
+            "sleep 2s",
+            "upload app.apk"
+        ]
+    );
+
+    // Given up after the last retry
+    let uploader = RecordingUploader::new(&[502, 502, 502, 502]);
+    let err = upload_with_retries(&gctx, &uploader, apk).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Upload of 'app.apk' failed with HTTP status 502"
+    );
+    assert_eq!(
+        uploader.calls.into_inner(),
+        vec![
+            "upload app.apk",
+            "sleep 1s",
+            "upload app.apk",
+            "sleep 2s",
+            "upload app.apk",
+            "sleep 4s",
+            "upload app.apk"
+        ]
+    );
+
+    // Other errors aren't retried
+    let uploader = RecordingUploader::new(&[403]);
+    let err = upload_with_retries(&gctx, &uploader, apk).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Upload of 'app.apk' failed with HTTP status 403"
+    );
+    assert_eq!(uploader.calls.into_inner(), vec!["upload app.apk"]);
+}
+
+#[test]
+fn publish_copies_and_dry_runs() {
+    let root = std::env::temp_dir().join(format!("cargo-quad-apk-publish-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let apk = root.join("target/app.apk");
+    fs::create_dir_all(apk.parent().unwrap()).unwrap();
+    fs::write(&apk, "apk").unwrap();
+
+    let gctx = GlobalContext::default().unwrap();
+    let mut config = crate::config::from_metadata("");
+    config.manifest_path = root.join("Cargo.toml");
+    let copy = PublishConfig::Copy {
+        path: PathBuf::from("releases"),
+    };
+    let http = PublishConfig::Http {
+        url: "https://example.com/apks".to_owned(),
+        method: None,
+        token_env: None,
+        form_field: None,
+    };
+    let apks = vec![apk.clone()];
+    let published = root.join("releases/app.apk");
+
+    // Nothing is copied nor uploaded by a dry run
+    let uploader = RecordingUploader::new(&[]);
+    publish_apks(&gctx, &config, &copy, &apks, true, &uploader).unwrap();
+    publish_apks(&gctx, &config, &http, &apks, true, &uploader).unwrap();
+    assert!(!published.exists());
+    assert!(uploader.calls.borrow().is_empty());
+
+    // Copied to the directory relative to the package, created when missing
+    publish_apks(&gctx, &config, &copy, &apks, false, &uploader).unwrap();
+    assert_eq!(fs::read_to_string(&published).unwrap(), "apk");
+
+    let uploader = RecordingUploader::new(&[200]);
+    publish_apks(&gctx, &config, &http, &apks, false, &uploader).unwrap();
+    assert_eq!(uploader.calls.into_inner(), vec!["upload app.apk"]);
+
+    let err = publish_apks(
+        &gctx,
+        &config,
+        &copy,
+        &[root.join("missing.apk")],
+        false,
+        &RecordingUploader::new(&[]),
+    )
+    .unwrap_err();
+    assert!(
+        err.to_string().ends_with("missing.apk' does not exist"),
+        "{}",
+        err
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,660 @@
+//! Checklist of the release APKs before they are uploaded to Play.
+//!
+//! The facts are gathered once per APK, from the report of the last release build, the APK
+//! itself and its signature, then every rule of `RULES` is evaluated on them. A new policy of
+//! Play is a new entry of the table.
+
+use super::build;
+use super::diff::axml;
+use crate::config::AndroidConfig;
+use anyhow::format_err;
+use cargo::core::Workspace;
+use cargo::util::CargoResult;
+use clap::ArgMatches;
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Lowest target SDK accepted by Play for new apps and updates, since August 31, 2025
+const PLAY_MIN_TARGET_SDK: u32 = 35;
+
+/// Largest APK accepted by Play
+const PLAY_MAX_APK_SIZE: u64 = 100 * 1024 * 1024;
+
+/// 64-bit ABI which Play requires alongside each 32-bit one
+const ABI_PAIRS: &[(&str, &str)] = &[("armeabi-v7a", "arm64-v8a"), ("x86", "x86_64")];
+
+/// What the rules know of an APK
+#[derive(Debug, Clone, Default)]
+pub struct ApkFacts {
+    /// Whether the report of the last release build lists the APK, or one of the same name
+    pub release_build: bool,
+    /// Distinguished names of the signer certificates, empty for an unsigned APK
+    pub signers: Vec<String>,
+    pub version_code: Option<i64>,
+    pub target_sdk_version: Option<u32>,
+    pub debuggable: bool,
+    pub test_only: bool,
+    /// ABIs of the native libraries
+    pub abis: BTreeSet<String>,
+    pub size: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct CheckOptions {
+    /// versionCode of the last published release, from `--baseline-version-code`
+    pub baseline_version_code: Option<i64>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Pass(String),
+    Fail(String),
+    /// Not enough information to evaluate the rule
+    Skip(String),
+}
+
+pub struct Rule {
+    pub name: &'static str,
+    pub check: fn(&ApkFacts, &CheckOptions) -> Outcome,
+}
+
+pub const RULES: &[Rule] = &[
+    Rule {
+        name: "release-profile",
+        check: release_profile,
+    },
+    Rule {
+        name: "release-key",
+        check: release_key,
+    },
+    Rule {
+        name: "version-code",
+        check: version_code,
+    },
+    Rule {
+        name: "target-sdk",
+        check: target_sdk,
+    },
+    Rule {
+        name: "not-debuggable",
+        check: not_debuggable,
+    },
+    Rule {
+        name: "not-test-only",
+        check: not_test_only,
+    },
+    Rule {
+        name: "64-bit-abis",
+        check: abis_64_bit,
+    },
+    Rule {
+        name: "apk-size",
+        check: apk_size,
+    },
+];
+
+fn release_profile(facts: &ApkFacts, _: &CheckOptions) -> Outcome {
+    if facts.release_build {
+        Outcome::Pass("built with the release profile".to_owned())
+    } else {
+        Outcome::Fail(
+            "not listed in the report of the last release build, build it with `--release`"
+                .to_owned(),
+        )
+    }
+}
+
+fn release_key(facts: &ApkFacts, _: &CheckOptions) -> Outcome {
+    if facts.signers.is_empty() {
+        Outcome::Fail("the APK is not signed".to_owned())
+    } else if let Some(signer) = facts
+        .signers
+        .iter()
+        .find(|signer| signer.contains("CN=Android Debug"))
+    {
+        Outcome::Fail(format!(
+            "signed with the debug key ({}), configure the release key in \
+             `[package.metadata.android.signing]`",
+            signer
+        ))
+    } else {
+        Outcome::Pass(format!("signed by {}", facts.signers.join("; ")))
+    }
+}
+
+fn version_code(facts: &ApkFacts, options: &CheckOptions) -> Outcome {
+    let (version_code, baseline) = match (facts.version_code, options.baseline_version_code) {
+        (None, _) => return Outcome::Fail("the manifest has no versionCode".to_owned()),
+        (Some(version_code), None) => {
+            return Outcome::Skip(format!(
+                "versionCode is {}, give `--baseline-version-code` to compare it with the last \
+                 published one",
+                version_code
+            ))
+        }
+        (Some(version_code), Some(baseline)) => (version_code, baseline),
+    };
+    if version_code > baseline {
+        Outcome::Pass(format!(
+            "versionCode {} is above the published {}",
+            version_code, baseline
+        ))
+    } else {
+        Outcome::Fail(format!(
+            "versionCode {} is not above the published {}, bump `version_code`",
+            version_code, baseline
+        ))
+    }
+}
+
+fn target_sdk(facts: &ApkFacts, _: &CheckOptions) -> Outcome {
+    match facts.target_sdk_version {
+        Some(target) if target >= PLAY_MIN_TARGET_SDK => Outcome::Pass(format!(
+            "targets API {} (Play requires {})",
+            target, PLAY_MIN_TARGET_SDK
+        )),
+        Some(target) => Outcome::Fail(format!(
+            "targets API {}, Play requires {} or higher, raise `target_sdk_version`",
+            target, PLAY_MIN_TARGET_SDK
+        )),
+        None => Outcome::Fail(format!(
+            "the manifest has no targetSdkVersion, Play requires {} or higher",
+            PLAY_MIN_TARGET_SDK
+        )),
+    }
+}
+
+fn not_debuggable(facts: &ApkFacts, _: &CheckOptions) -> Outcome {
+    if facts.debuggable {
+        Outcome::Fail("android:debuggable is set, Play rejects debuggable APKs".to_owned())
+    } else {
+        Outcome::Pass("not debuggable".to_owned())
+    }
+}
+
+fn not_test_only(facts: &ApkFacts, _: &CheckOptions) -> Outcome {
+    if facts.test_only {
+        Outcome::Fail("android:testOnly is set, unset `test_only`".to_owned())
+    } else {
+        Outcome::Pass("not test only".to_owned())
+    }
+}
+
+fn abis_64_bit(facts: &ApkFacts, _: &CheckOptions) -> Outcome {
+    if facts.abis.is_empty() {
+        return Outcome::Pass("no native libraries".to_owned());
+    }
+    let missing = ABI_PAIRS
+        .iter()
+        .filter(|(abi_32, abi_64)| facts.abis.contains(*abi_32) && !facts.abis.contains(*abi_64))
+        .map(|(abi_32, abi_64)| format!("{} (for {})", abi_64, abi_32))
+        .collect::<Vec<_>>();
+    let abis = facts.abis.iter().cloned().collect::<Vec<_>>().join(", ");
+    if missing.is_empty() {
+        Outcome::Pass(format!("native libraries for {}", abis))
+    } else {
+        Outcome::Fail(format!(
+            "native libraries for {} lack the 64-bit {}, add them to `build_targets`",
+            abis,
+            missing.join(", ")
+        ))
+    }
+}
+
+fn apk_size(facts: &ApkFacts, _: &CheckOptions) -> Outcome {
+    let mib = |size: u64| size as f64 / (1024.0 * 1024.0);
+    if facts.size <= PLAY_MAX_APK_SIZE {
+        Outcome::Pass(format!(
+            "{:.1} MiB, within the {} MiB limit",
+            mib(facts.size),
+            mib(PLAY_MAX_APK_SIZE)
+        ))
+    } else {
+        Outcome::Fail(format!(
+            "{:.1} MiB, above the {} MiB limit of Play, move assets to asset packs or a download",
+            mib(facts.size),
+            mib(PLAY_MAX_APK_SIZE)
+        ))
+    }
+}
+
+/// Evaluates every rule, in the order of the table
+pub fn evaluate(facts: &ApkFacts, options: &CheckOptions) -> Vec<(&'static str, Outcome)> {
+    RULES
+        .iter()
+        .map(|rule| (rule.name, (rule.check)(facts, options)))
+        .collect()
+}
+
+/// Lists the outcome of each rule, with its explanation
+fn render_outcomes(outcomes: &[(&str, Outcome)]) -> String {
+    let width = outcomes
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0);
+    outcomes
+        .iter()
+        .map(|(name, outcome)| {
+            let (status, explanation) = match outcome {
+                Outcome::Pass(explanation) => ("pass", explanation),
+                Outcome::Fail(explanation) => ("FAIL", explanation),
+                Outcome::Skip(explanation) => ("skip", explanation),
+            };
+            format!(
+                "  {}  {:width$}  {}\n",
+                status,
+                name,
+                explanation,
+                width = width
+            )
+        })
+        .collect()
+}
+
+impl ApkFacts {
+    /// Reads the facts stored in the APK itself: its manifest, native libraries and size
+    fn read<R: Read + Seek>(mut reader: R) -> CargoResult<ApkFacts> {
+        let size = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+        let mut archive = zip::ZipArchive::new(reader)?;
+
+        let abis = archive
+            .file_names()
+            .filter_map(|name| {
+                let mut parts = name.split('/');
+                match (parts.next(), parts.next(), parts.next()) {
+                    (Some("lib"), Some(abi), Some(file)) if !file.is_empty() => {
+                        Some(abi.to_owned())
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+
+        let mut manifest_data = vec![];
+        archive
+            .by_name("AndroidManifest.xml")?
+            .read_to_end(&mut manifest_data)?;
+        let mut facts = ApkFacts {
+            abis,
+            size,
+            ..ApkFacts::default()
+        };
+        for element in axml::parse(&manifest_data)? {
+            match element.name.as_str() {
+                "manifest" => {
+                    facts.version_code = element
+                        .attribute("versionCode")
+                        .and_then(|v| v.parse().ok())
+                }
+                "uses-sdk" => {
+                    facts.target_sdk_version = element
+                        .attribute("targetSdkVersion")
+                        .and_then(|v| v.parse().ok())
+                }
+                "application" => {
+                    facts.debuggable = element.attribute("debuggable") == Some("true");
+                    facts.test_only = element.attribute("testOnly") == Some("true");
+                }
+                _ => {}
+            }
+        }
+        Ok(facts)
+    }
+}
+
+/// Reads the distinguished names of the signers from `apksigner verify --print-certs`
+fn parse_signers(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| line.split_once("certificate DN: "))
+        .map(|(_, dn)| dn.trim().to_owned())
+        .collect()
+}
+
+fn signers(config: &AndroidConfig, apk: &Path) -> CargoResult<Vec<String>> {
+    let output = build::apksigner(config)?
+        .arg("verify")
+        .arg("--print-certs")
+        .arg(apk)
+        .output()?;
+    // Unsigned APKs fail the verification
+    if !output.status.success() {
+        return Ok(vec![]);
+    }
+    Ok(parse_signers(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Reads `--baseline-version-code`, either a number or a file holding it
+fn baseline_version_code(workspace: &Workspace, options: &ArgMatches) -> CargoResult<Option<i64>> {
+    let value = match options.get_one::<String>("baseline-version-code") {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let (source, contents) = if value.chars().all(|c| c.is_ascii_digit()) {
+        (value.clone(), value.clone())
+    } else {
+        let path = workspace.gctx().cwd().join(value);
+        let contents = fs::read_to_string(&path)
+            .map_err(|err| format_err!("Unable to read '{}': {}", path.display(), err))?;
+        (path.display().to_string(), contents)
+    };
+    contents.trim().parse().map(Some).map_err(|_| {
+        format_err!(
+            "Invalid `--baseline-version-code` `{}`, expected a versionCode or a file holding one",
+            source
+        )
+    })
+}
+
+pub fn release_check(
+    workspace: &Workspace,
+    config: &AndroidConfig,
+    options: &ArgMatches,
+) -> CargoResult<()> {
+    let check_options = CheckOptions {
+        baseline_version_code: baseline_version_code(workspace, options)?,
+    };
+    let report = build::last_build_report(workspace, config);
+    let apks: Vec<PathBuf> = match options.get_one::<String>("apk") {
+        Some(apk) => vec![workspace.gctx().cwd().join(apk)],
+        None => report
+            .as_ref()
+            .map_err(|_| {
+                format_err!("No release build found, run `cargo quad-apk build --release` first")
+            })?
+            .apks
+            .iter()
+            .map(|apk| apk.path.clone())
+            .collect(),
+    };
+    if apks.is_empty() {
+        return Err(format_err!("No APKs to check."));
+    }
+
+    let mut failures = 0;
+    let mut checks = 0;
+    for apk in &apks {
+        let file = File::open(apk)
+            .map_err(|err| format_err!("Unable to open '{}': {}", apk.display(), err))?;
+        // The APKs of the report are matched on their whole path, as the APKs of several
+        // profiles or flavors share their file names
+        let canonical_apk = fs::canonicalize(apk)?;
+        let facts = ApkFacts {
+            release_build: report.as_ref().map_or(false, |report| {
+                report.apks.iter().any(|built| {
+                    fs::canonicalize(&built.path).map_or(false, |built| built == canonical_apk)
+                })
+            }),
+            signers: signers(config, apk)?,
+            ..ApkFacts::read(file)?
+        };
+        let outcomes = evaluate(&facts, &check_options);
+        checks += outcomes.len();
+        failures += outcomes
+            .iter()
+            .filter(|(_, outcome)| matches!(outcome, Outcome::Fail(_)))
+            .count();
+
+        workspace.gctx().shell().status("Checking", apk.display())?;
+        drop(write!(
+            workspace.gctx().shell().out(),
+            "{}",
+            render_outcomes(&outcomes)
+        ));
+    }
+
+    if failures > 0 {
+        return Err(format_err!(
+            "{} of {} release checks failed",
+            failures,
+            checks
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+fn passing_facts() -> ApkFacts {
+    ApkFacts {
+        release_build: true,
+        signers: vec!["CN=Jane Doe, O=Example, C=US".to_owned()],
+        version_code: Some(12),
+        target_sdk_version: Some(PLAY_MIN_TARGET_SDK),
+        debuggable: false,
+        test_only: false,
+        abis: ["arm64-v8a", "armeabi-v7a"]
+            .iter()
+            .map(|abi| abi.to_string())
+            .collect(),
+        size: 20 * 1024 * 1024,
+    }
+}
+
+#[cfg(test)]
+fn check(facts: &ApkFacts, rule: &str) -> Outcome {
+    let options = CheckOptions {
+        baseline_version_code: Some(11),
+    };
+    let rule = RULES.iter().find(|r| r.name == rule).unwrap();
+    (rule.check)(facts, &options)
+}
+
+#[cfg(test)]
+fn fails(outcome: Outcome) -> bool {
+    matches!(outcome, Outcome::Fail(_))
+}
+
+#[test]
+fn release_profile_rule() {
+    let facts = passing_facts();
+    assert!(!fails(check(&facts, "release-profile")));
+    let debug = ApkFacts {
+        release_build: false,
+        ..facts
+    };
+    assert!(fails(check(&debug, "release-profile")));
+}
+
+#[test]
+fn release_key_rule() {
+    let facts = passing_facts();
+    assert_eq!(
+        check(&facts, "release-key"),
+        Outcome::Pass("signed by CN=Jane Doe, O=Example, C=US".to_owned())
+    );
+    let debug_key = ApkFacts {
+        signers: vec!["C=US, O=Android, CN=Android Debug".to_owned()],
+        ..facts.clone()
+    };
+    assert!(fails(check(&debug_key, "release-key")));
+    let unsigned = ApkFacts {
+        signers: vec![],
+        ..facts
+    };
+    assert_eq!(
+        check(&unsigned, "release-key"),
+        Outcome::Fail("the APK is not signed".to_owned())
+    );
+}
+
+#[test]
+fn version_code_rule() {
+    let facts = passing_facts();
+    assert!(!fails(check(&facts, "version-code")));
+    let same = ApkFacts {
+        version_code: Some(11),
+        ..facts.clone()
+    };
+    assert_eq!(
+        check(&same, "version-code"),
+        Outcome::Fail(
+            "versionCode 11 is not above the published 11, bump `version_code`".to_owned()
+        )
+    );
+    // Without a baseline there is nothing to compare with
+    assert!(matches!(
+        version_code(&facts, &CheckOptions::default()),
+        Outcome::Skip(_)
+    ));
+    let missing = ApkFacts {
+        version_code: None,
+        ..facts
+    };
+    assert!(fails(check(&missing, "version-code")));
+}
+
+#[test]
+fn target_sdk_rule() {
+    let facts = passing_facts();
+    assert!(!fails(check(&facts, "target-sdk")));
+    let old = ApkFacts {
+        target_sdk_version: Some(PLAY_MIN_TARGET_SDK - 1),
+        ..facts.clone()
+    };
+    assert!(fails(check(&old, "target-sdk")));
+    let missing = ApkFacts {
+        target_sdk_version: None,
+        ..facts
+    };
+    assert!(fails(check(&missing, "target-sdk")));
+}
+
+#[test]
+fn not_debuggable_rule() {
+    let facts = passing_facts();
+    assert!(!fails(check(&facts, "not-debuggable")));
+    let debuggable = ApkFacts {
+        debuggable: true,
+        ..facts
+    };
+    assert!(fails(check(&debuggable, "not-debuggable")));
+}
+
+#[test]
+fn not_test_only_rule() {
+    let facts = passing_facts();
+    assert!(!fails(check(&facts, "not-test-only")));
+    let test_only = ApkFacts {
+        test_only: true,
+        ..facts
+    };
+    assert!(fails(check(&test_only, "not-test-only")));
+}
+
+#[test]
+fn abis_64_bit_rule() {
+    let with_abis = |abis: &[&str]| ApkFacts {
+        abis: abis.iter().map(|abi| abi.to_string()).collect(),
+        ..passing_facts()
+    };
+    assert!(!fails(check(&with_abis(&[]), "64-bit-abis")));
+    assert!(!fails(check(&with_abis(&["arm64-v8a"]), "64-bit-abis")));
+    assert!(!fails(check(
+        &with_abis(&["armeabi-v7a", "arm64-v8a", "x86", "x86_64"]),
+        "64-bit-abis"
+    )));
+    assert_eq!(
+        check(&with_abis(&["armeabi-v7a", "x86", "x86_64"]), "64-bit-abis"),
+        Outcome::Fail(
+            "native libraries for armeabi-v7a, x86, x86_64 lack the 64-bit arm64-v8a (for \
+             armeabi-v7a), add them to `build_targets`"
+                .to_owned()
+        )
+    );
+}
+
+#[test]
+fn apk_size_rule() {
+    let facts = passing_facts();
+    assert_eq!(
+        check(&facts, "apk-size"),
+        Outcome::Pass("20.0 MiB, within the 100 MiB limit".to_owned())
+    );
+    let large = ApkFacts {
+        size: PLAY_MAX_APK_SIZE + 1,
+        ..facts
+    };
+    assert!(fails(check(&large, "apk-size")));
+}
+
+#[test]
+fn release_check_rules() {
+    // Every rule passes on a publishable APK, and has a test above
+    let outcomes = evaluate(
+        &passing_facts(),
+        &CheckOptions {
+            baseline_version_code: Some(11),
+        },
+    );
+    assert_eq!(outcomes.len(), RULES.len());
+    assert!(outcomes
+        .iter()
+        .all(|(_, outcome)| matches!(outcome, Outcome::Pass(_))));
+
+    let rendered = render_outcomes(&[
+        (
+            "release-key",
+            Outcome::Pass("signed by CN=Jane Doe".to_owned()),
+        ),
+        ("version-code", Outcome::Skip("no baseline".to_owned())),
+        (
+            "not-test-only",
+            Outcome::Fail("android:testOnly is set".to_owned()),
+        ),
+    ]);
+    assert_eq!(
+        rendered,
+        "  pass  release-key    signed by CN=Jane Doe\n  \
+         skip  version-code   no baseline\n  \
+         FAIL  not-test-only  android:testOnly is set\n"
+    );
+}
+
+#[test]
+fn apk_facts() {
+    use axml::TestValue::{Int, Str};
+    use std::io::{Cursor, Write};
+    use zip::write::{FileOptions, ZipWriter};
+
+    let mut zip = ZipWriter::new(Cursor::new(vec![]));
+    zip.start_file("AndroidManifest.xml", FileOptions::default())
+        .unwrap();
+    zip.write_all(&axml::encode(&[
+        ("manifest", &[("versionCode", Int(7))]),
+        ("uses-sdk", &[("targetSdkVersion", Int(34))]),
+        ("application", &[("debuggable", Str("true"))]),
+    ]))
+    .unwrap();
+    for name in &[
+        "lib/arm64-v8a/libapp.so",
+        "lib/x86/libapp.so",
+        "classes.dex",
+    ] {
+        zip.start_file(*name, FileOptions::default()).unwrap();
+        zip.write_all(b"data").unwrap();
+    }
+    let apk = zip.finish().unwrap().into_inner();
+
+    let facts = ApkFacts::read(Cursor::new(&apk)).unwrap();
+    assert_eq!(facts.version_code, Some(7));
+    assert_eq!(facts.target_sdk_version, Some(34));
+    assert!(facts.debuggable);
+    assert!(!facts.test_only);
+    assert_eq!(
+        facts.abis.into_iter().collect::<Vec<_>>(),
+        vec!["arm64-v8a", "x86"]
+    );
+    assert_eq!(facts.size, apk.len() as u64);
+
+    assert_eq!(
+        parse_signers(
+            "Signer #1 certificate DN: CN=Android Debug, O=Android, C=US\n\
+             Signer #1 certificate SHA-256 digest: 1f2e\n"
+        ),
+        vec!["CN=Android Debug, O=Android, C=US"]
+    );
+}
@@ -0,0 +1,102 @@
+//! `restart`: stops and starts the installed app again without building, for hot-reload testing.
+
+use crate::config::AndroidConfig;
+use crate::error::{FailureKind, ResultExt};
+use crate::ops::{device, logcat, run};
+use anyhow::format_err;
+use cargo::core::{TargetKind, Workspace};
+use cargo::util::CargoResult;
+use clap::ArgMatches;
+
+pub fn restart(
+    workspace: &Workspace,
+    config: &AndroidConfig,
+    options: &ArgMatches,
+) -> CargoResult<()> {
+    let target = app_target(workspace, config, options)?;
+    let target_config = config.resolve(target)?;
+    let application_id = target_config.application_id();
+
+    device::check_device_selected(config)?;
+    let user = device::selected_user(options)?;
+    check_installed(config, user, &application_id)?;
+
+    run::stop_app(config, options, &target_config).failure_kind(FailureKind::Device)?;
+    if options.get_flag("clear-data") {
+        let mut clear_cmd = config.adb_command()?;
+        clear_cmd.arg("shell").arg("pm").arg("clear");
+        if let Some(user) = user {
+            clear_cmd.arg("--user").arg(user.to_string());
+        }
+        clear_cmd
+            .arg(&application_id)
+            .exec_with_output()
+            .failure_kind(FailureKind::Device)?;
+        workspace
+            .gctx()
+            .shell()
+            .status("Cleared", format!("the data of `{}`", application_id))?;
+    }
+    let started_at = run::start_app(workspace, config, options, &target_config, &[])
+        .failure_kind(FailureKind::Device)?;
+
+    if options.get_flag("logcat") {
+        logcat::app_logcat(workspace, config, &application_id, started_at, None)?;
+    }
+    Ok(())
+}
+
+/// Returns the target of `--bin` or `--example`, or the only binary of the package, whose app
+/// is acted on without building it
+pub(super) fn app_target(
+    workspace: &Workspace,
+    config: &AndroidConfig,
+    options: &ArgMatches,
+) -> CargoResult<(TargetKind, String)> {
+    if let Some(bin) = options.get_one::<String>("bin") {
+        return Ok((TargetKind::Bin, bin.clone()));
+    }
+    if let Some(example) = options.get_one::<String>("example") {
+        return Ok((TargetKind::ExampleBin, example.clone()));
+    }
+    let package = workspace
+        .members()
+        .find(|package| *package.name() == config.cargo_package_name)
+        .ok_or_else(|| format_err!("Unable to find package `{}`", config.cargo_package_name))?;
+    let mut bins = package.targets().iter().filter(|target| target.is_bin());
+    match (bins.next(), bins.next()) {
+        (Some(bin), None) => Ok((TargetKind::Bin, bin.name().to_owned())),
+        (None, _) => Err(format_err!("The package has no binaries")),
+        (Some(_), Some(_)) => Err(format_err!(
+            "The package has several binaries, specify which app to use with '--bin' or \
+             '--example'"
+        )),
+    }
+}
+
+/// Fails when the app isn't installed for the user, as `am start` would only print an error
+pub(super) fn check_installed(
+    config: &AndroidConfig,
+    user: Option<u32>,
+    application_id: &str,
+) -> CargoResult<()> {
+    let mut path_cmd = config.adb_command()?;
+    path_cmd.arg("shell").arg("pm").arg("path");
+    if let Some(user) = user {
+        path_cmd.arg("--user").arg(user.to_string());
+    }
+    // `pm path` exits with an error when the package isn't installed
+    let installed = path_cmd
+        .arg(application_id)
+        .output()
+        .map_err(|err| FailureKind::Device.mark(err.into()))?
+        .stdout
+        .starts_with(b"package:");
+    if !installed {
+        return Err(FailureKind::Device.mark(format_err!(
+            "`{}` is not installed on the device, install it with `cargo quad-apk install`",
+            application_id
+        )));
+    }
+    Ok(())
+}
@@ -1,16 +1,31 @@
-use crate::config::AndroidConfig;
-use crate::ops::install;
+use crate::config::{AndroidConfig, AndroidTargetConfig};
+use crate::error::{FailureKind, ResultExt};
+use crate::ops::adb_retry::AdbRetry;
+use crate::ops::external_assets::shell_quote;
+use crate::ops::state::DeviceState;
+use crate::ops::symbolicate::Symbolicator;
+use crate::ops::{device, install, logcat};
 use anyhow::format_err;
 use cargo::core::{TargetKind, Workspace};
 use cargo::util::CargoResult;
 use cargo_util::ProcessBuilder;
 use clap::ArgMatches;
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
 
 pub fn run(workspace: &Workspace, config: &AndroidConfig, options: &ArgMatches) -> CargoResult<()> {
+    if options.get_flag("examples-sequence") {
+        return run_examples_sequence(workspace, config, options);
+    }
+
     let build_result = install::install(workspace, config, options)?;
 
     // Determine the target that should be executed
-    let requested_target = if options.get_flag("example") && options.get_flag("bin") {
+    let requested_target = if options.get_one::<String>("example").is_some()
+        && options.get_one::<String>("bin").is_some()
+    {
         return Err(format_err!(
             "Specifying both example and bin targets is not supported"
         ));
@@ -36,27 +51,261 @@ pub fn run(workspace: &Workspace, config: &AndroidConfig, options: &ArgMatches)
     };
 
     // Determine package name
-    let package_name = config.resolve(requested_target)?.package_name;
+    let target_config = config.resolve(requested_target)?;
+    let started_at = start_app(
+        workspace,
+        config,
+        options,
+        &target_config,
+        &app_args(options),
+    )
+    .failure_kind(FailureKind::Device)?;
+    if !options.get_flag("no-logcat") {
+        let symbolicator = if options.get_flag("symbolicate") {
+            Some(Symbolicator::new(workspace, config)?)
+        } else {
+            None
+        };
+        logcat::app_logcat(
+            workspace,
+            config,
+            &target_config.application_id(),
+            started_at,
+            symbolicator,
+        )?;
+    }
+    Ok(())
+}
+
+/// Returns the arguments given to the app after `--`
+fn app_args(options: &ArgMatches) -> Vec<String> {
+    options
+        .get_many::<String>("args")
+        .unwrap_or_default()
+        .cloned()
+        .collect()
+}
 
-    //
-    // Start the APK using adb
-    //
-    let adb = config.sdk_path.join("platform-tools/adb");
+/// Starts the main activity of the app with adb, with `app_args` as the `args` string array
+/// extra of the intent. Returns the device time it was started at.
+pub(super) fn start_app(
+    workspace: &Workspace,
+    config: &AndroidConfig,
+    options: &ArgMatches,
+    target_config: &AndroidTargetConfig,
+    app_args: &[String],
+) -> CargoResult<u64> {
+    let adb = config.adb_command()?;
 
     // Found it by doing this :
     //     adb shell "cmd package resolve-activity --brief com.author.myproject | tail -n 1"
-    let activity_path = format!("{}/.MainActivity", package_name.replace("-", "_"),);
+    let activity_path = format!(
+        "{}/{}",
+        target_config.application_id(),
+        target_config.main_activity_name()
+    );
+
+    // Remembered for `cargo quad-apk logcat --since-run`
+    let started_at = device::device_time(config)?;
+    let mut state = DeviceState::load(workspace);
+    state.last_run_started_at = Some(started_at);
+    state.save(workspace)?;
 
     drop(writeln!(workspace.gctx().shell().err(), "Running apk"));
-    ProcessBuilder::new(&adb)
-        .arg("shell")
-        .arg("am")
-        .arg("start")
+    let mut start_cmd = adb.clone();
+    start_cmd.arg("shell").arg("am").arg("start");
+    // Otherwise the app opens in the profile of the current user
+    if let Some(user) = device::selected_user(options)? {
+        start_cmd.arg("--user").arg(user.to_string());
+    }
+    start_cmd
         .arg("-a")
         .arg("android.intent.action.MAIN")
         .arg("-n")
-        .arg(&activity_path)
-        .exec()?;
+        .arg(&activity_path);
+    if !app_args.is_empty() {
+        start_cmd
+            .arg("--esa")
+            .arg("args")
+            .arg(shell_quote(&string_array_extra(app_args)));
+    }
+    start_cmd.exec()?;
+
+    Ok(started_at)
+}
+
+/// Joins the values of a string array extra of `am start`, which separates them by commas
+fn string_array_extra(values: &[String]) -> String {
+    values
+        .iter()
+        .map(|value| value.replace(',', "\\,"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Builds every example, then installs, starts and stops them one after the other, for demo
+/// reels. The examples have their own application id, so they stay installed side by side.
+fn run_examples_sequence(
+    workspace: &Workspace,
+    config: &AndroidConfig,
+    options: &ArgMatches,
+) -> CargoResult<()> {
+    if options.get_one::<String>("bin").is_some() || options.get_one::<String>("example").is_some()
+    {
+        return Err(format_err!(
+            "`--examples-sequence` runs every example, it can't be combined with `--bin` or \
+             `--example`"
+        ));
+    }
+    let each_duration = options
+        .get_one::<String>("each-duration")
+        .map(|secs| {
+            secs.parse().map(Duration::from_secs).map_err(|_| {
+                format_err!("Invalid duration `{}`, expected a number of seconds", secs)
+            })
+        })
+        .transpose()?
+        .unwrap_or(Duration::from_secs(10));
+    let screenshots_dir = options
+        .get_one::<String>("screenshots")
+        .map(|dir| workspace.gctx().cwd().join(dir));
+    if let Some(dir) = &screenshots_dir {
+        fs::create_dir_all(dir)?;
+    }
+    let fail_fast = options.get_flag("fail-fast");
+    let all_splits = options.get_flag("all-splits");
+
+    let build_result = install::build_for_install(workspace, config, options)?;
+    // Sorted by name, as the map is
+    let examples = build_result
+        .target_to_apk_map
+        .iter()
+        .filter(|((kind, _), _)| *kind == TargetKind::ExampleBin)
+        .collect::<Vec<_>>();
+    if examples.is_empty() {
+        return Err(format_err!("The package has no examples to run"));
+    }
+    let mut installer =
+        install::Installer::new(workspace, config, options).failure_kind(FailureKind::Device)?;
+    let retry = AdbRetry::new(workspace.gctx(), &config.adb()?, options)?;
+    install::reverse_ports(workspace, config, options)?;
+
+    let mut results = vec![];
+    for (target, _) in examples {
+        workspace.gctx().shell().status("Example", &target.1)?;
+        let result = (|| {
+            let apks = build_result.apks_to_install(target, installer.abi, all_splits);
+            for apk_path in apks {
+                installer.install(target, apk_path)?;
+            }
+            let target_config = config.resolve(target.clone())?;
+            start_app(
+                workspace,
+                config,
+                options,
+                &target_config,
+                &app_args(options),
+            )?;
+            thread::sleep(each_duration);
+            if let Some(dir) = &screenshots_dir {
+                let path = dir.join(format!("{}.png", target.1));
+                retry.run("adb screencap", || screenshot(config, &path))?;
+            }
+            stop_app(config, options, &target_config)
+        })()
+        .failure_kind(FailureKind::Device);
+        if let Err(err) = &result {
+            workspace
+                .gctx()
+                .shell()
+                .warn(format!("example '{}' failed: {:#}", target.1, err))?;
+            if fail_fast {
+                return result;
+            }
+        }
+        results.push((target.1.as_str(), result.is_ok()));
+    }
 
+    drop(writeln!(
+        workspace.gctx().shell().err(),
+        "{}",
+        render_sequence_summary(&results)
+    ));
+    let failed = results.iter().filter(|(_, launched)| !launched).count();
+    if failed > 0 {
+        return Err(format_err!(
+            "{} of {} examples failed",
+            failed,
+            results.len()
+        ));
+    }
     Ok(())
 }
+
+/// Saves a screenshot of the device as a PNG
+fn screenshot(config: &AndroidConfig, path: &Path) -> CargoResult<()> {
+    let output = config
+        .adb_command()?
+        .arg("exec-out")
+        .arg("screencap")
+        .arg("-p")
+        .exec_with_output()?;
+    fs::write(path, output.stdout)?;
+    Ok(())
+}
+
+pub(super) fn stop_app(
+    config: &AndroidConfig,
+    options: &ArgMatches,
+    target_config: &AndroidTargetConfig,
+) -> CargoResult<()> {
+    let mut stop_cmd = config.adb_command()?;
+    stop_cmd.arg("shell").arg("am").arg("force-stop");
+    if let Some(user) = device::selected_user(options)? {
+        stop_cmd.arg("--user").arg(user.to_string());
+    }
+    stop_cmd.arg(target_config.application_id()).exec()?;
+    Ok(())
+}
+
+/// Lists whether each example launched, in the order they ran
+fn render_sequence_summary(results: &[(&str, bool)]) -> String {
+    let width = results
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0);
+    let mut summary = format!(
+        "Launched {} of {} examples",
+        results.iter().filter(|(_, launched)| *launched).count(),
+        results.len()
+    );
+    for (name, launched) in results {
+        summary.push_str(&format!(
+            "\n  {:width$}  {}",
+            name,
+            if *launched { "ok" } else { "FAILED" },
+            width = width
+        ));
+    }
+    summary
+}
+
+#[test]
+fn examples_sequence_summary() {
+    assert_eq!(
+        render_sequence_summary(&[("clock", true), ("cube_3d", false), ("sprite", true)]),
+        "Launched 2 of 3 examples\n  clock    ok\n  cube_3d  FAILED\n  sprite   ok"
+    );
+    assert_eq!(render_sequence_summary(&[]), "Launched 0 of 0 examples");
+}
+
+#[test]
+fn app_args_extra() {
+    let args = ["level 1".to_owned(), "a,b".to_owned(), "it's".to_owned()];
+    assert_eq!(string_array_extra(&args), r"level 1,a\,b,it's");
+    assert_eq!(
+        shell_quote(&string_array_extra(&args)),
+        r"'level 1,a\,b,it'\''s'"
+    );
+}
@@ -0,0 +1,37 @@
+use cargo::core::Workspace;
+use cargo::util::CargoResult;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+/// What commands remember about the device between invocations, stored in the target directory
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DeviceState {
+    /// Device time, in seconds since the epoch, at which `cargo quad-apk run` last started the app
+    pub last_run_started_at: Option<u64>,
+}
+
+fn state_path(workspace: &Workspace) -> PathBuf {
+    workspace
+        .target_dir()
+        .join("android-artifacts")
+        .join("device-state.json")
+        .into_path_unlocked()
+}
+
+impl DeviceState {
+    /// Reads the state, which is empty when no command saved it yet
+    pub fn load(workspace: &Workspace) -> DeviceState {
+        fs::read_to_string(state_path(workspace))
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, workspace: &Workspace) -> CargoResult<()> {
+        let path = state_path(workspace);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
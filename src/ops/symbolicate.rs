@@ -0,0 +1,200 @@
+//! `--symbolicate` of `logcat` and `run`: the native crashes dumped to the log by crash_dump are
+//! given to ndk-stack along with the libraries of their ABI as linked, which still have their
+//! symbols.
+
+use super::build;
+use super::logcat::threadtime_fields;
+use crate::config::{AndroidBuildTarget, AndroidConfig};
+use cargo::core::shell::{ColorChoice, Shell, Verbosity};
+use cargo::core::Workspace;
+use cargo::util::CargoResult;
+use cargo_util::ProcessBuilder;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Tag of the lines of the crashes dumped by crash_dump
+const CRASH_TAG: &str = "DEBUG";
+
+/// Prefix of the first line of a crash
+const CRASH_START: &str = "*** *** ***";
+
+/// Runs ndk-stack on the crashes of the lines of `logcat -v threadtime` it is given
+pub struct Symbolicator {
+    ndk_stack: PathBuf,
+    root_build_dir: PathBuf,
+    crash: CrashCollector,
+    /// Settings of the shell of cargo, which can't be sent to the thread reading the log
+    verbosity: Verbosity,
+    color_choice: ColorChoice,
+}
+
+impl Symbolicator {
+    /// Finds ndk-stack, and prints a hint for each library of the build lacking symbols
+    pub fn new(workspace: &Workspace, config: &AndroidConfig) -> CargoResult<Symbolicator> {
+        let ndk_stack = build::ndk_stack(config)?;
+        let root_build_dir = build::root_build_directory(workspace, config);
+        for &build_target in &config.build_targets {
+            let symbol_dir = build::symbol_directory(&root_build_dir, build_target);
+            for hint in build::missing_symbols_hints(&symbol_dir) {
+                workspace.gctx().shell().note(format!(
+                    "{} ({})",
+                    hint,
+                    build_target.android_abi()
+                ))?;
+            }
+        }
+        let shell = workspace.gctx().shell();
+        Ok(Symbolicator {
+            ndk_stack,
+            root_build_dir,
+            crash: CrashCollector::default(),
+            verbosity: shell.verbosity(),
+            color_choice: shell.color_choice(),
+        })
+    }
+
+    /// Returns a shell with the settings of the one of cargo
+    fn shell(&self) -> Shell {
+        let mut shell = Shell::new();
+        shell.set_verbosity(self.verbosity);
+        let color = match self.color_choice {
+            ColorChoice::Always => "always",
+            ColorChoice::Never => "never",
+            ColorChoice::CargoAuto => "auto",
+        };
+        drop(shell.set_color_choice(Some(color)));
+        shell
+    }
+
+    /// Reads a line of the log, once printed. The crash it ends is symbolicated.
+    pub fn push_line(&mut self, line: &str) {
+        if let Some(crash) = self.crash.push_line(line) {
+            self.symbolicate(crash);
+        }
+    }
+
+    /// Symbolicates the crash the log ends with, if any
+    pub fn finish(&mut self) {
+        if let Some(crash) = self.crash.finish() {
+            self.symbolicate(crash);
+        }
+    }
+
+    /// Prints the output of ndk-stack for a crash. Failures are printed as warnings, as the log
+    /// goes on.
+    fn symbolicate(&self, crash: Crash) {
+        let build_target = match crash.build_target {
+            Some(build_target) => build_target,
+            None => {
+                drop(
+                    self.shell()
+                        .warn("the ABI of the crash is unknown, it can't be symbolicated"),
+                );
+                return;
+            }
+        };
+        let symbol_dir = build::symbol_directory(&self.root_build_dir, build_target);
+        if !symbol_dir.exists() {
+            drop(self.shell().warn(format!(
+                "no {} libraries were built to symbolicate the crash with",
+                build_target.android_abi()
+            )));
+            return;
+        }
+        let output = ProcessBuilder::new(&self.ndk_stack)
+            .arg("-sym")
+            .arg(&symbol_dir)
+            .stdin(crash.lines.join("\n") + "\n")
+            .exec_with_output();
+        match output {
+            Ok(output) => {
+                let stdout = std::io::stdout();
+                let mut out = stdout.lock();
+                drop(out.write_all(&output.stdout));
+                drop(out.flush());
+            }
+            Err(err) => drop(
+                self.shell()
+                    .warn(format!("unable to symbolicate the crash: {:#}", err)),
+            ),
+        }
+    }
+}
+
+/// Lines of a native crash, with the build target of the `ABI:` line of its header
+#[derive(Debug, PartialEq)]
+struct Crash {
+    lines: Vec<String>,
+    build_target: Option<AndroidBuildTarget>,
+}
+
+/// Gathers the lines of the crashes, from the `*** *** ***` line starting them to the first line
+/// of another tag
+#[derive(Default)]
+struct CrashCollector {
+    crash: Option<Crash>,
+}
+
+impl CrashCollector {
+    /// Reads a line, returning the crash it ends
+    fn push_line(&mut self, line: &str) -> Option<Crash> {
+        let (tag, message) = match threadtime_fields(line) {
+            Some((_, tag, message)) => (tag, message),
+            None => return None,
+        };
+        if tag != CRASH_TAG {
+            return self.crash.take();
+        }
+        if message.starts_with(CRASH_START) {
+            let ended = self.crash.take();
+            self.crash = Some(Crash {
+                lines: vec![line.to_owned()],
+                build_target: None,
+            });
+            return ended;
+        }
+        if let Some(crash) = &mut self.crash {
+            crash.lines.push(line.to_owned());
+            // Like `ABI: 'arm64'`
+            if let Some(abi) = message.strip_prefix("ABI: ") {
+                crash.build_target = AndroidBuildTarget::from_name(abi.trim_matches('\'')).ok();
+            }
+        }
+        None
+    }
+
+    fn finish(&mut self) -> Option<Crash> {
+        self.crash.take()
+    }
+}
+
+#[test]
+fn crash_lines() {
+    let log = "\
+10-18 12:00:00.100  4321  4321 I miniquad: frame\n\
+10-18 12:00:00.200  4400  4400 F DEBUG   : *** *** *** *** *** *** *** *** *** *** *** *** *** *** *** ***\n\
+10-18 12:00:00.200  4400  4400 F DEBUG   : Build fingerprint: 'google/sdk_gphone64_arm64/emu64a:14'\n\
+10-18 12:00:00.200  4400  4400 F DEBUG   : ABI: 'arm'\n\
+10-18 12:00:00.200  4400  4400 F DEBUG   : backtrace:\n\
+10-18 12:00:00.200  4400  4400 F DEBUG   :       #00 pc 0001a2b4  /data/app/rust.app/lib/arm/libapp.so\n\
+10-18 12:00:00.300   567   600 I ActivityManager: Process rust.app (pid 4321) has died\n";
+    let mut collector = CrashCollector::default();
+    let crashes = log
+        .lines()
+        .filter_map(|line| collector.push_line(line))
+        .collect::<Vec<_>>();
+    assert_eq!(crashes.len(), 1);
+    assert_eq!(crashes[0].build_target, Some(AndroidBuildTarget::ArmV7a));
+    assert_eq!(crashes[0].lines.len(), 5);
+    assert!(crashes[0].lines[4].ends_with("libapp.so"));
+    assert_eq!(collector.finish(), None);
+
+    // A crash ending the log is returned by `finish`, with no ABI before its `ABI:` line
+    let mut collector = CrashCollector::default();
+    for line in log.lines().skip(1).take(2) {
+        assert_eq!(collector.push_line(line), None);
+    }
+    let crash = collector.finish().unwrap();
+    assert_eq!(crash.build_target, None);
+    assert_eq!(crash.lines.len(), 2);
+}
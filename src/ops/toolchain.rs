@@ -0,0 +1,73 @@
+//! Pinning the Rust toolchain with the `rust_toolchain` key.
+//!
+//! cargo runs in process and resolves rustc once from the environment, so the command is run
+//! again with `RUSTC` pointing at the rustc of the pinned toolchain. Everything else, the
+//! executor injecting the glue in particular, is left as is.
+
+use super::interrupt;
+use crate::config::AndroidConfig;
+use crate::error::{FailureKind, ResultExt};
+use anyhow::format_err;
+use cargo::core::Workspace;
+use cargo::util::CargoResult;
+use cargo_util::ProcessBuilder;
+use std::path::PathBuf;
+
+/// Set in the environment of the command run again, to the toolchain it runs with
+const PINNED_TOOLCHAIN_VAR: &str = "CARGO_QUAD_APK_TOOLCHAIN";
+
+/// Runs the current command again with the rustc of `rust_toolchain`, when it is set and the
+/// command doesn't already run with it. Returns the exit code of the command run again.
+pub fn run_with_toolchain(
+    workspace: &Workspace,
+    config: &AndroidConfig,
+) -> CargoResult<Option<i32>> {
+    let toolchain = match &config.rust_toolchain {
+        Some(toolchain) => toolchain,
+        None => return Ok(None),
+    };
+    if std::env::var(PINNED_TOOLCHAIN_VAR).ok().as_ref() == Some(toolchain) {
+        return Ok(None);
+    }
+
+    let rustc = toolchain_rustc(toolchain).failure_kind(FailureKind::Environment)?;
+    workspace
+        .gctx()
+        .shell()
+        .status("Toolchain", format!("{} ({})", toolchain, rustc.display()))?;
+
+    let mut cmd = ProcessBuilder::new(std::env::current_exe()?);
+    cmd.args(&std::env::args_os().skip(1).collect::<Vec<_>>())
+        .env("RUSTC", &rustc)
+        // For the build scripts and tools calling the rustup proxies themselves
+        .env("RUSTUP_TOOLCHAIN", toolchain)
+        .env(PINNED_TOOLCHAIN_VAR, toolchain);
+    let mut child = cmd
+        .build_command()
+        .spawn()
+        .map_err(|err| format_err!("could not execute process {}: {}", cmd, err))?;
+    let status = interrupt::wait(&mut child)?;
+    Ok(Some(status.code().unwrap_or(1)))
+}
+
+/// Returns the path of the rustc of a toolchain installed with rustup
+fn toolchain_rustc(toolchain: &str) -> CargoResult<PathBuf> {
+    let output = ProcessBuilder::new("rustup")
+        .arg("which")
+        .arg("--toolchain")
+        .arg(toolchain)
+        .arg("rustc")
+        .exec_with_output()
+        .map_err(|err| {
+            format_err!(
+                "Unable to find the rustc of the `{}` toolchain of `rust_toolchain`, install it \
+                 with `rustup toolchain install {}`: {}",
+                toolchain,
+                toolchain,
+                err
+            )
+        })?;
+    Ok(PathBuf::from(
+        String::from_utf8_lossy(&output.stdout).trim(),
+    ))
+}
@@ -0,0 +1,64 @@
+use crate::config::AndroidConfig;
+use crate::error::FailureKind;
+use crate::ops::device;
+use anyhow::format_err;
+use cargo::core::{TargetKind, Workspace};
+use cargo::util::CargoResult;
+use cargo_util::ProcessBuilder;
+use clap::ArgMatches;
+
+pub fn uninstall(
+    workspace: &Workspace,
+    config: &AndroidConfig,
+    options: &ArgMatches,
+) -> CargoResult<()> {
+    // Uninstall the requested target, or all the binaries of the package
+    let targets = if let Some(bin) = options.get_one::<String>("bin") {
+        vec![(TargetKind::Bin, bin.clone())]
+    } else if let Some(example) = options.get_one::<String>("example") {
+        vec![(TargetKind::ExampleBin, example.clone())]
+    } else {
+        workspace
+            .members()
+            .find(|package| *package.name() == config.cargo_package_name)
+            .ok_or_else(|| format_err!("Unable to find package `{}`", config.cargo_package_name))?
+            .targets()
+            .iter()
+            .filter(|target| target.is_bin())
+            .map(|target| (TargetKind::Bin, target.name().to_owned()))
+            .collect()
+    };
+
+    device::check_device_selected(config)?;
+    let adb = config.adb_command()?;
+    let user = device::selected_user(options)?;
+
+    for target in targets {
+        let package_name = config.resolve(target)?.package_name.replace("-", "_");
+
+        let mut uninstall_cmd = adb.clone();
+        uninstall_cmd.arg("shell").arg("pm").arg("uninstall");
+        if let Some(user) = user {
+            uninstall_cmd.arg("--user").arg(user.to_string());
+        }
+        let output = uninstall_cmd
+            .arg(&package_name)
+            .output()
+            .map_err(|err| FailureKind::Device.mark(err.into()))?;
+
+        if String::from_utf8_lossy(&output.stdout).contains("Success") {
+            workspace
+                .gctx()
+                .shell()
+                .status("Uninstalled", &package_name)?;
+        } else {
+            workspace.gctx().shell().warn(format!(
+                "Unable to uninstall `{}`. {}",
+                package_name,
+                String::from_utf8_lossy(&output.stdout).trim()
+            ))?;
+        }
+    }
+
+    Ok(())
+}
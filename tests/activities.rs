@@ -0,0 +1,51 @@
+mod common;
+
+use common::{build_command, fixture, miniquad_java, write};
+use std::fs;
+
+/// Builds an app with a second activity against the real Android SDK, NDK and JDK found in
+/// the environment, and checks that the activity class ends up in the dex.
+#[test]
+#[ignore = "requires ANDROID_HOME, NDK_HOME, a JDK and the aarch64-linux-android rust target"]
+fn additional_activity_is_dexed() {
+    let root = fixture("activities");
+
+    let mut manifest = fs::read_to_string(root.join("app/Cargo.toml")).unwrap();
+    manifest.push_str(
+        r#"
+[package.metadata.android]
+build_targets = ["aarch64-linux-android"]
+
+[[package.metadata.android.activities]]
+name = "com.example.LicenseActivity"
+exported = false
+"#,
+    );
+    write(&root, "app/Cargo.toml", &manifest);
+
+    write(
+        &root,
+        "miniquad/quad.toml",
+        r#"java_files = ["java/com/example/LicenseActivity.java"]"#,
+    );
+    write(
+        &root,
+        "miniquad/java/com/example/LicenseActivity.java",
+        "package com.example;\n\
+         public class LicenseActivity extends android.app.Activity {}\n",
+    );
+    miniquad_java(&root);
+
+    let output = build_command(&root, &["--nosign"]).output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let dex = fs::read(root.join("target/android-artifacts/debug/bin/app/classes.dex")).unwrap();
+    let class = b"Lcom/example/LicenseActivity;";
+    assert!(dex.windows(class.len()).any(|window| window == class));
+
+    fs::remove_dir_all(&root).unwrap();
+}
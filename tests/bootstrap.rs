@@ -0,0 +1,25 @@
+mod common;
+
+use common::{fixture, quad_apk};
+use std::fs;
+
+#[test]
+fn bootstrap_refuses_to_run_offline() {
+    let root = fixture("bootstrap-offline");
+
+    let output = quad_apk(
+        &root,
+        "bootstrap",
+        &["--offline", "--dir", "new-sdk", "--accept-licenses"],
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains("`bootstrap` downloads the Android SDK, it can't run with `--offline`"),
+        "{}",
+        stderr
+    );
+    assert!(!root.join("app/new-sdk").exists());
+
+    fs::remove_dir_all(&root).unwrap();
+}
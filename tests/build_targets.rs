@@ -0,0 +1,49 @@
+mod common;
+
+use common::{build, fixture, quad_apk};
+use std::fs;
+
+#[test]
+fn unknown_abi_is_rejected() {
+    let root = fixture("unknown-abi");
+
+    for subcommand in &["build", "run"] {
+        let output = quad_apk(&root, subcommand, &["--offline", "--abi", "mips"]);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!output.status.success());
+        assert!(
+            stderr.contains("Invalid `--abi`: unknown build target `mips`")
+                && stderr.contains("x86_64-linux-android      x86_64")
+                && stderr.contains("or one of the aliases arm, arm64, x86, x64"),
+            "{}",
+            stderr
+        );
+    }
+
+    // Rust triples are accepted too
+    let output = build(
+        &root,
+        &["--offline", "--no-apk", "--abi", "x86_64-linux-android"],
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("Invalid `--abi`"), "{}", stderr);
+    assert!(stderr.contains("Unable to find NDK clang"), "{}", stderr);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn all_abis_conflicts_with_abi() {
+    let root = fixture("all-abis");
+
+    let output = quad_apk(&root, "run", &["--offline", "--all-abis", "--abi", "x86"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains("'--all-abis' cannot be used with '--abi <ABI>'"),
+        "{}",
+        stderr
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,71 @@
+mod common;
+
+use common::{fixture, quad_apk, write};
+use std::fs;
+
+#[test]
+fn clean_removes_android_artifacts_only() {
+    let root = fixture("clean");
+    let artifacts = |target_dir: &str| {
+        for profile in &["debug", "release"] {
+            let dir = format!("{}/android-artifacts/{}", target_dir, profile);
+            write(&root, &format!("{}/apk/app.apk", dir), "apk");
+            write(
+                &root,
+                &format!("{}/flavors/demo/apk/app-demo.apk", dir),
+                "apk",
+            );
+            write(
+                &root,
+                &format!("{}/bin/app/build/obj/A.class", dir),
+                "class",
+            );
+        }
+        write(&root, &format!("{}/debug/app", target_dir), "host build");
+    };
+    artifacts("target");
+    let exists = |path: &str| root.join("target").join(path).exists();
+
+    let output = quad_apk(&root, "clean", &["--apk-only", "--release"]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(!exists("android-artifacts/release/apk"));
+    assert!(!exists("android-artifacts/release/flavors/demo/apk"));
+    assert!(exists("android-artifacts/release/flavors/demo"));
+    assert!(exists(
+        "android-artifacts/release/bin/app/build/obj/A.class"
+    ));
+    assert!(exists("android-artifacts/debug/apk/app.apk"));
+
+    let output = quad_apk(&root, "clean", &["--apk-only"]);
+    assert!(output.status.success());
+    assert!(!exists("android-artifacts/debug/apk"));
+    assert!(!exists("android-artifacts/debug/flavors/demo/apk"));
+    assert!(exists("android-artifacts/debug/bin/app/build/obj/A.class"));
+
+    let output = quad_apk(&root, "clean", &[]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("Removed 2 files"), "{}", stderr);
+    assert!(!exists("android-artifacts/debug"));
+    assert!(!exists("android-artifacts/release"));
+    assert!(exists("debug/app"));
+
+    // `--target-dir` is respected
+    artifacts("custom");
+    let custom = root.join("custom");
+    let output = quad_apk(
+        &root,
+        "clean",
+        &["--release", "--target-dir", custom.to_str().unwrap()],
+    );
+    assert!(output.status.success());
+    assert!(!custom.join("android-artifacts/release").exists());
+    assert!(custom.join("android-artifacts/debug/apk/app.apk").exists());
+    assert!(exists("debug/app"));
+
+    fs::remove_dir_all(&root).unwrap();
+}
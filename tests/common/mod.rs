@@ -0,0 +1,136 @@
+#![allow(dead_code)]
+
+use assert_cmd::prelude::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// Creates a package depending on a local `miniquad` stub, along with a fake Android SDK
+/// which is complete enough for the configuration to load.
+pub fn fixture(name: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("cargo-quad-apk-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+
+    write(
+        &root,
+        "app/Cargo.toml",
+        r#"[package]
+name = "app"
+version = "0.1.0"
+edition = "2018"
+
+[dependencies]
+miniquad = { path = "../miniquad" }
+"#,
+    );
+    write(&root, "app/src/main.rs", "fn main() {}\n");
+    write(
+        &root,
+        "miniquad/Cargo.toml",
+        r#"[package]
+name = "miniquad"
+version = "0.1.0"
+edition = "2018"
+"#,
+    );
+    write(&root, "miniquad/src/lib.rs", "");
+    write(&root, "sdk/build-tools/31.0.0/aapt", "");
+    write(&root, "sdk/platforms/android-31/android.jar", "");
+    // Used by packages without `[package.metadata.android]`
+    write(&root, "sdk/platforms/android-34/android.jar", "");
+    fs::create_dir_all(root.join("ndk")).unwrap();
+
+    root
+}
+
+/// Creates a package without miniquad, exporting the entry point of NativeActivity itself, along
+/// with the same fake Android SDK as `fixture`
+pub fn native_activity_fixture(name: &str) -> PathBuf {
+    let root = fixture(name);
+    fs::remove_dir_all(root.join("miniquad")).unwrap();
+    write(
+        &root,
+        "app/Cargo.toml",
+        r#"[package]
+name = "app"
+version = "0.1.0"
+edition = "2018"
+
+[package.metadata.android]
+framework = "none"
+"#,
+    );
+    write(
+        &root,
+        "app/src/main.rs",
+        "#[no_mangle]\n\
+         pub extern \"C\" fn ANativeActivity_onCreate(\n\
+         \x20   _activity: *mut std::ffi::c_void,\n\
+         \x20   _saved_state: *mut std::ffi::c_void,\n\
+         \x20   _saved_state_size: usize,\n\
+         ) {\n\
+         }\n\
+         \n\
+         fn main() {}\n",
+    );
+    root
+}
+
+/// Adds the Java sources which a real build needs to the fixture's `miniquad` stub
+pub fn miniquad_java(root: &Path) {
+    write(
+        root,
+        "miniquad/java/MainActivity.java",
+        "package TARGET_PACKAGE_NAME;\n\
+         //% IMPORTS\n\
+         public class MainActivity extends android.app.Activity {\n\
+         //% MAIN_ACTIVITY_BODY\n\
+         }\n",
+    );
+    write(
+        root,
+        "miniquad/java/QuadNative.java",
+        "package quad_native;\npublic class QuadNative {}\n",
+    );
+    write(root, "miniquad/src/native/android/mod_inject.rs", "");
+}
+
+/// Writes a file of a fixture, creating its parent directories
+pub fn write(root: &Path, path: &str, contents: &str) {
+    let path = root.join(path);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+}
+
+/// Runs `cargo quad-apk build` on the fixture's app, using the fixture's fake SDK and NDK
+pub fn build(root: &Path, args: &[&str]) -> Output {
+    quad_apk(root, "build", args)
+}
+
+/// Runs a `cargo quad-apk` subcommand on the fixture's app, using the fixture's fake SDK and NDK
+pub fn quad_apk(root: &Path, subcommand: &str, args: &[&str]) -> Output {
+    subcommand_command(root, subcommand, args)
+        .env("ANDROID_HOME", root.join("sdk"))
+        .env_remove("ANDROID_SDK_HOME")
+        .env_remove("ANDROID_SERIAL")
+        .env("NDK_HOME", root.join("ndk"))
+        .output()
+        .unwrap()
+}
+
+/// Returns the `cargo quad-apk build` command for the fixture's app
+pub fn build_command(root: &Path, args: &[&str]) -> Command {
+    subcommand_command(root, "build", args)
+}
+
+/// Returns the `cargo quad-apk <subcommand>` command for the fixture's app
+pub fn subcommand_command(root: &Path, subcommand: &str, args: &[&str]) -> Command {
+    let mut cmd = Command::cargo_bin("cargo-quad-apk").unwrap();
+    cmd.arg("quad-apk")
+        .arg(subcommand)
+        .args(args)
+        .arg("--manifest-path")
+        .arg(root.join("app/Cargo.toml"))
+        .env("CARGO_TARGET_DIR", root.join("target"));
+    cmd
+}
@@ -0,0 +1,53 @@
+mod common;
+
+use common::{build_command, fixture, miniquad_java, write};
+use std::fs;
+
+/// Builds an app using `java.time` for API 24 against the real Android SDK, NDK and JDK found
+/// in the environment, with the desugar_jdk_libs artifacts given by `DESUGAR_JDK_LIBS_CONFIG`
+/// and `DESUGAR_JDK_LIBS_JAR`.
+#[test]
+#[ignore = "requires ANDROID_HOME, NDK_HOME, a JDK, the aarch64-linux-android rust target and \
+            DESUGAR_JDK_LIBS_CONFIG/DESUGAR_JDK_LIBS_JAR"]
+fn java_time_is_desugared() {
+    let root = fixture("desugaring");
+
+    let mut manifest = fs::read_to_string(root.join("app/Cargo.toml")).unwrap();
+    manifest.push_str(&format!(
+        r#"
+[package.metadata.android]
+build_targets = ["aarch64-linux-android"]
+min_sdk_version = 24
+java_sources = ["android/java"]
+core_library_desugaring = true
+desugar_lib_config = {:?}
+desugar_lib_jars = [{:?}]
+"#,
+        std::env::var("DESUGAR_JDK_LIBS_CONFIG").unwrap(),
+        std::env::var("DESUGAR_JDK_LIBS_JAR").unwrap(),
+    ));
+    write(&root, "app/Cargo.toml", &manifest);
+    write(
+        &root,
+        "app/android/java/Clock.java",
+        "package com.example.clock;\n\
+         public class Clock {\n\
+             public static long now() { return java.time.Instant.now().toEpochMilli(); }\n\
+         }\n",
+    );
+    miniquad_java(&root);
+
+    let output = build_command(&root, &["--nosign"]).output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    // References to java.time are rewritten to the desugared library
+    let dex = fs::read(root.join("target/android-artifacts/debug/bin/app/classes.dex")).unwrap();
+    let class = b"Lj$/time/Instant;";
+    assert!(dex.windows(class.len()).any(|window| window == class));
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,118 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::{fixture, quad_apk, write};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+/// Installs an adb which lists two devices and records the arguments of the other commands
+fn two_devices_adb(root: &std::path::Path) {
+    write(
+        root,
+        "sdk/platform-tools/adb",
+        &format!(
+            "#!/bin/sh\n\
+             if [ \"$1\" = devices ]; then\n\
+             \x20   printf 'List of devices attached\\nemulator-5554\\tdevice\\nR58M12ABCDE\\tdevice\\n\\n'\n\
+             \x20   exit 0\n\
+             fi\n\
+             echo \"$@\" >> {}\n",
+            root.join("adb-args").display()
+        ),
+    );
+    fs::set_permissions(
+        root.join("sdk/platform-tools/adb"),
+        fs::Permissions::from_mode(0o755),
+    )
+    .unwrap();
+}
+
+#[test]
+fn several_devices_are_listed() {
+    let root = fixture("several-devices");
+    two_devices_adb(&root);
+
+    let output = quad_apk(&root, "logcat", &["--offline"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains("2 devices are connected, select one with `--device <SERIAL>`")
+            && stderr.contains("emulator-5554\tdevice")
+            && stderr.contains("R58M12ABCDE\tdevice"),
+        "{}",
+        stderr
+    );
+    assert!(!root.join("adb-args").exists());
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn selected_device_is_given_to_adb() {
+    let root = fixture("selected-device");
+    two_devices_adb(&root);
+
+    let output = quad_apk(&root, "logcat", &["--offline", "-s", "R58M12ABCDE"]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        fs::read_to_string(root.join("adb-args")).unwrap(),
+        "-s R58M12ABCDE logcat -v threadtime\n"
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn devices_are_listed_with_their_abis() {
+    let root = fixture("list-devices");
+    write(
+        &root,
+        "sdk/platform-tools/adb",
+        "#!/bin/sh\n\
+         if [ \"$1\" = devices ]; then\n\
+         \x20   printf 'List of devices attached\\n'\n\
+         \x20   printf 'emulator-5554 device product:sdk model:Pixel_7 transport_id:1\\n'\n\
+         \x20   printf 'R58M12ABCDE unauthorized usb:1-1 transport_id:2\\n\\n'\n\
+         \x20   exit 0\n\
+         fi\n\
+         if [ \"$4\" = getprop ]; then\n\
+         \x20   printf '[ro.build.version.release]: [14]\\n[ro.product.cpu.abilist]: [x86_64,x86]\\n'\n\
+         fi\n",
+    );
+    fs::set_permissions(
+        root.join("sdk/platform-tools/adb"),
+        fs::Permissions::from_mode(0o755),
+    )
+    .unwrap();
+
+    let mut manifest = fs::read_to_string(root.join("app/Cargo.toml")).unwrap();
+    manifest
+        .push_str("\n[package.metadata.android]\nbuild_targets = [\"aarch64-linux-android\"]\n");
+    write(&root, "app/Cargo.toml", &manifest);
+
+    let output = quad_apk(&root, "devices", &["--offline"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert_eq!(
+        stdout,
+        "SERIAL        MODEL   ANDROID ABIS\n\
+         emulator-5554 Pixel 7 14      x86_64,x86\n\
+         R58M12ABCDE   -       -       (unauthorized)\n"
+    );
+    assert!(
+        stderr.contains(
+            "warning: device emulator-5554 supports the ABIs x86_64, x86 but `build_targets` only \
+             has arm64-v8a"
+        ),
+        "{}",
+        stderr
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
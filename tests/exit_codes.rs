@@ -0,0 +1,136 @@
+mod common;
+
+use common::{build, fixture, quad_apk, write};
+use std::fs;
+use std::process::Output;
+
+/// Returns the `failure_kind` of the final record of `--message-format json`
+fn failure_kind(output: &Output) -> String {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let record = stdout
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .find(|record| record["reason"] == "cargo-quad-apk-failure")
+        .unwrap_or_else(|| panic!("no failure record in {}", stdout));
+    assert_eq!(record["exit_code"], output.status.code().unwrap());
+    record["failure_kind"].as_str().unwrap().to_owned()
+}
+
+#[test]
+fn environment_failure() {
+    let root = fixture("exit-environment");
+
+    // The fake NDK has no clang
+    let output = build(&root, &["--offline", "--message-format", "json"]);
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(failure_kind(&output), "environment");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("error: Unable to find NDK clang"),
+        "{}",
+        stderr
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn compilation_failure() {
+    let root = fixture("exit-compilation");
+    let bin = "ndk/toolchains/llvm/prebuilt/linux-x86_64/bin";
+    for tool in &[
+        "aarch64-linux-android18-clang",
+        "aarch64-linux-android18-clang++",
+        "llvm-ar",
+    ] {
+        write(&root, &format!("{}/{}", bin, tool), "");
+    }
+    let mut manifest = fs::read_to_string(root.join("app/Cargo.toml")).unwrap();
+    manifest
+        .push_str("\n[package.metadata.android]\nbuild_targets = [\"aarch64-linux-android\"]\n");
+    write(&root, "app/Cargo.toml", &manifest);
+    write(&root, "app/src/lib.rs", "pub fn broken( {}\n");
+
+    let output = build(&root, &["--offline", "--message-format", "json"]);
+    // The code of cargo
+    assert_eq!(output.status.code(), Some(101));
+    assert_eq!(failure_kind(&output), "compilation");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn device_failure() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = fixture("exit-device");
+    write(
+        &root,
+        "sdk/platform-tools/adb",
+        "#!/bin/sh\necho 'error: no devices/emulators found' >&2\nexit 1\n",
+    );
+    fs::set_permissions(
+        root.join("sdk/platform-tools/adb"),
+        fs::Permissions::from_mode(0o755),
+    )
+    .unwrap();
+
+    let output = quad_apk(&root, "logcat", &["--offline"]);
+    assert_eq!(output.status.code(), Some(4));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("no devices/emulators found"), "{}", stderr);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn signing_failure() {
+    let root = fixture("exit-signing");
+    let mut manifest = fs::read_to_string(root.join("app/Cargo.toml")).unwrap();
+    manifest.push_str(
+        "\n[package.metadata.android.signing]\n\
+         keystore = \"release.jks\"\n\
+         key_alias = \"upload\"\n\
+         store_password_env = \"CARGO_QUAD_APK_TEST_UNSET_PASSWORD\"\n",
+    );
+    write(&root, "app/Cargo.toml", &manifest);
+    write(&root, "app/release.jks", "");
+
+    let output = build(
+        &root,
+        &["--offline", "--release", "--message-format", "json"],
+    );
+    assert_eq!(output.status.code(), Some(5));
+    assert_eq!(failure_kind(&output), "signing");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("`CARGO_QUAD_APK_TEST_UNSET_PASSWORD`, which is not set"),
+        "{}",
+        stderr
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn missing_rust_toolchain() {
+    let root = fixture("exit-toolchain");
+    let mut manifest = fs::read_to_string(root.join("app/Cargo.toml")).unwrap();
+    manifest.push_str(
+        "\n[package.metadata.android]\nrust_toolchain = \"cargo-quad-apk-missing-toolchain\"\n",
+    );
+    write(&root, "app/Cargo.toml", &manifest);
+
+    let output = build(&root, &["--offline", "--message-format", "json"]);
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(failure_kind(&output), "environment");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("`cargo-quad-apk-missing-toolchain` toolchain of `rust_toolchain`"),
+        "{}",
+        stderr
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,31 @@
+mod common;
+
+use common::{build, fixture};
+use std::fs;
+
+#[test]
+fn defaults_notice_is_printed_once() {
+    let root = fixture("first-run");
+
+    // The fake NDK fails the build after the notice
+    let output = build(&root, &["--offline"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(
+            "note: Cargo.toml has no `[package.metadata.android]`, building with these defaults:"
+        ) && stderr.contains("package_name = \"rust.app\"")
+            && stderr.contains("build_targets = [\"aarch64-linux-android\"]"),
+        "{}",
+        stderr
+    );
+
+    let output = build(&root, &["--offline"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        !stderr.contains("building with these defaults"),
+        "{}",
+        stderr
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
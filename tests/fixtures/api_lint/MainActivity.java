@@ -0,0 +1,32 @@
+// Compiled into MainActivity.class against stubs of android.app.Activity and android.os.Build with
+// `javac --release 8`, for the tests of the API lint
+package rust.app;
+
+import android.app.Activity;
+import android.os.Build;
+
+public class MainActivity extends Activity {
+    // isInMultiWindowMode is added in API 24, behind a switch to exercise the bytecode walk
+    public boolean unguarded(int mode) {
+        switch (mode) {
+            case 1: return false;
+            case 2: finish(); return true;
+            case 3: return isInMultiWindowMode();
+            default: return true;
+        }
+    }
+
+    public void guarded() {
+        if (Build.VERSION.SDK_INT >= 27) {
+            setShowWhenLocked(true);
+        }
+    }
+
+    public void allowed() {
+        setShowWhenLocked(true);
+    }
+
+    public void old() {
+        finish();
+    }
+}
@@ -0,0 +1,6 @@
+// Source of the libanswer_*.so fixtures, built for x86_64 with
+//   gcc -g -O1 -shared -fPIC -nostdlib -Wl,--build-id=sha1 -Wl,-z,noseparate-code \
+//       -Wl,-z,max-page-size=0x1000 -Wl,--hash-style=gnu <FLAGS> -o libanswer_<STRIP>.so answer.c
+// where FLAGS is nothing for `none`, `-Wl,--strip-debug` for `debuginfo` and `-Wl,--strip-all`
+// for `all`.
+int answer(void) { return 42; }
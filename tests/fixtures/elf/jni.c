@@ -0,0 +1,15 @@
+// Source of the libjni.so fixture, built for x86_64 with
+//   gcc -O1 -shared -fPIC -nostdlib -Wl,--build-id=sha1 -Wl,-z,noseparate-code \
+//       -Wl,-z,max-page-size=0x1000 -Wl,--hash-style=gnu -Wl,--strip-all -o libjni.so jni.c
+// It has the strings a library calling back into Java through JNI holds in .rodata, and exports
+// the native methods of a Java class.
+typedef const char *const entry[2];
+
+// Class and method looked up with FindClass and GetMethodID, the way miniquad does
+__attribute__((used)) static entry lookups[] = {
+    {"com/example/game/Bridge", "onEvent"},
+    {"(Lcom/example/game/Event;I)V", "(Ljava/lang/String;)Landroid/view/View;"},
+};
+
+int Java_com_example_game_Bridge_nativeInit(void) { return 0; }
+int Java_com_example_game_Native_1Lib_onFrame__J(void) { return 1; }
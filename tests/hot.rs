@@ -0,0 +1,73 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::{fixture, quad_apk, write};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// Installs an adb with one device, where the app isn't installed, and which records the
+/// arguments of the commands
+fn fake_adb(root: &Path) {
+    write(
+        root,
+        "sdk/platform-tools/adb",
+        &format!(
+            "#!/bin/sh\n\
+             echo \"$@\" >> {}\n\
+             case \"$*\" in\n\
+             \x20   devices) printf 'List of devices attached\\nR58M12ABCDE\\tdevice\\n\\n' ;;\n\
+             \x20   'shell pm path'*) exit 1 ;;\n\
+             esac\n",
+            root.join("adb-args").display(),
+        ),
+    );
+    fs::set_permissions(
+        root.join("sdk/platform-tools/adb"),
+        fs::Permissions::from_mode(0o755),
+    )
+    .unwrap();
+}
+
+#[test]
+fn hot_refuses_release_builds() {
+    let root = fixture("hot-release");
+    fake_adb(&root);
+
+    let output = quad_apk(&root, "hot", &["--offline", "--release"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains("`hot` only works with debug builds"),
+        "{}",
+        stderr
+    );
+    // Refused before touching the device
+    assert!(!root.join("adb-args").exists());
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn hot_requires_the_app_to_be_installed() {
+    let root = fixture("hot-not-installed");
+    fake_adb(&root);
+
+    let output = quad_apk(&root, "hot", &["--offline"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains(
+            "`rust.app` is not installed on the device, install it with `cargo quad-apk install`"
+        ),
+        "{}",
+        stderr
+    );
+    // Checked before building
+    assert!(!stderr.contains("Compiling"), "{}", stderr);
+    let adb_args = fs::read_to_string(root.join("adb-args")).unwrap();
+    assert!(!adb_args.contains("push"), "{}", adb_args);
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,36 @@
+mod common;
+
+use common::{build_command, fixture, miniquad_java, write};
+use std::fs;
+
+/// Truncates the manifest like a build killed while writing it would, and checks that the next
+/// build generates the files again instead of handing the truncated manifest to aapt.
+#[test]
+#[ignore = "requires ANDROID_HOME, NDK_HOME, a JDK and the aarch64-linux-android rust target"]
+fn truncated_manifest_is_regenerated() {
+    let root = fixture("interrupted-build");
+
+    let mut manifest = fs::read_to_string(root.join("app/Cargo.toml")).unwrap();
+    manifest
+        .push_str("\n[package.metadata.android]\nbuild_targets = [\"aarch64-linux-android\"]\n");
+    write(&root, "app/Cargo.toml", &manifest);
+    miniquad_java(&root);
+
+    let build = || {
+        let output = build_command(&root, &["--nosign"]).output().unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    };
+    build();
+    let android_manifest = root.join("target/android-artifacts/debug/bin/app/AndroidManifest.xml");
+    let generated = fs::read_to_string(&android_manifest).unwrap();
+
+    fs::write(&android_manifest, &generated[..generated.len() / 2]).unwrap();
+    build();
+    assert_eq!(fs::read_to_string(&android_manifest).unwrap(), generated);
+
+    fs::remove_dir_all(&root).unwrap();
+}
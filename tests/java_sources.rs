@@ -0,0 +1,44 @@
+mod common;
+
+use common::{build_command, fixture, miniquad_java, write};
+use std::fs;
+
+/// Builds an app with its own Java helper class against the real Android SDK, NDK and JDK
+/// found in the environment, and checks that the class ends up in the dex.
+#[test]
+#[ignore = "requires ANDROID_HOME, NDK_HOME, a JDK and the aarch64-linux-android rust target"]
+fn app_java_sources_are_dexed() {
+    let root = fixture("java-sources");
+
+    let mut manifest = fs::read_to_string(root.join("app/Cargo.toml")).unwrap();
+    manifest.push_str(
+        r#"
+[package.metadata.android]
+build_targets = ["aarch64-linux-android"]
+java_sources = ["android/java"]
+"#,
+    );
+    write(&root, "app/Cargo.toml", &manifest);
+    write(
+        &root,
+        "app/android/java/Helper.java",
+        "package com.example.helper;\n\
+         public class Helper {\n\
+             public static String library() { return \"LIBRARY_NAME\"; }\n\
+         }\n",
+    );
+    miniquad_java(&root);
+
+    let output = build_command(&root, &["--nosign"]).output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let dex = fs::read(root.join("target/android-artifacts/debug/bin/app/classes.dex")).unwrap();
+    let class = b"Lcom/example/helper/Helper;";
+    assert!(dex.windows(class.len()).any(|window| window == class));
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,53 @@
+mod common;
+
+use assert_cmd::prelude::*;
+use common::{build, fixture};
+use std::fs;
+use std::process::Command;
+
+#[test]
+fn locked_build_does_not_write_lockfile() {
+    let root = fixture("locked");
+    let lockfile = root.join("app/Cargo.lock");
+
+    Command::new(std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned()))
+        .arg("generate-lockfile")
+        .arg("--offline")
+        .arg("--manifest-path")
+        .arg(root.join("app/Cargo.toml"))
+        .assert()
+        .success();
+    let contents = fs::read(&lockfile).unwrap();
+    let modified = fs::metadata(&lockfile).unwrap().modified().unwrap();
+
+    // The fake NDK makes the build fail after the workspace has been resolved
+    let output = build(&root, &["--locked", "--offline"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Unable to find NDK clang"), "{}", stderr);
+
+    assert_eq!(fs::read(&lockfile).unwrap(), contents);
+    assert_eq!(
+        fs::metadata(&lockfile).unwrap().modified().unwrap(),
+        modified
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn locked_build_without_lockfile_fails() {
+    let root = fixture("unlocked");
+
+    let output = build(&root, &["--locked", "--offline"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains("--locked was passed to prevent this"),
+        "{}",
+        stderr
+    );
+    assert!(!root.join("app/Cargo.lock").exists());
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,63 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::{fixture, quad_apk, write};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+#[test]
+fn logcat_filters_compose() {
+    let root = fixture("logcat-filters");
+    write(
+        &root,
+        "sdk/platform-tools/adb",
+        &format!(
+            "#!/bin/sh\n\
+             echo \"$@\" >> {}\n\
+             case \"$*\" in\n\
+             \x20   devices) printf 'List of devices attached\\nR58M12ABCDE\\tdevice\\n\\n' ;;\n\
+             \x20   'shell pidof rust.app') echo 1234 ;;\n\
+             esac\n",
+            root.join("adb-args").display()
+        ),
+    );
+    fs::set_permissions(
+        root.join("sdk/platform-tools/adb"),
+        fs::Permissions::from_mode(0o755),
+    )
+    .unwrap();
+
+    let output = quad_apk(
+        &root,
+        "logcat",
+        &[
+            "--offline",
+            "--pid-of-app",
+            "--level",
+            "w",
+            "--tag",
+            "SAPP",
+            "--tag",
+            "RustStdoutStderr",
+            "--clear",
+            "--dump",
+        ],
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    let adb_args = fs::read_to_string(root.join("adb-args")).unwrap();
+    assert_eq!(
+        adb_args
+            .lines()
+            .filter(|line| *line != "devices")
+            .collect::<Vec<_>>(),
+        vec![
+            "shell pidof rust.app",
+            "logcat -c",
+            "logcat -v threadtime -d --pid=1234 SAPP:W RustStdoutStderr:W *:S",
+        ]
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
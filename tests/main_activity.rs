@@ -0,0 +1,66 @@
+mod common;
+
+use common::{build_command, fixture, miniquad_java, write};
+use std::fs;
+
+/// Builds an app replacing the MainActivity of miniquad with its own, which has an extra method
+/// and is among its Java sources, against the real Android SDK, NDK and JDK found in the
+/// environment, and checks that the manifest starts it and that the method ends up in the dex.
+#[test]
+#[ignore = "requires ANDROID_HOME, NDK_HOME, a JDK and the aarch64-linux-android rust target"]
+fn user_main_activity_is_dexed() {
+    let root = fixture("main-activity");
+
+    let mut manifest = fs::read_to_string(root.join("app/Cargo.toml")).unwrap();
+    manifest.push_str(
+        r#"
+[package.metadata.android]
+build_targets = ["aarch64-linux-android"]
+java_sources = ["android/java"]
+main_activity = "android/java/GameActivity.java"
+"#,
+    );
+    write(&root, "app/Cargo.toml", &manifest);
+    // Next to the other Java sources, like in the README
+    write(
+        &root,
+        "app/android/java/Helper.java",
+        "package com.example.helper;\npublic class Helper {}\n",
+    );
+    write(
+        &root,
+        "app/android/java/GameActivity.java",
+        "package TARGET_PACKAGE_NAME;\n\
+         //% IMPORTS\n\
+         public class GameActivity extends android.app.Activity {\n\
+         \x20   static {\n\
+         \x20       System.loadLibrary(\"LIBRARY_NAME\");\n\
+         \x20   }\n\
+         \x20   public int shareScore(int score) { return score; }\n\
+         //% MAIN_ACTIVITY_BODY\n\
+         }\n",
+    );
+    miniquad_java(&root);
+
+    let output = build_command(&root, &["--nosign"]).output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let target_directory = root.join("target/android-artifacts/debug/bin/app");
+    let manifest = fs::read_to_string(target_directory.join("AndroidManifest.xml")).unwrap();
+    assert!(
+        manifest.contains(r#"android:name=".GameActivity""#),
+        "{}",
+        manifest
+    );
+    let dex = fs::read(target_directory.join("classes.dex")).unwrap();
+    for name in &[&b"Lrust/app/GameActivity;"[..], b"shareScore"] {
+        assert!(dex.windows(name.len()).any(|window| window == *name));
+    }
+    assert!(!target_directory.join("rust/app/MainActivity.java").exists());
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,70 @@
+mod common;
+
+use common::{build, build_command, native_activity_fixture, write};
+use std::fs;
+use std::io::Read;
+
+#[test]
+fn native_activity_builds_without_miniquad() {
+    let root = native_activity_fixture("native-activity");
+
+    let output = build(&root, &["--offline"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("miniquad"), "{}", stderr);
+    // The build itself carries on until the fake NDK makes it fail
+    assert!(stderr.contains("Unable to find NDK clang"), "{}", stderr);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn missing_miniquad_suggests_native_activity() {
+    let root = native_activity_fixture("no-miniquad");
+    let manifest = fs::read_to_string(root.join("app/Cargo.toml")).unwrap();
+    write(
+        &root,
+        "app/Cargo.toml",
+        &manifest.replace("framework = \"none\"\n", ""),
+    );
+
+    let output = build(&root, &["--offline"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains("`miniquad` is not in the dependency tree")
+            && stderr.contains("`framework = \"none\"`"),
+        "{}",
+        stderr
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+/// Builds the NativeActivity app against the real Android SDK and NDK found in the environment,
+/// and checks that the APK has the library but no dex.
+#[test]
+#[ignore = "requires ANDROID_HOME, NDK_HOME and the aarch64-linux-android rust target"]
+fn native_activity_apk_has_no_dex() {
+    let root = native_activity_fixture("native-activity-apk");
+    let mut manifest = fs::read_to_string(root.join("app/Cargo.toml")).unwrap();
+    manifest.push_str("build_targets = [\"aarch64-linux-android\"]\n");
+    write(&root, "app/Cargo.toml", &manifest);
+
+    let output = build_command(&root, &["--nosign"]).output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let mut apk = Vec::new();
+    fs::File::open(root.join("target/android-artifacts/debug/apk/app.apk"))
+        .unwrap()
+        .read_to_end(&mut apk)
+        .unwrap();
+    let contains = |name: &[u8]| apk.windows(name.len()).any(|window| window == name);
+    assert!(contains(b"lib/arm64-v8a/libapp.so"));
+    assert!(!contains(b"classes.dex"));
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,59 @@
+mod common;
+
+use assert_cmd::prelude::*;
+use common::{build, fixture, quad_apk};
+use std::fs;
+use std::process::Command;
+
+#[test]
+fn no_apk_build_needs_no_build_tools() {
+    let root = fixture("no-apk-build");
+    fs::remove_dir_all(root.join("sdk/build-tools")).unwrap();
+
+    let output = build(&root, &["--offline"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains("has no build-tools, install them with `sdkmanager"),
+        "{}",
+        stderr
+    );
+    // Checked before compiling anything
+    assert!(!stderr.contains("Unable to find NDK clang"), "{}", stderr);
+
+    let output = build(&root, &["--offline", "--no-apk"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("build-tools"), "{}", stderr);
+    assert!(!stderr.contains("platform-tools"), "{}", stderr);
+    // The build itself carries on until the fake NDK makes it fail
+    assert!(stderr.contains("Unable to find NDK clang"), "{}", stderr);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn no_apk_has_nothing_to_install() {
+    let root = fixture("no-apk-install");
+
+    let output = quad_apk(&root, "run", &["--no-apk"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("there is no APK to install"), "{}", stderr);
+
+    // `install` has no `--manifest-path`, it installs the package of the current directory
+    let output = Command::cargo_bin("cargo-quad-apk")
+        .unwrap()
+        .args(["quad-apk", "install", "--no-apk"])
+        .current_dir(root.join("app"))
+        .env("CARGO_TARGET_DIR", root.join("target"))
+        .env("ANDROID_HOME", root.join("sdk"))
+        .env_remove("ANDROID_SDK_HOME")
+        .env("NDK_HOME", root.join("ndk"))
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("there is no APK to install"), "{}", stderr);
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,38 @@
+mod common;
+
+use common::{build, fixture, quad_apk};
+use std::fs;
+
+#[test]
+fn build_warns_without_platform_tools() {
+    let root = fixture("no-platform-tools-build");
+
+    let output = build(&root, &["--offline"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("has no platform-tools"), "{}", stderr);
+    assert!(
+        stderr.contains("sdkmanager \"platform-tools\""),
+        "{}",
+        stderr
+    );
+    // The build itself carries on until the fake NDK makes it fail
+    assert!(stderr.contains("Unable to find NDK clang"), "{}", stderr);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn uninstall_fails_without_platform_tools() {
+    let root = fixture("no-platform-tools-uninstall");
+
+    let output = quad_apk(&root, "uninstall", &[]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains("has no platform-tools, install them with `sdkmanager \"platform-tools\"`"),
+        "{}",
+        stderr
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
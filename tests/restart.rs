@@ -0,0 +1,121 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::{fixture, quad_apk, write};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// Installs an adb with one device, where the app is installed if `installed` is true, and which
+/// records the arguments of the commands. The app runs as process 1234 for the first two
+/// `pidof`, and logcat prints a line of the app, one of another process and one of a panic.
+fn fake_adb(root: &Path, installed: bool) {
+    let pidof_count = root.join("pidof-count");
+    write(
+        root,
+        "sdk/platform-tools/adb",
+        &format!(
+            "#!/bin/sh\n\
+             echo \"$@\" >> {}\n\
+             case \"$*\" in\n\
+             \x20   devices) printf 'List of devices attached\\nR58M12ABCDE\\tdevice\\n\\n' ;;\n\
+             \x20   'shell pm path'*) {} ;;\n\
+             \x20   'shell date +%s') echo 1700000000 ;;\n\
+             \x20   'shell pidof'*)\n\
+             \x20       count=$(cat {pidof_count} 2>/dev/null || echo 0)\n\
+             \x20       echo $((count + 1)) > {pidof_count}\n\
+             \x20       [ \"$count\" -lt 2 ] && echo 1234 ;;\n\
+             \x20   logcat*)\n\
+             \x20       echo '11-14 22:13:20.100  1234  1250 I miniquad: started'\n\
+             \x20       echo '11-14 22:13:20.200   567   890 I ActivityManager: Displayed rust.app'\n\
+             \x20       echo '11-14 22:13:20.300  1234  1251 E SAPP    : panicked at src/main.rs'\n\
+             \x20       exec sleep 30 ;;\n\
+             esac\n",
+            root.join("adb-args").display(),
+            if installed {
+                "echo package:/data/app/rust.app/base.apk"
+            } else {
+                "exit 1"
+            },
+            pidof_count = pidof_count.display(),
+        ),
+    );
+    fs::set_permissions(
+        root.join("sdk/platform-tools/adb"),
+        fs::Permissions::from_mode(0o755),
+    )
+    .unwrap();
+}
+
+#[test]
+fn restart_stops_and_starts_the_app() {
+    let root = fixture("restart");
+    fake_adb(&root, true);
+
+    let output = quad_apk(&root, "restart", &["--offline", "--clear-data"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    let adb_args = fs::read_to_string(root.join("adb-args")).unwrap();
+    let commands = adb_args
+        .lines()
+        .filter(|line| *line != "devices" && *line != "shell date +%s")
+        .collect::<Vec<_>>();
+    assert_eq!(
+        commands,
+        vec![
+            "shell pm path rust.app",
+            "shell am force-stop rust.app",
+            "shell pm clear rust.app",
+            "shell am start -a android.intent.action.MAIN -n rust.app/.MainActivity",
+        ]
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn restart_requires_the_app_to_be_installed() {
+    let root = fixture("restart-not-installed");
+    fake_adb(&root, false);
+
+    let output = quad_apk(&root, "restart", &["--offline"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains(
+            "`rust.app` is not installed on the device, install it with `cargo quad-apk install`"
+        ),
+        "{}",
+        stderr
+    );
+    let adb_args = fs::read_to_string(root.join("adb-args")).unwrap();
+    assert!(!adb_args.contains("force-stop"), "{}", adb_args);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn restart_follows_the_log_until_the_app_exits() {
+    let root = fixture("restart-logcat");
+    fake_adb(&root, true);
+
+    let output = quad_apk(&root, "restart", &["--offline", "--logcat"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert_eq!(
+        stdout,
+        "11-14 22:13:20.100  1234  1250 I miniquad: started\n\
+         11-14 22:13:20.300  1234  1251 E SAPP    : panicked at src/main.rs\n"
+    );
+    assert!(stderr.contains("Exited `rust.app`"), "{}", stderr);
+    let adb_args = fs::read_to_string(root.join("adb-args")).unwrap();
+    assert!(
+        adb_args.contains("logcat -v threadtime -T 1700000000.000"),
+        "{}",
+        adb_args
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,33 @@
+mod common;
+
+use common::{build_command, native_activity_fixture, write};
+use std::fs;
+
+/// Builds an app with the nightly toolchain pinned by `rust_toolchain` against the real Android SDK
+/// and NDK found in the environment, while cargo runs with the stable toolchain. The app only
+/// compiles with a nightly rustc.
+#[test]
+#[ignore = "requires ANDROID_HOME, NDK_HOME, and stable and nightly toolchains with the aarch64-linux-android rust target"]
+fn build_with_pinned_toolchain() {
+    let root = native_activity_fixture("pinned-toolchain");
+    let mut manifest = fs::read_to_string(root.join("app/Cargo.toml")).unwrap();
+    manifest
+        .push_str("build_targets = [\"aarch64-linux-android\"]\nrust_toolchain = \"nightly\"\n");
+    write(&root, "app/Cargo.toml", &manifest);
+    let mut main = fs::read_to_string(root.join("app/src/main.rs")).unwrap();
+    main.insert_str(0, "#![feature(never_type)]\n");
+    write(&root, "app/src/main.rs", &main);
+
+    let output = build_command(&root, &["--nosign"])
+        .env("RUSTUP_TOOLCHAIN", "stable")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("Toolchain nightly"), "{}", stderr);
+    assert!(root
+        .join("target/android-artifacts/debug/apk/app.apk")
+        .exists());
+
+    fs::remove_dir_all(&root).unwrap();
+}